@@ -21,7 +21,9 @@ use notecalc_lib::helper::*;
 use notecalc_lib::units::units::Units;
 use notecalc_lib::{
     Layer, NoteCalcApp, OutputMessage, OutputMessageCommandId, RenderAsciiTextMsg, RenderBuckets,
-    RenderStringMsg, RenderUtf8TextMsg, Variable, MAX_LINE_COUNT,
+    RenderStringMsg, RenderUtf8TextMsg, Variable, COLOR_HEADER, COLOR_NUMBER, COLOR_NUMBER_ERROR,
+    COLOR_OPERATOR, COLOR_RESULTS, COLOR_TEXT, COLOR_UNIT, COLOR_VARIABLE, MAX_CARRIED_VAR_COUNT,
+    MAX_EXTERNAL_VAR_COUNT, MAX_LINE_COUNT,
 };
 
 mod utils;
@@ -101,13 +103,19 @@ impl AppPointers {
     fn mut_vars<'a>(ptr: u32) -> &'a mut [Option<Variable>] {
         let ptr_holder = unsafe { &*(ptr as *const AppPointers) };
         unsafe {
-            &mut (&mut *(ptr_holder.vars_ptr as *mut [Option<Variable>; MAX_LINE_COUNT + 1]))[..]
+            &mut (&mut *(ptr_holder.vars_ptr
+                as *mut [Option<Variable>;
+                    MAX_LINE_COUNT + 1 + MAX_EXTERNAL_VAR_COUNT + MAX_CARRIED_VAR_COUNT]))[..]
         }
     }
 
     fn vars<'a>(ptr: u32) -> &'a [Option<Variable>] {
         let ptr_holder = unsafe { &*(ptr as *const AppPointers) };
-        unsafe { &(&*(ptr_holder.vars_ptr as *const [Option<Variable>; MAX_LINE_COUNT + 1]))[..] }
+        unsafe {
+            &(&*(ptr_holder.vars_ptr
+                as *const [Option<Variable>;
+                    MAX_LINE_COUNT + 1 + MAX_EXTERNAL_VAR_COUNT + MAX_CARRIED_VAR_COUNT]))[..]
+        }
     }
 
     fn allocator<'a>(ptr: u32) -> &'a Bump {
@@ -272,9 +280,9 @@ pub fn handle_drag(app_ptr: u32, x: usize, y: usize) -> bool {
         AppPointers::mut_editor_objects(app_ptr),
         AppPointers::units(app_ptr),
         AppPointers::allocator(app_ptr),
-        AppPointers::tokens(app_ptr),
-        AppPointers::results(app_ptr),
-        AppPointers::vars(app_ptr),
+        AppPointers::mut_tokens(app_ptr),
+        AppPointers::mut_results(app_ptr),
+        AppPointers::mut_vars(app_ptr),
         AppPointers::mut_render_bucket(app_ptr),
     );
 }
@@ -457,15 +465,6 @@ pub fn handle_input(app_ptr: u32, input: u32, modifiers: u8) -> bool {
     return modif.is_some();
 }
 
-pub const COLOR_TEXT: u32 = 0x595959_FF;
-pub const COLOR_HEADER: u32 = 0x000000_FF;
-pub const COLOR_RESULTS: u32 = 0x000000_FF;
-pub const COLOR_NUMBER: u32 = 0xF92672_FF;
-pub const COLOR_NUMBER_ERROR: u32 = 0xFF0000_FF;
-pub const COLOR_OPERATOR: u32 = 0x000000_FF;
-pub const COLOR_UNIT: u32 = 0x000BED_FF;
-pub const COLOR_VARIABLE: u32 = 0x269d94_FF;
-
 fn send_render_commands_to_js(render_buckets: &RenderBuckets) {
     use byteorder::{LittleEndian, WriteBytesExt};
     use std::io::Cursor;
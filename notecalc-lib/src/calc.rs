@@ -1,19 +1,26 @@
+use std::cmp::Ordering;
 use std::ops::BitXor;
 use std::ops::Neg;
 use std::ops::Not;
 
+use crate::functions::{solve_linear, CustomFns, FnType};
 use crate::matrix::MatrixData;
 use crate::token_parser::{OperatorTokenType, Token, TokenType};
 use crate::units::consts::EMPTY_UNIT_DIMENSIONS;
 use crate::units::units::UnitOutput;
-use crate::Variables;
+use crate::{AngleMode, RoundingMode, Variables};
 use rust_decimal::prelude::*;
+use rust_decimal::RoundingStrategy;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CalcResult {
     pub typ: CalcResultType,
     index_into_tokens: usize,
     index2_into_tokens: Option<usize>,
+    // the base of a Number set by tobase, it is rendered in that base instead of the line's format
+    pub radix: Option<u32>,
+    // the 1 or 0 Number of a comparison, it is rendered as "true" or "false"
+    pub is_boolean: bool,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -31,6 +38,8 @@ impl CalcResult {
             typ,
             index_into_tokens: index,
             index2_into_tokens: None,
+            radix: None,
+            is_boolean: false,
         }
     }
 
@@ -39,6 +48,8 @@ impl CalcResult {
             typ,
             index_into_tokens: index,
             index2_into_tokens: Some(index2),
+            radix: None,
+            is_boolean: false,
         }
     }
 
@@ -66,6 +77,8 @@ impl CalcResult {
             }),
             index_into_tokens: 0,
             index2_into_tokens: None,
+            radix: None,
+            is_boolean: false,
         }
     }
 
@@ -100,13 +113,101 @@ pub fn evaluate_tokens<'text_ptr>(
     tokens: &mut [Token<'text_ptr>],
     shunting_tokens: &mut Vec<ShuntingYardResult>,
     variables: &Variables,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
 ) -> Result<Option<EvaluationResult>, ()> {
+    evaluate_tokens_with_fns(
+        tokens,
+        shunting_tokens,
+        variables,
+        &CustomFns::default(),
+        rounding_mode,
+        angle_mode,
+    )
+}
+
+/// Same as `evaluate_tokens`, but the tokens can call the functions of `fns` too
+pub fn evaluate_tokens_with_fns<'text_ptr>(
+    tokens: &mut [Token<'text_ptr>],
+    shunting_tokens: &mut Vec<ShuntingYardResult>,
+    variables: &Variables,
+    fns: &CustomFns,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+) -> Result<Option<EvaluationResult>, ()> {
+    let mut ctx = EvaluationContext {
+        variables,
+        fns,
+        bound_values: vec![],
+        active_user_fns: vec![],
+        rounding_mode,
+        angle_mode,
+    };
     let mut stack: Vec<CalcResult> = vec![];
+    let state = evaluate_range(
+        tokens,
+        shunting_tokens,
+        0,
+        shunting_tokens.len(),
+        &mut ctx,
+        &mut stack,
+    )?;
+    return match state.last_success_operation_result_index {
+        Some(last_success_operation_index) => {
+            // e.g. "1+2 some text 3"
+            // in this case prefer the result of 1+2 and ignore the number 3
+            Ok(Some(EvaluationResult {
+                there_was_unit_conversion: state.there_was_unit_conversion,
+                there_was_operation: true,
+                assignment: state.assignment,
+                result: stack[last_success_operation_index].clone(),
+            }))
+        }
+        None => Ok(stack.pop().map(|it| EvaluationResult {
+            there_was_operation: false,
+            there_was_unit_conversion: state.there_was_unit_conversion,
+            assignment: state.assignment,
+            result: it,
+        })),
+    };
+}
+
+struct EvaluationContext<'a> {
+    variables: &'a Variables,
+    fns: &'a CustomFns<'a>,
+    // the arguments of the user defined function being evaluated
+    bound_values: Vec<CalcResult>,
+    // the user defined functions being evaluated, calling them again is an error
+    active_user_fns: Vec<usize>,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+}
+
+struct RangeEvaluation {
+    there_was_unit_conversion: bool,
+    assignment: bool,
+    last_success_operation_result_index: Option<usize>,
+}
+
+/// Evaluates `shunting_tokens[from..to]` onto `stack`
+fn evaluate_range<'text_ptr>(
+    tokens: &mut [Token<'text_ptr>],
+    shunting_tokens: &[ShuntingYardResult],
+    from: usize,
+    to: usize,
+    ctx: &mut EvaluationContext,
+    stack: &mut Vec<CalcResult>,
+) -> Result<RangeEvaluation, ()> {
     let mut there_was_unit_conversion = false;
     let mut assignment = false;
     let mut last_success_operation_result_index = None;
+    // the indices of the ':' tokens which close the currently evaluated else branches
+    let mut else_branch_ends: Vec<usize> = vec![];
 
-    for token in shunting_tokens.iter_mut() {
+    let mut i = from;
+    while i < to {
+        let token = &shunting_tokens[i];
+        i += 1;
         match &token.typ {
             TokenType::NumberLiteral(num) => stack.push(CalcResult::new(
                 CalcResultType::Number(num.clone()),
@@ -122,12 +223,84 @@ pub fn evaluate_tokens<'text_ptr>(
                     token.index_into_tokens,
                 ))
             }
+            TokenType::Operator(OperatorTokenType::Question) => {
+                let condition = match stack.pop().map(|it| it.typ) {
+                    Some(CalcResultType::Number(num)) => !num.is_zero(),
+                    _ => return Err(()),
+                };
+                let else_index = find_conditional_branch_end(shunting_tokens, i).ok_or(())?;
+                let end_index =
+                    find_conditional_branch_end(shunting_tokens, else_index + 1).ok_or(())?;
+                if !condition {
+                    else_branch_ends.push(end_index);
+                    i = else_index + 1;
+                }
+                if last_success_operation_result_index
+                    .map(|it| it >= stack.len())
+                    .unwrap_or(false)
+                {
+                    last_success_operation_result_index = None;
+                }
+            }
+            TokenType::Operator(OperatorTokenType::Colon) => {
+                if else_branch_ends.last() == Some(&(i - 1)) {
+                    else_branch_ends.pop();
+                } else {
+                    // the then branch was evaluated, skip the else branch
+                    i = find_conditional_branch_end(shunting_tokens, i).ok_or(())? + 1;
+                }
+                if !stack.is_empty() {
+                    last_success_operation_result_index = Some(stack.len() - 1);
+                }
+            }
+            TokenType::Operator(OperatorTokenType::Fn { arg_count: 0, typ })
+                if typ.has_lazy_args() =>
+            {
+                // the marker in front of the first argument, see `ShuntingYard::shunting_yard`
+                let (arg_ranges, fn_entry_index) =
+                    find_lazy_args(shunting_tokens, i - 1).ok_or(())?;
+                if let Some(result) = call_lazy_fn(*typ, tokens, shunting_tokens, &arg_ranges, ctx)
+                {
+                    stack.push(CalcResult::new(result, token.index_into_tokens));
+                    last_success_operation_result_index = Some(stack.len() - 1);
+                    i = fn_entry_index + 1;
+                } else {
+                    Token::set_token_error_flag_by_index(token.index_into_tokens, tokens);
+                    return Err(());
+                }
+            }
+            TokenType::Operator(OperatorTokenType::Fn { arg_count, typ })
+                if matches!(typ, FnType::User(..) | FnType::Native(..)) =>
+            {
+                let result = match typ {
+                    FnType::User(fn_index) => call_user_fn(*fn_index, *arg_count, stack, ctx),
+                    FnType::Native(fn_index) => call_native_fn(*fn_index, *arg_count, stack, ctx),
+                    _ => None,
+                };
+                if let Some(mut result) = result {
+                    result.index_into_tokens = token.index_into_tokens;
+                    result.index2_into_tokens = None;
+                    stack.push(result);
+                    last_success_operation_result_index = Some(stack.len() - 1);
+                } else {
+                    Token::set_token_error_flag_by_index(token.index_into_tokens, tokens);
+                    return Err(());
+                }
+            }
             TokenType::Operator(typ) => {
                 if *typ == OperatorTokenType::Assign {
                     assignment = true;
                     continue;
                 }
-                if apply_operation(tokens, &mut stack, &typ, token.index_into_tokens) == true {
+                if apply_operation(
+                    tokens,
+                    stack,
+                    &typ,
+                    token.index_into_tokens,
+                    ctx.rounding_mode,
+                    ctx.angle_mode,
+                ) == true
+                {
                     if matches!(typ, OperatorTokenType::UnitConverter) {
                         there_was_unit_conversion = true;
                     }
@@ -141,7 +314,7 @@ pub fn evaluate_tokens<'text_ptr>(
             TokenType::StringLiteral | TokenType::Header => panic!(),
             TokenType::Variable { var_index } | TokenType::LineReference { var_index } => {
                 // TODO clone :(
-                match &variables[*var_index]
+                match &ctx.variables[*var_index]
                     .as_ref()
                     .expect("var_index should be valid")
                     .value
@@ -154,26 +327,258 @@ pub fn evaluate_tokens<'text_ptr>(
                     }
                 }
             }
+            TokenType::BoundVariable { index } => {
+                let value = ctx.bound_values.get(*index).ok_or(())?;
+                stack.push(CalcResult::new(value.typ.clone(), token.index_into_tokens));
+            }
         }
     }
-    return match last_success_operation_result_index {
-        Some(last_success_operation_index) => {
-            // e.g. "1+2 some text 3"
-            // in this case prefer the result of 1+2 and ignore the number 3
-            Ok(Some(EvaluationResult {
-                there_was_unit_conversion,
-                there_was_operation: true,
-                assignment,
-                result: stack[last_success_operation_index].clone(),
-            }))
+    Ok(RangeEvaluation {
+        there_was_unit_conversion,
+        assignment,
+        last_success_operation_result_index,
+    })
+}
+
+/// The value of a part of the output, e.g. the body of a function,
+/// chosen the same way as the result of a line
+fn evaluate_value<'text_ptr>(
+    tokens: &mut [Token<'text_ptr>],
+    shunting_tokens: &[ShuntingYardResult],
+    from: usize,
+    to: usize,
+    ctx: &mut EvaluationContext,
+) -> Result<CalcResult, ()> {
+    let mut stack: Vec<CalcResult> = vec![];
+    let state = evaluate_range(tokens, shunting_tokens, from, to, ctx, &mut stack)?;
+    match state.last_success_operation_result_index {
+        Some(index) => Ok(stack.swap_remove(index)),
+        None => stack.pop().ok_or(()),
+    }
+}
+
+/// Evaluates the body of the user defined function with the arguments on the top of the stack.
+/// A wrong argument count or a recursive call is an error.
+fn call_user_fn(
+    fn_index: usize,
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    ctx: &mut EvaluationContext,
+) -> Option<CalcResult> {
+    let fns = ctx.fns;
+    let user_fn = fns.user_fns.get(fn_index)?;
+    if arg_count != user_fn.param_count
+        || stack.len() < arg_count
+        || ctx.active_user_fns.contains(&fn_index)
+    {
+        return None;
+    }
+    let args = stack.split_off(stack.len() - arg_count);
+    let outer_bound_values = std::mem::replace(&mut ctx.bound_values, args);
+    ctx.active_user_fns.push(fn_index);
+    // the error flags set in the body are not rendered anywhere
+    let mut body_tokens = user_fn.tokens.clone();
+    let result = evaluate_value(
+        &mut body_tokens,
+        &user_fn.shunting_output_stack,
+        0,
+        user_fn.shunting_output_stack.len(),
+        ctx,
+    );
+    ctx.active_user_fns.pop();
+    ctx.bound_values = outer_bound_values;
+    result.ok()
+}
+
+/// Calls the function registered by the host with the arguments on the top of the stack.
+/// A wrong argument count or a failing function is an error.
+fn call_native_fn(
+    fn_index: usize,
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    ctx: &EvaluationContext,
+) -> Option<CalcResult> {
+    let native_fn = ctx.fns.native_fns.get(fn_index)?;
+    if arg_count != native_fn.arity || stack.len() < arg_count {
+        return None;
+    }
+    let args = stack.split_off(stack.len() - arg_count);
+    (native_fn.f)(&args)
+}
+
+/// Returns the ranges of the arguments of the lazy call whose first marker is at `marker_index`
+/// and the index of its `Fn` entry, see `ShuntingYard::shunting_yard`
+fn find_lazy_args(
+    shunting_tokens: &[ShuntingYardResult],
+    marker_index: usize,
+) -> Option<(Vec<(usize, usize)>, usize)> {
+    let fn_token_index = shunting_tokens[marker_index].index_into_tokens;
+    let mut arg_ranges = vec![];
+    let mut arg_start = marker_index + 1;
+    for (i, entry) in shunting_tokens.iter().enumerate().skip(arg_start) {
+        match entry.typ {
+            TokenType::Operator(OperatorTokenType::Fn { arg_count, .. })
+                if entry.index_into_tokens == fn_token_index =>
+            {
+                arg_ranges.push((arg_start, i));
+                if arg_count > 0 {
+                    return if arg_count == arg_ranges.len() {
+                        Some((arg_ranges, i))
+                    } else {
+                        None
+                    };
+                }
+                arg_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn call_lazy_fn<'text_ptr>(
+    typ: FnType,
+    tokens: &mut [Token<'text_ptr>],
+    shunting_tokens: &[ShuntingYardResult],
+    arg_ranges: &[(usize, usize)],
+    ctx: &mut EvaluationContext,
+) -> Option<CalcResultType> {
+    match typ {
+        FnType::Sum => evaluate_summation(tokens, shunting_tokens, arg_ranges, ctx),
+        FnType::Solve => evaluate_solve(tokens, shunting_tokens, arg_ranges, ctx),
+        _ => None,
+    }
+}
+
+// protection against e.g. "sum(i, 1, 1e9, i)"
+const MAX_SUMMATION_TERM_COUNT: i64 = 1000;
+
+/// "sum(i, 1, 5, i^2)", the last argument is evaluated with every integer between the bounds
+/// and the values are added together. It is 0 if the upper bound is less than the lower one.
+fn evaluate_summation<'text_ptr>(
+    tokens: &mut [Token<'text_ptr>],
+    shunting_tokens: &[ShuntingYardResult],
+    arg_ranges: &[(usize, usize)],
+    ctx: &mut EvaluationContext,
+) -> Option<CalcResultType> {
+    if arg_ranges.len() != 4 || !is_binding_declaration(shunting_tokens, arg_ranges[0], ctx) {
+        return None;
+    }
+    let start = evaluate_integer(tokens, shunting_tokens, arg_ranges[1], ctx)?;
+    let end = evaluate_integer(tokens, shunting_tokens, arg_ranges[2], ctx)?;
+    if end.saturating_sub(start) >= MAX_SUMMATION_TERM_COUNT {
+        return None;
+    }
+    let (body_start, body_end) = arg_ranges[3];
+    let mut sum: Option<CalcResult> = None;
+    for n in start..=end {
+        ctx.bound_values
+            .push(CalcResult::new(CalcResultType::Number(Decimal::from(n)), 0));
+        let term = evaluate_value(tokens, shunting_tokens, body_start, body_end, ctx);
+        ctx.bound_values.pop();
+        let term = term.ok()?;
+        sum = Some(match sum {
+            Some(sum) => add_op(&sum, &term)?,
+            None => term,
+        });
+    }
+    Some(
+        sum.map(|it| it.typ)
+            .unwrap_or(CalcResultType::Number(Decimal::zero())),
+    )
+}
+
+/// "solve(2*x + 3 = 11, x)", the value of the variable for which the two sides of
+/// the linear equation are equal
+fn evaluate_solve<'text_ptr>(
+    tokens: &mut [Token<'text_ptr>],
+    shunting_tokens: &[ShuntingYardResult],
+    arg_ranges: &[(usize, usize)],
+    ctx: &mut EvaluationContext,
+) -> Option<CalcResultType> {
+    if arg_ranges.len() != 2 || !is_binding_declaration(shunting_tokens, arg_ranges[1], ctx) {
+        return None;
+    }
+    let (equation_start, equation_end) = arg_ranges[0];
+    if equation_end <= equation_start
+        || shunting_tokens[equation_end - 1].typ != TokenType::Operator(OperatorTokenType::Eq)
+    {
+        return None;
+    }
+    let mut difference = |x: i64| {
+        ctx.bound_values
+            .push(CalcResult::new(CalcResultType::Number(Decimal::from(x)), 0));
+        // the two sides, without the '='
+        let mut stack = vec![];
+        let state = evaluate_range(
+            tokens,
+            shunting_tokens,
+            equation_start,
+            equation_end - 1,
+            ctx,
+            &mut stack,
+        );
+        ctx.bound_values.pop();
+        if state.is_err() || stack.len() != 2 {
+            return None;
+        }
+        match sub_op(&stack[0], &stack[1])?.typ {
+            CalcResultType::Number(num) => Some(num),
+            _ => None,
         }
-        None => Ok(stack.pop().map(|it| EvaluationResult {
-            there_was_operation: false,
-            there_was_unit_conversion,
-            assignment,
-            result: it,
-        })),
     };
+    solve_linear(&mut difference).map(CalcResultType::Number)
+}
+
+/// Whether the argument declares the next bound value, e.g. "i" in "sum(i, 1, 5, i^2)"
+fn is_binding_declaration(
+    shunting_tokens: &[ShuntingYardResult],
+    (from, to): (usize, usize),
+    ctx: &EvaluationContext,
+) -> bool {
+    to == from + 1
+        && matches!(
+            shunting_tokens[from].typ,
+            TokenType::BoundVariable { index } if index == ctx.bound_values.len()
+        )
+}
+
+fn evaluate_integer<'text_ptr>(
+    tokens: &mut [Token<'text_ptr>],
+    shunting_tokens: &[ShuntingYardResult],
+    (from, to): (usize, usize),
+    ctx: &mut EvaluationContext,
+) -> Option<i64> {
+    match evaluate_value(tokens, shunting_tokens, from, to, ctx)
+        .ok()?
+        .typ
+    {
+        CalcResultType::Number(num) if num.fract().is_zero() => num.to_i64(),
+        _ => None,
+    }
+}
+
+/// Returns the index of the ':' which closes the branch of a conditional starting at `from`,
+/// skipping the nested conditionals.
+fn find_conditional_branch_end(
+    shunting_tokens: &[ShuntingYardResult],
+    from: usize,
+) -> Option<usize> {
+    // every nested '?' is followed by two ':', one for its else branch and one for its end
+    let mut depth = 0;
+    for (i, token) in shunting_tokens.iter().enumerate().skip(from) {
+        match token.typ {
+            TokenType::Operator(OperatorTokenType::Question) => depth += 2,
+            TokenType::Operator(OperatorTokenType::Colon) => {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    return None;
 }
 
 fn apply_operation<'text_ptr>(
@@ -181,10 +586,13 @@ fn apply_operation<'text_ptr>(
     stack: &mut Vec<CalcResult>,
     op: &OperatorTokenType,
     op_token_index: usize,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
 ) -> bool {
     let succeed = match &op {
         OperatorTokenType::Mult
         | OperatorTokenType::Div
+        | OperatorTokenType::Mod
         | OperatorTokenType::Add
         | OperatorTokenType::Sub
         | OperatorTokenType::BinAnd
@@ -193,6 +601,12 @@ fn apply_operation<'text_ptr>(
         | OperatorTokenType::Pow
         | OperatorTokenType::ShiftLeft
         | OperatorTokenType::ShiftRight
+        | OperatorTokenType::Less
+        | OperatorTokenType::Greater
+        | OperatorTokenType::LessEq
+        | OperatorTokenType::GreaterEq
+        | OperatorTokenType::Eq
+        | OperatorTokenType::NotEq
         | OperatorTokenType::UnitConverter => {
             if stack.len() > 1 {
                 let (lhs, rhs) = (&stack[stack.len() - 2], &stack[stack.len() - 1]);
@@ -213,6 +627,7 @@ fn apply_operation<'text_ptr>(
         OperatorTokenType::UnaryMinus
         | OperatorTokenType::UnaryPlus
         | OperatorTokenType::Perc
+        | OperatorTokenType::Factorial
         | OperatorTokenType::BinNot => {
             let maybe_top = stack.last();
             if let Some(result) =
@@ -241,16 +656,25 @@ fn apply_operation<'text_ptr>(
                 false
             }
         }
-        OperatorTokenType::Fn { arg_count, typ } => {
-            typ.execute(*arg_count, stack, op_token_index, tokens)
-        }
+        OperatorTokenType::Fn { arg_count, typ } => typ.execute(
+            *arg_count,
+            stack,
+            op_token_index,
+            tokens,
+            rounding_mode,
+            angle_mode,
+        ),
         OperatorTokenType::Semicolon | OperatorTokenType::Comma => {
             // ignore
             true
         }
-        OperatorTokenType::Assign => panic!("handled in the main loop above"),
+        OperatorTokenType::Assign | OperatorTokenType::Question | OperatorTokenType::Colon => {
+            panic!("handled in the main loop above")
+        }
         OperatorTokenType::ParenOpen
         | OperatorTokenType::ParenClose
+        | OperatorTokenType::AbsBarOpen
+        | OperatorTokenType::AbsBarClose
         | OperatorTokenType::BracketOpen
         | OperatorTokenType::BracketClose => {
             // this branch was executed during fuzz testing, don't panic here
@@ -262,6 +686,9 @@ fn apply_operation<'text_ptr>(
             if let Some(result) = maybe_top.and_then(|top| unit_conversion(top, &target_unit)) {
                 stack.pop();
                 stack.push(result);
+            } else if let Some(CalcResultType::Number(..)) = maybe_top.map(|it| &it.typ) {
+                // the unit can't be applied, e.g. a currency without exchange rate
+                return false;
             } else {
                 // it is the unit operand for "in" conversion
                 // e.g. "3m in cm",
@@ -277,6 +704,77 @@ fn apply_operation<'text_ptr>(
     return succeed;
 }
 
+pub fn round_dp(num: &Decimal, dp: u32, mode: RoundingMode) -> Decimal {
+    let strategy = match mode {
+        RoundingMode::HalfUp => RoundingStrategy::RoundHalfUp,
+        RoundingMode::HalfEven => RoundingStrategy::BankersRounding,
+        RoundingMode::TowardZero => RoundingStrategy::RoundDown,
+        RoundingMode::Floor if num.is_sign_negative() => RoundingStrategy::RoundUp,
+        RoundingMode::Floor => RoundingStrategy::RoundDown,
+        RoundingMode::Ceil if num.is_sign_negative() => RoundingStrategy::RoundDown,
+        RoundingMode::Ceil => RoundingStrategy::RoundUp,
+    };
+    num.round_dp_with_strategy(dp, strategy)
+}
+
+/// Replaces the numeric parts of the result with the nearest integer if they are
+/// within `tolerance` to it, e.g. 0.9999999999 -> 1
+pub fn snap_result(result: &mut CalcResult, tolerance: &Decimal) {
+    fn snap(num: &Decimal, tolerance: &Decimal) -> Option<Decimal> {
+        let rounded = num.round();
+        if rounded != *num && (*num - rounded).abs() <= *tolerance {
+            Some(rounded)
+        } else {
+            None
+        }
+    }
+    match &mut result.typ {
+        CalcResultType::Number(num) | CalcResultType::Percentage(num) => {
+            if let Some(snapped) = snap(num, tolerance) {
+                *num = snapped;
+            }
+        }
+        CalcResultType::Quantity(num, unit) => {
+            // snap in the unit of the quantity, e.g. 0.9999999999 km -> 1 km
+            if let Some(snapped) = unit
+                .from_base_to_this_unit(num)
+                .and_then(|it| snap(&it, tolerance))
+                .and_then(|it| unit.normalize(&it))
+            {
+                *num = snapped;
+            }
+        }
+        CalcResultType::Matrix(mat) => {
+            for cell in &mut mat.cells {
+                snap_result(cell, tolerance);
+            }
+        }
+        CalcResultType::Unit(_) => {}
+    }
+}
+
+/// Rounds the numeric parts of the result to `scale` fractional digits
+pub fn round_result(result: &mut CalcResult, scale: u32, mode: RoundingMode) {
+    match &mut result.typ {
+        CalcResultType::Number(num) | CalcResultType::Percentage(num) => {
+            *num = round_dp(num, scale, mode)
+        }
+        CalcResultType::Quantity(num, unit) => {
+            // it is rounded in its own unit, e.g. 1.234 mm is 0.001234 in base unit (m)
+            *num = unit
+                .from_base_to_this_unit(num)
+                .and_then(|it| unit.normalize(&round_dp(&it, scale, mode)))
+                .unwrap_or_else(|| round_dp(num, scale, mode));
+        }
+        CalcResultType::Matrix(mat) => {
+            for cell in &mut mat.cells {
+                round_result(cell, scale, mode);
+            }
+        }
+        CalcResultType::Unit(_) => {}
+    }
+}
+
 fn unit_conversion(top: &CalcResult, target_unit: &UnitOutput) -> Option<CalcResult> {
     match &top.typ {
         CalcResultType::Number(num) => {
@@ -304,6 +802,7 @@ fn unary_operation(
         OperatorTokenType::UnaryMinus => unary_minus_op(top),
         OperatorTokenType::Perc => percentage_operator(top, op_token_index),
         OperatorTokenType::BinNot => binary_complement(top),
+        OperatorTokenType::Factorial => factorial(top),
         _ => None,
     };
 }
@@ -316,6 +815,7 @@ fn binary_operation(
     let result = match &op {
         OperatorTokenType::Mult => multiply_op(lhs, rhs),
         OperatorTokenType::Div => divide_op(lhs, rhs),
+        OperatorTokenType::Mod => modulo_op(lhs, rhs),
         OperatorTokenType::Add => add_op(lhs, rhs),
         OperatorTokenType::Sub => sub_op(lhs, rhs),
         OperatorTokenType::BinAnd => binary_and_op(lhs, rhs),
@@ -324,13 +824,21 @@ fn binary_operation(
         OperatorTokenType::Pow => pow_op(lhs, rhs),
         OperatorTokenType::ShiftLeft => binary_shift_left(lhs, rhs),
         OperatorTokenType::ShiftRight => binary_shift_right(lhs, rhs),
+        OperatorTokenType::Less
+        | OperatorTokenType::Greater
+        | OperatorTokenType::LessEq
+        | OperatorTokenType::GreaterEq
+        | OperatorTokenType::Eq
+        | OperatorTokenType::NotEq => comparison_op(op, lhs, rhs),
         OperatorTokenType::UnitConverter => {
             return match (&lhs.typ, &rhs.typ) {
                 (
                     CalcResultType::Quantity(lhs_num, source_unit),
                     CalcResultType::Unit(target_unit),
                 ) => {
-                    if source_unit == target_unit {
+                    // e.g. the target is a currency without exchange rate
+                    let convertible = target_unit.from_base_to_this_unit(lhs_num).is_some();
+                    if source_unit == target_unit && convertible {
                         Some(CalcResult::new(
                             CalcResultType::Quantity(lhs_num.clone(), target_unit.clone()),
                             0,
@@ -380,9 +888,31 @@ fn percentage_operator(lhs: &CalcResult, op_token_index: usize) -> Option<CalcRe
     }
 }
 
+/// The result has to fit into a Decimal, so 27! is the biggest one which can be calculated
+fn factorial(lhs: &CalcResult) -> Option<CalcResult> {
+    match &lhs.typ {
+        CalcResultType::Number(lhs_num)
+            if lhs_num.fract().is_zero() && !lhs_num.is_sign_negative() =>
+        {
+            let mut result = Decimal::one();
+            let mut i = Decimal::one();
+            while i <= *lhs_num {
+                result = result.checked_mul(&i)?;
+                i += Decimal::one();
+            }
+            Some(CalcResult::new(
+                CalcResultType::Number(result),
+                lhs.index_into_tokens,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// NOT(x) and ~x work on 64 bit two's complement integers, e.g. ~0 = -1
 fn binary_complement(lhs: &CalcResult) -> Option<CalcResult> {
     match &lhs.typ {
-        CalcResultType::Number(lhs_num) => {
+        CalcResultType::Number(lhs_num) if lhs_num.fract().is_zero() => {
             // 0b01 and 0b10
             let lhs_num = lhs_num.to_i64()?;
             Some(CalcResult::new(
@@ -441,6 +971,61 @@ fn binary_shift_right(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult>
     }
 }
 
+fn comparison_op(op: &OperatorTokenType, lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
+    // quantities are stored in their base units, so 1 km and 999 m are comparable
+    let ordering = match (&lhs.typ, &rhs.typ) {
+        (CalcResultType::Number(lhs), CalcResultType::Number(rhs))
+        | (CalcResultType::Percentage(lhs), CalcResultType::Percentage(rhs)) => lhs.cmp(rhs),
+        (CalcResultType::Quantity(lhs, lhs_unit), CalcResultType::Quantity(rhs, rhs_unit))
+            if lhs_unit == rhs_unit =>
+        {
+            lhs.cmp(rhs)
+        }
+        _ => return None,
+    };
+    let holds = match op {
+        OperatorTokenType::Less => ordering == Ordering::Less,
+        OperatorTokenType::Greater => ordering == Ordering::Greater,
+        OperatorTokenType::LessEq => ordering != Ordering::Greater,
+        OperatorTokenType::GreaterEq => ordering != Ordering::Less,
+        OperatorTokenType::Eq => ordering == Ordering::Equal,
+        OperatorTokenType::NotEq => ordering != Ordering::Equal,
+        _ => return None,
+    };
+    // it is still a number, so "(3 > 2) + 1" is 2
+    let mut result = CalcResult::new(
+        CalcResultType::Number(if holds {
+            Decimal::one()
+        } else {
+            Decimal::zero()
+        }),
+        0,
+    );
+    result.is_boolean = true;
+    Some(result)
+}
+
+/// The remainder of the truncated division, so its sign is the sign of the dividend,
+/// e.g. "-7 mod 3" = -1 and "7 mod -3" = 1
+fn modulo_op(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
+    match (&lhs.typ, &rhs.typ) {
+        (CalcResultType::Number(lhs), CalcResultType::Number(rhs)) => Some(CalcResult::new(
+            CalcResultType::Number(lhs.checked_rem(rhs)?),
+            0,
+        )),
+        // quantities are stored in their base units, so 1 km mod 300 m is 0.1 km
+        (CalcResultType::Quantity(lhs, lhs_unit), CalcResultType::Quantity(rhs, rhs_unit))
+            if lhs_unit == rhs_unit =>
+        {
+            Some(CalcResult::new(
+                CalcResultType::Quantity(lhs.checked_rem(rhs)?, lhs_unit.clone()),
+                0,
+            ))
+        }
+        _ => None,
+    }
+}
+
 fn binary_shift_left(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
     match (&lhs.typ, &rhs.typ) {
         (CalcResultType::Number(lhs), CalcResultType::Number(rhs)) => {
@@ -503,15 +1088,21 @@ fn pow_op(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
         // 1^x
         //////////////
         (CalcResultType::Number(lhs), CalcResultType::Number(rhs)) => {
-            // 2^3
-            rhs.to_i64()
-                .and_then(|rhs| {
-                    let p = pow(lhs.clone(), rhs);
-                    p
-                })
-                .map(|pow| CalcResult::new(CalcResultType::Number(pow), 0))
+            let result = if rhs.fract().is_zero() {
+                // 2^3, exact
+                pow(lhs.clone(), rhs.to_i64()?)
+            } else {
+                // 2^0.5, from_f64 returns None for NaN (e.g. (-8)^0.5) and infinity
+                let p = lhs.to_f64()?.powf(rhs.to_f64()?);
+                Decimal::from_f64(p)
+            };
+            result.map(|pow| CalcResult::new(CalcResultType::Number(pow), 0))
         }
         (CalcResultType::Quantity(lhs, lhs_unit), CalcResultType::Number(rhs)) => {
+            // unit powers must be integers
+            if !rhs.fract().is_zero() {
+                return None;
+            }
             let p = rhs.to_i64()?;
             let num_powered = pow(lhs.clone(), p)?;
             let unit_powered = lhs_unit.pow(p);
@@ -625,7 +1216,8 @@ pub fn multiply_op(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
                 0,
             ))
         }
-        (CalcResultType::Percentage(..), CalcResultType::Matrix(..)) => None,
+        // 50% * [10, 20], the same as [10, 20] * 50%
+        (CalcResultType::Percentage(..), CalcResultType::Matrix(mat)) => mat.mult_scalar(lhs),
         //////////////
         // Matrix
         //////////////
@@ -781,7 +1373,7 @@ pub fn add_op(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
     }
 }
 
-fn sub_op(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
+pub fn sub_op(lhs: &CalcResult, rhs: &CalcResult) -> Option<CalcResult> {
     match (&lhs.typ, &rhs.typ) {
         (CalcResultType::Unit(..), CalcResultType::Unit(..))
         | (CalcResultType::Unit(..), CalcResultType::Number(..))
@@ -1081,13 +1673,13 @@ mod tests {
         apply_to_prev_token_unit, num, num_with_err, op, op_err, str, unit,
     };
     use crate::units::units::Units;
-    use crate::{ResultFormat, Variable, Variables};
+    use crate::{AngleMode, ResultFormat, RoundingMode, Variable, Variables};
     use std::str::FromStr;
 
-    use crate::calc::{CalcResult, CalcResultType, EvaluationResult};
+    use crate::calc::{dec, CalcResult, CalcResultType, EvaluationResult};
     use crate::functions::FnType;
     use crate::helper::create_vars;
-    use crate::renderer::render_result;
+    use crate::renderer::{get_int_frac_part_len, render_result, ScientificNotation};
     use crate::token_parser::{OperatorTokenType, Token};
     use bumpalo::Bump;
     use rust_decimal::prelude::*;
@@ -1109,7 +1701,13 @@ mod tests {
             &vars,
             &arena,
         );
-        let _result_stack = crate::calc::evaluate_tokens(&mut tokens, &mut shunting_output, &vars);
+        let _result_stack = crate::calc::evaluate_tokens(
+            &mut tokens,
+            &mut shunting_output,
+            &vars,
+            RoundingMode::HalfEven,
+            AngleMode::Rad,
+        );
 
         crate::shunting_yard::tests::compare_tokens(expected_tokens, &tokens);
     }
@@ -1126,7 +1724,13 @@ mod tests {
         let mut shunting_output =
             crate::shunting_yard::tests::do_shunting_yard(&temp, &units, &mut tokens, vars, &arena);
 
-        let result = crate::calc::evaluate_tokens(&mut tokens, &mut shunting_output, vars);
+        let result = crate::calc::evaluate_tokens(
+            &mut tokens,
+            &mut shunting_output,
+            vars,
+            RoundingMode::HalfEven,
+            AngleMode::Rad,
+        );
 
         if let Err(..) = &result {
             assert_eq!("Err", expected);
@@ -1149,6 +1753,8 @@ mod tests {
                     *there_was_unit_conversion,
                     Some(dec_count),
                     false,
+                    &ScientificNotation::default(),
+                    RoundingMode::HalfEven,
                 ),
                 expected
             );
@@ -1162,7 +1768,9 @@ mod tests {
                         &ResultFormat::Dec,
                         false,
                         Some(dec_count),
-                        false
+                        false,
+                        &ScientificNotation::default(),
+                        RoundingMode::HalfEven,
                     ))
                     .unwrap_or(" ".to_string()),
                 expected,
@@ -1287,6 +1895,16 @@ mod tests {
         test("10% * 200", "20");
         test("0% * 200", "0");
         test("(10 + 20)%", "30 %");
+        // a bare percentage is y/100 when it is multiplied,
+        // but adding it to or subtracting it from x means x * (1 +- y/100)
+        test("20% of 500", "100");
+        test("20% of 500 km", "100 km");
+        test("20% of 500 + 10", "110");
+        test("10 + 20% of 500", "110");
+        test("(10 + 10)% of 50", "10");
+        test("500 + 20%", "600");
+        test("500 - 20%", "400");
+        test("20%", "20 %");
 
         test("30/200%", "15 %");
     }
@@ -1480,6 +2098,19 @@ mod tests {
         test_with_dec_count(51, "1 deg in rad", "0.0174532925199432957692369077 rad");
     }
 
+    #[test]
+    fn test_angle_unit_conversions() {
+        // deg <-> rad
+        test("180 deg in rad", "3.1416 rad");
+        test("1 rad in deg", "57.2958 deg");
+        // grad <-> rad
+        test("200 grad in rad", "3.1416 rad");
+        test("1 rad in grad", "63.662 grad");
+        // deg <-> grad
+        test("200 grad in deg", "180 deg");
+        test("90 deg in grad", "100 grad");
+    }
+
     #[test]
     fn test_cancelling_out() {
         test("40 m * 40 N / 40 J", "40");
@@ -1552,6 +2183,12 @@ mod tests {
         test("[2, 3; 4, 5] * 2", "[4, 6; 8, 10]");
 
         test("2km * [2]", "[4 km]");
+        test("2 m * [1, 2]", "[2 m, 4 m]");
+        test("[1, 2] * 2 m", "[2 m, 4 m]");
+        test("[1 m, 2 m] * 3 m", "[3 m^2, 6 m^2]");
+
+        test("50% * [10, 20]", "[5, 10]");
+        test("[10, 20] * 50%", "[5, 10]");
     }
 
     #[test]
@@ -1574,6 +2211,9 @@ mod tests {
         test("[2, 3; 4, 5] / 2", "[1, 1.5; 2, 2.5]");
 
         test("[100g] / 2g", "[50]");
+        test("[2 m, 4 m] / 2", "[1 m, 2 m]");
+        test("[2, 4; 6, 8] / 2", "[1, 2; 3, 4]");
+        test("[2, 4] / 0", "Err");
     }
 
     #[test]
@@ -1683,6 +2323,48 @@ mod tests {
         test_vars(&vars, "var - var", "0", 0);
     }
 
+    #[test]
+    fn test_fully_cancelled_units_are_rendered_without_unit_part() {
+        let units = Units::new();
+        let temp = "10 m / 5 m".chars().collect::<Vec<char>>();
+        let mut tokens = vec![];
+        let vars = create_vars();
+        let arena = Bump::new();
+        let mut shunting_output = crate::shunting_yard::tests::do_shunting_yard(
+            &temp,
+            &units,
+            &mut tokens,
+            &vars,
+            &arena,
+        );
+        let result = crate::calc::evaluate_tokens(
+            &mut tokens,
+            &mut shunting_output,
+            &vars,
+            RoundingMode::HalfEven,
+            AngleMode::Rad,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(&result.result.typ, &CalcResultType::Number(dec(2)));
+
+        let rendered = render_result(
+            &units,
+            &result.result,
+            &ResultFormat::Dec,
+            result.there_was_unit_conversion,
+            Some(DECIMAL_COUNT),
+            false,
+            &ScientificNotation::default(),
+            RoundingMode::HalfEven,
+        );
+        assert_eq!(rendered, "2");
+        let lengths = get_int_frac_part_len(&rendered);
+        assert_eq!(lengths.int_part_len, 1);
+        assert_eq!(lengths.frac_part_len, 0);
+        assert_eq!(lengths.unit_part_len, 0);
+    }
+
     #[test]
     fn test_unit_cancelling() {
         test("1 km / 50m", "20");
@@ -1776,6 +2458,12 @@ mod tests {
     fn test_binary_not() {
         test("NOT(0b11)", "-4");
         test("13 AND NOT(4 - 1)", "12");
+        test("~0b11", "-4");
+        test("~5 + 1", "-5");
+        test("2 * ~0", "-2");
+        test("~(4 - 1)", "-4");
+        test("~2.5", "Err");
+        test("NOT(2.5)", "Err");
     }
 
     #[test]
@@ -1783,6 +2471,374 @@ mod tests {
         test("transpose([5, 6, 7])", "[5; 6; 7]");
         test("transpose([1, 2; 3, 4])", "[1, 3; 2, 4]");
         test("transpose([1, 2; 3, 4; 5, 6])", "[1, 3, 5; 2, 4, 6]");
+        // column vector to row vector
+        test("transpose([5; 6; 7])", "[5, 6, 7]");
+        // transposing twice is identity
+        test(
+            "transpose(transpose([1, 2; 3, 4; 5, 6]))",
+            "[1, 2; 3, 4; 5, 6]",
+        );
+        test("transpose([1, 2; 3, 4]) * [1; 0]", "[1; 2]");
+        test("transpose(2)", "Err");
+    }
+
+    #[test]
+    fn test_func_inverse() {
+        test("inverse([4, 7; 2, 6])", "[0.6, -0.7; -0.2, 0.4]");
+        test(
+            "inverse([2, 1, 1; 1, 3, 2; 1, 0, 0])",
+            "[0, 0, 1; -2, 1, 3; 3, -1, -5]",
+        );
+        test(
+            "inverse([2, 0, 0; 0, 4, 0; 1, 0, 8])",
+            "[0.5, 0, 0; 0, 0.25, 0; -0.0625, 0, 0.125]",
+        );
+        test("inverse([2])", "[0.5]");
+        // the product with the inverse is the identity
+        test("[4, 7; 2, 6] * inverse([4, 7; 2, 6])", "[1, 0; 0, 1]");
+        test(
+            "inverse([2, 1, 1; 1, 3, 2; 1, 0, 0]) * [2, 1, 1; 1, 3, 2; 1, 0, 0]",
+            "[1, 0, 0; 0, 1, 0; 0, 0, 1]",
+        );
+        // singular
+        test("inverse([1, 2; 2, 4])", "Err");
+        // not square
+        test("inverse([1, 2, 3])", "Err");
+        test("inverse(2)", "Err");
+        test("inverse([1 m, 2; 3, 4])", "Err");
+    }
+
+    #[test]
+    fn test_func_round() {
+        test("round(2.4)", "2");
+        test("round(2.5)", "2");
+        test("round(3.5)", "4");
+        test("round(2.6)", "3");
+        test("round(3.14159, 2)", "3.14");
+        test("round(23.5%)", "24 %");
+        test("round(3.5, 1.5)", "Err");
+        test("round(3.5, -1)", "Err");
+        test("round([1.5, 2])", "Err");
+        test("round(1, 2, 3)", "Err");
+    }
+
+    #[test]
+    fn test_func_floor_ceil() {
+        test("floor(3.7)", "3");
+        test("ceil(3.2)", "4");
+        test("floor(-2.5)", "-3");
+        test("ceil(-2.5)", "-2");
+        test("floor(3.14159, 3)", "3.141");
+        test("ceil(3.14159, 1)", "3.2");
+        test("ceil(2 m)", "2 m");
+    }
+
+    #[test]
+    fn test_rounding_functions_keep_the_unit() {
+        test("round(2 m)", "2 m");
+        test("round(3.7 kg)", "4 kg");
+        test("round(1.26 km, 1)", "1.3 km");
+        test("floor(2.5 cm)", "2 cm");
+        test("ceil(350.2 g)", "351 g");
+        test("round(3.7 kg) + 1 kg", "5 kg");
+    }
+
+    #[test]
+    fn test_factorial() {
+        test("0!", "1");
+        test("5!", "120");
+        test("2 * 3!", "12");
+        test("(1 + 2)!", "6");
+        test("20!", "2432902008176640000");
+        test("27!", "10888869450418352160768000000");
+        test("28!", "Err");
+        test("(-3)!", "Err");
+        test("2.5!", "Err");
+        test("3 m!", "Err");
+        test("5 apples!", "5");
+        test("5 != 4", "true");
+    }
+
+    #[test]
+    fn test_hex_and_bin_literals() {
+        test("0xFF + 0b1010", "265");
+        test("0xfF * 2", "510");
+        test("0x10 m", "16 m");
+        test("0x", "Err");
+        test("0b + 1", "Err");
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        test("1 km > 999 m", "true");
+        test("1 km < 999 m", "false");
+        test("5 == 5", "true");
+        test("5 != 5", "false");
+        test("3 <= 3", "true");
+        test("2 >= 3", "false");
+        test("1 + 2 > 2", "true");
+        test("100 cm == 1 m", "true");
+        test("10% < 20%", "true");
+        test("1 m > 2 s", "Err");
+        test("1 m > 2", "Err");
+        // they are 1 and 0 in calculations
+        test("(3 > 2) + 1", "2");
+        test("(3 < 2) * 5", "0");
+        test("(3 > 2) ? 10 : 20", "10");
+        // the bit operators bind stronger than the comparisons
+        test("1 << 3 > 4", "true");
+        test("6 AND 3 == 2", "true");
+        test("1 + 1 << 2", "8");
+    }
+
+    #[test]
+    fn test_modulo_operator() {
+        test("17 mod 5", "2");
+        test("-7 mod 3", "-1");
+        test("7 mod -3", "1");
+        test("-7 mod -3", "-1");
+        test("7.5 mod 2", "1.5");
+        test("2 * 7 mod 4", "2");
+        test("1 + 7 mod 4", "4");
+        test("7 m mod 2 m", "1 m");
+        test("1 km mod 300 m", "0.1 km");
+        test("7 m mod 2 s", "Err");
+        test("7 m mod 2", "Err");
+        test("7 mod 0", "Err");
+    }
+
+    #[test]
+    fn test_conditional_operator() {
+        test("3 > 2 ? 10 : 20", "10");
+        test("3 < 2 ? 10 : 20", "20");
+        test("2 ? 10 : 20", "10");
+        test("0 ? 10 : 20", "20");
+        test("1 + 1 == 2 ? 10 + 1 : 20 + 1", "11");
+        test("1 km > 999 m ? 5 m : 6 m", "5 m");
+        // nested
+        test("0 ? 1 : 0 ? 2 : 3", "3");
+        test("0 ? 1 : 1 ? 2 : 3", "2");
+        test("1 ? 0 ? 2 : 3 : 4", "3");
+        test("(0 ? 1 : 2) * 10", "20");
+        // only the taken branch is evaluated
+        test("1 ? 5 : 1 / 0", "5");
+        test("0 ? 1 / 0 : 5", "5");
+        // malformed
+        test("3 > 2 ? 10", "Err");
+        test("3 > 2 ? 10 :", "Err");
+        test("1 m ? 10 : 20", "Err");
+    }
+
+    #[test]
+    fn test_to_converts_like_in() {
+        for (text, expected) in &[
+            ("5 km {} m", "5000 m"),
+            ("100 N {} kg*m / s ^ 2", "100 (kg m) / s^2"),
+            ("981 cm/s^2 {} m/s^2", "9.81 m / s^2"),
+            ("100 Hz {} s", "Err"),
+        ] {
+            test(&text.replace("{}", "in"), expected);
+            test(&text.replace("{}", "to"), expected);
+        }
+    }
+
+    #[test]
+    fn test_func_min_max() {
+        test("max(3, 7, 2)", "7");
+        test("min(3, 7, 2)", "2");
+        test("max(5)", "5");
+        test("min(-3, -7)", "-7");
+        test("max(3, 7) * 2", "14");
+        test("max(10%, 20%)", "20 %");
+        // the units are converted before the comparison
+        test("max(1 km, 500 m)", "1 km");
+        test("min(1 km, 500 m)", "500 m");
+        test("max(1 m, 150 cm)", "150 cm");
+        test("min(2 kg, 3000 g, 1500 g)", "1500 g");
+        test("max(1 km, 2 kg)", "Err");
+        test("max(1 km, 2)", "Err");
+        test("max(2, 10%)", "Err");
+    }
+
+    #[test]
+    fn test_func_elementwise_min_max() {
+        test("emin([1, 5; 7, 2], [3, 2; 7, 4])", "[1, 2; 7, 2]");
+        test("emax([1, 5; 7, 2], [3, 2; 7, 4])", "[3, 5; 7, 4]");
+        test("emax([1 km, 2 m], [500 m, 3 m])", "[1 km, 3 m]");
+        test("emin([1, 2], [1, 2; 3, 4])", "Err");
+        test("emin([1 km, 2], [1 kg, 2])", "Err");
+        test("emin(1, 2)", "Err");
+    }
+
+    #[test]
+    fn test_func_dot_and_cross_product() {
+        test("dot([1, 2, 3], [4, 5, 6])", "32");
+        test("dot([1; 2; 3], [4; 5; 6])", "32");
+        test("dot([1, 2, 3], [4; 5; 6])", "32");
+        test("dot([1 m, 2 m], [3 m, 4 m])", "11 m^2");
+        test("dot([1, 2], [1, 2, 3])", "Err");
+        test("dot([1, 2; 3, 4], [1, 2; 3, 4])", "Err");
+        test("dot(1, 2)", "Err");
+
+        test("cross([1, 0, 0], [0, 1, 0])", "[0, 0, 1]");
+        test("cross([1, 2, 3], [4, 5, 6])", "[-3, 6, -3]");
+        test("cross([1; 2; 3], [4, 5, 6])", "[-3; 6; -3]");
+        test("cross([1, 2], [3, 4])", "Err");
+        test("cross([1, 2, 3], [4, 5])", "Err");
+    }
+
+    #[test]
+    fn test_abs() {
+        test("abs(-5)", "5");
+        test("abs(5)", "5");
+        test("abs(-3 kg)", "3 kg");
+        test("abs(-20%)", "20 %");
+        test("abs([-1, 2; -3, 4])", "[1, 2; 3, 4]");
+        test("abs(1, 2)", "Err");
+
+        test("|-5|", "5");
+        test("|2 - 7|", "5");
+        test("|-3 kg|", "3 kg");
+        test("2 * |3 - 5|", "4");
+        test("|-5| * 2", "10");
+        test("||-5| - 8|", "3");
+        test("|abs(-2) - |-7||", "5");
+        test("|(1 - 3)|", "2");
+    }
+
+    #[test]
+    fn test_func_roots() {
+        test("sqrt(16)", "4");
+        test("sqrt(2)", "1.4142");
+        test("sqrt(0)", "0");
+        test("sqrt(-4)", "Err");
+        test("cbrt(27)", "3");
+        test("cbrt(-27)", "-3");
+        test("root(32, 5)", "2");
+        test("root(81, 4)", "3");
+        test("root(-16, 4)", "Err");
+        test("root(16, 0)", "Err");
+        test("root(16, 1.5)", "Err");
+        test("sqrt(4 m^2)", "2 m");
+        test("sqrt(9 km^2)", "3 km");
+        test("cbrt(8 m^3)", "2 m");
+        test("sqrt(4 m)", "Err");
+        test("sqrt(16, 2)", "Err");
+    }
+
+    #[test]
+    fn test_func_logarithms() {
+        test("ln(e())", "1");
+        test("ln(e)", "1");
+        test("2 * e", "5.4366");
+        test("ln(e^2)", "2");
+        test("ln(1)", "0");
+        test("ln(10)", "2.3026");
+        test("log(1000)", "3");
+        test("log(0.01)", "-2");
+        test("log(8, 2)", "3");
+        test("log(81, 3)", "4");
+        test("ln(0)", "Err");
+        test("ln(-1)", "Err");
+        test("log(-10)", "Err");
+        test("log(8, 1)", "Err");
+        test("log(8, -2)", "Err");
+        test("log(2 m)", "Err");
+        test("ln(2, 3)", "Err");
+    }
+
+    #[test]
+    fn test_func_approx_eq() {
+        test("approx_eq(1, 1.0001, 0.001)", "1");
+        test("approx_eq(1, 1.1, 0.001)", "0");
+        test("approx_eq(2, 2, 0)", "1");
+        test("approx_eq(1 km, 1000.5 m, 1 m)", "1");
+        test("approx_eq(1 km, 1010 m, 1 m)", "0");
+        test("approx_eq([1, 2], [1.0001, 2], 0.001)", "1");
+        test("approx_eq([1, 2], [1.1, 2], 0.001)", "0");
+        test(
+            "approx_eq(inverse([1, 2; 3, 3]) * [1, 2; 3, 3], [1, 0; 0, 1], 0.000001)",
+            "1",
+        );
+        test("approx_eq(1 km, 1 kg, 1)", "Err");
+        test("approx_eq(1 km, 1000 m, 1)", "Err");
+        test("approx_eq([1, 2], [1, 2, 3], 1)", "Err");
+        test("approx_eq(1, 2)", "Err");
+    }
+
+    #[test]
+    fn test_func_pctchange() {
+        test("pctchange(200, 250)", "25 %");
+        test("pctchange(250, 200)", "-20 %");
+        test("pctchange(2 m, 250 cm)", "25 %");
+        test("pctchange(0, 250)", "Err");
+        test("pctchange(2 m, 250)", "Err");
+        test("pctchange(100, 200, 250)", "Err");
+        test("pctchange(200)", "Err");
+    }
+
+    #[test]
+    fn test_func_trig() {
+        test("sin(0)", "0");
+        test("sin(90 deg)", "1");
+        test("sin(30 degree)", "0.5");
+        test("sin(pi() / 2)", "1");
+        test("cos(0)", "1");
+        test("cos(pi())", "-1");
+        test("cos(180 deg)", "-1");
+        test("cos(60 deg)", "0.5");
+        test("tan(0)", "0");
+        test("tan(45 deg)", "1");
+        test("tan(1)", "1.5574");
+        test("sin(2 m)", "Err");
+        test("2 * sin(90 deg) + 1", "3");
+        test("sin(1, 2)", "Err");
+    }
+
+    #[test]
+    fn test_func_hyperbolic() {
+        test("sinh(0)", "0");
+        test("sinh(1)", "1.1752");
+        test("cosh(0)", "1");
+        test("cosh(1)", "1.5431");
+        test("tanh(0)", "0");
+        test("tanh(1)", "0.7616");
+        test("asinh(1)", "0.8814");
+        test("acosh(1)", "0");
+        test("acosh(2)", "1.317");
+        test("atanh(0.5)", "0.5493");
+        test("acosh(0.5)", "Err");
+        test("atanh(1)", "Err");
+        test("sinh(1 m)", "Err");
+        test("sinh(1, 2)", "Err");
+    }
+
+    #[test]
+    fn test_func_tobase_frombase() {
+        test("tobase(5, 2)", "101");
+        test("tobase(0, 2)", "0");
+        test("tobase(255, 8)", "377");
+        test("frombase(101, 2)", "5");
+        test("frombase(377, 8)", "255");
+        test("frombase(FF, 16)", "255");
+        test("frombase(ff, 16)", "255");
+        test("frombase(\"FF\", 16)", "255");
+        test("frombase(\"377\", 8)", "255");
+        test("frombase(\"101\", 2)", "5");
+        test("frombase(BEEF, 16) + 1", "48880");
+        test("tobase(frombase(1234, 7), 7)", "1234");
+        test("tobase(255, 16)", "FF");
+        test("tobase(48879, 16)", "BEEF");
+        test("tobase(35, 36)", "Err");
+        // only the rendering changes, the value is the same
+        test("tobase(255, 16) + 1", "256");
+        // digit is out of range for the base
+        test("frombase(12, 2)", "Err");
+        test("frombase(\"12\", 2)", "Err");
+        test("frombase(FG, 16)", "Err");
+        test("tobase(2.5, 2)", "Err");
+        test("tobase(-1, 2)", "Err");
+        test("tobase(5, 2, 3)", "Err");
     }
 
     #[test]
@@ -1810,6 +2866,19 @@ mod tests {
         test("=", " ");
     }
 
+    #[test]
+    fn test_exponentiation() {
+        test("2^10", "1024");
+        test("2^3^2", "512");
+        test("(2^3)^2", "64");
+        test("2 * 3^2", "18");
+        test("2^0.5", "1.4142");
+        test("4^0.5", "2");
+        test("(-8)^0.5", "Err");
+        test("(2 m)^2", "4 m^2");
+        test("(2 m)^0.5", "Err");
+    }
+
     #[test]
     fn test_error_for_pow_percent() {
         test_tokens(
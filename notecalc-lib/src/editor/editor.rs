@@ -63,6 +63,14 @@ impl InputModifiers {
         }
     }
 
+    pub fn alt_shift() -> InputModifiers {
+        InputModifiers {
+            shift: true,
+            ctrl: false,
+            alt: true,
+        }
+    }
+
     pub fn ctrl_shift() -> InputModifiers {
         InputModifiers {
             shift: true,
@@ -256,7 +264,7 @@ pub enum RowModificationType {
 }
 
 impl RowModificationType {
-    fn merge(&mut self, other: Option<&RowModificationType>) {
+    pub fn merge(&mut self, other: Option<&RowModificationType>) {
         let self_row = match self {
             RowModificationType::SingleLine(row) => *row,
             RowModificationType::AllLinesFrom(row) => *row,
@@ -401,6 +409,16 @@ impl Editor {
         debug_assert!(self.last_column_index <= 120, "{}", self.last_column_index);
     }
 
+    /// The following modifications are undone together, but separately from the previous ones.
+    pub fn start_new_undo_group<T: Default + Clone + Debug>(
+        &mut self,
+        content: &mut EditorContent<T>,
+    ) {
+        content.undo_stack.push(Vec::with_capacity(4));
+        content.apply_undo_limit();
+        self.modif_time_treshold_expires_at = self.time + EDITOR_CURSOR_TICK_MS;
+    }
+
     pub fn is_cursor_shown(&self) -> bool {
         self.show_cursor
     }
@@ -698,13 +716,20 @@ impl Editor {
     ) -> Option<RowModificationType> {
         self.next_blink_at = self.time + EDITOR_CURSOR_TICK_MS;
         self.show_cursor = true;
+        if Editor::modifies_locked_line(&command, content, false) {
+            return None;
+        }
         let modif_type = self.do_command(&command, content);
         if modif_type.is_some() {
-            if self.modif_time_treshold_expires_at < self.time || content.undo_stack.is_empty() {
-                // new undo group
+            let new_undo_group =
+                self.modif_time_treshold_expires_at < self.time || content.undo_stack.is_empty();
+            if new_undo_group {
                 content.undo_stack.push(Vec::with_capacity(4));
             }
             content.undo_stack.last_mut().unwrap().push(command);
+            if new_undo_group {
+                content.apply_undo_limit();
+            }
             content.redo_stack.clear();
             self.modif_time_treshold_expires_at = self.time + EDITOR_CURSOR_TICK_MS;
         }
@@ -1132,6 +1157,15 @@ impl Editor {
         content: &mut EditorContent<T>,
     ) -> Option<RowModificationType> {
         let mut sum_modif_type: Option<RowModificationType> = None;
+        if let Some(command_group) = content.undo_stack.last() {
+            if command_group
+                .iter()
+                .rev()
+                .any(|it| Editor::modifies_locked_line(it, content, true))
+            {
+                return None;
+            }
+        }
         if let Some(command_group) = content.undo_stack.pop() {
             for command in command_group.iter().rev() {
                 let modif_type = self.undo_command(command, content);
@@ -1151,6 +1185,14 @@ impl Editor {
         content: &mut EditorContent<T>,
     ) -> Option<RowModificationType> {
         let mut sum_modif_type: Option<RowModificationType> = None;
+        if let Some(command_group) = content.redo_stack.last() {
+            if command_group
+                .iter()
+                .any(|it| Editor::modifies_locked_line(it, content, false))
+            {
+                return None;
+            }
+        }
         if let Some(command_group) = content.redo_stack.pop() {
             for command in command_group.iter() {
                 let modif_type = self.do_command(command, content);
@@ -1165,6 +1207,86 @@ impl Editor {
         sum_modif_type
     }
 
+    /// Whether executing (or undoing) the command would change the text of a locked line.
+    /// Inserting and moving lines don't count, e.g. a new line can be started after a locked one.
+    fn modifies_locked_line<T: Default + Clone + Debug>(
+        command: &EditorCommand<T>,
+        content: &EditorContent<T>,
+        undo: bool,
+    ) -> bool {
+        let single = |pos: &Pos| Some((pos.row, pos.row));
+        let rows = match command {
+            EditorCommand::SwapLineUpwards(_)
+            | EditorCommand::SwapLineDownards(_)
+            | EditorCommand::InsertEmptyRow(_) => None,
+            EditorCommand::InsertText {
+                pos,
+                text,
+                is_there_line_overflow,
+            } if undo => Some((
+                pos.row,
+                pos.row + text.matches('\n').count() + *is_there_line_overflow as usize,
+            )),
+            EditorCommand::InsertTextSelection {
+                selection,
+                text,
+                is_there_line_overflow,
+                ..
+            } if undo => {
+                let first = selection.get_first().row;
+                Some((
+                    first,
+                    first + text.matches('\n').count() + *is_there_line_overflow as usize,
+                ))
+            }
+            EditorCommand::DelSelection { selection, .. }
+            | EditorCommand::BackspaceSelection { selection, .. }
+            | EditorCommand::InsertCharSelection { selection, .. }
+                if undo =>
+            {
+                single(&selection.get_first())
+            }
+            EditorCommand::EnterSelection { selection, .. } if undo => {
+                let first = selection.get_first().row;
+                Some((first, first + 1))
+            }
+            EditorCommand::InsertTextSelection { selection, .. }
+            | EditorCommand::DelSelection { selection, .. }
+            | EditorCommand::BackspaceSelection { selection, .. }
+            | EditorCommand::InsertCharSelection { selection, .. }
+            | EditorCommand::EnterSelection { selection, .. } => {
+                Some((selection.get_first().row, selection.get_second().row))
+            }
+            EditorCommand::Del { pos, .. } if !undo && pos.column == content.line_len(pos.row) => {
+                Some((pos.row, pos.row + 1))
+            }
+            EditorCommand::InsertText { pos, .. }
+            | EditorCommand::Del { pos, .. }
+            | EditorCommand::DelCtrl { pos, .. }
+            | EditorCommand::Backspace { pos, .. }
+            | EditorCommand::BackspaceCtrl { pos, .. }
+            | EditorCommand::InsertChar { pos, .. }
+            | EditorCommand::CutLine { pos, .. } => single(pos),
+            EditorCommand::DuplicateLine { pos, .. } if undo => Some((pos.row + 1, pos.row + 1)),
+            EditorCommand::DuplicateLine { .. } => None,
+            // undo removes the split off line, which changes the upper one only if it was not empty
+            EditorCommand::Enter(pos) if undo && content.line_len(pos.row + 1) == 0 => {
+                Some((pos.row + 1, pos.row + 1))
+            }
+            EditorCommand::Enter(pos) if undo => Some((pos.row, pos.row + 1)),
+            EditorCommand::Enter(pos) if pos.column == content.line_len(pos.row) => None,
+            EditorCommand::Enter(pos) => single(pos),
+            EditorCommand::MergeLineWithNextRow {
+                upper_row_index, ..
+            } if undo => Some((*upper_row_index, *upper_row_index)),
+            EditorCommand::MergeLineWithNextRow {
+                upper_row_index, ..
+            } => Some((*upper_row_index, upper_row_index + 1)),
+        };
+        rows.map(|(first, last)| (first..=last).any(|row| content.is_line_locked(row)))
+            .unwrap_or(false)
+    }
+
     fn undo_command<T: Default + Clone + Debug>(
         &mut self,
         command: &EditorCommand<T>,
@@ -86,10 +86,14 @@ pub struct EditorContent<T: Default + Clone + Debug> {
     // TODO: need for fuzz testing, set it back to priv later
     pub undo_stack: Vec<EditorCommandGroup<T>>,
     pub(super) redo_stack: Vec<EditorCommandGroup<T>>,
+    // the oldest undo groups are dropped above this count
+    pub(super) undo_limit: Option<usize>,
     pub(super) max_line_len: usize,
     pub(super) line_lens: Vec<usize>,
     pub(super) canvas: Canvas,
     pub(super) line_data: Vec<T>,
+    // the editor refuses the commands which would modify the text of these lines
+    pub(super) is_line_locked: fn(&T) -> bool,
 }
 
 impl<T: Default + Clone + Debug> EditorContent<T> {
@@ -97,13 +101,48 @@ impl<T: Default + Clone + Debug> EditorContent<T> {
         EditorContent {
             undo_stack: Vec::with_capacity(32),
             redo_stack: Vec::with_capacity(32),
+            undo_limit: None,
             canvas: Vec::with_capacity(max_len * 64),
             line_lens: Vec::with_capacity(64),
             line_data: Vec::with_capacity(642),
             max_line_len: max_len,
+            is_line_locked: |_| false,
         }
     }
 
+    /// Tells the editor which lines can't be modified, based on their data.
+    pub fn set_line_lock_check(&mut self, is_line_locked: fn(&T) -> bool) {
+        self.is_line_locked = is_line_locked;
+    }
+
+    pub fn is_line_locked(&self, row_index: usize) -> bool {
+        row_index < self.line_count() && (self.is_line_locked)(&self.line_data[row_index])
+    }
+
+    /// None means unlimited (the default). The grouped commands (e.g. a matrix edit)
+    /// count as one entry.
+    pub fn set_undo_limit(&mut self, limit: Option<usize>) {
+        self.undo_limit = limit;
+        self.apply_undo_limit();
+    }
+
+    pub(super) fn apply_undo_limit(&mut self) {
+        if let Some(limit) = self.undo_limit {
+            if self.undo_stack.len() > limit {
+                let remove_count = self.undo_stack.len() - limit;
+                self.undo_stack.drain(0..remove_count);
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
     pub fn max_line_len(&self) -> usize {
         self.max_line_len
     }
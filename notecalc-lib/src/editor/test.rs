@@ -355,6 +355,40 @@ mod tests {
         assert_eq!(content.get_char(1, 24), 'Y');
     }
 
+    #[test]
+    fn test_undo_limit_drops_the_oldest_groups() {
+        let mut content = EditorContent::<usize>::new(80);
+        let mut editor = Editor::new(&mut content);
+        content.set_undo_limit(Some(2));
+        assert!(!content.can_undo());
+        for (i, ch) in ['a', 'b', 'c', 'd'].iter().enumerate() {
+            // each char goes into a separate undo group
+            editor.handle_tick(1000 * (i as u32 + 1));
+            editor.handle_input(
+                EditorInputEvent::Char(*ch),
+                InputModifiers::none(),
+                &mut content,
+            );
+        }
+        assert_eq!(content.undo_stack.len(), 2);
+
+        editor.undo(&mut content);
+        editor.undo(&mut content);
+        assert_eq!(content.get_content(), "ab");
+        assert!(!content.can_undo());
+        assert!(content.can_redo());
+        editor.undo(&mut content);
+        assert_eq!(content.get_content(), "ab");
+
+        editor.redo(&mut content);
+        assert_eq!(content.get_content(), "abc");
+        assert!(content.can_undo());
+
+        // lowering the limit drops the existing entries as well
+        content.set_undo_limit(Some(0));
+        assert!(!content.can_undo());
+    }
+
     #[test]
     #[should_panic(expected = "Cursor")]
     fn test_the_test2() {
@@ -1,5 +1,11 @@
-use crate::calc::{add_op, CalcResult, CalcResultType};
-use crate::token_parser::Token;
+use crate::calc::{
+    add_op, divide_op, multiply_op, pow, round_dp, sub_op, CalcResult, CalcResultType,
+    ShuntingYardResult,
+};
+use crate::matrix::MatrixData;
+use crate::token_parser::{OperatorTokenType, Token, TokenType};
+use crate::units::consts::ANGLE_UNIT_DIMENSIONS;
+use crate::{AngleMode, RoundingMode};
 use rust_decimal::prelude::*;
 use std::str::FromStr;
 use strum::IntoEnumIterator;
@@ -9,11 +15,43 @@ use strum_macros::EnumIter;
 pub enum FnType {
     Sin,
     Cos,
+    Tan,
     Nth,
     Sum,
     Transpose,
+    Inverse,
     Pi,
     Ceil,
+    Floor,
+    Round,
+    PctChange,
+    Sinh,
+    Cosh,
+    Tanh,
+    Asinh,
+    Acosh,
+    Atanh,
+    ToBase,
+    FromBase,
+    Min,
+    Max,
+    ApproxEq,
+    EMin,
+    EMax,
+    Sqrt,
+    Cbrt,
+    Root,
+    E,
+    Ln,
+    Log,
+    Abs,
+    Dot,
+    Cross,
+    Solve,
+    // a function defined in the document, the index into `CustomFns::user_fns`
+    User(usize),
+    // a function registered by the host, the index into `CustomFns::native_fns`
+    Native(usize),
 }
 
 impl FnType {
@@ -32,14 +70,51 @@ impl FnType {
         match self {
             FnType::Sin => &['s', 'i', 'n'],
             FnType::Cos => &['c', 'o', 's'],
+            FnType::Tan => &['t', 'a', 'n'],
             FnType::Nth => &['n', 't', 'h'],
             FnType::Sum => &['s', 'u', 'm'],
             FnType::Transpose => &['t', 'r', 'a', 'n', 's', 'p', 'o', 's', 'e'],
+            FnType::Inverse => &['i', 'n', 'v', 'e', 'r', 's', 'e'],
             FnType::Pi => &['p', 'i'],
             FnType::Ceil => &['c', 'e', 'i', 'l'],
+            FnType::Floor => &['f', 'l', 'o', 'o', 'r'],
+            FnType::Round => &['r', 'o', 'u', 'n', 'd'],
+            FnType::PctChange => &['p', 'c', 't', 'c', 'h', 'a', 'n', 'g', 'e'],
+            FnType::Sinh => &['s', 'i', 'n', 'h'],
+            FnType::Cosh => &['c', 'o', 's', 'h'],
+            FnType::Tanh => &['t', 'a', 'n', 'h'],
+            FnType::Asinh => &['a', 's', 'i', 'n', 'h'],
+            FnType::Acosh => &['a', 'c', 'o', 's', 'h'],
+            FnType::Atanh => &['a', 't', 'a', 'n', 'h'],
+            FnType::ToBase => &['t', 'o', 'b', 'a', 's', 'e'],
+            FnType::FromBase => &['f', 'r', 'o', 'm', 'b', 'a', 's', 'e'],
+            FnType::Min => &['m', 'i', 'n'],
+            FnType::Max => &['m', 'a', 'x'],
+            FnType::ApproxEq => &['a', 'p', 'p', 'r', 'o', 'x', '_', 'e', 'q'],
+            FnType::EMin => &['e', 'm', 'i', 'n'],
+            FnType::EMax => &['e', 'm', 'a', 'x'],
+            FnType::Sqrt => &['s', 'q', 'r', 't'],
+            FnType::Cbrt => &['c', 'b', 'r', 't'],
+            FnType::Root => &['r', 'o', 'o', 't'],
+            FnType::E => &['e'],
+            FnType::Ln => &['l', 'n'],
+            FnType::Log => &['l', 'o', 'g'],
+            FnType::Abs => &['a', 'b', 's'],
+            FnType::Dot => &['d', 'o', 't'],
+            FnType::Cross => &['c', 'r', 'o', 's', 's'],
+            FnType::Solve => &['s', 'o', 'l', 'v', 'e'],
+            // they are recognized by the tokenizer
+            FnType::User(..) | FnType::Native(..) => &[],
         }
     }
 
+    /// The arguments of these functions are not evaluated before the call, since they
+    /// bind a variable for another argument, e.g. "sum(i, 1, 5, i^2)"
+    #[inline]
+    pub fn has_lazy_args(&self) -> bool {
+        matches!(self, FnType::Sum | FnType::Solve)
+    }
+
     #[inline]
     pub fn execute<'text_ptr>(
         &self,
@@ -47,15 +122,75 @@ impl FnType {
         stack: &mut Vec<CalcResult>,
         fn_token_index: usize,
         tokens: &mut [Token<'text_ptr>],
+        rounding_mode: RoundingMode,
+        angle_mode: AngleMode,
     ) -> bool {
         match self {
             FnType::Nth => fn_nth(arg_count, stack, tokens, fn_token_index),
             FnType::Sum => fn_sum(arg_count, stack),
             FnType::Transpose => fn_transpose(arg_count, stack),
+            FnType::Inverse => fn_inverse(arg_count, stack),
             FnType::Pi => fn_pi(arg_count, stack, fn_token_index),
-            FnType::Sin => true,
-            FnType::Cos => true,
-            FnType::Ceil => fn_ceil(arg_count, stack, tokens, fn_token_index),
+            FnType::Sin => fn_trig(
+                arg_count,
+                stack,
+                tokens,
+                fn_token_index,
+                angle_mode,
+                f64::sin,
+            ),
+            FnType::Cos => fn_trig(
+                arg_count,
+                stack,
+                tokens,
+                fn_token_index,
+                angle_mode,
+                f64::cos,
+            ),
+            FnType::Tan => fn_trig(
+                arg_count,
+                stack,
+                tokens,
+                fn_token_index,
+                angle_mode,
+                f64::tan,
+            ),
+            FnType::Ceil => fn_round(arg_count, stack, tokens, fn_token_index, RoundingMode::Ceil),
+            FnType::Floor => fn_round(
+                arg_count,
+                stack,
+                tokens,
+                fn_token_index,
+                RoundingMode::Floor,
+            ),
+            FnType::Round => fn_round(arg_count, stack, tokens, fn_token_index, rounding_mode),
+            FnType::PctChange => fn_pctchange(arg_count, stack, tokens, fn_token_index),
+            FnType::Sinh => fn_f64(arg_count, stack, tokens, fn_token_index, f64::sinh),
+            FnType::Cosh => fn_f64(arg_count, stack, tokens, fn_token_index, f64::cosh),
+            FnType::Tanh => fn_f64(arg_count, stack, tokens, fn_token_index, f64::tanh),
+            FnType::Asinh => fn_f64(arg_count, stack, tokens, fn_token_index, f64::asinh),
+            FnType::Acosh => fn_f64(arg_count, stack, tokens, fn_token_index, f64::acosh),
+            FnType::Atanh => fn_f64(arg_count, stack, tokens, fn_token_index, f64::atanh),
+            FnType::ToBase => fn_base_conversion(arg_count, stack, tokens, fn_token_index, true),
+            FnType::FromBase => fn_base_conversion(arg_count, stack, tokens, fn_token_index, false),
+            FnType::Min => fn_min_max(arg_count, stack, tokens, fn_token_index, false),
+            FnType::Max => fn_min_max(arg_count, stack, tokens, fn_token_index, true),
+            FnType::ApproxEq => fn_approx_eq(arg_count, stack, tokens, fn_token_index),
+            FnType::EMin => fn_elementwise_min_max(arg_count, stack, tokens, fn_token_index, false),
+            FnType::EMax => fn_elementwise_min_max(arg_count, stack, tokens, fn_token_index, true),
+            FnType::Sqrt => fn_root(arg_count, stack, tokens, fn_token_index, Some(2)),
+            FnType::Cbrt => fn_root(arg_count, stack, tokens, fn_token_index, Some(3)),
+            FnType::Root => fn_root(arg_count, stack, tokens, fn_token_index, None),
+            FnType::E => fn_e(arg_count, stack, fn_token_index),
+            FnType::Ln => fn_log(arg_count, stack, tokens, fn_token_index, true),
+            FnType::Log => fn_log(arg_count, stack, tokens, fn_token_index, false),
+            FnType::Abs => fn_abs(arg_count, stack, tokens, fn_token_index),
+            FnType::Dot => fn_vector_product(arg_count, stack, tokens, fn_token_index, false),
+            FnType::Cross => fn_vector_product(arg_count, stack, tokens, fn_token_index, true),
+            // its arguments are lazy, see `has_lazy_args`
+            FnType::Solve => false,
+            // the evaluator calls them with the arguments of the custom functions
+            FnType::User(..) | FnType::Native(..) => false,
         }
     }
 }
@@ -72,31 +207,642 @@ fn fn_pi(arg_count: usize, stack: &mut Vec<CalcResult>, token_index: usize) -> b
     true
 }
 
-fn fn_ceil<'text_ptr>(
+/// abs(x) or |x|, matrices are processed element-wise
+fn fn_abs<'text_ptr>(
     arg_count: usize,
     stack: &mut Vec<CalcResult>,
     tokens: &mut [Token<'text_ptr>],
     fn_token_index: usize,
 ) -> bool {
-    if arg_count < 1 || stack.len() < 1 {
+    if arg_count != 1 || stack.is_empty() {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let param = &stack[stack.len() - 1];
+    if let Some(result) = abs(&param.typ) {
+        let token_index = param.get_index_into_tokens();
+        stack.pop();
+        stack.push(CalcResult::new(result, token_index));
+        true
+    } else {
+        param.set_token_error_flag(tokens);
+        false
+    }
+}
+
+fn abs(typ: &CalcResultType) -> Option<CalcResultType> {
+    Some(match typ {
+        CalcResultType::Number(num) => CalcResultType::Number(num.abs()),
+        CalcResultType::Quantity(num, unit) => CalcResultType::Quantity(num.abs(), unit.clone()),
+        CalcResultType::Percentage(num) => CalcResultType::Percentage(num.abs()),
+        CalcResultType::Matrix(mat) => {
+            let cells = mat
+                .cells
+                .iter()
+                .map(|cell| {
+                    abs(&cell.typ).map(|typ| CalcResult::new(typ, cell.get_index_into_tokens()))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            CalcResultType::Matrix(MatrixData::new(cells, mat.row_count, mat.col_count))
+        }
+        CalcResultType::Unit(..) => return None,
+    })
+}
+
+fn fn_e(arg_count: usize, stack: &mut Vec<CalcResult>, token_index: usize) -> bool {
+    if arg_count != 0 {
+        return false;
+    }
+
+    stack.push(CalcResult::new(
+        CalcResultType::Number(Decimal::from_str("2.7182818284590452353602874714").unwrap()),
+        token_index,
+    ));
+
+    true
+}
+
+/// ln(x), log(x) (base 10) and log(x, base).
+/// Decimal has no logarithm, so it is calculated on f64 and the result is rounded
+/// to LOG_DECIMAL_COUNT decimals to hide the float noise (e.g. log(8, 2) = 3).
+fn fn_log<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    natural: bool,
+) -> bool {
+    let arg_count_is_valid = if natural {
+        arg_count == 1
+    } else {
+        arg_count == 1 || arg_count == 2
+    };
+    if !arg_count_is_valid || stack.len() < arg_count {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let positive_f64 = |param: &CalcResult| match &param.typ {
+        CalcResultType::Number(num) if num.is_sign_positive() && !num.is_zero() => num.to_f64(),
+        _ => None,
+    };
+    let param = &stack[stack.len() - arg_count];
+    let base = if natural {
+        Some(std::f64::consts::E)
+    } else if arg_count == 2 {
+        positive_f64(&stack[stack.len() - 1]).filter(|base| *base != 1.0)
+    } else {
+        Some(10.0)
+    };
+    let result = positive_f64(param)
+        .and_then(|num| Some(num.log(base?)))
+        .and_then(Decimal::from_f64)
+        .map(|it| round_dp(&it, LOG_DECIMAL_COUNT, RoundingMode::HalfEven));
+    if let Some(result) = result {
+        let token_index = param.get_index_into_tokens();
+        stack.truncate(stack.len() - arg_count);
+        stack.push(CalcResult::new(CalcResultType::Number(result), token_index));
+        true
+    } else {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        false
+    }
+}
+
+const LOG_DECIMAL_COUNT: u32 = 12;
+
+/// round, floor and ceil with an optional decimal count, e.g. round(3.14159, 2) = 3.14.
+/// Quantities are rounded in their own unit, so round(3.7 kg) = 4 kg
+fn fn_round<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    rounding_mode: RoundingMode,
+) -> bool {
+    if arg_count < 1 || arg_count > 2 || stack.len() < arg_count {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let dp = if arg_count == 2 {
+        let dp_param = &stack[stack.len() - 1];
+        let dp = match &dp_param.typ {
+            CalcResultType::Number(n) if n.fract().is_zero() => n.to_u32().filter(|it| *it <= 28),
+            _ => None,
+        };
+        if let Some(dp) = dp {
+            dp
+        } else {
+            dp_param.set_token_error_flag(tokens);
+            return false;
+        }
+    } else {
+        0
+    };
+    let param = &stack[stack.len() - arg_count];
+    let result = match &param.typ {
+        CalcResultType::Number(num) => {
+            Some(CalcResultType::Number(round_dp(num, dp, rounding_mode)))
+        }
+        CalcResultType::Percentage(num) => {
+            Some(CalcResultType::Percentage(round_dp(num, dp, rounding_mode)))
+        }
+        CalcResultType::Quantity(num, unit) => unit
+            .from_base_to_this_unit(num)
+            .and_then(|num| unit.normalize(&round_dp(&num, dp, rounding_mode)))
+            .map(|num| CalcResultType::Quantity(num, unit.clone())),
+        _ => None,
+    };
+    if let Some(result) = result {
+        let token_index = param.get_index_into_tokens();
+        stack.truncate(stack.len() - arg_count);
+        stack.push(CalcResult::new(result, token_index));
+        true
+    } else {
+        param.set_token_error_flag(tokens);
+        false
+    }
+}
+
+/// Applies an f64 function on a single Number argument, for those which Decimal does not support
+fn fn_f64<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    f: fn(f64) -> f64,
+) -> bool {
+    if arg_count != 1 || stack.is_empty() {
         Token::set_token_error_flag_by_index(fn_token_index, tokens);
         false
     } else {
         let param = &stack[stack.len() - 1];
-        match &param.typ {
-            CalcResultType::Number(num) => {
-                let result = num.ceil();
-                let token_index = param.get_index_into_tokens();
-                stack.pop();
-                stack.push(CalcResult::new(CalcResultType::Number(result), token_index));
-                true
+        // from_f64 returns None for NaN and infinity
+        let result = match &param.typ {
+            CalcResultType::Number(num) => num.to_f64().map(f).and_then(Decimal::from_f64),
+            _ => None,
+        };
+        if let Some(result) = result {
+            let token_index = param.get_index_into_tokens();
+            stack.pop();
+            stack.push(CalcResult::new(CalcResultType::Number(result), token_index));
+            true
+        } else {
+            param.set_token_error_flag(tokens);
+            false
+        }
+    }
+}
+
+/// The argument is either an angle (e.g. 90 deg) or a Number, which is interpreted
+/// according to the angle mode
+fn fn_trig<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    angle_mode: AngleMode,
+    f: fn(f64) -> f64,
+) -> bool {
+    if arg_count != 1 || stack.is_empty() {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        false
+    } else {
+        let param = &stack[stack.len() - 1];
+        let radians = match &param.typ {
+            CalcResultType::Number(num) => match angle_mode {
+                AngleMode::Rad => num.to_f64(),
+                AngleMode::Deg => num.to_f64().map(f64::to_radians),
+            },
+            // angles are stored in radians
+            CalcResultType::Quantity(num, unit) if unit.dimensions == ANGLE_UNIT_DIMENSIONS => {
+                num.to_f64()
             }
-            _ => {
-                param.set_token_error_flag(tokens);
-                false
+            _ => None,
+        };
+        if let Some(result) = radians.map(f).and_then(Decimal::from_f64) {
+            let token_index = param.get_index_into_tokens();
+            stack.pop();
+            stack.push(CalcResult::new(CalcResultType::Number(result), token_index));
+            true
+        } else {
+            param.set_token_error_flag(tokens);
+            false
+        }
+    }
+}
+
+/// tobase(x, base) keeps the value of x and only sets the base it is rendered in
+/// (2..=16, e.g. tobase(255, 16) is "FF"), so tobase(255, 16) + 1 is 256 in decimal again.
+/// frombase(digits, base) reads the digits in the given base (2..=16), e.g. frombase(FF, 16),
+/// frombase(ff, 16) and frombase("FF", 16) are 255. The written digits are read by the
+/// tokenizer (see `find_base_digits_arg`), other arguments, e.g. a variable, are read
+/// by their decimal digits, so frombase(x, 2) is 5 if x is 101.
+fn fn_base_conversion<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    to_base: bool,
+) -> bool {
+    if arg_count != 2 || stack.len() < 2 {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let num_param = &stack[stack.len() - 2];
+    let base_param = &stack[stack.len() - 1];
+    let base = match &base_param.typ {
+        CalcResultType::Number(base) if base.fract().is_zero() => {
+            base.to_u32().filter(|it| (2..=16).contains(it))
+        }
+        _ => None,
+    };
+    let base = if let Some(base) = base {
+        base
+    } else {
+        base_param.set_token_error_flag(tokens);
+        return false;
+    };
+    let num = match &num_param.typ {
+        CalcResultType::Number(num) if num.fract().is_zero() => num.to_u64(),
+        _ => None,
+    };
+    let digits_were_read = is_base_digits_arg(tokens, num_param.get_index_into_tokens());
+    let result = num.and_then(|num| {
+        if to_base || digits_were_read {
+            Decimal::from_u64(num)
+        } else {
+            convert_from_base(&num.to_string(), base)
+        }
+    });
+    if let Some(result) = result {
+        let token_index = num_param.get_index_into_tokens();
+        stack.truncate(stack.len() - 2);
+        let mut result = CalcResult::new(CalcResultType::Number(result), token_index);
+        if to_base {
+            result.radix = Some(base);
+        }
+        stack.push(result);
+        true
+    } else {
+        num_param.set_token_error_flag(tokens);
+        false
+    }
+}
+
+fn convert_from_base(digits: &str, base: u32) -> Option<Decimal> {
+    u64::from_str_radix(digits, base)
+        .ok()
+        .and_then(Decimal::from_u64)
+}
+
+// whether the argument is the first one of "frombase(", which was read by the tokenizer
+fn is_base_digits_arg(tokens: &[Token], token_index: usize) -> bool {
+    let is_not_ws = |it: &&Token| !it.ptr.iter().all(|ch| ch.is_whitespace());
+    tokens.get(token_index).map_or(false, |it| it.is_number())
+        && tokens[..token_index]
+            .iter()
+            .rev()
+            .find(is_not_ws)
+            .map(|it| it.ptr)
+            == Some(&['('][..])
+        && tokens[token_index + 1..]
+            .iter()
+            .find(is_not_ws)
+            .map(|it| it.ptr)
+            == Some(&[','][..])
+}
+
+/// The first argument of "frombase(FF, 16)" is the digits in the base given as its second one,
+/// they can be quoted as well, e.g. "frombase(\"ff\", 16)". `index` is the start of the
+/// argument, returns its end and its value, which is None if the digits are invalid
+/// in the base. The base has to be written as a number.
+pub(crate) fn find_base_digits_arg(
+    line: &[char],
+    index: usize,
+) -> Option<(usize, Option<Decimal>)> {
+    const NAME: &[char] = &['f', 'r', 'o', 'm', 'b', 'a', 's', 'e', '('];
+    let mut call_end = index;
+    while call_end > 0 && line[call_end - 1].is_whitespace() {
+        call_end -= 1;
+    }
+    if !line[..call_end].ends_with(NAME)
+        || (call_end > NAME.len() && is_identifier_char(line[call_end - NAME.len() - 1]))
+    {
+        return None;
+    }
+    let quoted = line.get(index) == Some(&'"');
+    let digits_start = if quoted { index + 1 } else { index };
+    let mut digits_end = digits_start;
+    while digits_end < line.len() && line[digits_end].is_ascii_alphanumeric() {
+        digits_end += 1;
+    }
+    if digits_end == digits_start || (quoted && line.get(digits_end) != Some(&'"')) {
+        return None;
+    }
+    let arg_end = if quoted { digits_end + 1 } else { digits_end };
+    // ", base)"
+    let mut i = skip_whitespaces(line, arg_end);
+    if line.get(i) != Some(&',') {
+        return None;
+    }
+    let base_start = skip_whitespaces(line, i + 1);
+    i = base_start;
+    while i < line.len() && line[i].is_ascii_digit() {
+        i += 1;
+    }
+    let base = line[base_start..i]
+        .iter()
+        .collect::<String>()
+        .parse::<u32>()
+        .ok()
+        .filter(|it| (2..=16).contains(it))?;
+    if line.get(skip_whitespaces(line, i)) != Some(&')') {
+        return None;
+    }
+    let digits = line[digits_start..digits_end].iter().collect::<String>();
+    let value = convert_from_base(&digits, base);
+    // a name which is not made of digits of the base can be a variable, e.g. "frombase(x, 2)"
+    if value.is_none() && !quoted && !line[digits_start].is_ascii_digit() {
+        return None;
+    }
+    Some((arg_end, value))
+}
+
+/// sqrt(x), cbrt(x) and root(x, n). Units are rooted as well if their powers allow it,
+/// e.g. sqrt(4 m^2) = 2 m
+fn fn_root<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    degree: Option<i64>,
+) -> bool {
+    let expected_arg_count = if degree.is_some() { 1 } else { 2 };
+    if arg_count != expected_arg_count || stack.len() < arg_count {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let degree = match degree {
+        Some(degree) => Some(degree),
+        None => match &stack[stack.len() - 1].typ {
+            CalcResultType::Number(n) if n.fract().is_zero() => n.to_i64(),
+            _ => None,
+        },
+    };
+    let param = &stack[stack.len() - arg_count];
+    let result = degree.and_then(|degree| match &param.typ {
+        CalcResultType::Number(num) => nth_root(*num, degree).map(CalcResultType::Number),
+        CalcResultType::Quantity(num, unit) => {
+            let rooted_unit = unit.root(degree)?;
+            let rooted_num = nth_root(*num, degree)?;
+            Some(CalcResultType::Quantity(rooted_num, rooted_unit))
+        }
+        _ => None,
+    });
+    if let Some(result) = result {
+        let token_index = param.get_index_into_tokens();
+        stack.truncate(stack.len() - arg_count);
+        stack.push(CalcResult::new(result, token_index));
+        true
+    } else {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        false
+    }
+}
+
+/// Newton iteration on Decimals, starting from the f64 approximation,
+/// so perfect roots like sqrt(16) come out exact.
+/// Even roots of negative numbers have no result.
+fn nth_root(num: Decimal, degree: i64) -> Option<Decimal> {
+    if degree < 1 || (num.is_sign_negative() && degree % 2 == 0) {
+        return None;
+    }
+    if num.is_zero() || degree == 1 {
+        return Some(num);
+    }
+    let a = num.abs();
+    let n = Decimal::from(degree);
+    let mut x = Decimal::from_f64(a.to_f64()?.powf(1.0 / degree as f64))?;
+    for _ in 0..MAX_ROOT_ITERATION_COUNT {
+        if x.is_zero() {
+            break;
+        }
+        // x' = ((n - 1) * x + a / x^(n-1)) / n
+        let x_pow = pow(x, degree - 1)?;
+        let next = (n - Decimal::one())
+            .checked_mul(&x)?
+            .checked_add(&a.checked_div(&x_pow)?)?
+            .checked_div(&n)?;
+        if next == x {
+            break;
+        }
+        x = next;
+    }
+    Some(if num.is_sign_negative() { -x } else { x })
+}
+
+const MAX_ROOT_ITERATION_COUNT: usize = 32;
+
+fn fn_pctchange<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+) -> bool {
+    if arg_count != 2 || stack.len() < 2 {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let old = &stack[stack.len() - 2];
+    let new = &stack[stack.len() - 1];
+    let old_is_zero = match &old.typ {
+        CalcResultType::Number(num) | CalcResultType::Quantity(num, _) => num.is_zero(),
+        _ => true,
+    };
+    if old_is_zero {
+        old.set_token_error_flag(tokens);
+        return false;
+    }
+    // (new - old) / old
+    let ratio = sub_op(new, old).and_then(|diff| divide_op(&diff, old));
+    if let Some(CalcResult {
+        typ: CalcResultType::Number(ratio),
+        ..
+    }) = ratio
+    {
+        if let Some(result) = ratio.checked_mul(&Decimal::from(100)) {
+            let token_index = old.get_index_into_tokens();
+            stack.truncate(stack.len() - 2);
+            stack.push(CalcResult::new(
+                CalcResultType::Percentage(result),
+                token_index,
+            ));
+            return true;
+        }
+    }
+    Token::set_token_error_flag_by_index(fn_token_index, tokens);
+    false
+}
+
+/// Returns one of the arguments unchanged, so max(1 km, 500 m) = 1 km.
+/// Quantities are compared in their base unit, so they have to be of the same dimension.
+fn fn_min_max<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    max: bool,
+) -> bool {
+    if arg_count < 1 || stack.len() < arg_count {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let args_start = stack.len() - arg_count;
+    let mut selected = args_start;
+    for i in args_start + 1..stack.len() {
+        let (candidate, current) = (&stack[i], &stack[selected]);
+        match is_more_extreme(candidate, current, max) {
+            Some(true) => selected = i,
+            Some(false) => {}
+            None => {
+                candidate.set_token_error_flag(tokens);
+                return false;
+            }
+        }
+    }
+    let result = stack[selected].clone();
+    stack.truncate(args_start);
+    stack.push(result);
+    true
+}
+
+/// Whether `candidate` is bigger (or smaller if not `max`) than `current`,
+/// None if they are not comparable
+fn is_more_extreme(candidate: &CalcResult, current: &CalcResult, max: bool) -> Option<bool> {
+    let comparable = match (&candidate.typ, &current.typ) {
+        (CalcResultType::Number(..), CalcResultType::Number(..))
+        | (CalcResultType::Quantity(..), CalcResultType::Quantity(..))
+        | (CalcResultType::Percentage(..), CalcResultType::Percentage(..)) => {
+            sub_op(candidate, current)
+        }
+        _ => None,
+    };
+    let diff = match comparable.as_ref().map(|it| &it.typ) {
+        Some(CalcResultType::Number(diff))
+        | Some(CalcResultType::Quantity(diff, _))
+        | Some(CalcResultType::Percentage(diff)) => *diff,
+        _ => return None,
+    };
+    Some(if max {
+        diff > Decimal::zero()
+    } else {
+        diff < Decimal::zero()
+    })
+}
+
+/// e.g. emax([1, 5], [3, 2]) = [3, 5]
+fn fn_elementwise_min_max<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    max: bool,
+) -> bool {
+    if arg_count != 2 || stack.len() < 2 {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let (lhs, rhs) = (&stack[stack.len() - 2], &stack[stack.len() - 1]);
+    let result = match (&lhs.typ, &rhs.typ) {
+        (CalcResultType::Matrix(lhs_mat), CalcResultType::Matrix(rhs_mat))
+            if lhs_mat.row_count == rhs_mat.row_count && lhs_mat.col_count == rhs_mat.col_count =>
+        {
+            let cells: Option<Vec<CalcResult>> = lhs_mat
+                .cells
+                .iter()
+                .zip(rhs_mat.cells.iter())
+                .map(|(lhs_cell, rhs_cell)| {
+                    is_more_extreme(rhs_cell, lhs_cell, max).map(|rhs_is_better| {
+                        if rhs_is_better {
+                            rhs_cell.clone()
+                        } else {
+                            lhs_cell.clone()
+                        }
+                    })
+                })
+                .collect();
+            cells.map(|cells| MatrixData::new(cells, lhs_mat.row_count, lhs_mat.col_count))
+        }
+        _ => None,
+    };
+    if let Some(result) = result {
+        stack.truncate(stack.len() - 2);
+        stack.push(CalcResult::new(
+            CalcResultType::Matrix(result),
+            fn_token_index,
+        ));
+        true
+    } else {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        false
+    }
+}
+
+/// approx_eq(a, b, tolerance) is 1 if |a - b| <= tolerance, otherwise 0.
+/// Matrices are compared cell by cell, the tolerance of quantities must be a quantity as well.
+fn fn_approx_eq<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+) -> bool {
+    fn is_within(diff: &CalcResult, tolerance: &CalcResult) -> Option<bool> {
+        match (&diff.typ, &tolerance.typ) {
+            (CalcResultType::Number(diff), CalcResultType::Number(tol))
+            | (CalcResultType::Percentage(diff), CalcResultType::Percentage(tol))
+            | (CalcResultType::Percentage(diff), CalcResultType::Number(tol)) => {
+                Some(diff.abs() <= *tol)
+            }
+            (CalcResultType::Quantity(diff, unit), CalcResultType::Quantity(tol, tol_unit))
+                if unit.dimensions == tol_unit.dimensions =>
+            {
+                Some(diff.abs() <= *tol)
             }
+            (CalcResultType::Matrix(mat), _) => {
+                let mut all = true;
+                for cell in &mat.cells {
+                    all &= is_within(cell, tolerance)?;
+                }
+                Some(all)
+            }
+            _ => None,
         }
     }
+
+    if arg_count != 3 || stack.len() < 3 {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let lhs = &stack[stack.len() - 3];
+    let rhs = &stack[stack.len() - 2];
+    let tolerance = &stack[stack.len() - 1];
+    let result = sub_op(lhs, rhs).and_then(|diff| is_within(&diff, tolerance));
+    if let Some(result) = result {
+        let token_index = lhs.get_index_into_tokens();
+        stack.truncate(stack.len() - 3);
+        stack.push(CalcResult::new(
+            CalcResultType::Number(if result {
+                Decimal::one()
+            } else {
+                Decimal::zero()
+            }),
+            token_index,
+        ));
+        true
+    } else {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        false
+    }
 }
 
 fn fn_nth<'text_ptr>(
@@ -169,6 +915,92 @@ fn fn_sum(arg_count: usize, stack: &mut Vec<CalcResult>) -> bool {
     }
 }
 
+fn fn_inverse(arg_count: usize, stack: &mut Vec<CalcResult>) -> bool {
+    if arg_count < 1 {
+        false
+    } else {
+        let param = &stack[stack.len() - 1];
+        let index_into_tokens = param.get_index_into_tokens();
+        if let Some(inverse) = match &param.typ {
+            CalcResultType::Matrix(mat) => mat.inverse().map(CalcResultType::Matrix),
+            _ => None,
+        } {
+            stack.truncate(stack.len() - 1);
+            stack.push(CalcResult::new(inverse, index_into_tokens));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// dot(a, b) and cross(a, b) for row or column vectors, e.g. dot([1, 2, 3], [4, 5, 6]) = 32.
+/// The result of cross has the shape of `a`, and it requires 3 dimensional vectors.
+fn fn_vector_product<'text_ptr>(
+    arg_count: usize,
+    stack: &mut Vec<CalcResult>,
+    tokens: &mut [Token<'text_ptr>],
+    fn_token_index: usize,
+    cross: bool,
+) -> bool {
+    if arg_count != 2 || stack.len() < 2 {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        return false;
+    }
+    let as_vector = |param: &CalcResult| match &param.typ {
+        CalcResultType::Matrix(mat) if mat.row_count == 1 || mat.col_count == 1 => Some(mat),
+        _ => None,
+    };
+    let result = match (
+        as_vector(&stack[stack.len() - 2]),
+        as_vector(&stack[stack.len() - 1]),
+    ) {
+        (Some(a), Some(b)) if a.cells.len() != b.cells.len() => None,
+        (Some(a), Some(b)) if cross => cross_product(a, b),
+        (Some(a), Some(b)) => dot_product(a, b),
+        _ => None,
+    };
+    if let Some(result) = result {
+        stack.truncate(stack.len() - 2);
+        stack.push(CalcResult::new(result, fn_token_index));
+        true
+    } else {
+        Token::set_token_error_flag_by_index(fn_token_index, tokens);
+        false
+    }
+}
+
+fn dot_product(a: &MatrixData, b: &MatrixData) -> Option<CalcResultType> {
+    let mut products = a
+        .cells
+        .iter()
+        .zip(b.cells.iter())
+        .map(|(a, b)| multiply_op(a, b));
+    let first = products.next()??;
+    let sum = products.try_fold(first, |sum, product| add_op(&sum, &product?))?;
+    Some(sum.typ)
+}
+
+fn cross_product(a: &MatrixData, b: &MatrixData) -> Option<CalcResultType> {
+    if a.cells.len() != 3 {
+        return None;
+    }
+    // the i-th element is a[j] * b[k] - a[k] * b[j]
+    let cells = [(1, 2), (2, 0), (0, 1)]
+        .iter()
+        .map(|(j, k)| {
+            let lhs = multiply_op(&a.cells[*j], &b.cells[*k])?;
+            let rhs = multiply_op(&a.cells[*k], &b.cells[*j])?;
+            sub_op(&lhs, &rhs)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(CalcResultType::Matrix(MatrixData::new(
+        cells,
+        a.row_count,
+        a.col_count,
+    )))
+}
+
 fn fn_transpose(arg_count: usize, stack: &mut Vec<CalcResult>) -> bool {
     if arg_count < 1 {
         false
@@ -190,3 +1022,342 @@ fn fn_transpose(arg_count: usize, stack: &mut Vec<CalcResult>) -> bool {
         }
     }
 }
+
+/// A function defined in the document, e.g. "f(x, y) = x * y + 1"
+pub struct UserFnDefinition<'a> {
+    pub name: &'a [char],
+    pub params: Vec<&'a [char]>,
+    pub body: &'a [char],
+}
+
+/// The functions the tokenizer recognizes besides the builtin ones
+#[derive(Clone, Copy, Default)]
+pub struct FnNames<'a> {
+    /// indexed by `FnType::User`, later definitions hide the earlier ones with the same name
+    pub user_fns: &'a [UserFnDefinition<'a>],
+    /// indexed by `FnType::Native`, the user defined functions hide them
+    pub native_fns: &'a [NativeFn],
+}
+
+/// The body of a user defined function tokenized for the line which calls it,
+/// its parameters are `TokenType::BoundVariable`s
+pub struct UserFn<'text_ptr> {
+    pub param_count: usize,
+    pub tokens: Vec<Token<'text_ptr>>,
+    pub shunting_output_stack: Vec<ShuntingYardResult>,
+}
+
+/// The functions the evaluator can call besides the builtin ones
+#[derive(Default)]
+pub struct CustomFns<'a> {
+    pub user_fns: &'a [UserFn<'a>],
+    pub native_fns: &'a [NativeFn],
+}
+
+/// Returns whether calling the function leads to a recursion or to a call with a wrong
+/// argument count, following the calls in the bodies.
+/// `active_fns` contains the functions whose bodies are being checked.
+pub fn user_fn_call_is_invalid(
+    fn_index: usize,
+    user_fns: &[UserFn],
+    active_fns: &mut Vec<usize>,
+) -> bool {
+    if active_fns.contains(&fn_index) {
+        return true;
+    }
+    active_fns.push(fn_index);
+    let is_invalid = user_fns[fn_index]
+        .shunting_output_stack
+        .iter()
+        .any(|it| match &it.typ {
+            TokenType::Operator(OperatorTokenType::Fn {
+                arg_count,
+                typ: FnType::User(index),
+            }) => {
+                *arg_count != user_fns[*index].param_count
+                    || user_fn_call_is_invalid(*index, user_fns, active_fns)
+            }
+            _ => false,
+        });
+    active_fns.pop();
+    is_invalid
+}
+
+pub(crate) fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+fn skip_whitespaces(line: &[char], mut i: usize) -> usize {
+    while i < line.len() && line[i].is_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// Returns the end of the identifier starting at `i`, or None if there is no identifier there
+pub(crate) fn identifier_end(line: &[char], i: usize) -> Option<usize> {
+    if !line
+        .get(i)
+        .map(|it| it.is_alphabetic() || *it == '_')
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let mut end = i;
+    while end < line.len() && is_identifier_char(line[end]) {
+        end += 1;
+    }
+    Some(end)
+}
+
+pub fn parse_user_fn_definition(line: &[char]) -> Option<UserFnDefinition> {
+    let name_start = skip_whitespaces(line, 0);
+    let name_end = identifier_end(line, name_start)?;
+    let name = &line[name_start..name_end];
+    // the builtin functions can't be redefined
+    if FnType::value_of(name).is_some() || line.get(name_end) != Some(&'(') {
+        return None;
+    }
+    let mut params = Vec::new();
+    let mut i = skip_whitespaces(line, name_end + 1);
+    if line.get(i) != Some(&')') {
+        loop {
+            let param_start = skip_whitespaces(line, i);
+            let param_end = identifier_end(line, param_start)?;
+            params.push(&line[param_start..param_end]);
+            i = skip_whitespaces(line, param_end);
+            match line.get(i) {
+                Some(',') => i += 1,
+                Some(')') => break,
+                _ => return None,
+            }
+        }
+    }
+    i = skip_whitespaces(line, i + 1);
+    if line.get(i) != Some(&'=') || line.get(i + 1) == Some(&'=') {
+        return None;
+    }
+    let body_start = skip_whitespaces(line, i + 1);
+    let mut body_end = line.len();
+    while body_end > body_start && line[body_end - 1].is_whitespace() {
+        body_end -= 1;
+    }
+    if body_start >= body_end {
+        return None;
+    }
+    Some(UserFnDefinition {
+        name,
+        params,
+        body: &line[body_start..body_end],
+    })
+}
+
+/// A call which binds a variable in one of its arguments, e.g. "i" in "sum(i, 1, 5, i^2)"
+pub(crate) struct Binding<'a> {
+    pub typ: FnType,
+    // the start of the function name
+    pub fn_index: usize,
+    pub name: &'a [char],
+    // the start of the declaration of the name
+    pub name_index: usize,
+    // the index of the value among the ones bound by the evaluator
+    pub value_index: usize,
+    // the argument in which the name refers to the bound value
+    pub scope: (usize, usize),
+}
+
+/// Collects the calls in `text` which bind variables, the outer ones first.
+/// `first_value_index` is the count of the values bound outside of `text`, e.g. the
+/// parameters of a function.
+pub(crate) fn find_bindings(text: &[char], first_value_index: usize) -> Vec<Binding> {
+    let mut bindings: Vec<Binding> = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        let ident_end = if i == 0 || !is_identifier_char(text[i - 1]) {
+            identifier_end(text, i)
+        } else {
+            None
+        };
+        let ident_end = if let Some(end) = ident_end {
+            end
+        } else {
+            i += 1;
+            continue;
+        };
+        let name = &text[i..ident_end];
+        // the argument count, the argument of the name and the argument of its scope
+        let form = if name == FnType::Sum.name() {
+            Some((FnType::Sum, 4, 0, 3))
+        } else if name == FnType::Solve.name() {
+            Some((FnType::Solve, 2, 1, 0))
+        } else {
+            None
+        };
+        let binding = form.filter(|_| text.get(ident_end) == Some(&'(')).and_then(
+            |(typ, arg_count, name_arg, scope_arg)| {
+                let (_, args) =
+                    find_call_args(text, ident_end).filter(|(_, args)| args.len() == arg_count)?;
+                if typ == FnType::Solve && !is_equation(&text[args[0].0..args[0].1]) {
+                    return None;
+                }
+                let (name_start, name_end) = args[name_arg];
+                let name_start = skip_whitespaces(text, name_start);
+                let name_end = identifier_end(text, name_start)
+                    .filter(|end| skip_whitespaces(text, *end) == name_end)?;
+                let value_index = first_value_index
+                    + bindings
+                        .iter()
+                        .filter(|it| it.scope.0 <= i && i < it.scope.1)
+                        .count();
+                Some(Binding {
+                    typ,
+                    fn_index: i,
+                    name: &text[name_start..name_end],
+                    name_index: name_start,
+                    value_index,
+                    scope: args[scope_arg],
+                })
+            },
+        );
+        if let Some(binding) = binding {
+            bindings.push(binding);
+        }
+        // the arguments can contain further bindings
+        i = ident_end;
+    }
+    bindings
+}
+
+/// Returns the index of the parenthesis closing the one at `open_index` and the ranges of
+/// the arguments between them
+fn find_call_args(text: &[char], open_index: usize) -> Option<(usize, Vec<(usize, usize)>)> {
+    let mut depth = 0;
+    let mut args = Vec::new();
+    let mut arg_start = open_index + 1;
+    for (i, ch) in text.iter().enumerate().skip(open_index) {
+        match ch {
+            '(' | '[' => depth += 1,
+            ')' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    args.push((arg_start, i));
+                    return Some((i, args));
+                }
+            }
+            ',' if depth == 1 => {
+                args.push((arg_start, i));
+                arg_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A function injected by the host, see `NoteCalcApp::register_function`
+pub struct NativeFn {
+    pub name: Box<[char]>,
+    pub arity: usize,
+    pub f: Box<dyn Fn(&[CalcResult]) -> Option<CalcResult>>,
+}
+
+/// Whether `text` is "lhs = rhs" with a single top level '='
+fn is_equation(text: &[char]) -> bool {
+    let mut depth = 0;
+    let mut eq_index = None;
+    for (i, ch) in text.iter().enumerate() {
+        match ch {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '=' if depth == 0 => {
+                if eq_index.is_some() {
+                    return false;
+                }
+                eq_index = Some(i);
+            }
+            _ => {}
+        }
+    }
+    match eq_index {
+        Some(eq_index) => {
+            !trim(&text[..eq_index]).is_empty() && !trim(&text[eq_index + 1..]).is_empty()
+        }
+        None => false,
+    }
+}
+
+/// Returns the root of `f`, the difference of the two sides of an equation at the given
+/// value of its variable, or None if it is not linear.
+/// It is evaluated at a few points, its slope has to be the same between all of them.
+pub fn solve_linear(f: &mut dyn FnMut(i64) -> Option<Decimal>) -> Option<Decimal> {
+    let f0 = f(0)?;
+    let f1 = f(1)?;
+    let f2 = f(2)?;
+    let f10 = f(10)?;
+    let slope = f1.checked_sub(f0)?;
+    if slope.is_zero() {
+        return None;
+    }
+    let tolerance = Decimal::new(1, 15) * slope.abs().max(Decimal::one());
+    let is_on_line = |x: i64, fx: Decimal| {
+        slope
+            .checked_mul(Decimal::from(x))
+            .and_then(|it| it.checked_add(f0))
+            .and_then(|it| fx.checked_sub(it))
+            .map(|it| it.abs() <= tolerance * Decimal::from(x))
+            .unwrap_or(false)
+    };
+    if !is_on_line(2, f2) || !is_on_line(10, f10) {
+        return None;
+    }
+    Some((-f0).checked_div(slope)?.normalize())
+}
+
+fn trim(text: &[char]) -> &[char] {
+    let start = skip_whitespaces(text, 0);
+    let mut end = text.len();
+    while end > start && text[end - 1].is_whitespace() {
+        end -= 1;
+    }
+    &text[start..end]
+}
+
+/// Replaces the whole words which are localized keywords with their canonical form,
+/// e.g. "1 km nach m" -> "1 km in m" for the alias ("nach", "in").
+/// Returns None if there was nothing to replace.
+pub fn replace_keyword_aliases(
+    text: &[char],
+    aliases: &[(Box<[char]>, Box<[char]>)],
+) -> Option<Vec<char>> {
+    if aliases.is_empty() {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len());
+    let mut there_was_alias = false;
+    let mut i = 0;
+    while i < text.len() {
+        let ident_end = if i == 0 || !is_identifier_char(text[i - 1]) {
+            identifier_end(text, i)
+        } else {
+            None
+        };
+        if let Some(end) = ident_end {
+            let word = &text[i..end];
+            if let Some((_, keyword)) = aliases.iter().find(|(alias, _)| &**alias == word) {
+                out.extend_from_slice(keyword);
+                there_was_alias = true;
+            } else {
+                out.extend_from_slice(word);
+            }
+            i = end;
+        } else {
+            out.push(text[i]);
+            i += 1;
+        }
+    }
+    if there_was_alias {
+        Some(out)
+    } else {
+        None
+    }
+}
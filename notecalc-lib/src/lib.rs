@@ -13,28 +13,40 @@
     clippy::all
 )]
 
+use std::collections::HashMap;
 use std::io::Cursor;
-use std::mem::MaybeUninit;
 use std::ops::Range;
+use std::str::FromStr;
 use std::time::Duration;
 
 use bumpalo::Bump;
+use rust_decimal::prelude::{ToPrimitive, Zero};
+use rust_decimal::Decimal;
 use smallvec::SmallVec;
+use strum::IntoEnumIterator;
 use strum_macros::EnumDiscriminants;
 
 use helper::*;
 
 use crate::calc::{
-    add_op, evaluate_tokens, CalcResult, CalcResultType, EvaluationResult, ShuntingYardResult,
+    add_op, divide_op, evaluate_tokens_with_fns, round_result, snap_result, CalcResult,
+    CalcResultType, EvaluationResult, ShuntingYardResult,
 };
 use crate::consts::{LINE_NUM_CONSTS, LINE_NUM_CONSTS2, LINE_NUM_CONSTS3};
 use crate::editor::editor::{
     Editor, EditorInputEvent, InputModifiers, Pos, RowModificationType, Selection,
 };
 use crate::editor::editor_content::EditorContent;
+use crate::functions::{
+    parse_user_fn_definition, replace_keyword_aliases, user_fn_call_is_invalid, CustomFns, FnNames,
+    FnType, NativeFn, UserFn, UserFnDefinition,
+};
 use crate::matrix::MatrixData;
-use crate::renderer::{get_int_frac_part_len, render_result, render_result_into};
-use crate::shunting_yard::ShuntingYard;
+use crate::renderer::{
+    get_int_frac_part_len_in_format, render_latex, render_result, render_result_into,
+    ScientificNotation,
+};
+use crate::shunting_yard::{ShuntingYard, SyntaxError, SyntaxErrorKind};
 use crate::token_parser::{OperatorTokenType, Token, TokenParser, TokenType};
 use crate::units::units::Units;
 
@@ -49,15 +61,29 @@ pub mod consts;
 pub mod editor;
 pub mod renderer;
 
+pub const COLOR_TEXT: u32 = 0x595959_FF;
+pub const COLOR_HEADER: u32 = 0x000000_FF;
+pub const COLOR_RESULTS: u32 = 0x000000_FF;
+pub const COLOR_NUMBER: u32 = 0xF92672_FF;
+pub const COLOR_NUMBER_ERROR: u32 = 0xFF0000_FF;
+pub const COLOR_OPERATOR: u32 = 0x000000_FF;
+pub const COLOR_UNIT: u32 = 0x000BED_FF;
+pub const COLOR_VARIABLE: u32 = 0x269d94_FF;
+
 const SCROLLBAR_HOVER_COLOR: u32 = 0xFFBBBB_FF;
 const SCROLLBAR_NORMAL_COLOR: u32 = 0xFFCCCC_FF;
 const SCROLLBAR_WIDTH: usize = 1;
 
 const RENDERED_RESULT_PRECISION: usize = 28;
+// the max scale of rust_decimal's Decimal
+const MAX_INTERNAL_SCALE: u32 = 28;
 const LINE_REF_BACKGROUND_COLOR: u32 = 0xDCE2F7_FF;
+const FIND_MATCH_BACKGROUND_COLOR: u32 = 0xFFE28A_FF;
 const MAX_EDITOR_WIDTH: usize = 120;
 const LEFT_GUTTER_MIN_WIDTH: usize = 2;
 pub const MAX_LINE_COUNT: usize = 128;
+pub const MAX_EXTERNAL_VAR_COUNT: usize = 16;
+pub const MAX_CARRIED_VAR_COUNT: usize = 128;
 const RIGHT_GUTTER_WIDTH: usize = 2;
 const CHANGE_RESULT_PULSE_START_COLOR: u32 = 0xFF88FF_AA;
 const CHANGE_RESULT_PULSE_END_COLOR: u32 = 0xFFFFFF_55;
@@ -65,6 +91,10 @@ const REFERENCE_PULSE_PULSE_START_COLOR: u32 = 0x00FF7F_33;
 const MIN_RESULT_PANEL_WIDTH: usize = 7;
 const DEFAULT_RESULT_PANEL_WIDTH_PERCENT: usize = 30;
 const SUM_VARIABLE_INDEX: usize = MAX_LINE_COUNT;
+// the variables injected by the host are stored after the sum variable
+const FIRST_EXTERNAL_VAR_INDEX: usize = SUM_VARIABLE_INDEX + 1;
+// the variables defined above the evaluated window are carried after the external ones
+const FIRST_CARRIED_VAR_INDEX: usize = FIRST_EXTERNAL_VAR_INDEX + MAX_EXTERNAL_VAR_COUNT;
 const MATRIX_ASCII_HEADER_FOOTER_LINE_COUNT: usize = 2;
 const ACTIVE_LINE_REF_HIGHLIGHT_COLORS: [u32; 9] = [
     0xFFD300, 0xDE3163, 0x73c2fb, 0xc7ea46, 0x702963, 0x997950, 0x777b73, 0xFC6600, 0xED2939,
@@ -91,13 +121,15 @@ pub enum Click {
 pub mod helper {
     // so code from the lib module can't access the private parts
 
-    use std::ops::{Index, IndexMut};
+    use std::ops::{Index, IndexMut, Range};
 
     use crate::calc::CalcResultType;
     pub use crate::{MAX_LINE_COUNT, *};
 
-    pub fn create_vars() -> [Option<Variable>; MAX_LINE_COUNT + 1] {
-        let mut vars = [None; MAX_LINE_COUNT + 1];
+    pub fn create_vars(
+    ) -> [Option<Variable>; MAX_LINE_COUNT + 1 + MAX_EXTERNAL_VAR_COUNT + MAX_CARRIED_VAR_COUNT]
+    {
+        let mut vars = [None; MAX_LINE_COUNT + 1 + MAX_EXTERNAL_VAR_COUNT + MAX_CARRIED_VAR_COUNT];
         vars[SUM_VARIABLE_INDEX] = Some(Variable {
             name: Box::from(&['s', 'u', 'm'][..]),
             value: Err(()),
@@ -105,24 +137,48 @@ pub mod helper {
         return vars;
     }
 
+    // Only a window of MAX_LINE_COUNT lines is evaluated and rendered, it starts at the line
+    // `window_y` of the document. The per line data below is stored only for the lines of the
+    // window, the lines outside of it are read as if they were empty.
+    // The variables and the BitFlag128 rows are indexed relative to the window as well.
+    fn window_index(window_y: usize, y: ContentIndex) -> Option<usize> {
+        y.0.checked_sub(window_y).filter(|it| *it < MAX_LINE_COUNT)
+    }
+
+    // Only the lines of the window are evaluated and rendered, so nothing is written
+    // into the data of the lines outside of it.
+    fn window_index_mut(window_y: usize, y: ContentIndex) -> usize {
+        window_index(window_y, y).expect("the modified line is outside of the window")
+    }
+
     #[derive(Debug)]
-    pub struct EditorObjects(Vec<Vec<EditorObject>>);
+    pub struct EditorObjects {
+        objs: Vec<Vec<EditorObject>>,
+        window_y: usize,
+        // the objects of the lines outside of the window
+        empty: Vec<EditorObject>,
+    }
 
     impl EditorObjects {
         pub fn new() -> EditorObjects {
-            EditorObjects(
-                std::iter::repeat(Vec::with_capacity(8))
+            EditorObjects {
+                objs: std::iter::repeat(Vec::with_capacity(8))
                     .take(MAX_LINE_COUNT)
                     .collect::<Vec<_>>(),
-            )
+                window_y: 0,
+                empty: Vec::new(),
+            }
         }
 
-        pub fn clear(&mut self) {
-            self.0.clear();
+        pub fn window_y(&self) -> usize {
+            self.window_y
         }
 
-        pub fn push(&mut self, d: Vec<EditorObject>) {
-            self.0.push(d);
+        pub fn move_window(&mut self, window_y: usize) {
+            for objs in self.objs.iter_mut() {
+                objs.clear();
+            }
+            self.window_y = window_y;
         }
     }
 
@@ -130,28 +186,53 @@ pub mod helper {
         type Output = Vec<EditorObject>;
 
         fn index(&self, index: ContentIndex) -> &Self::Output {
-            &self.0[index.0]
+            match window_index(self.window_y, index) {
+                Some(i) => &self.objs[i],
+                None => &self.empty,
+            }
         }
     }
 
     impl IndexMut<ContentIndex> for EditorObjects {
         fn index_mut(&mut self, index: ContentIndex) -> &mut Self::Output {
-            &mut self.0[index.0]
+            &mut self.objs[window_index_mut(self.window_y, index)]
         }
     }
 
-    pub struct Results([LineResult; MAX_LINE_COUNT]);
+    pub struct Results {
+        results: [LineResult; MAX_LINE_COUNT],
+        window_y: usize,
+        // the result of the lines outside of the window
+        empty: LineResult,
+    }
 
     impl Results {
         pub fn new() -> Results {
-            Results([Ok(None); MAX_LINE_COUNT])
+            Results {
+                results: [Ok(None); MAX_LINE_COUNT],
+                window_y: 0,
+                empty: Ok(None),
+            }
         }
+
+        pub fn window_y(&self) -> usize {
+            self.window_y
+        }
+
+        pub fn move_window(&mut self, window_y: usize) {
+            for r in self.results.iter_mut() {
+                *r = Ok(None);
+            }
+            self.window_y = window_y;
+        }
+
+        /// The results of the lines of the window
         pub fn as_slice(&self) -> &[LineResult] {
-            &self.0[..]
+            &self.results[..]
         }
 
         pub fn as_mut_slice(&mut self) -> &mut [LineResult] {
-            &mut self.0[..]
+            &mut self.results[..]
         }
     }
 
@@ -159,26 +240,50 @@ pub mod helper {
         type Output = LineResult;
 
         fn index(&self, index: ContentIndex) -> &Self::Output {
-            &self.0[index.0]
+            match window_index(self.window_y, index) {
+                Some(i) => &self.results[i],
+                None => &self.empty,
+            }
         }
     }
 
     impl IndexMut<ContentIndex> for Results {
         fn index_mut(&mut self, index: ContentIndex) -> &mut Self::Output {
-            &mut self.0[index.0]
+            &mut self.results[window_index_mut(self.window_y, index)]
         }
     }
 
     #[derive(Debug)]
-    pub struct AppTokens<'a>([Option<Tokens<'a>>; MAX_LINE_COUNT]);
+    pub struct AppTokens<'a> {
+        tokens: [Option<Tokens<'a>>; MAX_LINE_COUNT],
+        window_y: usize,
+        // the tokens of the lines outside of the window
+        empty: Option<Tokens<'a>>,
+    }
 
     impl<'a> AppTokens<'a> {
         pub fn new() -> AppTokens<'a> {
-            AppTokens([None; MAX_LINE_COUNT])
+            AppTokens {
+                tokens: [None; MAX_LINE_COUNT],
+                window_y: 0,
+                empty: None,
+            }
+        }
+
+        pub fn window_y(&self) -> usize {
+            self.window_y
+        }
+
+        pub fn move_window(&mut self, window_y: usize) {
+            for t in self.tokens.iter_mut() {
+                *t = None;
+            }
+            self.window_y = window_y;
         }
 
+        /// The tokens of the lines of the window
         pub fn iter(&self) -> std::slice::Iter<Option<Tokens<'a>>> {
-            self.0.iter()
+            self.tokens.iter()
         }
     }
 
@@ -186,13 +291,16 @@ pub mod helper {
         type Output = Option<Tokens<'a>>;
 
         fn index(&self, index: ContentIndex) -> &Self::Output {
-            &self.0[index.0]
+            match window_index(self.window_y, index) {
+                Some(i) => &self.tokens[i],
+                None => &self.empty,
+            }
         }
     }
 
     impl<'a> IndexMut<ContentIndex> for AppTokens<'a> {
         fn index_mut(&mut self, index: ContentIndex) -> &mut Self::Output {
-            &mut self.0[index.0]
+            &mut self.tokens[window_index_mut(self.window_y, index)]
         }
     }
 
@@ -292,14 +400,37 @@ pub mod helper {
     pub struct GlobalRenderData {
         pub client_height: usize,
         pub scroll_y: usize,
+        // the number of editor columns hidden on the left, so the cursor remains visible
+        pub scroll_x: usize,
         pub result_gutter_x: usize,
         pub left_gutter_width: usize,
         pub longest_visible_result_len: usize,
         pub longest_visible_editor_line_len: usize,
         pub current_editor_width: usize,
         pub current_result_panel_width: usize,
+        pub cursor_style: CursorStyle,
+        pub align_comments: bool,
+        pub selection_sum_respects_resets: bool,
+        pub selection_average: bool,
+        pub result_separator: String,
+        pub result_row_separators: bool,
+        pub superscript_unit_exponents: bool,
+        pub repeating_decimal_notation: bool,
+        pub scientific_notation: ScientificNotation,
+        pub rounding_mode: RoundingMode,
+        pub thousands_separator: Option<ThousandsSeparator>,
+        pub assignment_display: AssignmentDisplay,
+        pub soft_wrap: bool,
+        pub horizontal_scroll: bool,
+        pub pretty_operators: bool,
+        // the first line of the evaluated and rendered window, see `window_index`
+        window_y: usize,
         editor_y_to_render_y: [Option<CanvasY>; MAX_LINE_COUNT],
         editor_y_to_rendered_height: [usize; MAX_LINE_COUNT],
+        // the lines which continue on the next render rows because of soft wrapping
+        editor_y_to_wrapped: [bool; MAX_LINE_COUNT],
+        // start and end index into RESULT_BUFFER
+        editor_y_to_result_range: [Option<(usize, usize)>; MAX_LINE_COUNT],
     }
 
     impl GlobalRenderData {
@@ -320,14 +451,33 @@ pub mod helper {
             }
             let mut r = GlobalRenderData {
                 scroll_y: 0,
+                scroll_x: 0,
                 longest_visible_result_len: 0,
                 longest_visible_editor_line_len: 0,
                 result_gutter_x,
                 left_gutter_width,
                 current_editor_width: 0,
                 current_result_panel_width: 0,
+                cursor_style: CursorStyle::Bar,
+                align_comments: false,
+                selection_sum_respects_resets: false,
+                selection_average: false,
+                result_separator: String::new(),
+                result_row_separators: false,
+                superscript_unit_exponents: false,
+                repeating_decimal_notation: false,
+                scientific_notation: ScientificNotation::default(),
+                rounding_mode: RoundingMode::HalfEven,
+                thousands_separator: Some(ThousandsSeparator::Space),
+                assignment_display: AssignmentDisplay::Value,
+                soft_wrap: false,
+                horizontal_scroll: false,
+                pretty_operators: false,
+                window_y: 0,
                 editor_y_to_render_y: [None; MAX_LINE_COUNT],
                 editor_y_to_rendered_height: [0; MAX_LINE_COUNT],
+                editor_y_to_wrapped: [false; MAX_LINE_COUNT],
+                editor_y_to_result_range: [None; MAX_LINE_COUNT],
                 client_height,
             };
 
@@ -337,14 +487,19 @@ pub mod helper {
         }
 
         pub fn set_result_gutter_x(&mut self, client_width: usize, x: usize) {
+            // the editor must have at least one visible column
+            let x = x.max(self.left_gutter_width + 2);
             self.result_gutter_x = x;
             // - 1 so that the last visible character in the editor is '…' if the content is to long
             self.current_editor_width = (x - self.left_gutter_width) - 1;
-            self.current_result_panel_width = client_width - x - RIGHT_GUTTER_WIDTH;
+            self.current_result_panel_width = client_width.saturating_sub(x + RIGHT_GUTTER_WIDTH);
         }
 
         pub fn set_left_gutter_width(&mut self, new_width: usize) {
             self.left_gutter_width = new_width;
+            if self.result_gutter_x < new_width + 2 {
+                self.result_gutter_x = new_width + 2;
+            }
             // - 1 so that the last visible character in the editor is '…' if the content is to long
             self.current_editor_width = (self.result_gutter_x - new_width) - 1;
         }
@@ -365,13 +520,32 @@ pub mod helper {
         }
 
         pub fn clear(&mut self) {
+            self.move_window(0);
+            self.scroll_y = 0;
+            self.scroll_x = 0;
+        }
+
+        pub fn window_y(&self) -> usize {
+            self.window_y
+        }
+
+        /// The rows of the window which exist in a content of `content_len` lines
+        pub fn window_rows(&self, content_len: usize) -> Range<usize> {
+            self.window_y..content_len.min(self.window_y + MAX_LINE_COUNT)
+        }
+
+        pub fn move_window(&mut self, window_y: usize) {
             for e in self.editor_y_to_render_y.iter_mut() {
                 *e = None;
             }
             for e in self.editor_y_to_rendered_height.iter_mut() {
                 *e = 0;
             }
-            self.scroll_y = 0;
+            for e in self.editor_y_to_wrapped.iter_mut() {
+                *e = false;
+            }
+            self.clear_result_ranges();
+            self.window_y = window_y;
         }
 
         pub fn is_visible(&self, y: ContentIndex) -> bool {
@@ -390,23 +564,52 @@ pub mod helper {
         }
 
         pub fn get_render_y(&self, y: ContentIndex) -> Option<CanvasY> {
-            self.editor_y_to_render_y[y.0]
+            window_index(self.window_y, y).and_then(|i| self.editor_y_to_render_y[i])
         }
 
         pub fn set_render_y(&mut self, y: ContentIndex, newy: Option<CanvasY>) {
-            self.editor_y_to_render_y[y.0] = newy;
+            if let Some(i) = window_index(self.window_y, y) {
+                self.editor_y_to_render_y[i] = newy;
+            }
         }
 
+        /// The render y of the lines of the window
         pub fn editor_y_to_render_y(&self) -> &[Option<CanvasY>] {
             &self.editor_y_to_render_y
         }
 
         pub fn get_rendered_height(&self, y: ContentIndex) -> usize {
-            self.editor_y_to_rendered_height[y.0]
+            window_index(self.window_y, y).map_or(0, |i| self.editor_y_to_rendered_height[i])
         }
 
         pub fn set_rendered_height(&mut self, y: ContentIndex, h: usize) {
-            self.editor_y_to_rendered_height[y.0] = h;
+            if let Some(i) = window_index(self.window_y, y) {
+                self.editor_y_to_rendered_height[i] = h;
+            }
+        }
+
+        pub fn is_wrapped(&self, y: ContentIndex) -> bool {
+            window_index(self.window_y, y).map_or(false, |i| self.editor_y_to_wrapped[i])
+        }
+
+        pub fn set_wrapped(&mut self, y: ContentIndex, wrapped: bool) {
+            if let Some(i) = window_index(self.window_y, y) {
+                self.editor_y_to_wrapped[i] = wrapped;
+            }
+        }
+
+        pub fn get_result_range(&self, y: ContentIndex) -> Option<(usize, usize)> {
+            window_index(self.window_y, y).and_then(|i| self.editor_y_to_result_range[i])
+        }
+
+        pub fn set_result_range(&mut self, y: ContentIndex, range: Option<(usize, usize)>) {
+            if let Some(i) = window_index(self.window_y, y) {
+                self.editor_y_to_result_range[i] = range;
+            }
+        }
+
+        pub fn clear_result_ranges(&mut self) {
+            self.editor_y_to_result_range = [None; MAX_LINE_COUNT];
         }
     }
 
@@ -467,6 +670,7 @@ pub mod helper {
             tokens: &[Token],
             vars: &Variables,
             active_mat_edit_height: Option<usize>,
+            collapsed_matrices: u32,
         ) -> usize {
             let mut max_height = active_mat_edit_height.unwrap_or(1);
             // determine max height based on result's height
@@ -485,12 +689,21 @@ pub mod helper {
             };
 
             // determine max height based on tokens' height
+            let mut matrix_index = 0;
             for token in tokens {
                 let token_height = match token.typ {
                     TokenType::Operator(OperatorTokenType::Matrix {
                         row_count,
                         col_count: _,
-                    }) => MatrixData::calc_render_height(row_count),
+                    }) => {
+                        let collapsed = is_matrix_collapsed(collapsed_matrices, matrix_index);
+                        matrix_index += 1;
+                        if collapsed {
+                            1
+                        } else {
+                            MatrixData::calc_render_height(row_count)
+                        }
+                    }
                     TokenType::LineReference { var_index } => {
                         let var = &vars[var_index];
                         match &var {
@@ -697,7 +910,23 @@ pub enum OutputMessage<'a> {
     FollowingTextCommandsAreHeaders(bool),
 }
 
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ThousandsSeparator {
+    // "1 000 000"
+    Space,
+    // "1,000,000"
+    Comma,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CursorStyle {
+    Bar,
+    Block,
+    Underline,
+}
+
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub enum Layer {
     BehindText,
     Text,
@@ -806,6 +1035,60 @@ pub enum ResultFormat {
     Bin,
     Dec,
     Hex,
+    Oct,
+    // same as Dec, but too big or too small numbers are rendered in scientific notation
+    Sci,
+}
+
+/// How the results of the assignment lines (e.g. `x = 2 + 3`) are rendered
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum AssignmentDisplay {
+    // "5"
+    Value,
+    // no result
+    Hide,
+    // "x = 5"
+    WithVarName,
+    // "x: 5"
+    Labeled,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum Warning {
+    // e.g. "5 metre", the suggestions are similar known units
+    UnknownUnit {
+        row: usize,
+        name: String,
+        suggestions: Vec<String>,
+    },
+}
+
+/// A compact descriptor of a row, e.g. for drawing a mini-map of the document.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct RowOverview {
+    pub has_result: bool,
+    pub is_comment: bool,
+    pub is_error: bool,
+    pub is_matrix: bool,
+    // the length of the row relative to the longest row, between 0 and 1
+    pub relative_width: f32,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum AngleMode {
+    Rad,
+    Deg,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum RoundingMode {
+    // 2.5 -> 3, -2.5 -> -3
+    HalfUp,
+    // banker's rounding, 2.5 -> 2, 3.5 -> 4
+    HalfEven,
+    Floor,
+    Ceil,
+    TowardZero,
 }
 
 #[derive(Clone, Debug)]
@@ -813,6 +1096,11 @@ pub struct LineData {
     // has to be pub because of external tests...
     pub line_id: usize,
     result_format: ResultFormat,
+    // the nth bit is set if the nth matrix of the line is rendered as a one-line placeholder
+    collapsed_matrices: u32,
+    locked: bool,
+    // the ids of the lines which are referenced by the line's tokens
+    referenced_line_ids: Vec<usize>,
 }
 
 impl Default for LineData {
@@ -820,6 +1108,9 @@ impl Default for LineData {
         LineData {
             line_id: 0,
             result_format: ResultFormat::Dec,
+            collapsed_matrices: 0,
+            locked: false,
+            referenced_line_ids: Vec::new(),
         }
     }
 }
@@ -836,6 +1127,15 @@ pub struct MatrixEditing {
     cell_strings: Vec<String>,
 }
 
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct MatrixEditState {
+    pub row_count: usize,
+    pub col_count: usize,
+    pub current_cell: Pos,
+    // row-major, the content of the current cell is the one being edited
+    pub cell_strings: Vec<String>,
+}
+
 impl MatrixEditing {
     pub fn new<'a>(
         row_count: usize,
@@ -861,7 +1161,74 @@ impl MatrixEditing {
             // from below
             Pos::from_row_column(row_count - 1, 0)
         };
+        MatrixEditing::with_current_cell(
+            row_count,
+            col_count,
+            src_canvas,
+            row_index,
+            start_text_index,
+            end_text_index,
+            |_cell_strings| current_cell,
+        )
+    }
+
+    /// The edited cell is the one under the click, `local_x` and `local_y` are relative to
+    /// the top left corner of the rendered matrix (its left bracket).
+    pub fn new_at_click(
+        row_count: usize,
+        col_count: usize,
+        src_canvas: &[char],
+        row_index: ContentIndex,
+        start_text_index: usize,
+        end_text_index: usize,
+        local_x: usize,
+        local_y: usize,
+    ) -> MatrixEditing {
+        MatrixEditing::with_current_cell(
+            row_count,
+            col_count,
+            src_canvas,
+            row_index,
+            start_text_index,
+            end_text_index,
+            |cell_strings| {
+                // the cells of a multiline matrix start below the top part of the brackets
+                let header_h = if row_count == 1 { 0 } else { 1 };
+                let row = local_y.saturating_sub(header_h).min(row_count - 1);
+                // skip the left bracket, the columns are separated by 2 spaces
+                let mut col_start_x = 1;
+                let mut col = 0;
+                while col + 1 < col_count {
+                    let col_w = (0..row_count)
+                        .map(|row_i| {
+                            cell_strings
+                                .get(row_i * col_count + col)
+                                .map(|it| it.chars().count())
+                                .unwrap_or(0)
+                        })
+                        .max()
+                        .unwrap_or(0);
+                    // the first space belongs to the left column, the second to the right one
+                    if local_x <= col_start_x + col_w {
+                        break;
+                    }
+                    col_start_x += col_w + 2;
+                    col += 1;
+                }
+                Pos::from_row_column(row, col)
+            },
+        )
+    }
 
+    fn with_current_cell(
+        row_count: usize,
+        col_count: usize,
+        src_canvas: &[char],
+        row_index: ContentIndex,
+        start_text_index: usize,
+        end_text_index: usize,
+        calc_current_cell: impl FnOnce(&[String]) -> Pos,
+    ) -> MatrixEditing {
         let mut editor_content = EditorContent::new(32);
         let mut mat_edit = MatrixEditing {
             row_index,
@@ -871,12 +1238,11 @@ impl MatrixEditing {
             editor_content,
             row_count,
             col_count,
-            current_cell,
+            current_cell: Pos::from_row_column(0, 0),
             cell_strings: Vec::with_capacity((row_count * col_count).max(4)),
         };
         let mut str: String = String::with_capacity(8);
         let mut can_ignore_ws = true;
-        dbg!(src_canvas);
         for ch in src_canvas {
             match ch {
                 '[' => {
@@ -885,12 +1251,9 @@ impl MatrixEditing {
                 ']' => {
                     break;
                 }
-                ',' => {
-                    mat_edit.cell_strings.push(str);
-                    str = String::with_capacity(8);
-                    can_ignore_ws = true;
-                }
-                ';' => {
+                ',' | ';' => {
+                    // the trailing whitespaces are ignored as well, e.g. "[1 , 2]"
+                    str.truncate(str.trim_end().len());
                     mat_edit.cell_strings.push(str);
                     str = String::with_capacity(8);
                     can_ignore_ws = true;
@@ -904,10 +1267,16 @@ impl MatrixEditing {
                 }
             }
         }
+        str.truncate(str.trim_end().len());
         if str.len() > 0 {
             mat_edit.cell_strings.push(str);
         }
+        // a ragged matrix can have less cells than its size
+        while mat_edit.cell_strings.len() < row_count * col_count {
+            mat_edit.cell_strings.push(String::new());
+        }
 
+        mat_edit.current_cell = calc_current_cell(&mat_edit.cell_strings);
         let cell_index = mat_edit.current_cell.row * col_count + mat_edit.current_cell.column;
         mat_edit
             .editor_content
@@ -998,6 +1367,7 @@ impl MatrixEditing {
         left_gutter_width: usize,
         render_buckets: &mut RenderBuckets<'b>,
         rendered_row_height: usize,
+        cursor_style: CursorStyle,
     ) -> usize {
         let vert_align_offset =
             (rendered_row_height - MatrixData::calc_render_height(self.row_count)) / 2;
@@ -1086,13 +1456,14 @@ impl MatrixEditing {
                     && self.editor.is_cursor_shown()
                 {
                     render_buckets.set_color(Layer::Text, 0x000000_FF);
-                    render_buckets.draw_char(
+                    draw_cursor_glyph(
+                        render_buckets,
                         Layer::Text,
                         (self.editor.get_selection().get_cursor_pos().column + left_gutter_width)
                             + render_x
                             + padding_x,
                         dst_y,
-                        '▏',
+                        cursor_style,
                     );
                 }
             }
@@ -1137,7 +1508,7 @@ pub struct EditorObject {
     rendered_h: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Variable {
     pub name: Box<[char]>,
     pub value: Result<CalcResult, ()>,
@@ -1150,6 +1521,7 @@ type Variables = [Option<Variable>];
 pub struct Tokens<'a> {
     tokens: Vec<Token<'a>>,
     shunting_output_stack: Vec<ShuntingYardResult>,
+    syntax_errors: Vec<SyntaxError>,
 }
 
 pub enum MouseClickType {
@@ -1190,6 +1562,30 @@ pub struct NoteCalcApp {
     pub render_data: GlobalRenderData,
     // when pressing Ctrl-c without any selection, the result of the current line will be put into this clipboard
     pub clipboard: Option<String>,
+    sum_enabled: bool,
+    internal_scale: u32,
+    snap_tolerance: Option<Decimal>,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+    // currency code and how many of it one USD is worth
+    exchange_rates: Vec<(String, Decimal)>,
+    // the rates are applied on the Units only at the next recalculation after they changed
+    exchange_rates_changed: bool,
+    // name and value
+    external_vars: Vec<(Box<[char]>, Box<[char]>)>,
+    // localized word and the keyword it stands for
+    keyword_aliases: Vec<(Box<[char]>, Box<[char]>)>,
+    // the functions registered by the host
+    native_fns: Vec<NativeFn>,
+    // the rows which were part of a line reference cycle during the last evaluation
+    line_ref_cycle_rows: BitFlag128,
+    // the sum which the lines above the window pass on to it, None if they have no sum
+    above_window_sum: Option<Result<CalcResult, ()>>,
+    // Enter can't create more lines than this, the lines past it are not evaluated
+    line_limit: Option<usize>,
+    // the text searched by `find`, its matches are highlighted while it is not empty
+    find_needle: Vec<char>,
+    find_ignore_case: bool,
 }
 
 pub const EMPTY_FILE_DEFUALT_CONTENT: &str = "\n\n\n\n\n\n\n\n\n\n";
@@ -1197,6 +1593,7 @@ pub const EMPTY_FILE_DEFUALT_CONTENT: &str = "\n\n\n\n\n\n\n\n\n\n";
 impl NoteCalcApp {
     pub fn new(client_width: usize, client_height: usize) -> NoteCalcApp {
         let mut editor_content = EditorContent::new(MAX_EDITOR_WIDTH);
+        editor_content.set_line_lock_check(|data: &LineData| data.locked);
         NoteCalcApp {
             line_reference_chooser: None,
             client_width,
@@ -1217,1447 +1614,2075 @@ impl NoteCalcApp {
                 RIGHT_GUTTER_WIDTH,
             ),
             clipboard: None,
-        }
+            sum_enabled: true,
+            internal_scale: MAX_INTERNAL_SCALE,
+            snap_tolerance: None,
+            rounding_mode: RoundingMode::HalfEven,
+            angle_mode: AngleMode::Rad,
+            exchange_rates: Vec::new(),
+            exchange_rates_changed: false,
+            external_vars: Vec::new(),
+            keyword_aliases: Vec::new(),
+            native_fns: Vec::new(),
+            line_ref_cycle_rows: BitFlag128::empty(),
+            above_window_sum: None,
+            line_limit: None,
+            find_needle: Vec::new(),
+            find_ignore_case: false,
+        }
+    }
+
+    /// When disabled, `sum` is not a reserved name and no accumulation happens.
+    /// The lines already using `sum` keep their results until they are evaluated again,
+    /// e.g. by `reparse_everything`.
+    pub fn set_sum_enabled(&mut self, on: bool) {
+        self.sum_enabled = on;
+    }
+
+    pub fn set_result_format(&mut self, row: usize, format: ResultFormat) {
+        self.editor_content.mut_data(row).result_format = format;
+    }
+
+    /// Picks a result format for the row based on its last result, `results` is the one
+    /// which was passed to `handle_input`, `reparse_everything` etc.
+    /// Bit-mask like integers (e.g. 256 or 255) are rendered in hex, everything else in decimal.
+    pub fn auto_format_row(&mut self, row: usize, results: &Results) {
+        let looks_like_bitmask = match self.get_line_result(results, row).map(|it| &it.typ) {
+            Some(CalcResultType::Number(num)) if num.fract().is_zero() => num
+                .to_u64()
+                .map(|it| {
+                    it >= 0xFF && ((it & it.wrapping_sub(1)) == 0 || (it & it.wrapping_add(1)) == 0)
+                })
+                .unwrap_or(false),
+            _ => false,
+        };
+        self.set_result_format(
+            row,
+            if looks_like_bitmask {
+                ResultFormat::Hex
+            } else {
+                ResultFormat::Dec
+            },
+        );
     }
 
-    pub fn get_selected_text_and_clear_app_clipboard(&mut self) -> Option<String> {
-        // TODO: use fix buffer don't allocate
-        let mut str = String::with_capacity(64);
-        return if let Some(clipboard) = std::mem::replace(&mut self.clipboard, None) {
-            Some(clipboard)
-        } else if let Some(matrix_editing) = &self.matrix_editing {
-            matrix_editing
-                .editor_content
-                .write_selection_into(matrix_editing.editor.get_selection(), &mut str);
-            Some(str)
-        } else if self.editor.get_selection().is_range().is_some() {
-            self.editor_content
-                .write_selection_into(self.editor.get_selection(), &mut str);
-            Some(str)
-        } else {
-            None
+    /// The text of a locked line can't be modified, neither by the input nor by paste, undo,
+    /// replace etc., but new lines can be inserted around it.
+    pub fn set_line_locked(&mut self, row: usize, locked: bool) {
+        self.editor_content.mut_data(row).locked = locked;
+    }
+
+    /// Enter is refused when the document already has `limit` lines, and the lines past it
+    /// (e.g. of a longer loaded text) are shown but not evaluated. There is no limit by default.
+    /// It applies to the lines which are evaluated after it was set, so a lower limit should be
+    /// followed by `reparse_everything`.
+    pub fn set_line_limit(&mut self, limit: Option<usize>) {
+        self.line_limit = limit;
+    }
+
+    /// Whether the document has more lines than the line limit, the rest is not evaluated.
+    pub fn is_truncated(&self) -> bool {
+        self.editor_content.line_count() > self.evaluated_line_count()
+    }
+
+    fn evaluated_line_count(&self) -> usize {
+        let line_count = self.editor_content.line_count();
+        self.line_limit
+            .map_or(line_count, |limit| line_count.min(limit))
+    }
+
+    fn input_would_exceed_line_limit(&self, input: &EditorInputEvent) -> bool {
+        let removes_a_line = match self.editor.get_selection().is_range() {
+            Some((start, end)) => start.row != end.row,
+            None => false,
+        };
+        *input == EditorInputEvent::Enter
+            && !removes_a_line
+            && self
+                .line_limit
+                .map_or(false, |limit| self.editor_content.line_count() >= limit)
+    }
+
+    /// The first line of the `MAX_LINE_COUNT` lines which are evaluated and rendered.
+    /// The window stays where it is as long as the visible lines are inside it, otherwise
+    /// it is centered on them, including the cursor if they fit together.
+    fn calc_window_y(&self) -> usize {
+        // the lines above the window are counted as 1 high in `scroll_y`,
+        // so the first visible line is at `scroll_y` or above it, but not above the window
+        let line_count = self.editor_content.line_count();
+        let max_window_y = line_count.saturating_sub(MAX_LINE_COUNT);
+        let visible_bottom = (self.render_data.scroll_y + self.render_data.client_height)
+            .min(line_count)
+            .saturating_sub(1);
+        let visible_top = self.render_data.scroll_y.min(visible_bottom);
+        let cursor_row = self.editor.get_selection().get_cursor_pos().row;
+        let (top, bottom) = {
+            let top = visible_top.min(cursor_row);
+            let bottom = visible_bottom.max(cursor_row);
+            if bottom - top < MAX_LINE_COUNT {
+                (top, bottom)
+            } else {
+                (visible_top, visible_bottom)
+            }
         };
+        let window_y = self.render_data.window_y();
+        if window_y <= max_window_y && window_y <= top && bottom < window_y + MAX_LINE_COUNT {
+            window_y
+        } else {
+            let margin = (MAX_LINE_COUNT - 1 - (bottom - top).min(MAX_LINE_COUNT - 1)) / 2;
+            top.saturating_sub(margin).min(max_window_y)
+        }
     }
 
-    pub fn set_normalized_content<'b>(
+    /// The data of the lines is indexed relative to the window, so it is reset and
+    /// the lines of the new window, and the ones above it, have to be evaluated again.
+    fn move_window(
         &mut self,
-        mut text: &str,
-        units: &Units,
-        allocator: &'b Bump,
-        tokens: &mut AppTokens<'b>,
+        window_y: usize,
+        tokens: &mut AppTokens,
         results: &mut Results,
         vars: &mut Variables,
         editor_objs: &mut EditorObjects,
-        render_buckets: &mut RenderBuckets<'b>,
     ) {
-        if text.is_empty() {
-            text = EMPTY_FILE_DEFUALT_CONTENT;
+        self.render_data.move_window(window_y);
+        tokens.move_window(window_y);
+        results.move_window(window_y);
+        editor_objs.move_window(window_y);
+        for var in vars[0..MAX_LINE_COUNT].iter_mut() {
+            *var = None;
+        }
+        // the lines above the new window are evaluated again
+        for var in vars[FIRST_CARRIED_VAR_INDEX..].iter_mut() {
+            *var = None;
+        }
+        self.above_window_sum = None;
+        self.line_ref_cycle_rows.clear();
+        self.updated_line_ref_obj_indices.clear();
+        self.editor_objs_referencing_current_line.clear();
+    }
+
+    /// Evaluates the lines `0..end` with scratch storage, a window of `MAX_LINE_COUNT` lines
+    /// at a time, and passes the rows, tokens, results and variables of each window
+    /// to `on_window`. The variables a window defines are carried into the next ones,
+    /// so at the end the carried variables of `vars` are the ones visible from the line `end`,
+    /// and the returned value is the sum it starts with (None if there is nothing to sum).
+    fn evaluate_lines_until(
+        &self,
+        end: usize,
+        units: &Units,
+        vars: &mut Variables,
+        mut on_window: impl FnMut(Range<usize>, &AppTokens, &Results, &Variables),
+    ) -> Option<Result<CalcResult, ()>> {
+        for var in vars[FIRST_CARRIED_VAR_INDEX..].iter_mut() {
+            *var = None;
         }
-        self.editor_content.init_with(text);
-        self.editor.set_cursor_pos_r_c(0, 0);
-        for (i, data) in self.editor_content.data_mut().iter_mut().enumerate() {
-            data.line_id = i + 1;
+        let referenced_line_ids = find_referenced_line_ids_in_text(&self.editor_content);
+        let mut sum_is_null = true;
+        let mut window_y = 0;
+        while window_y < end {
+            let window_end = (window_y + MAX_LINE_COUNT).min(end);
+            let allocator = Bump::new();
+            let mut tokens = AppTokens::new();
+            let mut results = Results::new();
+            tokens.move_window(window_y);
+            results.move_window(window_y);
+            for var in vars[0..MAX_LINE_COUNT].iter_mut() {
+                *var = None;
+            }
+            let line_ref_cycle_rows = find_line_ref_cycles(&self.editor_content, window_y);
+            for editor_y in window_y..window_end {
+                if editor_y >= self.evaluated_line_count() {
+                    break;
+                }
+                let var_index = editor_y - window_y;
+                let y = content_y(editor_y);
+                eval_line(
+                    &self.editor_content,
+                    self.editor_content.get_line_valid_chars(editor_y),
+                    units,
+                    &allocator,
+                    &mut tokens,
+                    &mut results,
+                    &mut *vars,
+                    y,
+                    window_y,
+                    &mut Vec::new(),
+                    self.internal_scale,
+                    self.snap_tolerance,
+                    self.rounding_mode,
+                    self.angle_mode,
+                    &self.keyword_aliases,
+                    &self.native_fns,
+                );
+                if line_ref_cycle_rows.is_true(var_index) {
+                    results[y] = Err(());
+                    set_line_var_to_err(vars, editor_y, var_index, &self.editor_content);
+                }
+                let line = self.editor_content.get_line_valid_chars(editor_y);
+                // headers and empty lines separate the blocks of the sum
+                if line.starts_with(&['#']) || line.iter().all(|ch| ch.is_whitespace()) {
+                    sum_is_null = true;
+                }
+                if let (Ok(Some(result)), Some(sum_var)) =
+                    (&results[y], vars[SUM_VARIABLE_INDEX].as_mut())
+                {
+                    sum_result(sum_var, result, &mut sum_is_null);
+                }
+            }
+            on_window(window_y..window_end, &tokens, &results, vars);
+            carry_vars(
+                vars,
+                window_end - window_y,
+                &referenced_line_ids,
+                &self.editor_content,
+                window_y,
+            );
+            window_y = window_end;
         }
-        self.line_id_generator = self.editor_content.line_count() + 1;
-
-        for r in results.as_mut_slice() {
-            *r = Ok(None)
+        if sum_is_null {
+            None
+        } else {
+            vars[SUM_VARIABLE_INDEX].as_ref().map(|it| it.value.clone())
         }
-        for v in vars.iter_mut() {
-            *v = None;
+    }
+
+    /// Variables for evaluating the document apart from the window,
+    /// only the sum and the external variables are taken from `vars`.
+    fn create_scratch_vars(
+        vars: &Variables,
+    ) -> [Option<Variable>; MAX_LINE_COUNT + 1 + MAX_EXTERNAL_VAR_COUNT + MAX_CARRIED_VAR_COUNT]
+    {
+        let mut scratch_vars = create_vars();
+        scratch_vars[SUM_VARIABLE_INDEX..FIRST_CARRIED_VAR_INDEX]
+            .clone_from_slice(&vars[SUM_VARIABLE_INDEX..FIRST_CARRIED_VAR_INDEX]);
+        return scratch_vars;
+    }
+
+    /// The lines above the window are evaluated at every recalculation which could change
+    /// them, their variables are carried into `vars` and their sum into `above_window_sum`.
+    /// Returns whether they were evaluated, then the lines of the window have to be as well.
+    fn evaluate_lines_above_window(
+        &mut self,
+        input_effect: RowModificationType,
+        window_moved: bool,
+        units: &Units,
+        vars: &mut Variables,
+    ) -> bool {
+        let window_y = self.render_data.window_y();
+        let above_window_modified = match input_effect {
+            RowModificationType::SingleLine(row) => row < window_y,
+            RowModificationType::AllLinesFrom(row) => row < window_y,
+        };
+        if !window_moved && !above_window_modified {
+            return false;
         }
-        vars[SUM_VARIABLE_INDEX] = Some(Variable {
-            name: Box::from(&['s', 'u', 'm'][..]),
-            value: Err(()),
-        });
-        self.render_data.clear();
-        self.editor_objs_referencing_current_line.clear();
-        self.process_and_render_tokens(
-            RowModificationType::AllLinesFrom(0),
-            units,
-            allocator,
-            tokens,
-            results,
-            vars,
-            editor_objs,
-            render_buckets,
-        );
+        let mut scratch_vars = NoteCalcApp::create_scratch_vars(vars);
+        self.above_window_sum =
+            self.evaluate_lines_until(window_y, units, &mut scratch_vars, |_, _, _, _| {});
+        vars[FIRST_CARRIED_VAR_INDEX..]
+            .swap_with_slice(&mut scratch_vars[FIRST_CARRIED_VAR_INDEX..]);
+        return true;
     }
 
-    pub fn calc_full_content_height(gr: &GlobalRenderData, content_len: usize) -> usize {
-        // TODO csak az utolsó sorig iterálj, gr.be asszem letárolom
-        let mut h = 0;
-        for i in 0..content_len.min(MAX_LINE_COUNT) {
-            let editor_y = content_y(i);
-            if gr.is_visible(editor_y) {
-                h += gr.get_rendered_height(editor_y);
-            } else if gr.get_render_y(editor_y).is_some() {
-                h += 1;
+    fn input_would_modify_locked_line(
+        &self,
+        input: &EditorInputEvent,
+        modifiers: InputModifiers,
+    ) -> bool {
+        let selection = self.editor.get_selection();
+        let (start, end) = selection.get_range();
+        let cursor = selection.get_cursor_pos();
+        let is_range = selection.is_range().is_some();
+        let at_line_end = cursor.column == self.editor_content.line_len(cursor.row);
+        let (first_row, last_row) = match input {
+            EditorInputEvent::Char('x') | EditorInputEvent::Char('X') if modifiers.ctrl => {
+                (start.row, end.row)
             }
+            EditorInputEvent::Char(_) if modifiers.ctrl => return false,
+            EditorInputEvent::Char(_) | EditorInputEvent::Tab => (start.row, end.row),
+            // a new line can be started after a locked one
+            EditorInputEvent::Enter if !is_range && at_line_end => return false,
+            EditorInputEvent::Enter => (start.row, end.row),
+            // it would merge the line into the previous one
+            EditorInputEvent::Backspace if !is_range && cursor.column == 0 => {
+                (cursor.row.saturating_sub(1), cursor.row)
+            }
+            // it would merge the next line into this one
+            EditorInputEvent::Del if !is_range && at_line_end => (cursor.row, cursor.row + 1),
+            EditorInputEvent::Backspace | EditorInputEvent::Del => (start.row, end.row),
+            _ => return false,
+        };
+        (first_row..=last_row).any(|row| {
+            row < self.editor_content.line_count() && self.editor_content.get_data(row).locked
+        })
+    }
+
+    /// Injects a variable which is available in every line and overrides the document's definitions.
+    /// It takes effect on the next recalculation (e.g. `recalculate_from_row`).
+    pub fn set_external_var(&mut self, name: &str, value: &str) {
+        let name: Box<[char]> = name.chars().collect::<Vec<_>>().into_boxed_slice();
+        let value: Box<[char]> = value.chars().collect::<Vec<_>>().into_boxed_slice();
+        if let Some(var) = self.external_vars.iter_mut().find(|it| it.0 == name) {
+            var.1 = value;
+        } else if self.external_vars.len() < MAX_EXTERNAL_VAR_COUNT {
+            self.external_vars.push((name, value));
         }
-        h
     }
 
-    pub fn renderr<'b>(
-        editor: &mut Editor,
-        editor_content: &EditorContent<LineData>,
-        units: &Units,
-        matrix_editing: &mut Option<MatrixEditing>,
-        line_reference_chooser: &mut Option<ContentIndex>,
-        render_buckets: &mut RenderBuckets<'b>,
-        result_change_flag: BitFlag128,
-        gr: &mut GlobalRenderData,
-        allocator: &'b Bump,
-        tokens: &AppTokens<'b>,
-        results: &Results,
-        vars: &Variables,
-        editor_objs: &mut EditorObjects,
-        updated_line_ref_obj_indices: &[EditorObjId],
-        editor_objs_referencing_current_line: &mut Vec<EditorObjId>,
-        mouse_hover_type: MouseHoverType,
+    /// Makes `name` callable from the document, `f` gets the evaluated arguments.
+    /// A call with other than `arity` arguments, or for which `f` returns None, is an error.
+    /// Registering a name again replaces the previous function, builtin function names
+    /// can't be registered, and functions defined in the document hide the native ones.
+    /// The lines already calling `name` are evaluated with it only after
+    /// `recalculate_from_row` or `reparse_everything`.
+    pub fn register_function(
+        &mut self,
+        name: &str,
+        arity: usize,
+        f: Box<dyn Fn(&[CalcResult]) -> Option<CalcResult>>,
     ) {
-        gr.longest_visible_editor_line_len = 0;
-        // x, h
-        let mut editor_y_to_render_w: [usize; MAX_LINE_COUNT] = [0; MAX_LINE_COUNT];
-        {
-            let mut r = PerLineRenderData::new();
-            r.render_y = canvas_y(-(gr.scroll_y as isize));
-            gr.clear_editor_y_to_render_y();
-            for line in editor_content.lines().take(MAX_LINE_COUNT) {
-                r.new_line_started();
-                let editor_y = r.editor_y;
-                {
-                    if gr.scroll_y > editor_y.as_usize()
-                        || editor_y.as_usize() >= gr.scroll_y + gr.client_height
-                    {
-                        gr.set_render_y(editor_y, Some(r.render_y));
-                        r.line_render_ended(1);
-                        continue;
-                    } else if editor_y.as_usize() > 0 {
-                        let prev_editor_y = editor_y.sub(1);
+        let name = name.chars().collect::<Vec<_>>().into_boxed_slice();
+        if FnType::value_of(&name).is_some() {
+            return;
+        }
+        self.native_fns.retain(|it| it.name != name);
+        self.native_fns.push(NativeFn { name, arity, f });
+    }
 
-                        if let Some(r_y) = gr.get_render_y(prev_editor_y) {
-                            if r_y.as_isize() + (gr.get_rendered_height(prev_editor_y) as isize)
-                                >= gr.client_height as isize
-                            {
-                                for i in editor_y.as_usize()..MAX_LINE_COUNT {
-                                    gr.set_render_y(content_y(i), Some(r.render_y));
-                                    r.line_render_ended(1);
-                                }
-                                break;
-                            }
-                        }
-                    }
-                }
+    /// Localized words which are evaluated as the given keyword, e.g. `("nach", "in")`
+    /// makes "1 km nach m" a unit conversion. It replaces the previous aliases.
+    /// The aliases are resolved when a line is tokenized, so the already parsed lines
+    /// need `reparse_everything`.
+    pub fn set_keyword_aliases(&mut self, aliases: &[(&str, &str)]) {
+        self.keyword_aliases = aliases
+            .iter()
+            .map(|(alias, keyword)| {
+                (
+                    alias.chars().collect::<Vec<_>>().into_boxed_slice(),
+                    keyword.chars().collect::<Vec<_>>().into_boxed_slice(),
+                )
+            })
+            .collect();
+    }
+
+    /// The number of fractional digits every line result is rounded to before it is stored,
+    /// so line references and variables use the rounded value, e.g. the non-terminating
+    /// quotients of `1/7` chains. Quantities are rounded in their own unit
+    /// (e.g. "1.234 mm" is 1.23 mm with a scale of 2).
+    /// A `scale` above 28 (the default) is clamped to 28, since that is the maximum scale
+    /// of `Decimal`, so larger scales (like 50) can't be represented.
+    /// The results are rounded when their line is evaluated, so the lines evaluated
+    /// before the call keep their scale until e.g. `reparse_everything`.
+    pub fn set_internal_scale(&mut self, scale: u64) {
+        self.internal_scale = scale.min(MAX_INTERNAL_SCALE as u64) as u32;
+    }
+
+    /// Line results which are within `tolerance` to an integer are replaced by that integer,
+    /// e.g. 0.9999999999 -> 1 with a tolerance of 1e-9. It is disabled (None) by default.
+    /// Like the internal scale, it is applied to a result when its line is evaluated.
+    pub fn set_snap_tolerance(&mut self, tolerance: Option<Decimal>) {
+        self.snap_tolerance = tolerance;
+    }
+
+    /// Used by the internal scale rounding of the results and by the `round` function.
+    /// The default is HalfEven.
+    /// The displayed results are rounded to their shown digits with it as well,
+    /// which already happens on the next render, while the stored results are rounded
+    /// with it only when their line is evaluated again, e.g. by `reparse_everything`.
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.rounding_mode = mode;
+        self.render_data.rounding_mode = mode;
+    }
+
+    /// How trigonometric functions interpret arguments without an angle unit,
+    /// e.g. `sin(90)`. The default is Rad.
+    /// The lines are not re-evaluated by the call, `recalculate_from_row(0, ..)` updates
+    /// the trigonometric results of the whole document.
+    pub fn set_angle_mode(&mut self, mode: AngleMode) {
+        self.angle_mode = mode;
+    }
+
+    /// Sets how many units of a currency one USD is worth, e.g. `("EUR", 0.92)`.
+    /// Currencies without a rate (or with a non-positive one) can't be used in calculations.
+    /// The rates get into the units on the next evaluation, which evaluates only the modified
+    /// lines, so `reparse_everything` is needed to update every currency conversion.
+    pub fn set_exchange_rates(&mut self, rates: &[(&str, f64)]) {
+        self.exchange_rates = rates
+            .iter()
+            .filter_map(|(code, rate)| {
+                // through its shortest textual form, so e.g. 0.92 is not 0.9200000000000000399...
+                Decimal::from_str(&rate.to_string())
+                    .ok()
+                    .filter(|it| it.is_sign_positive() && !it.is_zero())
+                    .map(|it| (code.to_string(), it))
+            })
+            .collect();
+        self.exchange_rates_changed = true;
+    }
 
-                let render_y = r.render_y;
-                gr.set_render_y(editor_y, Some(render_y));
-                r.rendered_row_height = gr.get_rendered_height(editor_y);
-                // "- 1" so if it is even, it always appear higher
-                r.vert_align_offset = (r.rendered_row_height - 1) / 2;
+    /// The maximum number of undo steps, None (the default) means unlimited.
+    pub fn set_undo_limit(&mut self, limit: Option<usize>) {
+        self.editor_content.set_undo_limit(limit);
+    }
 
-                highlight_current_line(render_buckets, &r, editor, &gr);
+    pub fn can_undo(&self) -> bool {
+        self.editor_content.can_undo()
+    }
 
-                if let Some(tokens) = &tokens[editor_y] {
-                    // TODO: choose a better name
-                    // it means that either we use the nice token rendering (e.g. for matrix it is the multiline matrix stuff),
-                    // or render simply the backend content (e.g. for matrix it is [1;2;3]
-                    let need_matrix_renderer =
-                        if let Some((first, second)) = editor.get_selection().is_range() {
-                            NOT((first.row..=second.row).contains(&(editor_y.as_usize())))
-                        } else {
-                            true
-                        };
-                    // Todo: refactor the parameters into a struct
-                    render_tokens(
-                        &tokens.tokens,
-                        &mut r,
-                        gr,
-                        render_buckets,
-                        // TODO &mut code smell
-                        &mut editor_objs[editor_y],
-                        editor,
-                        matrix_editing,
-                        vars,
-                        &units,
-                        need_matrix_renderer,
-                        Some(RENDERED_RESULT_PRECISION),
-                    );
-                    // don't highlight refs in the current row as they will be pulsing in different colors
-                    if editor.get_selection().get_cursor_pos().row != r.editor_y.as_usize() {
-                        highlight_line_ref_background(
-                            &editor_objs[editor_y],
-                            render_buckets,
-                            &r,
-                            gr,
-                        );
-                    } else {
-                        highlight_active_line_refs(&editor_objs[editor_y], render_buckets, &r, gr);
-                    }
-                } else {
-                    r.rendered_row_height = 1;
-                    render_simple_text_line(line, &mut r, gr, render_buckets, allocator);
-                }
+    pub fn can_redo(&self) -> bool {
+        self.editor_content.can_redo()
+    }
 
-                editor_y_to_render_w[r.editor_y.as_usize()] = r.render_x;
+    /// Bar is the default, Block draws a filled rectangle behind the character under the cursor.
+    pub fn set_cursor_style(&mut self, style: CursorStyle) {
+        self.render_data.cursor_style = style;
+    }
 
-                draw_line_ref_chooser(
-                    render_buckets,
-                    &r,
-                    &gr,
-                    &line_reference_chooser,
-                    gr.result_gutter_x,
-                );
+    /// Lines longer than the editor continue on the next rows instead of being cut with '…'.
+    /// Lines with matrices or line references are not wrapped.
+    pub fn set_soft_wrap(&mut self, on: bool) {
+        self.render_data.soft_wrap = on;
+    }
 
-                draw_cursor(render_buckets, &r, &gr, &editor, &matrix_editing);
+    /// Renders `*` as `×` and `/` as `÷`, the content of the editor is not changed.
+    pub fn set_pretty_operators(&mut self, on: bool) {
+        self.render_data.pretty_operators = on;
+    }
 
-                draw_right_gutter_num_prefixes(
-                    render_buckets,
-                    gr.result_gutter_x,
-                    &editor_content,
-                    &r,
-                );
-                // result gutter
-                render_buckets.set_color(Layer::AboveText, 0xD2D2D2_FF);
-                render_buckets.draw_rect(
-                    Layer::AboveText,
-                    gr.result_gutter_x,
-                    render_y,
-                    RIGHT_GUTTER_WIDTH,
-                    r.rendered_row_height,
-                );
+    /// Lines longer than the editor are scrolled horizontally so that the cursor remains
+    /// visible. Soft wrapping takes precedence over it.
+    pub fn set_horizontal_scroll(&mut self, on: bool) {
+        self.render_data.horizontal_scroll = on;
+    }
 
-                render_wrap_dots(render_buckets, &r, &gr);
+    /// Consecutive lines with inline comments are padded so that their `//` parts line up.
+    pub fn set_align_comments(&mut self, on: bool) {
+        self.render_data.align_comments = on;
+    }
 
-                // line number
-                {
-                    render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
-                    render_buckets.draw_rect(
-                        Layer::BehindText,
-                        0,
-                        render_y,
-                        gr.left_gutter_width,
-                        r.rendered_row_height,
-                    );
-                    if editor_y.as_usize() == editor.get_selection().get_cursor_pos().row {
-                        render_buckets.set_color(Layer::Text, 0x000000_FF);
-                    } else {
-                        render_buckets.set_color(Layer::Text, 0xADADAD_FF);
-                    }
-                    let vert_align_offset = (r.rendered_row_height - 1) / 2;
-                    let line_num_str = if r.editor_y.as_usize() < 9 {
-                        &(LINE_NUM_CONSTS[editor_y.as_usize()][..])
-                    } else if r.editor_y.as_usize() < 99 {
-                        &(LINE_NUM_CONSTS2[(editor_y.as_usize()) - 9][..])
-                    } else {
-                        &(LINE_NUM_CONSTS3[(editor_y.as_usize()) - 99][..])
-                    };
-                    render_buckets.draw_text(
-                        Layer::Text,
-                        0,
-                        render_y.add(vert_align_offset),
-                        line_num_str,
-                    );
-                }
-                // } else if redraw_result_area.need(editor_y) {
-                draw_right_gutter_num_prefixes(
-                    render_buckets,
-                    gr.result_gutter_x,
-                    &editor_content,
-                    &r,
-                );
-                // result background
-                render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
-                render_buckets.draw_rect(
-                    Layer::BehindText,
-                    gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
-                    render_y,
-                    gr.current_result_panel_width,
-                    r.rendered_row_height,
-                );
-                // result gutter
-                render_buckets.set_color(Layer::BehindText, 0xD2D2D2_FF);
-                render_buckets.draw_rect(
-                    Layer::BehindText,
-                    gr.result_gutter_x,
-                    render_y,
-                    RIGHT_GUTTER_WIDTH,
-                    r.rendered_row_height,
-                );
-                r.line_render_ended(gr.get_rendered_height(editor_y));
-                gr.longest_visible_editor_line_len =
-                    gr.longest_visible_editor_line_len.max(r.render_x);
-            }
+    /// When a multiline selection contains headers or `--` separator lines,
+    /// only the lines after the last one are summed.
+    pub fn set_selection_sum_respects_resets(&mut self, on: bool) {
+        self.render_data.selection_sum_respects_resets = on;
+    }
 
-            #[cfg(debug_assertions)]
-            {
-                let chars = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
-                for i in 0..(gr.left_gutter_width
-                    + (gr.current_editor_width + 1)
-                    + RIGHT_GUTTER_WIDTH
-                    + gr.current_result_panel_width)
-                {
-                    let d = i + 1;
-                    render_buckets.draw_char(Layer::AboveText, i, r.render_y, chars[d % 10]);
-                    if d % 10 == 0 && d < 100 {
-                        render_buckets.draw_char(
-                            Layer::AboveText,
-                            i,
-                            r.render_y.add(1),
-                            chars[d / 10],
-                        );
-                    }
-                }
-            }
+    /// The average of the results of a multiline selection is shown below their sum.
+    /// Lines without result (e.g. empty lines and comments) are not counted.
+    pub fn set_selection_average(&mut self, on: bool) {
+        self.render_data.selection_average = on;
+    }
+
+    /// Drawn right after the expression of every line which has a result (e.g. "=" or "→").
+    /// It is empty by default, since the results are in their own panel.
+    pub fn set_result_separator(&mut self, sep: String) {
+        self.render_data.result_separator = sep;
+    }
+
+    /// Draws a faint line at the bottom of every result row in the result panel.
+    pub fn set_result_row_separators(&mut self, on: bool) {
+        self.render_data.result_row_separators = on;
+    }
+
+    pub fn set_assignment_display(&mut self, mode: AssignmentDisplay) {
+        self.render_data.assignment_display = mode;
+    }
+
+    /// Shortcut for `set_assignment_display`, the results of `distance = 5 km` is "distance: 5 km".
+    /// Turning it off reverts to `Value` only if the labels are on, other modes are kept.
+    pub fn set_label_results_with_var_name(&mut self, on: bool) {
+        if on {
+            self.render_data.assignment_display = AssignmentDisplay::Labeled;
+        } else if self.render_data.assignment_display == AssignmentDisplay::Labeled {
+            self.render_data.assignment_display = AssignmentDisplay::Value;
         }
+    }
 
-        draw_line_refs_and_vars_referenced_from_cur_row(
-            &editor_objs[content_y(editor.get_selection().get_cursor_pos().row)],
-            gr,
-            render_buckets,
-            &editor_y_to_render_w,
-        );
+    /// How the integer part of the decimal results is grouped in the result panel, the default is Space.
+    /// None turns off the grouping, the hex and binary results are always grouped by spaces.
+    pub fn set_thousands_separator(&mut self, separator: Option<ThousandsSeparator>) {
+        self.render_data.thousands_separator = separator;
+    }
 
-        NoteCalcApp::fill_editor_objs_referencing_current_line(
-            content_y(editor.get_selection().get_cursor_pos().row),
-            tokens,
-            vars,
-            editor_objs_referencing_current_line,
-            editor_content,
-        );
+    /// Renders the integer exponents of the units in the result panel as superscripts (e.g. "m²").
+    pub fn set_superscript_unit_exponents(&mut self, enabled: bool) {
+        self.render_data.superscript_unit_exponents = enabled;
+    }
 
-        render_buckets
-            .clear_commands
-            .push(OutputMessage::SetColor(0xFFFFFF_FF));
+    /// Results with infinitely repeating digits are rendered like "0.(3)" for 1/3.
+    pub fn set_repeating_decimal_notation(&mut self, on: bool) {
+        self.render_data.repeating_decimal_notation = on;
+    }
 
-        // clear the whole scrollbar area
-        render_buckets
-            .clear_commands
-            .push(OutputMessage::RenderRectangle {
-                x: gr.result_gutter_x - SCROLLBAR_WIDTH,
-                y: canvas_y(0),
-                w: SCROLLBAR_WIDTH,
-                h: gr.client_height,
-            });
+    /// Numbers whose absolute value is at least 10^max_exp or less than 10^-min_exp
+    /// are rendered in scientific notation (e.g. "1.23e11") by the Sci result format.
+    /// The exponents are at most 28.
+    pub fn set_scientific_notation_threshold(&mut self, max_exp: u32, min_exp: u32) {
+        self.render_data.scientific_notation.max_exp = max_exp.min(28) as i32;
+        self.render_data.scientific_notation.min_exp = min_exp.min(28) as i32;
+    }
 
-        // TODO calc it once on content change (scroll_bar_h as well) (it is used in handle_drag)
-        if let Some(scrollbar_info) =
-            NoteCalcApp::get_scrollbar_info(gr, editor_content.line_count())
-        {
-            let color = if mouse_hover_type == MouseHoverType::Scrollbar {
-                SCROLLBAR_HOVER_COLOR
-            } else {
-                SCROLLBAR_NORMAL_COLOR
-            };
-            render_buckets.set_color(Layer::Text, color);
-            render_buckets.draw_rect(
-                Layer::Text,
-                gr.result_gutter_x - SCROLLBAR_WIDTH,
-                canvas_y(scrollbar_info.scroll_bar_render_y as isize),
-                SCROLLBAR_WIDTH,
-                scrollbar_info.scroll_bar_render_h,
-            );
-        }
+    /// The decimal results switch to scientific notation automatically
+    /// above/below the threshold as well, not only the ones with Sci result format.
+    pub fn set_auto_scientific_notation(&mut self, on: bool) {
+        self.render_data.scientific_notation.auto = on;
+    }
 
-        render_selection_and_its_sum(
-            &units,
-            render_buckets,
-            results,
-            &editor,
-            &editor_content,
-            &gr,
-            vars,
-            allocator,
-        );
+    pub fn get_matrix_editing_state(&self) -> Option<MatrixEditState> {
+        let mat_edit = self.matrix_editing.as_ref()?;
+        let mut cell_strings = mat_edit.cell_strings.clone();
+        cell_strings
+            [mat_edit.current_cell.row * mat_edit.col_count + mat_edit.current_cell.column] =
+            mat_edit.editor_content.get_content();
+        Some(MatrixEditState {
+            row_count: mat_edit.row_count,
+            col_count: mat_edit.col_count,
+            current_cell: mat_edit.current_cell,
+            cell_strings,
+        })
+    }
 
-        let mut tmp = ResultRender::new(SmallVec::with_capacity(MAX_LINE_COUNT));
+    /// The next newly created line will get this id, it is exposed only for tests and hosts.
+    #[doc(hidden)]
+    pub fn set_line_id_generator(&mut self, next: usize) {
+        self.line_id_generator = next;
+    }
 
-        render_results_into_buf_and_calc_len(
-            &units,
-            results.as_slice(),
-            &mut tmp,
-            &editor_content,
-            gr,
-            Some(RENDERED_RESULT_PRECISION),
-        );
-        tmp.max_len = create_render_commands_for_results_and_render_matrices(
-            &tmp,
-            units,
-            results.as_slice(),
-            render_buckets,
-            gr,
-            Some(RENDERED_RESULT_PRECISION),
-        )
-        .max(tmp.max_len);
-        gr.longest_visible_result_len = tmp.max_len;
+    #[doc(hidden)]
+    pub fn get_line_id(&self, row: usize) -> usize {
+        self.editor_content.get_data(row).line_id
+    }
 
-        pulse_changed_results(
-            render_buckets,
-            gr,
-            gr.longest_visible_result_len,
-            &result_change_flag,
-        );
+    /// The result of the row from the last evaluation, `results` is the one which was passed to
+    /// `handle_input`, `reparse_everything` etc.
+    /// It is None for rows without result (e.g. comments), for rows with errors and for rows
+    /// outside of the evaluated window (see `MAX_LINE_COUNT`).
+    pub fn get_line_result<'a>(&self, results: &'a Results, row: usize) -> Option<&'a CalcResult> {
+        if row >= self.editor_content.line_count() {
+            return None;
+        }
+        results[content_y(row)].as_ref().ok()?.as_ref()
+    }
 
-        pulse_modified_line_references(
-            render_buckets,
-            gr,
-            updated_line_ref_obj_indices,
-            editor_objs,
-        );
+    /// The names of the built-in functions in alphabetical order, e.g. "sin" or "sqrt".
+    /// The units can be listed with `list_units`.
+    pub fn list_functions(&self) -> Vec<String> {
+        let mut names: Vec<String> = FnType::iter()
+            // the user defined functions have no builtin name
+            .filter(|it| !it.name().is_empty())
+            .map(|it| it.name().iter().collect())
+            .collect();
+        names.sort();
+        names
+    }
 
-        pulse_editor_objs_referencing_current_line(
-            render_buckets,
-            gr,
-            editor_objs_referencing_current_line,
-            editor_objs,
-        );
+    /// The unit names and aliases in alphabetical order, including the commonly
+    /// prefixed forms like "kg" or "km".
+    pub fn list_units(&self, units: &Units) -> Vec<String> {
+        units.list_units()
     }
 
-    pub fn handle_wheel<'b>(
+    /// Completions for the partial word in front of the cursor: the variables defined
+    /// above the cursor first, then the functions and the units.
+    /// `vars` is the one which was passed to `handle_input`, `reparse_everything` etc.
+    pub fn autocomplete(&self, units: &Units, vars: &Variables) -> Vec<String> {
+        let cursor_pos = self.editor.get_selection().get_cursor_pos();
+        let word_start = self.partial_word_start_before_cursor();
+        if word_start == cursor_pos.column {
+            return vec![];
+        }
+        let word: String = self.editor_content.get_line_valid_chars(cursor_pos.row)
+            [word_start..cursor_pos.column]
+            .iter()
+            .collect();
+
+        // the variables are stored only for the lines of the window
+        let var_count = cursor_pos
+            .row
+            .saturating_sub(self.render_data.window_y())
+            .min(MAX_LINE_COUNT);
+        let var_names = vars[0..var_count]
+            .iter()
+            .rev()
+            .chain(vars[SUM_VARIABLE_INDEX..].iter())
+            .filter_map(|var| var.as_ref())
+            // line references are not typed by name
+            .filter(|var| !var.name.starts_with(&['&', '[']))
+            .map(|var| var.name.iter().collect::<String>());
+        let mut candidates: Vec<String> = Vec::new();
+        for name in var_names
+            .chain(self.list_functions().into_iter())
+            .chain(units.list_units().into_iter())
+        {
+            if name.starts_with(&word) && name != word && !candidates.contains(&name) {
+                candidates.push(name);
+            }
+        }
+        candidates
+    }
+
+    /// When enabled, `find` matches "Apple" for "apple" as well.
+    pub fn set_find_ignore_case(&mut self, on: bool) {
+        self.find_ignore_case = on;
+    }
+
+    /// Returns the start positions of `needle` in the editor content, and highlights them
+    /// on the next render until `clear_find` is called.
+    /// Matrices are searched in their textual form (e.g. "[1,2]").
+    pub fn find(&mut self, needle: &str) -> Vec<Pos> {
+        self.find_needle = needle.chars().collect();
+        self.find_matches()
+    }
+
+    pub fn clear_find(&mut self) {
+        self.find_needle.clear();
+    }
+
+    /// Selects the first match after the cursor, wrapping around at the end of the content.
+    pub fn find_next(&mut self) -> Option<Pos> {
+        let cursor_pos = self.editor.get_selection().get_cursor_pos();
+        let matches = self.find_matches();
+        let next = matches
+            .iter()
+            .find(|it| (it.row, it.column) >= (cursor_pos.row, cursor_pos.column))
+            .or(matches.first())
+            .copied();
+        if let Some(pos) = next {
+            self.select_find_match(pos);
+        }
+        next
+    }
+
+    /// Selects the last match before the selection, wrapping around at the start of the content.
+    pub fn find_prev(&mut self) -> Option<Pos> {
+        let first = self.editor.get_selection().get_first();
+        let matches = self.find_matches();
+        let prev = matches
+            .iter()
+            .rev()
+            .find(|it| (it.row, it.column) < (first.row, first.column))
+            .or(matches.last())
+            .copied();
+        if let Some(pos) = prev {
+            self.select_find_match(pos);
+        }
+        prev
+    }
+
+    fn select_find_match(&mut self, pos: Pos) {
+        self.editor.set_selection_save_col(Selection::range(
+            pos,
+            pos.with_column(pos.column + self.find_needle.len()),
+        ));
+    }
+
+    /// Replaces every match of `needle` (see `find`) with `replacement` and returns the number
+    /// of replacements. Matches inside matrices, line references and locked lines are skipped,
+    /// just like the ones overlapping a previous match.
+    /// The replacements can be undone in one step.
+    pub fn replace_all<'b>(
         &mut self,
-        dir: usize,
-        editor_objs: &mut EditorObjects,
+        needle: &str,
+        replacement: &str,
         units: &Units,
         allocator: &'b Bump,
         tokens: &mut AppTokens<'b>,
         results: &mut Results,
         vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
         render_buckets: &mut RenderBuckets<'b>,
-    ) -> bool {
-        let has_moved = if dir == 0 && self.render_data.scroll_y > 0 {
-            self.render_data.scroll_y -= 1;
-            true
-        } else if dir == 1 {
-            let content_height = NoteCalcApp::calc_full_content_height(
-                &self.render_data,
-                self.editor_content.line_count(),
-            );
-            if (self.render_data.scroll_y + self.render_data.client_height) < content_height {
-                self.render_data.scroll_y += 1;
-                true
+    ) -> usize {
+        let needle: Vec<char> = needle.chars().collect();
+        let mut matches: Vec<Pos> = Vec::new();
+        for pos in self.find_matches_of(&needle) {
+            // the search continues after a replaced match, so "aa" is replaced once in "aaa"
+            let overlaps_prev = matches.last().map_or(false, |prev: &Pos| {
+                prev.row == pos.row && pos.column < prev.column + needle.len()
+            });
+            if !overlaps_prev && !is_inside_editor_obj(pos, needle.len(), editor_objs) {
+                matches.push(pos);
+            }
+        }
+        if matches.is_empty() {
+            return 0;
+        }
+        self.editor.start_new_undo_group(&mut self.editor_content);
+        let mut sum_modif: Option<RowModificationType> = None;
+        let mut replaced_count = 0;
+        // backwards, so the positions of the remaining matches are not shifted
+        for pos in matches.iter().rev() {
+            self.editor.set_selection_save_col(Selection::range(
+                *pos,
+                pos.with_column(pos.column + needle.len()),
+            ));
+            let modif = self
+                .editor
+                .insert_text(replacement, &mut self.editor_content);
+            if modif.is_some() {
+                replaced_count += 1;
+            }
+            if let Some(sum_modif) = &mut sum_modif {
+                sum_modif.merge(modif.as_ref());
             } else {
-                false
+                sum_modif = modif;
             }
-        } else {
-            false
-        };
-        if has_moved {
-            self.generate_render_commands_and_fill_editor_objs(
+        }
+        if let Some(modif) = sum_modif {
+            self.process_and_render_tokens(
+                modif,
                 units,
-                render_buckets,
                 allocator,
                 tokens,
                 results,
                 vars,
                 editor_objs,
-                BitFlag128::empty(),
-            );
-            self.set_editor_and_result_panel_widths_and_rerender_if_necessary(
-                units,
                 render_buckets,
-                allocator,
-                tokens,
-                results,
-                vars,
-                editor_objs,
-                BitFlag128::empty(),
             );
         }
-        return has_moved;
+        replaced_count
     }
 
-    pub fn handle_click<'b>(
+    /// Replaces the selected match of `needle` with `replacement` and selects the next match.
+    /// If the selection is not a match, only the next match is selected.
+    /// Returns whether a replacement happened.
+    pub fn replace_next<'b>(
         &mut self,
-        x: usize,
-        clicked_y: CanvasY,
-        editor_objs: &mut EditorObjects,
+        needle: &str,
+        replacement: &str,
         units: &Units,
         allocator: &'b Bump,
         tokens: &mut AppTokens<'b>,
         results: &mut Results,
         vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
         render_buckets: &mut RenderBuckets<'b>,
-    ) {
-        let scroll_bar_x = self.render_data.result_gutter_x - SCROLLBAR_WIDTH;
-        if x < self.render_data.left_gutter_width {
-            // clicked on left gutter
-        } else if x < scroll_bar_x {
-            self.handle_editor_area_click(
-                x,
-                clicked_y,
-                editor_objs,
+    ) -> bool {
+        self.find_needle = needle.chars().collect();
+        let selected_match = self
+            .editor
+            .get_selection()
+            .is_range()
+            .and_then(|(start, end)| {
+                let is_match = start.row == end.row
+                    && end.column - start.column == self.find_needle.len()
+                    && self.find_matches().contains(&start)
+                    && !is_inside_editor_obj(start, self.find_needle.len(), editor_objs);
+                if is_match {
+                    Some(start)
+                } else {
+                    None
+                }
+            });
+        if let Some(start) = selected_match {
+            self.editor.start_new_undo_group(&mut self.editor_content);
+            self.handle_paste(
+                replacement.to_owned(),
                 units,
                 allocator,
                 tokens,
                 results,
                 vars,
+                editor_objs,
                 render_buckets,
             );
-        } else if self.mouse_state.is_none() {
-            self.mouse_state = if x - scroll_bar_x < SCROLLBAR_WIDTH {
-                Some(MouseClickType::ClickedInScrollBar {
-                    original_click_y: clicked_y,
-                    original_scroll_y: self.render_data.scroll_y,
-                })
-            } else if x - self.render_data.result_gutter_x < RIGHT_GUTTER_WIDTH {
-                Some(MouseClickType::RightGutterIsDragged)
+            // continue the search after the replaced text
+            self.editor.set_selection_save_col(Selection::single(
+                start.with_column(start.column + replacement.chars().count()),
+            ));
+        }
+        self.find_next();
+        selected_match.is_some()
+    }
+
+    fn find_matches(&self) -> Vec<Pos> {
+        self.find_matches_of(&self.find_needle)
+    }
+
+    fn find_matches_of(&self, needle: &[char]) -> Vec<Pos> {
+        let mut matches = Vec::new();
+        if needle.is_empty() {
+            return matches;
+        }
+        let chars_eq = |a: &char, b: &char| {
+            if self.find_ignore_case {
+                a.to_lowercase().eq(b.to_lowercase())
             } else {
-                // clicked in result
-                if let Some(editor_y) = self.rendered_y_to_editor_y(clicked_y) {
-                    self.insert_line_ref(
-                        units,
-                        allocator,
-                        tokens,
-                        results,
-                        vars,
-                        editor_y,
-                        editor_objs,
-                        render_buckets,
-                    );
+                a == b
+            }
+        };
+        for row in 0..self.editor_content.line_count() {
+            let line = self.editor_content.get_line_valid_chars(row);
+            if line.len() < needle.len() {
+                continue;
+            }
+            for column in 0..=(line.len() - needle.len()) {
+                if line[column..column + needle.len()]
+                    .iter()
+                    .zip(needle.iter())
+                    .all(|(a, b)| chars_eq(a, b))
+                {
+                    matches.push(Pos::from_row_column(row, column));
                 }
-                None
-            };
+            }
         }
+        matches
     }
 
-    pub fn handle_mouse_up(&mut self) {
-        match self.mouse_state {
-            Some(MouseClickType::RightGutterIsDragged) => {}
-            Some(MouseClickType::ClickedInEditor) => {}
-            Some(MouseClickType::ClickedInScrollBar {
-                original_click_y, ..
-            }) => {
-                let gr = &mut self.render_data;
-                if let Some(scrollbar_render_info) =
-                    NoteCalcApp::get_scrollbar_info(gr, self.editor_content.line_count())
-                {
-                    if original_click_y.as_usize() < scrollbar_render_info.scroll_bar_render_y {
-                        // scroll up
-                        gr.scroll_y -= 1;
-                    } else if original_click_y.as_usize()
-                        > scrollbar_render_info.scroll_bar_render_y
-                            + scrollbar_render_info.scroll_bar_render_h
-                    {
-                        // scroll down
-                        gr.scroll_y += 1;
-                    }
+    /// Puts the LaTeX form of the expression in `row` into the clipboard and returns it,
+    /// e.g. "[1,2] / 3" becomes "\frac{\begin{bmatrix}1 & 2\end{bmatrix}}{3}".
+    pub fn copy_as_latex(&mut self, row: usize, tokens: &AppTokens) -> Option<String> {
+        let tokens = tokens[content_y(row)].as_ref()?;
+        let latex = render_latex(&tokens.tokens, &tokens.shunting_output_stack)?;
+        self.clipboard = Some(latex.clone());
+        Some(latex)
+    }
+
+    /// Returns the character ranges per row which failed to evaluate or parse, so the host
+    /// can draw its own error styling: the tokens flagged by the evaluator
+    /// and the syntax errors recorded by the shunting yard (e.g. the `*` in "2 + * 3").
+    pub fn get_error_spans(&self, tokens: &AppTokens) -> Vec<(usize, Range<usize>)> {
+        let mut spans = vec![];
+        for row in self
+            .render_data
+            .window_rows(self.editor_content.line_count())
+        {
+            let tokens = match &tokens[content_y(row)] {
+                Some(tokens) => tokens,
+                None => continue,
+            };
+            let mut row_spans: Vec<Range<usize>> = tokens
+                .syntax_errors
+                .iter()
+                .map(|it| it.columns.clone())
+                .collect();
+            let mut column = 0;
+            for token in &tokens.tokens {
+                let len = token.ptr.len();
+                if token.has_error() {
+                    row_spans.push(column..column + len);
                 }
+                column += len;
             }
-            None => {}
+            row_spans.sort_by_key(|it| it.start);
+            row_spans.dedup();
+            spans.extend(row_spans.into_iter().map(|it| (row, it)));
         }
-        self.mouse_state = None;
+        spans
     }
 
-    fn handle_editor_area_click<'b>(
+    /// Replaces the partial word in front of the cursor (see `autocomplete`) with `completion`,
+    /// and puts the cursor after it, e.g. "2*me|" -> "2*meter|".
+    pub fn apply_completion<'b>(
         &mut self,
-        x: usize,
-        clicked_y: CanvasY,
-        editor_objs: &mut EditorObjects,
+        completion: &str,
         units: &Units,
         allocator: &'b Bump,
         tokens: &mut AppTokens<'b>,
         results: &mut Results,
         vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
         render_buckets: &mut RenderBuckets<'b>,
     ) {
-        let clicked_x = x - self.render_data.left_gutter_width;
-        let clicked_row = self.get_clicked_row_clamped(clicked_y);
+        let cursor_pos = self.editor.get_selection().get_cursor_pos();
+        let word_start = self.partial_word_start_before_cursor();
+        self.editor.set_selection_save_col(Selection::range(
+            cursor_pos.with_column(word_start),
+            cursor_pos,
+        ));
+        self.handle_paste(
+            completion.to_owned(),
+            units,
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            render_buckets,
+        );
+    }
 
-        let matrix_row_index = if self.matrix_editing.is_some() {
-            let matrix_row_index = self.matrix_editing.as_ref().unwrap().row_index;
-            end_matrix_editing(
-                &mut self.matrix_editing,
-                &mut self.editor,
-                &mut self.editor_content,
-                None,
-            );
-            Some(matrix_row_index)
-        } else {
-            None
-        };
+    fn partial_word_start_before_cursor(&self) -> usize {
+        let cursor_pos = self.editor.get_selection().get_cursor_pos();
+        let line = self.editor_content.get_line_valid_chars(cursor_pos.row);
+        let word_len = line[0..cursor_pos.column]
+            .iter()
+            .rev()
+            .take_while(|it| it.is_alphanumeric() || **it == '_')
+            .count();
+        cursor_pos.column - word_len
+    }
+
+    /// Returns the share of each numeric row in `rows` from their total in percent, e.g.
+    /// 10, 30 and 60 for the rows "10", "30" and "60".
+    /// It is None for the rest of the rows, and for every row if the total is zero.
+    pub fn get_row_percentages(&self, results: &Results, rows: Range<usize>) -> Vec<Option<f64>> {
+        let nums = rows
+            .map(
+                |row| match self.get_line_result(results, row).map(|it| &it.typ) {
+                    Some(CalcResultType::Number(num)) => num.to_f64(),
+                    _ => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        let total: f64 = nums.iter().filter_map(|it| *it).sum();
+        if total == 0.0 {
+            return nums.iter().map(|_| None).collect();
+        }
+        nums.iter()
+            .map(|it| it.map(|num| num * 100.0 / total))
+            .collect()
+    }
 
-        let editor_click_pos = if let Some(editor_obj) =
-            self.get_obj_at(clicked_x, clicked_row, clicked_y, editor_objs)
-        {
-            match editor_obj.typ {
-                EditorObjectType::LineReference { .. } => {
-                    Pos::from_row_column(editor_obj.row.as_usize(), editor_obj.end_x)
-                }
-                EditorObjectType::Matrix {
-                    row_count,
-                    col_count,
-                } => {
-                    self.matrix_editing = Some(MatrixEditing::new(
-                        row_count,
-                        col_count,
-                        &self
-                            .editor_content
-                            .get_line_valid_chars(editor_obj.row.as_usize())
-                            [editor_obj.start_x..editor_obj.end_x],
-                        editor_obj.row,
-                        editor_obj.start_x,
-                        editor_obj.end_x,
-                        Pos::from_row_column(0, 0),
-                    ));
-                    Pos::from_row_column(editor_obj.row.as_usize(), editor_obj.start_x + 1)
-                }
-                EditorObjectType::SimpleTokens | EditorObjectType::Variable { .. } => {
-                    let x_pos_within = clicked_x - editor_obj.rendered_x;
-                    Pos::from_row_column(
-                        editor_obj.row.as_usize(),
-                        editor_obj.start_x + x_pos_within,
-                    )
-                }
+    /// Returns the running total after each row in `rows`, e.g. "10", "40" and "100"
+    /// for the rows "10", "30" and "60".
+    /// It is None for the rows without a result, and for every row after
+    /// a result which could not be added to the total (e.g. 2 m + 3 kg).
+    pub fn get_cumulative_sums(
+        &self,
+        units: &Units,
+        results: &Results,
+        rows: Range<usize>,
+    ) -> Vec<Option<String>> {
+        let mut sum: Option<CalcResult> = None;
+        let mut sum_is_broken = false;
+        rows.map(|row| {
+            let line_result = self.get_line_result(results, row)?;
+            if sum_is_broken {
+                return None;
             }
-        } else {
-            let eol = self.editor_content.line_len(clicked_row.as_usize());
-            Pos::from_row_column(clicked_row.as_usize(), eol)
-        };
+            let new_sum = if let Some(sum) = &sum {
+                add_op(sum, line_result)
+            } else {
+                Some(line_result.clone())
+            };
+            if new_sum.is_none() {
+                sum_is_broken = true;
+            }
+            sum = new_sum;
+            Some(render_result(
+                units,
+                sum.as_ref()?,
+                &self.editor_content.get_data(row).result_format,
+                false,
+                Some(RENDERED_RESULT_PRECISION),
+                true,
+                &self.render_data.scientific_notation,
+                self.render_data.rounding_mode,
+            ))
+        })
+        .collect()
+    }
 
-        self.editor.handle_click(
-            editor_click_pos.column,
-            editor_click_pos.row,
-            &self.editor_content,
+    /// Returns a descriptor for every row. Only the window is evaluated by the recalculations,
+    /// so the whole document is evaluated for it, `vars` is the one which was passed
+    /// to `handle_input`, `reparse_everything` etc.
+    pub fn get_overview(&self, units: &Units, vars: &Variables) -> Vec<RowOverview> {
+        let line_count = self.editor_content.line_count();
+        let longest_line_len = (0..line_count)
+            .map(|row| self.editor_content.line_len(row))
+            .max()
+            .unwrap_or(0);
+        let mut overview = Vec::with_capacity(line_count);
+        let mut scratch_vars = NoteCalcApp::create_scratch_vars(vars);
+        self.evaluate_lines_until(
+            line_count,
+            units,
+            &mut scratch_vars,
+            |rows, _, results, _| {
+                for row in rows {
+                    let line = self.editor_content.get_line_valid_chars(row);
+                    let trimmed = {
+                        let first_non_ws = line.iter().position(|it| !it.is_whitespace());
+                        &line[first_non_ws.unwrap_or(line.len())..]
+                    };
+                    let result = &results[content_y(row)];
+                    overview.push(RowOverview {
+                        has_result: matches!(result, Ok(Some(_))),
+                        is_comment: trimmed.starts_with(&['#']) || trimmed.starts_with(&['/', '/']),
+                        is_error: result.is_err(),
+                        is_matrix: matches!(
+                        result,
+                        Ok(Some(CalcResult {
+                            typ: CalcResultType::Matrix(..),
+                            ..
+                        }))
+                    ),
+                        relative_width: if longest_line_len == 0 {
+                            0.0
+                        } else {
+                            line.len() as f32 / longest_line_len as f32
+                        },
+                    });
+                }
+            },
         );
+        overview
+    }
 
-        self.editor.blink_cursor();
+    /// Returns the result string of the row as it was rendered by the last `render` call.
+    /// It is None for empty results, matrices and rows which were not visible.
+    pub fn get_rendered_result(&self, row: usize) -> Option<&str> {
+        let (start, end) = self.render_data.get_result_range(content_y(row))?;
+        let result_buffer = unsafe { &RESULT_BUFFER };
+        std::str::from_utf8(&result_buffer[start..end]).ok()
+    }
 
-        if self.mouse_state.is_none() {
-            self.mouse_state = Some(MouseClickType::ClickedInEditor);
+    /// Returns the result of the row formatted the same way as in the result panel,
+    /// but independently of the last `render` call. Matrices are rendered in brackets,
+    /// e.g. "[1, 2; 3, 4]".
+    pub fn get_result_string(
+        &self,
+        row: usize,
+        units: &Units,
+        results: &Results,
+        vars: &Variables,
+    ) -> Option<String> {
+        if row >= self.editor_content.line_count() || self.editor_content.get_char(row, 0) == '#' {
+            return None;
+        }
+        let result = match &results[content_y(row)] {
+            Err(..) => return Some("Err".to_owned()),
+            Ok(None) => return None,
+            Ok(Some(result)) => result,
+        };
+        // there are results only inside the window
+        let assigned_var_name = get_assigned_var_name(vars, row - results.window_y());
+        if assigned_var_name.is_some()
+            && self.render_data.assignment_display == AssignmentDisplay::Hide
+        {
+            return None;
         }
+        let mut c = Cursor::new(Vec::with_capacity(64));
+        render_line_result_into(
+            units,
+            result,
+            assigned_var_name,
+            &self.editor_content.get_data(row).result_format,
+            &self.render_data,
+            Some(RENDERED_RESULT_PRECISION),
+            &mut c,
+        );
+        String::from_utf8(c.into_inner()).ok()
+    }
 
-        if let Some(matrix_row_index) = matrix_row_index {
-            self.process_and_render_tokens(
-                RowModificationType::SingleLine(matrix_row_index.as_usize()),
-                units,
-                allocator,
-                tokens,
-                results,
-                vars,
-                editor_objs,
-                render_buckets,
-            );
-        } else {
-            self.generate_render_commands_and_fill_editor_objs(
-                units,
-                render_buckets,
-                allocator,
-                tokens,
-                results,
-                vars,
-                editor_objs,
-                BitFlag128::empty(),
-            );
+    /// Words right after a number which are not units but look like one, e.g. "5 metre".
+    pub fn get_warnings(&self, units: &Units, tokens: &AppTokens) -> Vec<Warning> {
+        let mut warnings = vec![];
+        let window_y = tokens.window_y();
+        for (i, line_tokens) in tokens
+            .iter()
+            .take(self.editor_content.line_count().saturating_sub(window_y))
+            .enumerate()
+        {
+            let row = window_y + i;
+            let line_tokens = if let Some(line_tokens) = line_tokens {
+                &line_tokens.tokens
+            } else {
+                continue;
+            };
+            let mut prev_was_number = false;
+            for token in line_tokens {
+                match token.typ {
+                    TokenType::StringLiteral if token.ptr.iter().all(|it| it.is_whitespace()) => {
+                        continue;
+                    }
+                    TokenType::StringLiteral
+                        if prev_was_number && token.ptr.iter().all(|it| it.is_alphabetic()) =>
+                    {
+                        let name: String = token.ptr.iter().collect();
+                        let suggestions = units.suggest_unit(&name);
+                        if !suggestions.is_empty() {
+                            warnings.push(Warning::UnknownUnit {
+                                row,
+                                name,
+                                suggestions,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+                prev_was_number = matches!(token.typ, TokenType::NumberLiteral(..));
+            }
         }
+        return warnings;
     }
 
-    pub fn rendered_y_to_editor_y(&self, clicked_y: CanvasY) -> Option<ContentIndex> {
-        let editor_y_to_render_y = self.render_data.editor_y_to_render_y();
-        let mut was_visible_row = false;
-        for (ed_y, r_y) in editor_y_to_render_y.iter().enumerate() {
-            if let Some(r_y) = r_y {
-                was_visible_row = true;
-                if *r_y == clicked_y {
-                    return Some(content_y(ed_y));
-                } else if *r_y > clicked_y {
-                    return Some(content_y(ed_y - 1));
+    /// Evaluates a single line without touching the editor or rendering anything.
+    /// Every variable in `vars` is visible for it, so the variables, line references and `sum`
+    /// of the document can be used after they were filled by a recalculation,
+    /// just like the functions defined in the document.
+    pub fn evaluate_line(&self, text: &str, units: &Units, vars: &Variables) -> Option<CalcResult> {
+        let allocator = Bump::new();
+        let text: Vec<char> = text.chars().collect();
+        let mut tokens = Vec::with_capacity(text.len());
+        let mut result = evaluate_text(
+            units,
+            &text,
+            vars,
+            &self.editor_content,
+            &self.native_fns,
+            &mut tokens,
+            self.editor_content.line_count(),
+            self.render_data.window_y(),
+            &allocator,
+            self.rounding_mode,
+            self.angle_mode,
+        )
+        .ok()??;
+        round_result(&mut result.result, self.internal_scale, self.rounding_mode);
+        if let Some(tolerance) = &self.snap_tolerance {
+            snap_result(&mut result.result, tolerance);
+        }
+        Some(result.result)
+    }
+
+    /// One row per line which has a result: line number, expression, value and unit.
+    /// Quantities are written in base units (e.g. 5 km as 5000 and m). Errors and matrices
+    /// have no value, only an "Err" or "matrix" note. The whole document is evaluated for it,
+    /// not only the window, `vars` is the one which was passed to `handle_input` etc.
+    pub fn export_csv(&self, units: &Units, vars: &Variables) -> String {
+        fn push_field(dst: &mut String, field: &str) {
+            if field.contains(|it: char| it == ',' || it == '"' || it == '\n') {
+                dst.push('"');
+                dst.push_str(&field.replace('"', "\"\""));
+                dst.push('"');
+            } else {
+                dst.push_str(field);
+            }
+        }
+        let mut csv = String::from("line,expression,value,unit\n");
+        let mut scratch_vars = NoteCalcApp::create_scratch_vars(vars);
+        let line_count = self.editor_content.line_count();
+        self.evaluate_lines_until(
+            line_count,
+            units,
+            &mut scratch_vars,
+            |rows, _, results, _| {
+                for row in rows {
+                    let (value, unit) = match &results[content_y(row)] {
+                        Ok(None) => continue,
+                        Err(()) => ("Err".to_owned(), String::new()),
+                        Ok(Some(result)) => match &result.typ {
+                            CalcResultType::Number(num) => {
+                                (num.normalize().to_string(), String::new())
+                            }
+                            CalcResultType::Percentage(num) => {
+                                (num.normalize().to_string(), "%".to_owned())
+                            }
+                            CalcResultType::Quantity(num, unit) => {
+                                (num.normalize().to_string(), unit.base_unit_string())
+                            }
+                            CalcResultType::Unit(unit) => (String::new(), unit.base_unit_string()),
+                            CalcResultType::Matrix(..) => ("matrix".to_owned(), String::new()),
+                        },
+                    };
+                    csv.push_str(&(row + 1).to_string());
+                    csv.push(',');
+                    let line: String = self
+                        .editor_content
+                        .get_line_valid_chars(row)
+                        .iter()
+                        .collect();
+                    push_field(&mut csv, &line);
+                    csv.push(',');
+                    push_field(&mut csv, &value);
+                    csv.push(',');
+                    push_field(&mut csv, &unit);
+                    csv.push('\n');
+                }
+            },
+        );
+        return csv;
+    }
+
+    /// The document as an HTML table for pasting it into rich text editors. Every line is a row
+    /// with the highlighted expression and its result, matrices are nested tables.
+    /// The whole document is evaluated for it, not only the window, `vars` is the one
+    /// which was passed to `handle_input` etc.
+    pub fn export_html(&self, units: &Units, vars: &Variables) -> String {
+        fn push_escaped(dst: &mut String, text: impl Iterator<Item = char>) {
+            for ch in text {
+                match ch {
+                    '&' => dst.push_str("&amp;"),
+                    '<' => dst.push_str("&lt;"),
+                    '>' => dst.push_str("&gt;"),
+                    '"' => dst.push_str("&quot;"),
+                    _ => dst.push(ch),
                 }
-            } else if was_visible_row {
-                return Some(content_y(ed_y - 1));
             }
         }
-        return None;
+        fn push_colored(dst: &mut String, color: u32, text: impl Iterator<Item = char>) {
+            dst.push_str(&format!("<span style=\"color: #{:06X}\">", color >> 8));
+            push_escaped(dst, text);
+            dst.push_str("</span>");
+        }
+        const MATRIX_TABLE: &str = "<table style=\"display: inline-table\"><tr><td>";
+
+        let mut html = String::from("<table style=\"font-family: monospace\">\n");
+        let mut scratch_vars = NoteCalcApp::create_scratch_vars(vars);
+        let line_count = self.editor_content.line_count();
+        self.evaluate_lines_until(
+            line_count,
+            units,
+            &mut scratch_vars,
+            |rows, tokens, results, vars| {
+                for row in rows {
+                    // the class distinguishes the lines from the rows of the rendered matrices
+                    html.push_str("<tr class=\"line\"><td>");
+                    if let Some(tokens) = &tokens[content_y(row)] {
+                        let mut inside_matrix = false;
+                        for token in &tokens.tokens {
+                            let color = match &token.typ {
+                                TokenType::Operator(OperatorTokenType::BracketOpen) => {
+                                    inside_matrix = true;
+                                    html.push_str(MATRIX_TABLE);
+                                    continue;
+                                }
+                                TokenType::Operator(OperatorTokenType::Comma) if inside_matrix => {
+                                    html.push_str("</td><td>");
+                                    continue;
+                                }
+                                TokenType::Operator(OperatorTokenType::Semicolon)
+                                    if inside_matrix =>
+                                {
+                                    html.push_str("</td></tr><tr><td>");
+                                    continue;
+                                }
+                                TokenType::Operator(OperatorTokenType::BracketClose)
+                                    if inside_matrix =>
+                                {
+                                    inside_matrix = false;
+                                    html.push_str("</td></tr></table>");
+                                    continue;
+                                }
+                                TokenType::StringLiteral
+                                    if token.ptr.iter().all(|it| it.is_whitespace()) =>
+                                {
+                                    push_escaped(&mut html, token.ptr.iter().copied());
+                                    continue;
+                                }
+                                _ if token.has_error() => COLOR_NUMBER_ERROR,
+                                TokenType::StringLiteral => COLOR_TEXT,
+                                TokenType::Header => COLOR_HEADER,
+                                TokenType::Variable { .. }
+                                | TokenType::LineReference { .. }
+                                | TokenType::BoundVariable { .. } => COLOR_VARIABLE,
+                                TokenType::NumberLiteral(_) => COLOR_NUMBER,
+                                TokenType::NumberErr => COLOR_NUMBER_ERROR,
+                                TokenType::Operator(OperatorTokenType::ApplyUnit(_))
+                                | TokenType::Unit(_) => COLOR_UNIT,
+                                TokenType::Operator(_) => COLOR_OPERATOR,
+                            };
+                            push_colored(
+                                &mut html,
+                                color,
+                                get_token_text(token, self.render_data.pretty_operators)
+                                    .iter()
+                                    .copied(),
+                            );
+                        }
+                        if inside_matrix {
+                            html.push_str("</td></tr></table>");
+                        }
+                    } else {
+                        push_escaped(
+                            &mut html,
+                            self.editor_content
+                                .get_line_valid_chars(row)
+                                .iter()
+                                .copied(),
+                        );
+                    }
+                    html.push_str("</td><td style=\"text-align: right\">");
+                    match (
+                        self.get_result_string(row, units, results, vars),
+                        &results[content_y(row)],
+                    ) {
+                        (None, _) => {}
+                        (
+                            Some(..),
+                            Ok(Some(CalcResult {
+                                typ: CalcResultType::Matrix(mat),
+                                ..
+                            })),
+                        ) => {
+                            let format = &self.editor_content.get_data(row).result_format;
+                            html.push_str("<table style=\"display: inline-table\">");
+                            for mat_row in 0..mat.row_count {
+                                html.push_str("<tr>");
+                                for mat_col in 0..mat.col_count {
+                                    html.push_str("<td style=\"text-align: right\">");
+                                    let cell = render_result(
+                                        units,
+                                        mat.cell(mat_row, mat_col),
+                                        format,
+                                        false,
+                                        Some(RENDERED_RESULT_PRECISION),
+                                        true,
+                                        &self.render_data.scientific_notation,
+                                        self.render_data.rounding_mode,
+                                    );
+                                    push_colored(&mut html, COLOR_RESULTS, cell.chars());
+                                    html.push_str("</td>");
+                                }
+                                html.push_str("</tr>");
+                            }
+                            html.push_str("</table>");
+                        }
+                        (Some(result), _) => push_colored(&mut html, COLOR_RESULTS, result.chars()),
+                    }
+                    html.push_str("</td></tr>\n");
+                }
+            },
+        );
+        html.push_str("</table>\n");
+        return html;
     }
 
-    pub fn get_clicked_row_clamped<'a>(&self, render_y: CanvasY) -> ContentIndex {
-        let latest_bottom_i = self
-            .render_data
-            .calc_bottom_y(self.editor_content.line_count().min(MAX_LINE_COUNT - 1));
-        return if render_y >= latest_bottom_i {
-            content_y(self.editor_content.line_count() - 1)
-        } else if let Some(editor_y) = self.rendered_y_to_editor_y(render_y) {
-            editor_y
-        } else {
-            panic!();
-        };
+    /// Tokenizes every line of `text` without evaluating it and returns the rows with
+    /// a description of their problems, e.g. numbers out of range or unbalanced parentheses.
+    pub fn validate(text: &str, units: &Units) -> Vec<(usize, String)> {
+        let allocator = Bump::new();
+        // nothing is evaluated, so there are no variables
+        let vars = create_vars();
+        let mut errors = vec![];
+        for (row, line) in text.lines().enumerate() {
+            let line: Vec<char> = line.chars().collect();
+            let tokens = parse_tokens(
+                &line,
+                0,
+                0,
+                units,
+                &vars,
+                &allocator,
+                FnNames::default(),
+                &[],
+            );
+            for token in &tokens.tokens {
+                if let TokenType::NumberErr = token.typ {
+                    let number: String = token.ptr.iter().collect();
+                    errors.push((row, format!("invalid number: {}", number)));
+                }
+            }
+            for syntax_error in &tokens.syntax_errors {
+                let msg = match syntax_error.kind {
+                    SyntaxErrorKind::MissingOperand => "missing operand",
+                    SyntaxErrorKind::MisplacedComma => "misplaced comma",
+                    SyntaxErrorKind::UnbalancedParenthesis => "unbalanced parenthesis",
+                };
+                errors.push((row, msg.to_owned()));
+            }
+        }
+        return errors;
     }
 
-    pub fn get_obj_at_rendered_pos<'a>(
-        &self,
-        x: usize,
-        render_y: CanvasY,
-        editor_objects: &'a EditorObjects,
-    ) -> Option<&'a EditorObject> {
-        let editor_y = if render_y
-            >= self
-                .render_data
-                .calc_bottom_y(self.editor_content.line_count())
-        {
-            content_y(self.editor_content.line_count() - 1)
-        } else if let Some(editor_y) = self.rendered_y_to_editor_y(render_y) {
-            editor_y
+    pub fn get_selected_text_and_clear_app_clipboard(&mut self) -> Option<String> {
+        // TODO: use fix buffer don't allocate
+        let mut str = String::with_capacity(64);
+        return if let Some(clipboard) = std::mem::replace(&mut self.clipboard, None) {
+            Some(clipboard)
+        } else if let Some(matrix_editing) = &self.matrix_editing {
+            matrix_editing
+                .editor_content
+                .write_selection_into(matrix_editing.editor.get_selection(), &mut str);
+            Some(str)
+        } else if self.editor.get_selection().is_range().is_some() {
+            self.editor_content
+                .write_selection_into(self.editor.get_selection(), &mut str);
+            Some(str)
         } else {
-            return None;
+            None
         };
-        return editor_objects[editor_y].iter().find(|editor_obj| {
-            (editor_obj.rendered_x..editor_obj.rendered_x + editor_obj.rendered_w).contains(&x)
-                && (editor_obj.rendered_y.as_usize()
-                    ..editor_obj.rendered_y.as_usize() + editor_obj.rendered_h)
-                    .contains(&render_y.as_usize())
-        });
     }
 
-    pub fn get_obj_at<'a>(
+    /// Evaluates `other` in a scratch app and returns the rows whose results differ
+    /// from the current document's as (row index, current result, other result).
+    /// Both documents are evaluated as a whole, `vars` is the one which was passed
+    /// to `handle_input` etc.
+    pub fn diff_results(
         &self,
-        x: usize,
-        editor_y: ContentIndex,
-        render_y: CanvasY,
-        editor_objects: &'a EditorObjects,
-    ) -> Option<&'a EditorObject> {
-        return editor_objects[editor_y].iter().find(|editor_obj| {
-            (editor_obj.rendered_x..editor_obj.rendered_x + editor_obj.rendered_w).contains(&x)
-                && (editor_obj.rendered_y.as_usize()
-                    ..editor_obj.rendered_y.as_usize() + editor_obj.rendered_h)
-                    .contains(&render_y.as_usize())
-        });
+        other: &str,
+        units: &Units,
+        vars: &Variables,
+    ) -> Vec<(usize, String, String)> {
+        let allocator = Bump::with_capacity(MAX_LINE_COUNT * 120);
+        let mut scratch_app = NoteCalcApp::new(self.client_width, self.render_data.client_height);
+        let mut scratch_tokens = AppTokens::new();
+        let mut scratch_results = Results::new();
+        let mut scratch_vars = create_vars();
+        let mut scratch_editor_objs = EditorObjects::new();
+        let mut scratch_render_buckets = RenderBuckets::new();
+        scratch_app.set_normalized_content(
+            other,
+            units,
+            &allocator,
+            &mut scratch_tokens,
+            &mut scratch_results,
+            &mut scratch_vars,
+            &mut scratch_editor_objs,
+            &mut scratch_render_buckets,
+        );
+
+        let this_results = self.render_all_line_results(units, vars);
+        let other_results = scratch_app.render_all_line_results(units, &scratch_vars);
+        let line_count = this_results.len().max(other_results.len());
+        let mut diff = Vec::new();
+        for i in 0..line_count {
+            let this_result = this_results.get(i).cloned().unwrap_or_default();
+            let other_result = other_results.get(i).cloned().unwrap_or_default();
+            if this_result != other_result {
+                diff.push((i, this_result, other_result));
+            }
+        }
+        return diff;
     }
 
-    pub fn get_obj_at_inside<'a>(
-        &self,
-        x: usize,
-        editor_y: ContentIndex,
-        editor_objects: &'a EditorObjects,
-    ) -> Option<&'a EditorObject> {
-        return editor_objects[editor_y]
-            .iter()
-            .find(|editor_obj| (editor_obj.start_x + 1..editor_obj.end_x).contains(&x));
+    /// The result of every line as it is rendered in the result panel,
+    /// the whole document is evaluated for it.
+    fn render_all_line_results(&self, units: &Units, vars: &Variables) -> Vec<String> {
+        let line_count = self.editor_content.line_count();
+        let mut rendered_results = Vec::with_capacity(line_count);
+        let mut scratch_vars = NoteCalcApp::create_scratch_vars(vars);
+        self.evaluate_lines_until(
+            line_count,
+            units,
+            &mut scratch_vars,
+            |rows, _, results, _| {
+                for row in rows {
+                    rendered_results.push(render_line_result(
+                        units,
+                        &results[content_y(row)],
+                        &self.editor_content.get_data(row).result_format,
+                        &self.render_data.scientific_notation,
+                        self.render_data.rounding_mode,
+                    ));
+                }
+            },
+        );
+        rendered_results
     }
 
-    pub fn handle_mouse_move<'b>(
+    pub fn set_normalized_content<'b>(
         &mut self,
-        x: usize,
-        _y: CanvasY,
-        editor_objs: &mut EditorObjects,
+        mut text: &str,
         units: &Units,
         allocator: &'b Bump,
-        tokens: &AppTokens<'b>,
-        results: &Results,
-        vars: &Variables,
-        render_buckets: &mut RenderBuckets<'b>,
-    ) -> usize {
-        let scroll_bar_x = self.render_data.result_gutter_x - SCROLLBAR_WIDTH;
-        let new_mouse_state = if x < self.render_data.left_gutter_width {
-            MouseHoverType::Normal
-        } else if x < scroll_bar_x {
-            // editor
-            MouseHoverType::Normal
-        } else if (x as isize - scroll_bar_x as isize) < (SCROLLBAR_WIDTH as isize) {
-            MouseHoverType::Scrollbar
-        } else if (x as isize - self.render_data.result_gutter_x as isize)
-            < (RIGHT_GUTTER_WIDTH as isize)
-        {
-            MouseHoverType::RightGutter
-        } else {
-            // result
-            return MouseHoverType::Result as usize;
-        };
-        if self.mouse_hover_type != new_mouse_state {
-            self.mouse_hover_type = new_mouse_state;
-            self.generate_render_commands_and_fill_editor_objs(
-                units,
-                render_buckets,
-                allocator,
-                tokens,
-                results,
-                vars,
-                editor_objs,
-                BitFlag128::empty(),
-            );
-        }
-        return self.mouse_hover_type as usize;
-    }
-
-    pub fn handle_drag<'b>(
-        &mut self,
-        x: usize,
-        y: CanvasY,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
         editor_objs: &mut EditorObjects,
-        units: &Units,
-        allocator: &'b Bump,
-        tokens: &AppTokens<'b>,
-        results: &Results,
-        vars: &Variables,
         render_buckets: &mut RenderBuckets<'b>,
-    ) -> bool {
-        let need_render = match self.mouse_state {
-            Some(MouseClickType::RightGutterIsDragged) => {
-                let x_bounded = x.max(self.render_data.left_gutter_width + 4);
-                let new_result_panel_width_percent =
-                    (self.client_width - x_bounded) * 100 / self.client_width;
-                self.result_panel_width_percent = new_result_panel_width_percent;
-                set_editor_and_result_panel_widths(
-                    self.client_width,
-                    self.result_panel_width_percent,
-                    &mut self.render_data,
-                );
-                true
-            }
-            Some(MouseClickType::ClickedInEditor) => {
-                if let Some(y) = self.rendered_y_to_editor_y(y) {
-                    self.editor.handle_drag(
-                        (x as isize - self.render_data.left_gutter_width as isize).max(0) as usize,
-                        y.as_usize(),
-                        &self.editor_content,
-                    );
-                    self.editor.blink_cursor();
-                    true
-                } else {
-                    false
-                }
-            }
-            Some(MouseClickType::ClickedInScrollBar {
-                original_click_y,
-                original_scroll_y,
-            }) => {
-                let gr = &mut self.render_data;
-                if let Some(scrollbar_info) =
-                    NoteCalcApp::get_scrollbar_info(gr, self.editor_content.line_count())
-                {
-                    let delta_y = y.as_isize() - original_click_y.as_isize();
-                    gr.scroll_y = ((original_scroll_y as isize + delta_y).max(0) as usize)
-                        .min(scrollbar_info.max_scroll_y);
-
-                    true
-                } else {
-                    false
-                }
+    ) {
+        let (content, result_formats) = split_result_formats(text);
+        text = content;
+        if text.is_empty() {
+            text = EMPTY_FILE_DEFUALT_CONTENT;
+        }
+        self.editor_content.init_with(text);
+        self.editor.set_cursor_pos_r_c(0, 0);
+        for (i, data) in self.editor_content.data_mut().iter_mut().enumerate() {
+            data.line_id = i + 1;
+            data.result_format = ResultFormat::Dec;
+        }
+        for (row, format) in result_formats.unwrap_or_default() {
+            if row < self.editor_content.line_count() {
+                self.editor_content.mut_data(row).result_format = format;
             }
-            None => false,
-        };
-        if need_render {
-            self.generate_render_commands_and_fill_editor_objs(
-                units,
-                render_buckets,
-                allocator,
-                tokens,
-                results,
-                vars,
-                editor_objs,
-                BitFlag128::empty(),
-            );
         }
-        return need_render;
-    }
+        self.line_id_generator = self.editor_content.line_count() + 1;
 
-    fn get_scrollbar_info(
-        gr: &GlobalRenderData,
-        content_len: usize,
-    ) -> Option<ScrollBarRenderInfo> {
-        let content_height = NoteCalcApp::calc_full_content_height(gr, content_len);
-        let max_scroll_y = content_height as isize - gr.client_height as isize;
-        if max_scroll_y > 0 {
-            let max_scroll_y = max_scroll_y as usize;
-            let scroll_bar_h = (gr.client_height as isize - max_scroll_y as isize).max(1) as usize;
-            let scroll_bar_y = if scroll_bar_h > 1 {
-                gr.scroll_y
-            } else {
-                ((gr.scroll_y as f64 / (max_scroll_y + 1) as f64) * gr.client_height as f64)
-                    as usize
-            };
-            Some(ScrollBarRenderInfo {
-                scroll_bar_render_y: scroll_bar_y,
-                scroll_bar_render_h: scroll_bar_h,
-                max_scroll_y,
-            })
-        } else {
-            None
+        for v in vars.iter_mut() {
+            *v = None;
         }
+        self.render_data.clear();
+        // the window of the new content is chosen by the evaluation
+        self.move_window(0, tokens, results, vars, editor_objs);
+        self.process_and_render_tokens(
+            RowModificationType::AllLinesFrom(0),
+            units,
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            render_buckets,
+        );
     }
 
-    pub fn handle_resize<'b>(
+    /// Like `set_normalized_content`, but the unchanged lines at the beginning and at the end
+    /// of the text keep their data (line id, result format etc), and the cursor stays
+    /// at its logical position.
+    /// If the text contains the result formats line, it overrides the kept result formats.
+    pub fn replace_content_preserving_state<'b>(
         &mut self,
-        new_client_width: usize,
-        editor_objs: &mut EditorObjects,
+        mut text: &str,
         units: &Units,
         allocator: &'b Bump,
         tokens: &mut AppTokens<'b>,
         results: &mut Results,
         vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
         render_buckets: &mut RenderBuckets<'b>,
     ) {
-        if new_client_width
-            < LEFT_GUTTER_MIN_WIDTH + RIGHT_GUTTER_WIDTH + MIN_RESULT_PANEL_WIDTH + SCROLLBAR_WIDTH
-        {
-            return;
+        let (content, result_formats) = split_result_formats(text);
+        text = content;
+        if text.is_empty() {
+            text = EMPTY_FILE_DEFUALT_CONTENT;
         }
-        self.client_width = new_client_width;
-        set_editor_and_result_panel_widths(
-            new_client_width,
-            self.result_panel_width_percent,
-            &mut self.render_data,
-        );
-        self.generate_render_commands_and_fill_editor_objs(
+        let old_lines = self
+            .editor_content
+            .lines()
+            .map(|it| it.to_vec())
+            .collect::<Vec<_>>();
+        let old_data = self.editor_content.data()[0..old_lines.len()].to_vec();
+        let old_cursor = self.editor.get_selection().get_cursor_pos();
+
+        self.editor_content.init_with(text);
+        let old_len = old_lines.len();
+        let new_len = self.editor_content.line_count();
+        let common_prefix_len = (0..old_len.min(new_len))
+            .take_while(|i| old_lines[*i] == self.editor_content.get_line_valid_chars(*i))
+            .count();
+        let common_suffix_len = (0..old_len.min(new_len) - common_prefix_len)
+            .take_while(|i| {
+                old_lines[old_len - 1 - i]
+                    == self.editor_content.get_line_valid_chars(new_len - 1 - i)
+            })
+            .count();
+        let changed_end = new_len - common_suffix_len;
+        for row in 0..new_len {
+            let data = if row < common_prefix_len {
+                old_data[row].clone()
+            } else if row >= changed_end {
+                old_data[row + old_len - new_len].clone()
+            } else {
+                self.line_id_generator += 1;
+                LineData {
+                    line_id: self.line_id_generator - 1,
+                    ..Default::default()
+                }
+            };
+            *self.editor_content.mut_data(row) = data;
+        }
+        if let Some(result_formats) = result_formats {
+            for row in 0..new_len {
+                self.editor_content.mut_data(row).result_format = ResultFormat::Dec;
+            }
+            for (row, format) in result_formats {
+                if row < new_len {
+                    self.editor_content.mut_data(row).result_format = format;
+                }
+            }
+        }
+
+        let new_cursor_row = if old_cursor.row < common_prefix_len {
+            old_cursor.row
+        } else if old_cursor.row >= old_len - common_suffix_len {
+            old_cursor.row + new_len - old_len
+        } else if changed_end > common_prefix_len {
+            old_cursor.row.min(changed_end - 1)
+        } else {
+            common_prefix_len.min(new_len - 1)
+        };
+        let new_cursor_col = old_cursor
+            .column
+            .min(self.editor_content.line_len(new_cursor_row));
+        self.editor
+            .set_cursor_pos_r_c(new_cursor_row, new_cursor_col);
+
+        for v in vars.iter_mut() {
+            *v = None;
+        }
+        self.render_data.clear();
+        // the window of the new content is chosen by the evaluation
+        self.move_window(0, tokens, results, vars, editor_objs);
+        self.process_and_render_tokens(
+            RowModificationType::AllLinesFrom(0),
             units,
-            render_buckets,
             allocator,
             tokens,
             results,
             vars,
             editor_objs,
-            BitFlag128::empty(),
+            render_buckets,
         );
     }
 
-    pub fn handle_time<'b>(
-        &mut self,
-        now: u32,
+    pub fn calc_full_content_height(gr: &GlobalRenderData, content_len: usize) -> usize {
+        // TODO csak az utolsó sorig iterálj, gr.be asszem letárolom
+        let mut h = 0;
+        let window_rows = gr.window_rows(content_len);
+        for i in 0..content_len {
+            let editor_y = content_y(i);
+            if gr.is_visible(editor_y) {
+                h += gr.get_rendered_height(editor_y);
+            } else if gr.get_render_y(editor_y).is_some() || !window_rows.contains(&i) {
+                // the lines outside of the window are rendered in a single row when scrolled into
+                h += 1;
+            }
+        }
+        h
+    }
+
+    pub fn renderr<'b>(
+        editor: &mut Editor,
+        editor_content: &EditorContent<LineData>,
         units: &Units,
+        matrix_editing: &mut Option<MatrixEditing>,
+        line_reference_chooser: &mut Option<ContentIndex>,
+        render_buckets: &mut RenderBuckets<'b>,
+        result_change_flag: BitFlag128,
+        gr: &mut GlobalRenderData,
         allocator: &'b Bump,
-        tokens: &mut AppTokens<'b>,
-        results: &mut Results,
-        vars: &mut Variables,
+        tokens: &AppTokens<'b>,
+        results: &Results,
+        vars: &Variables,
+        native_fns: &[NativeFn],
         editor_objs: &mut EditorObjects,
-        render_buckets: &mut RenderBuckets<'b>,
-    ) -> bool {
-        let need_rerender = if let Some(mat_editor) = &mut self.matrix_editing {
-            mat_editor.editor.handle_tick(now)
+        updated_line_ref_obj_indices: &[EditorObjId],
+        editor_objs_referencing_current_line: &mut Vec<EditorObjId>,
+        mouse_hover_type: MouseHoverType,
+        rounding_mode: RoundingMode,
+        angle_mode: AngleMode,
+        find_matches: &[Pos],
+        find_match_len: usize,
+    ) {
+        gr.longest_visible_editor_line_len = 0;
+        let cursor_column = editor.get_selection().get_cursor_pos().column;
+        if !gr.horizontal_scroll || gr.soft_wrap {
+            gr.scroll_x = 0;
+        } else if cursor_column < gr.scroll_x {
+            gr.scroll_x = cursor_column;
+        } else if cursor_column > gr.scroll_x + gr.current_editor_width {
+            gr.scroll_x = cursor_column - gr.current_editor_width;
+        }
+        // x, h
+        // both of them are indexed relative to the window
+        let window_y = gr.window_y();
+        let mut editor_y_to_render_w: [usize; MAX_LINE_COUNT] = [0; MAX_LINE_COUNT];
+        let comment_render_x = if gr.align_comments {
+            calc_aligned_comment_render_x(editor_content, tokens, vars, units)
         } else {
-            self.editor.handle_tick(now)
+            [0; MAX_LINE_COUNT]
         };
-        if need_rerender {
-            self.generate_render_commands_and_fill_editor_objs(
-                units,
-                render_buckets,
-                allocator,
-                tokens,
-                results,
-                vars,
-                editor_objs,
-                BitFlag128::empty(),
-            );
-        }
-        need_rerender
-    }
+        {
+            let mut r = PerLineRenderData::new();
+            r.editor_y = content_y(window_y);
+            // the lines above the window take one row each, like the ones above the visible area
+            r.render_y = canvas_y(window_y as isize - gr.scroll_y as isize);
+            gr.clear_editor_y_to_render_y();
+            for line in editor_content.lines().skip(window_y).take(MAX_LINE_COUNT) {
+                r.new_line_started();
+                let editor_y = r.editor_y;
+                {
+                    if gr.scroll_y > editor_y.as_usize()
+                        || editor_y.as_usize() >= gr.scroll_y + gr.client_height
+                    {
+                        gr.set_render_y(editor_y, Some(r.render_y));
+                        r.line_render_ended(1);
+                        continue;
+                    } else if editor_y.as_usize() > 0 {
+                        let prev_editor_y = editor_y.sub(1);
 
-    pub fn get_line_ref_normalized_content(&self) -> String {
-        // TODO: no alloc
-        let mut result: String = String::with_capacity(self.editor_content.line_count() * 40);
-        for line in self.editor_content.lines() {
-            let mut i = 0;
-            'i: while i < line.len() {
-                if i + 3 < line.len() && line[i] == '&' && line[i + 1] == '[' {
-                    let mut end = i + 2;
-                    let mut num: u32 = 0;
-                    while end < line.len() {
-                        if line[end] == ']' && num > 0 {
-                            // which row has the id of 'num'?
-                            let referenced_row_index = self
-                                .editor_content
-                                .data()
-                                .iter()
-                                .position(|it| it.line_id == num as usize)
-                                .unwrap_or(0)
-                                + 1; // '+1' line id cannot be 0
-                            result.push('&');
-                            result.push('[');
+                        if let Some(r_y) = gr.get_render_y(prev_editor_y) {
+                            if r_y.as_isize() + (gr.get_rendered_height(prev_editor_y) as isize)
+                                >= gr.client_height as isize
                             {
-                                // TODO: change this code if 64/99/etc line count limit is removed
-                                let mut tmp_arr = ['0', '0', '0'];
-                                let mut tmp_rev_index = 3;
-                                let mut line_id = referenced_row_index;
-                                while line_id > 0 {
-                                    tmp_rev_index -= 1;
-                                    let to_insert = line_id % 10;
-                                    tmp_arr[tmp_rev_index] = (48 + to_insert as u8) as char;
-                                    line_id /= 10;
-                                }
-                                for i in tmp_rev_index..=2 {
-                                    result.push(tmp_arr[i]);
+                                for i in editor_y.as_usize()..window_y + MAX_LINE_COUNT {
+                                    gr.set_render_y(content_y(i), Some(r.render_y));
+                                    r.line_render_ended(1);
                                 }
+                                break;
                             }
-                            result.push(']');
-                            i = end + 1;
-                            continue 'i;
-                        } else if let Some(digit) = line[end].to_digit(10) {
-                            num = if num == 0 { digit } else { num * 10 + digit };
-                        } else {
-                            break;
                         }
-                        end += 1;
                     }
                 }
-                result.push(line[i]);
-                i += 1;
-            }
-            result.push('\n');
-        }
-
-        return result;
-    }
 
-    pub fn normalize_line_refs_in_place(&mut self) {
-        let mut original_selection = self.editor.get_selection();
-        for line_i in 0..self.editor_content.line_count() {
-            let mut i = 0;
-            'i: while i < self.editor_content.line_len(line_i) {
-                //self.editor_content.get_line_valid_chars(line_i)
-                let start = i;
-                if i + 3 < self.editor_content.line_len(line_i)
-                    && self.editor_content.get_char(line_i, i) == '&'
-                    && self.editor_content.get_char(line_i, i + 1) == '['
-                {
-                    let mut end = i + 2;
-                    let mut num_inside_lineref: u32 = 0;
-                    while end < self.editor_content.line_len(line_i) {
-                        if self.editor_content.get_char(line_i, end) == ']'
-                            && num_inside_lineref > 0
-                        {
-                            let num_len = end - (start + 2); // start --> &[num] <- end
+                let render_y = r.render_y;
+                gr.set_render_y(editor_y, Some(render_y));
+                if gr.get_rendered_height(editor_y) == 0 {
+                    // the line has not been processed yet (e.g. a freshly created app),
+                    // render it as a simple text line
+                    gr.set_rendered_height(editor_y, 1);
+                }
+                // nothing can be wrapped into a zero wide editor
+                let is_wrapped = gr.soft_wrap
+                    && gr.current_editor_width > 0
+                    && line.len() > gr.current_editor_width
+                    && tokens[editor_y].as_ref().map_or(false, |it| {
+                        is_wrappable(
+                            editor_y,
+                            &it.tokens,
+                            matrix_editing,
+                            tokens,
+                            results,
+                            vars,
+                            editor_content
+                                .get_data(editor_y.as_usize())
+                                .collapsed_matrices,
+                        )
+                    });
+                if is_wrapped {
+                    let wrapped_row_count =
+                        (line.len() + gr.current_editor_width - 1) / gr.current_editor_width;
+                    gr.set_rendered_height(editor_y, wrapped_row_count);
+                } else if gr.is_wrapped(editor_y) {
+                    // e.g. the editor has become wider
+                    gr.set_rendered_height(editor_y, 1);
+                }
+                gr.set_wrapped(editor_y, is_wrapped);
+                r.rendered_row_height = gr.get_rendered_height(editor_y);
+                // "- 1" so if it is even, it always appear higher
+                r.vert_align_offset = if is_wrapped {
+                    0
+                } else {
+                    (r.rendered_row_height - 1) / 2
+                };
 
-                            // remove the number from the original line_ref text '&[x]' (remove only x)
-                            {
-                                let start_pos = Pos::from_row_column(line_i, start + 2);
-                                let end_pos = start_pos.with_column(end);
-                                self.editor.set_cursor_range(start_pos, end_pos);
-                                self.editor.handle_input(
-                                    EditorInputEvent::Del,
-                                    InputModifiers::none(),
-                                    &mut self.editor_content,
-                                );
-                            }
-                            {
-                                // which row has the id of 'num_inside_lineref'?
-                                let referenced_row_index = self
-                                    .editor_content
-                                    .data()
-                                    .iter()
-                                    .position(|it| it.line_id == num_inside_lineref as usize)
-                                    .unwrap_or(0)
-                                    + 1; // '+1' line id cannot be 0
-                                         // TODO: change this code if 64/99/etc line count limit is removed
-                                let mut tmp_arr = ['0', '0', '0'];
-                                let mut tmp_rev_index = 3;
-                                let mut line_id = referenced_row_index;
-                                while line_id > 0 {
-                                    tmp_rev_index -= 1;
-
-                                    let to_insert = line_id % 10;
-                                    tmp_arr[tmp_rev_index] = (48 + to_insert as u8) as char;
-                                    line_id /= 10;
-                                }
+                highlight_current_line(render_buckets, &r, editor, &gr);
 
-                                i = start + 2;
-                                let mut align_selection = 0;
-                                if line_i == original_selection.start.row
-                                    && original_selection.start.column >= i
-                                {
-                                    original_selection.start.column -= num_len;
-                                    align_selection |= 1;
-                                }
-                                if let Some(end) = original_selection.end {
-                                    if line_i == end.row && end.column >= i {
-                                        original_selection.end.as_mut().expect("must").column -=
-                                            num_len;
-                                        align_selection |= 2;
-                                    }
-                                };
-                                for tmp_arr_i in tmp_rev_index..=2 {
-                                    self.editor.handle_input(
-                                        EditorInputEvent::Char(tmp_arr[tmp_arr_i]),
-                                        InputModifiers::none(),
-                                        &mut self.editor_content,
-                                    );
-                                    i += 1;
-                                    if (align_selection & 1) > 0 {
-                                        original_selection.start.column += 1;
-                                    }
-                                    if (align_selection & 2) > 0 {
-                                        original_selection.end.as_mut().expect("must").column += 1;
-                                    }
-                                }
-                                i += 1; // skip ']'
-                            }
-                            continue 'i;
-                        } else if let Some(digit) =
-                            self.editor_content.get_char(line_i, end).to_digit(10)
-                        {
-                            num_inside_lineref = if num_inside_lineref == 0 {
-                                digit
-                            } else {
-                                num_inside_lineref * 10 + digit
-                            };
+                if let Some(tokens) = tokens[editor_y].as_ref().filter(|_| is_wrapped) {
+                    editor_objs[editor_y].clear();
+                    render_wrapped_tokens(&tokens.tokens, &mut r, gr, render_buckets);
+                } else if let Some(tokens) = &tokens[editor_y] {
+                    // TODO: choose a better name
+                    // it means that either we use the nice token rendering (e.g. for matrix it is the multiline matrix stuff),
+                    // or render simply the backend content (e.g. for matrix it is [1;2;3]
+                    // the rendered matrices and line references are not scrolled horizontally
+                    let need_matrix_renderer = gr.scroll_x == 0
+                        && if let Some((first, second)) = editor.get_selection().is_range() {
+                            NOT((first.row..=second.row).contains(&(editor_y.as_usize())))
                         } else {
-                            break;
-                        }
-                        end += 1;
+                            true
+                        };
+                    // Todo: refactor the parameters into a struct
+                    render_tokens(
+                        &tokens.tokens,
+                        &mut r,
+                        gr,
+                        render_buckets,
+                        // TODO &mut code smell
+                        &mut editor_objs[editor_y],
+                        editor,
+                        matrix_editing,
+                        vars,
+                        &units,
+                        need_matrix_renderer,
+                        Some(RENDERED_RESULT_PRECISION),
+                        comment_render_x[editor_y.as_usize() - window_y],
+                        editor_content
+                            .get_data(editor_y.as_usize())
+                            .collapsed_matrices,
+                    );
+                    // don't highlight refs in the current row as they will be pulsing in different colors
+                    if gr.scroll_x > 0 {
+                        // the highlighted objects are rendered as simple text when scrolled
+                    } else if editor.get_selection().get_cursor_pos().row != r.editor_y.as_usize() {
+                        highlight_line_ref_background(
+                            &editor_objs[editor_y],
+                            render_buckets,
+                            &r,
+                            gr,
+                        );
+                    } else {
+                        highlight_active_line_refs(&editor_objs[editor_y], render_buckets, &r, gr);
                     }
+                } else {
+                    r.rendered_row_height = 1;
+                    render_simple_text_line(line, &mut r, gr, render_buckets, allocator);
                 }
-                i += 1;
-            }
-        }
-        for line_i in 0..self.editor_content.line_count() {
-            self.editor_content.mut_data(line_i).line_id = line_i + 1;
-        }
-        self.line_id_generator = self.editor_content.line_count() + 1;
 
-        self.editor.set_selection_save_col(original_selection);
-    }
+                editor_y_to_render_w[r.editor_y.as_usize() - window_y] = r.render_x;
 
-    pub fn alt_key_released<'b>(
-        &mut self,
-        units: &Units,
-        allocator: &'b Bump,
-        tokens: &mut AppTokens<'b>,
-        results: &mut Results,
-        vars: &mut Variables,
-        editor_objs: &mut EditorObjects,
-        render_buckets: &mut RenderBuckets<'b>,
-    ) {
-        if let Some(line_ref_row) = self.line_reference_chooser {
-            self.line_reference_chooser = None;
-            self.insert_line_ref(
-                units,
-                allocator,
-                tokens,
-                results,
-                vars,
-                line_ref_row,
-                editor_objs,
-                render_buckets,
-            );
-        } else {
-            return;
-        }
-    }
+                draw_line_ref_chooser(
+                    render_buckets,
+                    &r,
+                    &gr,
+                    &line_reference_chooser,
+                    gr.result_gutter_x,
+                );
 
-    fn insert_line_ref<'b>(
-        &mut self,
-        units: &Units,
-        allocator: &'b Bump,
-        tokens: &mut AppTokens<'b>,
-        results: &mut Results,
-        vars: &mut Variables,
-        line_ref_row: ContentIndex,
-        editor_objs: &mut EditorObjects,
-        render_buckets: &mut RenderBuckets<'b>,
-    ) {
-        let cursor_row = self.editor.get_selection().get_cursor_pos().row;
-        if cursor_row == line_ref_row.as_usize()
-            || matches!(&results[line_ref_row], Err(_) | Ok(None))
-        {
-            self.generate_render_commands_and_fill_editor_objs(
-                units,
-                render_buckets,
-                allocator,
-                tokens,
-                results,
-                vars,
-                editor_objs,
-                BitFlag128::empty(),
-            );
-            return;
-        }
-        if let Some(var) = &vars[line_ref_row.as_usize()] {
-            let pos = self.editor.get_selection().get_cursor_pos();
-            if pos.column > 0 {
-                let prev_ch = self.editor_content.get_char(pos.row, pos.column - 1);
-                let prev_token_is_lineref = pos.column > 3 /* smallest lineref is 4 char long '&[1]'*/
-                    && self.editor_content.get_char(pos.row, pos.column - 1) == ']' && {
-                    let mut i = (pos.column - 2) as  isize;
-                    while i > 1 {
-                        if NOT(self.editor_content.get_char(pos.row, i as usize).is_ascii_digit()) {
-                            break;
-                        }
-                        i -= 1;
-                    }
-                    i > 0
-                        && self.editor_content.get_char(pos.row, i as usize) == '['
-                        && self.editor_content.get_char(pos.row, (i-1) as usize) == '&'
-                };
-                if prev_ch.is_alphanumeric() || prev_ch == '_' || prev_token_is_lineref {
-                    self.editor.handle_input(
-                        EditorInputEvent::Char(' '),
-                        InputModifiers::none(),
-                        &mut self.editor_content,
+                draw_cursor(render_buckets, &r, &gr, &editor, &matrix_editing);
+
+                draw_right_gutter_num_prefixes(
+                    render_buckets,
+                    gr.result_gutter_x,
+                    &editor_content,
+                    &r,
+                );
+                // result gutter
+                render_buckets.set_color(Layer::AboveText, 0xD2D2D2_FF);
+                render_buckets.draw_rect(
+                    Layer::AboveText,
+                    gr.result_gutter_x,
+                    render_y,
+                    RIGHT_GUTTER_WIDTH,
+                    r.rendered_row_height,
+                );
+
+                render_wrap_dots(render_buckets, &r, &gr);
+
+                // line number
+                {
+                    render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
+                    render_buckets.draw_rect(
+                        Layer::BehindText,
+                        0,
+                        render_y,
+                        gr.left_gutter_width,
+                        r.rendered_row_height,
                     );
+                    if editor_y.as_usize() == editor.get_selection().get_cursor_pos().row {
+                        render_buckets.set_color(Layer::Text, 0x000000_FF);
+                    } else {
+                        render_buckets.set_color(Layer::Text, 0xADADAD_FF);
+                    }
+                    let vert_align_offset = (r.rendered_row_height - 1) / 2;
+                    let line_num_str = if r.editor_y.as_usize() < 9 {
+                        Some(&(LINE_NUM_CONSTS[editor_y.as_usize()][..]))
+                    } else if r.editor_y.as_usize() < 99 {
+                        Some(&(LINE_NUM_CONSTS2[(editor_y.as_usize()) - 9][..]))
+                    } else if r.editor_y.as_usize() < 99 + LINE_NUM_CONSTS3.len() {
+                        Some(&(LINE_NUM_CONSTS3[(editor_y.as_usize()) - 99][..]))
+                    } else {
+                        None
+                    };
+                    if let Some(line_num_str) = line_num_str {
+                        render_buckets.draw_text(
+                            Layer::Text,
+                            0,
+                            render_y.add(vert_align_offset),
+                            line_num_str,
+                        );
+                    } else {
+                        // there are constants only for the first MAX_LINE_COUNT lines
+                        render_buckets.draw_string(
+                            Layer::Text,
+                            0,
+                            render_y.add(vert_align_offset),
+                            (editor_y.as_usize() + 1).to_string(),
+                        );
+                    }
                 }
-            }
-            for ch in var.name.iter() {
-                self.editor.handle_input(
-                    EditorInputEvent::Char(*ch),
-                    InputModifiers::none(),
-                    &mut self.editor_content,
+                // } else if redraw_result_area.need(editor_y) {
+                draw_right_gutter_num_prefixes(
+                    render_buckets,
+                    gr.result_gutter_x,
+                    &editor_content,
+                    &r,
+                );
+                // result background
+                render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
+                render_buckets.draw_rect(
+                    Layer::BehindText,
+                    gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
+                    render_y,
+                    gr.current_result_panel_width,
+                    r.rendered_row_height,
+                );
+                // result gutter
+                render_buckets.set_color(Layer::BehindText, 0xD2D2D2_FF);
+                render_buckets.draw_rect(
+                    Layer::BehindText,
+                    gr.result_gutter_x,
+                    render_y,
+                    RIGHT_GUTTER_WIDTH,
+                    r.rendered_row_height,
                 );
+                r.line_render_ended(gr.get_rendered_height(editor_y));
+                gr.longest_visible_editor_line_len =
+                    gr.longest_visible_editor_line_len.max(r.render_x);
             }
-        } else {
-            let line_id = {
-                let line_data = self.editor_content.get_data(line_ref_row.as_usize());
-                line_data.line_id
-            };
 
-            let inserting_text = format!("&[{}]", line_id);
-            self.editor
-                .insert_text(&inserting_text, &mut self.editor_content);
+            #[cfg(debug_assertions)]
+            {
+                let chars = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+                for i in 0..(gr.left_gutter_width
+                    + (gr.current_editor_width + 1)
+                    + RIGHT_GUTTER_WIDTH
+                    + gr.current_result_panel_width)
+                {
+                    let d = i + 1;
+                    render_buckets.draw_char(Layer::AboveText, i, r.render_y, chars[d % 10]);
+                    if d % 10 == 0 && d < 100 {
+                        render_buckets.draw_char(
+                            Layer::AboveText,
+                            i,
+                            r.render_y.add(1),
+                            chars[d / 10],
+                        );
+                    }
+                }
+            }
         }
 
-        self.process_and_render_tokens(
-            RowModificationType::SingleLine(cursor_row),
-            units,
-            allocator,
+        draw_line_refs_and_vars_referenced_from_cur_row(
+            &editor_objs[content_y(editor.get_selection().get_cursor_pos().row)],
+            gr,
+            render_buckets,
+            &editor_y_to_render_w,
+        );
+
+        NoteCalcApp::fill_editor_objs_referencing_current_line(
+            content_y(editor.get_selection().get_cursor_pos().row),
             tokens,
+            vars,
+            editor_objs_referencing_current_line,
+            editor_content,
+        );
+
+        render_buckets
+            .clear_commands
+            .push(OutputMessage::SetColor(0xFFFFFF_FF));
+
+        // clear the whole scrollbar area
+        render_buckets
+            .clear_commands
+            .push(OutputMessage::RenderRectangle {
+                x: gr.result_gutter_x - SCROLLBAR_WIDTH,
+                y: canvas_y(0),
+                w: SCROLLBAR_WIDTH,
+                h: gr.client_height,
+            });
+
+        // TODO calc it once on content change (scroll_bar_h as well) (it is used in handle_drag)
+        if let Some(scrollbar_info) =
+            NoteCalcApp::get_scrollbar_info(gr, editor_content.line_count())
+        {
+            let color = if mouse_hover_type == MouseHoverType::Scrollbar {
+                SCROLLBAR_HOVER_COLOR
+            } else {
+                SCROLLBAR_NORMAL_COLOR
+            };
+            render_buckets.set_color(Layer::Text, color);
+            render_buckets.draw_rect(
+                Layer::Text,
+                gr.result_gutter_x - SCROLLBAR_WIDTH,
+                canvas_y(scrollbar_info.scroll_bar_render_y as isize),
+                SCROLLBAR_WIDTH,
+                scrollbar_info.scroll_bar_render_h,
+            );
+        }
+
+        if !find_matches.is_empty() {
+            render_buckets.set_color(Layer::BehindText, FIND_MATCH_BACKGROUND_COLOR);
+        }
+        for find_match in find_matches {
+            draw_range_background(
+                render_buckets,
+                *find_match,
+                find_match.with_column(find_match.column + find_match_len),
+                editor_content,
+                gr,
+            );
+        }
+
+        render_selection_and_its_sum(
+            &units,
+            render_buckets,
             results,
+            &editor,
+            &editor_content,
+            &gr,
+            vars,
+            native_fns,
+            allocator,
+            rounding_mode,
+            angle_mode,
+        );
+
+        let mut tmp = ResultRender::new(SmallVec::with_capacity(MAX_LINE_COUNT));
+
+        render_results_into_buf_and_calc_len(
+            &units,
+            results.as_slice(),
+            &mut tmp,
+            &editor_content,
+            gr,
+            vars,
+            Some(RENDERED_RESULT_PRECISION),
+        );
+        gr.clear_result_ranges();
+        for result_tmp in &tmp.result_ranges {
+            gr.set_result_range(
+                result_tmp.editor_y,
+                result_tmp.buffer_ptr.as_ref().map(|it| (it.start, it.end)),
+            );
+        }
+        tmp.max_len = create_render_commands_for_results_and_render_matrices(
+            &tmp,
+            units,
+            results.as_slice(),
+            render_buckets,
+            editor_content,
+            gr,
             vars,
+            Some(RENDERED_RESULT_PRECISION),
+        )
+        .max(tmp.max_len);
+        gr.longest_visible_result_len = tmp.max_len;
+
+        draw_incomplete_line_hints(render_buckets, gr, tokens, results.as_slice());
+        draw_result_separators(
+            render_buckets,
+            gr,
+            results.as_slice(),
+            &editor_y_to_render_w,
+        );
+        draw_result_row_separators(render_buckets, gr, results.as_slice());
+
+        pulse_changed_results(
+            render_buckets,
+            gr,
+            gr.longest_visible_result_len,
+            &result_change_flag,
+        );
+
+        pulse_modified_line_references(
+            render_buckets,
+            gr,
+            updated_line_ref_obj_indices,
             editor_objs,
+        );
+
+        pulse_editor_objs_referencing_current_line(
             render_buckets,
+            gr,
+            editor_objs_referencing_current_line,
+            editor_objs,
         );
     }
 
-    pub fn handle_paste<'b>(
+    pub fn handle_wheel<'b>(
         &mut self,
-        text: String,
+        dir: usize,
+        editor_objs: &mut EditorObjects,
         units: &Units,
         allocator: &'b Bump,
         tokens: &mut AppTokens<'b>,
         results: &mut Results,
         vars: &mut Variables,
-        editor_objs: &mut EditorObjects,
         render_buckets: &mut RenderBuckets<'b>,
-    ) {
-        let prev_row = self.editor.get_selection().get_cursor_pos().row;
-        match self.editor.insert_text(&text, &mut self.editor_content) {
-            Some(modif) => {
-                if self.editor.get_selection().get_cursor_pos().row >= MAX_LINE_COUNT {
-                    self.editor.set_cursor_pos_r_c(MAX_LINE_COUNT - 1, 0);
-                }
-                let cursor_pos = self.editor.get_selection().get_cursor_pos();
-                let scroll_y =
-                    get_scroll_y_after_cursor_movement(prev_row, cursor_pos.row, &self.render_data);
-                if let Some(scroll_y) = scroll_y {
-                    self.render_data.scroll_y = scroll_y;
-                }
-                self.process_and_render_tokens(
-                    modif,
-                    units,
-                    allocator,
-                    tokens,
-                    results,
-                    vars,
-                    editor_objs,
-                    render_buckets,
-                );
-            }
-            None => {}
-        };
-    }
-
-    pub fn reparse_everything<'b, 'q>(
-        &'q mut self,
-        allocator: &'b Bump,
-        units: &Units,
-        tokens: &mut AppTokens<'b>,
-        results: &mut Results,
-        vars: &mut Variables,
-        editor_objs: &mut EditorObjects,
-        render_buckets: &mut RenderBuckets<'b>,
-    ) {
-        self.process_and_render_tokens(
-            RowModificationType::AllLinesFrom(0),
-            units,
-            allocator,
-            tokens,
-            results,
-            vars,
-            editor_objs,
-            render_buckets,
-        );
-    }
-
-    pub fn handle_input<'b, 'q>(
-        &'q mut self,
-        input: EditorInputEvent,
-        modifiers: InputModifiers,
-        allocator: &'b Bump,
-        units: &Units,
-        tokens: &mut AppTokens<'b>,
-        results: &mut Results,
-        vars: &mut Variables,
-        editor_objs: &mut EditorObjects,
-        render_buckets: &mut RenderBuckets<'b>,
-    ) -> Option<RowModificationType> {
-        fn handle_input_with_alt<'b>(
-            app: &mut NoteCalcApp,
-            input: EditorInputEvent,
-        ) -> Option<RowModificationType> {
-            if input == EditorInputEvent::Left {
-                let selection = app.editor.get_selection();
-                let (start, end) = selection.get_range();
-                for row_i in start.row..=end.row {
-                    let new_format = match &app.editor_content.get_data(row_i).result_format {
-                        ResultFormat::Bin => ResultFormat::Hex,
-                        ResultFormat::Dec => ResultFormat::Bin,
-                        ResultFormat::Hex => ResultFormat::Dec,
-                    };
-                    app.editor_content.mut_data(row_i).result_format = new_format;
-                }
-                None
-            } else if input == EditorInputEvent::Right {
-                let selection = app.editor.get_selection();
-                let (start, end) = selection.get_range();
-                for row_i in start.row..=end.row {
-                    let new_format = match &app.editor_content.get_data(row_i).result_format {
-                        ResultFormat::Bin => ResultFormat::Dec,
-                        ResultFormat::Dec => ResultFormat::Hex,
-                        ResultFormat::Hex => ResultFormat::Bin,
-                    };
-                    app.editor_content.mut_data(row_i).result_format = new_format;
-                }
-                None
-            } else if input == EditorInputEvent::Up {
-                let cur_pos = app.editor.get_selection().get_cursor_pos();
-                let rows = if let Some(selector_row) = app.line_reference_chooser {
-                    if selector_row.as_usize() > 0 {
-                        Some((selector_row.as_usize(), selector_row.as_usize() - 1))
-                    } else {
-                        Some((selector_row.as_usize(), selector_row.as_usize()))
-                    }
-                } else if cur_pos.row > 0 {
-                    Some((cur_pos.row - 1, cur_pos.row - 1))
-                } else {
-                    None
-                };
-                if let Some((_, new_selected_row)) = rows {
-                    app.line_reference_chooser = Some(content_y(new_selected_row));
-                    None
-                } else {
-                    None
-                }
-            } else if input == EditorInputEvent::Down {
-                let cur_pos = app.editor.get_selection().get_cursor_pos();
-                let rows = if let Some(selector_row) = app.line_reference_chooser {
-                    if selector_row.as_usize() < cur_pos.row - 1 {
-                        Some((selector_row.as_usize(), selector_row.as_usize() + 1))
-                    } else {
-                        Some((selector_row.as_usize(), selector_row.as_usize()))
-                    }
-                } else {
-                    None
-                };
-                if let Some((_prev_selected_row, new_selected_row)) = rows {
-                    app.line_reference_chooser = Some(content_y(new_selected_row));
-                    None
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        }
-
-        ////////////////////////////////////////////////////
-        ////////////////////////////////////////////////////
-        ////////////////////////////////////////////////////
-        let prev_row = self.editor.get_selection().get_cursor_pos().row;
-        let modif = if self.matrix_editing.is_none() && modifiers.alt {
-            handle_input_with_alt(&mut *self, input)
-        } else if self.matrix_editing.is_some() {
-            self.handle_matrix_editor_input(input, modifiers);
-            if self.matrix_editing.is_none() {
-                // user left a matrix
-                Some(RowModificationType::SingleLine(prev_row))
+    ) -> bool {
+        let has_moved = if dir == 0 && self.render_data.scroll_y > 0 {
+            self.render_data.scroll_y -= 1;
+            true
+        } else if dir == 1 {
+            let content_height = NoteCalcApp::calc_full_content_height(
+                &self.render_data,
+                self.editor_content.line_count(),
+            );
+            if (self.render_data.scroll_y + self.render_data.client_height) < content_height {
+                self.render_data.scroll_y += 1;
+                true
             } else {
-                if modifiers.alt {
-                    let y = content_y(prev_row);
-                    let new_h =
-                        calc_rendered_height(y, &self.matrix_editing, tokens, results, vars);
-                    self.render_data.set_rendered_height(y, new_h);
-                };
-                None
-            }
-        } else if self.handle_completion(&input, editor_objs, vars) {
-            Some(RowModificationType::SingleLine(prev_row))
-        } else if let Some(modif_type) = self.handle_obj_deletion(&input, editor_objs) {
-            Some(modif_type)
-        } else if input == EditorInputEvent::Char('c')
-            && modifiers.ctrl
-            && self.editor.get_selection().is_range().is_none()
-        {
-            let row = self.editor.get_selection().get_cursor_pos().row;
-            if let Ok(Some(result)) = &results[content_y(row)] {
-                self.clipboard = Some(render_result(
-                    &units,
-                    &result,
-                    &self.editor_content.get_data(row).result_format,
-                    false,
-                    Some(RENDERED_RESULT_PRECISION),
-                    true,
-                ));
+                false
             }
-            None
-        } else if input == EditorInputEvent::Char('b') && modifiers.ctrl {
-            self.handle_jump_to_definition(&input, modifiers, editor_objs);
-            None
-        } else if self.handle_obj_jump_over(&input, modifiers, editor_objs) {
-            None
         } else {
-            let prev_selection = self.editor.get_selection();
-            let prev_cursor_pos = prev_selection.get_cursor_pos();
-
-            // if the cursor is inside a matrix, put it afterwards
-            if let Some(obj) = self.get_obj_at_inside(
-                prev_cursor_pos.column,
-                content_y(prev_cursor_pos.row),
-                editor_objs,
-            ) {
-                match obj.typ {
-                    EditorObjectType::Matrix { .. } => self
-                        .editor
-                        .set_cursor_pos_r_c(obj.row.as_usize(), obj.end_x),
-                    _ => {}
-                }
-            }
-            let modif_type = self
-                .editor
-                .handle_input(input, modifiers, &mut self.editor_content);
-
-            if self.editor.get_selection().get_cursor_pos().row >= MAX_LINE_COUNT {
-                if let Some((start, _end)) = self.editor.get_selection().is_range() {
-                    self.editor.set_selection_save_col(Selection::range(
-                        start,
-                        Pos::from_row_column(MAX_LINE_COUNT - 1, 0),
-                    ));
-                } else {
-                    self.editor
-                        .set_selection_save_col(Selection::single_r_c(MAX_LINE_COUNT - 1, 0));
-                }
-            }
-
-            if modif_type.is_none() {
-                // it is possible to step into a matrix only through navigation
-                self.check_stepping_into_matrix(prev_cursor_pos, editor_objs);
-                // if there was no selection but now there is
-                if prev_selection.is_range().is_none()
-                    && self.editor.get_selection().is_range().is_some()
-                {
-                    self.normalize_line_refs_in_place();
-                    Some(RowModificationType::AllLinesFrom(0))
-                } else {
-                    modif_type
-                }
-            } else {
-                modif_type
-            }
+            false
         };
-
-        let cursor_pos = self.editor.get_selection().get_cursor_pos();
-        let scroll_y =
-            get_scroll_y_after_cursor_movement(prev_row, cursor_pos.row, &self.render_data);
-        if let Some(scroll_y) = scroll_y {
-            self.render_data.scroll_y = scroll_y;
-        }
-
-        if let Some(modif) = modif {
+        if has_moved && self.calc_window_y() != self.render_data.window_y() {
             self.process_and_render_tokens(
-                modif,
+                RowModificationType::AllLinesFrom(0),
                 units,
                 allocator,
                 tokens,
@@ -2666,7 +3691,7 @@ impl NoteCalcApp {
                 editor_objs,
                 render_buckets,
             );
-        } else {
+        } else if has_moved {
             self.generate_render_commands_and_fill_editor_objs(
                 units,
                 render_buckets,
@@ -2688,330 +3713,195 @@ impl NoteCalcApp {
                 BitFlag128::empty(),
             );
         }
-
-        return modif;
+        return has_moved;
     }
 
-    pub fn process_and_render_tokens<'b>(
+    pub fn handle_click<'b>(
         &mut self,
-        input_effect: RowModificationType,
+        x: usize,
+        clicked_y: CanvasY,
+        editor_objs: &mut EditorObjects,
         units: &Units,
         allocator: &'b Bump,
         tokens: &mut AppTokens<'b>,
         results: &mut Results,
         vars: &mut Variables,
-        editor_objs: &mut EditorObjects,
         render_buckets: &mut RenderBuckets<'b>,
     ) {
-        fn eval_line<'a>(
-            editor_content: &EditorContent<LineData>,
-            line: &[char],
-            units: &Units,
-            allocator: &'a Bump,
-            tokens_per_lines: &mut AppTokens<'a>,
-            results: &mut Results,
-            vars: &mut Variables,
-            editor_y: ContentIndex,
-            updated_line_ref_obj_indices: &mut Vec<EditorObjId>,
-        ) -> (bool, BitFlag128) {
-            // TODO avoid clone
-            let prev_var_name = vars[editor_y.as_usize()].as_ref().map(|it| it.name.clone());
-
-            tokens_per_lines[editor_y] = Some(parse_tokens(
-                line,
-                editor_y.as_usize(),
+        let scroll_bar_x = self.render_data.result_gutter_x - SCROLLBAR_WIDTH;
+        if x < self.render_data.left_gutter_width {
+            // clicked on left gutter
+        } else if x < scroll_bar_x {
+            self.handle_editor_area_click(
+                x,
+                clicked_y,
+                editor_objs,
                 units,
-                &*vars,
                 allocator,
-            ));
-            let new_result = if let Some(tokens) = &mut tokens_per_lines[editor_y] {
-                let result = evaluate_tokens_and_save_result(
-                    &mut *vars,
-                    editor_y.as_usize(),
-                    editor_content,
-                    &mut tokens.tokens,
-                    &mut tokens.shunting_output_stack,
-                    editor_content.get_line_valid_chars(editor_y.as_usize()),
-                );
-                let result = result.map(|it| it.map(|it| it.result));
-                result
+                tokens,
+                results,
+                vars,
+                render_buckets,
+            );
+        } else if self.mouse_state.is_none() {
+            self.mouse_state = if x - scroll_bar_x < SCROLLBAR_WIDTH {
+                Some(MouseClickType::ClickedInScrollBar {
+                    original_click_y: clicked_y,
+                    original_scroll_y: self.render_data.scroll_y,
+                })
+            } else if x - self.render_data.result_gutter_x < RIGHT_GUTTER_WIDTH {
+                Some(MouseClickType::RightGutterIsDragged)
             } else {
-                Ok(None)
-            };
-            let vars: &Variables = vars;
-
-            let prev_result = std::mem::replace(&mut results[editor_y], new_result);
-            let result_has_changed = {
-                let new_result = &results[editor_y];
-                match (&prev_result, new_result) {
-                    (Ok(Some(_)), Err(_)) => true,
-                    (Ok(Some(_)), Ok(None)) => true,
-                    (Ok(Some(prev_r)), Ok(Some(new_r))) => prev_r.typ != new_r.typ,
-                    (Err(_), Err(_)) => false,
-                    (Err(_), Ok(None)) => true,
-                    (Err(_), Ok(Some(_))) => true,
-                    (Ok(None), Ok(Some(_))) => true,
-                    (Ok(None), Ok(None)) => false,
-                    (Ok(None), Err(_)) => true,
+                // clicked in result
+                if let Some(editor_y) = self.rendered_y_to_editor_y(clicked_y) {
+                    self.insert_line_ref(
+                        units,
+                        allocator,
+                        tokens,
+                        results,
+                        vars,
+                        editor_y,
+                        editor_objs,
+                        render_buckets,
+                    );
                 }
+                None
             };
+        }
+    }
 
-            let mut rows_to_recalc = BitFlag128::empty();
-            if result_has_changed {
-                let line_ref_name =
-                    NoteCalcApp::get_line_ref_name(&editor_content, editor_y.as_usize());
-                rows_to_recalc.merge(NoteCalcApp::find_line_ref_dependant_lines(
-                    &line_ref_name,
-                    tokens_per_lines,
-                    editor_y.as_usize(),
-                    updated_line_ref_obj_indices,
-                ));
-            }
-
-            let curr_var_name = vars[editor_y.as_usize()].as_ref().map(|it| &it.name);
-            rows_to_recalc.merge(find_lines_that_affected_by_var_change(
-                result_has_changed,
-                curr_var_name,
-                prev_var_name,
-                tokens_per_lines,
-                editor_y.as_usize(),
-            ));
-
-            rows_to_recalc.merge(find_sum_variable_name(
-                tokens_per_lines,
-                editor_y.as_usize(),
-            ));
-            return (result_has_changed, rows_to_recalc);
-        }
-
-        fn find_sum_variable_name(tokens_per_lines: &AppTokens, editor_y: usize) -> BitFlag128 {
-            let mut rows_to_recalc = BitFlag128::empty();
-            'outer: for (line_index, tokens) in
-                tokens_per_lines.iter().skip(editor_y + 1).enumerate()
-            {
-                if let Some(tokens) = tokens {
-                    for token in &tokens.tokens {
-                        match token.typ {
-                            TokenType::Header => {
-                                break 'outer;
-                            }
-                            TokenType::Variable { var_index }
-                                if var_index == SUM_VARIABLE_INDEX =>
-                            {
-                                rows_to_recalc
-                                    .merge(BitFlag128::single_row(editor_y + 1 + line_index));
-                                break 'outer;
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            }
-            return rows_to_recalc;
-        }
-
-        fn find_lines_that_affected_by_var_change<'b>(
-            needs_dependency_check: bool,
-            curr_var_name: Option<&Box<[char]>>,
-            prev_var_name: Option<Box<[char]>>,
-            tokens_per_lines: &AppTokens<'b>,
-            editor_y: usize,
-        ) -> BitFlag128 {
-            let mut rows_to_recalc = BitFlag128::empty();
-            match (prev_var_name, curr_var_name) {
-                (None, Some(var_name)) => {
-                    // nem volt még, de most van
-                    // recalc all the rows which uses this variable name
-                    for (i, tokens) in tokens_per_lines.iter().skip(editor_y + 1).enumerate() {
-                        if let Some(tokens) = tokens {
-                            for token in &tokens.tokens {
-                                match token.typ {
-                                    TokenType::StringLiteral if *token.ptr == **var_name => {
-                                        rows_to_recalc
-                                            .merge(BitFlag128::single_row(editor_y + 1 + i));
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                    }
-                }
-                (Some(old_var_name), None) => {
-                    // volt, de most nincs
-                    // recalc all the rows which uses the old variable name
-                    for (i, tokens) in tokens_per_lines.iter().skip(editor_y + 1).enumerate() {
-                        if let Some(tokens) = tokens {
-                            for token in &tokens.tokens {
-                                match token.typ {
-                                    TokenType::Variable { .. } if *token.ptr == *old_var_name => {
-                                        rows_to_recalc
-                                            .merge(BitFlag128::single_row(editor_y + 1 + i));
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                    }
-                }
-                (Some(old_var_name), Some(var_name)) if old_var_name != *var_name => {
-                    // volt, de most más a neve
-                    for (i, tokens) in tokens_per_lines.iter().skip(editor_y + 1).enumerate() {
-                        if let Some(tokens) = tokens {
-                            for token in &tokens.tokens {
-                                let recalc = match token.typ {
-                                    TokenType::StringLiteral => var_name.starts_with(token.ptr),
-                                    TokenType::Variable { .. } => *token.ptr == *old_var_name,
-                                    _ => false,
-                                };
-                                if recalc {
-                                    rows_to_recalc.merge(BitFlag128::single_row(editor_y + 1 + i));
-                                }
-                            }
-                        }
-                    }
-                }
-                (Some(_old_var_name), Some(var_name)) => {
-                    if !needs_dependency_check {
-                        return BitFlag128::empty();
-                    }
-                    // volt is, van is, a neve is ugyanaz
-                    for (i, tokens) in tokens_per_lines.iter().skip(editor_y + 1).enumerate() {
-                        if let Some(tokens) = tokens {
-                            for token in &tokens.tokens {
-                                let recalc = match token.typ {
-                                    TokenType::Variable { .. } if *token.ptr == **var_name => true,
-                                    _ => false,
-                                };
-                                if recalc {
-                                    rows_to_recalc.merge(BitFlag128::single_row(editor_y + 1 + i));
-                                }
-                            }
-                        }
+    pub fn handle_mouse_up(&mut self) {
+        match self.mouse_state {
+            Some(MouseClickType::RightGutterIsDragged) => {}
+            Some(MouseClickType::ClickedInEditor) => {}
+            Some(MouseClickType::ClickedInScrollBar {
+                original_click_y, ..
+            }) => {
+                let gr = &mut self.render_data;
+                if let Some(scrollbar_render_info) =
+                    NoteCalcApp::get_scrollbar_info(gr, self.editor_content.line_count())
+                {
+                    if original_click_y.as_usize() < scrollbar_render_info.scroll_bar_render_y {
+                        // scroll up
+                        gr.scroll_y -= 1;
+                    } else if original_click_y.as_usize()
+                        > scrollbar_render_info.scroll_bar_render_y
+                            + scrollbar_render_info.scroll_bar_render_h
+                    {
+                        // scroll down
+                        gr.scroll_y += 1;
                     }
                 }
-                (None, None) => {}
             }
-            return rows_to_recalc;
+            None => {}
         }
+        self.mouse_state = None;
+    }
 
-        let mut sum_is_null = true;
-        let mut dependant_rows = BitFlag128::empty();
-        let mut result_change_flag = BitFlag128::empty();
-        for editor_y in 0..self.editor_content.line_count().min(MAX_LINE_COUNT) {
-            let recalc = match input_effect {
-                RowModificationType::SingleLine(to_change_index) if to_change_index == editor_y => {
-                    true
-                }
-                RowModificationType::AllLinesFrom(to_change_index_from)
-                    if editor_y >= to_change_index_from =>
-                {
-                    true
-                }
-                _ => dependant_rows.need(content_y(editor_y)),
-            };
-            if recalc {
-                if self.editor_content.get_data(editor_y).line_id == 0 {
-                    self.editor_content.mut_data(editor_y).line_id = self.line_id_generator;
-                    self.line_id_generator += 1;
-                }
-                let y = content_y(editor_y);
+    fn handle_editor_area_click<'b>(
+        &mut self,
+        x: usize,
+        clicked_y: CanvasY,
+        editor_objs: &mut EditorObjects,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        let clicked_x = x - self.render_data.left_gutter_width + self.render_data.scroll_x;
+        let clicked_row = self.get_clicked_row_clamped(clicked_y);
 
-                let (result_has_changed, rows_to_recalc) = eval_line(
-                    &self.editor_content,
-                    self.editor_content.get_line_valid_chars(editor_y),
-                    units,
-                    allocator,
-                    tokens,
-                    results,
-                    &mut *vars,
-                    y,
-                    &mut self.updated_line_ref_obj_indices,
-                );
-                if result_has_changed {
-                    result_change_flag.merge(BitFlag128::single_row(editor_y));
-                }
-                dependant_rows.merge(rows_to_recalc);
-                let new_h = calc_rendered_height(y, &self.matrix_editing, tokens, results, vars);
-                self.render_data.set_rendered_height(y, new_h);
-            }
-            if self
-                .editor_content
-                .get_line_valid_chars(editor_y)
-                .starts_with(&['#'])
-            {
-                sum_is_null = true;
-            }
+        let matrix_row_index = if self.matrix_editing.is_some() {
+            let matrix_row_index = self.matrix_editing.as_ref().unwrap().row_index;
+            end_matrix_editing(
+                &mut self.matrix_editing,
+                &mut self.editor,
+                &mut self.editor_content,
+                None,
+            );
+            Some(matrix_row_index)
+        } else {
+            None
+        };
 
-            match &results[content_y(editor_y)] {
-                Ok(Some(result)) => {
-                    sum_result(
-                        vars[SUM_VARIABLE_INDEX]
-                            .as_mut()
-                            .expect("SUM always exists"),
-                        result,
-                        &mut sum_is_null,
-                    );
+        let editor_click_pos = if let Some(column) =
+            self.get_wrapped_line_column_at(clicked_x, clicked_row, clicked_y)
+        {
+            Pos::from_row_column(clicked_row.as_usize(), column)
+        } else if let Some(editor_obj) =
+            self.get_obj_at(clicked_x, clicked_row, clicked_y, editor_objs)
+        {
+            match editor_obj.typ {
+                EditorObjectType::LineReference { .. } => {
+                    Pos::from_row_column(editor_obj.row.as_usize(), editor_obj.end_x)
+                }
+                EditorObjectType::Matrix {
+                    row_count,
+                    col_count,
+                } => {
+                    // the matrix is vertically centered in a taller row
+                    let vert_align_offset = self
+                        .render_data
+                        .get_rendered_height(editor_obj.row)
+                        .saturating_sub(editor_obj.rendered_h)
+                        / 2;
+                    let local_y = clicked_y
+                        .as_usize()
+                        .saturating_sub(editor_obj.rendered_y.as_usize() + vert_align_offset);
+                    self.matrix_editing = Some(MatrixEditing::new_at_click(
+                        row_count,
+                        col_count,
+                        &self
+                            .editor_content
+                            .get_line_valid_chars(editor_obj.row.as_usize())
+                            [editor_obj.start_x..editor_obj.end_x],
+                        editor_obj.row,
+                        editor_obj.start_x,
+                        editor_obj.end_x,
+                        clicked_x.saturating_sub(editor_obj.rendered_x),
+                        local_y,
+                    ));
+                    Pos::from_row_column(editor_obj.row.as_usize(), editor_obj.start_x + 1)
+                }
+                EditorObjectType::SimpleTokens | EditorObjectType::Variable { .. } => {
+                    let x_pos_within = clicked_x - editor_obj.rendered_x;
+                    Pos::from_row_column(
+                        editor_obj.row.as_usize(),
+                        editor_obj.start_x + x_pos_within,
+                    )
                 }
-                Err(_) | Ok(None) => {}
             }
-        }
-
-        if self.editor_content.line_count() > 99 {
-            self.render_data
-                .set_left_gutter_width(LEFT_GUTTER_MIN_WIDTH + 2);
-        } else if self.editor_content.line_count() > 9 {
-            self.render_data
-                .set_left_gutter_width(LEFT_GUTTER_MIN_WIDTH + 1);
         } else {
-            self.render_data
-                .set_left_gutter_width(LEFT_GUTTER_MIN_WIDTH);
-        }
+            let eol = self.editor_content.line_len(clicked_row.as_usize());
+            Pos::from_row_column(clicked_row.as_usize(), eol)
+        };
 
-        self.generate_render_commands_and_fill_editor_objs(
-            units,
-            render_buckets,
-            allocator,
-            tokens,
-            results,
-            vars,
-            editor_objs,
-            result_change_flag,
-        );
-        self.set_editor_and_result_panel_widths_and_rerender_if_necessary(
-            units,
-            render_buckets,
-            allocator,
-            tokens,
-            results,
-            vars,
-            editor_objs,
-            result_change_flag,
+        self.editor.handle_click(
+            editor_click_pos.column,
+            editor_click_pos.row,
+            &self.editor_content,
         );
-    }
 
-    fn set_editor_and_result_panel_widths_and_rerender_if_necessary<'b>(
-        &mut self,
-        units: &Units,
-        render_buckets: &mut RenderBuckets<'b>,
-        allocator: &'b Bump,
-        tokens: &AppTokens<'b>,
-        results: &Results,
-        vars: &Variables,
-        editor_objs: &mut EditorObjects,
-        result_change_flag: BitFlag128,
-    ) {
-        let current_result_g_x = self.render_data.result_gutter_x;
-        set_editor_and_result_panel_widths(
-            self.client_width,
-            self.result_panel_width_percent,
-            &mut self.render_data,
-        );
-        if self.render_data.result_gutter_x != current_result_g_x {
-            // HACKY
-            // we know the length of each line and results only after rendering them,
-            // so we can set the right gutter position only after a full render pass.
-            // If it turns out that the gutter has to/and can be moved,
-            // we rerender everything with the new gutter position.
+        self.editor.blink_cursor();
+
+        if self.mouse_state.is_none() {
+            self.mouse_state = Some(MouseClickType::ClickedInEditor);
+        }
+
+        if let Some(matrix_row_index) = matrix_row_index {
+            self.process_and_render_tokens(
+                RowModificationType::SingleLine(matrix_row_index.as_usize()),
+                units,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                render_buckets,
+            );
+        } else {
             self.generate_render_commands_and_fill_editor_objs(
                 units,
                 render_buckets,
@@ -3020,6174 +3910,11077 @@ impl NoteCalcApp {
                 results,
                 vars,
                 editor_objs,
-                result_change_flag,
+                BitFlag128::empty(),
             );
         }
     }
 
-    fn get_line_ref_name(editor_content: &EditorContent<LineData>, y: usize) -> Vec<char> {
-        let line_data = editor_content.get_data(y);
-        // TODO opt
-        let line_ref_name: Vec<char> = format!("&[{}]", line_data.line_id).chars().collect();
-        return line_ref_name;
+    fn get_wrapped_line_column_at(
+        &self,
+        x: usize,
+        editor_y: ContentIndex,
+        render_y: CanvasY,
+    ) -> Option<usize> {
+        let gr = &self.render_data;
+        if !gr.is_wrapped(editor_y) {
+            return None;
+        }
+        let line_render_y = gr.get_render_y(editor_y)?;
+        let wrapped_row = (render_y.as_isize() - line_render_y.as_isize()).max(0) as usize;
+        let column = wrapped_row * gr.current_editor_width + x.min(gr.current_editor_width);
+        Some(column.min(self.editor_content.line_len(editor_y.as_usize())))
     }
 
-    fn fill_editor_objs_referencing_current_line<'b>(
-        current_y: ContentIndex,
-        tokens: &AppTokens<'b>,
-        vars: &Variables,
-        editor_objs_referencing_current_line: &mut Vec<EditorObjId>,
-        editor_content: &EditorContent<LineData>,
-    ) {
-        editor_objs_referencing_current_line.clear();
-        if let Some(var) = &vars[current_y.as_usize()] {
-            NoteCalcApp::find_line_ref_dependant_lines(
-                &var.name,
-                tokens,
-                current_y.as_usize(),
-                editor_objs_referencing_current_line,
-            );
-        } else {
-            let line_ref_name =
-                NoteCalcApp::get_line_ref_name(&editor_content, current_y.as_usize());
-            NoteCalcApp::find_line_ref_dependant_lines(
-                &line_ref_name,
-                tokens,
-                current_y.as_usize(),
-                editor_objs_referencing_current_line,
-            );
-        };
-    }
-
-    fn find_line_ref_dependant_lines<'b>(
-        editor_obj_name: &[char],
-        tokens_per_lines: &AppTokens<'b>,
-        editor_y: usize,
-        updated_line_ref_obj_indices: &mut Vec<EditorObjId>,
-    ) -> BitFlag128 {
-        let mut rows_to_recalc = BitFlag128::empty();
-        for (token_line_index, tokens) in tokens_per_lines.iter().skip(editor_y + 1).enumerate() {
-            if let Some(tokens) = tokens {
-                let mut already_added = BitFlag128::empty();
-                for token in &tokens.tokens {
-                    let var_index = match token.typ {
-                        TokenType::LineReference { var_index }
-                            if already_added.is_false(var_index)
-                                && token.ptr == editor_obj_name =>
-                        {
-                            var_index
-                        }
-                        TokenType::Variable { var_index }
-                            if var_index != SUM_VARIABLE_INDEX
-                                && already_added.is_false(var_index)
-                                && token.ptr == editor_obj_name =>
-                        {
-                            var_index
-                        }
-
-                        _ => {
-                            continue;
-                        }
-                    };
-                    let index = editor_y + 1 + token_line_index;
-                    updated_line_ref_obj_indices.push(EditorObjId {
-                        content_index: content_y(index),
-                        var_index,
-                    });
-                    rows_to_recalc.merge(BitFlag128::single_row(index));
-                    already_added.set(var_index);
+    pub fn rendered_y_to_editor_y(&self, clicked_y: CanvasY) -> Option<ContentIndex> {
+        // the render y-s are stored for the lines of the window
+        let window_y = self.render_data.window_y();
+        let editor_y_to_render_y = self.render_data.editor_y_to_render_y();
+        let mut was_visible_row = false;
+        for (ed_y, r_y) in editor_y_to_render_y.iter().enumerate() {
+            if let Some(r_y) = r_y {
+                was_visible_row = true;
+                if *r_y == clicked_y {
+                    return Some(content_y(window_y + ed_y));
+                } else if *r_y > clicked_y {
+                    return Some(content_y(window_y + ed_y - 1));
                 }
-            } else {
-                break;
+            } else if was_visible_row {
+                return Some(content_y(window_y + ed_y - 1));
             }
         }
-        return rows_to_recalc;
+        return None;
     }
 
-    // export
-    pub fn copy_selected_rows_with_result_to_clipboard<'b>(
-        &'b mut self,
-        units: &'b Units,
-        render_buckets: &'b mut RenderBuckets<'b>,
-        tokens: &AppTokens<'b>,
-        vars: &Variables,
-        results: &Results,
-    ) -> String {
-        render_buckets.clear();
-
-        let (first_row, second_row) =
-            if let Some((start, end)) = self.editor.get_selection().is_range() {
-                (start.row, end.row)
-            } else {
-                (0, self.editor_content.line_count() - 1)
-            };
-        let row_nums = second_row - first_row + 1;
-
-        let mut gr = GlobalRenderData::new(1024, 1000 /*dummy value*/, 1024 / 2, 0, 2);
-        // evaluate all the lines so variables are defined even if they are not selected
-        let mut render_height = 0;
-        {
-            let mut r = PerLineRenderData::new();
-            for i in first_row..=second_row {
-                let i = content_y(i);
-                // tokens must be evaluated to register variables for line reference inlining in the output text
-
-                if let Some(tokens) = &tokens[i] {
-                    r.new_line_started();
-                    gr.set_render_y(r.editor_y, Some(r.render_y));
-
-                    r.rendered_row_height = PerLineRenderData::calc_rendered_row_height(
-                        &results[i],
-                        &tokens.tokens,
-                        &vars[..],
-                        None,
-                    );
-                    // "- 1" so if it is even, it always appear higher
-                    r.vert_align_offset = (r.rendered_row_height - 1) / 2;
-                    gr.set_rendered_height(r.editor_y, r.rendered_row_height);
-                    render_height += r.rendered_row_height;
-                    // Todo: refactor the parameters into a struct
-                    render_tokens(
-                        &tokens.tokens,
-                        &mut r,
-                        &mut gr,
-                        render_buckets,
-                        // TODO &mut code smell
-                        &mut Vec::new(),
-                        &self.editor,
-                        &self.matrix_editing,
-                        &vars[..],
-                        &units,
-                        true, // force matrix rendering
-                        None,
-                    );
-                    r.line_render_ended(r.rendered_row_height);
-                }
-            }
-        }
-
-        // TODO what is this 256?
-        let mut tmp_canvas: Vec<[char; 256]> = Vec::with_capacity(render_height);
-        for _ in 0..render_height {
-            tmp_canvas.push([' '; 256]);
-        }
-        // render all tokens to the tmp canvas, so we can measure the longest row
-        render_buckets_into(&render_buckets, &mut tmp_canvas);
-        let mut max_len = 0;
-        for canvas_line in &tmp_canvas {
-            let mut len = 256;
-            for ch in canvas_line.iter().rev() {
-                if *ch != ' ' {
-                    break;
-                }
-                len -= 1;
-            }
-            if len > max_len {
-                max_len = len;
-            }
-        }
-
-        //////////////////////////////////////////////////////////////////////////
-        //////////////////////////////////////////////////////////////////////////
-        render_buckets.clear();
-
-        let mut tmp = ResultRender::new(SmallVec::with_capacity(MAX_LINE_COUNT));
-
-        gr.result_gutter_x = max_len + 2;
-        render_results_into_buf_and_calc_len(
-            &units,
-            &results.as_slice()[first_row..=second_row],
-            &mut tmp,
-            &self.editor_content,
-            &gr,
-            None,
+    pub fn get_clicked_row_clamped<'a>(&self, render_y: CanvasY) -> ContentIndex {
+        let latest_bottom_i = self.render_data.calc_bottom_y(
+            self.editor_content
+                .line_count()
+                .min(self.render_data.window_y() + MAX_LINE_COUNT - 1),
         );
-        gr.longest_visible_result_len = tmp.max_len;
+        return if render_y >= latest_bottom_i {
+            content_y(self.editor_content.line_count() - 1)
+        } else if let Some(editor_y) = self.rendered_y_to_editor_y(render_y) {
+            editor_y
+        } else {
+            panic!();
+        };
+    }
 
-        create_render_commands_for_results_and_render_matrices(
-            &tmp,
-            units,
-            &results.as_slice()[first_row..=second_row],
-            render_buckets,
-            &gr,
-            None,
-        );
+    pub fn get_obj_at_rendered_pos<'a>(
+        &self,
+        x: usize,
+        render_y: CanvasY,
+        editor_objects: &'a EditorObjects,
+    ) -> Option<&'a EditorObject> {
+        let editor_y = if render_y
+            >= self
+                .render_data
+                .calc_bottom_y(self.editor_content.line_count())
+        {
+            content_y(self.editor_content.line_count() - 1)
+        } else if let Some(editor_y) = self.rendered_y_to_editor_y(render_y) {
+            editor_y
+        } else {
+            return None;
+        };
+        return editor_objects[editor_y].iter().find(|editor_obj| {
+            (editor_obj.rendered_x..editor_obj.rendered_x + editor_obj.rendered_w).contains(&x)
+                && (editor_obj.rendered_y.as_usize()
+                    ..editor_obj.rendered_y.as_usize() + editor_obj.rendered_h)
+                    .contains(&render_y.as_usize())
+        });
+    }
 
-        for i in 0..render_height {
-            render_buckets.draw_char(
-                Layer::AboveText,
-                gr.result_gutter_x,
-                canvas_y(i as isize),
-                '█',
-            );
-        }
-        render_buckets_into(&render_buckets, &mut tmp_canvas);
-        let mut result_str = String::with_capacity(row_nums * 64);
-        for canvas_line in &tmp_canvas {
-            result_str.extend(canvas_line.iter());
-            while result_str.chars().last().unwrap_or('x') == ' ' {
-                result_str.pop();
-            }
-            result_str.push('\n');
-        }
+    pub fn get_obj_at<'a>(
+        &self,
+        x: usize,
+        editor_y: ContentIndex,
+        render_y: CanvasY,
+        editor_objects: &'a EditorObjects,
+    ) -> Option<&'a EditorObject> {
+        return editor_objects[editor_y].iter().find(|editor_obj| {
+            (editor_obj.rendered_x..editor_obj.rendered_x + editor_obj.rendered_w).contains(&x)
+                && (editor_obj.rendered_y.as_usize()
+                    ..editor_obj.rendered_y.as_usize() + editor_obj.rendered_h)
+                    .contains(&render_y.as_usize())
+        });
+    }
 
-        return result_str;
+    pub fn get_obj_at_inside<'a>(
+        &self,
+        x: usize,
+        editor_y: ContentIndex,
+        editor_objects: &'a EditorObjects,
+    ) -> Option<&'a EditorObject> {
+        return editor_objects[editor_y]
+            .iter()
+            .find(|editor_obj| (editor_obj.start_x + 1..editor_obj.end_x).contains(&x));
     }
 
-    fn handle_completion<'b>(
+    pub fn handle_mouse_move<'b>(
         &mut self,
-        input: &EditorInputEvent,
-        editor_objects: &mut EditorObjects,
+        x: usize,
+        _y: CanvasY,
+        editor_objs: &mut EditorObjects,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &AppTokens<'b>,
+        results: &Results,
         vars: &Variables,
-    ) -> bool {
-        let cursor_pos = self.editor.get_selection();
-        if *input != EditorInputEvent::Tab || cursor_pos.get_cursor_pos().column == 0 {
-            return false;
-        }
-
-        // matrix autocompletion 'm' + tab
-        let cursor_pos = cursor_pos.get_cursor_pos();
-
-        for autocompl_const in &AUTOCOMPLETION_CONSTS {
-            let len = autocompl_const.abbrev.len();
-            if cursor_pos.column <= len {
-                continue;
-            }
-            let start_x = cursor_pos.column - (len + 1);
-            let autocompletion_match = {
-                let line = self.editor_content.get_line_valid_chars(cursor_pos.row);
-                line[start_x] == '.'
-                    && &line[start_x + 1..cursor_pos.column] == autocompl_const.abbrev
-            };
-            if autocompletion_match {
-                let start = cursor_pos.with_column(start_x);
-                self.editor
-                    .set_selection_save_col(Selection::range(start, cursor_pos));
-                self.editor.handle_input(
-                    EditorInputEvent::Backspace,
-                    InputModifiers::none(),
-                    &mut self.editor_content,
-                );
-                for ch in autocompl_const.replace_to {
-                    self.editor.handle_input(
-                        EditorInputEvent::Char(*ch),
-                        InputModifiers::none(),
-                        &mut self.editor_content,
-                    );
-                }
-                if let Some(relative_new_cursor_pos) = autocompl_const.relative_new_cursor_pos {
-                    self.editor.set_selection_save_col(Selection::single(
-                        start.add_column(relative_new_cursor_pos),
-                    ));
-                }
-                if autocompl_const.abbrev.len() >= 3
-                    && &autocompl_const.abbrev[0..3] == &['m', 'a', 't']
-                {
-                    // remove the SimpleToken of the .mat string
-                    let size = if autocompl_const.abbrev.len() == 3 {
-                        1 // .mat
-                    } else if autocompl_const.abbrev[3] == '3' {
-                        3
-                    } else {
-                        4
-                    };
-                    editor_objects[content_y(cursor_pos.row)].pop();
-                    editor_objects[content_y(cursor_pos.row)].push(EditorObject {
-                        typ: EditorObjectType::Matrix {
-                            row_count: size,
-                            col_count: size,
-                        },
-                        row: content_y(cursor_pos.row),
-                        start_x,
-                        end_x: start_x + autocompl_const.replace_to.len(),
-                        rendered_x: 0,           // dummy
-                        rendered_y: canvas_y(0), // dummy
-                        rendered_w: size + 2,
-                        rendered_h: 1,
-                    });
-                    self.check_stepping_into_matrix(Pos::from_row_column(0, 0), &editor_objects);
-                }
-                return true;
-            }
+        render_buckets: &mut RenderBuckets<'b>,
+    ) -> usize {
+        let scroll_bar_x = self.render_data.result_gutter_x - SCROLLBAR_WIDTH;
+        let new_mouse_state = if x < self.render_data.left_gutter_width {
+            MouseHoverType::Normal
+        } else if x < scroll_bar_x {
+            // editor
+            MouseHoverType::Normal
+        } else if (x as isize - scroll_bar_x as isize) < (SCROLLBAR_WIDTH as isize) {
+            MouseHoverType::Scrollbar
+        } else if (x as isize - self.render_data.result_gutter_x as isize)
+            < (RIGHT_GUTTER_WIDTH as isize)
+        {
+            MouseHoverType::RightGutter
+        } else {
+            // result
+            return MouseHoverType::Result as usize;
+        };
+        if self.mouse_hover_type != new_mouse_state {
+            self.mouse_hover_type = new_mouse_state;
+            self.generate_render_commands_and_fill_editor_objs(
+                units,
+                render_buckets,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                BitFlag128::empty(),
+            );
         }
+        return self.mouse_hover_type as usize;
+    }
 
-        let line = self.editor_content.get_line_valid_chars(cursor_pos.row);
-        // check for autocompletion
-        // find space
-        let (begin_index, expected_len) = {
-            let mut begin_index = cursor_pos.column - 1;
-            let mut len = 1;
-            while begin_index > 0
-                && (line[begin_index - 1].is_alphanumeric() || line[begin_index - 1] == '_')
-            {
-                begin_index -= 1;
-                len += 1;
-            }
-            (begin_index, len)
-        };
-        // find the best match
-        let mut matched_var_index = None;
-        for (var_i, var) in vars[0..cursor_pos.row].iter().enumerate() {
-            if var.is_none() {
-                continue;
+    pub fn handle_drag<'b>(
+        &mut self,
+        x: usize,
+        y: CanvasY,
+        editor_objs: &mut EditorObjects,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) -> bool {
+        let need_render = match self.mouse_state {
+            Some(MouseClickType::RightGutterIsDragged) => {
+                let x_bounded = x.max(self.render_data.left_gutter_width + 4);
+                let new_result_panel_width_percent =
+                    (self.client_width - x_bounded) * 100 / self.client_width;
+                self.result_panel_width_percent = new_result_panel_width_percent;
+                set_editor_and_result_panel_widths(
+                    self.client_width,
+                    self.result_panel_width_percent,
+                    &mut self.render_data,
+                );
+                true
             }
-            let mut match_len = 0;
-            for (var_ch, actual_ch) in var
-                .as_ref()
-                .unwrap()
-                .name
-                .iter()
-                .zip(&line[begin_index..begin_index + expected_len])
-            {
-                if *var_ch != *actual_ch {
-                    break;
+            Some(MouseClickType::ClickedInEditor) => {
+                if let Some(editor_y) = self.rendered_y_to_editor_y(y) {
+                    let x = (x as isize - self.render_data.left_gutter_width as isize).max(0)
+                        as usize
+                        + self.render_data.scroll_x;
+                    let column = self.get_wrapped_line_column_at(x, editor_y, y).unwrap_or(x);
+                    self.editor
+                        .handle_drag(column, editor_y.as_usize(), &self.editor_content);
+                    self.editor.blink_cursor();
+                    true
+                } else {
+                    false
                 }
-                match_len += 1;
             }
-            if expected_len == match_len {
-                if matched_var_index.is_some() {
-                    // multiple match, don't autocomplete
-                    matched_var_index = None;
-                    break;
+            Some(MouseClickType::ClickedInScrollBar {
+                original_click_y,
+                original_scroll_y,
+            }) => {
+                let gr = &mut self.render_data;
+                if let Some(scrollbar_info) =
+                    NoteCalcApp::get_scrollbar_info(gr, self.editor_content.line_count())
+                {
+                    let delta_y = y.as_isize() - original_click_y.as_isize();
+                    gr.scroll_y = ((original_scroll_y as isize + delta_y).max(0) as usize)
+                        .min(scrollbar_info.max_scroll_y);
+
+                    true
                 } else {
-                    matched_var_index = Some(var_i);
+                    false
                 }
             }
+            None => false,
+        };
+        if need_render && self.calc_window_y() != self.render_data.window_y() {
+            self.process_and_render_tokens(
+                RowModificationType::AllLinesFrom(0),
+                units,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                render_buckets,
+            );
+        } else if need_render {
+            self.generate_render_commands_and_fill_editor_objs(
+                units,
+                render_buckets,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                BitFlag128::empty(),
+            );
         }
+        return need_render;
+    }
 
-        if let Some(matched_var_index) = matched_var_index {
-            for ch in vars[matched_var_index]
-                .as_ref()
-                .unwrap()
-                .name
-                .iter()
-                .skip(expected_len)
-            {
-                self.editor.handle_input(
-                    EditorInputEvent::Char(*ch),
-                    InputModifiers::none(),
-                    &mut self.editor_content,
-                );
-            }
-            return true;
+    fn get_scrollbar_info(
+        gr: &GlobalRenderData,
+        content_len: usize,
+    ) -> Option<ScrollBarRenderInfo> {
+        let content_height = NoteCalcApp::calc_full_content_height(gr, content_len);
+        let max_scroll_y = content_height as isize - gr.client_height as isize;
+        if max_scroll_y > 0 {
+            let max_scroll_y = max_scroll_y as usize;
+            let scroll_bar_h = (gr.client_height as isize - max_scroll_y as isize).max(1) as usize;
+            let scroll_bar_y = if scroll_bar_h > 1 {
+                gr.scroll_y
+            } else {
+                ((gr.scroll_y as f64 / (max_scroll_y + 1) as f64) * gr.client_height as f64)
+                    as usize
+            };
+            Some(ScrollBarRenderInfo {
+                scroll_bar_render_y: scroll_bar_y,
+                scroll_bar_render_h: scroll_bar_h,
+                max_scroll_y,
+            })
+        } else {
+            None
         }
-
-        return false;
     }
 
-    fn handle_obj_deletion<'b>(
+    pub fn handle_resize<'b>(
         &mut self,
-        input: &EditorInputEvent,
-        editor_objects: &mut EditorObjects,
-    ) -> Option<RowModificationType> {
-        let selection = self.editor.get_selection();
-        let cursor_pos = selection.get_cursor_pos();
-        if *input == EditorInputEvent::Backspace
-            && selection.is_range().is_none()
-            && selection.start.column > 0
+        new_client_width: usize,
+        editor_objs: &mut EditorObjects,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        if new_client_width
+            < LEFT_GUTTER_MIN_WIDTH + RIGHT_GUTTER_WIDTH + MIN_RESULT_PANEL_WIDTH + SCROLLBAR_WIDTH
         {
-            if let Some(index) =
-                self.index_of_matrix_or_lineref_at(cursor_pos.with_prev_col(), editor_objects)
-            {
-                // remove it
-                let obj = editor_objects[content_y(cursor_pos.row)].remove(index);
-                let sel = Selection::range(
-                    Pos::from_row_column(obj.row.as_usize(), obj.start_x),
-                    Pos::from_row_column(obj.row.as_usize(), obj.end_x),
-                );
-                self.editor.set_selection_save_col(sel);
-                self.editor.handle_input(
-                    EditorInputEvent::Backspace,
-                    InputModifiers::none(),
-                    &mut self.editor_content,
-                );
-                return if obj.rendered_h > 1 {
-                    Some(RowModificationType::AllLinesFrom(cursor_pos.row))
-                } else {
-                    Some(RowModificationType::SingleLine(cursor_pos.row))
-                };
-            }
-        } else if *input == EditorInputEvent::Del && selection.is_range().is_none() {
-            if let Some(index) = self.index_of_matrix_or_lineref_at(cursor_pos, editor_objects) {
-                // remove it
-                let obj = editor_objects[content_y(cursor_pos.row)].remove(index);
-                let sel = Selection::range(
-                    Pos::from_row_column(obj.row.as_usize(), obj.start_x),
-                    Pos::from_row_column(obj.row.as_usize(), obj.end_x),
-                );
-                self.editor.set_selection_save_col(sel);
-                self.editor.handle_input(
-                    EditorInputEvent::Del,
-                    InputModifiers::none(),
-                    &mut self.editor_content,
-                );
-                return if obj.rendered_h > 1 {
-                    Some(RowModificationType::AllLinesFrom(cursor_pos.row))
-                } else {
-                    Some(RowModificationType::SingleLine(cursor_pos.row))
-                };
-            }
-        }
-        return None;
-    }
-
-    fn handle_obj_jump_over<'b>(
-        &mut self,
-        input: &EditorInputEvent,
-        modifiers: InputModifiers,
-        editor_objects: &EditorObjects,
-    ) -> bool {
-        let selection = self.editor.get_selection();
-        let cursor_pos = selection.get_cursor_pos();
-        if *input == EditorInputEvent::Left
-            && selection.is_range().is_none()
-            && selection.start.column > 0
-            && modifiers.shift == false
-        {
-            let obj = self
-                .find_editor_object_at_including_end_of_word(cursor_pos, editor_objects)
-                .map(|it| (it.typ, it.row, it.start_x));
-            if let Some((EditorObjectType::LineReference { .. }, row, start_x)) = obj {
-                //  jump over it
-                self.editor.set_cursor_pos_r_c(row.as_usize(), start_x);
-                return true;
-            }
-        } else if *input == EditorInputEvent::Right
-            && selection.is_range().is_none()
-            && modifiers.shift == false
-        {
-            let obj = self
-                .find_editor_object_at_excluding_end_of_word(cursor_pos, editor_objects)
-                .map(|it| (it.typ, it.row, it.end_x));
-
-            if let Some((EditorObjectType::LineReference { .. }, row, end_x)) = obj {
-                //  jump over it
-                self.editor.set_cursor_pos_r_c(row.as_usize(), end_x);
-                return true;
-            }
+            return;
         }
-        return false;
+        self.client_width = new_client_width;
+        set_editor_and_result_panel_widths(
+            new_client_width,
+            self.result_panel_width_percent,
+            &mut self.render_data,
+        );
+        self.generate_render_commands_and_fill_editor_objs(
+            units,
+            render_buckets,
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            BitFlag128::empty(),
+        );
     }
 
-    fn handle_jump_to_definition<'b>(
+    pub fn handle_time<'b>(
         &mut self,
-        input: &EditorInputEvent,
-        modifiers: InputModifiers,
-        editor_objects: &EditorObjects,
+        now: u32,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
     ) -> bool {
-        let selection = self.editor.get_selection();
-        let cursor_pos = selection.get_cursor_pos();
-        if *input == EditorInputEvent::Char('b') && modifiers.ctrl {
-            if let Some(var_index) =
-                self.find_var_index_of_var_or_lineref_at(cursor_pos, editor_objects)
-            {
-                self.editor.set_cursor_pos_r_c(var_index, 0);
-                return true;
-            }
+        let need_rerender = if let Some(mat_editor) = &mut self.matrix_editing {
+            mat_editor.editor.handle_tick(now)
+        } else {
+            self.editor.handle_tick(now)
+        };
+        if need_rerender {
+            self.generate_render_commands_and_fill_editor_objs(
+                units,
+                render_buckets,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                BitFlag128::empty(),
+            );
         }
-        return false;
+        need_rerender
     }
 
-    fn check_stepping_into_matrix<'b>(
-        &mut self,
-        enter_from_pos: Pos,
-        editor_objects: &EditorObjects,
-    ) {
-        if let Some(editor_obj) =
-            is_pos_inside_an_obj(editor_objects, self.editor.get_selection().get_cursor_pos())
-        {
-            match editor_obj.typ {
-                EditorObjectType::Matrix {
-                    row_count,
-                    col_count,
-                } => {
-                    if self.matrix_editing.is_none()
-                        && self.editor.get_selection().is_range().is_none()
-                    {
-                        self.matrix_editing = Some(MatrixEditing::new(
-                            row_count,
-                            col_count,
-                            &self
+    pub fn get_line_ref_normalized_content(&self) -> String {
+        // TODO: no alloc
+        let mut result: String = String::with_capacity(self.editor_content.line_count() * 40);
+        for line in self.editor_content.lines() {
+            let mut i = 0;
+            'i: while i < line.len() {
+                if i + 3 < line.len() && line[i] == '&' && line[i + 1] == '[' {
+                    let mut end = i + 2;
+                    let mut num: u32 = 0;
+                    while end < line.len() {
+                        if line[end] == ']' && num > 0 {
+                            // which row has the id of 'num'?
+                            let referenced_row_index = self
                                 .editor_content
-                                .get_line_valid_chars(editor_obj.row.as_usize())
-                                [editor_obj.start_x..editor_obj.end_x],
-                            editor_obj.row,
-                            editor_obj.start_x,
-                            editor_obj.end_x,
-                            enter_from_pos,
-                        ));
+                                .data()
+                                .iter()
+                                .position(|it| it.line_id == num as usize)
+                                .unwrap_or(0)
+                                + 1; // '+1' line id cannot be 0
+                            result.push('&');
+                            result.push('[');
+                            result.push_str(&referenced_row_index.to_string());
+                            result.push(']');
+                            i = end + 1;
+                            continue 'i;
+                        } else if let Some(digit) = line[end].to_digit(10) {
+                            num = if num == 0 { digit } else { num * 10 + digit };
+                        } else {
+                            break;
+                        }
+                        end += 1;
                     }
                 }
-                EditorObjectType::SimpleTokens
-                | EditorObjectType::LineReference { .. }
-                | EditorObjectType::Variable { .. } => {}
+                result.push(line[i]);
+                i += 1;
             }
+            result.push('\n');
         }
-    }
 
-    fn find_editor_object_at_including_end_of_word<'b>(
-        &self,
-        pos: Pos,
-        editor_objects: &'b EditorObjects,
-    ) -> Option<&'b EditorObject> {
-        for obj in &editor_objects[content_y(pos.row)] {
-            if (obj.start_x..=obj.end_x).contains(&pos.column) {
-                return Some(obj);
-            }
+        // the non-decimal result formats are stored in an extra last line,
+        // `set_normalized_content` restores and removes it
+        let line_count = self.editor_content.line_count();
+        // a real last line which looks like the formats line is protected by
+        // an (empty) formats line after it, since only the very last line is read back
+        let last_line_is_like_marker = self
+            .editor_content
+            .get_line_valid_chars(line_count - 1)
+            .iter()
+            .copied()
+            .take(RESULT_FORMATS_MARKER.len())
+            .eq(RESULT_FORMATS_MARKER.chars());
+        let result_formats = self.editor_content.data()[0..line_count]
+            .iter()
+            .enumerate()
+            .filter_map(|(row, data)| {
+                result_format_name(data.result_format).map(|name| format!("{}={}", row, name))
+            })
+            .collect::<Vec<_>>();
+        if !result_formats.is_empty() || last_line_is_like_marker {
+            result.push_str(RESULT_FORMATS_MARKER);
+            result.push_str(&result_formats.join(","));
+            result.push('\n');
         }
-        return None;
-    }
 
-    // "asd |var"
-    // here, the cursor is at the edge of the first and second tokens as well, but the simpletoken
-    // comes first so the variable won't be found, that's why we need this specific find method for
-    // linerefs and vars
-    fn find_var_index_of_var_or_lineref_at(
-        &self,
-        pos: Pos,
-        editor_objects: &EditorObjects,
-    ) -> Option<usize> {
-        for obj in &editor_objects[content_y(pos.row)] {
-            match obj.typ {
-                EditorObjectType::Variable { var_index }
-                | EditorObjectType::LineReference { var_index }
-                    if (obj.start_x..=obj.end_x).contains(&pos.column) =>
-                {
-                    return Some(var_index);
-                }
-                _ => {}
-            }
-        }
-        return None;
+        return result;
     }
 
-    fn find_editor_object_at_excluding_end_of_word<'b>(
-        &self,
-        pos: Pos,
-        editor_objects: &'b EditorObjects,
-    ) -> Option<&'b EditorObject> {
-        for obj in &editor_objects[content_y(pos.row)] {
-            if (obj.start_x..obj.end_x).contains(&pos.column) {
-                return Some(obj);
-            }
-        }
-        return None;
-    }
+    pub fn normalize_line_refs_in_place(&mut self) {
+        let mut original_selection = self.editor.get_selection();
+        for line_i in 0..self.editor_content.line_count() {
+            let mut i = 0;
+            'i: while i < self.editor_content.line_len(line_i) {
+                //self.editor_content.get_line_valid_chars(line_i)
+                let start = i;
+                if i + 3 < self.editor_content.line_len(line_i)
+                    && self.editor_content.get_char(line_i, i) == '&'
+                    && self.editor_content.get_char(line_i, i + 1) == '['
+                {
+                    let mut end = i + 2;
+                    let mut num_inside_lineref: u32 = 0;
+                    while end < self.editor_content.line_len(line_i) {
+                        if self.editor_content.get_char(line_i, end) == ']'
+                            && num_inside_lineref > 0
+                        {
+                            let num_len = end - (start + 2); // start --> &[num] <- end
 
-    fn index_of_matrix_or_lineref_at<'b>(
-        &self,
-        pos: Pos,
-        editor_objects: &EditorObjects,
-    ) -> Option<usize> {
-        return editor_objects[content_y(pos.row)].iter().position(|obj| {
-            matches!(obj.typ, EditorObjectType::LineReference{..} | EditorObjectType::Matrix {..})
-                && (obj.start_x..obj.end_x).contains(&pos.column)
-        });
-    }
-
-    fn handle_matrix_editor_input(&mut self, input: EditorInputEvent, modifiers: InputModifiers) {
-        let mat_edit = self.matrix_editing.as_mut().unwrap();
-        let cur_pos = self.editor.get_selection().get_cursor_pos();
+                            // remove the number from the original line_ref text '&[x]' (remove only x)
+                            {
+                                let start_pos = Pos::from_row_column(line_i, start + 2);
+                                let end_pos = start_pos.with_column(end);
+                                self.editor.set_cursor_range(start_pos, end_pos);
+                                self.editor.handle_input(
+                                    EditorInputEvent::Del,
+                                    InputModifiers::none(),
+                                    &mut self.editor_content,
+                                );
+                            }
+                            {
+                                // which row has the id of 'num_inside_lineref'?
+                                let referenced_row_index = self
+                                    .editor_content
+                                    .data()
+                                    .iter()
+                                    .position(|it| it.line_id == num_inside_lineref as usize)
+                                    .unwrap_or(0)
+                                    + 1; // '+1' line id cannot be 0
 
-        let simple = !modifiers.shift && !modifiers.alt;
-        let alt = modifiers.alt;
-        if input == EditorInputEvent::Esc || input == EditorInputEvent::Enter {
-            end_matrix_editing(
-                &mut self.matrix_editing,
-                &mut self.editor,
-                &mut self.editor_content,
-                None,
-            );
-        } else if input == EditorInputEvent::Tab {
-            if mat_edit.current_cell.column + 1 < mat_edit.col_count {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_next_col());
-            } else if mat_edit.current_cell.row + 1 < mat_edit.row_count {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_next_row().with_column(0));
-            } else {
-                end_matrix_editing(
-                    &mut self.matrix_editing,
-                    &mut self.editor,
-                    &mut self.editor_content,
-                    None,
-                );
-            }
-        } else if alt && input == EditorInputEvent::Right {
-            mat_edit.add_column();
-        } else if alt && input == EditorInputEvent::Left && mat_edit.col_count > 1 {
-            mat_edit.remove_column();
-        } else if alt && input == EditorInputEvent::Down {
-            mat_edit.add_row();
-        } else if alt && input == EditorInputEvent::Up && mat_edit.row_count > 1 {
-            mat_edit.remove_row();
-        } else if simple
-            && input == EditorInputEvent::Left
-            && mat_edit.editor.is_cursor_at_beginning()
-        {
-            if mat_edit.current_cell.column > 0 {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_prev_col());
-            } else {
-                let start_text_index = mat_edit.start_text_index;
-                end_matrix_editing(
-                    &mut self.matrix_editing,
-                    &mut self.editor,
-                    &mut self.editor_content,
-                    Some(cur_pos.with_column(start_text_index)),
-                );
-            }
-        } else if simple
-            && input == EditorInputEvent::Right
-            && mat_edit.editor.is_cursor_at_eol(&mat_edit.editor_content)
-        {
-            if let Some((_from, to)) = mat_edit.editor.get_selection().is_range() {
-                mat_edit.editor.set_cursor_pos_r_c(0, to.column);
-            } else if mat_edit.current_cell.column + 1 < mat_edit.col_count {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_next_col());
-            } else {
-                end_matrix_editing(
-                    &mut self.matrix_editing,
-                    &mut self.editor,
-                    &mut self.editor_content,
-                    None,
-                );
-            }
-        } else if simple && input == EditorInputEvent::Up {
-            if mat_edit.current_cell.row > 0 {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_prev_row());
-            } else {
-                end_matrix_editing(
-                    &mut self.matrix_editing,
-                    &mut self.editor,
-                    &mut self.editor_content,
-                    None,
-                );
-                self.editor
-                    .handle_input(input, modifiers, &mut self.editor_content);
-            }
-        } else if simple && input == EditorInputEvent::Down {
-            if mat_edit.current_cell.row + 1 < mat_edit.row_count {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_next_row());
-            } else {
-                end_matrix_editing(
-                    &mut self.matrix_editing,
-                    &mut self.editor,
-                    &mut self.editor_content,
-                    None,
-                );
-                self.editor
-                    .handle_input(input, modifiers, &mut self.editor_content);
-            }
-        } else if simple && input == EditorInputEvent::End {
-            if mat_edit.current_cell.column != mat_edit.col_count - 1 {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_column(mat_edit.col_count - 1));
-            } else {
-                end_matrix_editing(
-                    &mut self.matrix_editing,
-                    &mut self.editor,
-                    &mut self.editor_content,
-                    None,
-                );
-                self.editor
-                    .handle_input(input, modifiers, &mut self.editor_content);
-            }
-        } else if simple && input == EditorInputEvent::Home {
-            if mat_edit.current_cell.column != 0 {
-                mat_edit.move_to_cell(mat_edit.current_cell.with_column(0));
-            } else {
-                let start_index = mat_edit.start_text_index;
-                end_matrix_editing(
-                    &mut self.matrix_editing,
-                    &mut self.editor,
-                    &mut self.editor_content,
-                    Some(cur_pos.with_column(start_index)),
-                );
-                self.editor
-                    .handle_input(input, modifiers, &mut self.editor_content);
+                                i = start + 2;
+                                let mut align_selection = 0;
+                                if line_i == original_selection.start.row
+                                    && original_selection.start.column >= i
+                                {
+                                    original_selection.start.column -= num_len;
+                                    align_selection |= 1;
+                                }
+                                if let Some(end) = original_selection.end {
+                                    if line_i == end.row && end.column >= i {
+                                        original_selection.end.as_mut().expect("must").column -=
+                                            num_len;
+                                        align_selection |= 2;
+                                    }
+                                };
+                                for digit in referenced_row_index.to_string().chars() {
+                                    self.editor.handle_input(
+                                        EditorInputEvent::Char(digit),
+                                        InputModifiers::none(),
+                                        &mut self.editor_content,
+                                    );
+                                    i += 1;
+                                    if (align_selection & 1) > 0 {
+                                        original_selection.start.column += 1;
+                                    }
+                                    if (align_selection & 2) > 0 {
+                                        original_selection.end.as_mut().expect("must").column += 1;
+                                    }
+                                }
+                                i += 1; // skip ']'
+                            }
+                            continue 'i;
+                        } else if let Some(digit) =
+                            self.editor_content.get_char(line_i, end).to_digit(10)
+                        {
+                            num_inside_lineref = if num_inside_lineref == 0 {
+                                digit
+                            } else {
+                                num_inside_lineref * 10 + digit
+                            };
+                        } else {
+                            break;
+                        }
+                        end += 1;
+                    }
+                }
+                i += 1;
             }
-        } else {
-            mat_edit
-                .editor
-                .handle_input(input, modifiers, &mut mat_edit.editor_content);
         }
+        for line_i in 0..self.editor_content.line_count() {
+            self.editor_content.mut_data(line_i).line_id = line_i + 1;
+        }
+        self.line_id_generator = self.editor_content.line_count() + 1;
+
+        self.editor.set_selection_save_col(original_selection);
     }
 
-    pub fn generate_render_commands_and_fill_editor_objs<'b>(
+    pub fn alt_key_released<'b>(
         &mut self,
         units: &Units,
-        render_buckets: &mut RenderBuckets<'b>,
         allocator: &'b Bump,
-        tokens: &AppTokens<'b>,
-        results: &Results,
-        vars: &Variables,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
         editor_objs: &mut EditorObjects,
-        result_change_flag: BitFlag128,
+        render_buckets: &mut RenderBuckets<'b>,
     ) {
-        render_buckets.clear();
-        NoteCalcApp::renderr(
-            &mut self.editor,
-            &self.editor_content,
-            units,
-            &mut self.matrix_editing,
-            &mut self.line_reference_chooser,
-            render_buckets,
-            result_change_flag,
-            &mut self.render_data,
-            allocator,
-            tokens,
-            results,
-            vars,
-            editor_objs,
-            &self.updated_line_ref_obj_indices,
-            &mut self.editor_objs_referencing_current_line,
-            self.mouse_hover_type,
-        );
-        self.updated_line_ref_obj_indices.clear();
+        if let Some(line_ref_row) = self.line_reference_chooser {
+            self.line_reference_chooser = None;
+            self.insert_line_ref(
+                units,
+                allocator,
+                tokens,
+                results,
+                vars,
+                line_ref_row,
+                editor_objs,
+                render_buckets,
+            );
+        } else {
+            return;
+        }
     }
-}
-
-#[derive(Debug)]
-pub struct ResultLengths {
-    int_part_len: usize,
-    frac_part_len: usize,
-    unit_part_len: usize,
-}
 
-impl ResultLengths {
-    fn set_max(&mut self, other: &ResultLengths) {
-        if self.int_part_len < other.int_part_len {
-            self.int_part_len = other.int_part_len;
-        }
-        if self.frac_part_len < other.frac_part_len {
-            self.frac_part_len = other.frac_part_len;
-        }
-        if self.unit_part_len < other.unit_part_len {
-            self.unit_part_len = other.unit_part_len;
-        }
-    }
-}
-
-fn draw_cursor(
-    render_buckets: &mut RenderBuckets,
-    r: &PerLineRenderData,
-    gr: &GlobalRenderData,
-    editor: &Editor,
-    matrix_editing: &Option<MatrixEditing>,
-) {
-    let cursor_pos = editor.get_selection().get_cursor_pos();
-    if cursor_pos.row == r.editor_y.as_usize() {
-        render_buckets.set_color(Layer::AboveText, 0x000000_FF);
-        if editor.is_cursor_shown()
-            && matrix_editing.is_none()
-            && ((cursor_pos.column as isize + r.cursor_render_x_offset) as usize)
-                <= gr.current_editor_width
+    fn insert_line_ref<'b>(
+        &mut self,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        line_ref_row: ContentIndex,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        let cursor_row = self.editor.get_selection().get_cursor_pos().row;
+        if cursor_row == line_ref_row.as_usize()
+            || matches!(&results[line_ref_row], Err(_) | Ok(None))
         {
-            render_buckets.draw_char(
-                Layer::AboveText,
-                ((cursor_pos.column + gr.left_gutter_width) as isize + r.cursor_render_x_offset)
-                    as usize,
-                r.render_y.add(r.vert_align_offset),
-                '▏',
+            self.generate_render_commands_and_fill_editor_objs(
+                units,
+                render_buckets,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                BitFlag128::empty(),
             );
+            return;
         }
-    }
-}
-
-pub fn pulse_modified_line_references(
-    render_buckets: &mut RenderBuckets,
-    gr: &GlobalRenderData,
-    updated_line_ref_obj_indices: &[EditorObjId],
-    editor_objects: &EditorObjects,
-) {
-    // Pulsing changed line references
-    for id in updated_line_ref_obj_indices {
-        for ed_obj in &editor_objects[id.content_index] {
-            match ed_obj {
-                EditorObject {
-                    typ: EditorObjectType::LineReference { var_index },
-                    rendered_x,
-                    rendered_y,
-                    rendered_w,
-                    rendered_h,
-                    ..
-                } if *var_index == id.var_index => {
-                    render_buckets.custom_commands[Layer::AboveText as usize].push(
-                        OutputMessage::PulsingRectangle {
-                            x: gr.left_gutter_width + *rendered_x,
-                            y: *rendered_y,
-                            w: *rendered_w,
-                            h: *rendered_h,
-                            start_color: CHANGE_RESULT_PULSE_START_COLOR,
-                            end_color: CHANGE_RESULT_PULSE_END_COLOR,
-                            animation_time: Duration::from_millis(2000),
-                        },
+        // the referenced line has a result, so it is inside the window
+        if let Some(var) = &vars[line_ref_row.as_usize() - results.window_y()] {
+            let pos = self.editor.get_selection().get_cursor_pos();
+            if pos.column > 0 {
+                let prev_ch = self.editor_content.get_char(pos.row, pos.column - 1);
+                let prev_token_is_lineref = pos.column > 3 /* smallest lineref is 4 char long '&[1]'*/
+                    && self.editor_content.get_char(pos.row, pos.column - 1) == ']' && {
+                    let mut i = (pos.column - 2) as  isize;
+                    while i > 1 {
+                        if NOT(self.editor_content.get_char(pos.row, i as usize).is_ascii_digit()) {
+                            break;
+                        }
+                        i -= 1;
+                    }
+                    i > 0
+                        && self.editor_content.get_char(pos.row, i as usize) == '['
+                        && self.editor_content.get_char(pos.row, (i-1) as usize) == '&'
+                };
+                if prev_ch.is_alphanumeric() || prev_ch == '_' || prev_token_is_lineref {
+                    self.editor.handle_input(
+                        EditorInputEvent::Char(' '),
+                        InputModifiers::none(),
+                        &mut self.editor_content,
                     );
                 }
-                _ => {}
             }
-        }
-    }
-}
-
-pub fn pulse_editor_objs_referencing_current_line(
-    render_buckets: &mut RenderBuckets,
-    gr: &GlobalRenderData,
-    editor_objs_referencing_current_line: &[EditorObjId],
-    editor_objects: &EditorObjects,
-) {
-    for id in editor_objs_referencing_current_line {
-        for ed_obj in &editor_objects[id.content_index] {
-            match ed_obj {
-                EditorObject {
-                    typ: EditorObjectType::LineReference { var_index },
-                    ..
-                }
-                | EditorObject {
-                    typ: EditorObjectType::Variable { var_index },
-                    ..
-                } if *var_index == id.var_index => {
-                    let end_editor_x = gr.current_editor_width + gr.left_gutter_width + 1;
-                    if gr.is_visible(ed_obj.row) {
-                        let rendered_row_height = gr.get_rendered_height(ed_obj.row);
-                        let vert_align_offset = (rendered_row_height - ed_obj.rendered_h) / 2;
-                        let obj_start_x =
-                            (gr.left_gutter_width + ed_obj.rendered_x).min(end_editor_x - 1);
-                        let obj_end_x = (obj_start_x + ed_obj.rendered_w).min(end_editor_x);
-                        render_buckets.custom_commands[Layer::AboveText as usize].push(
-                            OutputMessage::PulsingRectangle {
-                                x: obj_start_x,
-                                y: ed_obj.rendered_y.add(vert_align_offset),
-                                w: obj_end_x - obj_start_x,
-                                h: ed_obj.rendered_h,
-                                start_color: REFERENCE_PULSE_PULSE_START_COLOR,
-                                end_color: 0x00FF7F_00,
-                                animation_time: Duration::from_millis(1000),
-                            },
-                        );
-                    }
-                }
-                _ => {}
+            for ch in var.name.iter() {
+                self.editor.handle_input(
+                    EditorInputEvent::Char(*ch),
+                    InputModifiers::none(),
+                    &mut self.editor_content,
+                );
             }
+        } else {
+            let line_id = {
+                let line_data = self.editor_content.get_data(line_ref_row.as_usize());
+                line_data.line_id
+            };
+
+            let inserting_text = format!("&[{}]", line_id);
+            self.editor
+                .insert_text(&inserting_text, &mut self.editor_content);
         }
-    }
-}
 
-pub fn pulse_changed_results(
-    render_buckets: &mut RenderBuckets,
-    gr: &GlobalRenderData,
-    longest_rendered_result_len: usize,
-    result_change_flag: &BitFlag128,
-) {
-    if gr.get_render_y(content_y(0)).is_none() {
-        // there were no render yet
-        return;
+        self.process_and_render_tokens(
+            RowModificationType::SingleLine(cursor_row),
+            units,
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            render_buckets,
+        );
     }
-    // TODO iter through only visible rows
-    // Pulsing changed results
-    for i in 0..MAX_LINE_COUNT {
-        if result_change_flag.is_true(i) {
-            if let Some(render_y) = gr.get_render_y(content_y(i)) {
-                render_buckets.custom_commands[Layer::AboveText as usize].push(
-                    OutputMessage::PulsingRectangle {
-                        x: gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
-                        y: render_y,
-                        w: longest_rendered_result_len,
-                        h: gr.get_rendered_height(content_y(i)),
-                        start_color: CHANGE_RESULT_PULSE_START_COLOR,
-                        end_color: CHANGE_RESULT_PULSE_END_COLOR,
-                        animation_time: Duration::from_millis(1000),
-                    },
+
+    /// Inserts the text literally as a single (undoable) editor command,
+    /// without the per-character handling of `handle_input` (e.g. stepping into a matrix).
+    /// It is the text input of the editor: `Editor::insert_text` executes one
+    /// `EditorCommand::InsertText` (`InsertTextSelection` over a selection) and never goes
+    /// through `Editor::handle_input`, so no auto-closing or matrix editing is triggered
+    /// by the inserted characters.
+    pub fn handle_paste<'b>(
+        &mut self,
+        text: String,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        let prev_row = self.editor.get_selection().get_cursor_pos().row;
+        match self.editor.insert_text(&text, &mut self.editor_content) {
+            Some(modif) => {
+                let cursor_pos = self.editor.get_selection().get_cursor_pos();
+                let scroll_y =
+                    get_scroll_y_after_cursor_movement(prev_row, cursor_pos.row, &self.render_data);
+                if let Some(scroll_y) = scroll_y {
+                    self.render_data.scroll_y = scroll_y;
+                }
+                self.process_and_render_tokens(
+                    modif,
+                    units,
+                    allocator,
+                    tokens,
+                    results,
+                    vars,
+                    editor_objs,
+                    render_buckets,
                 );
             }
-        }
+            None => {}
+        };
     }
-}
 
-pub fn parse_tokens<'b>(
-    line: &[char],
-    editor_y: usize,
-    units: &Units,
-    vars: &Variables,
-    allocator: &'b Bump,
-) -> Tokens<'b> {
-    // TODO optimize vec allocations
-    let mut tokens = Vec::with_capacity(128);
-    TokenParser::parse_line(line, &vars, &mut tokens, &units, editor_y, allocator);
+    /// Replaces the selected part of a line with `name` and defines `name` as that
+    /// expression in a new line above it, e.g. selecting "3 * 4" in "2 + 3 * 4" results in
+    /// "t = (3 * 4)\n2 + t". It does nothing if the selection is empty or spans multiple lines,
+    /// if `name` is not a new identifier or if the replacement would change the line's result
+    /// (e.g. selecting "2 + 3" in "2 + 3 * 4").
+    pub fn extract_to_variable<'b>(
+        &mut self,
+        name: &str,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        let (start, end) = match self.editor.get_selection().is_range() {
+            Some((start, end)) if start.row == end.row => (start, end),
+            _ => return,
+        };
+        let name = name.trim();
+        let expr: String = self.editor_content.get_line_valid_chars(start.row)
+            [start.column..end.column]
+            .iter()
+            .collect();
+        let expr = expr.trim();
+        // the line has to be evaluated, so it must be in the window
+        if expr.is_empty()
+            || !self
+                .render_data
+                .window_rows(self.editor_content.line_count())
+                .contains(&start.row)
+            || self.matrix_editing.is_some()
+        {
+            return;
+        }
+        let name_chars: Vec<char> = name.chars().collect();
+        let is_identifier = name_chars
+            .first()
+            .map(|it| it.is_alphabetic() || *it == '_')
+            .unwrap_or(false)
+            && name_chars
+                .iter()
+                .all(|it| it.is_alphanumeric() || *it == '_');
+        let is_taken = FnType::value_of(&name_chars).is_some()
+            || name == "sum"
+            || vars.iter().any(|var| {
+                var.as_ref()
+                    .map(|var| *var.name == name_chars[..])
+                    .unwrap_or(false)
+            });
+        if !is_identifier || is_taken {
+            return;
+        }
+        let definition = format!("{} = ({})\n", name, expr);
 
-    // TODO: measure is 128 necessary?
-    // and remove allocation
-    let mut shunting_output_stack = Vec::with_capacity(128);
-    ShuntingYard::shunting_yard(&mut tokens, &mut shunting_output_stack);
-    Tokens {
-        tokens,
-        shunting_output_stack,
+        // the line has to evaluate to the same result with the new variable
+        let line = self.editor_content.get_line_valid_chars(start.row);
+        let mut new_line: Vec<char> = line[..start.column].to_vec();
+        new_line.extend_from_slice(&name_chars);
+        new_line.extend_from_slice(&line[end.column..]);
+        let paren_expr: Vec<char> = format!("({})", expr).chars().collect();
+        let eval = |text: &[char], vars: &Variables, editor_y: usize| {
+            evaluate_text(
+                units,
+                text,
+                vars,
+                &self.editor_content,
+                &self.native_fns,
+                &mut Vec::with_capacity(32),
+                editor_y,
+                self.render_data.window_y(),
+                allocator,
+                self.rounding_mode,
+                self.angle_mode,
+            )
+        };
+        let new_var_value = match eval(&paren_expr, &*vars, start.row) {
+            Ok(Some(result)) => result.result,
+            _ => return,
+        };
+        let mut vars_with_new_var: Vec<Option<Variable>> = vars
+            .iter()
+            .map(|var| {
+                var.as_ref().map(|var| Variable {
+                    name: var.name.clone(),
+                    value: var.value.clone(),
+                })
+            })
+            .collect();
+        vars_with_new_var[start.row - self.render_data.window_y()] = Some(Variable {
+            name: Box::from(&name_chars[..]),
+            value: Ok(new_var_value),
+        });
+        let result_is_kept = match (
+            eval(line, &*vars, start.row),
+            eval(&new_line, &vars_with_new_var, start.row + 1),
+        ) {
+            (Ok(Some(old)), Ok(Some(new))) => old.result.typ == new.result.typ,
+            _ => false,
+        };
+        if !result_is_kept {
+            return;
+        }
+        // the two insertions are undone together
+        self.editor.start_new_undo_group(&mut self.editor_content);
+        if self
+            .editor
+            .insert_text(name, &mut self.editor_content)
+            .is_none()
+        {
+            // e.g. the line is locked
+            return;
+        }
+        self.editor.set_cursor_pos_r_c(start.row, 0);
+        self.editor
+            .insert_text(&definition, &mut self.editor_content);
+        self.editor
+            .set_cursor_pos_r_c(start.row + 1, start.column + name.chars().count());
+        self.process_and_render_tokens(
+            RowModificationType::AllLinesFrom(start.row),
+            units,
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            render_buckets,
+        );
     }
-}
-
-fn render_simple_text_line<'text_ptr>(
-    line: &[char],
-    r: &mut PerLineRenderData,
-    gr: &mut GlobalRenderData,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    allocator: &'text_ptr Bump,
-) {
-    r.set_fix_row_height(1);
-    gr.set_rendered_height(r.editor_y, 1);
-
-    let text_len = line.len().min(gr.current_editor_width);
-
-    render_buckets.utf8_texts.push(RenderUtf8TextMsg {
-        text: allocator.alloc_slice_fill_iter(line.iter().map(|it| *it).take(text_len)),
-        row: r.render_y,
-        column: gr.left_gutter_width,
-    });
 
-    r.token_render_done(text_len, text_len, 0);
-}
+    /// Re-evaluates the lines from `row` to the end of the document (and the lines
+    /// depending on them), e.g. after the host has changed an external variable
+    /// which is used only from that line.
+    pub fn recalculate_from_row<'b>(
+        &mut self,
+        row: usize,
+        allocator: &'b Bump,
+        units: &Units,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        let row = row.min(self.editor_content.line_count() - 1);
+        self.process_and_render_tokens(
+            RowModificationType::AllLinesFrom(row),
+            units,
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            render_buckets,
+        );
+    }
 
-#[inline]
-fn highlight_line_ref_background<'text_ptr>(
-    editor_objs: &Vec<EditorObject>,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    r: &PerLineRenderData,
-    gr: &GlobalRenderData,
-) {
-    for editor_obj in editor_objs.iter() {
-        if matches!(editor_obj.typ, EditorObjectType::LineReference{..}) {
-            let vert_align_offset = (r.rendered_row_height - editor_obj.rendered_h) / 2;
-            render_buckets.set_color(Layer::BehindText, LINE_REF_BACKGROUND_COLOR);
-            render_buckets.draw_rect(
-                Layer::BehindText,
-                gr.left_gutter_width + editor_obj.rendered_x,
-                editor_obj.rendered_y.add(vert_align_offset),
-                editor_obj.rendered_w,
-                editor_obj.rendered_h,
-            );
-        }
+    pub fn reparse_everything<'b, 'q>(
+        &'q mut self,
+        allocator: &'b Bump,
+        units: &Units,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        self.process_and_render_tokens(
+            RowModificationType::AllLinesFrom(0),
+            units,
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            render_buckets,
+        );
     }
-}
 
-#[inline]
-fn highlight_active_line_refs<'text_ptr>(
-    editor_objs: &[EditorObject],
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    r: &PerLineRenderData,
-    gr: &GlobalRenderData,
-) {
-    let mut color_index = 0;
-    let mut colors: [Option<u32>; MAX_LINE_COUNT] = [None; MAX_LINE_COUNT];
-    for editor_obj in editor_objs.iter() {
-        match editor_obj.typ {
-            EditorObjectType::LineReference { var_index }
-            | EditorObjectType::Variable { var_index }
-                if var_index != SUM_VARIABLE_INDEX =>
-            {
-                let color = if let Some(color) = colors[var_index] {
-                    color
+    pub fn handle_input<'b, 'q>(
+        &'q mut self,
+        input: EditorInputEvent,
+        modifiers: InputModifiers,
+        allocator: &'b Bump,
+        units: &Units,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) -> Option<RowModificationType> {
+        fn handle_input_with_alt<'b>(
+            app: &mut NoteCalcApp,
+            input: EditorInputEvent,
+            modifiers: InputModifiers,
+        ) -> Option<RowModificationType> {
+            let format_of_all_lines = match input {
+                _ if !modifiers.shift => None,
+                EditorInputEvent::Char('h') | EditorInputEvent::Char('H') => {
+                    Some(ResultFormat::Hex)
+                }
+                EditorInputEvent::Char('b') | EditorInputEvent::Char('B') => {
+                    Some(ResultFormat::Bin)
+                }
+                EditorInputEvent::Char('d') | EditorInputEvent::Char('D') => {
+                    Some(ResultFormat::Dec)
+                }
+                _ => None,
+            };
+            if let Some(new_format) = format_of_all_lines {
+                // Alt+Shift+H/B/D sets the format of the whole document
+                for row_i in 0..app.editor_content.line_count() {
+                    app.editor_content.mut_data(row_i).result_format = new_format;
+                }
+                None
+            } else if input == EditorInputEvent::Left {
+                let selection = app.editor.get_selection();
+                let (start, end) = selection.get_range();
+                for row_i in start.row..=end.row {
+                    let new_format = match &app.editor_content.get_data(row_i).result_format {
+                        ResultFormat::Bin => ResultFormat::Oct,
+                        ResultFormat::Oct => ResultFormat::Hex,
+                        ResultFormat::Dec => ResultFormat::Bin,
+                        ResultFormat::Hex => ResultFormat::Sci,
+                        ResultFormat::Sci => ResultFormat::Dec,
+                    };
+                    app.editor_content.mut_data(row_i).result_format = new_format;
+                }
+                None
+            } else if input == EditorInputEvent::Right {
+                let selection = app.editor.get_selection();
+                let (start, end) = selection.get_range();
+                for row_i in start.row..=end.row {
+                    let new_format = match &app.editor_content.get_data(row_i).result_format {
+                        ResultFormat::Bin => ResultFormat::Dec,
+                        ResultFormat::Dec => ResultFormat::Sci,
+                        ResultFormat::Sci => ResultFormat::Hex,
+                        ResultFormat::Hex => ResultFormat::Oct,
+                        ResultFormat::Oct => ResultFormat::Bin,
+                    };
+                    app.editor_content.mut_data(row_i).result_format = new_format;
+                }
+                None
+            } else if input == EditorInputEvent::Up {
+                let cur_pos = app.editor.get_selection().get_cursor_pos();
+                let rows = if let Some(selector_row) = app.line_reference_chooser {
+                    if selector_row.as_usize() > 0 {
+                        Some((selector_row.as_usize(), selector_row.as_usize() - 1))
+                    } else {
+                        Some((selector_row.as_usize(), selector_row.as_usize()))
+                    }
+                } else if cur_pos.row > 0 {
+                    Some((cur_pos.row - 1, cur_pos.row - 1))
                 } else {
-                    let color = ACTIVE_LINE_REF_HIGHLIGHT_COLORS[color_index] << 8 | 0x55;
-                    colors[var_index] = Some(color);
-                    color_index = if color_index < 8 { color_index + 1 } else { 0 };
-                    color
+                    None
                 };
-
-                let vert_align_offset = (r.rendered_row_height - editor_obj.rendered_h) / 2;
-                render_buckets.set_color(Layer::BehindText, color);
-                render_buckets.draw_rect(
-                    Layer::BehindText,
-                    gr.left_gutter_width + editor_obj.rendered_x,
-                    editor_obj.rendered_y.add(vert_align_offset),
-                    editor_obj.rendered_w,
-                    editor_obj.rendered_h,
-                );
+                if let Some((_, new_selected_row)) = rows {
+                    app.line_reference_chooser = Some(content_y(new_selected_row));
+                    None
+                } else {
+                    None
+                }
+            } else if input == EditorInputEvent::Down {
+                let cur_pos = app.editor.get_selection().get_cursor_pos();
+                let rows = if let Some(selector_row) = app.line_reference_chooser {
+                    if selector_row.as_usize() < cur_pos.row - 1 {
+                        Some((selector_row.as_usize(), selector_row.as_usize() + 1))
+                    } else {
+                        Some((selector_row.as_usize(), selector_row.as_usize()))
+                    }
+                } else {
+                    None
+                };
+                if let Some((_prev_selected_row, new_selected_row)) = rows {
+                    app.line_reference_chooser = Some(content_y(new_selected_row));
+                    None
+                } else {
+                    None
+                }
+            } else {
+                None
             }
-            _ => {}
         }
-    }
-}
-
-fn render_tokens<'text_ptr>(
-    tokens: &[Token<'text_ptr>],
-    r: &mut PerLineRenderData,
-    gr: &mut GlobalRenderData,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    editor_objects: &mut Vec<EditorObject>,
-    editor: &Editor,
-    matrix_editing: &Option<MatrixEditing>,
-    vars: &Variables,
-    units: &Units,
-    need_matrix_renderer: bool,
-    decimal_count: Option<usize>,
-) {
-    editor_objects.clear();
-    let cursor_pos = editor.get_selection().get_cursor_pos();
-
-    let mut token_index = 0;
-    while token_index < tokens.len() {
-        let token = &tokens[token_index];
 
-        if !need_matrix_renderer {
-            simple_draw(r, gr, render_buckets, editor_objects, token);
-            token_index += 1;
-        } else {
-            match &token.typ {
-                TokenType::Operator(OperatorTokenType::Matrix {
-                    row_count,
-                    col_count,
-                }) => {
-                    token_index = render_matrix(
-                        token_index,
-                        &tokens,
-                        *row_count,
-                        *col_count,
-                        r,
-                        gr,
-                        render_buckets,
-                        editor_objects,
-                        &editor,
-                        &matrix_editing,
-                        decimal_count,
-                    );
-                }
-                TokenType::Variable { var_index } => {
-                    editor_objects.push(EditorObject {
-                        typ: EditorObjectType::Variable {
-                            var_index: *var_index,
-                        },
-                        row: r.editor_y,
-                        start_x: r.editor_x,
-                        end_x: r.editor_x + token.ptr.len(),
-                        rendered_x: r.render_x,
-                        rendered_y: r.render_y,
-                        rendered_w: token.ptr.len(),
-                        rendered_h: r.rendered_row_height,
-                    });
-                    draw_token(
-                        token,
-                        r.render_x,
-                        r.render_y.add(r.vert_align_offset),
-                        gr.current_editor_width,
-                        gr.left_gutter_width,
-                        render_buckets,
+        ////////////////////////////////////////////////////
+        ////////////////////////////////////////////////////
+        ////////////////////////////////////////////////////
+        let prev_row = self.editor.get_selection().get_cursor_pos().row;
+        let modif = if self.matrix_editing.is_none() && modifiers.alt {
+            handle_input_with_alt(&mut *self, input, modifiers)
+        } else if self.matrix_editing.is_some() {
+            self.handle_matrix_editor_input(input, modifiers);
+            if self.matrix_editing.is_none() {
+                // user left a matrix
+                Some(RowModificationType::SingleLine(prev_row))
+            } else {
+                if modifiers.alt {
+                    let y = content_y(prev_row);
+                    let new_h = calc_rendered_height(
+                        y,
+                        &self.matrix_editing,
+                        tokens,
+                        results,
+                        vars,
+                        self.editor_content.get_data(prev_row).collapsed_matrices,
                     );
+                    self.render_data.set_rendered_height(y, new_h);
+                };
+                None
+            }
+        } else if self.input_would_modify_locked_line(&input, modifiers) {
+            None
+        } else if self.input_would_exceed_line_limit(&input) {
+            None
+        } else if self.handle_completion(&input, editor_objs, vars) {
+            Some(RowModificationType::SingleLine(prev_row))
+        } else if let Some(modif_type) = self.handle_obj_deletion(&input, editor_objs) {
+            Some(modif_type)
+        } else if matches!(
+            input,
+            EditorInputEvent::Char('c') | EditorInputEvent::Char('C')
+        ) && modifiers.is_ctrl_shift()
+        {
+            // copies the result even if there is a selection
+            self.copy_result_of_current_line_to_clipboard(units, results);
+            None
+        } else if input == EditorInputEvent::Char('c')
+            && modifiers.ctrl
+            && self.editor.get_selection().is_range().is_none()
+        {
+            self.copy_result_of_current_line_to_clipboard(units, results);
+            None
+        } else if input == EditorInputEvent::Char('b') && modifiers.ctrl {
+            self.handle_jump_to_definition(&input, modifiers, editor_objs);
+            None
+        } else if input == EditorInputEvent::Char('m') && modifiers.ctrl {
+            self.toggle_matrix_collapse(editor_objs)
+        } else if self.handle_obj_jump_over(&input, modifiers, editor_objs) {
+            None
+        } else {
+            let prev_selection = self.editor.get_selection();
+            let prev_cursor_pos = prev_selection.get_cursor_pos();
 
-                    token_index += 1;
-                    r.token_render_done(token.ptr.len(), token.ptr.len(), 0);
+            // if the cursor is inside a matrix, put it afterwards
+            if let Some(obj) = self.get_obj_at_inside(
+                prev_cursor_pos.column,
+                content_y(prev_cursor_pos.row),
+                editor_objs,
+            ) {
+                match obj.typ {
+                    EditorObjectType::Matrix { .. } => self
+                        .editor
+                        .set_cursor_pos_r_c(obj.row.as_usize(), obj.end_x),
+                    _ => {}
                 }
-                TokenType::LineReference { var_index } => {
-                    let var = vars[*var_index].as_ref().unwrap();
-
-                    let (rendered_width, rendered_height) = render_result_inside_editor(
-                        units,
-                        render_buckets,
-                        &var.value,
-                        r,
-                        gr,
-                        decimal_count,
-                    );
-
-                    let var_name_len = var.name.len();
-                    editor_objects.push(EditorObject {
-                        typ: EditorObjectType::LineReference {
-                            var_index: *var_index,
-                        },
-                        row: r.editor_y,
-                        start_x: r.editor_x,
-                        end_x: r.editor_x + var_name_len,
-                        rendered_x: r.render_x,
-                        rendered_y: r.render_y,
-                        rendered_w: rendered_width,
-                        rendered_h: rendered_height,
-                    });
+            }
+            let modif_type = self
+                .editor
+                .handle_input(input, modifiers, &mut self.editor_content);
 
-                    token_index += 1;
-                    r.token_render_done(
-                        var_name_len,
-                        rendered_width,
-                        if cursor_pos.column > r.editor_x {
-                            let diff = rendered_width as isize - var_name_len as isize;
-                            diff
-                        } else {
-                            0
-                        },
-                    );
-                }
-                TokenType::StringLiteral
-                | TokenType::Header
-                | TokenType::NumberLiteral(_)
-                | TokenType::Operator(_)
-                | TokenType::Unit(_)
-                | TokenType::NumberErr => {
-                    simple_draw(r, gr, render_buckets, editor_objects, token);
-                    token_index += 1;
+            if modif_type.is_none() {
+                // it is possible to step into a matrix only through navigation
+                self.check_stepping_into_matrix(prev_cursor_pos, editor_objs);
+                // if there was no selection but now there is
+                if prev_selection.is_range().is_none()
+                    && self.editor.get_selection().is_range().is_some()
+                {
+                    self.normalize_line_refs_in_place();
+                    Some(RowModificationType::AllLinesFrom(0))
+                } else {
+                    modif_type
                 }
+            } else {
+                modif_type
             }
-        }
-    }
-}
-
-fn simple_draw<'text_ptr>(
-    r: &mut PerLineRenderData,
-    gr: &mut GlobalRenderData,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    editor_objects: &mut Vec<EditorObject>,
-    token: &Token<'text_ptr>,
-) {
-    if let Some(EditorObject {
-        typ: EditorObjectType::SimpleTokens,
-        end_x,
-        rendered_w,
-        ..
-    }) = editor_objects.last_mut()
-    {
-        // last token was a simple token too, extend it
-        *end_x += token.ptr.len();
-        *rendered_w += token.ptr.len();
-    } else {
-        editor_objects.push(EditorObject {
-            typ: EditorObjectType::SimpleTokens,
-            row: r.editor_y,
-            start_x: r.editor_x,
-            end_x: r.editor_x + token.ptr.len(),
-            rendered_x: r.render_x,
-            rendered_y: r.render_y,
-            rendered_w: token.ptr.len(),
-            rendered_h: r.rendered_row_height,
-        });
-    }
-    draw_token(
-        token,
-        r.render_x,
-        r.render_y.add(r.vert_align_offset),
-        gr.current_editor_width,
-        gr.left_gutter_width,
-        render_buckets,
-    );
+        };
 
-    r.token_render_done(token.ptr.len(), token.ptr.len(), 0);
-}
+        let cursor_pos = self.editor.get_selection().get_cursor_pos();
+        let scroll_y =
+            get_scroll_y_after_cursor_movement(prev_row, cursor_pos.row, &self.render_data);
+        if let Some(scroll_y) = scroll_y {
+            self.render_data.scroll_y = scroll_y;
+        }
 
-fn render_wrap_dots(
-    render_buckets: &mut RenderBuckets,
-    r: &PerLineRenderData,
-    gr: &GlobalRenderData,
-) {
-    if r.render_x > gr.current_editor_width {
-        // gutter is above text so it has to be abovetext as well
-        render_buckets.set_color(Layer::AboveText, 0x000000_FF);
-        for y in 0..r.rendered_row_height {
-            render_buckets.draw_char(
-                Layer::AboveText,
-                gr.result_gutter_x - 1,
-                r.render_y.add(y),
-                '…',
+        if let Some(modif) = modif {
+            self.process_and_render_tokens(
+                modif,
+                units,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                render_buckets,
+            );
+        } else if self.calc_window_y() != self.render_data.window_y() {
+            // the cursor or the scrolling left the evaluated lines
+            self.process_and_render_tokens(
+                RowModificationType::AllLinesFrom(0),
+                units,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                render_buckets,
+            );
+        } else {
+            self.generate_render_commands_and_fill_editor_objs(
+                units,
+                render_buckets,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                BitFlag128::empty(),
+            );
+            self.set_editor_and_result_panel_widths_and_rerender_if_necessary(
+                units,
+                render_buckets,
+                allocator,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                BitFlag128::empty(),
             );
         }
+
+        return modif;
     }
-}
 
-fn draw_line_ref_chooser(
-    render_buckets: &mut RenderBuckets,
-    r: &PerLineRenderData,
-    gr: &GlobalRenderData,
-    line_reference_chooser: &Option<ContentIndex>,
-    result_gutter_x: usize,
-) {
-    if let Some(selection_row) = line_reference_chooser {
-        if *selection_row == r.editor_y {
-            render_buckets.set_color(Layer::Text, 0xFFCCCC_FF);
-            render_buckets.draw_rect(
-                Layer::Text,
-                0,
-                r.render_y,
-                result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width,
-                r.rendered_row_height,
-            );
+    pub fn process_and_render_tokens<'b>(
+        &mut self,
+        input_effect: RowModificationType,
+        units: &Units,
+        allocator: &'b Bump,
+        tokens: &mut AppTokens<'b>,
+        results: &mut Results,
+        vars: &mut Variables,
+        editor_objs: &mut EditorObjects,
+        render_buckets: &mut RenderBuckets<'b>,
+    ) {
+        // only the lines of the window are evaluated, when it moves, all of them are new
+        let window_y = self.calc_window_y();
+        let window_moved = window_y != self.render_data.window_y();
+        if window_moved {
+            self.move_window(window_y, tokens, results, vars, editor_objs);
         }
-    }
-}
 
-fn draw_right_gutter_num_prefixes(
-    render_buckets: &mut RenderBuckets,
-    result_gutter_x: usize,
-    editor_content: &EditorContent<LineData>,
-    r: &PerLineRenderData,
-) {
-    match editor_content.get_data(r.editor_y.as_usize()).result_format {
-        ResultFormat::Hex => {
-            render_buckets.set_color(Layer::AboveText, 0x000000_FF);
-            render_buckets.draw_text(Layer::AboveText, result_gutter_x, r.render_y, &['0', 'x']);
+        if self.exchange_rates_changed {
+            units.set_exchange_rates(&self.exchange_rates);
+            self.exchange_rates_changed = false;
         }
-        ResultFormat::Bin => {
-            render_buckets.set_color(Layer::AboveText, 0x000000_FF);
-            render_buckets.draw_text(Layer::AboveText, result_gutter_x, r.render_y, &['0', 'b']);
+
+        if !self.sum_enabled {
+            vars[SUM_VARIABLE_INDEX] = None;
+        } else if vars[SUM_VARIABLE_INDEX].is_none() {
+            vars[SUM_VARIABLE_INDEX] = Some(Variable {
+                name: Box::from(&['s', 'u', 'm'][..]),
+                value: Err(()),
+            });
         }
-        ResultFormat::Dec => {}
-    }
-}
 
-fn highlight_current_line(
-    render_buckets: &mut RenderBuckets,
-    r: &PerLineRenderData,
-    editor: &Editor,
-    gr: &GlobalRenderData,
-) {
-    let cursor_pos = editor.get_selection().get_cursor_pos();
-    if cursor_pos.row == r.editor_y.as_usize() {
-        render_buckets.set_color(Layer::Text, 0xFFFFCC_55);
-        render_buckets.draw_rect(
-            Layer::Text,
-            0,
-            r.render_y,
-            gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width,
-            r.rendered_row_height,
-        );
-    }
-}
+        for i in 0..MAX_EXTERNAL_VAR_COUNT {
+            let var = self.external_vars.get(i).map(|(name, value)| {
+                let result = evaluate_text(
+                    units,
+                    value,
+                    vars,
+                    &self.editor_content,
+                    &self.native_fns,
+                    &mut Vec::new(),
+                    0,
+                    0,
+                    allocator,
+                    self.rounding_mode,
+                    self.angle_mode,
+                );
+                Variable {
+                    name: name.clone(),
+                    value: match result {
+                        Ok(Some(result)) => Ok(result.result),
+                        _ => Err(()),
+                    },
+                }
+            });
+            vars[FIRST_EXTERNAL_VAR_INDEX + i] = var;
+        }
 
-fn evaluate_tokens_and_save_result<'text_ptr>(
-    vars: &mut Variables,
-    editor_y: usize,
-    editor_content: &EditorContent<LineData>,
-    tokens: &mut [Token<'text_ptr>],
-    shunting_output_stack: &mut Vec<ShuntingYardResult>,
-    line: &[char],
-) -> Result<Option<EvaluationResult>, ()> {
-    let result = evaluate_tokens(tokens, shunting_output_stack, &vars);
-    if let Ok(Some(result)) = &result {
-        fn replace_or_insert_var(
-            vars: &mut Variables,
-            var_name: &[char],
-            result: CalcResult,
-            editor_y: usize,
-        ) {
-            if let Some(var) = &mut vars[editor_y] {
-                var.name = Box::from(var_name);
-                var.value = Ok(result);
+        // the lines of the window can use everything defined above it
+        let input_effect =
+            if self.evaluate_lines_above_window(input_effect, window_moved, units, vars) {
+                RowModificationType::AllLinesFrom(0)
             } else {
-                vars[editor_y] = Some(Variable {
-                    name: Box::from(var_name),
-                    value: Ok(result),
-                });
+                input_effect
             };
-        }
 
-        if result.assignment {
-            let var_name = {
-                let mut i = 0;
-                if line[0] == '=' {
-                    // it might happen that there are more '=' in a line.
-                    // To avoid panic, start the index from 1, so if the first char is
-                    // '=', it will be ignored.
-                    i += 1;
+        // The references of the recalculated lines are known only after their tokenization,
+        // so if the cycles turn out to be different, the lines which entered or left a cycle
+        // are recalculated in a second pass. The references depend only on the text,
+        // so the second pass does not change the cycles.
+        let mut line_ref_cycle_rows = self.line_ref_cycle_rows;
+        let mut cycle_rows_to_recalc = BitFlag128::empty();
+        let mut first_pass = true;
+        let mut result_change_flag = BitFlag128::empty();
+        loop {
+            let mut sum_is_null = match (&self.above_window_sum, vars[SUM_VARIABLE_INDEX].as_mut())
+            {
+                (Some(sum), Some(sum_var)) => {
+                    sum_var.value = sum.clone();
+                    false
                 }
-                // skip whitespaces
-                while line[i].is_ascii_whitespace() {
-                    i += 1;
+                _ => true,
+            };
+            let mut dependant_rows = BitFlag128::empty();
+            for editor_y in self
+                .render_data
+                .window_rows(self.editor_content.line_count())
+            {
+                let var_index = editor_y - window_y;
+                let is_modified = match input_effect {
+                    RowModificationType::SingleLine(to_change_index) => to_change_index == editor_y,
+                    RowModificationType::AllLinesFrom(to_change_index_from) => {
+                        editor_y >= to_change_index_from
+                    }
+                };
+                let recalc = (first_pass && is_modified)
+                    || dependant_rows.is_true(var_index)
+                    || cycle_rows_to_recalc.is_true(var_index);
+                if recalc && editor_y >= self.evaluated_line_count() {
+                    // the lines past the line limit are shown as text
+                    let y = content_y(editor_y);
+                    tokens[y] = None;
+                    results[y] = Ok(None);
+                    vars[var_index] = None;
+                    self.render_data.set_rendered_height(y, 1);
+                } else if recalc {
+                    if self.editor_content.get_data(editor_y).line_id == 0 {
+                        self.editor_content.mut_data(editor_y).line_id = self.line_id_generator;
+                        self.line_id_generator += 1;
+                    }
+                    let y = content_y(editor_y);
+                    let prev_matrix_count = matrix_count(&tokens[y]);
+
+                    let (result_has_changed, rows_to_recalc) = eval_line(
+                        &self.editor_content,
+                        self.editor_content.get_line_valid_chars(editor_y),
+                        units,
+                        allocator,
+                        tokens,
+                        results,
+                        &mut *vars,
+                        y,
+                        window_y,
+                        &mut self.updated_line_ref_obj_indices,
+                        self.internal_scale,
+                        self.snap_tolerance,
+                        self.rounding_mode,
+                        self.angle_mode,
+                        &self.keyword_aliases,
+                        &self.native_fns,
+                    );
+                    let result_has_changed = if line_ref_cycle_rows.is_true(var_index) {
+                        results[y] = Err(());
+                        set_line_var_to_err(vars, editor_y, var_index, &self.editor_content);
+                        true
+                    } else {
+                        result_has_changed
+                    };
+                    self.editor_content.mut_data(editor_y).referenced_line_ids =
+                        find_referenced_line_ids(
+                            tokens[y].as_ref().map(|it| &it.tokens[..]).unwrap_or(&[]),
+                        );
+                    // the collapsed flags are indexed by the position of the matrices,
+                    // they would point to different matrices
+                    if prev_matrix_count.is_some() && prev_matrix_count != matrix_count(&tokens[y])
+                    {
+                        self.editor_content.mut_data(editor_y).collapsed_matrices = 0;
+                    }
+                    if result_has_changed {
+                        result_change_flag.merge(BitFlag128::single_row(var_index));
+                    }
+                    dependant_rows.merge(rows_to_recalc);
+                    let new_h = calc_rendered_height(
+                        y,
+                        &self.matrix_editing,
+                        tokens,
+                        results,
+                        vars,
+                        self.editor_content.get_data(editor_y).collapsed_matrices,
+                    );
+                    self.render_data.set_rendered_height(y, new_h);
                 }
-                let start = i;
-                // take until '='
-                while line[i] != '=' {
-                    i += 1;
+                let line = self.editor_content.get_line_valid_chars(editor_y);
+                // headers and empty lines separate the blocks of the sum
+                if line.starts_with(&['#']) || line.iter().all(|ch| ch.is_whitespace()) {
+                    sum_is_null = true;
                 }
-                // remove trailing whitespaces
-                i -= 1;
-                while i > start && line[i].is_ascii_whitespace() {
-                    i -= 1;
+
+                if let (Ok(Some(result)), Some(sum_var)) = (
+                    &results[content_y(editor_y)],
+                    vars[SUM_VARIABLE_INDEX].as_mut(),
+                ) {
+                    sum_result(sum_var, result, &mut sum_is_null);
                 }
-                let end = i;
-                &line[start..=end]
-            };
-            if !var_name.is_empty() {
-                replace_or_insert_var(vars, var_name, result.result.clone(), editor_y);
             }
-        } else {
-            let line_data = editor_content.get_data(editor_y);
-            debug_assert!(line_data.line_id > 0);
-            let line_id = line_data.line_id;
-            // TODO opt
-            let var_name: Vec<char> = format!("&[{}]", line_id).chars().collect();
-            replace_or_insert_var(vars, &var_name, result.result.clone(), editor_y);
-        }
-    } else if let Some(var) = &mut vars[editor_y] {
-        let line_data = editor_content.get_data(editor_y);
-        debug_assert!(line_data.line_id > 0);
-        let line_id = line_data.line_id;
-        // TODO opt
-        let var_name: Vec<char> = format!("&[{}]", line_id).chars().collect();
-        var.name = Box::from(var_name);
-        var.value = Err(());
-    } else {
-        vars[editor_y] = None;
-    }
-    result
-}
 
-fn sum_result(sum_var: &mut Variable, result: &CalcResult, sum_is_null: &mut bool) {
-    if *sum_is_null {
-        sum_var.value = Ok(result.clone());
-        *sum_is_null = false;
-    } else {
-        sum_var.value = match &sum_var.value {
-            Ok(current_sum) => {
-                if let Some(ok) = add_op(&current_sum, &result) {
-                    Ok(ok)
-                } else {
-                    Err(())
+            let new_cycle_rows = find_line_ref_cycles(&self.editor_content, window_y);
+            if !first_pass || new_cycle_rows.as_u128() == line_ref_cycle_rows.as_u128() {
+                break;
+            }
+            cycle_rows_to_recalc = BitFlag128::empty();
+            for row in 0..MAX_LINE_COUNT {
+                if new_cycle_rows.is_true(row) != line_ref_cycle_rows.is_true(row) {
+                    cycle_rows_to_recalc.set(row);
                 }
             }
-            _ => Err(()),
+            line_ref_cycle_rows = new_cycle_rows;
+            first_pass = false;
         }
-    }
-}
-
-fn render_matrix<'text_ptr>(
-    token_index: usize,
-    tokens: &[Token<'text_ptr>],
-    row_count: usize,
-    col_count: usize,
-    r: &mut PerLineRenderData,
-    gr: &mut GlobalRenderData,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    editor_objects: &mut Vec<EditorObject>,
-    editor: &Editor,
-    matrix_editing: &Option<MatrixEditing>,
-    // TODO: why unused?
-    _decimal_count: Option<usize>,
-) -> usize {
-    let mut text_width = 0;
-    let mut end_token_index = token_index;
-    while tokens[end_token_index].typ != TokenType::Operator(OperatorTokenType::BracketClose) {
-        text_width += tokens[end_token_index].ptr.len();
-        end_token_index += 1;
-    }
-    // ignore the bracket as well
-    text_width += 1;
-    end_token_index += 1;
+        self.line_ref_cycle_rows = line_ref_cycle_rows;
 
-    let cursor_pos = editor.get_selection().get_cursor_pos();
-    let cursor_inside_matrix: bool = if editor.get_selection().is_range().is_none()
-        && cursor_pos.row == r.editor_y.as_usize()
-        && cursor_pos.column > r.editor_x
-        && cursor_pos.column < r.editor_x + text_width
-    {
-        // cursor is inside the matrix
-        true
-    } else {
-        false
-    };
+        if self.editor_content.line_count() > 999 {
+            self.render_data
+                .set_left_gutter_width(LEFT_GUTTER_MIN_WIDTH + 3);
+        } else if self.editor_content.line_count() > 99 {
+            self.render_data
+                .set_left_gutter_width(LEFT_GUTTER_MIN_WIDTH + 2);
+        } else if self.editor_content.line_count() > 9 {
+            self.render_data
+                .set_left_gutter_width(LEFT_GUTTER_MIN_WIDTH + 1);
+        } else {
+            self.render_data
+                .set_left_gutter_width(LEFT_GUTTER_MIN_WIDTH);
+        }
 
-    let new_render_x = if let (true, Some(mat_editor)) = (cursor_inside_matrix, matrix_editing) {
-        mat_editor.render(
-            r.render_x,
-            r.render_y,
-            gr.current_editor_width,
-            gr.left_gutter_width,
+        self.generate_render_commands_and_fill_editor_objs(
+            units,
             render_buckets,
-            r.rendered_row_height,
-        )
-    } else {
-        render_matrix_obj(
-            r.render_x,
-            r.render_y,
-            gr.current_editor_width,
-            gr.left_gutter_width,
-            row_count,
-            col_count,
-            &tokens[token_index..],
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            result_change_flag,
+        );
+        self.set_editor_and_result_panel_widths_and_rerender_if_necessary(
+            units,
             render_buckets,
-            r.rendered_row_height,
-        )
-    };
-
-    let rendered_width = new_render_x - r.render_x;
-    editor_objects.push(EditorObject {
-        typ: EditorObjectType::Matrix {
-            row_count,
-            col_count,
-        },
-        row: r.editor_y,
-        start_x: r.editor_x,
-        end_x: r.editor_x + text_width,
-        rendered_x: r.render_x,
-        rendered_y: r.render_y,
-        rendered_w: rendered_width,
-        rendered_h: MatrixData::calc_render_height(row_count),
-    });
-
-    let x_diff = if cursor_pos.row == r.editor_y.as_usize()
-        && cursor_pos.column >= r.editor_x + text_width
-    {
-        let diff = rendered_width as isize - text_width as isize;
-        diff
-    } else {
-        0
-    };
-
-    r.token_render_done(text_width, rendered_width, x_diff);
-    return end_token_index;
-}
+            allocator,
+            tokens,
+            results,
+            vars,
+            editor_objs,
+            result_change_flag,
+        );
+    }
 
-fn evaluate_selection(
-    units: &Units,
-    editor: &Editor,
-    editor_content: &EditorContent<LineData>,
-    vars: &Variables,
-    results: &[LineResult],
-    allocator: &Bump,
-) -> Option<String> {
-    let sel = editor.get_selection();
-    // TODO optimize vec allocations
-    let mut tokens = Vec::with_capacity(128);
-    // TODO we should be able to mark the arena allcoator and free it at the end of the function
-    if sel.start.row == sel.end.unwrap().row {
-        if let Some(selected_text) = Editor::get_selected_text_single_line(sel, &editor_content) {
-            if let Ok(Some(result)) = evaluate_text(
+    fn set_editor_and_result_panel_widths_and_rerender_if_necessary<'b>(
+        &mut self,
+        units: &Units,
+        render_buckets: &mut RenderBuckets<'b>,
+        allocator: &'b Bump,
+        tokens: &AppTokens<'b>,
+        results: &Results,
+        vars: &Variables,
+        editor_objs: &mut EditorObjects,
+        result_change_flag: BitFlag128,
+    ) {
+        let current_result_g_x = self.render_data.result_gutter_x;
+        set_editor_and_result_panel_widths(
+            self.client_width,
+            self.result_panel_width_percent,
+            &mut self.render_data,
+        );
+        if self.render_data.result_gutter_x != current_result_g_x {
+            // HACKY
+            // we know the length of each line and results only after rendering them,
+            // so we can set the right gutter position only after a full render pass.
+            // If it turns out that the gutter has to/and can be moved,
+            // we rerender everything with the new gutter position.
+            self.generate_render_commands_and_fill_editor_objs(
                 units,
-                selected_text,
-                vars,
-                &mut tokens,
-                sel.start.row,
+                render_buckets,
                 allocator,
-            ) {
-                if result.there_was_operation {
-                    let result_str = render_result(
-                        &units,
-                        &result.result,
-                        &editor_content.get_data(sel.start.row).result_format,
-                        result.there_was_unit_conversion,
-                        Some(RENDERED_RESULT_PRECISION),
-                        true,
-                    );
-                    return Some(result_str);
-                }
-            }
-        }
-    } else {
-        let mut sum: Option<&CalcResult> = None;
-        // so sum can contain references to temp values
-        #[allow(unused_assignments)]
-        let mut tmp_sum = CalcResult::hack_empty();
-        for row_index in sel.get_first().row..=sel.get_second().row {
-            if let Err(..) = &results[row_index] {
-                return None;
-            } else if let Ok(Some(line_result)) = &results[row_index] {
-                if let Some(sum_r) = &sum {
-                    if let Some(add_result) = add_op(sum_r, &line_result) {
-                        tmp_sum = add_result;
-                        sum = Some(&tmp_sum);
-                    } else {
-                        return None; // don't show anything if can't add all the rows
-                    }
-                } else {
-                    sum = Some(&line_result);
-                }
-            }
-        }
-        if let Some(sum) = sum {
-            let result_str = render_result(
-                &units,
-                sum,
-                &editor_content.get_data(sel.start.row).result_format,
-                false,
-                Some(RENDERED_RESULT_PRECISION),
-                true,
+                tokens,
+                results,
+                vars,
+                editor_objs,
+                result_change_flag,
             );
-            return Some(result_str);
         }
     }
-    return None;
-}
-
-fn evaluate_text<'text_ptr>(
-    units: &Units,
-    text: &[char],
-    vars: &Variables,
-    tokens: &mut Vec<Token<'text_ptr>>,
-    editor_y: usize,
-    allocator: &'text_ptr Bump,
-) -> Result<Option<EvaluationResult>, ()> {
-    TokenParser::parse_line(text, vars, tokens, &units, editor_y, allocator);
-    let mut shunting_output_stack = Vec::with_capacity(4);
-    ShuntingYard::shunting_yard(tokens, &mut shunting_output_stack);
-    return evaluate_tokens(tokens, &mut shunting_output_stack, &vars);
-}
 
-fn render_matrix_obj<'text_ptr>(
-    mut render_x: usize,
-    render_y: CanvasY,
-    current_editor_width: usize,
-    left_gutter_width: usize,
-    row_count: usize,
-    col_count: usize,
-    tokens: &[Token<'text_ptr>],
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    rendered_row_height: usize,
-) -> usize {
-    let vert_align_offset = (rendered_row_height - MatrixData::calc_render_height(row_count)) / 2;
+    fn get_line_ref_name(editor_content: &EditorContent<LineData>, y: usize) -> Vec<char> {
+        let line_data = editor_content.get_data(y);
+        // TODO opt
+        let line_ref_name: Vec<char> = format!("&[{}]", line_data.line_id).chars().collect();
+        return line_ref_name;
+    }
 
-    if render_x < current_editor_width {
-        render_matrix_left_brackets(
-            render_x + left_gutter_width,
-            render_y,
-            row_count,
-            render_buckets,
-            vert_align_offset,
-        );
+    fn fill_editor_objs_referencing_current_line<'b>(
+        current_y: ContentIndex,
+        tokens: &AppTokens<'b>,
+        vars: &Variables,
+        editor_objs_referencing_current_line: &mut Vec<EditorObjId>,
+        editor_content: &EditorContent<LineData>,
+    ) {
+        editor_objs_referencing_current_line.clear();
+        let var_index = match current_y.as_usize().checked_sub(tokens.window_y()) {
+            Some(var_index) if var_index < MAX_LINE_COUNT => var_index,
+            // the lines outside of the window are not evaluated
+            _ => return,
+        };
+        if let Some(var) = &vars[var_index] {
+            NoteCalcApp::find_line_ref_dependant_lines(
+                &var.name,
+                tokens,
+                var_index,
+                editor_objs_referencing_current_line,
+            );
+        } else {
+            let line_ref_name =
+                NoteCalcApp::get_line_ref_name(&editor_content, current_y.as_usize());
+            NoteCalcApp::find_line_ref_dependant_lines(
+                &line_ref_name,
+                tokens,
+                var_index,
+                editor_objs_referencing_current_line,
+            );
+        };
     }
-    render_x += 1;
 
-    let tokens_per_cell = {
-        // TODO smallvec
-        // so it can hold a 6*6 matrix maximum
-        let mut matrix_cells_for_tokens: [MaybeUninit<&[Token]>; 36] =
-            unsafe { MaybeUninit::uninit().assume_init() };
+    fn find_line_ref_dependant_lines<'b>(
+        editor_obj_name: &[char],
+        tokens_per_lines: &AppTokens<'b>,
+        line_var_index: usize,
+        updated_line_ref_obj_indices: &mut Vec<EditorObjId>,
+    ) -> BitFlag128 {
+        let mut rows_to_recalc = BitFlag128::empty();
+        for (token_line_index, tokens) in
+            tokens_per_lines.iter().skip(line_var_index + 1).enumerate()
+        {
+            if let Some(tokens) = tokens {
+                let mut already_added = BitFlag128::empty();
+                for token in &tokens.tokens {
+                    let var_index = match token.typ {
+                        TokenType::LineReference { var_index }
+                            if var_index < MAX_LINE_COUNT
+                                && already_added.is_false(var_index)
+                                && token.ptr == editor_obj_name =>
+                        {
+                            var_index
+                        }
+                        TokenType::Variable { var_index }
+                            if var_index < MAX_LINE_COUNT
+                                && already_added.is_false(var_index)
+                                && token.ptr == editor_obj_name =>
+                        {
+                            var_index
+                        }
 
-        let mut start_token_index = 0;
-        let mut cell_index = 0;
-        let mut can_ignore_ws = true;
-        for (token_index, token) in tokens.iter().enumerate() {
-            if token.typ == TokenType::Operator(OperatorTokenType::BracketClose) {
-                matrix_cells_for_tokens[cell_index] =
-                    MaybeUninit::new(&tokens[start_token_index..token_index]);
-                break;
-            } else if token.typ
-                == TokenType::Operator(OperatorTokenType::Matrix {
-                    row_count,
-                    col_count,
-                })
-                || token.typ == TokenType::Operator(OperatorTokenType::BracketOpen)
-            {
-                // skip them
-                start_token_index = token_index + 1;
-            } else if can_ignore_ws && token.ptr[0].is_ascii_whitespace() {
-                start_token_index = token_index + 1;
-            } else if token.typ == TokenType::Operator(OperatorTokenType::Comma)
-                || token.typ == TokenType::Operator(OperatorTokenType::Semicolon)
-            {
-                matrix_cells_for_tokens[cell_index] =
-                    MaybeUninit::new(&tokens[start_token_index..token_index]);
-                start_token_index = token_index + 1;
-                cell_index += 1;
-                can_ignore_ws = true;
+                        _ => {
+                            continue;
+                        }
+                    };
+                    let index = line_var_index + 1 + token_line_index;
+                    updated_line_ref_obj_indices.push(EditorObjId {
+                        content_index: content_y(tokens_per_lines.window_y() + index),
+                        var_index,
+                    });
+                    rows_to_recalc.merge(BitFlag128::single_row(index));
+                    already_added.set(var_index);
+                }
             } else {
-                can_ignore_ws = false;
+                break;
             }
         }
-        unsafe { std::mem::transmute::<_, [&[Token]; 36]>(matrix_cells_for_tokens) }
-    };
+        return rows_to_recalc;
+    }
 
-    for col_i in 0..col_count {
-        let max_width: usize = (0..row_count)
-            .map(|row_i| {
-                tokens_per_cell[row_i * col_count + col_i]
-                    .iter()
-                    .map(|it| it.ptr.len())
-                    .sum()
-            })
-            .max()
-            .unwrap();
-        for row_i in 0..row_count {
-            let tokens = &tokens_per_cell[row_i * col_count + col_i];
-            let len: usize = tokens.iter().map(|it| it.ptr.len()).sum();
-            let offset_x = max_width - len;
-            let mut local_x = 0;
-            // the content of the matrix starts from the second row
-            let matrix_ascii_header_offset = if row_count == 1 { 0 } else { 1 };
-            let dst_y = row_i + vert_align_offset + matrix_ascii_header_offset;
-            for token in tokens.iter() {
-                if render_x <= current_editor_width {
-                    draw_token(
-                        token,
-                        render_x + offset_x + local_x,
-                        render_y.add(dst_y),
-                        current_editor_width,
-                        left_gutter_width,
+    // export
+    pub fn copy_selected_rows_with_result_to_clipboard<'b>(
+        &'b mut self,
+        units: &'b Units,
+        render_buckets: &'b mut RenderBuckets<'b>,
+        tokens: &AppTokens<'b>,
+        vars: &Variables,
+        results: &Results,
+    ) -> String {
+        render_buckets.clear();
+
+        let (first_row, second_row) =
+            if let Some((start, end)) = self.editor.get_selection().is_range() {
+                (start.row, end.row)
+            } else {
+                (0, self.editor_content.line_count() - 1)
+            };
+        // only the lines of the window are evaluated
+        let window_rows = self
+            .render_data
+            .window_rows(self.editor_content.line_count());
+        let first_row = first_row.max(window_rows.start);
+        let second_row = second_row.min(window_rows.end - 1);
+        if first_row > second_row {
+            return String::new();
+        }
+        let row_nums = second_row - first_row + 1;
+        let first_index = first_row - window_rows.start;
+        let second_index = second_row - window_rows.start;
+
+        let mut gr = GlobalRenderData::new(1024, 1000 /*dummy value*/, 1024 / 2, 0, 2);
+        // the selected rows are rendered from the top of the canvas
+        gr.move_window(first_row);
+        gr.scroll_y = first_row;
+        // evaluate all the lines so variables are defined even if they are not selected
+        let mut render_height = 0;
+        {
+            let mut r = PerLineRenderData::new();
+            r.editor_y = content_y(first_row);
+            for i in first_row..=second_row {
+                let i = content_y(i);
+                // tokens must be evaluated to register variables for line reference inlining in the output text
+
+                if let Some(tokens) = &tokens[i] {
+                    r.new_line_started();
+                    gr.set_render_y(r.editor_y, Some(r.render_y));
+
+                    r.rendered_row_height = PerLineRenderData::calc_rendered_row_height(
+                        &results[i],
+                        &tokens.tokens,
+                        &vars[..],
+                        None,
+                        0,
+                    );
+                    // "- 1" so if it is even, it always appear higher
+                    r.vert_align_offset = (r.rendered_row_height - 1) / 2;
+                    gr.set_rendered_height(r.editor_y, r.rendered_row_height);
+                    render_height += r.rendered_row_height;
+                    // Todo: refactor the parameters into a struct
+                    render_tokens(
+                        &tokens.tokens,
+                        &mut r,
+                        &mut gr,
                         render_buckets,
+                        // TODO &mut code smell
+                        &mut Vec::new(),
+                        &self.editor,
+                        &self.matrix_editing,
+                        &vars[..],
+                        &units,
+                        true, // force matrix rendering
+                        None,
+                        0,
+                        0,
                     );
+                    r.line_render_ended(r.rendered_row_height);
                 }
-                local_x += token.ptr.len();
             }
         }
-        render_x += if col_i + 1 < col_count {
-            max_width + 2
-        } else {
-            max_width
-        };
-    }
 
-    if render_x < current_editor_width {
-        render_matrix_right_brackets(
-            render_x + left_gutter_width,
-            render_y,
-            row_count,
-            render_buckets,
-            vert_align_offset,
-        );
-    }
-    render_x += 1;
+        // TODO what is this 256?
+        let mut tmp_canvas: Vec<[char; 256]> = Vec::with_capacity(render_height);
+        for _ in 0..render_height {
+            tmp_canvas.push([' '; 256]);
+        }
+        // render all tokens to the tmp canvas, so we can measure the longest row
+        render_buckets_into(&render_buckets, &mut tmp_canvas);
+        let mut max_len = 0;
+        for canvas_line in &tmp_canvas {
+            let mut len = 256;
+            for ch in canvas_line.iter().rev() {
+                if *ch != ' ' {
+                    break;
+                }
+                len -= 1;
+            }
+            if len > max_len {
+                max_len = len;
+            }
+        }
 
-    render_x
-}
+        //////////////////////////////////////////////////////////////////////////
+        //////////////////////////////////////////////////////////////////////////
+        render_buckets.clear();
 
-fn render_matrix_left_brackets(
-    x: usize,
-    render_y: CanvasY,
-    row_count: usize,
-    render_buckets: &mut RenderBuckets,
-    vert_align_offset: usize,
-) {
-    if row_count == 1 {
-        render_buckets.operators.push(RenderUtf8TextMsg {
-            text: &['['],
-            row: render_y.add(vert_align_offset),
-            column: x,
-        });
-    } else {
-        render_buckets.operators.push(RenderUtf8TextMsg {
-            text: &['┌'],
-            row: render_y.add(vert_align_offset),
-            column: x,
-        });
-        for i in 0..row_count {
-            render_buckets.operators.push(RenderUtf8TextMsg {
-                text: &['│'],
-                row: render_y.add(i + vert_align_offset + 1),
-                column: x,
-            });
-        }
-        render_buckets.operators.push(RenderUtf8TextMsg {
-            text: &['└'],
-            row: render_y.add(row_count + vert_align_offset + 1),
-            column: x,
-        });
-    };
-}
+        let mut tmp = ResultRender::new(SmallVec::with_capacity(MAX_LINE_COUNT));
 
-fn render_matrix_right_brackets(
-    x: usize,
-    render_y: CanvasY,
-    row_count: usize,
-    render_buckets: &mut RenderBuckets,
-    vert_align_offset: usize,
-) {
-    if row_count == 1 {
-        render_buckets.operators.push(RenderUtf8TextMsg {
-            text: &[']'],
-            row: render_y.add(vert_align_offset),
-            column: x,
-        });
-    } else {
-        render_buckets.operators.push(RenderUtf8TextMsg {
-            text: &['┐'],
-            row: render_y.add(vert_align_offset),
-            column: x,
-        });
-        for i in 0..row_count {
-            render_buckets.operators.push(RenderUtf8TextMsg {
-                text: &['│'],
-                row: render_y.add(i + 1 + vert_align_offset),
-                column: x,
-            });
-        }
-        render_buckets.operators.push(RenderUtf8TextMsg {
-            text: &['┘'],
-            row: render_y.add(row_count + 1 + vert_align_offset),
-            column: x,
-        });
-    }
-}
+        gr.result_gutter_x = max_len + 2;
+        render_results_into_buf_and_calc_len(
+            &units,
+            &results.as_slice()[first_index..=second_index],
+            &mut tmp,
+            &self.editor_content,
+            &gr,
+            &vars[first_index..=second_index],
+            None,
+        );
+        gr.longest_visible_result_len = tmp.max_len;
+        // the result buffer was overwritten
+        self.render_data.clear_result_ranges();
 
-fn render_matrix_result<'text_ptr>(
-    units: &Units,
-    mut render_x: usize,
-    render_y: CanvasY,
-    mat: &MatrixData,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    prev_mat_result_lengths: Option<&ResultLengths>,
-    rendered_row_height: usize,
-    decimal_count: Option<usize>,
-) -> usize {
-    let start_x = render_x;
+        create_render_commands_for_results_and_render_matrices(
+            &tmp,
+            units,
+            &results.as_slice()[first_index..=second_index],
+            render_buckets,
+            &self.editor_content,
+            &gr,
+            &vars[first_index..=second_index],
+            None,
+        );
 
-    let vert_align_offset = (rendered_row_height - mat.render_height()) / 2;
-    render_matrix_left_brackets(
-        start_x,
-        render_y,
-        mat.row_count,
-        render_buckets,
-        vert_align_offset,
-    );
-    render_x += 1;
+        for i in 0..render_height {
+            render_buckets.draw_char(
+                Layer::AboveText,
+                gr.result_gutter_x,
+                canvas_y(i as isize),
+                '█',
+            );
+        }
+        render_buckets_into(&render_buckets, &mut tmp_canvas);
+        let mut result_str = String::with_capacity(row_nums * 64);
+        for canvas_line in &tmp_canvas {
+            result_str.extend(canvas_line.iter());
+            while result_str.chars().last().unwrap_or('x') == ' ' {
+                result_str.pop();
+            }
+            result_str.push('\n');
+        }
 
-    let cells_strs = {
-        let mut tokens_per_cell: SmallVec<[String; 32]> = SmallVec::with_capacity(32);
+        return result_str;
+    }
 
-        for cell in mat.cells.iter() {
-            let result_str =
-                render_result(units, cell, &ResultFormat::Dec, false, decimal_count, true);
-            tokens_per_cell.push(result_str);
+    fn handle_completion<'b>(
+        &mut self,
+        input: &EditorInputEvent,
+        editor_objects: &mut EditorObjects,
+        vars: &Variables,
+    ) -> bool {
+        let cursor_pos = self.editor.get_selection();
+        if *input != EditorInputEvent::Tab || cursor_pos.get_cursor_pos().column == 0 {
+            return false;
         }
-        tokens_per_cell
-    };
 
-    let max_lengths = {
-        let mut max_lengths = ResultLengths {
-            int_part_len: prev_mat_result_lengths
-                .as_ref()
-                .map(|it| it.int_part_len)
-                .unwrap_or(0),
-            frac_part_len: prev_mat_result_lengths
+        // matrix autocompletion 'm' + tab
+        let cursor_pos = cursor_pos.get_cursor_pos();
+
+        for autocompl_const in &AUTOCOMPLETION_CONSTS {
+            let len = autocompl_const.abbrev.len();
+            if cursor_pos.column <= len {
+                continue;
+            }
+            let start_x = cursor_pos.column - (len + 1);
+            let autocompletion_match = {
+                let line = self.editor_content.get_line_valid_chars(cursor_pos.row);
+                line[start_x] == '.'
+                    && &line[start_x + 1..cursor_pos.column] == autocompl_const.abbrev
+            };
+            if autocompletion_match {
+                let start = cursor_pos.with_column(start_x);
+                self.editor
+                    .set_selection_save_col(Selection::range(start, cursor_pos));
+                self.editor.handle_input(
+                    EditorInputEvent::Backspace,
+                    InputModifiers::none(),
+                    &mut self.editor_content,
+                );
+                for ch in autocompl_const.replace_to {
+                    self.editor.handle_input(
+                        EditorInputEvent::Char(*ch),
+                        InputModifiers::none(),
+                        &mut self.editor_content,
+                    );
+                }
+                if let Some(relative_new_cursor_pos) = autocompl_const.relative_new_cursor_pos {
+                    self.editor.set_selection_save_col(Selection::single(
+                        start.add_column(relative_new_cursor_pos),
+                    ));
+                }
+                // the objects exist only for the lines of the window
+                let cursor_is_in_window = self
+                    .render_data
+                    .window_rows(self.editor_content.line_count())
+                    .contains(&cursor_pos.row);
+                if cursor_is_in_window
+                    && autocompl_const.abbrev.len() >= 3
+                    && &autocompl_const.abbrev[0..3] == &['m', 'a', 't']
+                {
+                    // remove the SimpleToken of the .mat string
+                    let size = if autocompl_const.abbrev.len() == 3 {
+                        1 // .mat
+                    } else if autocompl_const.abbrev[3] == '3' {
+                        3
+                    } else {
+                        4
+                    };
+                    editor_objects[content_y(cursor_pos.row)].pop();
+                    editor_objects[content_y(cursor_pos.row)].push(EditorObject {
+                        typ: EditorObjectType::Matrix {
+                            row_count: size,
+                            col_count: size,
+                        },
+                        row: content_y(cursor_pos.row),
+                        start_x,
+                        end_x: start_x + autocompl_const.replace_to.len(),
+                        rendered_x: 0,           // dummy
+                        rendered_y: canvas_y(0), // dummy
+                        rendered_w: size + 2,
+                        rendered_h: 1,
+                    });
+                    self.check_stepping_into_matrix(Pos::from_row_column(0, 0), &editor_objects);
+                }
+                return true;
+            }
+        }
+
+        let line = self.editor_content.get_line_valid_chars(cursor_pos.row);
+        // check for autocompletion
+        // find space
+        let (begin_index, expected_len) = {
+            let mut begin_index = cursor_pos.column - 1;
+            let mut len = 1;
+            while begin_index > 0
+                && (line[begin_index - 1].is_alphanumeric() || line[begin_index - 1] == '_')
+            {
+                begin_index -= 1;
+                len += 1;
+            }
+            (begin_index, len)
+        };
+        // find the best match
+        let mut matched_var_index = None;
+        let var_count = cursor_pos
+            .row
+            .saturating_sub(self.render_data.window_y())
+            .min(MAX_LINE_COUNT);
+        for (var_i, var) in vars[0..var_count].iter().enumerate() {
+            if var.is_none() {
+                continue;
+            }
+            let mut match_len = 0;
+            for (var_ch, actual_ch) in var
                 .as_ref()
-                .map(|it| it.frac_part_len)
-                .unwrap_or(0),
-            unit_part_len: prev_mat_result_lengths
+                .unwrap()
+                .name
+                .iter()
+                .zip(&line[begin_index..begin_index + expected_len])
+            {
+                if *var_ch != *actual_ch {
+                    break;
+                }
+                match_len += 1;
+            }
+            if expected_len == match_len {
+                if matched_var_index.is_some() {
+                    // multiple match, don't autocomplete
+                    matched_var_index = None;
+                    break;
+                } else {
+                    matched_var_index = Some(var_i);
+                }
+            }
+        }
+
+        if let Some(matched_var_index) = matched_var_index {
+            for ch in vars[matched_var_index]
                 .as_ref()
-                .map(|it| it.unit_part_len)
-                .unwrap_or(0),
-        };
-        for cell_str in &cells_strs {
-            let lengths = get_int_frac_part_len(cell_str);
-            max_lengths.set_max(&lengths);
+                .unwrap()
+                .name
+                .iter()
+                .skip(expected_len)
+            {
+                self.editor.handle_input(
+                    EditorInputEvent::Char(*ch),
+                    InputModifiers::none(),
+                    &mut self.editor_content,
+                );
+            }
+            return true;
         }
-        max_lengths
-    };
-    render_buckets.set_color(Layer::Text, 0x000000_FF);
 
-    for col_i in 0..mat.col_count {
-        for row_i in 0..mat.row_count {
-            let cell_str = &cells_strs[row_i * mat.col_count + col_i];
-            let lengths = get_int_frac_part_len(cell_str);
-            // Draw integer part
-            let offset_x = max_lengths.int_part_len - lengths.int_part_len;
-            // the content of the matrix starts from the second row
-            let matrix_ascii_header_offset = if mat.row_count == 1 { 0 } else { 1 };
-            let dst_y = render_y.add(row_i + vert_align_offset + matrix_ascii_header_offset);
-            render_buckets.draw_string(
-                Layer::Text,
-                render_x + offset_x,
-                dst_y,
-                // TOOD nem kell clone, csinálj iter into vhogy
-                cell_str[0..lengths.int_part_len].to_owned(),
-            );
+        return false;
+    }
 
-            let mut frac_offset_x = 0;
-            if lengths.frac_part_len > 0 {
-                render_buckets.draw_string(
-                    Layer::Text,
-                    render_x + offset_x + lengths.int_part_len,
-                    dst_y,
-                    // TOOD nem kell clone, csinálj iter into vhogy
-                    cell_str[lengths.int_part_len..lengths.int_part_len + lengths.frac_part_len]
-                        .to_owned(),
-                )
-            } else if max_lengths.frac_part_len > 0 {
-                render_buckets.draw_char(
-                    Layer::Text,
-                    render_x + offset_x + lengths.int_part_len,
-                    dst_y,
-                    '.',
+    fn handle_obj_deletion<'b>(
+        &mut self,
+        input: &EditorInputEvent,
+        editor_objects: &mut EditorObjects,
+    ) -> Option<RowModificationType> {
+        let selection = self.editor.get_selection();
+        let cursor_pos = selection.get_cursor_pos();
+        if *input == EditorInputEvent::Backspace
+            && selection.is_range().is_none()
+            && selection.start.column > 0
+        {
+            if let Some(index) =
+                self.index_of_matrix_or_lineref_at(cursor_pos.with_prev_col(), editor_objects)
+            {
+                // remove it
+                let obj = editor_objects[content_y(cursor_pos.row)].remove(index);
+                let sel = Selection::range(
+                    Pos::from_row_column(obj.row.as_usize(), obj.start_x),
+                    Pos::from_row_column(obj.row.as_usize(), obj.end_x),
                 );
-                frac_offset_x = 1;
-            }
-            for i in 0..max_lengths.frac_part_len - lengths.frac_part_len - frac_offset_x {
-                render_buckets.draw_char(
-                    Layer::Text,
-                    render_x
-                        + offset_x
-                        + lengths.int_part_len
-                        + lengths.frac_part_len
-                        + frac_offset_x
-                        + i,
-                    dst_y,
-                    '0',
-                )
+                self.editor.set_selection_save_col(sel);
+                self.editor.handle_input(
+                    EditorInputEvent::Backspace,
+                    InputModifiers::none(),
+                    &mut self.editor_content,
+                );
+                return if obj.rendered_h > 1 {
+                    Some(RowModificationType::AllLinesFrom(cursor_pos.row))
+                } else {
+                    Some(RowModificationType::SingleLine(cursor_pos.row))
+                };
             }
-            if lengths.unit_part_len > 0 {
-                render_buckets.draw_string(
-                    Layer::Text,
-                    render_x + offset_x + lengths.int_part_len + max_lengths.frac_part_len + 1,
-                    dst_y,
-                    // TOOD nem kell clone, csinálj iter into vhogy
-                    // +1, skip space
-                    cell_str[lengths.int_part_len + lengths.frac_part_len + 1..].to_owned(),
-                )
+        } else if *input == EditorInputEvent::Del && selection.is_range().is_none() {
+            if let Some(index) = self.index_of_matrix_or_lineref_at(cursor_pos, editor_objects) {
+                // remove it
+                let obj = editor_objects[content_y(cursor_pos.row)].remove(index);
+                let sel = Selection::range(
+                    Pos::from_row_column(obj.row.as_usize(), obj.start_x),
+                    Pos::from_row_column(obj.row.as_usize(), obj.end_x),
+                );
+                self.editor.set_selection_save_col(sel);
+                self.editor.handle_input(
+                    EditorInputEvent::Del,
+                    InputModifiers::none(),
+                    &mut self.editor_content,
+                );
+                return if obj.rendered_h > 1 {
+                    Some(RowModificationType::AllLinesFrom(cursor_pos.row))
+                } else {
+                    Some(RowModificationType::SingleLine(cursor_pos.row))
+                };
             }
         }
-        render_x += if col_i + 1 < mat.col_count {
-            (max_lengths.int_part_len + max_lengths.frac_part_len + max_lengths.unit_part_len) + 2
-        } else {
-            max_lengths.int_part_len + max_lengths.frac_part_len + max_lengths.unit_part_len
-        };
+        return None;
     }
 
-    render_matrix_right_brackets(
-        render_x,
-        render_y,
-        mat.row_count,
-        render_buckets,
-        vert_align_offset,
-    );
-    render_x += 1;
-    return render_x - start_x;
-}
+    fn handle_obj_jump_over<'b>(
+        &mut self,
+        input: &EditorInputEvent,
+        modifiers: InputModifiers,
+        editor_objects: &EditorObjects,
+    ) -> bool {
+        let selection = self.editor.get_selection();
+        let cursor_pos = selection.get_cursor_pos();
+        if *input == EditorInputEvent::Left
+            && selection.is_range().is_none()
+            && selection.start.column > 0
+            && modifiers.shift == false
+        {
+            let obj = self
+                .find_editor_object_at_including_end_of_word(cursor_pos, editor_objects)
+                .map(|it| (it.typ, it.row, it.start_x));
+            if let Some((EditorObjectType::LineReference { .. }, row, start_x)) = obj {
+                //  jump over it
+                self.editor.set_cursor_pos_r_c(row.as_usize(), start_x);
+                return true;
+            }
+        } else if *input == EditorInputEvent::Right
+            && selection.is_range().is_none()
+            && modifiers.shift == false
+        {
+            let obj = self
+                .find_editor_object_at_excluding_end_of_word(cursor_pos, editor_objects)
+                .map(|it| (it.typ, it.row, it.end_x));
 
-fn render_result_inside_editor<'text_ptr>(
-    units: &Units,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    result: &Result<CalcResult, ()>,
-    r: &PerLineRenderData,
-    gr: &GlobalRenderData,
-    decimal_count: Option<usize>,
-) -> (usize, usize) {
-    return match &result {
-        Ok(CalcResult {
-            typ: CalcResultType::Matrix(mat),
-            ..
-        }) => {
-            let rendered_width = render_matrix_result(
-                units,
-                gr.left_gutter_width + r.render_x,
-                r.render_y,
-                mat,
-                render_buckets,
-                None,
-                r.rendered_row_height,
-                decimal_count,
-            );
-            (rendered_width, mat.render_height())
+            if let Some((EditorObjectType::LineReference { .. }, row, end_x)) = obj {
+                //  jump over it
+                self.editor.set_cursor_pos_r_c(row.as_usize(), end_x);
+                return true;
+            }
         }
-        Ok(result) => {
-            // TODO: optimize string alloc
-            let result_str = render_result(
+        return false;
+    }
+
+    fn copy_result_of_current_line_to_clipboard(&mut self, units: &Units, results: &Results) {
+        let row = self.editor.get_selection().get_cursor_pos().row;
+        if let Ok(Some(result)) = &results[content_y(row)] {
+            self.clipboard = Some(render_result(
                 &units,
-                result,
-                &ResultFormat::Dec,
+                &result,
+                &self.editor_content.get_data(row).result_format,
                 false,
-                decimal_count,
+                Some(RENDERED_RESULT_PRECISION),
                 true,
-            );
-            let text_len = result_str.chars().count();
-            let bounded_text_len = text_len
-                .min((gr.current_editor_width as isize - r.render_x as isize).max(0) as usize);
-
-            render_buckets.line_ref_results.push(RenderStringMsg {
-                text: result_str[0..bounded_text_len].to_owned(),
-                row: r.render_y,
-                column: r.render_x + gr.left_gutter_width,
-            });
-            (text_len, 1)
-        }
-        Err(_) => {
-            render_buckets.line_ref_results.push(RenderStringMsg {
-                text: "Err".to_owned(),
-                row: r.render_y,
-                column: r.render_x + gr.left_gutter_width,
-            });
-            (3, 1)
+                &self.render_data.scientific_notation,
+                self.render_data.rounding_mode,
+            ));
         }
-    };
-}
+    }
 
-struct ResultTmp {
-    buffer_ptr: Option<Range<usize>>,
-    editor_y: ContentIndex,
-    lengths: ResultLengths,
-}
+    fn toggle_matrix_collapse(
+        &mut self,
+        editor_objs: &EditorObjects,
+    ) -> Option<RowModificationType> {
+        let cursor_pos = self.editor.get_selection().get_cursor_pos();
+        let matrix_index = editor_objs[content_y(cursor_pos.row)]
+            .iter()
+            .filter(|it| matches!(it.typ, EditorObjectType::Matrix { .. }))
+            .position(|it| (it.start_x..=it.end_x).contains(&cursor_pos.column))
+            .filter(|it| *it < 32)?;
+        self.editor_content
+            .mut_data(cursor_pos.row)
+            .collapsed_matrices ^= 1 << matrix_index;
+        Some(RowModificationType::SingleLine(cursor_pos.row))
+    }
 
-pub const MAX_VISIBLE_HEADER_COUNT: usize = 16;
-struct ResultRender {
-    result_ranges: SmallVec<[ResultTmp; MAX_LINE_COUNT]>,
-    max_len: usize,
-    max_lengths: [ResultLengths; MAX_VISIBLE_HEADER_COUNT],
-    result_counts_in_regions: [usize; MAX_VISIBLE_HEADER_COUNT],
-}
+    fn handle_jump_to_definition<'b>(
+        &mut self,
+        input: &EditorInputEvent,
+        modifiers: InputModifiers,
+        editor_objects: &EditorObjects,
+    ) -> bool {
+        let selection = self.editor.get_selection();
+        let cursor_pos = selection.get_cursor_pos();
+        if *input == EditorInputEvent::Char('b') && modifiers.ctrl {
+            if let Some(var_index) = self
+                .find_var_index_of_var_or_lineref_at(cursor_pos, editor_objects)
+                .filter(|it| *it < MAX_LINE_COUNT)
+            {
+                self.editor
+                    .set_cursor_pos_r_c(editor_objects.window_y() + var_index, 0);
+                return true;
+            }
+        }
+        return false;
+    }
 
-impl ResultRender {
-    pub fn new(vec: SmallVec<[ResultTmp; MAX_LINE_COUNT]>) -> ResultRender {
-        return ResultRender {
-            result_ranges: vec,
-            max_len: 0,
-            max_lengths: [ResultLengths {
-                int_part_len: 0,
-                frac_part_len: 0,
-                unit_part_len: 0,
-            }; 16],
-            result_counts_in_regions: [0; MAX_VISIBLE_HEADER_COUNT],
-        };
+    fn check_stepping_into_matrix<'b>(
+        &mut self,
+        enter_from_pos: Pos,
+        editor_objects: &EditorObjects,
+    ) {
+        if let Some(editor_obj) =
+            is_pos_inside_an_obj(editor_objects, self.editor.get_selection().get_cursor_pos())
+        {
+            match editor_obj.typ {
+                EditorObjectType::Matrix {
+                    row_count,
+                    col_count,
+                } => {
+                    // the matrices of a locked line can't be edited
+                    if self.matrix_editing.is_none()
+                        && self.editor.get_selection().is_range().is_none()
+                        && !self
+                            .editor_content
+                            .is_line_locked(editor_obj.row.as_usize())
+                    {
+                        self.matrix_editing = Some(MatrixEditing::new(
+                            row_count,
+                            col_count,
+                            &self
+                                .editor_content
+                                .get_line_valid_chars(editor_obj.row.as_usize())
+                                [editor_obj.start_x..editor_obj.end_x],
+                            editor_obj.row,
+                            editor_obj.start_x,
+                            editor_obj.end_x,
+                            enter_from_pos,
+                        ));
+                    }
+                }
+                EditorObjectType::SimpleTokens
+                | EditorObjectType::LineReference { .. }
+                | EditorObjectType::Variable { .. } => {}
+            }
+        }
     }
-}
 
-fn render_results_into_buf_and_calc_len<'text_ptr>(
-    units: &Units,
-    results: &[LineResult],
-    tmp: &mut ResultRender,
-    editor_content: &EditorContent<LineData>,
-    gr: &GlobalRenderData,
-    decimal_count: Option<usize>,
-) {
-    let mut result_buffer_index = 0;
-    let result_buffer = unsafe { &mut RESULT_BUFFER };
-    // calc max length and render results into buffer
-    let mut region_index = 0;
-    let mut region_count_offset = 0;
-    for (editor_y, result) in results.iter().enumerate() {
-        let editor_y = content_y(editor_y);
-        let render_y = if let Some(render_y) = gr.get_render_y(editor_y) {
-            render_y
-        } else {
-            continue;
-        };
-        if !gr.is_visible(editor_y) {
-            continue;
+    fn find_editor_object_at_including_end_of_word<'b>(
+        &self,
+        pos: Pos,
+        editor_objects: &'b EditorObjects,
+    ) -> Option<&'b EditorObject> {
+        for obj in &editor_objects[content_y(pos.row)] {
+            if (obj.start_x..=obj.end_x).contains(&pos.column) {
+                return Some(obj);
+            }
         }
-        if render_y.as_usize() != 0 && editor_content.get_char(editor_y.as_usize(), 0) == '#' {
-            let max_lens = &tmp.max_lengths[region_index];
-            tmp.max_len = max_lens.int_part_len
-                + max_lens.frac_part_len
-                + if max_lens.unit_part_len > 0 {
-                    max_lens.unit_part_len + 1
-                } else {
-                    0
+        return None;
+    }
+
+    // "asd |var"
+    // here, the cursor is at the edge of the first and second tokens as well, but the simpletoken
+    // comes first so the variable won't be found, that's why we need this specific find method for
+    // linerefs and vars
+    fn find_var_index_of_var_or_lineref_at(
+        &self,
+        pos: Pos,
+        editor_objects: &EditorObjects,
+    ) -> Option<usize> {
+        for obj in &editor_objects[content_y(pos.row)] {
+            match obj.typ {
+                EditorObjectType::Variable { var_index }
+                | EditorObjectType::LineReference { var_index }
+                    if (obj.start_x..=obj.end_x).contains(&pos.column) =>
+                {
+                    return Some(var_index);
                 }
-                .max(tmp.max_len);
-            tmp.result_counts_in_regions[region_index] =
-                tmp.result_ranges.len() - region_count_offset;
-            region_count_offset = tmp.result_ranges.len();
-            // TODO remove this limitation
-            if region_index < MAX_VISIBLE_HEADER_COUNT - 1 {
-                region_index += 1;
+                _ => {}
             }
-            continue;
         }
+        return None;
+    }
 
-        if let Err(..) = result {
-            result_buffer[result_buffer_index] = b'E';
-            result_buffer[result_buffer_index + 1] = b'r';
-            result_buffer[result_buffer_index + 2] = b'r';
-            tmp.result_ranges.push(ResultTmp {
-                buffer_ptr: Some(result_buffer_index..result_buffer_index + 3),
-                editor_y,
-                lengths: ResultLengths {
-                    int_part_len: 999,
-                    frac_part_len: 0,
-                    unit_part_len: 0,
-                },
-            });
-            result_buffer_index += 3;
-        } else if let Ok(Some(result)) = result {
-            match &result.typ {
-                CalcResultType::Matrix(_mat) => {
-                    tmp.result_ranges.push(ResultTmp {
-                        buffer_ptr: None,
-                        editor_y,
-                        lengths: ResultLengths {
-                            int_part_len: 0,
-                            frac_part_len: 0,
-                            unit_part_len: 0,
-                        },
-                    });
-                }
-                _ => {
-                    let start = result_buffer_index;
-                    let mut c = Cursor::new(&mut result_buffer[start..]);
-                    let lens = render_result_into(
-                        &units,
-                        &result,
-                        &editor_content.get_data(editor_y.as_usize()).result_format,
-                        false,
-                        &mut c,
-                        decimal_count,
-                        true,
-                    );
-                    let len = c.position() as usize;
-                    let range = start..start + len;
-                    tmp.max_lengths[region_index].set_max(&lens);
-                    tmp.result_ranges.push(ResultTmp {
-                        buffer_ptr: Some(range),
-                        editor_y,
-                        lengths: lens,
-                    });
-                    result_buffer_index += len;
-                }
-            };
-        } else {
-            tmp.result_ranges.push(ResultTmp {
-                buffer_ptr: None,
-                editor_y,
-                lengths: ResultLengths {
-                    int_part_len: 0,
-                    frac_part_len: 0,
-                    unit_part_len: 0,
-                },
-            });
+    fn find_editor_object_at_excluding_end_of_word<'b>(
+        &self,
+        pos: Pos,
+        editor_objects: &'b EditorObjects,
+    ) -> Option<&'b EditorObject> {
+        for obj in &editor_objects[content_y(pos.row)] {
+            if (obj.start_x..obj.end_x).contains(&pos.column) {
+                return Some(obj);
+            }
         }
+        return None;
     }
-    result_buffer[result_buffer_index] = 0; // tests depend on it
 
-    tmp.result_counts_in_regions[region_index] = tmp.result_ranges.len() - region_count_offset;
-    tmp.max_len = (tmp.max_lengths[region_index].int_part_len
-        + tmp.max_lengths[region_index].frac_part_len
-        + if tmp.max_lengths[region_index].unit_part_len > 0 {
-            tmp.max_lengths[region_index].unit_part_len + 1
-        } else {
-            0
-        })
-    .max(tmp.max_len);
-}
+    fn index_of_matrix_or_lineref_at<'b>(
+        &self,
+        pos: Pos,
+        editor_objects: &EditorObjects,
+    ) -> Option<usize> {
+        return editor_objects[content_y(pos.row)].iter().position(|obj| {
+            matches!(obj.typ, EditorObjectType::LineReference{..} | EditorObjectType::Matrix {..})
+                && (obj.start_x..obj.end_x).contains(&pos.column)
+        });
+    }
 
-fn create_render_commands_for_results_and_render_matrices<'text_ptr>(
-    tmp: &ResultRender,
-    units: &Units,
-    results: &[LineResult],
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    gr: &GlobalRenderData,
-    decimal_count: Option<usize>,
-) -> usize {
-    let mut prev_result_matrix_length = None;
-    let mut matrix_len = 0;
-    let result_buffer = unsafe { &RESULT_BUFFER };
-    let mut region_index = 0;
-    let mut result_count_in_this_region = tmp.result_counts_in_regions[0];
-    let mut max_lens = &tmp.max_lengths[0];
-    for result_tmp in tmp.result_ranges.iter() {
-        while result_count_in_this_region == 0 {
-            region_index += 1;
-            result_count_in_this_region = tmp.result_counts_in_regions[region_index];
-            max_lens = &tmp.max_lengths[region_index];
-        }
-        let rendered_row_height = gr.get_rendered_height(result_tmp.editor_y);
-        let render_y = gr.get_render_y(result_tmp.editor_y).expect("");
-        if let Some(result_range) = &result_tmp.buffer_ptr {
-            // result background
-            render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
-            render_buckets.draw_rect(
-                Layer::BehindText,
-                gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
-                render_y,
-                gr.current_result_panel_width,
-                rendered_row_height,
-            );
+    fn handle_matrix_editor_input(&mut self, input: EditorInputEvent, modifiers: InputModifiers) {
+        let mat_edit = self.matrix_editing.as_mut().unwrap();
+        let cur_pos = self.editor.get_selection().get_cursor_pos();
 
-            let lengths = &result_tmp.lengths;
-            let from = result_range.start;
-            let vert_align_offset = (rendered_row_height - 1) / 2;
-            let row = render_y.add(vert_align_offset);
-            enum ResultOffsetX {
-                Err,
-                Ok(usize),
-                TooLong,
+        let simple = !modifiers.shift && !modifiers.alt;
+        let alt = modifiers.alt;
+        if input == EditorInputEvent::Esc || input == EditorInputEvent::Enter {
+            end_matrix_editing(
+                &mut self.matrix_editing,
+                &mut self.editor,
+                &mut self.editor_content,
+                None,
+            );
+        } else if input == EditorInputEvent::Tab {
+            if mat_edit.current_cell.column + 1 < mat_edit.col_count {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_next_col());
+            } else if mat_edit.current_cell.row + 1 < mat_edit.row_count {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_next_row().with_column(0));
+            } else {
+                end_matrix_editing(
+                    &mut self.matrix_editing,
+                    &mut self.editor,
+                    &mut self.editor_content,
+                    None,
+                );
             }
-            let offset_x = if max_lens.int_part_len < lengths.int_part_len {
-                // it is an "Err"
-                ResultOffsetX::Err
+        } else if alt && input == EditorInputEvent::Right {
+            mat_edit.add_column();
+        } else if alt && input == EditorInputEvent::Left && mat_edit.col_count > 1 {
+            mat_edit.remove_column();
+        } else if alt && input == EditorInputEvent::Down {
+            mat_edit.add_row();
+        } else if alt && input == EditorInputEvent::Up && mat_edit.row_count > 1 {
+            mat_edit.remove_row();
+        } else if simple
+            && input == EditorInputEvent::Left
+            && mat_edit.editor.is_cursor_at_beginning()
+        {
+            if mat_edit.current_cell.column > 0 {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_prev_col());
             } else {
-                let offset_x = max_lens.int_part_len - lengths.int_part_len;
-                let sum_len =
-                    lengths.int_part_len + max_lens.frac_part_len + max_lens.unit_part_len;
-                if offset_x + sum_len > gr.current_result_panel_width {
-                    if sum_len > gr.current_result_panel_width {
-                        ResultOffsetX::TooLong
-                    } else {
-                        ResultOffsetX::Ok(gr.current_result_panel_width - sum_len)
-                    }
-                } else {
-                    ResultOffsetX::Ok(offset_x)
-                }
-            };
-            let x = gr.result_gutter_x
-                + RIGHT_GUTTER_WIDTH
-                + match offset_x {
-                    ResultOffsetX::Err => 0,
-                    ResultOffsetX::TooLong => 0,
-                    ResultOffsetX::Ok(n) => n,
-                };
-            let int_w = match offset_x {
-                ResultOffsetX::Err => 3,
-                _ => lengths.int_part_len,
-            };
-            render_buckets.ascii_texts.push(RenderAsciiTextMsg {
-                text: &result_buffer[from..from + int_w],
-                row,
-                column: x,
-            });
-            if lengths.frac_part_len > 0 {
-                let from = result_range.start + lengths.int_part_len;
-                render_buckets.ascii_texts.push(RenderAsciiTextMsg {
-                    text: &result_buffer[from..from + lengths.frac_part_len],
-                    row,
-                    column: x + lengths.int_part_len,
-                });
+                let start_text_index = mat_edit.start_text_index;
+                end_matrix_editing(
+                    &mut self.matrix_editing,
+                    &mut self.editor,
+                    &mut self.editor_content,
+                    Some(cur_pos.with_column(start_text_index)),
+                );
             }
-            if lengths.unit_part_len > 0 {
-                let from = result_range.start + lengths.int_part_len + lengths.frac_part_len + 1;
-                // e.g. in case of 2 units mm and m, m should be 1 coordinates right
-                let offset_x = max_lens.unit_part_len - lengths.unit_part_len;
-                render_buckets.ascii_texts.push(RenderAsciiTextMsg {
-                    text: &result_buffer[from..result_range.end],
-                    row,
-                    column: gr.result_gutter_x
-                        + RIGHT_GUTTER_WIDTH
-                        + max_lens.int_part_len
-                        + max_lens.frac_part_len
-                        + 1
-                        + offset_x,
-                });
+        } else if simple
+            && input == EditorInputEvent::Right
+            && mat_edit.editor.is_cursor_at_eol(&mat_edit.editor_content)
+        {
+            if let Some((_from, to)) = mat_edit.editor.get_selection().is_range() {
+                mat_edit.editor.set_cursor_pos_r_c(0, to.column);
+            } else if mat_edit.current_cell.column + 1 < mat_edit.col_count {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_next_col());
+            } else {
+                end_matrix_editing(
+                    &mut self.matrix_editing,
+                    &mut self.editor,
+                    &mut self.editor_content,
+                    None,
+                );
             }
-            match offset_x {
-                ResultOffsetX::TooLong => {
-                    render_buckets.set_color(Layer::AboveText, 0xF2F2F2_FF);
-                    render_buckets.draw_char(
-                        Layer::AboveText,
-                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width - 1,
-                        row,
-                        '█',
-                    );
-                    render_buckets.set_color(Layer::AboveText, 0xFF0000_FF);
-                    render_buckets.draw_char(
-                        Layer::AboveText,
-                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width - 1,
-                        row,
-                        '…',
-                    );
-                }
-                _ => {}
+        } else if simple && input == EditorInputEvent::Up {
+            if mat_edit.current_cell.row > 0 {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_prev_row());
+            } else {
+                end_matrix_editing(
+                    &mut self.matrix_editing,
+                    &mut self.editor,
+                    &mut self.editor_content,
+                    None,
+                );
+                self.editor
+                    .handle_input(input, modifiers, &mut self.editor_content);
             }
-            prev_result_matrix_length = None;
-        } else {
-            match &results[result_tmp.editor_y.as_usize()] {
-                Ok(Some(CalcResult {
-                    typ: CalcResultType::Matrix(mat),
-                    ..
-                })) => {
-                    // TODO: why it is called "prev.."?
-                    if prev_result_matrix_length.is_none() {
-                        prev_result_matrix_length = calc_consecutive_matrices_max_lengths(
-                            units,
-                            &results[result_tmp.editor_y.as_usize()..],
-                        );
-                    }
-                    let width = render_matrix_result(
-                        units,
-                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
-                        render_y,
-                        mat,
-                        render_buckets,
-                        prev_result_matrix_length.as_ref(),
-                        gr.get_rendered_height(result_tmp.editor_y),
-                        decimal_count,
-                    );
-                    if width > matrix_len {
-                        matrix_len = width;
-                    }
-                }
-                _ => {
-                    // no result but need rerender
-                    // result background
-                    prev_result_matrix_length = None;
-                    render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
-                    render_buckets.draw_rect(
-                        Layer::BehindText,
-                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
-                        render_y,
-                        gr.current_result_panel_width,
-                        rendered_row_height,
+        } else if simple && input == EditorInputEvent::Down {
+            if mat_edit.current_cell.row + 1 < mat_edit.row_count {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_next_row());
+            } else {
+                end_matrix_editing(
+                    &mut self.matrix_editing,
+                    &mut self.editor,
+                    &mut self.editor_content,
+                    None,
+                );
+                self.editor
+                    .handle_input(input, modifiers, &mut self.editor_content);
+            }
+        } else if simple && input == EditorInputEvent::End {
+            if mat_edit.current_cell.column != mat_edit.col_count - 1 {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_column(mat_edit.col_count - 1));
+            } else {
+                end_matrix_editing(
+                    &mut self.matrix_editing,
+                    &mut self.editor,
+                    &mut self.editor_content,
+                    None,
+                );
+                self.editor
+                    .handle_input(input, modifiers, &mut self.editor_content);
+            }
+        } else if simple && input == EditorInputEvent::Home {
+            if mat_edit.current_cell.column != 0 {
+                mat_edit.move_to_cell(mat_edit.current_cell.with_column(0));
+            } else {
+                let start_index = mat_edit.start_text_index;
+                end_matrix_editing(
+                    &mut self.matrix_editing,
+                    &mut self.editor,
+                    &mut self.editor_content,
+                    Some(cur_pos.with_column(start_index)),
+                );
+                self.editor
+                    .handle_input(input, modifiers, &mut self.editor_content);
+            }
+        } else {
+            mat_edit
+                .editor
+                .handle_input(input, modifiers, &mut mat_edit.editor_content);
+        }
+    }
+
+    pub fn generate_render_commands_and_fill_editor_objs<'b>(
+        &mut self,
+        units: &Units,
+        render_buckets: &mut RenderBuckets<'b>,
+        allocator: &'b Bump,
+        tokens: &AppTokens<'b>,
+        results: &Results,
+        vars: &Variables,
+        editor_objs: &mut EditorObjects,
+        result_change_flag: BitFlag128,
+    ) {
+        render_buckets.clear();
+        let find_matches = self.find_matches();
+        NoteCalcApp::renderr(
+            &mut self.editor,
+            &self.editor_content,
+            units,
+            &mut self.matrix_editing,
+            &mut self.line_reference_chooser,
+            render_buckets,
+            result_change_flag,
+            &mut self.render_data,
+            allocator,
+            tokens,
+            results,
+            vars,
+            &self.native_fns,
+            editor_objs,
+            &self.updated_line_ref_obj_indices,
+            &mut self.editor_objs_referencing_current_line,
+            self.mouse_hover_type,
+            self.rounding_mode,
+            self.angle_mode,
+            &find_matches,
+            self.find_needle.len(),
+        );
+        self.updated_line_ref_obj_indices.clear();
+    }
+}
+
+#[derive(Debug)]
+pub struct ResultLengths {
+    int_part_len: usize,
+    frac_part_len: usize,
+    unit_part_len: usize,
+}
+
+impl ResultLengths {
+    fn set_max(&mut self, other: &ResultLengths) {
+        if self.int_part_len < other.int_part_len {
+            self.int_part_len = other.int_part_len;
+        }
+        if self.frac_part_len < other.frac_part_len {
+            self.frac_part_len = other.frac_part_len;
+        }
+        if self.unit_part_len < other.unit_part_len {
+            self.unit_part_len = other.unit_part_len;
+        }
+    }
+}
+
+fn draw_cursor(
+    render_buckets: &mut RenderBuckets,
+    r: &PerLineRenderData,
+    gr: &GlobalRenderData,
+    editor: &Editor,
+    matrix_editing: &Option<MatrixEditing>,
+) {
+    let cursor_pos = editor.get_selection().get_cursor_pos();
+    if cursor_pos.row == r.editor_y.as_usize() {
+        let (column, render_y) = if gr.is_wrapped(r.editor_y) {
+            let wrapped_row =
+                (cursor_pos.column / gr.current_editor_width).min(r.rendered_row_height - 1);
+            (
+                cursor_pos.column - wrapped_row * gr.current_editor_width,
+                r.render_y.add(wrapped_row),
+            )
+        } else {
+            (
+                cursor_pos.column - gr.scroll_x,
+                r.render_y.add(r.vert_align_offset),
+            )
+        };
+        render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+        let x = column as isize + r.cursor_render_x_offset;
+        if editor.is_cursor_shown()
+            && matrix_editing.is_none()
+            && x >= 0
+            && (x as usize) <= gr.current_editor_width
+        {
+            draw_cursor_glyph(
+                render_buckets,
+                Layer::AboveText,
+                gr.left_gutter_width + x as usize,
+                render_y,
+                gr.cursor_style,
+            );
+        }
+    }
+}
+
+fn draw_cursor_glyph(
+    render_buckets: &mut RenderBuckets,
+    layer: Layer,
+    x: usize,
+    y: CanvasY,
+    style: CursorStyle,
+) {
+    match style {
+        CursorStyle::Bar => render_buckets.draw_char(layer, x, y, '▏'),
+        CursorStyle::Underline => render_buckets.draw_char(layer, x, y, '▁'),
+        CursorStyle::Block => {
+            // behind the text so the character under the cursor remains visible
+            render_buckets.set_color(Layer::BehindText, 0x000000_55);
+            render_buckets.draw_rect(Layer::BehindText, x, y, 1, 1);
+        }
+    }
+}
+
+pub fn pulse_modified_line_references(
+    render_buckets: &mut RenderBuckets,
+    gr: &GlobalRenderData,
+    updated_line_ref_obj_indices: &[EditorObjId],
+    editor_objects: &EditorObjects,
+) {
+    // Pulsing changed line references
+    for id in updated_line_ref_obj_indices {
+        for ed_obj in &editor_objects[id.content_index] {
+            match ed_obj {
+                EditorObject {
+                    typ: EditorObjectType::LineReference { var_index },
+                    rendered_x,
+                    rendered_y,
+                    rendered_w,
+                    rendered_h,
+                    ..
+                } if *var_index == id.var_index => {
+                    render_buckets.custom_commands[Layer::AboveText as usize].push(
+                        OutputMessage::PulsingRectangle {
+                            x: gr.left_gutter_width + *rendered_x,
+                            y: *rendered_y,
+                            w: *rendered_w,
+                            h: *rendered_h,
+                            start_color: CHANGE_RESULT_PULSE_START_COLOR,
+                            end_color: CHANGE_RESULT_PULSE_END_COLOR,
+                            animation_time: Duration::from_millis(2000),
+                        },
                     );
                 }
+                _ => {}
             }
         }
-        result_count_in_this_region -= 1;
-        if result_count_in_this_region == 0 {
-            region_index += 1;
-            result_count_in_this_region = tmp.result_counts_in_regions[region_index];
-            max_lens = &tmp.max_lengths[region_index];
+    }
+}
+
+pub fn pulse_editor_objs_referencing_current_line(
+    render_buckets: &mut RenderBuckets,
+    gr: &GlobalRenderData,
+    editor_objs_referencing_current_line: &[EditorObjId],
+    editor_objects: &EditorObjects,
+) {
+    for id in editor_objs_referencing_current_line {
+        for ed_obj in &editor_objects[id.content_index] {
+            match ed_obj {
+                EditorObject {
+                    typ: EditorObjectType::LineReference { var_index },
+                    ..
+                }
+                | EditorObject {
+                    typ: EditorObjectType::Variable { var_index },
+                    ..
+                } if *var_index == id.var_index => {
+                    let end_editor_x = gr.current_editor_width + gr.left_gutter_width + 1;
+                    if gr.is_visible(ed_obj.row) {
+                        let rendered_row_height = gr.get_rendered_height(ed_obj.row);
+                        let vert_align_offset = (rendered_row_height - ed_obj.rendered_h) / 2;
+                        let obj_start_x =
+                            (gr.left_gutter_width + ed_obj.rendered_x).min(end_editor_x - 1);
+                        let obj_end_x = (obj_start_x + ed_obj.rendered_w).min(end_editor_x);
+                        render_buckets.custom_commands[Layer::AboveText as usize].push(
+                            OutputMessage::PulsingRectangle {
+                                x: obj_start_x,
+                                y: ed_obj.rendered_y.add(vert_align_offset),
+                                w: obj_end_x - obj_start_x,
+                                h: ed_obj.rendered_h,
+                                start_color: REFERENCE_PULSE_PULSE_START_COLOR,
+                                end_color: 0x00FF7F_00,
+                                animation_time: Duration::from_millis(1000),
+                            },
+                        );
+                    }
+                }
+                _ => {}
+            }
         }
     }
-    return matrix_len;
-}
+}
+
+pub fn pulse_changed_results(
+    render_buckets: &mut RenderBuckets,
+    gr: &GlobalRenderData,
+    longest_rendered_result_len: usize,
+    result_change_flag: &BitFlag128,
+) {
+    if gr.get_render_y(content_y(gr.window_y())).is_none() {
+        // there were no render yet
+        return;
+    }
+    // TODO iter through only visible rows
+    // Pulsing changed results
+    for i in 0..MAX_LINE_COUNT {
+        if result_change_flag.is_true(i) {
+            let editor_y = content_y(gr.window_y() + i);
+            if let Some(render_y) = gr.get_render_y(editor_y) {
+                render_buckets.custom_commands[Layer::AboveText as usize].push(
+                    OutputMessage::PulsingRectangle {
+                        x: gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
+                        y: render_y,
+                        w: longest_rendered_result_len,
+                        h: gr.get_rendered_height(editor_y),
+                        start_color: CHANGE_RESULT_PULSE_START_COLOR,
+                        end_color: CHANGE_RESULT_PULSE_END_COLOR,
+                        animation_time: Duration::from_millis(1000),
+                    },
+                );
+            }
+        }
+    }
+}
+
+fn collect_user_fn_definitions(
+    editor_content: &EditorContent<LineData>,
+    editor_y: usize,
+) -> Vec<UserFnDefinition> {
+    (0..editor_y.min(editor_content.line_count()))
+        .filter_map(|y| parse_user_fn_definition(editor_content.get_line_valid_chars(y)))
+        .collect()
+}
+
+/// Tokenizes the bodies of the functions for the line of `var_index`, the names in them
+/// are resolved as if they were written in that line
+fn tokenize_user_fns<'b>(
+    fns: FnNames,
+    units: &Units,
+    vars: &Variables,
+    var_index: usize,
+    window_y: usize,
+    allocator: &'b Bump,
+) -> Vec<UserFn<'b>> {
+    fns.user_fns
+        .iter()
+        .map(|definition| {
+            let tokens = parse_tokens(
+                definition.body,
+                var_index,
+                window_y,
+                units,
+                vars,
+                allocator,
+                fns,
+                &definition.params,
+            );
+            UserFn {
+                param_count: definition.params.len(),
+                tokens: tokens.tokens,
+                shunting_output_stack: tokens.shunting_output_stack,
+            }
+        })
+        .collect()
+}
+
+pub fn parse_tokens<'b>(
+    line: &[char],
+    var_index: usize,
+    window_y: usize,
+    units: &Units,
+    vars: &Variables,
+    allocator: &'b Bump,
+    fns: FnNames,
+    params: &[&[char]],
+) -> Tokens<'b> {
+    // TODO optimize vec allocations
+    let mut tokens = Vec::with_capacity(128);
+    TokenParser::parse_line_with_fns(
+        line,
+        &vars,
+        &mut tokens,
+        &units,
+        var_index,
+        window_y,
+        allocator,
+        fns,
+        params,
+    );
+
+    // TODO: measure is 128 necessary?
+    // and remove allocation
+    let mut shunting_output_stack = Vec::with_capacity(128);
+    let mut syntax_errors = Vec::new();
+    ShuntingYard::shunting_yard_with_errors(
+        &mut tokens,
+        &mut shunting_output_stack,
+        &mut syntax_errors,
+    );
+    Tokens {
+        tokens,
+        shunting_output_stack,
+        syntax_errors,
+    }
+}
+
+fn render_simple_text_line<'text_ptr>(
+    line: &[char],
+    r: &mut PerLineRenderData,
+    gr: &mut GlobalRenderData,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    allocator: &'text_ptr Bump,
+) {
+    r.set_fix_row_height(1);
+    gr.set_rendered_height(r.editor_y, 1);
+
+    let text_len = line.len().min(gr.current_editor_width);
+
+    render_buckets.utf8_texts.push(RenderUtf8TextMsg {
+        text: allocator.alloc_slice_fill_iter(
+            line.iter()
+                .map(|it| *it)
+                .skip(gr.scroll_x)
+                .take(gr.current_editor_width),
+        ),
+        row: r.render_y,
+        column: gr.left_gutter_width,
+    });
+
+    r.token_render_done(text_len, text_len, 0);
+}
+
+#[inline]
+fn highlight_line_ref_background<'text_ptr>(
+    editor_objs: &Vec<EditorObject>,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    r: &PerLineRenderData,
+    gr: &GlobalRenderData,
+) {
+    for editor_obj in editor_objs.iter() {
+        if matches!(editor_obj.typ, EditorObjectType::LineReference{..}) {
+            let vert_align_offset = (r.rendered_row_height - editor_obj.rendered_h) / 2;
+            render_buckets.set_color(Layer::BehindText, LINE_REF_BACKGROUND_COLOR);
+            render_buckets.draw_rect(
+                Layer::BehindText,
+                gr.left_gutter_width + editor_obj.rendered_x,
+                editor_obj.rendered_y.add(vert_align_offset),
+                editor_obj.rendered_w,
+                editor_obj.rendered_h,
+            );
+        }
+    }
+}
+
+#[inline]
+fn highlight_active_line_refs<'text_ptr>(
+    editor_objs: &[EditorObject],
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    r: &PerLineRenderData,
+    gr: &GlobalRenderData,
+) {
+    let mut color_index = 0;
+    let mut colors: [Option<u32>; MAX_LINE_COUNT] = [None; MAX_LINE_COUNT];
+    for editor_obj in editor_objs.iter() {
+        match editor_obj.typ {
+            EditorObjectType::LineReference { var_index }
+            | EditorObjectType::Variable { var_index }
+                if var_index < MAX_LINE_COUNT =>
+            {
+                let color = if let Some(color) = colors[var_index] {
+                    color
+                } else {
+                    let color = ACTIVE_LINE_REF_HIGHLIGHT_COLORS[color_index] << 8 | 0x55;
+                    colors[var_index] = Some(color);
+                    color_index = if color_index < 8 { color_index + 1 } else { 0 };
+                    color
+                };
+
+                let vert_align_offset = (r.rendered_row_height - editor_obj.rendered_h) / 2;
+                render_buckets.set_color(Layer::BehindText, color);
+                render_buckets.draw_rect(
+                    Layer::BehindText,
+                    gr.left_gutter_width + editor_obj.rendered_x,
+                    editor_obj.rendered_y.add(vert_align_offset),
+                    editor_obj.rendered_w,
+                    editor_obj.rendered_h,
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+fn render_tokens<'text_ptr>(
+    tokens: &[Token<'text_ptr>],
+    r: &mut PerLineRenderData,
+    gr: &mut GlobalRenderData,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    editor_objects: &mut Vec<EditorObject>,
+    editor: &Editor,
+    matrix_editing: &Option<MatrixEditing>,
+    vars: &Variables,
+    units: &Units,
+    need_matrix_renderer: bool,
+    decimal_count: Option<usize>,
+    // 0 means that the comment is rendered right after the expression
+    comment_render_x: usize,
+    collapsed_matrices: u32,
+) {
+    editor_objects.clear();
+    let cursor_pos = editor.get_selection().get_cursor_pos();
+
+    let mut matrix_index = 0;
+    let mut token_index = 0;
+    while token_index < tokens.len() {
+        let token = &tokens[token_index];
+
+        if !need_matrix_renderer {
+            simple_draw(r, gr, render_buckets, editor_objects, token);
+            token_index += 1;
+        } else {
+            match &token.typ {
+                TokenType::Operator(OperatorTokenType::Matrix {
+                    row_count,
+                    col_count,
+                }) => {
+                    token_index = render_matrix(
+                        token_index,
+                        &tokens,
+                        *row_count,
+                        *col_count,
+                        r,
+                        gr,
+                        render_buckets,
+                        editor_objects,
+                        &editor,
+                        &matrix_editing,
+                        decimal_count,
+                        is_matrix_collapsed(collapsed_matrices, matrix_index),
+                    );
+                    matrix_index += 1;
+                }
+                TokenType::Variable { var_index } => {
+                    editor_objects.push(EditorObject {
+                        typ: EditorObjectType::Variable {
+                            var_index: *var_index,
+                        },
+                        row: r.editor_y,
+                        start_x: r.editor_x,
+                        end_x: r.editor_x + token.ptr.len(),
+                        rendered_x: r.render_x,
+                        rendered_y: r.render_y,
+                        rendered_w: token.ptr.len(),
+                        rendered_h: r.rendered_row_height,
+                    });
+                    draw_token(
+                        token,
+                        r.render_x,
+                        r.render_y.add(r.vert_align_offset),
+                        gr.current_editor_width,
+                        gr.scroll_x,
+                        gr.left_gutter_width,
+                        gr.pretty_operators,
+                        render_buckets,
+                    );
+
+                    token_index += 1;
+                    r.token_render_done(token.ptr.len(), token.ptr.len(), 0);
+                }
+                TokenType::LineReference { var_index } => {
+                    let var = vars[*var_index].as_ref().unwrap();
+
+                    let (rendered_width, rendered_height) = render_result_inside_editor(
+                        units,
+                        render_buckets,
+                        &var.value,
+                        r,
+                        gr,
+                        decimal_count,
+                    );
+
+                    let var_name_len = var.name.len();
+                    editor_objects.push(EditorObject {
+                        typ: EditorObjectType::LineReference {
+                            var_index: *var_index,
+                        },
+                        row: r.editor_y,
+                        start_x: r.editor_x,
+                        end_x: r.editor_x + var_name_len,
+                        rendered_x: r.render_x,
+                        rendered_y: r.render_y,
+                        rendered_w: rendered_width,
+                        rendered_h: rendered_height,
+                    });
+
+                    token_index += 1;
+                    r.token_render_done(
+                        var_name_len,
+                        rendered_width,
+                        if cursor_pos.column > r.editor_x {
+                            let diff = rendered_width as isize - var_name_len as isize;
+                            diff
+                        } else {
+                            0
+                        },
+                    );
+                }
+                TokenType::StringLiteral
+                    if token.ptr.starts_with(&['/', '/']) && comment_render_x > r.render_x =>
+                {
+                    let padding = comment_render_x - r.render_x;
+                    r.token_render_done(
+                        0,
+                        padding,
+                        if cursor_pos.column > r.editor_x {
+                            padding as isize
+                        } else {
+                            0
+                        },
+                    );
+                    simple_draw(r, gr, render_buckets, editor_objects, token);
+                    token_index += 1;
+                }
+                TokenType::StringLiteral
+                | TokenType::Header
+                | TokenType::NumberLiteral(_)
+                | TokenType::Operator(_)
+                | TokenType::Unit(_)
+                | TokenType::NumberErr
+                | TokenType::BoundVariable { .. } => {
+                    simple_draw(r, gr, render_buckets, editor_objects, token);
+                    token_index += 1;
+                }
+            }
+        }
+    }
+}
+
+/// For each block of consecutive lines which have an expression and an inline comment as well,
+/// returns the render x where their comments should start, 0 for the other lines.
+/// The lines are indexed relative to the window of `tokens`.
+fn calc_aligned_comment_render_x(
+    editor_content: &EditorContent<LineData>,
+    tokens: &AppTokens,
+    vars: &Variables,
+    units: &Units,
+) -> [usize; MAX_LINE_COUNT] {
+    let mut comment_render_x = [0; MAX_LINE_COUNT];
+    let mut block_start = 0;
+    let mut block_max_x = 0;
+    let line_count = editor_content
+        .line_count()
+        .saturating_sub(tokens.window_y())
+        .min(MAX_LINE_COUNT);
+    for i in 0..=line_count {
+        let expr_width = if i < line_count {
+            tokens[content_y(tokens.window_y() + i)]
+                .as_ref()
+                .and_then(|tokens| calc_expr_width_before_comment(&tokens.tokens, vars, units))
+        } else {
+            None
+        };
+        if let Some(w) = expr_width {
+            block_max_x = block_max_x.max(w);
+        } else {
+            if i - block_start > 1 {
+                for x in &mut comment_render_x[block_start..i] {
+                    *x = block_max_x;
+                }
+            }
+            block_start = i + 1;
+            block_max_x = 0;
+        }
+    }
+    return comment_render_x;
+}
+
+fn calc_expr_width_before_comment(
+    tokens: &[Token],
+    vars: &Variables,
+    units: &Units,
+) -> Option<usize> {
+    let mut width = 0;
+    for token in tokens {
+        match &token.typ {
+            TokenType::StringLiteral if token.ptr.starts_with(&['/', '/']) => {
+                return if width > 0 { Some(width) } else { None };
+            }
+            TokenType::LineReference { var_index } => match &vars[*var_index].as_ref()?.value {
+                Ok(CalcResult {
+                    typ: CalcResultType::Matrix(..),
+                    ..
+                }) => return None,
+                Ok(result) => {
+                    width += render_result(
+                        units,
+                        result,
+                        &ResultFormat::Dec,
+                        false,
+                        Some(RENDERED_RESULT_PRECISION),
+                        true,
+                        &ScientificNotation::default(),
+                        RoundingMode::HalfEven,
+                    )
+                    .chars()
+                    .count();
+                }
+                Err(_) => width += 3,
+            },
+            // multiline matrices are not aligned
+            TokenType::Operator(OperatorTokenType::Matrix { .. }) => return None,
+            _ => width += token.ptr.len(),
+        }
+    }
+    return None;
+}
+
+fn simple_draw<'text_ptr>(
+    r: &mut PerLineRenderData,
+    gr: &mut GlobalRenderData,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    editor_objects: &mut Vec<EditorObject>,
+    token: &Token<'text_ptr>,
+) {
+    if let Some(EditorObject {
+        typ: EditorObjectType::SimpleTokens,
+        end_x,
+        rendered_w,
+        ..
+    }) = editor_objects.last_mut()
+    {
+        // last token was a simple token too, extend it
+        *end_x += token.ptr.len();
+        *rendered_w += token.ptr.len();
+    } else {
+        editor_objects.push(EditorObject {
+            typ: EditorObjectType::SimpleTokens,
+            row: r.editor_y,
+            start_x: r.editor_x,
+            end_x: r.editor_x + token.ptr.len(),
+            rendered_x: r.render_x,
+            rendered_y: r.render_y,
+            rendered_w: token.ptr.len(),
+            rendered_h: r.rendered_row_height,
+        });
+    }
+    draw_token(
+        token,
+        r.render_x,
+        r.render_y.add(r.vert_align_offset),
+        gr.current_editor_width,
+        gr.scroll_x,
+        gr.left_gutter_width,
+        gr.pretty_operators,
+        render_buckets,
+    );
+
+    r.token_render_done(token.ptr.len(), token.ptr.len(), 0);
+}
+
+fn is_wrappable(
+    editor_y: ContentIndex,
+    line_tokens: &[Token],
+    matrix_editing: &Option<MatrixEditing>,
+    tokens: &AppTokens,
+    results: &Results,
+    vars: &Variables,
+    collapsed_matrices: u32,
+) -> bool {
+    let has_line_ref = line_tokens
+        .iter()
+        .any(|it| matches!(it.typ, TokenType::LineReference { .. }));
+    !has_line_ref
+        && calc_rendered_height(
+            editor_y,
+            matrix_editing,
+            tokens,
+            results,
+            vars,
+            collapsed_matrices,
+        ) == 1
+}
+
+/// Draws the tokens in `current_editor_width` wide rows below each other.
+fn render_wrapped_tokens<'text_ptr>(
+    tokens: &[Token<'text_ptr>],
+    r: &mut PerLineRenderData,
+    gr: &GlobalRenderData,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+) {
+    let w = gr.current_editor_width;
+    for token in tokens {
+        let mut drawn_len = 0;
+        while drawn_len < token.ptr.len() {
+            let x = r.render_x + drawn_len;
+            let chunk_len = (token.ptr.len() - drawn_len).min(w - x % w);
+            get_token_render_bucket(token, render_buckets).push(RenderUtf8TextMsg {
+                text: &get_token_text(token, gr.pretty_operators)[drawn_len..drawn_len + chunk_len],
+                row: r.render_y.add(x / w),
+                column: gr.left_gutter_width + x % w,
+            });
+            drawn_len += chunk_len;
+        }
+        r.token_render_done(token.ptr.len(), token.ptr.len(), 0);
+    }
+}
+
+fn render_wrap_dots(
+    render_buckets: &mut RenderBuckets,
+    r: &PerLineRenderData,
+    gr: &GlobalRenderData,
+) {
+    if r.render_x > gr.current_editor_width + gr.scroll_x && !gr.is_wrapped(r.editor_y) {
+        // gutter is above text so it has to be abovetext as well
+        render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+        for y in 0..r.rendered_row_height {
+            render_buckets.draw_char(
+                Layer::AboveText,
+                gr.result_gutter_x - 1,
+                r.render_y.add(y),
+                '…',
+            );
+        }
+    }
+    if gr.scroll_x > 0 && r.render_x > 0 {
+        // the beginning of the line is scrolled out
+        render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+        for y in 0..r.rendered_row_height {
+            render_buckets.draw_char(
+                Layer::AboveText,
+                gr.left_gutter_width,
+                r.render_y.add(y),
+                '…',
+            );
+        }
+    }
+}
+
+fn draw_line_ref_chooser(
+    render_buckets: &mut RenderBuckets,
+    r: &PerLineRenderData,
+    gr: &GlobalRenderData,
+    line_reference_chooser: &Option<ContentIndex>,
+    result_gutter_x: usize,
+) {
+    if let Some(selection_row) = line_reference_chooser {
+        if *selection_row == r.editor_y {
+            render_buckets.set_color(Layer::Text, 0xFFCCCC_FF);
+            render_buckets.draw_rect(
+                Layer::Text,
+                0,
+                r.render_y,
+                result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width,
+                r.rendered_row_height,
+            );
+        }
+    }
+}
+
+/// Lines ending with an operator are waiting for more input, a dimmed '?' is rendered
+/// after their results so they can be distinguished from the finished lines.
+fn draw_incomplete_line_hints(
+    render_buckets: &mut RenderBuckets,
+    gr: &GlobalRenderData,
+    tokens: &AppTokens,
+    results: &[LineResult],
+) {
+    let x = gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.longest_visible_result_len + 1;
+    if x >= gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width {
+        return;
+    }
+    for (i, result) in results.iter().enumerate() {
+        let editor_y = content_y(gr.window_y() + i);
+        if result.is_err() || !gr.is_visible(editor_y) {
+            continue;
+        }
+        let render_y = if let Some(render_y) = gr.get_render_y(editor_y) {
+            render_y
+        } else {
+            continue;
+        };
+        let is_incomplete = tokens[editor_y]
+            .as_ref()
+            .map(|it| is_waiting_for_operand(&it.tokens))
+            .unwrap_or(false);
+        if is_incomplete {
+            let vert_align_offset = (gr.get_rendered_height(editor_y) - 1) / 2;
+            render_buckets.set_color(Layer::Text, 0xAAAAAA_FF);
+            render_buckets.draw_char(Layer::Text, x, render_y.add(vert_align_offset), '?');
+        }
+    }
+}
+
+fn draw_result_row_separators(
+    render_buckets: &mut RenderBuckets,
+    gr: &GlobalRenderData,
+    results: &[LineResult],
+) {
+    if !gr.result_row_separators {
+        return;
+    }
+    // the separator is the background of the last rendered line of the row (e.g. under a matrix)
+    render_buckets.set_color(Layer::BehindText, 0xF0F0F0_FF);
+    for (i, result) in results.iter().enumerate() {
+        let editor_y = content_y(gr.window_y() + i);
+        if !matches!(result, Ok(Some(..))) || !gr.is_visible(editor_y) {
+            continue;
+        }
+        if let Some(render_y) = gr.get_render_y(editor_y) {
+            render_buckets.draw_rect(
+                Layer::BehindText,
+                gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
+                render_y.add(gr.get_rendered_height(editor_y) - 1),
+                gr.current_result_panel_width,
+                1,
+            );
+        }
+    }
+}
+
+fn draw_result_separators(
+    render_buckets: &mut RenderBuckets,
+    gr: &GlobalRenderData,
+    results: &[LineResult],
+    editor_y_to_render_w: &[usize; MAX_LINE_COUNT],
+) {
+    if gr.result_separator.is_empty() {
+        return;
+    }
+    render_buckets.set_color(Layer::Text, 0xAAAAAA_FF);
+    for (i, result) in results.iter().enumerate() {
+        let editor_y = content_y(gr.window_y() + i);
+        if !matches!(result, Ok(Some(..))) || !gr.is_visible(editor_y) {
+            continue;
+        }
+        let render_y = if let Some(render_y) = gr.get_render_y(editor_y) {
+            render_y
+        } else {
+            continue;
+        };
+        let x = editor_y_to_render_w[i] + 1;
+        if x + gr.result_separator.chars().count() > gr.current_editor_width {
+            continue;
+        }
+        let vert_align_offset = (gr.get_rendered_height(editor_y) - 1) / 2;
+        render_buckets.draw_string(
+            Layer::Text,
+            gr.left_gutter_width + x,
+            render_y.add(vert_align_offset),
+            gr.result_separator.clone(),
+        );
+    }
+}
+
+fn is_waiting_for_operand(tokens: &[Token]) -> bool {
+    let last_token = tokens.iter().rev().find(|it| match it.typ {
+        TokenType::StringLiteral => {
+            !(it.ptr.starts_with(&['/', '/']) || it.ptr.iter().all(|ch| ch.is_ascii_whitespace()))
+        }
+        _ => true,
+    });
+    return match last_token.map(|it| &it.typ) {
+        Some(TokenType::Operator(op)) => match op {
+            OperatorTokenType::Add
+            | OperatorTokenType::Sub
+            | OperatorTokenType::Mult
+            | OperatorTokenType::Div
+            | OperatorTokenType::Mod
+            | OperatorTokenType::Pow
+            | OperatorTokenType::BinAnd
+            | OperatorTokenType::BinOr
+            | OperatorTokenType::BinXor
+            | OperatorTokenType::ShiftLeft
+            | OperatorTokenType::ShiftRight
+            | OperatorTokenType::Less
+            | OperatorTokenType::Greater
+            | OperatorTokenType::LessEq
+            | OperatorTokenType::GreaterEq
+            | OperatorTokenType::Eq
+            | OperatorTokenType::NotEq
+            | OperatorTokenType::Question
+            | OperatorTokenType::Colon
+            | OperatorTokenType::UnitConverter
+            | OperatorTokenType::Assign => true,
+            _ => false,
+        },
+        _ => false,
+    };
+}
+
+fn draw_right_gutter_num_prefixes(
+    render_buckets: &mut RenderBuckets,
+    result_gutter_x: usize,
+    editor_content: &EditorContent<LineData>,
+    r: &PerLineRenderData,
+) {
+    match editor_content.get_data(r.editor_y.as_usize()).result_format {
+        ResultFormat::Hex => {
+            render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+            render_buckets.draw_text(Layer::AboveText, result_gutter_x, r.render_y, &['0', 'x']);
+        }
+        ResultFormat::Bin => {
+            render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+            render_buckets.draw_text(Layer::AboveText, result_gutter_x, r.render_y, &['0', 'b']);
+        }
+        ResultFormat::Oct => {
+            render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+            render_buckets.draw_text(Layer::AboveText, result_gutter_x, r.render_y, &['0', 'o']);
+        }
+        ResultFormat::Dec | ResultFormat::Sci => {}
+    }
+}
+
+fn highlight_current_line(
+    render_buckets: &mut RenderBuckets,
+    r: &PerLineRenderData,
+    editor: &Editor,
+    gr: &GlobalRenderData,
+) {
+    let cursor_pos = editor.get_selection().get_cursor_pos();
+    if cursor_pos.row == r.editor_y.as_usize() {
+        render_buckets.set_color(Layer::Text, 0xFFFFCC_55);
+        render_buckets.draw_rect(
+            Layer::Text,
+            0,
+            r.render_y,
+            gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width,
+            r.rendered_row_height,
+        );
+    }
+}
+
+/// The ids of the lines referenced by the tokens of a line, both the resolved references
+/// (e.g. "&[2]" to a line above) and the ones to the lines below, which remain
+/// "&", "[", "2" and "]" tokens. References in comments are part of the comment token.
+fn find_referenced_line_ids(tokens: &[Token]) -> Vec<usize> {
+    fn parse_id(digits: &[char]) -> Option<usize> {
+        if digits.is_empty() || !digits.iter().all(|it| it.is_ascii_digit()) {
+            return None;
+        }
+        digits.iter().collect::<String>().parse::<usize>().ok()
+    }
+    let mut ids = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let id = match token.typ {
+            TokenType::LineReference { .. } => token
+                .ptr
+                .get(2..token.ptr.len().saturating_sub(1))
+                .and_then(parse_id),
+            _ if *token.ptr == ['&'] => match tokens.get(i + 1..i + 4) {
+                Some([open, id, close]) if *open.ptr == ['['] && *close.ptr == [']'] => {
+                    parse_id(id.ptr)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        ids.extend(id);
+    }
+    ids
+}
+
+/// The ids of the lines referenced anywhere in the text of the document, e.g. 2 for "&[2]".
+fn find_referenced_line_ids_in_text(editor_content: &EditorContent<LineData>) -> Vec<usize> {
+    let mut ids = Vec::new();
+    for row in 0..editor_content.line_count() {
+        let line = editor_content.get_line_valid_chars(row);
+        let mut i = 0;
+        while i + 1 < line.len() {
+            if line[i] == '&' && line[i + 1] == '[' {
+                let digit_count = line[i + 2..]
+                    .iter()
+                    .take_while(|it| it.is_ascii_digit())
+                    .count();
+                if digit_count > 0 && line.get(i + 2 + digit_count) == Some(&']') {
+                    let id = line[i + 2..i + 2 + digit_count].iter().collect::<String>();
+                    ids.extend(id.parse::<usize>().ok());
+                }
+            }
+            i += 1;
+        }
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+/// Moves the variables of the first `line_count` lines of the window at `window_y` after
+/// the carried ones. A redefined name replaces its earlier definition, and the results
+/// of the lines are carried only if a line reference points at them. When there are
+/// more than `MAX_CARRIED_VAR_COUNT`, the earliest definitions are dropped.
+fn carry_vars(
+    vars: &mut Variables,
+    line_count: usize,
+    referenced_line_ids: &[usize],
+    editor_content: &EditorContent<LineData>,
+    window_y: usize,
+) {
+    let mut carried_vars: Vec<Variable> = vars[FIRST_CARRIED_VAR_INDEX..]
+        .iter_mut()
+        .filter_map(|it| it.take())
+        .collect();
+    for (i, var) in vars[0..line_count].iter_mut().enumerate() {
+        let var = if let Some(var) = var.take() {
+            var
+        } else {
+            continue;
+        };
+        let is_line_ref = var.name.starts_with(&['&', '[']);
+        let line_id = editor_content.get_data(window_y + i).line_id;
+        if is_line_ref && (line_id == 0 || referenced_line_ids.binary_search(&line_id).is_err()) {
+            continue;
+        }
+        carried_vars.retain(|it| it.name != var.name);
+        carried_vars.push(var);
+    }
+    let dropped_count = carried_vars.len().saturating_sub(MAX_CARRIED_VAR_COUNT);
+    for (slot, var) in vars[FIRST_CARRIED_VAR_INDEX..]
+        .iter_mut()
+        .zip(carried_vars.into_iter().skip(dropped_count))
+    {
+        *slot = Some(var);
+    }
+}
+
+/// The rows which reach themselves through line references, e.g. when the first line
+/// references the second one and the second one references the first.
+/// They are the strongly connected components of the reference graph which have more than
+/// one row or a row referencing itself, found by Tarjan's algorithm in linear time.
+/// Only the lines of the window starting at `window_y` are evaluated, so the rows
+/// are relative to it.
+fn find_line_ref_cycles(editor_content: &EditorContent<LineData>, window_y: usize) -> BitFlag128 {
+    struct Tarjan {
+        referenced_rows: Vec<Vec<usize>>,
+        index: Vec<Option<usize>>,
+        low_link: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next_index: usize,
+        cyclic_rows: BitFlag128,
+    }
+
+    impl Tarjan {
+        fn visit(&mut self, row: usize) {
+            self.index[row] = Some(self.next_index);
+            self.low_link[row] = self.next_index;
+            self.next_index += 1;
+            self.stack.push(row);
+            self.on_stack[row] = true;
+            for i in 0..self.referenced_rows[row].len() {
+                let referenced_row = self.referenced_rows[row][i];
+                match self.index[referenced_row] {
+                    None => {
+                        self.visit(referenced_row);
+                        self.low_link[row] = self.low_link[row].min(self.low_link[referenced_row]);
+                    }
+                    Some(index) if self.on_stack[referenced_row] => {
+                        self.low_link[row] = self.low_link[row].min(index);
+                    }
+                    Some(..) => {}
+                }
+            }
+            if self.index[row] == Some(self.low_link[row]) {
+                let component_start = self.stack.iter().rposition(|it| *it == row).unwrap_or(0);
+                let component = self.stack.split_off(component_start);
+                let is_cycle = component.len() > 1 || self.referenced_rows[row].contains(&row);
+                for component_row in component {
+                    self.on_stack[component_row] = false;
+                    if is_cycle {
+                        self.cyclic_rows.set(component_row);
+                    }
+                }
+            }
+        }
+    }
+
+    let line_count = editor_content
+        .line_count()
+        .saturating_sub(window_y)
+        .min(MAX_LINE_COUNT);
+    let row_of_line_id: HashMap<usize, usize> = (0..line_count)
+        .map(|row| (editor_content.get_data(window_y + row).line_id, row))
+        .filter(|(line_id, _row)| *line_id != 0)
+        .collect();
+    let mut tarjan = Tarjan {
+        referenced_rows: (0..line_count)
+            .map(|row| {
+                editor_content
+                    .get_data(window_y + row)
+                    .referenced_line_ids
+                    .iter()
+                    .filter_map(|line_id| row_of_line_id.get(line_id).copied())
+                    .collect()
+            })
+            .collect(),
+        index: vec![None; line_count],
+        low_link: vec![0; line_count],
+        on_stack: vec![false; line_count],
+        stack: Vec::with_capacity(line_count),
+        next_index: 0,
+        cyclic_rows: BitFlag128::empty(),
+    };
+    for row in 0..line_count {
+        if tarjan.index[row].is_none() && !tarjan.referenced_rows[row].is_empty() {
+            tarjan.visit(row);
+        }
+    }
+    tarjan.cyclic_rows
+}
+
+// e.g. "#result_formats:0=hex,3=bin" at the end of the normalized content
+const RESULT_FORMATS_MARKER: &str = "#result_formats:";
+
+/// None for the default Dec format
+fn result_format_name(format: ResultFormat) -> Option<&'static str> {
+    match format {
+        ResultFormat::Bin => Some("bin"),
+        ResultFormat::Dec => None,
+        ResultFormat::Hex => Some("hex"),
+        ResultFormat::Oct => Some("oct"),
+        ResultFormat::Sci => Some("sci"),
+    }
+}
+
+/// Separates the result formats line written by `get_line_ref_normalized_content`
+/// from the content, None if there is no such line
+fn split_result_formats(text: &str) -> (&str, Option<Vec<(usize, ResultFormat)>>) {
+    let marker_start = if text.starts_with(RESULT_FORMATS_MARKER) {
+        Some(0)
+    } else {
+        text.rfind(&format!("\n{}", RESULT_FORMATS_MARKER))
+            .map(|it| it + 1)
+    };
+    let marker_start = match marker_start {
+        // it has to be the last line
+        Some(start) if !text[start..].trim_end().contains('\n') => start,
+        _ => return (text, None),
+    };
+    let formats = text[marker_start + RESULT_FORMATS_MARKER.len()..]
+        .trim_end()
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let row = parts.next()?.trim().parse::<usize>().ok()?;
+            let format = match parts.next()?.trim() {
+                "bin" => ResultFormat::Bin,
+                "hex" => ResultFormat::Hex,
+                "oct" => ResultFormat::Oct,
+                "sci" => ResultFormat::Sci,
+                _ => return None,
+            };
+            Some((row, format))
+        })
+        .collect();
+    // the line break in front of the marker belongs to it
+    let content_end = if marker_start > 0 {
+        marker_start - 1
+    } else {
+        0
+    };
+    (&text[0..content_end], Some(formats))
+}
+
+/// The value of the line's variable becomes Err, the same as when the evaluation fails
+fn set_line_var_to_err(
+    vars: &mut Variables,
+    editor_y: usize,
+    var_index: usize,
+    editor_content: &EditorContent<LineData>,
+) {
+    if let Some(var) = &mut vars[var_index] {
+        let line_id = editor_content.get_data(editor_y).line_id;
+        // TODO opt
+        let var_name: Vec<char> = format!("&[{}]", line_id).chars().collect();
+        var.name = Box::from(var_name);
+        var.value = Err(());
+    }
+}
+
+fn eval_line<'a>(
+    editor_content: &EditorContent<LineData>,
+    line: &[char],
+    units: &Units,
+    allocator: &'a Bump,
+    tokens_per_lines: &mut AppTokens<'a>,
+    results: &mut Results,
+    vars: &mut Variables,
+    editor_y: ContentIndex,
+    window_y: usize,
+    updated_line_ref_obj_indices: &mut Vec<EditorObjId>,
+    internal_scale: u32,
+    snap_tolerance: Option<Decimal>,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+    keyword_aliases: &[(Box<[char]>, Box<[char]>)],
+    native_fns: &[NativeFn],
+) -> (bool, BitFlag128) {
+    // the variables and the dependencies are indexed relative to the window
+    let var_index = editor_y.as_usize() - window_y;
+    // TODO avoid clone
+    let prev_var_name = vars[var_index].as_ref().map(|it| it.name.clone());
+    let line_was_fn_definition = tokens_per_lines[editor_y]
+        .as_ref()
+        .map(|tokens| {
+            let prev_line: Vec<char> = tokens
+                .tokens
+                .iter()
+                .flat_map(|it| it.ptr.iter().copied())
+                .collect();
+            parse_user_fn_definition(&prev_line).is_some()
+        })
+        .unwrap_or(false);
+    let fn_definition = parse_user_fn_definition(line);
+    let is_fn_definition = fn_definition.is_some();
+    let mut definitions = collect_user_fn_definitions(editor_content, editor_y.as_usize());
+
+    tokens_per_lines[editor_y] = Some(parse_tokens(
+        line,
+        var_index,
+        window_y,
+        units,
+        &*vars,
+        allocator,
+        FnNames {
+            user_fns: &definitions,
+            native_fns,
+        },
+        fn_definition
+            .as_ref()
+            .map(|it| &it.params[..])
+            .unwrap_or(&[]),
+    ));
+    let new_result = if let Some(fn_definition) = fn_definition {
+        // the definition itself has no value
+        vars[var_index] = None;
+        definitions.push(fn_definition);
+        let user_fns = tokenize_user_fns(
+            FnNames {
+                user_fns: &definitions,
+                native_fns,
+            },
+            units,
+            &*vars,
+            var_index,
+            window_y,
+            allocator,
+        );
+        // it is an error if the function calls itself
+        if user_fn_call_is_invalid(user_fns.len() - 1, &user_fns, &mut Vec::new()) {
+            Err(())
+        } else {
+            Ok(None)
+        }
+    } else if let Some(tokens) = &mut tokens_per_lines[editor_y] {
+        let aliased_line = replace_keyword_aliases(line, keyword_aliases);
+        let fn_names = FnNames {
+            user_fns: &definitions,
+            native_fns,
+        };
+        let user_fns = tokenize_user_fns(fn_names, units, &*vars, var_index, window_y, allocator);
+        let fns = CustomFns {
+            user_fns: &user_fns,
+            native_fns,
+        };
+        let result = match &aliased_line {
+            None => evaluate_tokens_and_save_result(
+                &mut *vars,
+                editor_y.as_usize(),
+                var_index,
+                editor_content,
+                &mut tokens.tokens,
+                &mut tokens.shunting_output_stack,
+                &fns,
+                line,
+                internal_scale,
+                snap_tolerance,
+                rounding_mode,
+                angle_mode,
+            ),
+            Some(aliased_line) => {
+                // the tokens of the original line are kept for rendering,
+                // only the result comes from the aliased one
+                let mut aliased_tokens = parse_tokens(
+                    aliased_line,
+                    var_index,
+                    window_y,
+                    units,
+                    &*vars,
+                    allocator,
+                    fn_names,
+                    &[],
+                );
+                evaluate_tokens_and_save_result(
+                    &mut *vars,
+                    editor_y.as_usize(),
+                    var_index,
+                    editor_content,
+                    &mut aliased_tokens.tokens,
+                    &mut aliased_tokens.shunting_output_stack,
+                    &fns,
+                    aliased_line,
+                    internal_scale,
+                    snap_tolerance,
+                    rounding_mode,
+                    angle_mode,
+                )
+            }
+        };
+        let result = result.map(|it| it.map(|it| it.result));
+        result
+    } else {
+        Ok(None)
+    };
+    let vars: &Variables = vars;
+
+    let prev_result = std::mem::replace(&mut results[editor_y], new_result);
+    let result_has_changed = {
+        let new_result = &results[editor_y];
+        match (&prev_result, new_result) {
+            (Ok(Some(_)), Err(_)) => true,
+            (Ok(Some(_)), Ok(None)) => true,
+            (Ok(Some(prev_r)), Ok(Some(new_r))) => prev_r.typ != new_r.typ,
+            (Err(_), Err(_)) => false,
+            (Err(_), Ok(None)) => true,
+            (Err(_), Ok(Some(_))) => true,
+            (Ok(None), Ok(Some(_))) => true,
+            (Ok(None), Ok(None)) => false,
+            (Ok(None), Err(_)) => true,
+        }
+    };
+
+    let mut rows_to_recalc = BitFlag128::empty();
+    if result_has_changed {
+        let line_ref_name = NoteCalcApp::get_line_ref_name(&editor_content, editor_y.as_usize());
+        rows_to_recalc.merge(NoteCalcApp::find_line_ref_dependant_lines(
+            &line_ref_name,
+            tokens_per_lines,
+            var_index,
+            updated_line_ref_obj_indices,
+        ));
+    }
+
+    let curr_var_name = vars[var_index].as_ref().map(|it| &it.name);
+    rows_to_recalc.merge(find_lines_that_affected_by_var_change(
+        result_has_changed,
+        curr_var_name,
+        prev_var_name,
+        tokens_per_lines,
+        var_index,
+    ));
+
+    rows_to_recalc.merge(find_sum_variable_name(tokens_per_lines, var_index));
+    if result_has_changed {
+        rows_to_recalc.merge(find_ans_references(tokens_per_lines, var_index));
+    }
+    if is_fn_definition || line_was_fn_definition {
+        rows_to_recalc.merge(find_fn_calls(tokens_per_lines, var_index));
+    }
+    return (result_has_changed, rows_to_recalc);
+}
+
+fn find_fn_calls(tokens_per_lines: &AppTokens, line_var_index: usize) -> BitFlag128 {
+    let mut rows_to_recalc = BitFlag128::empty();
+    for (line_index, tokens) in tokens_per_lines.iter().skip(line_var_index + 1).enumerate() {
+        if let Some(tokens) = tokens {
+            // the calls of the user defined functions, and the string literals followed
+            // by a parenthesis which might become one
+            let calls_fn = tokens.tokens.iter().any(|it| {
+                matches!(
+                    it.typ,
+                    TokenType::Operator(OperatorTokenType::Fn {
+                        typ: FnType::User(..),
+                        ..
+                    })
+                )
+            }) || tokens
+                .tokens
+                .windows(2)
+                .any(|it| it[0].typ == TokenType::StringLiteral && it[1].ptr.starts_with(&['(']));
+            if calls_fn {
+                rows_to_recalc.merge(BitFlag128::single_row(line_var_index + 1 + line_index));
+            }
+        }
+    }
+    return rows_to_recalc;
+}
+
+fn find_ans_references(tokens_per_lines: &AppTokens, line_var_index: usize) -> BitFlag128 {
+    let mut rows_to_recalc = BitFlag128::empty();
+    for (line_index, tokens) in tokens_per_lines.iter().skip(line_var_index + 1).enumerate() {
+        if let Some(tokens) = tokens {
+            // either a valid "ans[n]" or an "ans" literal which might become valid
+            let uses_ans = tokens
+                .tokens
+                .iter()
+                .any(|it| it.ptr.starts_with(&['a', 'n', 's']));
+            if uses_ans {
+                rows_to_recalc.merge(BitFlag128::single_row(line_var_index + 1 + line_index));
+            }
+        }
+    }
+    return rows_to_recalc;
+}
+
+fn find_sum_variable_name(tokens_per_lines: &AppTokens, line_var_index: usize) -> BitFlag128 {
+    let mut rows_to_recalc = BitFlag128::empty();
+    'outer: for (line_index, tokens) in tokens_per_lines.iter().skip(line_var_index + 1).enumerate()
+    {
+        if let Some(tokens) = tokens {
+            for token in &tokens.tokens {
+                match token.typ {
+                    TokenType::Header => {
+                        break 'outer;
+                    }
+                    TokenType::Variable { var_index } if var_index == SUM_VARIABLE_INDEX => {
+                        rows_to_recalc
+                            .merge(BitFlag128::single_row(line_var_index + 1 + line_index));
+                        break 'outer;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    return rows_to_recalc;
+}
+
+fn find_lines_that_affected_by_var_change<'b>(
+    needs_dependency_check: bool,
+    curr_var_name: Option<&Box<[char]>>,
+    prev_var_name: Option<Box<[char]>>,
+    tokens_per_lines: &AppTokens<'b>,
+    line_var_index: usize,
+) -> BitFlag128 {
+    let mut rows_to_recalc = BitFlag128::empty();
+    match (prev_var_name, curr_var_name) {
+        (None, Some(var_name)) => {
+            // nem volt még, de most van
+            // recalc all the rows which uses this variable name
+            for (i, tokens) in tokens_per_lines.iter().skip(line_var_index + 1).enumerate() {
+                if let Some(tokens) = tokens {
+                    for token in &tokens.tokens {
+                        match token.typ {
+                            TokenType::StringLiteral if *token.ptr == **var_name => {
+                                rows_to_recalc
+                                    .merge(BitFlag128::single_row(line_var_index + 1 + i));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        (Some(old_var_name), None) => {
+            // volt, de most nincs
+            // recalc all the rows which uses the old variable name
+            for (i, tokens) in tokens_per_lines.iter().skip(line_var_index + 1).enumerate() {
+                if let Some(tokens) = tokens {
+                    for token in &tokens.tokens {
+                        match token.typ {
+                            TokenType::Variable { .. } if *token.ptr == *old_var_name => {
+                                rows_to_recalc
+                                    .merge(BitFlag128::single_row(line_var_index + 1 + i));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        (Some(old_var_name), Some(var_name)) if old_var_name != *var_name => {
+            // volt, de most más a neve
+            for (i, tokens) in tokens_per_lines.iter().skip(line_var_index + 1).enumerate() {
+                if let Some(tokens) = tokens {
+                    for token in &tokens.tokens {
+                        let recalc = match token.typ {
+                            TokenType::StringLiteral => var_name.starts_with(token.ptr),
+                            TokenType::Variable { .. } => *token.ptr == *old_var_name,
+                            _ => false,
+                        };
+                        if recalc {
+                            rows_to_recalc.merge(BitFlag128::single_row(line_var_index + 1 + i));
+                        }
+                    }
+                }
+            }
+        }
+        (Some(_old_var_name), Some(var_name)) => {
+            if !needs_dependency_check {
+                return BitFlag128::empty();
+            }
+            // volt is, van is, a neve is ugyanaz
+            for (i, tokens) in tokens_per_lines.iter().skip(line_var_index + 1).enumerate() {
+                if let Some(tokens) = tokens {
+                    for token in &tokens.tokens {
+                        let recalc = match token.typ {
+                            TokenType::Variable { .. } if *token.ptr == **var_name => true,
+                            _ => false,
+                        };
+                        if recalc {
+                            rows_to_recalc.merge(BitFlag128::single_row(line_var_index + 1 + i));
+                        }
+                    }
+                }
+            }
+        }
+        (None, None) => {}
+    }
+    return rows_to_recalc;
+}
+
+fn evaluate_tokens_and_save_result<'text_ptr>(
+    vars: &mut Variables,
+    editor_y: usize,
+    var_index: usize,
+    editor_content: &EditorContent<LineData>,
+    tokens: &mut [Token<'text_ptr>],
+    shunting_output_stack: &mut Vec<ShuntingYardResult>,
+    fns: &CustomFns,
+    line: &[char],
+    internal_scale: u32,
+    snap_tolerance: Option<Decimal>,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+) -> Result<Option<EvaluationResult>, ()> {
+    let mut result = evaluate_tokens_with_fns(
+        tokens,
+        shunting_output_stack,
+        &vars,
+        fns,
+        rounding_mode,
+        angle_mode,
+    );
+    if let Ok(Some(result)) = &mut result {
+        round_result(&mut result.result, internal_scale, rounding_mode);
+        if let Some(tolerance) = &snap_tolerance {
+            snap_result(&mut result.result, tolerance);
+        }
+    }
+    if let Ok(Some(result)) = &result {
+        fn replace_or_insert_var(
+            vars: &mut Variables,
+            var_name: &[char],
+            result: CalcResult,
+            var_index: usize,
+        ) {
+            if let Some(var) = &mut vars[var_index] {
+                var.name = Box::from(var_name);
+                var.value = Ok(result);
+            } else {
+                vars[var_index] = Some(Variable {
+                    name: Box::from(var_name),
+                    value: Ok(result),
+                });
+            };
+        }
+
+        if result.assignment {
+            let var_name = {
+                let mut i = 0;
+                if line[0] == '=' {
+                    // it might happen that there are more '=' in a line.
+                    // To avoid panic, start the index from 1, so if the first char is
+                    // '=', it will be ignored.
+                    i += 1;
+                }
+                // skip whitespaces
+                while line[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                let start = i;
+                // take until '='
+                while line[i] != '=' {
+                    i += 1;
+                }
+                // remove trailing whitespaces
+                i -= 1;
+                while i > start && line[i].is_ascii_whitespace() {
+                    i -= 1;
+                }
+                let end = i;
+                &line[start..=end]
+            };
+            if !var_name.is_empty() {
+                replace_or_insert_var(vars, var_name, result.result.clone(), var_index);
+            }
+        } else {
+            let line_data = editor_content.get_data(editor_y);
+            debug_assert!(line_data.line_id > 0);
+            let line_id = line_data.line_id;
+            // TODO opt
+            let var_name: Vec<char> = format!("&[{}]", line_id).chars().collect();
+            replace_or_insert_var(vars, &var_name, result.result.clone(), var_index);
+        }
+    } else if let Some(var) = &mut vars[var_index] {
+        let line_data = editor_content.get_data(editor_y);
+        debug_assert!(line_data.line_id > 0);
+        let line_id = line_data.line_id;
+        // TODO opt
+        let var_name: Vec<char> = format!("&[{}]", line_id).chars().collect();
+        var.name = Box::from(var_name);
+        var.value = Err(());
+    } else {
+        vars[var_index] = None;
+    }
+    result
+}
+
+fn sum_result(sum_var: &mut Variable, result: &CalcResult, sum_is_null: &mut bool) {
+    if *sum_is_null {
+        sum_var.value = Ok(result.clone());
+        *sum_is_null = false;
+    } else {
+        sum_var.value = match &sum_var.value {
+            Ok(current_sum) => {
+                if let Some(ok) = add_op(&current_sum, &result) {
+                    Ok(ok)
+                } else {
+                    Err(())
+                }
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+fn render_matrix<'text_ptr>(
+    token_index: usize,
+    tokens: &[Token<'text_ptr>],
+    row_count: usize,
+    col_count: usize,
+    r: &mut PerLineRenderData,
+    gr: &mut GlobalRenderData,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    editor_objects: &mut Vec<EditorObject>,
+    editor: &Editor,
+    matrix_editing: &Option<MatrixEditing>,
+    // TODO: why unused?
+    _decimal_count: Option<usize>,
+    collapsed: bool,
+) -> usize {
+    let mut text_width = 0;
+    let mut end_token_index = token_index;
+    while tokens[end_token_index].typ != TokenType::Operator(OperatorTokenType::BracketClose) {
+        text_width += tokens[end_token_index].ptr.len();
+        end_token_index += 1;
+    }
+    // ignore the bracket as well
+    text_width += 1;
+    end_token_index += 1;
+
+    let cursor_pos = editor.get_selection().get_cursor_pos();
+    let cursor_inside_matrix: bool = if editor.get_selection().is_range().is_none()
+        && cursor_pos.row == r.editor_y.as_usize()
+        && cursor_pos.column > r.editor_x
+        && cursor_pos.column < r.editor_x + text_width
+    {
+        // cursor is inside the matrix
+        true
+    } else {
+        false
+    };
+
+    let new_render_x = if let (true, Some(mat_editor)) = (cursor_inside_matrix, matrix_editing) {
+        mat_editor.render(
+            r.render_x,
+            r.render_y,
+            gr.current_editor_width,
+            gr.left_gutter_width,
+            render_buckets,
+            r.rendered_row_height,
+            gr.cursor_style,
+        )
+    } else if collapsed {
+        render_collapsed_matrix_obj(
+            r.render_x,
+            r.render_y.add(r.vert_align_offset),
+            gr.current_editor_width,
+            gr.left_gutter_width,
+            row_count,
+            col_count,
+            render_buckets,
+        )
+    } else {
+        render_matrix_obj(
+            r.render_x,
+            r.render_y,
+            gr.current_editor_width,
+            gr.left_gutter_width,
+            row_count,
+            col_count,
+            &tokens[token_index..],
+            render_buckets,
+            r.rendered_row_height,
+            gr.pretty_operators,
+        )
+    };
+
+    let rendered_width = new_render_x - r.render_x;
+    editor_objects.push(EditorObject {
+        typ: EditorObjectType::Matrix {
+            row_count,
+            col_count,
+        },
+        row: r.editor_y,
+        start_x: r.editor_x,
+        end_x: r.editor_x + text_width,
+        rendered_x: r.render_x,
+        rendered_y: r.render_y,
+        rendered_w: rendered_width,
+        rendered_h: if collapsed && !(cursor_inside_matrix && matrix_editing.is_some()) {
+            1
+        } else {
+            MatrixData::calc_render_height(row_count)
+        },
+    });
+
+    let x_diff = if cursor_pos.row == r.editor_y.as_usize()
+        && cursor_pos.column >= r.editor_x + text_width
+    {
+        let diff = rendered_width as isize - text_width as isize;
+        diff
+    } else {
+        0
+    };
+
+    r.token_render_done(text_width, rendered_width, x_diff);
+    return end_token_index;
+}
+
+struct SelectionResult {
+    sum: String,
+    // only for multiline selections
+    average: Option<String>,
+    // how many lines contributed to the sum
+    count: usize,
+}
+
+fn evaluate_selection(
+    units: &Units,
+    editor: &Editor,
+    editor_content: &EditorContent<LineData>,
+    vars: &Variables,
+    native_fns: &[NativeFn],
+    results: &Results,
+    allocator: &Bump,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+    respect_resets: bool,
+    with_average: bool,
+    scientific_notation: &ScientificNotation,
+) -> Option<SelectionResult> {
+    let sel = editor.get_selection();
+    // TODO optimize vec allocations
+    let mut tokens = Vec::with_capacity(128);
+    // TODO we should be able to mark the arena allcoator and free it at the end of the function
+    if sel.start.row == sel.end.unwrap().row {
+        if let Some(selected_text) = Editor::get_selected_text_single_line(sel, &editor_content) {
+            if let Ok(Some(result)) = evaluate_text(
+                units,
+                selected_text,
+                vars,
+                editor_content,
+                native_fns,
+                &mut tokens,
+                sel.start.row,
+                results.window_y(),
+                allocator,
+                rounding_mode,
+                angle_mode,
+            ) {
+                if result.there_was_operation {
+                    let result_str = render_result(
+                        &units,
+                        &result.result,
+                        &editor_content.get_data(sel.start.row).result_format,
+                        result.there_was_unit_conversion,
+                        Some(RENDERED_RESULT_PRECISION),
+                        true,
+                        scientific_notation,
+                        rounding_mode,
+                    );
+                    return Some(SelectionResult {
+                        sum: result_str,
+                        average: None,
+                        count: 1,
+                    });
+                }
+            }
+        }
+    } else {
+        let mut sum: Option<&CalcResult> = None;
+        // so sum can contain references to temp values
+        #[allow(unused_assignments)]
+        let mut tmp_sum = CalcResult::hack_empty();
+        let mut result_count = 0;
+        for row_index in sel.get_first().row..=sel.get_second().row {
+            if respect_resets
+                && is_selection_sum_reset_line(editor_content.get_line_valid_chars(row_index))
+            {
+                sum = None;
+                result_count = 0;
+            } else if let Err(..) = &results[content_y(row_index)] {
+                return None;
+            } else if let Ok(Some(line_result)) = &results[content_y(row_index)] {
+                result_count += 1;
+                if let Some(sum_r) = &sum {
+                    if let Some(add_result) = add_op(sum_r, &line_result) {
+                        tmp_sum = add_result;
+                        sum = Some(&tmp_sum);
+                    } else {
+                        return None; // don't show anything if can't add all the rows
+                    }
+                } else {
+                    sum = Some(&line_result);
+                }
+            }
+        }
+        if let Some(sum) = sum {
+            let format = &editor_content.get_data(sel.start.row).result_format;
+            let render = |result: &CalcResult| {
+                render_result(
+                    &units,
+                    result,
+                    format,
+                    false,
+                    Some(RENDERED_RESULT_PRECISION),
+                    true,
+                    scientific_notation,
+                    rounding_mode,
+                )
+            };
+            let average = if with_average {
+                let count = CalcResult::new(CalcResultType::Number(Decimal::from(result_count)), 0);
+                divide_op(sum, &count).map(|it| render(&it))
+            } else {
+                None
+            };
+            return Some(SelectionResult {
+                sum: render(sum),
+                average,
+                count: result_count,
+            });
+        }
+    }
+    return None;
+}
+
+/// Evaluates `text` as if it were in the line `editor_y`, so the functions defined above
+/// that line can be called
+fn evaluate_text<'text_ptr>(
+    units: &Units,
+    text: &[char],
+    vars: &Variables,
+    editor_content: &EditorContent<LineData>,
+    native_fns: &[NativeFn],
+    tokens: &mut Vec<Token<'text_ptr>>,
+    editor_y: usize,
+    window_y: usize,
+    allocator: &'text_ptr Bump,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+) -> Result<Option<EvaluationResult>, ()> {
+    let definitions = collect_user_fn_definitions(editor_content, editor_y);
+    let fn_names = FnNames {
+        user_fns: &definitions,
+        native_fns,
+    };
+    // the variables of the lines above `editor_y` in the window are visible
+    let var_index = editor_y.saturating_sub(window_y).min(MAX_LINE_COUNT);
+    TokenParser::parse_line_with_fns(
+        text,
+        vars,
+        tokens,
+        &units,
+        var_index,
+        window_y,
+        allocator,
+        fn_names,
+        &[],
+    );
+    let mut shunting_output_stack = Vec::with_capacity(4);
+    ShuntingYard::shunting_yard(tokens, &mut shunting_output_stack);
+    let user_fns = tokenize_user_fns(fn_names, units, vars, var_index, window_y, allocator);
+    return evaluate_tokens_with_fns(
+        tokens,
+        &mut shunting_output_stack,
+        &vars,
+        &CustomFns {
+            user_fns: &user_fns,
+            native_fns,
+        },
+        rounding_mode,
+        angle_mode,
+    );
+}
+
+fn render_line_result(
+    units: &Units,
+    result: &LineResult,
+    format: &ResultFormat,
+    scientific_notation: &ScientificNotation,
+    rounding_mode: RoundingMode,
+) -> String {
+    match result {
+        Err(..) => "Err".to_owned(),
+        Ok(None) => String::new(),
+        Ok(Some(result)) => render_result(
+            units,
+            result,
+            format,
+            false,
+            Some(RENDERED_RESULT_PRECISION),
+            true,
+            scientific_notation,
+            rounding_mode,
+        ),
+    }
+}
+
+fn render_matrix_obj<'text_ptr>(
+    mut render_x: usize,
+    render_y: CanvasY,
+    current_editor_width: usize,
+    left_gutter_width: usize,
+    row_count: usize,
+    col_count: usize,
+    tokens: &[Token<'text_ptr>],
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    rendered_row_height: usize,
+    pretty_operators: bool,
+) -> usize {
+    // centered inside the row, independently from the other matrices in the line
+    let vert_align_offset = (rendered_row_height - MatrixData::calc_render_height(row_count)) / 2;
+
+    if render_x < current_editor_width {
+        render_matrix_left_brackets(
+            render_x + left_gutter_width,
+            render_y,
+            row_count,
+            render_buckets,
+            vert_align_offset,
+        );
+    }
+    render_x += 1;
+
+    let tokens_per_cell = {
+        // it grows for bigger matrices, missing cells are rendered empty
+        let mut matrix_cells_for_tokens: SmallVec<[&[Token]; 32]> =
+            SmallVec::with_capacity(row_count * col_count);
+
+        // without the trailing whitespaces, so "[1 , 2]" is rendered like "[1, 2]"
+        fn trim_end<'a, 'b>(cell_tokens: &'a [Token<'b>]) -> &'a [Token<'b>] {
+            let len = cell_tokens
+                .iter()
+                .rposition(|it| !it.ptr.iter().all(|ch| ch.is_ascii_whitespace()))
+                .map(|it| it + 1)
+                .unwrap_or(0);
+            &cell_tokens[0..len]
+        }
+        let mut start_token_index = 0;
+        let mut can_ignore_ws = true;
+        for (token_index, token) in tokens.iter().enumerate() {
+            if token.typ == TokenType::Operator(OperatorTokenType::BracketClose) {
+                matrix_cells_for_tokens.push(trim_end(&tokens[start_token_index..token_index]));
+                break;
+            } else if token.typ
+                == TokenType::Operator(OperatorTokenType::Matrix {
+                    row_count,
+                    col_count,
+                })
+                || token.typ == TokenType::Operator(OperatorTokenType::BracketOpen)
+            {
+                // skip them
+                start_token_index = token_index + 1;
+            } else if can_ignore_ws && token.ptr[0].is_ascii_whitespace() {
+                start_token_index = token_index + 1;
+            } else if token.typ == TokenType::Operator(OperatorTokenType::Comma)
+                || token.typ == TokenType::Operator(OperatorTokenType::Semicolon)
+            {
+                matrix_cells_for_tokens.push(trim_end(&tokens[start_token_index..token_index]));
+                start_token_index = token_index + 1;
+                can_ignore_ws = true;
+            } else {
+                can_ignore_ws = false;
+            }
+        }
+        matrix_cells_for_tokens
+    };
+
+    for col_i in 0..col_count {
+        let max_width: usize = (0..row_count)
+            .map(|row_i| {
+                tokens_per_cell
+                    .get(row_i * col_count + col_i)
+                    .copied()
+                    .unwrap_or(&[])
+                    .iter()
+                    .map(|it| it.ptr.len())
+                    .sum()
+            })
+            .max()
+            .unwrap();
+        for row_i in 0..row_count {
+            let tokens = tokens_per_cell
+                .get(row_i * col_count + col_i)
+                .copied()
+                .unwrap_or(&[]);
+            let len: usize = tokens.iter().map(|it| it.ptr.len()).sum();
+            let offset_x = max_width - len;
+            let mut local_x = 0;
+            // the content of the matrix starts from the second row
+            let matrix_ascii_header_offset = if row_count == 1 { 0 } else { 1 };
+            let dst_y = row_i + vert_align_offset + matrix_ascii_header_offset;
+            for token in tokens.iter() {
+                if render_x <= current_editor_width {
+                    draw_token(
+                        token,
+                        render_x + offset_x + local_x,
+                        render_y.add(dst_y),
+                        current_editor_width,
+                        0,
+                        left_gutter_width,
+                        pretty_operators,
+                        render_buckets,
+                    );
+                }
+                local_x += token.ptr.len();
+            }
+        }
+        render_x += if col_i + 1 < col_count {
+            max_width + 2
+        } else {
+            max_width
+        };
+    }
+
+    if render_x < current_editor_width {
+        render_matrix_right_brackets(
+            render_x + left_gutter_width,
+            render_y,
+            row_count,
+            render_buckets,
+            vert_align_offset,
+        );
+    }
+    render_x += 1;
+
+    render_x
+}
+
+fn render_collapsed_matrix_obj(
+    render_x: usize,
+    render_y: CanvasY,
+    current_editor_width: usize,
+    left_gutter_width: usize,
+    row_count: usize,
+    col_count: usize,
+    render_buckets: &mut RenderBuckets,
+) -> usize {
+    let placeholder = format!("[{}x{} matrix]", row_count, col_count);
+    let len = placeholder.len();
+    if render_x < current_editor_width {
+        render_buckets.draw_string(
+            Layer::Text,
+            render_x + left_gutter_width,
+            render_y,
+            placeholder,
+        );
+    }
+    return render_x + len;
+}
+
+fn render_matrix_left_brackets(
+    x: usize,
+    render_y: CanvasY,
+    row_count: usize,
+    render_buckets: &mut RenderBuckets,
+    vert_align_offset: usize,
+) {
+    if row_count == 1 {
+        render_buckets.operators.push(RenderUtf8TextMsg {
+            text: &['['],
+            row: render_y.add(vert_align_offset),
+            column: x,
+        });
+    } else {
+        render_buckets.operators.push(RenderUtf8TextMsg {
+            text: &['┌'],
+            row: render_y.add(vert_align_offset),
+            column: x,
+        });
+        for i in 0..row_count {
+            render_buckets.operators.push(RenderUtf8TextMsg {
+                text: &['│'],
+                row: render_y.add(i + vert_align_offset + 1),
+                column: x,
+            });
+        }
+        render_buckets.operators.push(RenderUtf8TextMsg {
+            text: &['└'],
+            row: render_y.add(row_count + vert_align_offset + 1),
+            column: x,
+        });
+    };
+}
+
+fn render_matrix_right_brackets(
+    x: usize,
+    render_y: CanvasY,
+    row_count: usize,
+    render_buckets: &mut RenderBuckets,
+    vert_align_offset: usize,
+) {
+    if row_count == 1 {
+        render_buckets.operators.push(RenderUtf8TextMsg {
+            text: &[']'],
+            row: render_y.add(vert_align_offset),
+            column: x,
+        });
+    } else {
+        render_buckets.operators.push(RenderUtf8TextMsg {
+            text: &['┐'],
+            row: render_y.add(vert_align_offset),
+            column: x,
+        });
+        for i in 0..row_count {
+            render_buckets.operators.push(RenderUtf8TextMsg {
+                text: &['│'],
+                row: render_y.add(i + 1 + vert_align_offset),
+                column: x,
+            });
+        }
+        render_buckets.operators.push(RenderUtf8TextMsg {
+            text: &['┘'],
+            row: render_y.add(row_count + 1 + vert_align_offset),
+            column: x,
+        });
+    }
+}
+
+fn render_matrix_result<'text_ptr>(
+    units: &Units,
+    mut render_x: usize,
+    render_y: CanvasY,
+    mat: &MatrixData,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    prev_mat_result_lengths: Option<&ResultLengths>,
+    rendered_row_height: usize,
+    decimal_count: Option<usize>,
+    format: &ResultFormat,
+    scientific_notation: &ScientificNotation,
+    rounding_mode: RoundingMode,
+) -> usize {
+    let start_x = render_x;
+
+    let vert_align_offset = (rendered_row_height - mat.render_height()) / 2;
+    render_matrix_left_brackets(
+        start_x,
+        render_y,
+        mat.row_count,
+        render_buckets,
+        vert_align_offset,
+    );
+    render_x += 1;
+
+    let cells_strs = {
+        let mut tokens_per_cell: SmallVec<[String; 32]> = SmallVec::with_capacity(32);
+
+        for cell in mat.cells.iter() {
+            let result_str = render_result(
+                units,
+                cell,
+                format,
+                false,
+                decimal_count,
+                true,
+                scientific_notation,
+                rounding_mode,
+            );
+            tokens_per_cell.push(result_str);
+        }
+        tokens_per_cell
+    };
+
+    let max_lengths = {
+        let mut max_lengths = ResultLengths {
+            int_part_len: prev_mat_result_lengths
+                .as_ref()
+                .map(|it| it.int_part_len)
+                .unwrap_or(0),
+            frac_part_len: prev_mat_result_lengths
+                .as_ref()
+                .map(|it| it.frac_part_len)
+                .unwrap_or(0),
+            unit_part_len: prev_mat_result_lengths
+                .as_ref()
+                .map(|it| it.unit_part_len)
+                .unwrap_or(0),
+        };
+        for cell_str in &cells_strs {
+            let lengths = get_int_frac_part_len_in_format(cell_str, format);
+            max_lengths.set_max(&lengths);
+        }
+        max_lengths
+    };
+    render_buckets.set_color(Layer::Text, 0x000000_FF);
+
+    for col_i in 0..mat.col_count {
+        for row_i in 0..mat.row_count {
+            let cell_str = &cells_strs[row_i * mat.col_count + col_i];
+            let lengths = get_int_frac_part_len_in_format(cell_str, format);
+            // Draw integer part
+            let offset_x = max_lengths.int_part_len - lengths.int_part_len;
+            // the content of the matrix starts from the second row
+            let matrix_ascii_header_offset = if mat.row_count == 1 { 0 } else { 1 };
+            let dst_y = render_y.add(row_i + vert_align_offset + matrix_ascii_header_offset);
+            render_buckets.draw_string(
+                Layer::Text,
+                render_x + offset_x,
+                dst_y,
+                // TOOD nem kell clone, csinálj iter into vhogy
+                cell_str[0..lengths.int_part_len].to_owned(),
+            );
+
+            let mut frac_offset_x = 0;
+            if lengths.frac_part_len > 0 {
+                render_buckets.draw_string(
+                    Layer::Text,
+                    render_x + offset_x + lengths.int_part_len,
+                    dst_y,
+                    // TOOD nem kell clone, csinálj iter into vhogy
+                    cell_str[lengths.int_part_len..lengths.int_part_len + lengths.frac_part_len]
+                        .to_owned(),
+                )
+            } else if max_lengths.frac_part_len > 0 {
+                render_buckets.draw_char(
+                    Layer::Text,
+                    render_x + offset_x + lengths.int_part_len,
+                    dst_y,
+                    '.',
+                );
+                frac_offset_x = 1;
+            }
+            for i in 0..max_lengths.frac_part_len - lengths.frac_part_len - frac_offset_x {
+                render_buckets.draw_char(
+                    Layer::Text,
+                    render_x
+                        + offset_x
+                        + lengths.int_part_len
+                        + lengths.frac_part_len
+                        + frac_offset_x
+                        + i,
+                    dst_y,
+                    '0',
+                )
+            }
+            if lengths.unit_part_len > 0 {
+                render_buckets.draw_string(
+                    Layer::Text,
+                    render_x + offset_x + lengths.int_part_len + max_lengths.frac_part_len + 1,
+                    dst_y,
+                    // TOOD nem kell clone, csinálj iter into vhogy
+                    // +1, skip space
+                    cell_str[lengths.int_part_len + lengths.frac_part_len + 1..].to_owned(),
+                )
+            }
+        }
+        render_x += if col_i + 1 < mat.col_count {
+            (max_lengths.int_part_len + max_lengths.frac_part_len + max_lengths.unit_part_len) + 2
+        } else {
+            max_lengths.int_part_len + max_lengths.frac_part_len + max_lengths.unit_part_len
+        };
+    }
+
+    render_matrix_right_brackets(
+        render_x,
+        render_y,
+        mat.row_count,
+        render_buckets,
+        vert_align_offset,
+    );
+    render_x += 1;
+    return render_x - start_x;
+}
+
+fn render_result_inside_editor<'text_ptr>(
+    units: &Units,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    result: &Result<CalcResult, ()>,
+    r: &PerLineRenderData,
+    gr: &GlobalRenderData,
+    decimal_count: Option<usize>,
+) -> (usize, usize) {
+    return match &result {
+        Ok(CalcResult {
+            typ: CalcResultType::Matrix(mat),
+            ..
+        }) => {
+            let rendered_width = render_matrix_result(
+                units,
+                gr.left_gutter_width + r.render_x,
+                r.render_y,
+                mat,
+                render_buckets,
+                None,
+                r.rendered_row_height,
+                decimal_count,
+                &ResultFormat::Dec,
+                &ScientificNotation::default(),
+                RoundingMode::HalfEven,
+            );
+            (rendered_width, mat.render_height())
+        }
+        Ok(result) => {
+            // TODO: optimize string alloc
+            let result_str = render_result(
+                &units,
+                result,
+                &ResultFormat::Dec,
+                false,
+                decimal_count,
+                true,
+                &ScientificNotation::default(),
+                RoundingMode::HalfEven,
+            );
+            let text_len = result_str.chars().count();
+            let bounded_text_len = text_len
+                .min((gr.current_editor_width as isize - r.render_x as isize).max(0) as usize);
+
+            render_buckets.line_ref_results.push(RenderStringMsg {
+                text: result_str[0..bounded_text_len].to_owned(),
+                row: r.render_y,
+                column: r.render_x + gr.left_gutter_width,
+            });
+            (text_len, 1)
+        }
+        Err(_) => {
+            render_buckets.line_ref_results.push(RenderStringMsg {
+                text: "Err".to_owned(),
+                row: r.render_y,
+                column: r.render_x + gr.left_gutter_width,
+            });
+            (3, 1)
+        }
+    };
+}
+
+struct ResultTmp {
+    buffer_ptr: Option<Range<usize>>,
+    editor_y: ContentIndex,
+    lengths: ResultLengths,
+}
+
+pub const MAX_VISIBLE_HEADER_COUNT: usize = 16;
+struct ResultRender {
+    result_ranges: SmallVec<[ResultTmp; MAX_LINE_COUNT]>,
+    max_len: usize,
+    max_lengths: [ResultLengths; MAX_VISIBLE_HEADER_COUNT],
+    result_counts_in_regions: [usize; MAX_VISIBLE_HEADER_COUNT],
+}
+
+impl ResultRender {
+    pub fn new(vec: SmallVec<[ResultTmp; MAX_LINE_COUNT]>) -> ResultRender {
+        return ResultRender {
+            result_ranges: vec,
+            max_len: 0,
+            max_lengths: [ResultLengths {
+                int_part_len: 0,
+                frac_part_len: 0,
+                unit_part_len: 0,
+            }; 16],
+            result_counts_in_regions: [0; MAX_VISIBLE_HEADER_COUNT],
+        };
+    }
+}
+
+fn render_results_into_buf_and_calc_len<'text_ptr>(
+    units: &Units,
+    results: &[LineResult],
+    tmp: &mut ResultRender,
+    editor_content: &EditorContent<LineData>,
+    gr: &GlobalRenderData,
+    vars: &Variables,
+    decimal_count: Option<usize>,
+) {
+    let mut result_buffer_index = 0;
+    let result_buffer = unsafe { &mut RESULT_BUFFER };
+    // calc max length and render results into buffer
+    let mut region_index = 0;
+    let mut region_count_offset = 0;
+    // `results` and `vars` start at the first line of the window
+    for (i, result) in results.iter().enumerate() {
+        let editor_y = content_y(gr.window_y() + i);
+        let render_y = if let Some(render_y) = gr.get_render_y(editor_y) {
+            render_y
+        } else {
+            continue;
+        };
+        if !gr.is_visible(editor_y) {
+            continue;
+        }
+        if render_y.as_usize() != 0 && editor_content.get_char(editor_y.as_usize(), 0) == '#' {
+            let max_lens = &tmp.max_lengths[region_index];
+            tmp.max_len = max_lens.int_part_len
+                + max_lens.frac_part_len
+                + if max_lens.unit_part_len > 0 {
+                    max_lens.unit_part_len + 1
+                } else {
+                    0
+                }
+                .max(tmp.max_len);
+            tmp.result_counts_in_regions[region_index] =
+                tmp.result_ranges.len() - region_count_offset;
+            region_count_offset = tmp.result_ranges.len();
+            // TODO remove this limitation
+            if region_index < MAX_VISIBLE_HEADER_COUNT - 1 {
+                region_index += 1;
+            }
+            continue;
+        }
+
+        let assigned_var_name = get_assigned_var_name(vars, i);
+        if assigned_var_name.is_some() && gr.assignment_display == AssignmentDisplay::Hide {
+            tmp.result_ranges.push(ResultTmp {
+                buffer_ptr: None,
+                editor_y,
+                lengths: ResultLengths {
+                    int_part_len: 0,
+                    frac_part_len: 0,
+                    unit_part_len: 0,
+                },
+            });
+        } else if let Err(..) = result {
+            result_buffer[result_buffer_index] = b'E';
+            result_buffer[result_buffer_index + 1] = b'r';
+            result_buffer[result_buffer_index + 2] = b'r';
+            tmp.result_ranges.push(ResultTmp {
+                buffer_ptr: Some(result_buffer_index..result_buffer_index + 3),
+                editor_y,
+                lengths: ResultLengths {
+                    int_part_len: 999,
+                    frac_part_len: 0,
+                    unit_part_len: 0,
+                },
+            });
+            result_buffer_index += 3;
+        } else if let Ok(Some(result)) = result {
+            match &result.typ {
+                CalcResultType::Matrix(_mat) => {
+                    tmp.result_ranges.push(ResultTmp {
+                        buffer_ptr: None,
+                        editor_y,
+                        lengths: ResultLengths {
+                            int_part_len: 0,
+                            frac_part_len: 0,
+                            unit_part_len: 0,
+                        },
+                    });
+                }
+                _ => {
+                    let start = result_buffer_index;
+                    let mut c = Cursor::new(&mut result_buffer[start..]);
+                    let lens = render_line_result_into(
+                        units,
+                        result,
+                        assigned_var_name,
+                        &editor_content.get_data(editor_y.as_usize()).result_format,
+                        gr,
+                        decimal_count,
+                        &mut c,
+                    );
+                    let len = c.position() as usize;
+                    let range = start..start + len;
+                    tmp.max_lengths[region_index].set_max(&lens);
+                    tmp.result_ranges.push(ResultTmp {
+                        buffer_ptr: Some(range),
+                        editor_y,
+                        lengths: lens,
+                    });
+                    result_buffer_index += len;
+                }
+            };
+        } else {
+            tmp.result_ranges.push(ResultTmp {
+                buffer_ptr: None,
+                editor_y,
+                lengths: ResultLengths {
+                    int_part_len: 0,
+                    frac_part_len: 0,
+                    unit_part_len: 0,
+                },
+            });
+        }
+    }
+    result_buffer[result_buffer_index] = 0; // tests depend on it
+
+    tmp.result_counts_in_regions[region_index] = tmp.result_ranges.len() - region_count_offset;
+    tmp.max_len = (tmp.max_lengths[region_index].int_part_len
+        + tmp.max_lengths[region_index].frac_part_len
+        + if tmp.max_lengths[region_index].unit_part_len > 0 {
+            tmp.max_lengths[region_index].unit_part_len + 1
+        } else {
+            0
+        })
+    .max(tmp.max_len);
+}
+
+fn create_render_commands_for_results_and_render_matrices<'text_ptr>(
+    tmp: &ResultRender,
+    units: &Units,
+    results: &[LineResult],
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    editor_content: &EditorContent<LineData>,
+    gr: &GlobalRenderData,
+    vars: &Variables,
+    decimal_count: Option<usize>,
+) -> usize {
+    let mut prev_result_matrix_length = None;
+    let mut matrix_len = 0;
+    let result_buffer = unsafe { &RESULT_BUFFER };
+    let mut region_index = 0;
+    let mut result_count_in_this_region = tmp.result_counts_in_regions[0];
+    let mut max_lens = &tmp.max_lengths[0];
+    for result_tmp in tmp.result_ranges.iter() {
+        while result_count_in_this_region == 0 {
+            region_index += 1;
+            result_count_in_this_region = tmp.result_counts_in_regions[region_index];
+            max_lens = &tmp.max_lengths[region_index];
+        }
+        let rendered_row_height = gr.get_rendered_height(result_tmp.editor_y);
+        let render_y = gr.get_render_y(result_tmp.editor_y).expect("");
+        if let Some(result_range) = &result_tmp.buffer_ptr {
+            // result background
+            render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
+            render_buckets.draw_rect(
+                Layer::BehindText,
+                gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
+                render_y,
+                gr.current_result_panel_width,
+                rendered_row_height,
+            );
+
+            let lengths = &result_tmp.lengths;
+            let from = result_range.start;
+            let vert_align_offset = (rendered_row_height - 1) / 2;
+            let row = render_y.add(vert_align_offset);
+            enum ResultOffsetX {
+                Err,
+                Ok(usize),
+                TooLong,
+            }
+            let offset_x = if max_lens.int_part_len < lengths.int_part_len {
+                // it is an "Err"
+                ResultOffsetX::Err
+            } else {
+                let offset_x = max_lens.int_part_len - lengths.int_part_len;
+                let sum_len =
+                    lengths.int_part_len + max_lens.frac_part_len + max_lens.unit_part_len;
+                if offset_x + sum_len > gr.current_result_panel_width {
+                    if sum_len > gr.current_result_panel_width {
+                        ResultOffsetX::TooLong
+                    } else {
+                        ResultOffsetX::Ok(gr.current_result_panel_width - sum_len)
+                    }
+                } else {
+                    ResultOffsetX::Ok(offset_x)
+                }
+            };
+            let x = gr.result_gutter_x
+                + RIGHT_GUTTER_WIDTH
+                + match offset_x {
+                    ResultOffsetX::Err => 0,
+                    ResultOffsetX::TooLong => 0,
+                    ResultOffsetX::Ok(n) => n,
+                };
+            let int_w = match offset_x {
+                ResultOffsetX::Err => 3,
+                _ => lengths.int_part_len,
+            };
+            render_buckets.ascii_texts.push(RenderAsciiTextMsg {
+                text: &result_buffer[from..from + int_w],
+                row,
+                column: x,
+            });
+            if lengths.frac_part_len > 0 {
+                let from = result_range.start + lengths.int_part_len;
+                render_buckets.ascii_texts.push(RenderAsciiTextMsg {
+                    text: &result_buffer[from..from + lengths.frac_part_len],
+                    row,
+                    column: x + lengths.int_part_len,
+                });
+            }
+            if lengths.unit_part_len > 0 {
+                let from = result_range.start + lengths.int_part_len + lengths.frac_part_len + 1;
+                // e.g. in case of 2 units mm and m, m should be 1 coordinates right
+                let offset_x = max_lens.unit_part_len - lengths.unit_part_len;
+                let unit_text = &result_buffer[from..result_range.end];
+                let column = gr.result_gutter_x
+                    + RIGHT_GUTTER_WIDTH
+                    + max_lens.int_part_len
+                    + max_lens.frac_part_len
+                    + 1
+                    + offset_x;
+                if unit_text.is_ascii() {
+                    render_buckets.ascii_texts.push(RenderAsciiTextMsg {
+                        text: unit_text,
+                        row,
+                        column,
+                    });
+                } else {
+                    // superscript exponents
+                    render_buckets.set_color(Layer::Text, 0x000000_FF);
+                    render_buckets.draw_string(
+                        Layer::Text,
+                        column,
+                        row,
+                        unsafe { std::str::from_utf8_unchecked(unit_text) }.to_owned(),
+                    );
+                }
+            }
+            match offset_x {
+                ResultOffsetX::TooLong => {
+                    render_buckets.set_color(Layer::AboveText, 0xF2F2F2_FF);
+                    render_buckets.draw_char(
+                        Layer::AboveText,
+                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width - 1,
+                        row,
+                        '█',
+                    );
+                    render_buckets.set_color(Layer::AboveText, 0xFF0000_FF);
+                    render_buckets.draw_char(
+                        Layer::AboveText,
+                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH + gr.current_result_panel_width - 1,
+                        row,
+                        '…',
+                    );
+                }
+                _ => {}
+            }
+            prev_result_matrix_length = None;
+        } else {
+            let i = result_tmp.editor_y.as_usize() - gr.window_y();
+            let is_hidden = gr.assignment_display == AssignmentDisplay::Hide
+                && get_assigned_var_name(vars, i).is_some();
+            match &results[i] {
+                Ok(Some(CalcResult {
+                    typ: CalcResultType::Matrix(mat),
+                    ..
+                })) if !is_hidden => {
+                    // TODO: why it is called "prev.."?
+                    if prev_result_matrix_length.is_none() {
+                        prev_result_matrix_length = calc_consecutive_matrices_max_lengths(
+                            units,
+                            results,
+                            editor_content,
+                            gr.window_y(),
+                            result_tmp.editor_y.as_usize(),
+                            &gr.scientific_notation,
+                            gr.rounding_mode,
+                        );
+                    }
+                    let width = render_matrix_result(
+                        units,
+                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
+                        render_y,
+                        mat,
+                        render_buckets,
+                        prev_result_matrix_length.as_ref(),
+                        gr.get_rendered_height(result_tmp.editor_y),
+                        decimal_count,
+                        &editor_content
+                            .get_data(result_tmp.editor_y.as_usize())
+                            .result_format,
+                        &gr.scientific_notation,
+                        gr.rounding_mode,
+                    );
+                    if width > matrix_len {
+                        matrix_len = width;
+                    }
+                }
+                _ => {
+                    // no result but need rerender
+                    // result background
+                    prev_result_matrix_length = None;
+                    render_buckets.set_color(Layer::BehindText, 0xF2F2F2_FF);
+                    render_buckets.draw_rect(
+                        Layer::BehindText,
+                        gr.result_gutter_x + RIGHT_GUTTER_WIDTH,
+                        render_y,
+                        gr.current_result_panel_width,
+                        rendered_row_height,
+                    );
+                }
+            }
+        }
+        result_count_in_this_region -= 1;
+        if result_count_in_this_region == 0 {
+            region_index += 1;
+            result_count_in_this_region = tmp.result_counts_in_regions[region_index];
+            max_lens = &tmp.max_lengths[region_index];
+        }
+    }
+    return matrix_len;
+}
+
+/// Renders the result as it appears in the result panel, prefixed by the
+/// assigned variable's name depending on `gr.assignment_display`.
+fn render_line_result_into(
+    units: &Units,
+    result: &CalcResult,
+    assigned_var_name: Option<&[char]>,
+    result_format: &ResultFormat,
+    gr: &GlobalRenderData,
+    decimal_count: Option<usize>,
+    f: &mut impl std::io::Write,
+) -> ResultLengths {
+    let mut prefix_len = 0;
+    match assigned_var_name {
+        // the result buffer is rendered as ascii text
+        Some(var_name)
+            if (gr.assignment_display == AssignmentDisplay::WithVarName
+                || gr.assignment_display == AssignmentDisplay::Labeled)
+                && var_name.iter().all(|ch| ch.is_ascii()) =>
+        {
+            for ch in var_name.iter() {
+                f.write_all(&[*ch as u8]).expect("");
+            }
+            let separator: &[u8] = if gr.assignment_display == AssignmentDisplay::Labeled {
+                b": "
+            } else {
+                b" = "
+            };
+            f.write_all(separator).expect("");
+            prefix_len = var_name.len() + separator.len();
+        }
+        _ => {}
+    }
+    let use_grouping = gr.thousands_separator.is_some()
+        || !matches!(result_format, ResultFormat::Dec | ResultFormat::Sci);
+    let mut lens = render_result_into(
+        &units,
+        &result,
+        result_format,
+        false,
+        f,
+        decimal_count,
+        use_grouping,
+        match gr.thousands_separator {
+            Some(ThousandsSeparator::Comma) => b',',
+            _ => b' ',
+        },
+        gr.superscript_unit_exponents,
+        gr.repeating_decimal_notation,
+        &gr.scientific_notation,
+        gr.rounding_mode,
+    );
+    lens.int_part_len += prefix_len;
+    lens
+}
+
+fn get_assigned_var_name(vars: &Variables, var_index: usize) -> Option<&[char]> {
+    let var = vars.get(var_index)?.as_ref()?;
+    // the results of the other lines are stored as "&[line_id]" variables
+    if var.value.is_err() || var.name.starts_with(&['&', '[']) {
+        None
+    } else {
+        Some(&var.name)
+    }
+}
+
+fn calc_consecutive_matrices_max_lengths(
+    units: &Units,
+    results: &[LineResult],
+    editor_content: &EditorContent<LineData>,
+    // the row of the first result
+    window_y: usize,
+    first_row: usize,
+    scientific_notation: &ScientificNotation,
+    rounding_mode: RoundingMode,
+) -> Option<ResultLengths> {
+    let mut max_lengths: Option<ResultLengths> = None;
+    for (i, result) in results.iter().enumerate().skip(first_row - window_y) {
+        let row = window_y + i;
+        match result {
+            Ok(Some(CalcResult {
+                typ: CalcResultType::Matrix(mat),
+                ..
+            })) => {
+                let lengths = calc_matrix_max_lengths(
+                    units,
+                    mat,
+                    &editor_content.get_data(row).result_format,
+                    scientific_notation,
+                    rounding_mode,
+                );
+                if let Some(max_lengths) = &mut max_lengths {
+                    max_lengths.set_max(&lengths);
+                } else {
+                    max_lengths = Some(lengths);
+                }
+            }
+            _ => {
+                break;
+            }
+        }
+    }
+    return max_lengths;
+}
+
+fn calc_matrix_max_lengths(
+    units: &Units,
+    mat: &MatrixData,
+    format: &ResultFormat,
+    scientific_notation: &ScientificNotation,
+    rounding_mode: RoundingMode,
+) -> ResultLengths {
+    let cells_strs = {
+        let mut tokens_per_cell: SmallVec<[String; 32]> = SmallVec::with_capacity(32);
+
+        for cell in mat.cells.iter() {
+            let result_str = render_result(
+                units,
+                cell,
+                format,
+                false,
+                Some(RENDERED_RESULT_PRECISION),
+                true,
+                scientific_notation,
+                rounding_mode,
+            );
+            tokens_per_cell.push(result_str);
+        }
+        tokens_per_cell
+    };
+    let max_lengths = {
+        let mut max_lengths = ResultLengths {
+            int_part_len: 0,
+            frac_part_len: 0,
+            unit_part_len: 0,
+        };
+        for cell_str in &cells_strs {
+            let lengths = get_int_frac_part_len_in_format(cell_str, format);
+            max_lengths.set_max(&lengths);
+        }
+        max_lengths
+    };
+    return max_lengths;
+}
+
+fn draw_line_refs_and_vars_referenced_from_cur_row<'b>(
+    editor_objs: &[EditorObject],
+    gr: &GlobalRenderData,
+    render_buckets: &mut RenderBuckets<'b>,
+    editor_y_to_render_w: &[usize; MAX_LINE_COUNT],
+) {
+    let mut color_index = 0;
+    let mut highlighted = BitFlag128::empty();
+    for editor_obj in editor_objs {
+        match editor_obj.typ {
+            EditorObjectType::LineReference { var_index }
+            | EditorObjectType::Variable { var_index } => {
+                // sum and the external variables are not defined in any line
+                if var_index >= MAX_LINE_COUNT {
+                    continue;
+                }
+                let color = if highlighted.is_true(var_index) {
+                    continue;
+                } else {
+                    highlighted.set(var_index);
+                    let color = ACTIVE_LINE_REF_HIGHLIGHT_COLORS[color_index];
+                    color_index = if color_index < 8 { color_index + 1 } else { 0 };
+                    color
+                };
+                let defined_at = content_y(gr.window_y() + var_index);
+                if let Some(render_y) = gr.get_render_y(defined_at) {
+                    render_buckets.custom_commands[Layer::AboveText as usize]
+                        .push(OutputMessage::SetColor(color << 8 | 0x33));
+                    render_buckets.custom_commands[Layer::AboveText as usize].push(
+                        OutputMessage::RenderRectangle {
+                            x: gr.left_gutter_width,
+                            y: render_y,
+                            w: editor_y_to_render_w[var_index].saturating_sub(gr.scroll_x),
+                            h: gr.get_rendered_height(defined_at),
+                        },
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn draw_token<'text_ptr>(
+    token: &Token<'text_ptr>,
+    render_x: usize,
+    render_y: CanvasY,
+    current_editor_width: usize,
+    scroll_x: usize,
+    left_gutter_width: usize,
+    pretty_operators: bool,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+) {
+    // the part of the token which is hidden on the left
+    let from = scroll_x.saturating_sub(render_x).min(token.ptr.len());
+    let to = token
+        .ptr
+        .len()
+        .min(((current_editor_width + scroll_x) as isize - render_x as isize).max(0) as usize)
+        .max(from);
+    get_token_render_bucket(token, render_buckets).push(RenderUtf8TextMsg {
+        text: &get_token_text(token, pretty_operators)[from..to],
+        row: render_y,
+        column: render_x + from - scroll_x + left_gutter_width,
+    });
+}
+
+/// The glyph of the token, it has the same length as the source text so
+/// the column calculations are not affected.
+fn get_token_text<'text_ptr>(
+    token: &Token<'text_ptr>,
+    pretty_operators: bool,
+) -> &'text_ptr [char] {
+    match (&token.typ, token.ptr) {
+        (TokenType::Operator(OperatorTokenType::Mult), ['*']) if pretty_operators => &['×'],
+        (TokenType::Operator(OperatorTokenType::Div), ['/']) if pretty_operators => &['÷'],
+        _ => token.ptr,
+    }
+}
+
+fn get_token_render_bucket<'a, 'text_ptr>(
+    token: &Token<'text_ptr>,
+    render_buckets: &'a mut RenderBuckets<'text_ptr>,
+) -> &'a mut Vec<RenderUtf8TextMsg<'text_ptr>> {
+    if token.has_error() {
+        &mut render_buckets.number_errors
+    } else {
+        match &token.typ {
+            TokenType::StringLiteral => &mut render_buckets.utf8_texts,
+            TokenType::Header => &mut render_buckets.headers,
+            TokenType::Variable { .. } => &mut render_buckets.variable,
+            TokenType::LineReference { .. } => &mut render_buckets.variable,
+            TokenType::BoundVariable { .. } => &mut render_buckets.variable,
+            TokenType::NumberLiteral(_) => &mut render_buckets.numbers,
+            TokenType::NumberErr => &mut render_buckets.number_errors,
+            TokenType::Operator(OperatorTokenType::ApplyUnit(_)) => &mut render_buckets.units,
+            TokenType::Unit(_) => &mut render_buckets.units,
+            TokenType::Operator(_) => &mut render_buckets.operators,
+        }
+    }
+}
+
+fn render_buckets_into(buckets: &RenderBuckets, canvas: &mut [[char; 256]]) {
+    fn write_char_slice(canvas: &mut [[char; 256]], row: CanvasY, col: usize, src: &[char]) {
+        let str = &mut canvas[row.as_usize()];
+        for (dst_char, src_char) in str[col..].iter_mut().zip(src.iter()) {
+            *dst_char = *src_char;
+        }
+    }
+
+    fn write_str(canvas: &mut [[char; 256]], row: CanvasY, col: usize, src: &str) {
+        let str = &mut canvas[row.as_usize()];
+        for (dst_char, src_char) in str[col..].iter_mut().zip(src.chars()) {
+            *dst_char = src_char;
+        }
+    }
+
+    fn write_ascii(canvas: &mut [[char; 256]], row: CanvasY, col: usize, src: &[u8]) {
+        let str = &mut canvas[row.as_usize()];
+        for (dst_char, src_char) in str[col..].iter_mut().zip(src.iter()) {
+            *dst_char = *src_char as char;
+        }
+    }
+
+    fn write_command(canvas: &mut [[char; 256]], command: &OutputMessage) {
+        match command {
+            OutputMessage::RenderUtf8Text(text) => {
+                write_char_slice(canvas, text.row, text.column, text.text);
+            }
+            OutputMessage::SetStyle(..) => {}
+            OutputMessage::SetColor(..) => {}
+            OutputMessage::RenderRectangle { .. } => {}
+            OutputMessage::RenderChar(x, y, ch) => {
+                let str = &mut canvas[*y];
+                str[*x] = *ch;
+            }
+            OutputMessage::RenderString(text) => {
+                write_str(canvas, text.row, text.column, &text.text);
+            }
+            OutputMessage::RenderAsciiText(text) => {
+                write_ascii(canvas, text.row, text.column, &text.text);
+            }
+            OutputMessage::PulsingRectangle { .. } => {}
+            OutputMessage::FollowingTextCommandsAreHeaders { .. } => {}
+        }
+    }
+
+    for command in &buckets.custom_commands[Layer::BehindText as usize] {
+        write_command(canvas, command);
+    }
+
+    for command in &buckets.utf8_texts {
+        write_char_slice(canvas, command.row, command.column, command.text);
+    }
+    for text in &buckets.ascii_texts {
+        write_ascii(canvas, text.row, text.column, text.text);
+    }
+    for command in &buckets.numbers {
+        write_char_slice(canvas, command.row, command.column, command.text);
+    }
+
+    for command in &buckets.number_errors {
+        write_char_slice(canvas, command.row, command.column, command.text);
+    }
+
+    for command in &buckets.units {
+        write_char_slice(canvas, command.row, command.column, command.text);
+    }
+
+    for command in &buckets.operators {
+        write_char_slice(canvas, command.row, command.column, command.text);
+    }
+
+    for command in &buckets.line_ref_results {
+        write_str(canvas, command.row, command.column, &command.text);
+    }
+
+    for command in &buckets.variable {
+        write_char_slice(canvas, command.row, command.column, command.text);
+    }
+
+    for command in &buckets.custom_commands[Layer::Text as usize] {
+        write_command(canvas, command);
+    }
+
+    for command in &buckets.custom_commands[Layer::AboveText as usize] {
+        write_command(canvas, command);
+    }
+}
+
+fn is_inside_editor_obj(pos: Pos, len: usize, editor_objs: &EditorObjects) -> bool {
+    editor_objs[content_y(pos.row)].iter().any(|obj| {
+        let is_protected = match obj.typ {
+            EditorObjectType::Matrix { .. } | EditorObjectType::LineReference { .. } => true,
+            EditorObjectType::Variable { .. } | EditorObjectType::SimpleTokens => false,
+        };
+        is_protected && pos.column < obj.end_x && obj.start_x < pos.column + len
+    })
+}
+
+fn draw_range_background<'text_ptr>(
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    start: Pos,
+    end: Pos,
+    editor_content: &EditorContent<LineData>,
+    gr: &GlobalRenderData,
+) {
+    // draws the [from, to) columns of the row, clipped to the horizontally scrolled editor
+    let mut draw = |row: usize, from: usize, to: usize| {
+        if gr.is_wrapped(content_y(row)) {
+            // the columns are split into the `current_editor_width` wide rows of the line
+            let w = gr.current_editor_width;
+            if let Some(render_y) = gr.get_render_y(content_y(row)) {
+                if to > from {
+                    for wrapped_row in from / w..=(to - 1) / w {
+                        let row_start = wrapped_row * w;
+                        let from = from.max(row_start) - row_start;
+                        let to = to.min(row_start + w) - row_start;
+                        render_buckets.draw_rect(
+                            Layer::BehindText,
+                            gr.left_gutter_width + from,
+                            render_y.add(wrapped_row),
+                            to - from,
+                            1,
+                        );
+                    }
+                }
+            }
+            return;
+        }
+        let from = from.max(gr.scroll_x);
+        let to = to.min(gr.scroll_x + gr.current_editor_width);
+        if let Some(render_y) = gr.get_render_y(content_y(row)) {
+            if to > from {
+                render_buckets.draw_rect(
+                    Layer::BehindText,
+                    gr.left_gutter_width + from - gr.scroll_x,
+                    render_y,
+                    to - from,
+                    gr.get_rendered_height(content_y(row)),
+                );
+            }
+        }
+    };
+    if end.row > start.row {
+        // first line
+        draw(start.row, start.column, editor_content.line_len(start.row));
+        // full lines
+        for i in start.row + 1..end.row {
+            draw(i, 0, editor_content.line_len(i));
+        }
+        // last line
+        draw(end.row, 0, end.column);
+    } else {
+        draw(start.row, start.column, end.column);
+    }
+}
+
+fn render_selection_and_its_sum<'text_ptr>(
+    units: &Units,
+    render_buckets: &mut RenderBuckets<'text_ptr>,
+    results: &Results,
+    editor: &Editor,
+    editor_content: &EditorContent<LineData>,
+    gr: &GlobalRenderData,
+    vars: &Variables,
+    native_fns: &[NativeFn],
+    allocator: &'text_ptr Bump,
+    rounding_mode: RoundingMode,
+    angle_mode: AngleMode,
+) {
+    render_buckets.set_color(Layer::BehindText, 0xA6D2FF_FF);
+    if let Some((start, end)) = editor.get_selection().is_range() {
+        draw_range_background(render_buckets, start, end, editor_content, gr);
+        // evaluated result of selection, selected text
+        if let Some(SelectionResult {
+            sum: mut partial_result,
+            average,
+            count,
+        }) = evaluate_selection(
+            &units,
+            editor,
+            editor_content,
+            &vars,
+            native_fns,
+            results,
+            allocator,
+            rounding_mode,
+            angle_mode,
+            gr.selection_sum_respects_resets,
+            gr.selection_average,
+            &gr.scientific_notation,
+        ) {
+            if start.row == end.row {
+                if let Some(start_render_y) = gr.get_render_y(content_y(start.row)) {
+                    let selection_center = start.column + ((end.column - start.column) / 2);
+                    partial_result.insert_str(0, "= ");
+                    let result_w = partial_result.chars().count();
+                    let centered_x =
+                        (selection_center as isize - (result_w / 2) as isize).max(0) as usize;
+                    render_buckets.set_color(Layer::AboveText, 0xAAFFAA_FF);
+                    let rect_y = if start.row == 0 {
+                        start_render_y.add(1)
+                    } else {
+                        start_render_y.sub(1)
+                    };
+                    render_buckets.draw_rect(
+                        Layer::AboveText,
+                        gr.left_gutter_width + centered_x,
+                        rect_y,
+                        result_w,
+                        1,
+                    );
+                    render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+                    render_buckets.draw_string(
+                        Layer::AboveText,
+                        gr.left_gutter_width + centered_x,
+                        rect_y,
+                        partial_result,
+                    );
+                }
+            } else {
+                partial_result.insert_str(0, " ∑ = ");
+                let average = average.map(|it| format!(" avg = {}", it));
+                let count = format!("n = {}", count);
+                let sum_w = partial_result.chars().count();
+                let result_w = (sum_w + 1 + count.chars().count())
+                    .max(average.as_ref().map(|it| it.chars().count()).unwrap_or(0));
+                let x = (start.row..=end.row)
+                    .map(|it| editor_content.line_len(it))
+                    .max_by(|a, b| a.cmp(b))
+                    .unwrap()
+                    + 3;
+                let frist_visible_row_index = content_y(start.row.max(gr.scroll_y));
+                let last_visible_row_index =
+                    content_y(end.row.min(gr.scroll_y + gr.client_height - 1));
+                let inner_height = gr
+                    .get_render_y(last_visible_row_index)
+                    .expect("")
+                    .as_usize()
+                    - gr.get_render_y(frist_visible_row_index)
+                        .expect("")
+                        .as_usize();
+                render_buckets.set_color(Layer::AboveText, 0xAAFFAA_FF);
+                render_buckets.draw_rect(
+                    Layer::AboveText,
+                    gr.left_gutter_width + x,
+                    gr.get_render_y(frist_visible_row_index).expect(""),
+                    result_w + 1,
+                    inner_height + 1,
+                );
+                // draw the parenthesis
+                render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+
+                render_buckets.draw_char(
+                    Layer::AboveText,
+                    gr.left_gutter_width + x,
+                    gr.get_render_y(frist_visible_row_index).expect(""),
+                    if frist_visible_row_index.as_usize() == start.row {
+                        '⎫'
+                    } else {
+                        '⎪'
+                    },
+                );
+
+                render_buckets.draw_char(
+                    Layer::AboveText,
+                    gr.left_gutter_width + x,
+                    gr.get_render_y(last_visible_row_index).expect(""),
+                    if last_visible_row_index.as_usize() == end.row {
+                        '⎭'
+                    } else {
+                        '⎪'
+                    },
+                );
+
+                for i in 1..inner_height {
+                    render_buckets.draw_char(
+                        Layer::AboveText,
+                        gr.left_gutter_width + x,
+                        gr.get_render_y(frist_visible_row_index).expect("").add(i),
+                        '⎪',
+                    );
+                }
+                // center
+                render_buckets.draw_string(
+                    Layer::AboveText,
+                    gr.left_gutter_width + x,
+                    gr.get_render_y(frist_visible_row_index)
+                        .expect("")
+                        .add(inner_height / 2),
+                    partial_result,
+                );
+                render_buckets.draw_string(
+                    Layer::AboveText,
+                    gr.left_gutter_width + x + sum_w + 1,
+                    gr.get_render_y(frist_visible_row_index)
+                        .expect("")
+                        .add(inner_height / 2),
+                    count,
+                );
+                if let Some(average) = average {
+                    // the selection is at least two rows high
+                    render_buckets.draw_string(
+                        Layer::AboveText,
+                        gr.left_gutter_width + x,
+                        gr.get_render_y(frist_visible_row_index)
+                            .expect("")
+                            .add(inner_height / 2 + 1),
+                        average,
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn calc_rendered_height<'b>(
+    editor_y: ContentIndex,
+    matrix_editing: &Option<MatrixEditing>,
+    tokens: &AppTokens,
+    results: &Results,
+    vars: &Variables,
+    collapsed_matrices: u32,
+) -> usize {
+    return if let Some(tokens) = &tokens[editor_y] {
+        let h = PerLineRenderData::calc_rendered_row_height(
+            &results[editor_y],
+            &tokens.tokens,
+            vars,
+            matrix_editing
+                .as_ref()
+                .filter(|it| it.row_index == editor_y)
+                .map(|it| MatrixData::calc_render_height(it.row_count)),
+            collapsed_matrices,
+        );
+        h
+    } else {
+        1
+    };
+}
+
+fn is_selection_sum_reset_line(line: &[char]) -> bool {
+    let mut chars = line.iter().skip_while(|it| it.is_whitespace());
+    match chars.next() {
+        Some('#') => true,
+        Some('-') => chars.next() == Some(&'-'),
+        _ => false,
+    }
+}
+
+fn matrix_count(tokens: &Option<Tokens>) -> Option<usize> {
+    tokens.as_ref().map(|tokens| {
+        tokens
+            .tokens
+            .iter()
+            .filter(|it| {
+                matches!(
+                    it.typ,
+                    TokenType::Operator(OperatorTokenType::Matrix { .. })
+                )
+            })
+            .count()
+    })
+}
+
+fn is_matrix_collapsed(collapsed_matrices: u32, matrix_index: usize) -> bool {
+    matrix_index < 32 && (collapsed_matrices & (1 << matrix_index)) != 0
+}
+
+fn is_pos_inside_an_obj(editor_objects: &EditorObjects, pos: Pos) -> Option<&EditorObject> {
+    for obj in &editor_objects[content_y(pos.row)] {
+        if (obj.start_x + 1..obj.end_x).contains(&pos.column) {
+            return Some(obj);
+        }
+    }
+    return None;
+}
+
+pub fn end_matrix_editing(
+    matrix_editing: &mut Option<MatrixEditing>,
+    editor: &mut Editor,
+    editor_content: &mut EditorContent<LineData>,
+    new_cursor_pos: Option<Pos>,
+) {
+    let mat_editor = {
+        let mat_editor = matrix_editing.as_mut().unwrap();
+        mat_editor.save_editor_content();
+        mat_editor
+    };
+    let mut concat = String::with_capacity(32);
+    concat.push('[');
+    for row_i in 0..mat_editor.row_count {
+        if row_i > 0 {
+            concat.push(';');
+        }
+        for col_i in 0..mat_editor.col_count {
+            if col_i > 0 {
+                concat.push(',');
+            }
+            // cells are written back without surrounding whitespaces, e.g. "[1,2;3,4]"
+            let cell_str = &mat_editor.cell_strings[row_i * mat_editor.col_count + col_i];
+            concat += cell_str.trim();
+        }
+    }
+    concat.push(']');
+    let selection = Selection::range(
+        Pos::from_row_column(mat_editor.row_index.as_usize(), mat_editor.start_text_index),
+        Pos::from_row_column(mat_editor.row_index.as_usize(), mat_editor.end_text_index),
+    );
+    editor.set_selection_save_col(selection);
+    // TODO: máshogy oldd meg, mert ez modositja az undo stacket is
+    // és az miért baj, legalább tudom ctrl z-zni a mátrix edition-t
+    editor.handle_input(
+        EditorInputEvent::Del,
+        InputModifiers::none(),
+        editor_content,
+    );
+    editor.insert_text(&concat, editor_content);
+    *matrix_editing = None;
+
+    if let Some(new_cursor_pos) = new_cursor_pos {
+        editor.set_selection_save_col(Selection::single(new_cursor_pos));
+    }
+    editor.blink_cursor();
+}
+
+fn get_scroll_y_after_cursor_movement(
+    prev_row: usize,
+    current_row: usize,
+    render_data: &GlobalRenderData,
+) -> Option<usize> {
+    if prev_row != current_row {
+        if current_row < render_data.scroll_y {
+            // scroll up
+            Some(current_row)
+        } else {
+            // scroll down
+            // if the new pos is 5. line and its height is 1, this var is 6
+            let new_pos_bottom_y =
+                if let Some(new_row_y) = render_data.get_render_y(content_y(current_row)) {
+                    let new_h = render_data.get_rendered_height(content_y(current_row));
+                    new_row_y.add(new_h)
+                } else {
+                    // find the last rendered line at the bottom
+                    let mut assumed_heights = 1;
+                    let mut prev_row_y = None;
+                    let mut prev_row_i = current_row as isize - 1;
+                    while prev_row_y.is_none() && prev_row_i >= 0 {
+                        prev_row_y = render_data.get_render_y(content_y(prev_row_i as usize));
+                        assumed_heights += 1;
+                        prev_row_i -= 1;
+                    }
+                    // we assume that the non-yet-rendered lines' height will be 1
+                    prev_row_y.unwrap_or(canvas_y(0)).add(assumed_heights)
+                };
+            let new_scroll_y = new_pos_bottom_y.as_isize() + render_data.scroll_y as isize
+                - (render_data.client_height as isize);
+            if new_scroll_y > render_data.scroll_y as isize {
+                Some(new_scroll_y as usize)
+            } else {
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
+fn set_editor_and_result_panel_widths(
+    client_width: usize,
+    result_panel_width_percent: usize,
+    gr: &mut GlobalRenderData,
+) {
+    let mut result_gutter_x: isize =
+        (client_width * (100 - result_panel_width_percent) / 100) as isize;
+    {
+        // the editor pushes the gutter to right
+        let editor_width =
+            (result_gutter_x - SCROLLBAR_WIDTH as isize - LEFT_GUTTER_MIN_WIDTH as isize) - 1;
+        let diff = gr.longest_visible_editor_line_len as isize - editor_width;
+        if diff > 0 {
+            result_gutter_x += diff;
+        }
+    }
+    {
+        // the result panel pushes the gutter to left, with higher priority
+        let result_panel_w: isize =
+            client_width as isize - result_gutter_x - RIGHT_GUTTER_WIDTH as isize;
+        let diff = gr.longest_visible_result_len as isize - result_panel_w;
+        if diff > 0 {
+            result_gutter_x -= diff;
+        }
+    }
+    // result panel width has a minimum required width
+    let result_panel_w = client_width as isize - result_gutter_x - RIGHT_GUTTER_WIDTH as isize;
+    let result_gutter_x = (client_width as isize - result_panel_w - RIGHT_GUTTER_WIDTH as isize)
+        .max(MIN_RESULT_PANEL_WIDTH as isize) as usize;
+    gr.set_result_gutter_x(client_width, result_gutter_x);
+}
+
+fn default_result_gutter_x(client_width: usize) -> usize {
+    (client_width * (100 - DEFAULT_RESULT_PANEL_WIDTH_PERCENT) / 100)
+        .max(LEFT_GUTTER_MIN_WIDTH + SCROLLBAR_WIDTH)
+}
+
+#[cfg(test)]
+mod main_tests {
+
+    use super::*;
+
+    const fn result_panel_w(client_width: usize) -> usize {
+        client_width * (100 - DEFAULT_RESULT_PANEL_WIDTH_PERCENT) / 100
+    }
+
+    fn pulsing_ref_rect<'a>(x: usize, y: usize, w: usize, h: usize) -> OutputMessage<'a> {
+        OutputMessage::PulsingRectangle {
+            x,
+            y: canvas_y(y as isize),
+            w,
+            h,
+            start_color: REFERENCE_PULSE_PULSE_START_COLOR,
+            end_color: 0x00FF7F_00,
+            animation_time: Duration::from_millis(1000),
+        }
+    }
+
+    fn pulsing_result_rect<'a>(x: usize, y: usize, w: usize, h: usize) -> OutputMessage<'a> {
+        OutputMessage::PulsingRectangle {
+            x,
+            y: canvas_y(y as isize),
+            w,
+            h,
+            start_color: CHANGE_RESULT_PULSE_START_COLOR,
+            end_color: CHANGE_RESULT_PULSE_END_COLOR,
+            animation_time: Duration::from_millis(1000),
+        }
+    }
+
+    fn pulsing_changed_content_rect<'a>(
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> OutputMessage<'a> {
+        OutputMessage::PulsingRectangle {
+            x,
+            y: canvas_y(y as isize),
+            w,
+            h,
+            start_color: CHANGE_RESULT_PULSE_START_COLOR,
+            end_color: CHANGE_RESULT_PULSE_END_COLOR,
+            animation_time: Duration::from_millis(2000),
+        }
+    }
+
+    fn assert_contains(
+        render_bucket: &[OutputMessage],
+        expected_count: usize,
+        expected_command: OutputMessage,
+    ) {
+        let mut count = 0;
+        for command in render_bucket {
+            if *command == expected_command {
+                count += 1;
+            }
+        }
+        assert_eq!(
+            count, expected_count,
+            "Found {} times, expected {}.\n{:?}\nin\n{:?}",
+            count, expected_count, expected_command, render_bucket
+        );
+    }
+
+    struct BorrowCheckerFighter {
+        app_ptr: u64,
+        units_ptr: u64,
+        render_bucket_ptr: u64,
+        tokens_ptr: u64,
+        results_ptr: u64,
+        vars_ptr: u64,
+        editor_objects_ptr: u64,
+        allocator: u64,
+    }
+
+    #[allow(dead_code)]
+    impl BorrowCheckerFighter {
+        fn mut_app<'a>(&self) -> &'a mut NoteCalcApp {
+            unsafe { &mut *(self.app_ptr as *mut NoteCalcApp) }
+        }
+
+        fn app<'a>(&self) -> &'a NoteCalcApp {
+            unsafe { &*(self.app_ptr as *const NoteCalcApp) }
+        }
+
+        fn units<'a>(&self) -> &'a mut Units {
+            unsafe { &mut *(self.units_ptr as *mut Units) }
+        }
+
+        fn render_bucket(&self) -> &RenderBuckets {
+            return self.mut_render_bucket();
+        }
+
+        fn mut_render_bucket<'a>(&self) -> &'a mut RenderBuckets<'a> {
+            unsafe { &mut *(self.render_bucket_ptr as *mut RenderBuckets) }
+        }
+
+        fn tokens<'a>(&self) -> &'a AppTokens<'a> {
+            unsafe { &*(self.tokens_ptr as *const AppTokens) }
+        }
+
+        fn mut_tokens<'a>(&self) -> &'a mut AppTokens<'a> {
+            unsafe { &mut *(self.tokens_ptr as *mut AppTokens) }
+        }
+
+        fn mut_results<'a>(&self) -> &'a mut Results {
+            unsafe { &mut *(self.results_ptr as *mut Results) }
+        }
+
+        fn mut_editor_objects<'a>(&self) -> &'a mut EditorObjects {
+            unsafe { &mut *(self.editor_objects_ptr as *mut EditorObjects) }
+        }
+
+        fn editor_objects<'a>(&self) -> &'a EditorObjects {
+            unsafe { &*(self.editor_objects_ptr as *const EditorObjects) }
+        }
+
+        fn mut_vars<'a>(&self) -> &'a mut [Option<Variable>] {
+            unsafe {
+                &mut (&mut *(self.vars_ptr
+                    as *mut [Option<Variable>;
+                        MAX_LINE_COUNT + 1 + MAX_EXTERNAL_VAR_COUNT + MAX_CARRIED_VAR_COUNT]))[..]
+            }
+        }
+
+        fn allocator<'a>(&self) -> &'a Bump {
+            unsafe { &*(self.allocator as *const Bump) }
+        }
+
+        fn render(&self) {
+            self.mut_app()
+                .generate_render_commands_and_fill_editor_objs(
+                    self.units(),
+                    self.mut_render_bucket(),
+                    self.allocator(),
+                    self.mut_tokens(),
+                    self.mut_results(),
+                    self.mut_vars(),
+                    self.mut_editor_objects(),
+                    BitFlag128::empty(),
+                );
+        }
+
+        fn paste(&self, str: &str) {
+            self.mut_app().handle_paste(
+                str.to_owned(),
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_editor_objects(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn assert_no_highlighting_rectangle(&self) {
+            let render_buckets = &self.render_bucket().custom_commands[Layer::AboveText as usize];
+            for i in 0..9 {
+                assert_contains(
+                    render_buckets,
+                    0,
+                    OutputMessage::SetColor(ACTIVE_LINE_REF_HIGHLIGHT_COLORS[i] << 8 | 0x33),
+                );
+            }
+        }
+
+        fn assert_results(&self, expected_results: &[&str]) {
+            let mut i = 0;
+            let mut ok_chars = Vec::with_capacity(32);
+            let expected_len = expected_results.iter().map(|it| it.len()).sum();
+            unsafe {
+                for (result_index, expected_result) in expected_results.iter().enumerate() {
+                    for ch in expected_result.bytes() {
+                        assert_eq!(
+                            RESULT_BUFFER[i] as char,
+                            ch as char,
+                            "{}. result, at char {}: {:?}, result_buffer: {:?}",
+                            result_index,
+                            i,
+                            String::from_utf8(ok_chars).unwrap(),
+                            &RESULT_BUFFER[0..expected_len]
+                                .iter()
+                                .map(|it| *it as char)
+                                .collect::<Vec<char>>()
+                        );
+                        ok_chars.push(ch);
+                        i += 1;
+                    }
+                    ok_chars.push(',' as u8);
+                    ok_chars.push(' ' as u8);
+                }
+                assert_eq!(
+                    RESULT_BUFFER[i], 0,
+                    "more results than expected at char {}.",
+                    i
+                );
+            }
+        }
+
+        fn assert_contains_operator<F>(&self, expected_count: usize, expected_command: F)
+        where
+            F: Fn(&RenderUtf8TextMsg) -> bool,
+        {
+            let mut count = 0;
+            let operators = &self.render_bucket().operators;
+            for op in operators {
+                if expected_command(op) {
+                    count += 1;
+                }
+            }
+            assert_eq!(
+                count, expected_count,
+                "Found {} times.\nExpected: {}\nin\n{:?}",
+                count, expected_count, operators
+            );
+        }
+
+        fn assert_contains_line_ref_result<F>(&self, expected_count: usize, expected_command: F)
+        where
+            F: Fn(&RenderStringMsg) -> bool,
+        {
+            let mut count = 0;
+            let operators = &self.render_bucket().line_ref_results;
+            for op in operators {
+                if expected_command(op) {
+                    count += 1;
+                }
+            }
+            assert_eq!(
+                count, expected_count,
+                "Found {} times.\nExpected: {}\nin\n{:?}",
+                count, expected_count, operators
+            );
+        }
+
+        fn assert_contains_result<F>(&self, expected_count: usize, expected_command: F)
+        where
+            F: Fn(&RenderAsciiTextMsg) -> bool,
+        {
+            let mut count = 0;
+            let commands = &self.render_bucket().ascii_texts;
+            for cmd in commands {
+                if expected_command(cmd) {
+                    count += 1;
+                }
+            }
+            assert_eq!(
+                count, expected_count,
+                "Found {} times.\nExpected: {}\nin\n{:?}",
+                count, expected_count, commands
+            );
+        }
+
+        fn assert_contains_text<F>(&self, expected_count: usize, expected_command: F)
+        where
+            F: Fn(&RenderUtf8TextMsg) -> bool,
+        {
+            let mut count = 0;
+            let operators = &self.render_bucket().utf8_texts;
+            for op in operators {
+                if expected_command(op) {
+                    count += 1;
+                }
+            }
+            assert_eq!(
+                count, expected_count,
+                "Found {} times.\nExpected: {}\nin\n{:?}",
+                count, expected_count, operators
+            );
+        }
+
+        fn assert_contains_variable<F>(&self, expected_count: usize, expected_command: F)
+        where
+            F: Fn(&RenderUtf8TextMsg) -> bool,
+        {
+            let mut count = 0;
+            let operators = &self.render_bucket().variable;
+            for op in operators {
+                if expected_command(op) {
+                    count += 1;
+                }
+            }
+            assert_eq!(
+                count, expected_count,
+                "Found {} times.\nExpected: {}\nin\n{:?}",
+                count, expected_count, operators
+            );
+        }
+
+        fn assert_no_pulsing(&self) {
+            let render_bucket = &self.render_bucket().custom_commands[Layer::AboveText as usize];
+            for command in render_bucket {
+                assert!(
+                    !matches!(command, OutputMessage::PulsingRectangle {..}),
+                    "Pulsing was found but did not expected: {:?}",
+                    command
+                );
+            }
+        }
+
+        fn set_normalized_content(&self, str: &str) {
+            self.mut_app().set_normalized_content(
+                str,
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_editor_objects(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn repeated_paste(&self, str: &str, times: usize) {
+            self.mut_app().handle_paste(
+                str.repeat(times),
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_editor_objects(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn click(&self, x: usize, y: isize) {
+            self.mut_app().handle_click(
+                x,
+                canvas_y(y),
+                self.mut_editor_objects(),
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn handle_resize(&self, new_client_width: usize) {
+            self.mut_app().handle_resize(
+                new_client_width,
+                self.mut_editor_objects(),
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn handle_wheel(&self, dir: usize) {
+            self.mut_app().handle_wheel(
+                dir,
+                self.mut_editor_objects(),
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn handle_drag(&self, x: usize, y: isize) {
+            self.mut_app().handle_drag(
+                x,
+                canvas_y(y),
+                self.mut_editor_objects(),
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn handle_mouse_move(&self, x: usize, y: isize) {
+            self.mut_app().handle_mouse_move(
+                x,
+                canvas_y(y),
+                self.mut_editor_objects(),
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn alt_key_released(&self) {
+            self.mut_app().alt_key_released(
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_editor_objects(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn handle_time(&self, tick: u32) {
+            self.mut_app().handle_time(
+                tick,
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_editor_objects(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn input(&self, event: EditorInputEvent, modif: InputModifiers) {
+            self.mut_app().handle_input(
+                event,
+                modif,
+                self.allocator(),
+                self.units(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_editor_objects(),
+                self.mut_render_bucket(),
+            );
+        }
+
+        fn handle_mouse_up(&self) {
+            self.mut_app().handle_mouse_up();
+        }
+
+        fn get_render_data(&self) -> GlobalRenderData {
+            return self.mut_app().render_data.clone();
+        }
+
+        fn get_editor_content(&self) -> String {
+            return self.mut_app().editor_content.get_content();
+        }
+
+        fn get_cursor_pos(&self) -> Pos {
+            return self.mut_app().editor.get_selection().get_cursor_pos();
+        }
+
+        fn get_selection(&self) -> Selection {
+            return self.mut_app().editor.get_selection();
+        }
+
+        fn set_selection(&self, selection: Selection) {
+            let app = &mut self.mut_app();
+            app.editor.set_selection_save_col(selection);
+        }
+
+        fn set_cursor_row_col(&self, row: usize, col: usize) {
+            self.set_selection(Selection::single_r_c(row, col));
+        }
+
+        fn extract_to_variable(&self, name: &str) {
+            self.mut_app().extract_to_variable(
+                name,
+                self.units(),
+                self.allocator(),
+                self.mut_tokens(),
+                self.mut_results(),
+                self.mut_vars(),
+                self.mut_editor_objects(),
+                self.mut_render_bucket(),
+            );
+        }
+    }
+
+    fn create_app3<'a>(client_width: usize, client_height: usize) -> BorrowCheckerFighter {
+        for b in unsafe { &mut RESULT_BUFFER } {
+            *b = 0;
+        }
+        let app = NoteCalcApp::new(client_width, client_height);
+        let editor_objects = EditorObjects::new();
+        let tokens = AppTokens::new();
+        let results = Results::new();
+        let vars = create_vars();
+        fn to_box_ptr<T>(t: T) -> u64 {
+            let ptr = Box::into_raw(Box::new(t)) as u64;
+            ptr
+        }
+        return BorrowCheckerFighter {
+            app_ptr: to_box_ptr(app),
+            units_ptr: to_box_ptr(Units::new()),
+            render_bucket_ptr: to_box_ptr(RenderBuckets::new()),
+            tokens_ptr: to_box_ptr(tokens),
+            results_ptr: to_box_ptr(results),
+            vars_ptr: to_box_ptr(vars),
+            editor_objects_ptr: to_box_ptr(editor_objects),
+            allocator: to_box_ptr(Bump::with_capacity(MAX_LINE_COUNT * 120)),
+        };
+    }
+
+    fn create_app2<'a>(client_height: usize) -> BorrowCheckerFighter {
+        create_app3(120, client_height)
+    }
+
+    #[test]
+    fn bug1() {
+        let test = create_app2(35);
+        test.paste("[123, 2, 3; 4567981, 5, 6] * [1; 2; 3;4]");
+
+        test.set_cursor_row_col(0, 33);
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+        test.render();
+    }
+
+    #[test]
+    fn bug2() {
+        let test = create_app2(35);
+        test.paste("[123, 2, 3; 4567981, 5, 6] * [1; 2; 3;4]");
+        test.set_cursor_row_col(0, 1);
+
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+        test.render();
+        test.input(EditorInputEvent::Down, InputModifiers::none());
+        test.render();
+    }
+
+    #[test]
+    fn bug3() {
+        let test = create_app2(35);
+        test.paste(
+            "1\n\
+                    2+",
+        );
+        test.set_cursor_row_col(1, 2);
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        test.render();
+    }
+
+    #[test]
+    fn test_that_variable_name_is_inserted_when_referenced_a_var_line() {
+        let test = create_app2(35);
+        test.paste(
+            "var_name = 1\n\
+                    2+",
+        );
+        test.set_cursor_row_col(1, 2);
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        test.render();
+        assert_eq!(
+            "var_name = 1\n\
+                 2+var_name",
+            test.get_editor_content()
+        );
+    }
+
+    #[test]
+    fn bug4() {
+        let test = create_app2(35);
+        test.paste(
+            "1\n\
+                    ",
+        );
+        test.render();
+        test.set_cursor_row_col(1, 0);
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        test.render();
+        assert_eq!(
+            "1\n\
+                 &[1]",
+            test.get_editor_content()
+        );
+    }
+
+    #[test]
+    fn bug5() {
+        let test = create_app2(35);
+        test.paste("123\na ");
+
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        assert_eq!(
+            3,
+            test.tokens()[content_y(1)].as_ref().unwrap().tokens.len()
+        );
+    }
+
+    #[test]
+    fn test_rendering_empty_app_does_not_panic() {
+        let test = create_app2(35);
+        test.render();
+        test.assert_results(&[]);
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 0));
+        assert_eq!(
+            test.get_render_data().get_render_y(content_y(0)),
+            Some(canvas_y(0))
+        );
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 1);
+    }
+
+    #[test]
+    fn test_diff_results() {
+        let test = create_app2(35);
+        test.paste("a = 2\n3 * a\n4");
+        let diff = test
+            .app()
+            .diff_results("a = 2\n3 * a + 1\n4", test.units(), test.mut_vars());
+        assert_eq!(diff, vec![(1, "6".to_owned(), "7".to_owned())]);
+
+        let diff = test
+            .app()
+            .diff_results("a = 2\n3 * a\n4", test.units(), test.mut_vars());
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn it_is_not_allowed_to_ref_lines_below() {
+        let test = create_app2(35);
+        test.paste(
+            "1\n\
+                    2+\n3\n4",
+        );
+        test.render();
+        test.set_cursor_row_col(1, 2);
+        test.input(EditorInputEvent::Down, InputModifiers::alt());
+        test.alt_key_released();
+        test.render();
+        assert_eq!(
+            "1\n\
+                    2+\n3\n4",
+            test.get_editor_content()
+        );
+    }
+
+    #[test]
+    fn it_is_not_allowed_to_ref_lines_below2() {
+        let test = create_app2(35);
+        test.paste(
+            "1\n\
+                    2+\n3\n4",
+        );
+        test.render();
+        test.set_cursor_row_col(1, 2);
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.input(EditorInputEvent::Down, InputModifiers::alt());
+        test.alt_key_released();
+        test.render();
+        assert_eq!(
+            "1\n\
+                    2+&[1]\n3\n4",
+            test.get_editor_content()
+        );
+    }
+
+    #[test]
+    fn remove_matrix_backspace() {
+        let test = create_app2(35);
+        test.paste("abcd [1,2,3;4,5,6]");
+        test.render();
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
+        assert_eq!("abcd ", test.get_editor_content());
+    }
+
+    #[test]
+    fn matrix_step_in_dir() {
+        // from right
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1,2,3;4,5,6]");
+            test.render();
+            test.input(EditorInputEvent::Left, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            assert_eq!("abcd [1,2,1;4,5,6]", test.get_editor_content());
+        }
+        // from left
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1,2,3;4,5,6]");
+            test.set_cursor_row_col(0, 5);
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            assert_eq!("abcd [9,2,3;4,5,6]", test.get_editor_content());
+        }
+        // from below
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1,2,3;4,5,6]\naaaaaaaaaaaaaaaaaa");
+            test.set_cursor_row_col(1, 7);
+            test.render();
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            assert_eq!(
+                "abcd [1,2,3;9,5,6]\naaaaaaaaaaaaaaaaaa",
+                test.get_editor_content()
+            );
+        }
+        // from above
+        {
+            let test = create_app2(35);
+            test.paste("aaaaaaaaaaaaaaaaaa\nabcd [1,2,3;4,5,6]");
+            test.set_cursor_row_col(0, 7);
+            test.render();
+            test.input(EditorInputEvent::Down, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            assert_eq!(
+                "aaaaaaaaaaaaaaaaaa\nabcd [9,2,3;4,5,6]",
+                test.get_editor_content()
+            );
+        }
+    }
+
+    #[test]
+    fn cursor_is_put_after_the_matrix_after_finished_editing() {
+        let test = create_app2(35);
+        test.paste("abcd [1,2,3;4,5,6]");
+        test.render();
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.render();
+        test.input(EditorInputEvent::Char('6'), InputModifiers::none());
+        test.render();
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.render();
+        test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+        assert_eq!(test.get_editor_content(), "abcd [1,2,6;4,5,6]9");
+    }
+
+    #[test]
+    fn remove_matrix_del() {
+        let test = create_app2(35);
+        test.paste("abcd [1,2,3;4,5,6]");
+        test.set_cursor_row_col(0, 5);
+        test.render();
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+        assert_eq!("abcd ", test.get_editor_content());
+    }
+
+    #[test]
+    fn test_that_selected_matrix_content_is_copied_on_ctrl_c() {
+        let test = create_app2(35);
+        test.paste("abcd [69,2,3;4,5,6]");
+        test.set_cursor_row_col(0, 5);
+        test.render();
+        test.input(EditorInputEvent::Right, InputModifiers::none());
+        test.render();
+        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
+        assert_eq!(
+            test.mut_app()
+                .get_selected_text_and_clear_app_clipboard()
+                .as_ref()
+                .map(|it| it.as_str()),
+            Some("69")
+        );
+    }
+
+    #[test]
+    fn test_copying_a_matrix_line_gives_its_canonical_text() {
+        let test = create_app2(35);
+        test.paste("abc\n[1,2,3;4,5,6]\n");
+        test.set_cursor_row_col(1, 0);
+        test.render();
+        test.input(EditorInputEvent::End, InputModifiers::shift());
+        test.render();
+        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
+        assert_eq!(
+            test.mut_app()
+                .get_selected_text_and_clear_app_clipboard()
+                .as_ref()
+                .map(|it| it.as_str()),
+            Some("[1,2,3;4,5,6]")
+        );
+
+        test.set_cursor_row_col(0, 0);
+        test.input(EditorInputEvent::Down, InputModifiers::shift());
+        test.input(EditorInputEvent::End, InputModifiers::shift());
+        test.render();
+        assert_eq!(
+            test.mut_app()
+                .get_selected_text_and_clear_app_clipboard()
+                .as_ref()
+                .map(|it| it.as_str()),
+            Some("abc\n[1,2,3;4,5,6]")
+        );
+
+        // cut
+        test.set_cursor_row_col(1, 0);
+        test.input(EditorInputEvent::End, InputModifiers::shift());
+        test.render();
+        assert_eq!(
+            test.mut_app()
+                .get_selected_text_and_clear_app_clipboard()
+                .as_ref()
+                .map(|it| it.as_str()),
+            Some("[1,2,3;4,5,6]")
+        );
+        test.input(EditorInputEvent::Char('x'), InputModifiers::ctrl());
+        assert_eq!(test.get_editor_content(), "abc\n\n");
+    }
+
+    #[test]
+    fn test_insert_matrix_line_ref_panic() {
+        let test = create_app2(35);
+        test.paste("[1,2,3;4,5,6]\n[1;2;3]\n");
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(2)), 5);
+    }
+
+    #[test]
+    fn test_transposed_row_vector_is_rendered_as_column_vector() {
+        let test = create_app2(35);
+        test.paste("transpose([1,2,3])");
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 5);
+    }
+
+    #[test]
+    fn test_collapsing_a_matrix() {
+        let test = create_app2(35);
+        test.paste("[1,2,3] * [4;5;6]");
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 5);
+
+        test.input(EditorInputEvent::Char('m'), InputModifiers::ctrl());
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 1);
+        assert_eq!(test.editor_objects()[content_y(0)][1].rendered_h, 1);
+        assert_eq!(test.editor_objects()[content_y(0)][1].rendered_w, 12);
+        assert_eq!("[1,2,3] * [4;5;6]", test.get_editor_content());
+
+        test.input(EditorInputEvent::Char('m'), InputModifiers::ctrl());
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 5);
+        assert_eq!(test.editor_objects()[content_y(0)][1].rendered_h, 5);
+    }
+
+    #[test]
+    fn test_collapsed_matrices_are_reset_when_the_matrix_count_changes() {
+        let test = create_app2(35);
+        test.paste("[1,2,3] * [4;5;6]");
+        test.input(EditorInputEvent::Char('m'), InputModifiers::ctrl());
+        assert_eq!(test.editor_objects()[content_y(0)][1].rendered_h, 1);
+
+        // the 2nd matrix would be [1,2,3]
+        test.set_cursor_row_col(0, 0);
+        test.paste("[7;8;9] * ");
+        test.input(EditorInputEvent::End, InputModifiers::none());
+        assert_eq!("[7;8;9] * [1,2,3] * [4;5;6]", test.get_editor_content());
+        assert_eq!(test.app().editor_content.get_data(0).collapsed_matrices, 0);
+        assert_eq!(test.editor_objects()[content_y(0)][1].rendered_h, 1);
+        assert_eq!(test.editor_objects()[content_y(0)][2].rendered_h, 5);
+    }
+
+    #[test]
+    fn test_matrix_rendering_parameters_single_row() {
+        let test = create_app2(35);
+        test.paste("[1]");
+        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_x, 0);
+        assert_eq!(
+            test.editor_objects()[content_y(0)][0].rendered_y,
+            canvas_y(0)
+        );
+        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_h, 1);
+        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_w, 3);
+    }
+
+    #[test]
+    fn test_rendering_a_matrix_with_more_than_32_cells() {
+        let test = create_app2(35);
+        let row = (1..=7)
+            .map(|it| it.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let rows = (0..6).map(|_| row.clone()).collect::<Vec<_>>().join("; ");
+        test.paste(&format!("[{}]", rows));
+        test.render();
+        match test
+            .app()
+            .get_line_result(test.mut_results(), 0)
+            .map(|it| &it.typ)
+        {
+            Some(CalcResultType::Matrix(mat)) => assert_eq!(mat.cells.len(), 42),
+            _ => panic!(),
+        }
+        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_h, 8);
+    }
+
+    #[test]
+    fn test_scaled_matrix_results_are_rendered_as_matrices() {
+        let test = create_app2(35);
+        test.paste("3 * [1,2;3,4]\n[2,4;6,8] / 2\n[1, 2] * 2 m");
+        test.render();
+        test.assert_results(&["[3, 6; 9, 12]", "[1, 2; 3, 4]", "[2 m, 4 m]"][..]);
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 4);
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(1)), 4);
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(2)), 1);
+    }
+
+    #[test]
+    fn test_matrix_spacing_is_normalized() {
+        let mut rendered_widths = Vec::new();
+        for input in &["[1,2;3,4]", "[ 1 , 2 ; 3 ,4 ]", "[1, 2; 3, 4]"] {
+            let test = create_app2(35);
+            test.paste(input);
+            test.render();
+            test.assert_results(&["[1, 2; 3, 4]"][..]);
+            rendered_widths.push(test.editor_objects()[content_y(0)][0].rendered_w);
+
+            test.click(LEFT_GUTTER_MIN_WIDTH + 1, 1);
+            assert!(test.app().matrix_editing.is_some());
+            test.input(EditorInputEvent::Esc, InputModifiers::none());
+            assert!(test.app().matrix_editing.is_none());
+            assert_eq!(test.get_editor_content(), "[1,2;3,4]", "input: {}", input);
+        }
+        assert!(rendered_widths.iter().all(|it| *it == rendered_widths[0]));
+    }
+
+    #[test]
+    fn test_matrix_rendering_parameters_multiple_rows() {
+        let test = create_app2(35);
+        test.paste("[1;2;3]");
+        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_x, 0);
+        assert_eq!(
+            test.editor_objects()[content_y(0)][0].rendered_y,
+            canvas_y(0)
+        );
+        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_h, 5);
+        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_w, 3);
+    }
+
+    #[test]
+    fn test_referencing_matrix_size_correct2() {
+        let test = create_app2(35);
+        test.paste("[6]\n&[1]");
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        assert_eq!(test.editor_objects()[content_y(1)][0].rendered_h, 1);
+    }
+
+    #[test]
+    fn test_referencing_matrix_size_correct2_vert_align() {
+        let test = create_app2(35);
+        test.paste("[1;2;3]\n[4]\n&[1]  &[2]");
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        let first_line_h = 5;
+        let second_line_half = (5 / 2) + 1;
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        assert_contains(
+            &test.render_bucket().custom_commands[Layer::AboveText as usize],
+            1,
+            pulsing_ref_rect(left_gutter_width + 5, first_line_h + second_line_half, 3, 1),
+        )
+    }
+
+    #[test]
+    fn test_matrices_with_different_heights_are_centered_independently() {
+        let test = create_app2(35);
+        // the heights are 6, 4 and 1
+        test.paste("[1;2;3;4] + [5;6] + [7]\n");
+        let right_x = test.get_render_data().result_gutter_x;
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 6);
+        test.assert_contains_operator(1, |op| {
+            op.text == &['┌'] && op.row == canvas_y(0) && op.column < right_x
+        });
+        test.assert_contains_operator(1, |op| {
+            op.text == &['┌'] && op.row == canvas_y(1) && op.column < right_x
+        });
+        test.assert_contains_operator(1, |op| {
+            op.text == &['['] && op.row == canvas_y(2) && op.column < right_x
+        });
+    }
 
-fn calc_consecutive_matrices_max_lengths(
-    units: &Units,
-    results: &[LineResult],
-) -> Option<ResultLengths> {
-    let mut max_lengths: Option<ResultLengths> = None;
-    for result in results.iter() {
-        match result {
-            Ok(Some(CalcResult {
-                typ: CalcResultType::Matrix(mat),
-                ..
-            })) => {
-                let lengths = calc_matrix_max_lengths(units, mat);
-                if let Some(max_lengths) = &mut max_lengths {
-                    max_lengths.set_max(&lengths);
-                } else {
-                    max_lengths = Some(lengths);
-                }
-            }
-            _ => {
-                break;
-            }
-        }
+    #[test]
+    fn test_referencing_matrix_size_correct() {
+        let test = create_app2(35);
+        test.paste("[1;2;3]\n&[1]");
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        assert_eq!(test.editor_objects()[content_y(1)][0].rendered_h, 5);
     }
-    return max_lengths;
-}
 
-fn calc_matrix_max_lengths(units: &Units, mat: &MatrixData) -> ResultLengths {
-    let cells_strs = {
-        let mut tokens_per_cell: SmallVec<[String; 32]> = SmallVec::with_capacity(32);
+    #[test]
+    fn test_get_matrix_editing_state() {
+        let test = create_app2(35);
+        test.paste("abcd [1,2,3;4,5,6]");
+        test.render();
+        assert_eq!(test.app().get_matrix_editing_state(), None);
 
-        for cell in mat.cells.iter() {
-            let result_str = render_result(
-                units,
-                cell,
-                &ResultFormat::Dec,
-                false,
-                Some(RENDERED_RESULT_PRECISION),
-                true,
-            );
-            tokens_per_cell.push(result_str);
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+        let state = test.app().get_matrix_editing_state().unwrap();
+        assert_eq!(state.row_count, 2);
+        assert_eq!(state.col_count, 3);
+        assert_eq!(state.current_cell, Pos::from_row_column(0, 2));
+        assert_eq!(state.cell_strings, vec!["1", "2", "9", "4", "5", "6"]);
+    }
+
+    #[test]
+    fn test_moving_inside_a_matrix() {
+        // right to left, cursor at end
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1,2,3;4,5,6]");
+            test.render();
+            test.input(EditorInputEvent::Left, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Left, InputModifiers::none());
+            test.input(EditorInputEvent::Left, InputModifiers::none());
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
+            assert_eq!("abcd [1,9,3;4,5,6]", test.get_editor_content());
         }
-        tokens_per_cell
-    };
-    let max_lengths = {
-        let mut max_lengths = ResultLengths {
-            int_part_len: 0,
-            frac_part_len: 0,
-            unit_part_len: 0,
-        };
-        for cell_str in &cells_strs {
-            let lengths = get_int_frac_part_len(cell_str);
-            max_lengths.set_max(&lengths);
+        // pressing right while there is a selection, just cancels the selection and put the cursor
+        // at the end of it
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1,2,3;4,5,6]");
+            test.set_cursor_row_col(0, 5);
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
+            assert_eq!("abcd [19,2,3;4,5,6]", test.get_editor_content());
+        }
+        // left to right, cursor at start
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1,2,3;4,5,6]");
+            test.set_cursor_row_col(0, 5);
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
+            assert_eq!("abcd [1,2,9;4,5,6]", test.get_editor_content());
+        }
+        // vertical movement down, cursor tries to keep its position
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1111,22,3;44,55555,666]");
+            test.set_cursor_row_col(0, 5);
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.render();
+            // inside the matrix
+            test.input(EditorInputEvent::Down, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
+            assert_eq!("abcd [1111,22,3;9,55555,666]", test.get_editor_content());
         }
-        max_lengths
-    };
-    return max_lengths;
-}
 
-fn draw_line_refs_and_vars_referenced_from_cur_row<'b>(
-    editor_objs: &[EditorObject],
-    gr: &GlobalRenderData,
-    render_buckets: &mut RenderBuckets<'b>,
-    editor_y_to_render_w: &[usize; MAX_LINE_COUNT],
-) {
-    let mut color_index = 0;
-    let mut highlighted = BitFlag128::empty();
-    for editor_obj in editor_objs {
-        match editor_obj.typ {
-            EditorObjectType::LineReference { var_index }
-            | EditorObjectType::Variable { var_index } => {
-                if var_index == SUM_VARIABLE_INDEX {
-                    continue;
-                }
-                let color = if highlighted.is_true(var_index) {
-                    continue;
-                } else {
-                    highlighted.set(var_index);
-                    let color = ACTIVE_LINE_REF_HIGHLIGHT_COLORS[color_index];
-                    color_index = if color_index < 8 { color_index + 1 } else { 0 };
-                    color
-                };
-                let defined_at = content_y(var_index);
-                if let Some(render_y) = gr.get_render_y(defined_at) {
-                    render_buckets.custom_commands[Layer::AboveText as usize]
-                        .push(OutputMessage::SetColor(color << 8 | 0x33));
-                    render_buckets.custom_commands[Layer::AboveText as usize].push(
-                        OutputMessage::RenderRectangle {
-                            x: gr.left_gutter_width,
-                            y: render_y,
-                            w: editor_y_to_render_w[defined_at.as_usize()],
-                            h: gr.get_rendered_height(defined_at),
-                        },
-                    );
-                }
-            }
-            _ => {}
+        // vertical movement up, cursor tries to keep its position
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1111,22,3;44,55555,666]");
+            test.set_cursor_row_col(0, 5);
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.render();
+            // inside the matrix
+            test.input(EditorInputEvent::Down, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
+            assert_eq!("abcd [9,22,3;44,55555,666]", test.get_editor_content());
         }
     }
-}
 
-fn draw_token<'text_ptr>(
-    token: &Token<'text_ptr>,
-    render_x: usize,
-    render_y: CanvasY,
-    current_editor_width: usize,
-    left_gutter_width: usize,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-) {
-    let dst = if token.has_error() {
-        &mut render_buckets.number_errors
-    } else {
-        match &token.typ {
-            TokenType::StringLiteral => &mut render_buckets.utf8_texts,
-            TokenType::Header => &mut render_buckets.headers,
-            TokenType::Variable { .. } => &mut render_buckets.variable,
-            TokenType::LineReference { .. } => &mut render_buckets.variable,
-            TokenType::NumberLiteral(_) => &mut render_buckets.numbers,
-            TokenType::NumberErr => &mut render_buckets.number_errors,
-            TokenType::Operator(OperatorTokenType::ApplyUnit(_)) => &mut render_buckets.units,
-            TokenType::Unit(_) => &mut render_buckets.units,
-            TokenType::Operator(_) => &mut render_buckets.operators,
-        }
-    };
-    let text_len = token
-        .ptr
-        .len()
-        .min((current_editor_width as isize - render_x as isize).max(0) as usize);
-    dst.push(RenderUtf8TextMsg {
-        text: &token.ptr[0..text_len],
-        row: render_y,
-        column: render_x + left_gutter_width,
-    });
-}
+    #[test]
+    fn test_moving_inside_a_matrix_with_tab() {
+        let test = create_app2(35);
+        test.paste("[1,2,3;4,5,6]");
+        test.render();
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.input(EditorInputEvent::Right, InputModifiers::none());
 
-fn render_buckets_into(buckets: &RenderBuckets, canvas: &mut [[char; 256]]) {
-    fn write_char_slice(canvas: &mut [[char; 256]], row: CanvasY, col: usize, src: &[char]) {
-        let str = &mut canvas[row.as_usize()];
-        for (dst_char, src_char) in str[col..].iter_mut().zip(src.iter()) {
-            *dst_char = *src_char;
-        }
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Char('7'), InputModifiers::none());
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Char('8'), InputModifiers::none());
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Char('0'), InputModifiers::none());
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Char('4'), InputModifiers::none());
+        test.render();
+        assert_eq!("[1,7,8;9,0,9]4", test.get_editor_content());
     }
 
-    fn write_str(canvas: &mut [[char; 256]], row: CanvasY, col: usize, src: &str) {
-        let str = &mut canvas[row.as_usize()];
-        for (dst_char, src_char) in str[col..].iter_mut().zip(src.chars()) {
-            *dst_char = src_char;
-        }
+    #[test]
+    fn test_leaving_a_matrix_with_tab() {
+        let test = create_app2(35);
+        test.paste("[1,2,3;4,5,6]");
+        test.render();
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.render();
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        // the next tab should leave the matrix
+        test.input(EditorInputEvent::Tab, InputModifiers::none());
+        test.input(EditorInputEvent::Char('7'), InputModifiers::none());
+        test.render();
+        assert_eq!("[1,2,3;4,5,6]7", test.get_editor_content());
     }
 
-    fn write_ascii(canvas: &mut [[char; 256]], row: CanvasY, col: usize, src: &[u8]) {
-        let str = &mut canvas[row.as_usize()];
-        for (dst_char, src_char) in str[col..].iter_mut().zip(src.iter()) {
-            *dst_char = *src_char as char;
+    #[test]
+    fn end_btn_matrix() {
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1111,22,3;44,55555,666] qq");
+            test.set_cursor_row_col(0, 5);
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            test.render();
+            // inside the matrix
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
+            assert_eq!(
+                "abcd [1111,22,9;44,55555,666] qq",
+                test.get_editor_content()
+            );
         }
-    }
-
-    fn write_command(canvas: &mut [[char; 256]], command: &OutputMessage) {
-        match command {
-            OutputMessage::RenderUtf8Text(text) => {
-                write_char_slice(canvas, text.row, text.column, text.text);
-            }
-            OutputMessage::SetStyle(..) => {}
-            OutputMessage::SetColor(..) => {}
-            OutputMessage::RenderRectangle { .. } => {}
-            OutputMessage::RenderChar(x, y, ch) => {
-                let str = &mut canvas[*y];
-                str[*x] = *ch;
-            }
-            OutputMessage::RenderString(text) => {
-                write_str(canvas, text.row, text.column, &text.text);
-            }
-            OutputMessage::RenderAsciiText(text) => {
-                write_ascii(canvas, text.row, text.column, &text.text);
-            }
-            OutputMessage::PulsingRectangle { .. } => {}
-            OutputMessage::FollowingTextCommandsAreHeaders { .. } => {}
+        // pressing twice, exits the matrix
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1111,22,3;44,55555,666] qq");
+            test.set_cursor_row_col(0, 5);
+            test.render();
+            test.input(EditorInputEvent::Right, InputModifiers::none());
+            // inside the matrix
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.render();
+            assert_eq!(
+                "abcd [1111,22,3;44,55555,666] qq9",
+                test.get_editor_content()
+            );
         }
     }
 
-    for command in &buckets.custom_commands[Layer::BehindText as usize] {
-        write_command(canvas, command);
-    }
+    #[test]
+    fn pasting_a_matrix_does_not_start_matrix_editing() {
+        let test = create_app2(35);
+        test.paste("[1,2]");
+        test.render();
+        assert!(test.app().matrix_editing.is_none());
+        assert_eq!("[1,2]", test.get_editor_content());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 5));
+        test.assert_results(&["[1, 2]"][..]);
 
-    for command in &buckets.utf8_texts {
-        write_char_slice(canvas, command.row, command.column, command.text);
-    }
-    for text in &buckets.ascii_texts {
-        write_ascii(canvas, text.row, text.column, text.text);
-    }
-    for command in &buckets.numbers {
-        write_char_slice(canvas, command.row, command.column, command.text);
-    }
+        // into the middle of a line
+        let test = create_app2(35);
+        test.paste("2 * ");
+        test.render();
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.paste("[1,2;3,4]");
+        test.render();
+        assert!(test.app().matrix_editing.is_none());
+        assert_eq!("2 *[1,2;3,4] ", test.get_editor_content());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 12));
 
-    for command in &buckets.number_errors {
-        write_char_slice(canvas, command.row, command.column, command.text);
+        // a single undo removes the whole pasted text
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!("2 * ", test.get_editor_content());
     }
 
-    for command in &buckets.units {
-        write_char_slice(canvas, command.row, command.column, command.text);
-    }
+    #[test]
+    fn end_home_btn_on_matrix_only_line() {
+        let test = create_app2(35);
+        test.paste("[1,2;3,4]\n");
+        test.set_cursor_row_col(0, 9);
+        test.render();
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.render();
+        assert!(test.app().matrix_editing.is_none());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 0));
 
-    for command in &buckets.operators {
-        write_char_slice(canvas, command.row, command.column, command.text);
-    }
+        test.input(EditorInputEvent::End, InputModifiers::none());
+        test.render();
+        assert!(test.app().matrix_editing.is_none());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 9));
 
-    for command in &buckets.line_ref_results {
-        write_str(canvas, command.row, command.column, &command.text);
-    }
+        // from inside the matrix
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.render();
+        assert!(test.app().matrix_editing.is_some());
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.render();
+        assert!(test.app().matrix_editing.is_none());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 0));
 
-    for command in &buckets.variable {
-        write_char_slice(canvas, command.row, command.column, command.text);
+        test.input(EditorInputEvent::Right, InputModifiers::none());
+        test.render();
+        assert!(test.app().matrix_editing.is_some());
+        test.input(EditorInputEvent::End, InputModifiers::none());
+        test.input(EditorInputEvent::End, InputModifiers::none());
+        test.render();
+        assert!(test.app().matrix_editing.is_none());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 9));
+        assert_eq!(test.get_editor_content(), "[1,2;3,4]\n");
     }
 
-    for command in &buckets.custom_commands[Layer::Text as usize] {
-        write_command(canvas, command);
+    #[test]
+    fn end_home_btn_on_empty_line() {
+        let test = create_app2(35);
+        test.paste("[1,2;3,4]\n\n");
+        test.set_cursor_row_col(1, 0);
+        test.render();
+        test.input(EditorInputEvent::End, InputModifiers::none());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(1, 0));
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(1, 0));
+        assert!(test.app().matrix_editing.is_none());
     }
 
-    for command in &buckets.custom_commands[Layer::AboveText as usize] {
-        write_command(canvas, command);
+    #[test]
+    fn home_btn_matrix() {
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1111,22,3;44,55555,666]");
+            test.render();
+            test.input(EditorInputEvent::Left, InputModifiers::none());
+            test.render();
+            // inside the matrix
+            test.input(EditorInputEvent::Home, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
+            assert_eq!("abcd [9,22,3;44,55555,666]", test.get_editor_content());
+        }
+        {
+            let test = create_app2(35);
+            test.paste("abcd [1111,22,3;44,55555,666]");
+            test.render();
+            test.input(EditorInputEvent::Left, InputModifiers::none());
+            // inside the matrix
+            test.input(EditorInputEvent::Home, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Home, InputModifiers::none());
+            test.render();
+            test.input(EditorInputEvent::Char('6'), InputModifiers::none());
+            test.render();
+            assert_eq!("6abcd [1111,22,3;44,55555,666]", test.get_editor_content());
+        }
     }
-}
 
-fn render_selection_and_its_sum<'text_ptr>(
-    units: &Units,
-    render_buckets: &mut RenderBuckets<'text_ptr>,
-    results: &Results,
-    editor: &Editor,
-    editor_content: &EditorContent<LineData>,
-    gr: &GlobalRenderData,
-    vars: &Variables,
-    allocator: &'text_ptr Bump,
-) {
-    render_buckets.set_color(Layer::BehindText, 0xA6D2FF_FF);
-    if let Some((start, end)) = editor.get_selection().is_range() {
-        if end.row > start.row {
-            // first line
-            if let Some(start_render_y) = gr.get_render_y(content_y(start.row)) {
-                let height = gr.get_rendered_height(content_y(start.row));
-                render_buckets.draw_rect(
-                    Layer::BehindText,
-                    start.column + gr.left_gutter_width,
-                    start_render_y,
-                    (editor_content.line_len(start.row) - start.column)
-                        .min(gr.current_editor_width),
-                    height,
-                );
-            }
-            // full lines
-            for i in start.row + 1..end.row {
-                if let Some(render_y) = gr.get_render_y(content_y(i)) {
-                    let height = gr.get_rendered_height(content_y(i));
-                    render_buckets.draw_rect(
-                        Layer::BehindText,
-                        gr.left_gutter_width,
-                        render_y,
-                        editor_content.line_len(i).min(gr.current_editor_width),
-                        height,
-                    );
-                }
-            }
-            // last line
-            if let Some(end_render_y) = gr.get_render_y(content_y(end.row)) {
-                let height = gr.get_rendered_height(content_y(end.row));
-                render_buckets.draw_rect(
-                    Layer::BehindText,
-                    gr.left_gutter_width,
-                    end_render_y,
-                    end.column.min(gr.current_editor_width),
-                    height,
-                );
-            }
-        } else if let Some(start_render_y) = gr.get_render_y(content_y(start.row)) {
-            let height = gr.get_rendered_height(content_y(start.row));
-            render_buckets.draw_rect(
-                Layer::BehindText,
-                start.column + gr.left_gutter_width,
-                start_render_y,
-                (end.column - start.column).min(gr.current_editor_width),
-                height,
-            );
-        }
-        // evaluated result of selection, selected text
-        if let Some(mut partial_result) = evaluate_selection(
-            &units,
-            editor,
-            editor_content,
-            &vars,
-            results.as_slice(),
-            allocator,
-        ) {
-            if start.row == end.row {
-                if let Some(start_render_y) = gr.get_render_y(content_y(start.row)) {
-                    let selection_center = start.column + ((end.column - start.column) / 2);
-                    partial_result.insert_str(0, "= ");
-                    let result_w = partial_result.chars().count();
-                    let centered_x =
-                        (selection_center as isize - (result_w / 2) as isize).max(0) as usize;
-                    render_buckets.set_color(Layer::AboveText, 0xAAFFAA_FF);
-                    let rect_y = if start.row == 0 {
-                        start_render_y.add(1)
-                    } else {
-                        start_render_y.sub(1)
-                    };
-                    render_buckets.draw_rect(
-                        Layer::AboveText,
-                        gr.left_gutter_width + centered_x,
-                        rect_y,
-                        result_w,
-                        1,
-                    );
-                    render_buckets.set_color(Layer::AboveText, 0x000000_FF);
-                    render_buckets.draw_string(
-                        Layer::AboveText,
-                        gr.left_gutter_width + centered_x,
-                        rect_y,
-                        partial_result,
-                    );
-                }
-            } else {
-                partial_result.insert_str(0, " ∑ = ");
-                let result_w = partial_result.chars().count();
-                let x = (start.row..=end.row)
-                    .map(|it| editor_content.line_len(it))
-                    .max_by(|a, b| a.cmp(b))
-                    .unwrap()
-                    + 3;
-                let frist_visible_row_index = content_y(start.row.max(gr.scroll_y));
-                let last_visible_row_index =
-                    content_y(end.row.min(gr.scroll_y + gr.client_height - 1));
-                let inner_height = gr
-                    .get_render_y(last_visible_row_index)
-                    .expect("")
-                    .as_usize()
-                    - gr.get_render_y(frist_visible_row_index)
-                        .expect("")
-                        .as_usize();
-                render_buckets.set_color(Layer::AboveText, 0xAAFFAA_FF);
-                render_buckets.draw_rect(
-                    Layer::AboveText,
-                    gr.left_gutter_width + x,
-                    gr.get_render_y(frist_visible_row_index).expect(""),
-                    result_w + 1,
-                    inner_height + 1,
-                );
-                // draw the parenthesis
-                render_buckets.set_color(Layer::AboveText, 0x000000_FF);
+    #[test]
+    fn test_locked_lines_cant_be_modified() {
+        let test = create_app2(35);
+        test.paste("1\n2\n3");
+        test.mut_app().set_line_locked(1, true);
 
-                render_buckets.draw_char(
-                    Layer::AboveText,
-                    gr.left_gutter_width + x,
-                    gr.get_render_y(frist_visible_row_index).expect(""),
-                    if frist_visible_row_index.as_usize() == start.row {
-                        '⎫'
-                    } else {
-                        '⎪'
-                    },
-                );
+        test.set_cursor_row_col(1, 1);
+        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
+        // merging the next line into it
+        test.set_cursor_row_col(1, 1);
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+        // merging it into the previous line
+        test.set_cursor_row_col(1, 0);
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
+        // merging the next line into it
+        test.set_cursor_row_col(2, 0);
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 0),
+            Pos::from_row_column(2, 1),
+        ));
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+        assert_eq!("1\n2\n3", test.get_editor_content());
 
-                render_buckets.draw_char(
-                    Layer::AboveText,
-                    gr.left_gutter_width + x,
-                    gr.get_render_y(last_visible_row_index).expect(""),
-                    if last_visible_row_index.as_usize() == end.row {
-                        '⎭'
-                    } else {
-                        '⎪'
-                    },
-                );
+        test.set_cursor_row_col(2, 1);
+        test.input(EditorInputEvent::Char('b'), InputModifiers::none());
+        assert_eq!("1\n2\n3b", test.get_editor_content());
 
-                for i in 1..inner_height {
-                    render_buckets.draw_char(
-                        Layer::AboveText,
-                        gr.left_gutter_width + x,
-                        gr.get_render_y(frist_visible_row_index).expect("").add(i),
-                        '⎪',
-                    );
-                }
-                // center
-                render_buckets.draw_string(
-                    Layer::AboveText,
-                    gr.left_gutter_width + x,
-                    gr.get_render_y(frist_visible_row_index)
-                        .expect("")
-                        .add(inner_height / 2),
-                    partial_result,
-                );
-            }
-        }
+        test.mut_app().set_line_locked(1, false);
+        test.set_cursor_row_col(1, 1);
+        test.input(EditorInputEvent::Char('c'), InputModifiers::none());
+        assert_eq!("1\n2c\n3b", test.get_editor_content());
     }
-}
 
-fn calc_rendered_height<'b>(
-    editor_y: ContentIndex,
-    matrix_editing: &Option<MatrixEditing>,
-    tokens: &AppTokens,
-    results: &Results,
-    vars: &Variables,
-) -> usize {
-    return if let Some(tokens) = &tokens[editor_y] {
-        let h = PerLineRenderData::calc_rendered_row_height(
-            &results[editor_y],
-            &tokens.tokens,
-            vars,
-            matrix_editing
-                .as_ref()
-                .filter(|it| it.row_index == editor_y)
-                .map(|it| MatrixData::calc_render_height(it.row_count)),
+    #[test]
+    fn test_locked_lines_cant_be_modified_by_paste_undo_or_replace() {
+        let test = create_app2(35);
+        test.paste("a = 1\n2 * a\n3");
+        test.set_cursor_row_col(1, 5);
+        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
+        test.mut_app().set_line_locked(1, true);
+
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!("a = 1\n2 * aa\n3", test.get_editor_content());
+
+        test.set_cursor_row_col(1, 0);
+        test.paste("4 + ");
+        assert_eq!("a = 1\n2 * aa\n3", test.get_editor_content());
+
+        let count = test.mut_app().replace_all(
+            "a",
+            "b",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
         );
-        h
-    } else {
-        1
-    };
-}
+        assert_eq!(count, 1);
+        assert_eq!("b = 1\n2 * aa\n3", test.get_editor_content());
 
-fn is_pos_inside_an_obj(editor_objects: &EditorObjects, pos: Pos) -> Option<&EditorObject> {
-    for obj in &editor_objects[content_y(pos.row)] {
-        if (obj.start_x + 1..obj.end_x).contains(&pos.column) {
-            return Some(obj);
-        }
+        // a new line can still be started after it, and that can be undone
+        test.mut_app().editor_content.undo_stack.clear();
+        test.set_cursor_row_col(1, 6);
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        assert_eq!("b = 1\n2 * aa\n\n3", test.get_editor_content());
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!("b = 1\n2 * aa\n3", test.get_editor_content());
+
+        let test = create_app2(35);
+        test.paste("2 + 3 * 4");
+        test.mut_app().set_line_locked(0, true);
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 4),
+            Pos::from_row_column(0, 9),
+        ));
+        test.extract_to_variable("t");
+        assert_eq!("2 + 3 * 4", test.get_editor_content());
     }
-    return None;
-}
 
-pub fn end_matrix_editing(
-    matrix_editing: &mut Option<MatrixEditing>,
-    editor: &mut Editor,
-    editor_content: &mut EditorContent<LineData>,
-    new_cursor_pos: Option<Pos>,
-) {
-    let mat_editor = {
-        let mat_editor = matrix_editing.as_mut().unwrap();
-        mat_editor.save_editor_content();
-        mat_editor
-    };
-    let mut concat = String::with_capacity(32);
-    concat.push('[');
-    for row_i in 0..mat_editor.row_count {
-        if row_i > 0 {
-            concat.push(';');
-        }
-        for col_i in 0..mat_editor.col_count {
-            if col_i > 0 {
-                concat.push(',');
-            }
-            let cell_str = &mat_editor.cell_strings[row_i * mat_editor.col_count + col_i];
-            concat += &cell_str;
-        }
+    #[test]
+    fn test_matrices_of_locked_lines_cant_be_edited() {
+        let test = create_app2(35);
+        test.paste("[1,2;3,4]");
+        test.mut_app().set_line_locked(0, true);
+        test.render();
+
+        test.click(LEFT_GUTTER_MIN_WIDTH + 1, 1);
+        assert!(test.app().matrix_editing.is_none());
+        assert_eq!(test.get_editor_content(), "[1,2;3,4]");
     }
-    concat.push(']');
-    let selection = Selection::range(
-        Pos::from_row_column(mat_editor.row_index.as_usize(), mat_editor.start_text_index),
-        Pos::from_row_column(mat_editor.row_index.as_usize(), mat_editor.end_text_index),
-    );
-    editor.set_selection_save_col(selection);
-    // TODO: máshogy oldd meg, mert ez modositja az undo stacket is
-    // és az miért baj, legalább tudom ctrl z-zni a mátrix edition-t
-    editor.handle_input(
-        EditorInputEvent::Del,
-        InputModifiers::none(),
-        editor_content,
-    );
-    editor.insert_text(&concat, editor_content);
-    *matrix_editing = None;
 
-    if let Some(new_cursor_pos) = new_cursor_pos {
-        editor.set_selection_save_col(Selection::single(new_cursor_pos));
+    #[test]
+    fn test_can_undo_and_redo() {
+        let test = create_app2(35);
+        test.mut_app().set_undo_limit(Some(1));
+        assert!(!test.app().can_undo());
+        assert!(!test.app().can_redo());
+
+        test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+        test.handle_time(1000);
+        test.input(EditorInputEvent::Char('2'), InputModifiers::none());
+        assert!(test.app().can_undo());
+
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!("1", test.get_editor_content());
+        assert!(!test.app().can_undo());
+        assert!(test.app().can_redo());
+
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl_shift());
+        assert_eq!("12", test.get_editor_content());
+        assert!(!test.app().can_redo());
     }
-    editor.blink_cursor();
-}
 
-fn get_scroll_y_after_cursor_movement(
-    prev_row: usize,
-    current_row: usize,
-    render_data: &GlobalRenderData,
-) -> Option<usize> {
-    if prev_row != current_row {
-        if current_row < render_data.scroll_y {
-            // scroll up
-            Some(current_row)
-        } else {
-            // scroll down
-            // if the new pos is 5. line and its height is 1, this var is 6
-            let new_pos_bottom_y =
-                if let Some(new_row_y) = render_data.get_render_y(content_y(current_row)) {
-                    let new_h = render_data.get_rendered_height(content_y(current_row));
-                    new_row_y.add(new_h)
-                } else {
-                    // find the last rendered line at the bottom
-                    let mut assumed_heights = 1;
-                    let mut prev_row_y = None;
-                    let mut prev_row_i = current_row as isize - 1;
-                    while prev_row_y.is_none() && prev_row_i >= 0 {
-                        prev_row_y = render_data.get_render_y(content_y(prev_row_i as usize));
-                        assumed_heights += 1;
-                        prev_row_i -= 1;
-                    }
-                    // we assume that the non-yet-rendered lines' height will be 1
-                    prev_row_y.unwrap_or(canvas_y(0)).add(assumed_heights)
-                };
-            let new_scroll_y = new_pos_bottom_y.as_isize() + render_data.scroll_y as isize
-                - (render_data.client_height as isize);
-            if new_scroll_y > render_data.scroll_y as isize {
-                Some(new_scroll_y as usize)
-            } else {
-                None
-            }
-        }
-    } else {
-        None
+    #[test]
+    fn bug8() {
+        let test = create_app2(35);
+        test.paste("16892313\n14 * ");
+        test.set_cursor_row_col(1, 5);
+        test.render();
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        assert_eq!("16892313\n14 * &[1]", test.get_editor_content());
+        test.render();
+        test.handle_time(1000);
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
+        assert_eq!("16892313\n14 * ", test.get_editor_content());
+
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!("16892313\n14 * &[1]", test.get_editor_content());
+
+        let _input_eff = test.input(EditorInputEvent::Right, InputModifiers::none()); // end selection
+        test.render();
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
+        assert_eq!("16892313\n14 * a&[1]", test.get_editor_content());
+
+        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
+        test.render();
+        test.input(EditorInputEvent::Right, InputModifiers::none());
+        test.input(EditorInputEvent::Char('b'), InputModifiers::none());
+        assert_eq!("16892313\n14 * a &[1]b", test.get_editor_content());
+
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.input(EditorInputEvent::Left, InputModifiers::none());
+        test.input(EditorInputEvent::Char('c'), InputModifiers::none());
+        assert_eq!("16892313\n14 * a c&[1]b", test.get_editor_content());
     }
-}
 
-fn set_editor_and_result_panel_widths(
-    client_width: usize,
-    result_panel_width_percent: usize,
-    gr: &mut GlobalRenderData,
-) {
-    let mut result_gutter_x: isize =
-        (client_width * (100 - result_panel_width_percent) / 100) as isize;
-    {
-        // the editor pushes the gutter to right
-        let editor_width =
-            (result_gutter_x - SCROLLBAR_WIDTH as isize - LEFT_GUTTER_MIN_WIDTH as isize) - 1;
-        let diff = gr.longest_visible_editor_line_len as isize - editor_width;
-        if diff > 0 {
-            result_gutter_x += diff;
-        }
+    #[test]
+    fn test_referenced_line_calc() {
+        let test = create_app2(35);
+        test.paste("2\n3 * ");
+        test.set_cursor_row_col(1, 4);
+        test.render();
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        assert_eq!("2\n3 * &[1]", test.get_editor_content());
+
+        test.assert_results(&["2", "6"][..]);
+    }
+
+    #[test]
+    fn test_line_reference_cycles_are_errors() {
+        let test = create_app2(35);
+        test.set_normalized_content("1 + &[2]\n3 * &[1]\n5\n&[3] * 2");
+        test.assert_results(&["Err", "Err", "5", "10"][..]);
+
+        // breaking the cycle
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 1),
+            Pos::from_row_column(0, 8),
+        ));
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+        assert_eq!("1\n3 * &[1]\n5\n&[3] * 2", test.get_editor_content());
+        test.assert_results(&["1", "3", "5", "10"][..]);
     }
-    {
-        // the result panel pushes the gutter to left, with higher priority
-        let result_panel_w: isize =
-            client_width as isize - result_gutter_x - RIGHT_GUTTER_WIDTH as isize;
-        let diff = gr.longest_visible_result_len as isize - result_panel_w;
-        if diff > 0 {
-            result_gutter_x -= diff;
-        }
+
+    #[test]
+    fn test_line_references_in_comments_are_not_cycles() {
+        let test = create_app2(35);
+        test.set_normalized_content("1 // see &[2]\n3 * &[1]");
+        test.assert_results(&["1", "3"][..]);
     }
-    // result panel width has a minimum required width
-    let result_panel_w = client_width as isize - result_gutter_x - RIGHT_GUTTER_WIDTH as isize;
-    let result_gutter_x = (client_width as isize - result_panel_w - RIGHT_GUTTER_WIDTH as isize)
-        .max(MIN_RESULT_PANEL_WIDTH as isize) as usize;
-    gr.set_result_gutter_x(client_width, result_gutter_x);
-}
 
-fn default_result_gutter_x(client_width: usize) -> usize {
-    (client_width * (100 - DEFAULT_RESULT_PANEL_WIDTH_PERCENT) / 100)
-        .max(LEFT_GUTTER_MIN_WIDTH + SCROLLBAR_WIDTH)
-}
+    #[test]
+    fn test_result_formats_are_persisted_in_the_normalized_content() {
+        let test = create_app2(35);
+        test.paste("255\n10\n255\n&[1]");
+        test.mut_app().set_result_format(0, ResultFormat::Hex);
+        test.mut_app().set_result_format(2, ResultFormat::Bin);
+        let content = test.app().get_line_ref_normalized_content();
+        assert_eq!(content, "255\n10\n255\n&[1]\n#result_formats:0=hex,2=bin\n");
 
-#[cfg(test)]
-mod main_tests {
+        let test = create_app2(35);
+        test.set_normalized_content(&content);
+        assert_eq!(test.get_editor_content(), "255\n10\n255\n&[1]");
+        test.assert_results(&["FF", "10", "11111111", "255"][..]);
+        assert_eq!(test.app().get_line_ref_normalized_content(), content);
 
-    use super::*;
+        // the frontend trims the end of the content
+        let test = create_app2(35);
+        test.set_normalized_content(content.trim_end());
+        assert_eq!(test.get_editor_content(), "255\n10\n255\n&[1]");
+        assert_eq!(test.app().get_line_ref_normalized_content(), content);
 
-    const fn result_panel_w(client_width: usize) -> usize {
-        client_width * (100 - DEFAULT_RESULT_PANEL_WIDTH_PERCENT) / 100
-    }
+        // without non-decimal formats, there is no extra line
+        let test = create_app2(35);
+        test.set_normalized_content("1\n2");
+        assert_eq!(test.app().get_line_ref_normalized_content(), "1\n2\n");
 
-    fn pulsing_ref_rect<'a>(x: usize, y: usize, w: usize, h: usize) -> OutputMessage<'a> {
-        OutputMessage::PulsingRectangle {
-            x,
-            y: canvas_y(y as isize),
-            w,
-            h,
-            start_color: REFERENCE_PULSE_PULSE_START_COLOR,
-            end_color: 0x00FF7F_00,
-            animation_time: Duration::from_millis(1000),
-        }
+        // a real line which looks like the formats line survives the round trip
+        let test = create_app2(35);
+        test.paste("255\n#result_formats:0=hex");
+        let content = test.app().get_line_ref_normalized_content();
+        assert_eq!(content, "255\n#result_formats:0=hex\n#result_formats:\n");
+        let test = create_app2(35);
+        test.set_normalized_content(&content);
+        assert_eq!(test.get_editor_content(), "255\n#result_formats:0=hex");
+        assert_eq!(
+            test.app().editor_content.get_data(0).result_format,
+            ResultFormat::Dec
+        );
     }
 
-    fn pulsing_result_rect<'a>(x: usize, y: usize, w: usize, h: usize) -> OutputMessage<'a> {
-        OutputMessage::PulsingRectangle {
-            x,
-            y: canvas_y(y as isize),
-            w,
-            h,
-            start_color: CHANGE_RESULT_PULSE_START_COLOR,
-            end_color: CHANGE_RESULT_PULSE_END_COLOR,
-            animation_time: Duration::from_millis(1000),
-        }
+    #[test]
+    fn test_empty_right_gutter_min_len() {
+        let test = create_app2(35);
+        test.set_normalized_content("");
+        assert_eq!(test.get_render_data().result_gutter_x, result_panel_w(120));
     }
 
-    fn pulsing_changed_content_rect<'a>(
-        x: usize,
-        y: usize,
-        w: usize,
-        h: usize,
-    ) -> OutputMessage<'a> {
-        OutputMessage::PulsingRectangle {
-            x,
-            y: canvas_y(y as isize),
-            w,
-            h,
-            start_color: CHANGE_RESULT_PULSE_START_COLOR,
-            end_color: CHANGE_RESULT_PULSE_END_COLOR,
-            animation_time: Duration::from_millis(2000),
-        }
-    }
+    mod scrollbar_tests {
+        use super::super::*;
+        use super::*;
 
-    fn assert_contains(
-        render_bucket: &[OutputMessage],
-        expected_count: usize,
-        expected_command: OutputMessage,
-    ) {
-        let mut count = 0;
-        for command in render_bucket {
-            if *command == expected_command {
-                count += 1;
+        #[test]
+        fn test_scrolling_by_single_click_in_scrollbar() {
+            let test = create_app2(30);
+            test.repeated_paste("1\n", 60);
+            test.input(EditorInputEvent::PageUp, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 0);
+
+            for i in 0..4 {
+                let mouse_x = test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH;
+                test.click(mouse_x, 20 + i);
+                assert_eq!(test.get_render_data().scroll_y, i as usize);
+                test.handle_mouse_up();
+                assert_eq!(test.get_render_data().scroll_y, 1 + i as usize);
+            }
+            for i in 0..3 {
+                let mouse_x = test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH;
+                test.click(mouse_x, 0);
+                assert_eq!(test.get_render_data().scroll_y, 4 - i as usize);
+                test.handle_mouse_up();
+                assert_eq!(test.get_render_data().scroll_y, 3 - i as usize);
             }
         }
-        assert_eq!(
-            count, expected_count,
-            "Found {} times, expected {}.\n{:?}\nin\n{:?}",
-            count, expected_count, expected_command, render_bucket
-        );
-    }
 
-    struct BorrowCheckerFighter {
-        app_ptr: u64,
-        units_ptr: u64,
-        render_bucket_ptr: u64,
-        tokens_ptr: u64,
-        results_ptr: u64,
-        vars_ptr: u64,
-        editor_objects_ptr: u64,
-        allocator: u64,
-    }
+        #[test]
+        fn test_scrollbar_is_highlighted_on_mouse_hover() {
+            let test = create_app2(30);
+            test.repeated_paste("1\n", 60);
+            test.input(EditorInputEvent::PageUp, InputModifiers::none());
 
-    #[allow(dead_code)]
-    impl BorrowCheckerFighter {
-        fn mut_app<'a>(&self) -> &'a mut NoteCalcApp {
-            unsafe { &mut *(self.app_ptr as *mut NoteCalcApp) }
+            let result_gutter_x = test.get_render_data().result_gutter_x;
+            assert_eq!(test.app().mouse_hover_type, MouseHoverType::Normal);
+            let commands = &test.render_bucket().custom_commands[Layer::Text as usize];
+            assert_contains(commands, 0, OutputMessage::SetColor(SCROLLBAR_HOVER_COLOR));
+            assert_contains(commands, 1, OutputMessage::SetColor(SCROLLBAR_NORMAL_COLOR));
+            assert_contains(
+                commands,
+                1,
+                OutputMessage::RenderRectangle {
+                    x: result_gutter_x - SCROLLBAR_WIDTH,
+                    y: canvas_y(0),
+                    w: SCROLLBAR_WIDTH,
+                    h: 1,
+                },
+            );
+
+            test.handle_mouse_move(result_gutter_x - SCROLLBAR_WIDTH, 0);
+            assert_eq!(test.app().mouse_hover_type, MouseHoverType::Scrollbar);
+            assert_contains(commands, 1, OutputMessage::SetColor(SCROLLBAR_HOVER_COLOR));
+            assert_contains(commands, 0, OutputMessage::SetColor(SCROLLBAR_NORMAL_COLOR));
+            assert_contains(
+                commands,
+                1,
+                OutputMessage::RenderRectangle {
+                    x: result_gutter_x - SCROLLBAR_WIDTH,
+                    y: canvas_y(0),
+                    w: SCROLLBAR_WIDTH,
+                    h: 1,
+                },
+            );
+
+            test.handle_mouse_move(result_gutter_x, 0);
+            assert_eq!(test.app().mouse_hover_type, MouseHoverType::RightGutter);
+            assert_contains(commands, 0, OutputMessage::SetColor(SCROLLBAR_HOVER_COLOR));
+            assert_contains(commands, 1, OutputMessage::SetColor(SCROLLBAR_NORMAL_COLOR));
+            assert_contains(
+                commands,
+                1,
+                OutputMessage::RenderRectangle {
+                    x: result_gutter_x - SCROLLBAR_WIDTH,
+                    y: canvas_y(0),
+                    w: SCROLLBAR_WIDTH,
+                    h: 1,
+                },
+            );
         }
 
-        fn app<'a>(&self) -> &'a NoteCalcApp {
-            unsafe { &*(self.app_ptr as *const NoteCalcApp) }
+        #[test]
+        fn stepping_down_to_unrendered_line_scrolls_down_the_screen() {
+            let test = create_app2(35);
+            test.repeated_paste("1\n2\n3\n4\n5\n6\n7\n8\n9\n0", 6);
+            assert_eq!(test.get_render_data().scroll_y, 20);
+            test.input(EditorInputEvent::PageUp, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 0);
+            test.input(EditorInputEvent::PageDown, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 20);
+        }
+
+        #[test]
+        fn test_scrolling_by_keyboard() {
+            let test = create_app2(35);
+            test.paste(
+                "0
+1
+2
+[4;5;6;7]
+9
+10
+11
+12
+13
+14
+15
+16
+17
+18
+19
+20
+21
+22
+23
+24
+25
+26
+27
+28
+29
+30
+31
+32
+33
+34
+#
+1
+2
+3
+4
+5
+6
+7
+8
+10",
+            );
+            test.set_cursor_row_col(34, 0);
+            test.render();
+            test.input(EditorInputEvent::PageUp, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 0);
+            // in this setup (35 canvas height) only 30 line is visible, so the client
+            // has to press DOWN 29 times
+            let matrix_height = 6;
+            for _ in 0..(35 - matrix_height) {
+                test.input(EditorInputEvent::Down, InputModifiers::none());
+            }
+            assert_eq!(test.get_render_data().scroll_y, 0);
+            for i in 0..3 {
+                test.input(EditorInputEvent::Down, InputModifiers::none());
+                test.render();
+                assert_eq!(test.get_render_data().scroll_y, 1 + i);
+                assert_eq!(
+                    test.app().render_data.get_render_y(content_y(30 + i)),
+                    Some(canvas_y(34)),
+                );
+            }
+            // This step moves the matrix out of vision, so 6 line will appear instead of it at the bottom
+            test.input(EditorInputEvent::Down, InputModifiers::none());
+            test.render();
+            assert_eq!(test.get_render_data().scroll_y, 4);
+            assert_eq!(
+                test.get_render_data().get_render_y(content_y(33)),
+                Some(canvas_y(29)),
+            );
         }
 
-        fn units<'a>(&self) -> &'a mut Units {
-            unsafe { &mut *(self.units_ptr as *mut Units) }
-        }
+        #[test]
+        fn test_that_pressing_enter_eof_moves_scrollbar_down() {
+            let test = create_app2(35);
+            // editor height is 36 in tests, so create a 35 line text
+            test.repeated_paste("a\n", 35);
+            test.set_cursor_row_col(3, 0);
 
-        fn render_bucket(&self) -> &RenderBuckets {
-            return self.mut_render_bucket();
-        }
+            test.render();
+            assert_ne!(
+                test.get_render_data().get_render_y(content_y(5)),
+                Some(canvas_y(0))
+            );
 
-        fn mut_render_bucket<'a>(&self) -> &'a mut RenderBuckets<'a> {
-            unsafe { &mut *(self.render_bucket_ptr as *mut RenderBuckets) }
+            // removing a line
+            test.input(EditorInputEvent::Backspace, InputModifiers::none());
         }
 
-        fn tokens<'a>(&self) -> &'a AppTokens<'a> {
-            unsafe { &*(self.tokens_ptr as *const AppTokens) }
-        }
+        #[test]
+        fn test_that_scrollbar_stops_at_bottom() {
+            let client_height = 25;
+            let test = create_app2(client_height);
+            test.repeated_paste("1\n", client_height * 2);
+            test.set_cursor_row_col(0, 0);
 
-        fn mut_tokens<'a>(&self) -> &'a mut AppTokens<'a> {
-            unsafe { &mut *(self.tokens_ptr as *mut AppTokens) }
-        }
+            test.render();
 
-        fn mut_results<'a>(&self) -> &'a mut Results {
-            unsafe { &mut *(self.results_ptr as *mut Results) }
-        }
+            test.input(EditorInputEvent::PageDown, InputModifiers::none());
 
-        fn mut_editor_objects<'a>(&self) -> &'a mut EditorObjects {
-            unsafe { &mut *(self.editor_objects_ptr as *mut EditorObjects) }
+            assert_eq!(test.get_render_data().scroll_y, 26);
         }
 
-        fn editor_objects<'a>(&self) -> &'a EditorObjects {
-            unsafe { &*(self.editor_objects_ptr as *const EditorObjects) }
-        }
+        #[test]
+        fn test_that_scrollbar_stops_at_bottom2() {
+            let client_height = 36;
+            let test = create_app2(client_height);
+            test.paste("");
+            test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
+            test.input(EditorInputEvent::Del, InputModifiers::none());
 
-        fn mut_vars<'a>(&self) -> &'a mut [Option<Variable>] {
-            unsafe {
-                &mut (&mut *(self.vars_ptr as *mut [Option<Variable>; MAX_LINE_COUNT + 1]))[..]
+            for _ in 0..MAX_LINE_COUNT + 40 {
+                test.input(EditorInputEvent::Enter, InputModifiers::none());
             }
+
+            test.input(EditorInputEvent::PageUp, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 0);
+            test.input(EditorInputEvent::PageDown, InputModifiers::none());
+            assert_eq!(
+                test.get_render_data().scroll_y,
+                MAX_LINE_COUNT + 41 - client_height
+            );
         }
 
-        fn allocator<'a>(&self) -> &'a Bump {
-            unsafe { &*(self.allocator as *const Bump) }
+        #[test]
+        fn test_inserting_long_text_scrolls_down() {
+            let test = create_app2(32);
+            test.paste("a");
+            test.repeated_paste("asd\n", 40);
+            assert_eq!(test.get_render_data().scroll_y, 9);
         }
 
-        fn render(&self) {
-            self.mut_app()
-                .generate_render_commands_and_fill_editor_objs(
-                    self.units(),
-                    self.mut_render_bucket(),
-                    self.allocator(),
-                    self.mut_tokens(),
-                    self.mut_results(),
-                    self.mut_vars(),
-                    self.mut_editor_objects(),
-                    BitFlag128::empty(),
-                );
+        #[test]
+        fn test_that_no_overscrolling() {
+            let test = create_app2(35);
+            test.paste("1\n2\n3\n");
+            test.render();
+
+            test.handle_wheel(1);
+            assert_eq!(0, test.get_render_data().scroll_y);
         }
 
-        fn paste(&self, str: &str) {
-            self.mut_app().handle_paste(
-                str.to_owned(),
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_editor_objects(),
-                self.mut_render_bucket(),
+        #[test]
+        fn tall_rows_are_considered_in_scrollbar_height_calc() {
+            const CANVAS_HEIGHT: usize = 25;
+            let test = create_app2(CANVAS_HEIGHT);
+            test.repeated_paste("1\n2\n\n[1;2;3;4]", 5);
+            test.render();
+            assert_contains(
+                &test.render_bucket().custom_commands[Layer::Text as usize],
+                1,
+                OutputMessage::RenderRectangle {
+                    x: result_panel_w(120) - SCROLLBAR_WIDTH,
+                    y: canvas_y(0),
+                    w: 1,
+                    h: 19,
+                },
             );
         }
 
-        fn assert_no_highlighting_rectangle(&self) {
-            let render_buckets = &self.render_bucket().custom_commands[Layer::AboveText as usize];
-            for i in 0..9 {
-                assert_contains(
-                    render_buckets,
-                    0,
-                    OutputMessage::SetColor(ACTIVE_LINE_REF_HIGHLIGHT_COLORS[i] << 8 | 0x33),
-                );
-            }
-        }
+        #[test]
+        fn test_no_scrolling_in_empty_document() {
+            let test = create_app2(25);
+            test.paste("1");
 
-        fn assert_results(&self, expected_results: &[&str]) {
-            let mut i = 0;
-            let mut ok_chars = Vec::with_capacity(32);
-            let expected_len = expected_results.iter().map(|it| it.len()).sum();
-            unsafe {
-                for (result_index, expected_result) in expected_results.iter().enumerate() {
-                    for ch in expected_result.bytes() {
-                        assert_eq!(
-                            RESULT_BUFFER[i] as char,
-                            ch as char,
-                            "{}. result, at char {}: {:?}, result_buffer: {:?}",
-                            result_index,
-                            i,
-                            String::from_utf8(ok_chars).unwrap(),
-                            &RESULT_BUFFER[0..expected_len]
-                                .iter()
-                                .map(|it| *it as char)
-                                .collect::<Vec<char>>()
-                        );
-                        ok_chars.push(ch);
-                        i += 1;
-                    }
-                    ok_chars.push(',' as u8);
-                    ok_chars.push(' ' as u8);
-                }
-                assert_eq!(
-                    RESULT_BUFFER[i], 0,
-                    "more results than expected at char {}.",
-                    i
-                );
-            }
-        }
+            test.render();
 
-        fn assert_contains_operator<F>(&self, expected_count: usize, expected_command: F)
-        where
-            F: Fn(&RenderUtf8TextMsg) -> bool,
-        {
-            let mut count = 0;
-            let operators = &self.render_bucket().operators;
-            for op in operators {
-                if expected_command(op) {
-                    count += 1;
-                }
-            }
-            assert_eq!(
-                count, expected_count,
-                "Found {} times.\nExpected: {}\nin\n{:?}",
-                count, expected_count, operators
-            );
-        }
+            test.handle_wheel(1);
 
-        fn assert_contains_line_ref_result<F>(&self, expected_count: usize, expected_command: F)
-        where
-            F: Fn(&RenderStringMsg) -> bool,
-        {
-            let mut count = 0;
-            let operators = &self.render_bucket().line_ref_results;
-            for op in operators {
-                if expected_command(op) {
-                    count += 1;
-                }
-            }
-            assert_eq!(
-                count, expected_count,
-                "Found {} times.\nExpected: {}\nin\n{:?}",
-                count, expected_count, operators
-            );
-        }
+            test.render();
 
-        fn assert_contains_result<F>(&self, expected_count: usize, expected_command: F)
-        where
-            F: Fn(&RenderAsciiTextMsg) -> bool,
-        {
-            let mut count = 0;
-            let commands = &self.render_bucket().ascii_texts;
-            for cmd in commands {
-                if expected_command(cmd) {
-                    count += 1;
-                }
-            }
-            assert_eq!(
-                count, expected_count,
-                "Found {} times.\nExpected: {}\nin\n{:?}",
-                count, expected_count, commands
-            );
+            assert_eq!(0, test.get_render_data().scroll_y);
         }
 
-        fn assert_contains_text<F>(&self, expected_count: usize, expected_command: F)
-        where
-            F: Fn(&RenderUtf8TextMsg) -> bool,
-        {
-            let mut count = 0;
-            let operators = &self.render_bucket().utf8_texts;
-            for op in operators {
-                if expected_command(op) {
-                    count += 1;
-                }
-            }
-            assert_eq!(
-                count, expected_count,
-                "Found {} times.\nExpected: {}\nin\n{:?}",
-                count, expected_count, operators
-            );
+        #[test]
+        fn test_that_no_overscrolling2() {
+            let test = create_app2(35);
+            test.repeated_paste("aaaaaaaaaaaa\n", 35);
+            test.render();
+
+            test.handle_wheel(1);
+            assert_eq!(1, test.get_render_data().scroll_y);
+            test.handle_wheel(1);
+            assert_eq!(1, test.get_render_data().scroll_y);
         }
 
-        fn assert_contains_variable<F>(&self, expected_count: usize, expected_command: F)
-        where
-            F: Fn(&RenderUtf8TextMsg) -> bool,
-        {
-            let mut count = 0;
-            let operators = &self.render_bucket().variable;
-            for op in operators {
-                if expected_command(op) {
-                    count += 1;
-                }
+        #[test]
+        fn test_scrolling_down_on_enter_even() {
+            let test = create_app2(32);
+            test.paste("");
+            test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
+            test.input(EditorInputEvent::Del, InputModifiers::none());
+
+            for _i in 0..31 {
+                test.input(EditorInputEvent::Enter, InputModifiers::none());
             }
-            assert_eq!(
-                count, expected_count,
-                "Found {} times.\nExpected: {}\nin\n{:?}",
-                count, expected_count, operators
-            );
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 1);
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 2);
         }
 
-        fn assert_no_pulsing(&self) {
-            let render_bucket = &self.render_bucket().custom_commands[Layer::AboveText as usize];
-            for command in render_bucket {
-                assert!(
-                    !matches!(command, OutputMessage::PulsingRectangle {..}),
-                    "Pulsing was found but did not expected: {:?}",
-                    command
-                );
+        #[test]
+        fn test_scroll_bug_when_scrolling_upwrads_from_bottom() {
+            let test = create_app2(32);
+            test.paste("");
+
+            test.input(EditorInputEvent::PageDown, InputModifiers::none());
+            for _i in 0..40 {
+                test.input(EditorInputEvent::Enter, InputModifiers::none());
             }
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            let scroll_y_at_bottom = test.get_render_data().scroll_y;
+            test.handle_wheel(0);
+            assert_eq!(test.get_render_data().scroll_y, scroll_y_at_bottom - 1);
+            test.handle_wheel(0);
+            assert_eq!(test.get_render_data().scroll_y, scroll_y_at_bottom - 2);
         }
+    }
 
-        fn set_normalized_content(&self, str: &str) {
-            self.mut_app().set_normalized_content(
-                str,
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_editor_objects(),
-                self.mut_render_bucket(),
-            );
-        }
+    mod right_gutter_tests {
+        use super::super::*;
+        use super::*;
 
-        fn repeated_paste(&self, str: &str, times: usize) {
-            self.mut_app().handle_paste(
-                str.repeat(times),
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_editor_objects(),
-                self.mut_render_bucket(),
-            );
-        }
+        #[test]
+        fn right_gutter_is_moving_if_there_would_be_enough_space_for_result() {
+            let test = create_app3(40, 35);
+            test.paste("1\n");
+            assert_eq!(test.get_render_data().result_gutter_x, result_panel_w(40));
 
-        fn click(&self, x: usize, y: isize) {
-            self.mut_app().handle_click(
-                x,
-                canvas_y(y),
-                self.mut_editor_objects(),
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_render_bucket(),
+            test.paste("999 999 999 999");
+            assert_eq!(
+                test.get_render_data().result_gutter_x,
+                40 - ("999 999 999 999".len() + RIGHT_GUTTER_WIDTH)
             );
         }
 
-        fn handle_resize(&self, new_client_width: usize) {
-            self.mut_app().handle_resize(
-                new_client_width,
-                self.mut_editor_objects(),
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_render_bucket(),
-            );
-        }
+        #[test]
+        fn right_gutter_is_moving_if_there_would_be_enough_space_for_binary_result() {
+            let test = create_app3(40, 35);
+            test.paste("9999");
+            assert_eq!(test.get_render_data().result_gutter_x, result_panel_w(40),);
 
-        fn handle_wheel(&self, dir: usize) {
-            self.mut_app().handle_wheel(
-                dir,
-                self.mut_editor_objects(),
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_render_bucket(),
+            test.input(EditorInputEvent::Left, InputModifiers::alt());
+            assert_eq!(
+                test.get_render_data().result_gutter_x,
+                40 - ("100111 00001111".len() + RIGHT_GUTTER_WIDTH)
             );
         }
 
-        fn handle_drag(&self, x: usize, y: isize) {
-            self.mut_app().handle_drag(
-                x,
-                canvas_y(y),
-                self.mut_editor_objects(),
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_render_bucket(),
-            );
+        #[test]
+        fn right_gutter_calc_panic() {
+            let test = create_app3(176, 35);
+            test.paste("ok");
         }
 
-        fn handle_mouse_move(&self, x: usize, y: isize) {
-            self.mut_app().handle_mouse_move(
-                x,
-                canvas_y(y),
-                self.mut_editor_objects(),
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_render_bucket(),
-            );
-        }
+        #[test]
+        fn test_resize_keeps_result_width() {
+            let test = create_app3(60, 35);
+            test.set_normalized_content("80kg\n190cm\n0.0016\n0.128 kg");
+            let longest_result_len = 11;
+            let check_longest_line_did_not_change = || {
+                assert_eq!(
+                    test.get_render_data().longest_visible_result_len,
+                    longest_result_len
+                );
+            };
+            let asset_result_x_pos = |expected: usize| {
+                assert_eq!(test.get_render_data().result_gutter_x, expected);
+            };
 
-        fn alt_key_released(&self) {
-            self.mut_app().alt_key_released(
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_editor_objects(),
-                self.mut_render_bucket(),
-            );
-        }
+            check_longest_line_did_not_change();
+            asset_result_x_pos(default_result_gutter_x(60));
 
-        fn handle_time(&self, tick: u32) {
-            self.mut_app().handle_time(
-                tick,
-                self.units(),
-                self.allocator(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_editor_objects(),
-                self.mut_render_bucket(),
-            );
-        }
+            test.handle_resize(50);
+            asset_result_x_pos(default_result_gutter_x(50));
+            check_longest_line_did_not_change();
 
-        fn input(&self, event: EditorInputEvent, modif: InputModifiers) {
-            self.mut_app().handle_input(
-                event,
-                modif,
-                self.allocator(),
-                self.units(),
-                self.mut_tokens(),
-                self.mut_results(),
-                self.mut_vars(),
-                self.mut_editor_objects(),
-                self.mut_render_bucket(),
-            );
-        }
+            test.handle_resize(60);
+            check_longest_line_did_not_change();
+            asset_result_x_pos(default_result_gutter_x(60));
 
-        fn handle_mouse_up(&self) {
-            self.mut_app().handle_mouse_up();
-        }
+            test.handle_resize(100);
+            check_longest_line_did_not_change();
+            asset_result_x_pos(default_result_gutter_x(100));
 
-        fn get_render_data(&self) -> GlobalRenderData {
-            return self.mut_app().render_data.clone();
-        }
+            // there is no enough space for the panel,
+            // so it becomes bigger than 30%
+            test.handle_resize(40);
+            check_longest_line_did_not_change();
+            asset_result_x_pos(27);
 
-        fn get_editor_content(&self) -> String {
-            return self.mut_app().editor_content.get_content();
-        }
+            test.handle_resize(30);
+            check_longest_line_did_not_change();
+            asset_result_x_pos(17);
 
-        fn get_cursor_pos(&self) -> Pos {
-            return self.mut_app().editor.get_selection().get_cursor_pos();
-        }
+            test.handle_resize(20);
+            asset_result_x_pos(7);
 
-        fn get_selection(&self) -> Selection {
-            return self.mut_app().editor.get_selection();
+            // too small
+            test.handle_resize(10);
+            check_longest_line_did_not_change();
+            asset_result_x_pos(default_result_gutter_x(10));
         }
 
-        fn set_selection(&self, selection: Selection) {
-            let app = &mut self.mut_app();
-            app.editor.set_selection_save_col(selection);
+        #[test]
+        fn right_gutter_is_immediately_rendered_at_its_changed_position_after_scrolling() {
+            let test = create_app3(76, 10);
+            test.repeated_paste("1\n", 10);
+            test.paste("111111111111111111111");
+            test.input(EditorInputEvent::PageUp, InputModifiers::none());
+            assert_eq!(
+                test.get_render_data().result_gutter_x,
+                default_result_gutter_x(76)
+            );
+
+            test.handle_wheel(1);
+
+            let expected_result_pos = 76 - ("111 111 111 111 111 111 111".len());
+            test.assert_contains_result(1, |cmd| {
+                cmd.text == "111 111 111 111 111 111 111".as_bytes()
+                    && cmd.row == canvas_y(9)
+                    && cmd.column == expected_result_pos
+            })
         }
 
-        fn set_cursor_row_col(&self, row: usize, col: usize) {
-            self.set_selection(Selection::single_r_c(row, col));
-        }
-    }
+        #[test]
+        fn right_gutter_is_immediately_rendered_at_its_changed_position_after_input() {
+            let test = create_app3(76, 10);
+            test.repeated_paste("1\n", 10);
+            test.paste("111111111111111111111");
+            test.input(EditorInputEvent::PageUp, InputModifiers::none());
+            assert_eq!(
+                test.get_render_data().result_gutter_x,
+                default_result_gutter_x(76)
+            );
 
-    fn create_app3<'a>(client_width: usize, client_height: usize) -> BorrowCheckerFighter {
-        for b in unsafe { &mut RESULT_BUFFER } {
-            *b = 0;
-        }
-        let app = NoteCalcApp::new(client_width, client_height);
-        let editor_objects = EditorObjects::new();
-        let tokens = AppTokens::new();
-        let results = Results::new();
-        let vars = create_vars();
-        fn to_box_ptr<T>(t: T) -> u64 {
-            let ptr = Box::into_raw(Box::new(t)) as u64;
-            ptr
-        }
-        return BorrowCheckerFighter {
-            app_ptr: to_box_ptr(app),
-            units_ptr: to_box_ptr(Units::new()),
-            render_bucket_ptr: to_box_ptr(RenderBuckets::new()),
-            tokens_ptr: to_box_ptr(tokens),
-            results_ptr: to_box_ptr(results),
-            vars_ptr: to_box_ptr(vars),
-            editor_objects_ptr: to_box_ptr(editor_objects),
-            allocator: to_box_ptr(Bump::with_capacity(MAX_LINE_COUNT * 120)),
-        };
-    }
+            test.input(EditorInputEvent::PageDown, InputModifiers::none());
 
-    fn create_app2<'a>(client_height: usize) -> BorrowCheckerFighter {
-        create_app3(120, client_height)
+            let expected_result_pos = 76 - ("111 111 111 111 111 111 111".len());
+            test.assert_contains_result(1, |cmd| {
+                cmd.text == "111 111 111 111 111 111 111".as_bytes()
+                    && cmd.row == canvas_y(9)
+                    && cmd.column == expected_result_pos
+            })
+        }
     }
 
     #[test]
-    fn bug1() {
+    fn test_that_alignment_is_considered_for_longest_result_len() {
         let test = create_app2(35);
-        test.paste("[123, 2, 3; 4567981, 5, 6] * [1; 2; 3;4]");
-
-        test.set_cursor_row_col(0, 33);
-        test.input(EditorInputEvent::Right, InputModifiers::alt());
-        test.render();
+        test.set_normalized_content("80kg\n190cm\n0.0016\n0.128 kg");
+        assert_eq!(test.get_render_data().longest_visible_result_len, 11);
     }
 
     #[test]
-    fn bug2() {
+    fn test_scroll_y_reset() {
         let test = create_app2(35);
-        test.paste("[123, 2, 3; 4567981, 5, 6] * [1; 2; 3;4]");
-        test.set_cursor_row_col(0, 1);
-
-        test.input(EditorInputEvent::Right, InputModifiers::alt());
-        test.render();
-        test.input(EditorInputEvent::Down, InputModifiers::none());
-        test.render();
+        test.mut_app().render_data.scroll_y = 1;
+        test.set_normalized_content("1111\n2222\n14 * &[2]&[2]&[2]\n");
+        assert_eq!(0, test.get_render_data().scroll_y);
     }
 
     #[test]
-    fn bug3() {
+    fn test_tab_change_clears_variables() {
         let test = create_app2(35);
-        test.paste(
-            "1\n\
-                    2+",
+        test.set_normalized_content(
+            "source: https://rippedbody.com/how-to-calculate-leangains-macros/
+
+weight = 80 kg
+height = 190 cm
+age = 30
+
+# Step 1: Calculate your  (Basal Metabolic Rate) (BMR)
+men BMR = 66 + (13.7 * weight/1kg) + (5 * height/1cm) - (6.8 * age)
+
+'STEP 2. FIND YOUR TDEE BY ADJUSTING FOR ACTIVITY
+Activity
+' Sedentary (little or no exercise) [BMR x 1.15]
+' Mostly sedentary (office work), plus 3–6 days of weight lifting [BMR x 1.35]
+' Lightly active, plus 3–6 days of weight lifting [BMR x 1.55]
+' Highly active, plus 3–6 days of weight lifting [BMR x 1.75]
+TDEE = (men BMR * 1.35)
+
+'STEP 3. ADJUST CALORIE INTAKE BASED ON YOUR GOAL
+Fat loss
+    target weekly fat loss rate = 0.5%
+    TDEE - ((weight/1kg) * target weekly fat loss rate * 1100)kcal
+Muscle gain
+    monthly rates of weight gain = 1%
+    TDEE + (weight/1kg * monthly rates of weight gain * 330)kcal
+
+Protein intake
+    1.6 g/kg
+    2.2 g/kg
+    weight * &[27] to g
+    weight * &[28] to g
+Fat intake
+    0.5g/kg or at least 30 %
+    1g/kg minimum
+    fat calory = 9
+    &[24]",
         );
-        test.set_cursor_row_col(1, 2);
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
+
         test.render();
-    }
 
-    #[test]
-    fn test_that_variable_name_is_inserted_when_referenced_a_var_line() {
-        let test = create_app2(35);
-        test.paste(
-            "var_name = 1\n\
-                    2+",
+        test.set_normalized_content(
+            "Valaki elment Horvátba 12000 Ftért
+    3 éjszakát töltött ott
+    &[1]*&[2]
+    utána vacsorázott egyet 5000ért
+    
+    
+    999 + 1
+    22222
+    3
+    4 + 2
+    2
+    &[10]
+    722
+    alma = 3
+    alma * 2
+    alma * &[13] + &[12]
+    &[13] km
+    2222222222222222222722.22222222 km
+    
+    [1;0] * [1,2]
+    1 + 2
+    2
+    
+    
+    2
+    23
+    human brain: 10^16 op/s
+    so far000 humans lived
+    avg. human lifespan is 50 years
+    total human brain activity is &[27] * &[28] * (&[29]/1s)",
         );
-        test.set_cursor_row_col(1, 2);
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
+
         test.render();
-        assert_eq!(
-            "var_name = 1\n\
-                 2+var_name",
-            test.get_editor_content()
-        );
     }
 
     #[test]
-    fn bug4() {
+    fn test_panic_on_pressing_enter() {
         let test = create_app2(35);
-        test.paste(
-            "1\n\
-                    ",
-        );
-        test.render();
-        test.set_cursor_row_col(1, 0);
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
-        test.render();
-        assert_eq!(
-            "1\n\
-                 &[1]",
-            test.get_editor_content()
+        test.set_normalized_content(
+            "source: https://rippedbody.com/how-to-calculate-leangains-macros/
+
+weight = 80 kg
+height = 190 cm
+age = 30
+
+# Step 1: Calculate your  (Basal Metabolic Rate) (BMR)
+men BMR = 66 + (13.7 * weight/1kg) + (5 * height/1cm) - (6.8 * age)
+
+'STEP 2. FIND YOUR TDEE BY ADJUSTING FOR ACTIVITY
+Activity
+' Sedentary (little or no exercise) [BMR x 1.15]
+' Mostly sedentary (office work), plus 3–6 days of weight lifting [BMR x 1.35]
+' Lightly active, plus 3–6 days of weight lifting [BMR x 1.55]
+' Highly active, plus 3–6 days of weight lifting [BMR x 1.75]
+TDEE = (men BMR * 1.35)
+
+'STEP 3. ADJUST CALORIE INTAKE BASED ON YOUR GOAL
+Fat loss
+    target weekly fat loss rate = 0.5%
+    (TDEE - ((weight/1kg) * target weekly fat loss rate * 1100))kcal
+Muscle gain
+    monthly rates of weight gain = 1%
+    (TDEE + (weight/1kg * monthly rates of weight gain * 330))kcal
+
+Protein intake
+    1.6 g/kg
+    2.2 g/kg
+    weight * &[27] to g
+    weight * &[28] to g
+Fat intake
+    0.5g/kg or at least 30 %
+    1g/kg minimum
+    fat calory = 9
+    &[24]",
         );
-    }
 
-    #[test]
-    fn bug5() {
-        let test = create_app2(35);
-        test.paste("123\na ");
+        fn assert_var(vars: &Variables, name: &str, defined_at: usize) {
+            let var = vars[defined_at].as_ref().unwrap();
+            assert!(var.value.is_ok(), "{}", name);
+            assert_eq!(name.len(), var.name.len(), "{}", name);
+            for (a, b) in name.chars().zip(var.name.iter()) {
+                assert_eq!(a, *b, "{}", name);
+            }
+        }
+        {
+            let vars = &test.mut_vars();
+            assert_var(&vars[..], "weight", 2);
+            assert_var(&vars[..], "height", 3);
+            assert_var(&vars[..], "age", 4);
+            assert_var(&vars[..], "men BMR", 7);
+            assert_var(&vars[..], "TDEE", 15);
+            assert_var(&vars[..], "target weekly fat loss rate", 19);
+            assert_var(&vars[..], "&[21]", 20);
+            assert_var(&vars[..], "monthly rates of weight gain", 22);
+            assert_var(&vars[..], "&[24]", 23);
+            assert_var(&vars[..], "&[27]", 26);
+            assert_var(&vars[..], "&[28]", 27);
+            assert_var(&vars[..], "&[29]", 28);
+            assert_var(&vars[..], "&[30]", 29);
+            assert_var(&vars[..], "&[32]", 31);
+            assert_var(&vars[..], "&[33]", 32);
+            assert_var(&vars[..], "fat calory", 33);
+            assert_var(&vars[..], "&[35]", 34);
+        }
+
+        test.set_cursor_row_col(6, 33);
 
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
-        assert_eq!(
-            3,
-            test.tokens()[content_y(1)].as_ref().unwrap().tokens.len()
-        );
-    }
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.render();
 
-    #[test]
-    fn it_is_not_allowed_to_ref_lines_below() {
-        let test = create_app2(35);
-        test.paste(
-            "1\n\
-                    2+\n3\n4",
-        );
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
         test.render();
-        test.set_cursor_row_col(1, 2);
-        test.input(EditorInputEvent::Down, InputModifiers::alt());
-        test.alt_key_released();
+
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
         test.render();
-        assert_eq!(
-            "1\n\
-                    2+\n3\n4",
-            test.get_editor_content()
-        );
+        let vars = &test.mut_vars();
+        assert_var(&vars[..], "weight", 2);
+        assert_var(&vars[..], "height", 3);
+        assert_var(&vars[..], "age", 4);
+        assert_var(&vars[..], "men BMR", 8);
+        assert_var(&vars[..], "TDEE", 16);
+        assert_var(&vars[..], "target weekly fat loss rate", 20);
+        assert_var(&vars[..], "&[21]", 21);
+        assert_var(&vars[..], "monthly rates of weight gain", 23);
+        assert_var(&vars[..], "&[24]", 24);
+        assert_var(&vars[..], "&[27]", 27);
+        assert_var(&vars[..], "&[28]", 28);
+        assert_var(&vars[..], "&[29]", 29);
+        assert_var(&vars[..], "&[30]", 30);
+        assert_var(&vars[..], "&[32]", 32);
+        assert_var(&vars[..], "&[33]", 33);
+        assert_var(&vars[..], "fat calory", 34);
+        assert_var(&vars[..], "&[35]", 35);
     }
 
     #[test]
-    fn it_is_not_allowed_to_ref_lines_below2() {
+    fn no_memory_deallocation_bug_in_line_selection() {
         let test = create_app2(35);
-        test.paste(
-            "1\n\
-                    2+\n3\n4",
-        );
+        test.paste("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n");
+        test.set_cursor_row_col(12, 2);
         test.render();
-        test.set_cursor_row_col(1, 2);
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.input(EditorInputEvent::Down, InputModifiers::alt());
-        test.alt_key_released();
+        test.input(EditorInputEvent::Up, InputModifiers::shift());
         test.render();
-        assert_eq!(
-            "1\n\
-                    2+&[1]\n3\n4",
-            test.get_editor_content()
-        );
     }
 
     #[test]
-    fn remove_matrix_backspace() {
+    fn matrix_deletion() {
         let test = create_app2(35);
-        test.paste("abcd [1,2,3;4,5,6]");
+        test.paste(" [1,2,3]");
+        test.set_cursor_row_col(0, 0);
         test.render();
-        test.input(EditorInputEvent::Backspace, InputModifiers::none());
-        assert_eq!("abcd ", test.get_editor_content());
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+        assert_eq!("[1,2,3]", test.get_editor_content());
     }
 
     #[test]
-    fn matrix_step_in_dir() {
-        // from right
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1,2,3;4,5,6]");
-            test.render();
-            test.input(EditorInputEvent::Left, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            assert_eq!("abcd [1,2,1;4,5,6]", test.get_editor_content());
-        }
-        // from left
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1,2,3;4,5,6]");
-            test.set_cursor_row_col(0, 5);
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            assert_eq!("abcd [9,2,3;4,5,6]", test.get_editor_content());
-        }
-        // from below
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1,2,3;4,5,6]\naaaaaaaaaaaaaaaaaa");
-            test.set_cursor_row_col(1, 7);
-            test.render();
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            assert_eq!(
-                "abcd [1,2,3;9,5,6]\naaaaaaaaaaaaaaaaaa",
-                test.get_editor_content()
-            );
-        }
-        // from above
-        {
-            let test = create_app2(35);
-            test.paste("aaaaaaaaaaaaaaaaaa\nabcd [1,2,3;4,5,6]");
-            test.set_cursor_row_col(0, 7);
-            test.render();
-            test.input(EditorInputEvent::Down, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            assert_eq!(
-                "aaaaaaaaaaaaaaaaaa\nabcd [9,2,3;4,5,6]",
-                test.get_editor_content()
-            );
-        }
+    fn matrix_insertion_bug() {
+        let test = create_app2(35);
+        test.paste("[1,2,3]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
+        assert_eq!("a[1,2,3]", test.get_editor_content());
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        assert_eq!("a\n[1,2,3]", test.get_editor_content());
     }
 
     #[test]
-    fn cursor_is_put_after_the_matrix_after_finished_editing() {
+    fn matrix_insertion_bug2() {
         let test = create_app2(35);
-        test.paste("abcd [1,2,3;4,5,6]");
-        test.render();
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.render();
-        test.input(EditorInputEvent::Char('6'), InputModifiers::none());
-        test.render();
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.paste("'[X] nth, sum fv");
         test.render();
-        test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-        assert_eq!(test.get_editor_content(), "abcd [1,2,6;4,5,6]9");
+        test.set_cursor_row_col(0, 0);
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+
+        test.assert_results(&["Err"][..]);
     }
 
     #[test]
-    fn remove_matrix_del() {
+    fn test_err_result_rendering() {
         let test = create_app2(35);
-        test.paste("abcd [1,2,3;4,5,6]");
-        test.set_cursor_row_col(0, 5);
+        test.paste("'[X] nth, sum fv");
         test.render();
+        test.set_cursor_row_col(0, 0);
         test.input(EditorInputEvent::Del, InputModifiers::none());
-        assert_eq!("abcd ", test.get_editor_content());
+
+        match &test.render_bucket().ascii_texts[0] {
+            RenderAsciiTextMsg { text, row, column } => {
+                assert_eq!(text, &[b'E', b'r', b'r']);
+                assert_eq!(*row, canvas_y(0));
+                assert_eq!(*column, result_panel_w(120) + RIGHT_GUTTER_WIDTH);
+            }
+        }
     }
 
     #[test]
-    fn test_that_selected_matrix_content_is_copied_on_ctrl_c() {
+    fn sum_is_nulled_in_new_header_region() {
         let test = create_app2(35);
-        test.paste("abcd [69,2,3;4,5,6]");
-        test.set_cursor_row_col(0, 5);
-        test.render();
-        test.input(EditorInputEvent::Right, InputModifiers::none());
-        test.render();
-        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
-        assert_eq!(
-            test.mut_app()
-                .get_selected_text_and_clear_app_clipboard()
-                .as_ref()
-                .map(|it| it.as_str()),
-            Some("69")
+        test.paste(
+            "3m * 2m
+# new header
+1
+2
+sum
+# new header
+4
+5
+sum",
         );
+        test.assert_results(&["6 m^2", "", "1", "2", "3", "", "4", "5", "9"][..]);
     }
 
     #[test]
-    fn test_insert_matrix_line_ref_panic() {
+    fn sum_is_nulled_after_empty_line() {
         let test = create_app2(35);
-        test.paste("[1,2,3;4,5,6]\n[1;2;3]\n");
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
-        assert_eq!(test.get_render_data().get_rendered_height(content_y(2)), 5);
+        test.paste("1\n2\nsum\n\n4\n5\nsum\n  \n10\nsum");
+        test.assert_results(&["1", "2", "3", "", "4", "5", "9", "", "10", "10"][..]);
     }
 
     #[test]
-    fn test_matrix_rendering_parameters_single_row() {
+    fn no_sum_value_in_case_of_error() {
         let test = create_app2(35);
-        test.paste("[1]");
-        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_x, 0);
-        assert_eq!(
-            test.editor_objects()[content_y(0)][0].rendered_y,
-            canvas_y(0)
+        test.paste(
+            "3m * 2m\n\
+                    4\n\
+                    sum",
         );
-        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_h, 1);
-        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_w, 3);
+        test.assert_results(&["6 m^2", "4", "Err"][..]);
     }
 
     #[test]
-    fn test_matrix_rendering_parameters_multiple_rows() {
+    fn test_ctrl_c() {
         let test = create_app2(35);
-        test.paste("[1;2;3]");
-        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_x, 0);
-        assert_eq!(
-            test.editor_objects()[content_y(0)][0].rendered_y,
-            canvas_y(0)
-        );
-        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_h, 5);
-        assert_eq!(test.editor_objects()[content_y(0)][0].rendered_w, 3);
+        test.paste("aaaaaaaaa");
+        test.render();
+        test.input(EditorInputEvent::Left, InputModifiers::shift());
+        test.input(EditorInputEvent::Left, InputModifiers::shift());
+        test.input(EditorInputEvent::Left, InputModifiers::shift());
+        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
+        assert_eq!("aaa", &test.app().editor.clipboard);
+        assert_eq!(&None, &test.app().clipboard);
     }
 
     #[test]
-    fn test_referencing_matrix_size_correct2() {
+    fn test_ctrl_c_without_selection() {
         let test = create_app2(35);
-        test.paste("[6]\n&[1]");
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        assert_eq!(test.editor_objects()[content_y(1)][0].rendered_h, 1);
+        test.paste("12*3");
+        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
+        assert_eq!(&Some("36".to_owned()), &test.app().clipboard);
+        assert!(test.app().editor.clipboard.is_empty());
     }
 
     #[test]
-    fn test_referencing_matrix_size_correct2_vert_align() {
+    fn test_ctrl_c_without_selection2() {
         let test = create_app2(35);
-        test.paste("[1;2;3]\n[4]\n&[1]  &[2]");
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        let first_line_h = 5;
-        let second_line_half = (5 / 2) + 1;
-        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-        assert_contains(
-            &test.render_bucket().custom_commands[Layer::AboveText as usize],
-            1,
-            pulsing_ref_rect(left_gutter_width + 5, first_line_h + second_line_half, 3, 1),
-        )
+        test.paste("12*3");
+        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
+        assert_eq!(
+            Some("36".to_owned()),
+            test.mut_app().get_selected_text_and_clear_app_clipboard()
+        );
+        assert_eq!(
+            None,
+            test.mut_app().get_selected_text_and_clear_app_clipboard()
+        );
     }
 
     #[test]
-    fn test_referencing_matrix_size_correct() {
+    fn test_ctrl_shift_c_copies_the_result_even_with_selection() {
         let test = create_app2(35);
-        test.paste("[1;2;3]\n&[1]");
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        assert_eq!(test.editor_objects()[content_y(1)][0].rendered_h, 5);
+        test.paste("12*3");
+        test.input(EditorInputEvent::Left, InputModifiers::shift());
+        test.input(EditorInputEvent::Left, InputModifiers::shift());
+        test.input(EditorInputEvent::Char('C'), InputModifiers::ctrl_shift());
+        assert_eq!(&Some("36".to_owned()), &test.app().clipboard);
+        assert!(test.app().editor.clipboard.is_empty());
+        assert_eq!("12*3", test.get_editor_content());
     }
 
     #[test]
-    fn test_moving_inside_a_matrix() {
-        // right to left, cursor at end
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1,2,3;4,5,6]");
-            test.render();
-            test.input(EditorInputEvent::Left, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Left, InputModifiers::none());
-            test.input(EditorInputEvent::Left, InputModifiers::none());
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.render();
-            assert_eq!("abcd [1,9,3;4,5,6]", test.get_editor_content());
-        }
-        // pressing right while there is a selection, just cancels the selection and put the cursor
-        // at the end of it
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1,2,3;4,5,6]");
-            test.set_cursor_row_col(0, 5);
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.render();
-            assert_eq!("abcd [19,2,3;4,5,6]", test.get_editor_content());
-        }
-        // left to right, cursor at start
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1,2,3;4,5,6]");
-            test.set_cursor_row_col(0, 5);
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.render();
-            assert_eq!("abcd [1,2,9;4,5,6]", test.get_editor_content());
-        }
-        // vertical movement down, cursor tries to keep its position
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1111,22,3;44,55555,666]");
-            test.set_cursor_row_col(0, 5);
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.render();
-            // inside the matrix
-            test.input(EditorInputEvent::Down, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.render();
-            assert_eq!("abcd [1111,22,3;9,55555,666]", test.get_editor_content());
-        }
-
-        // vertical movement up, cursor tries to keep its position
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1111,22,3;44,55555,666]");
-            test.set_cursor_row_col(0, 5);
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.render();
-            // inside the matrix
-            test.input(EditorInputEvent::Down, InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.render();
-            assert_eq!("abcd [9,22,3;44,55555,666]", test.get_editor_content());
-        }
+    fn test_ctrl_shift_c_without_result_copies_nothing() {
+        let test = create_app2(35);
+        test.paste("some text");
+        test.input(EditorInputEvent::Char('C'), InputModifiers::ctrl_shift());
+        assert_eq!(&None, &test.app().clipboard);
+        assert!(test.app().editor.clipboard.is_empty());
     }
 
     #[test]
-    fn test_moving_inside_a_matrix_with_tab() {
+    fn test_changing_output_style_for_selected_rows() {
         let test = create_app2(35);
-        test.paste("[1,2,3;4,5,6]");
+        test.paste(
+            "2\n\
+                        4\n\
+                        5",
+        );
         test.render();
-        test.input(EditorInputEvent::Home, InputModifiers::none());
-        test.input(EditorInputEvent::Right, InputModifiers::none());
+        test.input(EditorInputEvent::Up, InputModifiers::shift());
+        test.input(EditorInputEvent::Up, InputModifiers::shift());
+        test.input(EditorInputEvent::Left, InputModifiers::alt());
 
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Char('7'), InputModifiers::none());
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Char('8'), InputModifiers::none());
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Char('0'), InputModifiers::none());
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Char('4'), InputModifiers::none());
-        test.render();
-        assert_eq!("[1,7,8;9,0,9]4", test.get_editor_content());
+        test.assert_results(&["10", "100", "101"][..]);
     }
 
     #[test]
-    fn test_leaving_a_matrix_with_tab() {
+    fn test_setting_the_format_of_all_lines() {
         let test = create_app2(35);
-        test.paste("[1,2,3;4,5,6]");
-        test.render();
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.render();
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        // the next tab should leave the matrix
-        test.input(EditorInputEvent::Tab, InputModifiers::none());
-        test.input(EditorInputEvent::Char('7'), InputModifiers::none());
-        test.render();
-        assert_eq!("[1,2,3;4,5,6]7", test.get_editor_content());
+        test.paste("255\n16\n\n3");
+        test.set_cursor_row_col(0, 0);
+        test.input(EditorInputEvent::Char('H'), InputModifiers::alt_shift());
+        test.assert_results(&["FF", "10", "", "3"][..]);
+        test.input(EditorInputEvent::Char('B'), InputModifiers::alt_shift());
+        test.assert_results(&["11111111", "10000", "", "11"][..]);
+        test.input(EditorInputEvent::Char('D'), InputModifiers::alt_shift());
+        test.assert_results(&["255", "16", "", "3"][..]);
+        assert_eq!(test.get_editor_content(), "255\n16\n\n3");
     }
 
     #[test]
-    fn end_btn_matrix() {
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1111,22,3;44,55555,666] qq");
-            test.set_cursor_row_col(0, 5);
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            test.render();
-            // inside the matrix
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.render();
-            assert_eq!(
-                "abcd [1111,22,9;44,55555,666] qq",
-                test.get_editor_content()
-            );
-        }
-        // pressing twice, exits the matrix
-        {
-            let test = create_app2(35);
-            test.paste("abcd [1111,22,3;44,55555,666] qq");
-            test.set_cursor_row_col(0, 5);
-            test.render();
-            test.input(EditorInputEvent::Right, InputModifiers::none());
-            // inside the matrix
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
-            test.render();
-            assert_eq!(
-                "abcd [1111,22,3;44,55555,666] qq9",
-                test.get_editor_content()
-            );
-        }
+    fn test_matrix_sum() {
+        let test = create_app2(35);
+        test.paste("[1,2,3]\nsum");
+        // both the first line and the 'sum' line renders a matrix, which leaves the result buffer empty
+        test.assert_results(&["\u{0}"][..]);
     }
 
     #[test]
-    fn home_btn_matrix() {
+    fn test_line_ref_selection() {
+        // left
         {
             let test = create_app2(35);
-            test.paste("abcd [1111,22,3;44,55555,666]");
-            test.render();
-            test.input(EditorInputEvent::Left, InputModifiers::none());
-            test.render();
-            // inside the matrix
-            test.input(EditorInputEvent::Home, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Char('9'), InputModifiers::none());
+            test.paste("16892313\n14 * ");
+            test.set_cursor_row_col(1, 5);
             test.render();
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
             test.render();
-            assert_eq!("abcd [9,22,3;44,55555,666]", test.get_editor_content());
+            test.input(EditorInputEvent::Left, InputModifiers::shift());
+            test.input(EditorInputEvent::Backspace, InputModifiers::none());
+            assert_eq!("16892313\n14 * &[1", test.get_editor_content());
         }
+        // right
         {
             let test = create_app2(35);
-            test.paste("abcd [1111,22,3;44,55555,666]");
-            test.render();
-            test.input(EditorInputEvent::Left, InputModifiers::none());
-            // inside the matrix
-            test.input(EditorInputEvent::Home, InputModifiers::none());
-            test.render();
-            test.input(EditorInputEvent::Home, InputModifiers::none());
+            test.paste("16892313\n14 * ");
+            test.set_cursor_row_col(1, 5);
             test.render();
-            test.input(EditorInputEvent::Char('6'), InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+
             test.render();
-            assert_eq!("6abcd [1111,22,3;44,55555,666]", test.get_editor_content());
+            test.input(EditorInputEvent::Left, InputModifiers::none());
+            test.input(EditorInputEvent::Right, InputModifiers::shift());
+            test.input(EditorInputEvent::Del, InputModifiers::none());
+            assert_eq!("16892313\n14 * [1]", test.get_editor_content());
         }
     }
 
     #[test]
-    fn bug8() {
-        let test = create_app2(35);
-        test.paste("16892313\n14 * ");
-        test.set_cursor_row_col(1, 5);
-        test.render();
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
-        assert_eq!("16892313\n14 * &[1]", test.get_editor_content());
-        test.render();
-        test.handle_time(1000);
-        test.input(EditorInputEvent::Backspace, InputModifiers::none());
-        assert_eq!("16892313\n14 * ", test.get_editor_content());
-
-        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
-        assert_eq!("16892313\n14 * &[1]", test.get_editor_content());
-
-        let _input_eff = test.input(EditorInputEvent::Right, InputModifiers::none()); // end selection
-        test.render();
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
-        assert_eq!("16892313\n14 * a&[1]", test.get_editor_content());
+    fn test_space_is_inserted_before_lineref() {
+        let requires_space = &['4', 'a', '_'];
+        let does_not_requires_space = &['+', '*', '/', '(', ')', '[', ']'];
+        for ch in requires_space {
+            let test = create_app2(35);
+            test.paste("16892313\n");
+            test.input(EditorInputEvent::Char(*ch), InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
 
-        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
-        test.render();
-        test.input(EditorInputEvent::Right, InputModifiers::none());
-        test.input(EditorInputEvent::Char('b'), InputModifiers::none());
-        assert_eq!("16892313\n14 * a &[1]b", test.get_editor_content());
+            let mut expected: String = "16892313\n".to_owned();
+            expected.push(*ch);
+            expected.push_str(" &[1]");
+            assert_eq!(test.get_editor_content(), expected);
+        }
 
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.input(EditorInputEvent::Left, InputModifiers::none());
-        test.input(EditorInputEvent::Char('c'), InputModifiers::none());
-        assert_eq!("16892313\n14 * a c&[1]b", test.get_editor_content());
+        for ch in does_not_requires_space {
+            let test = create_app2(35);
+            test.paste("16892313\n");
+            test.input(EditorInputEvent::Char(*ch), InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+
+            let mut expected: String = "16892313\n".to_owned();
+            expected.push(*ch);
+            expected.push_str("&[1]");
+            assert_eq!(test.get_editor_content(), expected);
+        }
     }
 
     #[test]
-    fn test_referenced_line_calc() {
+    fn test_line_refs_are_automatically_separated_by_space() {
         let test = create_app2(35);
-        test.paste("2\n3 * ");
-        test.set_cursor_row_col(1, 4);
-        test.render();
+        test.paste("16892313\n");
         test.input(EditorInputEvent::Up, InputModifiers::alt());
         test.alt_key_released();
-        assert_eq!("2\n3 * &[1]", test.get_editor_content());
-
-        test.assert_results(&["2", "6"][..]);
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+        assert_eq!("16892313\n&[1] &[1]", test.get_editor_content());
     }
 
     #[test]
-    fn test_empty_right_gutter_min_len() {
+    fn test_line_ref_selection_with_mouse() {
         let test = create_app2(35);
-        test.set_normalized_content("");
-        assert_eq!(test.get_render_data().result_gutter_x, result_panel_w(120));
-    }
-
-    mod scrollbar_tests {
-        use super::super::*;
-        use super::*;
-
-        #[test]
-        fn test_scrolling_by_single_click_in_scrollbar() {
-            let test = create_app2(30);
-            test.repeated_paste("1\n", 60);
-            test.input(EditorInputEvent::PageUp, InputModifiers::none());
-            assert_eq!(test.get_render_data().scroll_y, 0);
-
-            for i in 0..4 {
-                let mouse_x = test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH;
-                test.click(mouse_x, 20 + i);
-                assert_eq!(test.get_render_data().scroll_y, i as usize);
-                test.handle_mouse_up();
-                assert_eq!(test.get_render_data().scroll_y, 1 + i as usize);
-            }
-            for i in 0..3 {
-                let mouse_x = test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH;
-                test.click(mouse_x, 0);
-                assert_eq!(test.get_render_data().scroll_y, 4 - i as usize);
-                test.handle_mouse_up();
-                assert_eq!(test.get_render_data().scroll_y, 3 - i as usize);
-            }
-        }
-
-        #[test]
-        fn test_scrollbar_is_highlighted_on_mouse_hover() {
-            let test = create_app2(30);
-            test.repeated_paste("1\n", 60);
-            test.input(EditorInputEvent::PageUp, InputModifiers::none());
-
-            let result_gutter_x = test.get_render_data().result_gutter_x;
-            assert_eq!(test.app().mouse_hover_type, MouseHoverType::Normal);
-            let commands = &test.render_bucket().custom_commands[Layer::Text as usize];
-            assert_contains(commands, 0, OutputMessage::SetColor(SCROLLBAR_HOVER_COLOR));
-            assert_contains(commands, 1, OutputMessage::SetColor(SCROLLBAR_NORMAL_COLOR));
-            assert_contains(
-                commands,
-                1,
-                OutputMessage::RenderRectangle {
-                    x: result_gutter_x - SCROLLBAR_WIDTH,
-                    y: canvas_y(0),
-                    w: SCROLLBAR_WIDTH,
-                    h: 1,
-                },
-            );
-
-            test.handle_mouse_move(result_gutter_x - SCROLLBAR_WIDTH, 0);
-            assert_eq!(test.app().mouse_hover_type, MouseHoverType::Scrollbar);
-            assert_contains(commands, 1, OutputMessage::SetColor(SCROLLBAR_HOVER_COLOR));
-            assert_contains(commands, 0, OutputMessage::SetColor(SCROLLBAR_NORMAL_COLOR));
-            assert_contains(
-                commands,
-                1,
-                OutputMessage::RenderRectangle {
-                    x: result_gutter_x - SCROLLBAR_WIDTH,
-                    y: canvas_y(0),
-                    w: SCROLLBAR_WIDTH,
-                    h: 1,
-                },
-            );
+        test.paste("16892313\n3\n14 * ");
+        test.set_cursor_row_col(2, 5);
+        test.render();
+        test.click(125, 0);
 
-            test.handle_mouse_move(result_gutter_x, 0);
-            assert_eq!(test.app().mouse_hover_type, MouseHoverType::RightGutter);
-            assert_contains(commands, 0, OutputMessage::SetColor(SCROLLBAR_HOVER_COLOR));
-            assert_contains(commands, 1, OutputMessage::SetColor(SCROLLBAR_NORMAL_COLOR));
-            assert_contains(
-                commands,
-                1,
-                OutputMessage::RenderRectangle {
-                    x: result_gutter_x - SCROLLBAR_WIDTH,
-                    y: canvas_y(0),
-                    w: SCROLLBAR_WIDTH,
-                    h: 1,
-                },
-            );
-        }
+        test.render();
+        test.input(EditorInputEvent::Left, InputModifiers::shift());
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
+        assert_eq!("16892313\n3\n14 * &[1", test.get_editor_content());
+    }
 
-        #[test]
-        fn stepping_down_to_unrendered_line_scrolls_down_the_screen() {
+    #[test]
+    fn test_clicking_into_a_matrix_edits_the_cell_under_the_mouse() {
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        for (x, expected_col) in &[
+            (0, 0),
+            (1, 0),
+            (2, 0),
+            (3, 1),
+            (5, 1),
+            (6, 1),
+            (7, 2),
+            (11, 2),
+        ] {
             let test = create_app2(35);
-            test.repeated_paste("1\n2\n3\n4\n5\n6\n7\n8\n9\n0", 6);
-            assert_eq!(test.get_render_data().scroll_y, 20);
-            test.input(EditorInputEvent::PageUp, InputModifiers::none());
-            assert_eq!(test.get_render_data().scroll_y, 0);
-            test.input(EditorInputEvent::PageDown, InputModifiers::none());
-            assert_eq!(test.get_render_data().scroll_y, 20);
+            test.paste("[1, 22, 333]");
+            test.render();
+            test.click(left_gutter_width + x, 0);
+            assert_eq!(
+                test.app().matrix_editing.as_ref().unwrap().current_cell,
+                Pos::from_row_column(0, *expected_col),
+                "x: {}",
+                x
+            );
         }
 
-        #[test]
-        fn test_scrolling_by_keyboard() {
+        // multiline matrix
+        for (x, y, expected_cell) in &[
+            (1, 1, (0, 0)),
+            (4, 1, (0, 1)),
+            (1, 2, (1, 0)),
+            (4, 3, (1, 1)),
+        ] {
             let test = create_app2(35);
-            test.paste(
-                "0
-1
-2
-[4;5;6;7]
-9
-10
-11
-12
-13
-14
-15
-16
-17
-18
-19
-20
-21
-22
-23
-24
-25
-26
-27
-28
-29
-30
-31
-32
-33
-34
-#
-1
-2
-3
-4
-5
-6
-7
-8
-10",
-            );
-            test.set_cursor_row_col(34, 0);
+            test.paste("[1, 2; 3, 4]");
             test.render();
-            test.input(EditorInputEvent::PageUp, InputModifiers::none());
-            assert_eq!(test.get_render_data().scroll_y, 0);
-            // in this setup (35 canvas height) only 30 line is visible, so the client
-            // has to press DOWN 29 times
-            let matrix_height = 6;
-            for _ in 0..(35 - matrix_height) {
-                test.input(EditorInputEvent::Down, InputModifiers::none());
-            }
-            assert_eq!(test.get_render_data().scroll_y, 0);
-            for i in 0..3 {
-                test.input(EditorInputEvent::Down, InputModifiers::none());
-                test.render();
-                assert_eq!(test.get_render_data().scroll_y, 1 + i);
-                assert_eq!(
-                    test.app().render_data.get_render_y(content_y(30 + i)),
-                    Some(canvas_y(34)),
-                );
-            }
-            // This step moves the matrix out of vision, so 6 line will appear instead of it at the bottom
-            test.input(EditorInputEvent::Down, InputModifiers::none());
-            test.render();
-            assert_eq!(test.get_render_data().scroll_y, 4);
+            test.click(left_gutter_width + x, *y);
             assert_eq!(
-                test.get_render_data().get_render_y(content_y(33)),
-                Some(canvas_y(29)),
+                test.app().matrix_editing.as_ref().unwrap().current_cell,
+                Pos::from_row_column(expected_cell.0, expected_cell.1),
+                "x: {}, y: {}",
+                x,
+                y
             );
         }
+    }
 
-        #[test]
-        fn test_that_pressing_enter_eof_moves_scrollbar_down() {
-            let test = create_app2(35);
-            // editor height is 36 in tests, so create a 35 line text
-            test.repeated_paste("a\n", 35);
-            test.set_cursor_row_col(3, 0);
+    #[test]
+    fn test_click_1() {
+        let test = create_app2(35);
+        test.paste("'1st row\n[1;2;3] some text\n'3rd row");
+        test.render();
+        // click after the vector in 2nd row
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        test.click(left_gutter_width + 4, 2);
+        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
+        assert_eq!(
+            "'1st row\n[1;2;3] Xsome text\n'3rd row",
+            test.get_editor_content()
+        );
+    }
+
+    #[test]
+    fn test_click() {
+        let test = create_app2(35);
+        test.paste("'1st row\nsome text [1;2;3]\n'3rd row");
+        test.render();
+        // click after the vector in 2nd row
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        test.click(left_gutter_width + 4, 2);
+        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
+        assert_eq!(
+            test.get_editor_content(),
+            "'1st row\nsomeX text [1;2;3]\n'3rd row"
+        );
+    }
+
+    #[test]
+    fn test_click_after_eof() {
+        let test = create_app2(35);
+        test.paste("'1st row\n[1;2;3] some text\n'3rd row");
+        test.render();
+        let left_gutter_width = 1;
+        test.click(left_gutter_width + 40, 2);
+        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
+        assert_eq!(
+            "'1st row\n[1;2;3] some textX\n'3rd row",
+            test.get_editor_content()
+        );
+    }
 
-            test.render();
-            assert_ne!(
-                test.get_render_data().get_render_y(content_y(5)),
-                Some(canvas_y(0))
-            );
+    #[test]
+    fn test_click_after_eof2() {
+        let test = create_app2(35);
+        test.paste("'1st row\n[1;2;3] some text\n'3rd row");
+        test.render();
+        let left_gutter_width = 1;
+        test.click(left_gutter_width + 40, 40);
+        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
+        assert_eq!(
+            "'1st row\n[1;2;3] some text\n'3rd rowX",
+            test.get_editor_content()
+        );
+    }
 
-            // removing a line
-            test.input(EditorInputEvent::Backspace, InputModifiers::none());
-        }
+    #[test]
+    fn test_variable() {
+        let test = create_app2(35);
+        test.paste("apple = 12");
+        test.render();
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.paste("apple + 2");
+        test.assert_results(&["12", "14"][..]);
+    }
 
-        #[test]
-        fn test_that_scrollbar_stops_at_bottom() {
-            let client_height = 25;
-            let test = create_app2(client_height);
-            test.repeated_paste("1\n", client_height * 2);
-            test.set_cursor_row_col(0, 0);
+    #[test]
+    fn test_variable_must_be_defined() {
+        let test = create_app2(35);
+        test.paste("apple = 12");
+        test.render();
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.paste("apple + 2");
 
-            test.render();
+        test.assert_results(&["2", "12"][..]);
+    }
 
-            test.input(EditorInputEvent::PageDown, InputModifiers::none());
+    #[test]
+    fn test_variables_can_be_defined_afterwards_of_their_usage() {
+        let test = create_app2(35);
+        test.paste("apple * 2");
+        test.set_cursor_row_col(0, 0);
 
-            assert_eq!(test.get_render_data().scroll_y, 26);
-        }
+        test.render();
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.assert_results(&["", "2"][..]);
+        // now define the variable 'apple'
+        test.paste("apple = 3");
 
-        #[test]
-        fn test_that_scrollbar_stops_at_bottom2() {
-            let client_height = 36;
-            let test = create_app2(client_height);
-            test.paste("");
-            test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
-            test.input(EditorInputEvent::Del, InputModifiers::none());
+        test.assert_results(&["3", "6"][..]);
+    }
 
-            for _ in 0..MAX_LINE_COUNT + 40 {
-                test.input(EditorInputEvent::Enter, InputModifiers::none());
-            }
+    #[test]
+    fn test_variables_can_be_defined_afterwards_of_their_usage2() {
+        let test = create_app2(35);
+        test.paste("apple asd * 2");
+        test.set_cursor_row_col(0, 0);
 
-            test.input(EditorInputEvent::PageUp, InputModifiers::none());
-            assert_eq!(test.get_render_data().scroll_y, 0);
-            test.input(EditorInputEvent::PageDown, InputModifiers::none());
-            assert_eq!(
-                test.get_render_data().scroll_y,
-                MAX_LINE_COUNT - client_height
-            );
-        }
+        test.render();
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.input(EditorInputEvent::Up, InputModifiers::none());
 
-        #[test]
-        fn test_inserting_long_text_scrolls_down() {
-            let test = create_app2(32);
-            test.paste("a");
-            test.repeated_paste("asd\n", 40);
-            assert_eq!(test.get_render_data().scroll_y, 9);
-        }
+        test.assert_results(&["", "2"][..]);
+        // now define the variable 'apple'
+        test.paste("apple asd = 3");
 
-        #[test]
-        fn test_that_no_overscrolling() {
-            let test = create_app2(35);
-            test.paste("1\n2\n3\n");
-            test.render();
+        test.assert_results(&["3", "6"][..]);
+    }
 
-            test.handle_wheel(1);
-            assert_eq!(0, test.get_render_data().scroll_y);
-        }
+    #[test]
+    fn test_renaming_variable_declaration() {
+        let test = create_app2(35);
+        test.paste("apple = 2\napple * 3");
+        test.set_cursor_row_col(0, 0);
 
-        #[test]
-        fn tall_rows_are_considered_in_scrollbar_height_calc() {
-            const CANVAS_HEIGHT: usize = 25;
-            let test = create_app2(CANVAS_HEIGHT);
-            test.repeated_paste("1\n2\n\n[1;2;3;4]", 5);
-            test.render();
-            assert_contains(
-                &test.render_bucket().custom_commands[Layer::Text as usize],
-                1,
-                OutputMessage::RenderRectangle {
-                    x: result_panel_w(120) - SCROLLBAR_WIDTH,
-                    y: canvas_y(0),
-                    w: 1,
-                    h: 19,
-                },
-            );
-        }
+        test.assert_results(&["2", "6"][..]);
 
-        #[test]
-        fn test_no_scrolling_in_empty_document() {
-            let test = create_app2(25);
-            test.paste("1");
+        // rename apple to aapple
+        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
 
-            test.render();
+        test.assert_results(&["2", "3"][..]);
+    }
 
-            test.handle_wheel(1);
+    #[test]
+    fn test_moving_line_does_not_change_its_lineref() {
+        let test = create_app2(35);
+        test.paste("1\n2\n3\n\n\n50year");
+        // cursor is in 4th row
+        test.set_cursor_row_col(3, 0);
 
-            test.render();
+        test.assert_results(&["1", "2", "3", "", "", "50 year"][..]);
 
-            assert_eq!(0, test.get_render_data().scroll_y);
+        // insert linref of 1st line
+        for _ in 0..3 {
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
         }
+        test.alt_key_released();
+        test.render();
+        test.input(EditorInputEvent::Char('+'), InputModifiers::none());
 
-        #[test]
-        fn test_that_no_overscrolling2() {
-            let test = create_app2(35);
-            test.repeated_paste("aaaaaaaaaaaa\n", 35);
-            test.render();
-
-            test.handle_wheel(1);
-            assert_eq!(1, test.get_render_data().scroll_y);
-            test.handle_wheel(1);
-            assert_eq!(1, test.get_render_data().scroll_y);
+        // insert linref of 2st line
+        for _ in 0..2 {
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
         }
+        test.alt_key_released();
+        test.render();
+        test.input(EditorInputEvent::Char('+'), InputModifiers::none());
 
-        #[test]
-        fn test_scrolling_down_on_enter_even() {
-            let test = create_app2(32);
-            test.paste("");
-            test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
-            test.input(EditorInputEvent::Del, InputModifiers::none());
+        // insert linref of 3rd line
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
 
-            for _i in 0..31 {
-                test.input(EditorInputEvent::Enter, InputModifiers::none());
+        match &test.tokens()[content_y(3)] {
+            Some(Tokens {
+                tokens,
+                shunting_output_stack: _,
+                ..
+            }) => {
+                match tokens[0].typ {
+                    TokenType::LineReference { var_index } => assert_eq!(var_index, 0),
+                    _ => panic!(),
+                }
+                match tokens[2].typ {
+                    TokenType::LineReference { var_index } => assert_eq!(var_index, 1),
+                    _ => panic!(),
+                }
+                match tokens[4].typ {
+                    TokenType::LineReference { var_index } => assert_eq!(var_index, 2),
+                    _ => panic!(),
+                }
             }
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            assert_eq!(test.get_render_data().scroll_y, 1);
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            assert_eq!(test.get_render_data().scroll_y, 2);
+            _ => {}
+        };
+
+        // insert a newline between the 1st and 2nd row
+        for _ in 0..3 {
+            test.input(EditorInputEvent::Up, InputModifiers::none());
         }
 
-        #[test]
-        fn test_scroll_bug_when_scrolling_upwrads_from_bottom() {
-            let test = create_app2(32);
-            test.paste("");
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
 
-            test.input(EditorInputEvent::PageDown, InputModifiers::none());
-            for _i in 0..40 {
-                test.input(EditorInputEvent::Enter, InputModifiers::none());
-            }
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            let scroll_y_at_bottom = test.get_render_data().scroll_y;
-            test.handle_wheel(0);
-            assert_eq!(test.get_render_data().scroll_y, scroll_y_at_bottom - 1);
-            test.handle_wheel(0);
-            assert_eq!(test.get_render_data().scroll_y, scroll_y_at_bottom - 2);
-        }
+        test.assert_results(&["1", "", "2", "3", "6", "", "50 year"][..]);
+
+        match &test.tokens()[content_y(4)] {
+            Some(Tokens {
+                tokens,
+                shunting_output_stack: _,
+                ..
+            }) => {
+                match tokens[0].typ {
+                    TokenType::LineReference { var_index } => assert_eq!(var_index, 0),
+                    _ => panic!("{:?}", &tokens[0]),
+                }
+                match tokens[2].typ {
+                    TokenType::LineReference { var_index } => assert_eq!(var_index, 2),
+                    _ => panic!("{:?}", &tokens[2]),
+                }
+                match tokens[4].typ {
+                    TokenType::LineReference { var_index } => assert_eq!(var_index, 3),
+                    _ => panic!("{:?}", &tokens[4]),
+                }
+            }
+            _ => {}
+        };
     }
 
-    mod right_gutter_tests {
+    mod test_line_dependency_and_pulsing_on_change {
         use super::super::*;
         use super::*;
-
-        #[test]
-        fn right_gutter_is_moving_if_there_would_be_enough_space_for_result() {
-            let test = create_app3(40, 35);
-            test.paste("1\n");
-            assert_eq!(test.get_render_data().result_gutter_x, result_panel_w(40));
-
-            test.paste("999 999 999 999");
-            assert_eq!(
-                test.get_render_data().result_gutter_x,
-                40 - ("999 999 999 999".len() + RIGHT_GUTTER_WIDTH)
-            );
-        }
-
-        #[test]
-        fn right_gutter_is_moving_if_there_would_be_enough_space_for_binary_result() {
-            let test = create_app3(40, 35);
-            test.paste("9999");
-            assert_eq!(test.get_render_data().result_gutter_x, result_panel_w(40),);
-
-            test.input(EditorInputEvent::Left, InputModifiers::alt());
-            assert_eq!(
-                test.get_render_data().result_gutter_x,
-                40 - ("100111 00001111".len() + RIGHT_GUTTER_WIDTH)
-            );
-        }
-
-        #[test]
-        fn right_gutter_calc_panic() {
-            let test = create_app3(176, 35);
-            test.paste("ok");
-        }
-
         #[test]
-        fn test_resize_keeps_result_width() {
-            let test = create_app3(60, 35);
-            test.set_normalized_content("80kg\n190cm\n0.0016\n0.128 kg");
-            let longest_result_len = 11;
-            let check_longest_line_did_not_change = || {
-                assert_eq!(
-                    test.get_render_data().longest_visible_result_len,
-                    longest_result_len
-                );
-            };
-            let asset_result_x_pos = |expected: usize| {
-                assert_eq!(test.get_render_data().result_gutter_x, expected);
-            };
-
-            check_longest_line_did_not_change();
-            asset_result_x_pos(default_result_gutter_x(60));
+        fn test_modifying_a_lineref_recalcs_its_dependants() {
+            let test = create_app2(35);
+            test.paste("2\n * 3");
+            test.set_cursor_row_col(1, 0);
 
-            test.handle_resize(50);
-            asset_result_x_pos(default_result_gutter_x(50));
-            check_longest_line_did_not_change();
+            test.assert_results(&["2", "3"][..]);
 
-            test.handle_resize(60);
-            check_longest_line_did_not_change();
-            asset_result_x_pos(default_result_gutter_x(60));
+            // insert linref of 1st line
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
 
-            test.handle_resize(100);
-            check_longest_line_did_not_change();
-            asset_result_x_pos(default_result_gutter_x(100));
+            test.assert_results(&["2", "6"][..]);
 
-            // there is no enough space for the panel,
-            // so it becomes bigger than 30%
-            test.handle_resize(40);
-            check_longest_line_did_not_change();
-            asset_result_x_pos(27);
+            // now modify the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Home, InputModifiers::none());
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
 
-            test.handle_resize(30);
-            check_longest_line_did_not_change();
-            asset_result_x_pos(17);
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
 
-            test.handle_resize(20);
-            asset_result_x_pos(7);
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_result_rect(
+                    test.get_render_data().result_gutter_x + RIGHT_GUTTER_WIDTH,
+                    0,
+                    2,
+                    1,
+                ),
+            );
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_result_rect(
+                    test.get_render_data().result_gutter_x + RIGHT_GUTTER_WIDTH,
+                    1,
+                    2,
+                    1,
+                ),
+            );
 
-            // too small
-            test.handle_resize(10);
-            check_longest_line_did_not_change();
-            asset_result_x_pos(default_result_gutter_x(10));
+            test.assert_results(&["12", "36"][..]);
         }
 
         #[test]
-        fn right_gutter_is_immediately_rendered_at_its_changed_position_after_scrolling() {
-            let test = create_app3(76, 10);
-            test.repeated_paste("1\n", 10);
-            test.paste("111111111111111111111");
-            test.input(EditorInputEvent::PageUp, InputModifiers::none());
-            assert_eq!(
-                test.get_render_data().result_gutter_x,
-                default_result_gutter_x(76)
-            );
+        fn test_that_dependant_line_refs_are_pulsed_on_change() {
+            let test = create_app2(35);
+            test.paste("2\n * 3");
+            test.set_cursor_row_col(1, 0);
+            test.render();
 
-            test.handle_wheel(1);
+            // insert linref of 1st line
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+            test.render();
 
-            let expected_result_pos = 76 - ("111 111 111 111 111 111 111".len());
-            test.assert_contains_result(1, |cmd| {
-                cmd.text == "111 111 111 111 111 111 111".as_bytes()
-                    && cmd.row == canvas_y(9)
-                    && cmd.column == expected_result_pos
-            })
+            // now modify the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Home, InputModifiers::none());
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_changed_content_rect(LEFT_GUTTER_MIN_WIDTH, 1, 2, 1),
+            );
         }
 
         #[test]
-        fn right_gutter_is_immediately_rendered_at_its_changed_position_after_input() {
-            let test = create_app3(76, 10);
-            test.repeated_paste("1\n", 10);
-            test.paste("111111111111111111111");
-            test.input(EditorInputEvent::PageUp, InputModifiers::none());
-            assert_eq!(
-                test.get_render_data().result_gutter_x,
-                default_result_gutter_x(76)
-            );
+        fn test_that_all_dependant_line_refs_in_same_row_are_pulsed_only_once_on_change() {
+            let test = create_app2(35);
+            test.paste("2\n * 3");
+            test.set_cursor_row_col(1, 0);
+            test.render();
 
-            test.input(EditorInputEvent::PageDown, InputModifiers::none());
+            // insert linref of 1st line
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+            test.render();
 
-            let expected_result_pos = 76 - ("111 111 111 111 111 111 111".len());
-            test.assert_contains_result(1, |cmd| {
-                cmd.text == "111 111 111 111 111 111 111".as_bytes()
-                    && cmd.row == canvas_y(9)
-                    && cmd.column == expected_result_pos
-            })
-        }
-    }
+            test.input(EditorInputEvent::Char(' '), InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+            test.render();
 
-    #[test]
-    fn test_that_alignment_is_considered_for_longest_result_len() {
-        let test = create_app2(35);
-        test.set_normalized_content("80kg\n190cm\n0.0016\n0.128 kg");
-        assert_eq!(test.get_render_data().longest_visible_result_len, 11);
-    }
+            // now modify the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Home, InputModifiers::none());
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
 
-    #[test]
-    fn test_scroll_y_reset() {
-        let test = create_app2(35);
-        test.mut_app().render_data.scroll_y = 1;
-        test.set_normalized_content("1111\n2222\n14 * &[2]&[2]&[2]\n");
-        assert_eq!(0, test.get_render_data().scroll_y);
-    }
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
 
-    #[test]
-    fn test_tab_change_clears_variables() {
-        let test = create_app2(35);
-        test.set_normalized_content(
-            "source: https://rippedbody.com/how-to-calculate-leangains-macros/
+            let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_changed_content_rect(left_gutter_width, 1, 2, 1),
+            );
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_changed_content_rect(left_gutter_width + 3, 1, 2, 1),
+            );
 
-weight = 80 kg
-height = 190 cm
-age = 30
+            // the last 2 command is for pulsing references for the active row
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(left_gutter_width, 1, 2, 1),
+            );
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(left_gutter_width + 3, 1, 2, 1),
+            );
+        }
 
-# Step 1: Calculate your  (Basal Metabolic Rate) (BMR)
-men BMR = 66 + (13.7 * weight/1kg) + (5 * height/1cm) - (6.8 * age)
+        #[test]
+        fn test_that_all_dependant_line_refs_in_different_rows_are_pulsed_on_change() {
+            let test = create_app2(35);
+            test.paste("2\n * 3");
+            test.set_cursor_row_col(1, 0);
 
-'STEP 2. FIND YOUR TDEE BY ADJUSTING FOR ACTIVITY
-Activity
-' Sedentary (little or no exercise) [BMR x 1.15]
-' Mostly sedentary (office work), plus 3–6 days of weight lifting [BMR x 1.35]
-' Lightly active, plus 3–6 days of weight lifting [BMR x 1.55]
-' Highly active, plus 3–6 days of weight lifting [BMR x 1.75]
-TDEE = (men BMR * 1.35)
+            test.render();
 
-'STEP 3. ADJUST CALORIE INTAKE BASED ON YOUR GOAL
-Fat loss
-    target weekly fat loss rate = 0.5%
-    TDEE - ((weight/1kg) * target weekly fat loss rate * 1100)kcal
-Muscle gain
-    monthly rates of weight gain = 1%
-    TDEE + (weight/1kg * monthly rates of weight gain * 330)kcal
+            // insert linref of 1st line
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+            test.render();
 
-Protein intake
-    1.6 g/kg
-    2.2 g/kg
-    weight * &[27] to g
-    weight * &[28] to g
-Fat intake
-    0.5g/kg or at least 30 %
-    1g/kg minimum
-    fat calory = 9
-    &[24]",
-        );
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+            test.render();
 
-        test.render();
+            // now modify the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Home, InputModifiers::none());
 
-        test.set_normalized_content(
-            "Valaki elment Horvátba 12000 Ftért
-    3 éjszakát töltött ott
-    &[1]*&[2]
-    utána vacsorázott egyet 5000ért
-    
-    
-    999 + 1
-    22222
-    3
-    4 + 2
-    2
-    &[10]
-    722
-    alma = 3
-    alma * 2
-    alma * &[13] + &[12]
-    &[13] km
-    2222222222222222222722.22222222 km
-    
-    [1;0] * [1,2]
-    1 + 2
-    2
-    
-    
-    2
-    23
-    human brain: 10^16 op/s
-    so far000 humans lived
-    avg. human lifespan is 50 years
-    total human brain activity is &[27] * &[28] * (&[29]/1s)",
-        );
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
 
-        test.render();
-    }
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
 
-    #[test]
-    fn test_panic_on_pressing_enter() {
-        let test = create_app2(35);
-        test.set_normalized_content(
-            "source: https://rippedbody.com/how-to-calculate-leangains-macros/
+            let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_changed_content_rect(left_gutter_width, 1, 2, 1),
+            );
 
-weight = 80 kg
-height = 190 cm
-age = 30
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_changed_content_rect(left_gutter_width, 2, 2, 1),
+            );
+            // the last 2 command is for pulsing references for the active row
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(left_gutter_width, 1, 2, 1),
+            );
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(left_gutter_width, 2, 2, 1),
+            );
+        }
 
-# Step 1: Calculate your  (Basal Metabolic Rate) (BMR)
-men BMR = 66 + (13.7 * weight/1kg) + (5 * height/1cm) - (6.8 * age)
+        #[test]
+        fn test_that_dependant_line_refs_are_pulsing_when_the_cursor_is_on_the_referenced_line() {
+            let test = create_app2(35);
+            test.paste("2\n * 3");
+            test.set_cursor_row_col(1, 0);
+            test.render();
 
-'STEP 2. FIND YOUR TDEE BY ADJUSTING FOR ACTIVITY
-Activity
-' Sedentary (little or no exercise) [BMR x 1.15]
-' Mostly sedentary (office work), plus 3–6 days of weight lifting [BMR x 1.35]
-' Lightly active, plus 3–6 days of weight lifting [BMR x 1.55]
-' Highly active, plus 3–6 days of weight lifting [BMR x 1.75]
-TDEE = (men BMR * 1.35)
+            // insert linref of 1st line
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+            test.render(); // it is needed
 
-'STEP 3. ADJUST CALORIE INTAKE BASED ON YOUR GOAL
-Fat loss
-    target weekly fat loss rate = 0.5%
-    (TDEE - ((weight/1kg) * target weekly fat loss rate * 1100))kcal
-Muscle gain
-    monthly rates of weight gain = 1%
-    (TDEE + (weight/1kg * monthly rates of weight gain * 330))kcal
+            // there should not be pulsing here yet
+            test.assert_no_pulsing();
 
-Protein intake
-    1.6 g/kg
-    2.2 g/kg
-    weight * &[27] to g
-    weight * &[28] to g
-Fat intake
-    0.5g/kg or at least 30 %
-    1g/kg minimum
-    fat calory = 9
-    &[24]",
-        );
+            // step into the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-        fn assert_var(vars: &Variables, name: &str, defined_at: usize) {
-            let var = vars[defined_at].as_ref().unwrap();
-            assert!(var.value.is_ok(), "{}", name);
-            assert_eq!(name.len(), var.name.len(), "{}", name);
-            for (a, b) in name.chars().zip(var.name.iter()) {
-                assert_eq!(a, *b, "{}", name);
-            }
-        }
-        {
-            let vars = &test.mut_vars();
-            assert_var(&vars[..], "weight", 2);
-            assert_var(&vars[..], "height", 3);
-            assert_var(&vars[..], "age", 4);
-            assert_var(&vars[..], "men BMR", 7);
-            assert_var(&vars[..], "TDEE", 15);
-            assert_var(&vars[..], "target weekly fat loss rate", 19);
-            assert_var(&vars[..], "&[21]", 20);
-            assert_var(&vars[..], "monthly rates of weight gain", 22);
-            assert_var(&vars[..], "&[24]", 23);
-            assert_var(&vars[..], "&[27]", 26);
-            assert_var(&vars[..], "&[28]", 27);
-            assert_var(&vars[..], "&[29]", 28);
-            assert_var(&vars[..], "&[30]", 29);
-            assert_var(&vars[..], "&[32]", 31);
-            assert_var(&vars[..], "&[33]", 32);
-            assert_var(&vars[..], "fat calory", 33);
-            assert_var(&vars[..], "&[35]", 34);
-        }
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
 
-        test.set_cursor_row_col(6, 33);
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 1, 1),
+            );
+        }
 
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.render();
+        #[test]
+        fn test_that_variable_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it() {
+            let test = create_app3(30, 30);
+            test.paste(
+                "b = 1
+aaaaaaaaaaaaaaaaaaaaaa b",
+            );
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-        test.input(EditorInputEvent::Backspace, InputModifiers::none());
-        test.render();
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(
+                    "aaaaaaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
+                    1,
+                    1,
+                    1,
+                ),
+            );
 
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.render();
-        let vars = &test.mut_vars();
-        assert_var(&vars[..], "weight", 2);
-        assert_var(&vars[..], "height", 3);
-        assert_var(&vars[..], "age", 4);
-        assert_var(&vars[..], "men BMR", 8);
-        assert_var(&vars[..], "TDEE", 16);
-        assert_var(&vars[..], "target weekly fat loss rate", 20);
-        assert_var(&vars[..], "&[21]", 21);
-        assert_var(&vars[..], "monthly rates of weight gain", 23);
-        assert_var(&vars[..], "&[24]", 24);
-        assert_var(&vars[..], "&[27]", 27);
-        assert_var(&vars[..], "&[28]", 28);
-        assert_var(&vars[..], "&[29]", 29);
-        assert_var(&vars[..], "&[30]", 30);
-        assert_var(&vars[..], "&[32]", 32);
-        assert_var(&vars[..], "&[33]", 33);
-        assert_var(&vars[..], "fat calory", 34);
-        assert_var(&vars[..], "&[35]", 35);
-    }
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            // this step reduces the editor width
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            assert_eq!(test.get_render_data().current_editor_width, 23);
+            // if it is out of screen, it is rendered on the '...'
+            test.render(); // we currently need one render cycle to align widths
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(render_commands, 1, pulsing_ref_rect(25, 1, 1, 1));
 
-    #[test]
-    fn no_memory_deallocation_bug_in_line_selection() {
-        let test = create_app2(35);
-        test.paste("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n");
-        test.set_cursor_row_col(12, 2);
-        test.render();
-        test.input(EditorInputEvent::Up, InputModifiers::shift());
-        test.render();
-    }
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            test.render(); // we currently need one render cycle to align widths
+            assert_eq!(test.get_render_data().current_editor_width, 22);
 
-    #[test]
-    fn matrix_deletion() {
-        let test = create_app2(35);
-        test.paste(" [1,2,3]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        test.input(EditorInputEvent::Del, InputModifiers::none());
-        assert_eq!("[1,2,3]", test.get_editor_content());
-    }
+            // if it is out of screen, it is rendered on the '...'
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(render_commands, 1, pulsing_ref_rect(24, 1, 1, 1));
+        }
 
-    #[test]
-    fn matrix_insertion_bug() {
-        let test = create_app2(35);
-        test.paste("[1,2,3]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
-        assert_eq!("a[1,2,3]", test.get_editor_content());
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        assert_eq!("a\n[1,2,3]", test.get_editor_content());
-    }
+        #[test]
+        fn test_that_variable_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it_2() {
+            let test = create_app3(30, 30);
+            test.paste(
+                "bcdef = 1
+aaaaaaaaaaaaaaaaaa bcdef",
+            );
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-    #[test]
-    fn matrix_insertion_bug2() {
-        let test = create_app2(35);
-        test.paste("'[X] nth, sum fv");
-        test.render();
-        test.set_cursor_row_col(0, 0);
-        test.input(EditorInputEvent::Del, InputModifiers::none());
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(
+                    "aaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
+                    1,
+                    5,
+                    1,
+                ),
+            );
 
-        test.assert_results(&["Err"][..]);
-    }
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            // this step reduces the editor width
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            assert_eq!(test.get_render_data().current_editor_width, 23);
+            // if it is out of screen, it is rendered on the '...'
+            test.render(); // we currently need one render cycle to align widths
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 5, 1));
+            test.assert_contains_variable(1, |cmd| {
+                cmd.text == &['b', 'c', 'd', 'e'] && cmd.row == canvas_y(1) && cmd.column == 21
+            });
 
-    #[test]
-    fn test_err_result_rendering() {
-        let test = create_app2(35);
-        test.paste("'[X] nth, sum fv");
-        test.render();
-        test.set_cursor_row_col(0, 0);
-        test.input(EditorInputEvent::Del, InputModifiers::none());
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            test.render(); // we currently need one render cycle to align widths
+            assert_eq!(test.get_render_data().current_editor_width, 22);
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 4, 1));
+            test.assert_contains_variable(1, |cmd| {
+                cmd.text == &['b', 'c', 'd'] && cmd.row == canvas_y(1) && cmd.column == 21
+            });
 
-        match &test.render_bucket().ascii_texts[0] {
-            RenderAsciiTextMsg { text, row, column } => {
-                assert_eq!(text, &[b'E', b'r', b'r']);
-                assert_eq!(*row, canvas_y(0));
-                assert_eq!(*column, result_panel_w(120) + RIGHT_GUTTER_WIDTH);
-            }
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            test.render(); // we currently need one render cycle to align widths
+            assert_eq!(test.get_render_data().current_editor_width, 20);
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 2, 1));
+            test.assert_contains_variable(1, |cmd| {
+                cmd.text == &['b'] && cmd.row == canvas_y(1) && cmd.column == 21
+            });
+
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            test.render(); // we currently need one render cycle to align widths
+            assert_eq!(test.get_render_data().current_editor_width, 19);
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 1, 1));
+            test.assert_contains_variable(0, |cmd| cmd.row == canvas_y(1) && !cmd.text.is_empty());
         }
-    }
 
-    #[test]
-    fn sum_is_nulled_in_new_header_region() {
-        let test = create_app2(35);
-        test.paste(
-            "3m * 2m
-# new header
-1
-2
-sum
-# new header
-4
-5
-sum",
-        );
-        test.assert_results(&["6 m^2", "", "1", "2", "3", "", "4", "5", "9"][..]);
-    }
+        #[test]
+        fn test_that_lineref_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it() {
+            let test = create_app3(30, 30);
+            test.paste(
+                "1
+aaaaaaaaaaaaaaaaaaaaaa &[1]",
+            );
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-    #[test]
-    fn no_sum_value_in_case_of_error() {
-        let test = create_app2(35);
-        test.paste(
-            "3m * 2m\n\
-                    4\n\
-                    sum",
-        );
-        test.assert_results(&["6 m^2", "4", "Err"][..]);
-    }
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(
+                    "aaaaaaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
+                    1,
+                    1,
+                    1,
+                ),
+            );
 
-    #[test]
-    fn test_ctrl_c() {
-        let test = create_app2(35);
-        test.paste("aaaaaaaaa");
-        test.render();
-        test.input(EditorInputEvent::Left, InputModifiers::shift());
-        test.input(EditorInputEvent::Left, InputModifiers::shift());
-        test.input(EditorInputEvent::Left, InputModifiers::shift());
-        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
-        assert_eq!("aaa", &test.app().editor.clipboard);
-        assert_eq!(&None, &test.app().clipboard);
-    }
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            // this step reduces the editor width
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            assert_eq!(test.get_render_data().current_editor_width, 23);
+            // should appear
+            // if it is out of screen, it is rendered on the '...'
+            test.render(); // we currently need one render cycle to align widths
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                OutputMessage::RenderChar(test.get_render_data().result_gutter_x - 1, 1, '…'),
+            );
+            assert_contains(render_commands, 1, pulsing_ref_rect(25, 1, 1, 1));
 
-    #[test]
-    fn test_ctrl_c_without_selection() {
-        let test = create_app2(35);
-        test.paste("12*3");
-        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
-        assert_eq!(&Some("36".to_owned()), &test.app().clipboard);
-        assert!(test.app().editor.clipboard.is_empty());
-    }
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+            test.render(); // we currently need one render cycle to align widths
+            assert_eq!(test.get_render_data().current_editor_width, 22);
 
-    #[test]
-    fn test_ctrl_c_without_selection2() {
-        let test = create_app2(35);
-        test.paste("12*3");
-        test.input(EditorInputEvent::Char('c'), InputModifiers::ctrl());
-        assert_eq!(
-            Some("36".to_owned()),
-            test.mut_app().get_selected_text_and_clear_app_clipboard()
-        );
-        assert_eq!(
-            None,
-            test.mut_app().get_selected_text_and_clear_app_clipboard()
-        );
-    }
+            // if it is out of screen, it is rendered on the '...'
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(render_commands, 1, pulsing_ref_rect(24, 1, 1, 1));
+        }
 
-    #[test]
-    fn test_changing_output_style_for_selected_rows() {
-        let test = create_app2(35);
-        test.paste(
-            "2\n\
-                        4\n\
-                        5",
-        );
-        test.render();
-        test.input(EditorInputEvent::Up, InputModifiers::shift());
-        test.input(EditorInputEvent::Up, InputModifiers::shift());
-        test.input(EditorInputEvent::Left, InputModifiers::alt());
+        #[test]
+        fn test_that_lineref_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it_2() {
+            let test = create_app3(30, 30);
+            test.paste(
+                "1
+aaaaaaaaaaaaaaaaaaaa &[1]",
+            );
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-        test.assert_results(&["10", "100", "101"][..]);
-    }
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(
+                    "aaaaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
+                    1,
+                    1,
+                    1,
+                ),
+            );
 
-    #[test]
-    fn test_matrix_sum() {
-        let test = create_app2(35);
-        test.paste("[1,2,3]\nsum");
-        // both the first line and the 'sum' line renders a matrix, which leaves the result buffer empty
-        test.assert_results(&["\u{0}"][..]);
-    }
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            {
+                // this step reduces the editor width
+                test.input(EditorInputEvent::Char('2'), InputModifiers::none());
+                test.input(EditorInputEvent::Char('3'), InputModifiers::none());
+                assert_eq!(test.get_render_data().current_editor_width, 22);
+                // if it is out of screen, it is rendered on the '...'
+                test.render(); // we currently need one render cycle to align widths
+                let render_commands =
+                    &test.render_bucket().custom_commands[Layer::AboveText as usize];
+                assert_contains(
+                    render_commands,
+                    1,
+                    OutputMessage::RenderChar(test.get_render_data().result_gutter_x - 1, 1, '…'),
+                );
+                assert_contains(render_commands, 1, pulsing_ref_rect(23, 1, 2, 1));
+                test.assert_contains_line_ref_result(1, |cmd| {
+                    cmd.text == "1".to_owned() && cmd.row == canvas_y(1) && cmd.column == 23
+                });
+            }
 
-    #[test]
-    fn test_line_ref_selection() {
-        // left
-        {
+            {
+                test.input(EditorInputEvent::Char('4'), InputModifiers::none());
+                test.render(); // we currently need one render cycle to align widths
+                assert_eq!(test.get_render_data().current_editor_width, 20);
+                let render_commands =
+                    &test.render_bucket().custom_commands[Layer::AboveText as usize];
+                assert_contains(render_commands, 1, pulsing_ref_rect(22, 1, 1, 1));
+                test.assert_contains_line_ref_result(0, |cmd| {
+                    !cmd.text.is_empty() && cmd.row == canvas_y(1)
+                });
+                assert_contains(
+                    render_commands,
+                    1,
+                    OutputMessage::RenderChar(test.get_render_data().result_gutter_x - 1, 1, '…'),
+                );
+            }
+        }
+
+        #[test]
+        fn test_that_multiple_dependant_line_refs_are_pulsed_when_the_cursor_is_on_the_referenced_line(
+        ) {
             let test = create_app2(35);
-            test.paste("16892313\n14 * ");
-            test.set_cursor_row_col(1, 5);
+            test.paste("2\n * 3");
+            test.set_cursor_row_col(1, 0);
             test.render();
+
+            // insert linref of 1st line
             test.input(EditorInputEvent::Up, InputModifiers::alt());
             test.alt_key_released();
-            test.render();
-            test.input(EditorInputEvent::Left, InputModifiers::shift());
-            test.input(EditorInputEvent::Backspace, InputModifiers::none());
-            assert_eq!("16892313\n14 * &[1", test.get_editor_content());
-        }
-        // right
-        {
-            let test = create_app2(35);
-            test.paste("16892313\n14 * ");
-            test.set_cursor_row_col(1, 5);
-            test.render();
+            test.input(EditorInputEvent::Char(' '), InputModifiers::alt());
             test.input(EditorInputEvent::Up, InputModifiers::alt());
             test.alt_key_released();
 
+            // requires so the pulsings caused by the changes above are consumed
             test.render();
-            test.input(EditorInputEvent::Left, InputModifiers::none());
-            test.input(EditorInputEvent::Right, InputModifiers::shift());
-            test.input(EditorInputEvent::Del, InputModifiers::none());
-            assert_eq!("16892313\n14 * [1]", test.get_editor_content());
+
+            // there should not be pulsing here yet
+            test.assert_no_pulsing();
+
+            // step into the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 1, 1),
+            );
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH + 2, 1, 1, 1),
+            );
         }
-    }
 
-    #[test]
-    fn test_space_is_inserted_before_lineref() {
-        let requires_space = &['4', 'a', '_'];
-        let does_not_requires_space = &['+', '*', '/', '(', ')', '[', ']'];
-        for ch in requires_space {
+        #[test]
+        fn test_that_multiple_dependant_vars_are_pulsed_when_the_cursor_is_on_the_definition_line()
+        {
             let test = create_app2(35);
-            test.paste("16892313\n");
-            test.input(EditorInputEvent::Char(*ch), InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
+            test.paste("var = 2\nvar * 3\n12 * var");
+            test.set_cursor_row_col(1, 0);
+            test.render();
 
-            let mut expected: String = "16892313\n".to_owned();
-            expected.push(*ch);
-            expected.push_str(" &[1]");
-            assert_eq!(test.get_editor_content(), expected);
+            // there should not be pulsing here yet
+            test.assert_no_pulsing();
+
+            // step into the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 3, 1),
+            );
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH + 5, 2, 3, 1),
+            );
         }
 
-        for ch in does_not_requires_space {
+        #[test]
+        fn test_that_dependant_vars_are_pulsed_when_the_cursor_is_on_the_definition_line() {
             let test = create_app2(35);
-            test.paste("16892313\n");
-            test.input(EditorInputEvent::Char(*ch), InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
+            test.paste("var = 2\nvar * 3");
+            test.set_cursor_row_col(1, 0);
+            test.render();
 
-            let mut expected: String = "16892313\n".to_owned();
-            expected.push(*ch);
-            expected.push_str("&[1]");
-            assert_eq!(test.get_editor_content(), expected);
+            // there should not be pulsing here yet
+            test.assert_no_pulsing();
+
+            // step into the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            assert_contains(
+                render_commands,
+                1,
+                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 3, 1),
+            );
         }
     }
 
     #[test]
-    fn test_line_refs_are_automatically_separated_by_space() {
+    fn test_modifying_a_lineref_does_not_change_the_line_id() {
         let test = create_app2(35);
-        test.paste("16892313\n");
+        test.paste("2\n3\n");
+        test.set_cursor_row_col(2, 0);
+        test.render();
+        // insert linref of 1st line
         test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
         test.input(EditorInputEvent::Up, InputModifiers::alt());
         test.alt_key_released();
-        assert_eq!("16892313\n&[1] &[1]", test.get_editor_content());
-    }
-
-    #[test]
-    fn test_line_ref_selection_with_mouse() {
-        let test = create_app2(35);
-        test.paste("16892313\n3\n14 * ");
-        test.set_cursor_row_col(2, 5);
         test.render();
-        test.click(125, 0);
 
+        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
         test.render();
-        test.input(EditorInputEvent::Left, InputModifiers::shift());
-        test.input(EditorInputEvent::Backspace, InputModifiers::none());
-        assert_eq!("16892313\n3\n14 * &[1", test.get_editor_content());
-    }
-
-    #[test]
-    fn test_click_1() {
-        let test = create_app2(35);
-        test.paste("'1st row\n[1;2;3] some text\n'3rd row");
+        test.input(EditorInputEvent::Char('*'), InputModifiers::none());
         test.render();
-        // click after the vector in 2nd row
-        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-        test.click(left_gutter_width + 4, 2);
-        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
-        assert_eq!(
-            "'1st row\n[1;2;3] Xsome text\n'3rd row",
-            test.get_editor_content()
-        );
+        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
+        test.render();
+
+        // insert linref of 2st line
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+
+        test.assert_results(&["2", "3", "6"][..]);
+
+        // now modify the 2nd row
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+
+        test.assert_results(&["2", "Err", ""][..]);
+
+        test.input(EditorInputEvent::Char('4'), InputModifiers::none());
+
+        test.assert_results(&["2", "4", "8"][..]);
     }
 
     #[test]
-    fn test_click() {
+    fn test_line_ids_can_be_controlled_by_the_generator() {
         let test = create_app2(35);
-        test.paste("'1st row\nsome text [1;2;3]\n'3rd row");
+        test.paste("2\n3");
+        test.set_cursor_row_col(1, 1);
+        test.mut_app().set_line_id_generator(100);
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.input(EditorInputEvent::Char('4'), InputModifiers::none());
+        assert_eq!(test.app().get_line_id(2), 100);
+
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
         test.render();
-        // click after the vector in 2nd row
-        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-        test.click(left_gutter_width + 4, 2);
-        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
-        assert_eq!(
-            test.get_editor_content(),
-            "'1st row\nsomeX text [1;2;3]\n'3rd row"
-        );
+        test.input(EditorInputEvent::Up, InputModifiers::alt());
+        test.alt_key_released();
+
+        assert_eq!(test.get_editor_content(), "2\n3\n4\n&[100]");
+        test.assert_results(&["2", "3", "4", "4"][..]);
     }
 
-    #[test]
-    fn test_click_after_eof() {
-        let test = create_app2(35);
-        test.paste("'1st row\n[1;2;3] some text\n'3rd row");
-        test.render();
-        let left_gutter_width = 1;
-        test.click(left_gutter_width + 40, 2);
-        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
-        assert_eq!(
-            "'1st row\n[1;2;3] some textX\n'3rd row",
-            test.get_editor_content()
-        );
-    }
+    mod dependent_lines_recalculation_tests {
+        use super::super::*;
+        use super::*;
+
+        #[test]
+        fn test_modifying_a_lineref_recalcs_its_dependants_only_if_its_value_has_changed() {
+            let test = create_app2(35);
+            test.paste("2\n * 3");
+            test.set_cursor_row_col(1, 0);
+
+            test.assert_results(&["2", "3"][..]);
+
+            // insert linref of 1st line
+            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.alt_key_released();
+
+            test.assert_results(&["2", "6"][..]);
+
+            // now modify the first row
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::End, InputModifiers::none());
+            test.render();
+            // inserting a '.' does not modify the result of the line
+            test.input(EditorInputEvent::Char('.'), InputModifiers::none());
+
+            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+            // expect no pulsing since there were no value change
+            assert_contains(
+                render_commands,
+                0,
+                pulsing_changed_content_rect(90, 0, 2, 1),
+            );
+            assert_contains(
+                render_commands,
+                0,
+                pulsing_changed_content_rect(90, 1, 2, 1),
+            );
+
+            test.assert_results(&["2", "6"][..]);
+        }
+
+        #[test]
+        fn test_renaming_variable_declaration2() {
+            let test = create_app2(35);
+            test.paste("apple = 2\naapple * 3");
+            test.set_cursor_row_col(0, 0);
+
+            test.assert_results(&["2", "3"][..]);
+
+            // rename apple to aapple
+            test.input(EditorInputEvent::Char('a'), InputModifiers::none());
+
+            test.assert_results(&["2", "6"][..]);
+        }
+
+        #[test]
+        fn test_removing_variable_declaration() {
+            let test = create_app2(35);
+            test.paste("apple = 2\napple * 3");
+            test.set_cursor_row_col(0, 0);
+
+            test.assert_results(&["2", "6"][..]);
+
+            // remove the content of the first line
+            test.input(EditorInputEvent::End, InputModifiers::shift());
+
+            test.input(EditorInputEvent::Del, InputModifiers::none());
+
+            test.assert_results(&["", "3"][..]);
+        }
+
+        #[test]
+        fn test_that_variable_dependent_rows_are_recalculated() {
+            let test = create_app2(35);
+            test.paste("apple = 2\napple * 3");
+            test.set_cursor_row_col(0, 9);
+
+            test.assert_results(&["2", "6"][..]);
 
-    #[test]
-    fn test_click_after_eof2() {
-        let test = create_app2(35);
-        test.paste("'1st row\n[1;2;3] some text\n'3rd row");
-        test.render();
-        let left_gutter_width = 1;
-        test.click(left_gutter_width + 40, 40);
-        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
-        assert_eq!(
-            "'1st row\n[1;2;3] some text\n'3rd rowX",
-            test.get_editor_content()
-        );
-    }
+            // change value of 'apple' from 2 to 24
+            test.input(EditorInputEvent::Char('4'), InputModifiers::none());
 
-    #[test]
-    fn test_variable() {
-        let test = create_app2(35);
-        test.paste("apple = 12");
-        test.render();
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.paste("apple + 2");
-        test.assert_results(&["12", "14"][..]);
-    }
+            test.assert_results(&["24", "72"][..]);
+        }
 
-    #[test]
-    fn test_variable_must_be_defined() {
-        let test = create_app2(35);
-        test.paste("apple = 12");
-        test.render();
-        test.input(EditorInputEvent::Home, InputModifiers::none());
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        test.paste("apple + 2");
+        #[test]
+        fn test_that_sum_is_recalculated_if_anything_changes_above() {
+            let test = create_app2(35);
+            test.paste("2\n3\nsum");
+            test.set_cursor_row_col(0, 1);
 
-        test.assert_results(&["2", "12"][..]);
-    }
+            test.assert_results(&["2", "3", "5"][..]);
 
-    #[test]
-    fn test_variables_can_be_defined_afterwards_of_their_usage() {
-        let test = create_app2(35);
-        test.paste("apple * 2");
-        test.set_cursor_row_col(0, 0);
+            // change value from 2 to 21
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
 
-        test.render();
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        test.assert_results(&["", "2"][..]);
-        // now define the variable 'apple'
-        test.paste("apple = 3");
+            test.assert_results(&["21", "3", "24"][..]);
+        }
 
-        test.assert_results(&["3", "6"][..]);
-    }
+        #[test]
+        fn test_that_sum_is_recalculated_if_anything_changes_above2() {
+            let test = create_app2(35);
+            test.paste("2\n3\n4 * sum");
+            test.set_cursor_row_col(0, 1);
 
-    #[test]
-    fn test_variables_can_be_defined_afterwards_of_their_usage2() {
-        let test = create_app2(35);
-        test.paste("apple asd * 2");
-        test.set_cursor_row_col(0, 0);
+            test.assert_results(&["2", "3", "20"][..]);
 
-        test.render();
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.input(EditorInputEvent::Up, InputModifiers::none());
+            // change value from 2 to 21
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
 
-        test.assert_results(&["", "2"][..]);
-        // now define the variable 'apple'
-        test.paste("apple asd = 3");
+            test.assert_results(&["21", "3", "96"][..]);
+        }
 
-        test.assert_results(&["3", "6"][..]);
-    }
+        #[test]
+        fn test_that_sum_is_not_recalculated_if_there_is_separator() {
+            let test = create_app2(35);
+            test.paste("2\n3\n#\n5\nsum");
+            test.set_cursor_row_col(0, 1);
 
-    #[test]
-    fn test_renaming_variable_declaration() {
-        let test = create_app2(35);
-        test.paste("apple = 2\napple * 3");
-        test.set_cursor_row_col(0, 0);
+            test.assert_results(&["2", "3", "", "5", "5"][..]);
 
-        test.assert_results(&["2", "6"][..]);
+            // change value from 2 to 12
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
 
-        // rename apple to aapple
-        test.input(EditorInputEvent::Char('a'), InputModifiers::none());
+            test.assert_results(&["21", "3", "", "5", "5"][..]);
+        }
 
-        test.assert_results(&["2", "3"][..]);
-    }
+        #[test]
+        fn test_that_sum_is_not_recalculated_if_there_is_separator_with_comment() {
+            let test = create_app2(35);
+            test.paste("2\n3\n# some comment\n5\nsum");
+            test.set_cursor_row_col(0, 1);
 
-    #[test]
-    fn test_moving_line_does_not_change_its_lineref() {
-        let test = create_app2(35);
-        test.paste("1\n2\n3\n\n\n50year");
-        // cursor is in 4th row
-        test.set_cursor_row_col(3, 0);
+            test.assert_results(&["2", "3", "", "5", "5"][..]);
 
-        test.assert_results(&["1", "2", "3", "", "", "50 year"][..]);
+            // change value from 2 to 12
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
 
-        // insert linref of 1st line
-        for _ in 0..3 {
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
+            test.assert_results(&["21", "3", "", "5", "5"][..]);
         }
-        test.alt_key_released();
-        test.render();
-        test.input(EditorInputEvent::Char('+'), InputModifiers::none());
 
-        // insert linref of 2st line
-        for _ in 0..2 {
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
+        #[test]
+        fn test_adding_sum_updates_lower_sums() {
+            let test = create_app2(35);
+            test.paste("2\n3\n\n4\n5\nsum\n# some comment\n24\n25\nsum");
+            test.set_cursor_row_col(2, 0);
+
+            test.assert_results(&["2", "3", "", "4", "5", "9", "", "24", "25", "49"][..]);
+
+            test.paste("sum");
+
+            test.assert_results(&["2", "3", "5", "4", "5", "19", "", "24", "25", "49"][..]);
         }
-        test.alt_key_released();
-        test.render();
-        test.input(EditorInputEvent::Char('+'), InputModifiers::none());
 
-        // insert linref of 3rd line
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
+        #[test]
+        fn test_updating_two_sums() {
+            let test = create_app2(35);
+            test.paste("2\n3\nsum\n4\n5\nsum\n# some comment\n24\n25\nsum");
+            test.set_cursor_row_col(0, 1);
 
-        match &test.tokens()[content_y(3)] {
-            Some(Tokens {
-                tokens,
-                shunting_output_stack: _,
-            }) => {
-                match tokens[0].typ {
-                    TokenType::LineReference { var_index } => assert_eq!(var_index, 0),
-                    _ => panic!(),
-                }
-                match tokens[2].typ {
-                    TokenType::LineReference { var_index } => assert_eq!(var_index, 1),
-                    _ => panic!(),
-                }
-                match tokens[4].typ {
-                    TokenType::LineReference { var_index } => assert_eq!(var_index, 2),
-                    _ => panic!(),
-                }
-            }
-            _ => {}
-        };
+            test.assert_results(&["2", "3", "5", "4", "5", "19", "", "24", "25", "49"][..]);
 
-        // insert a newline between the 1st and 2nd row
-        for _ in 0..3 {
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+            // change value from 2 to 21
+            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+
+            test.assert_results(&["21", "3", "24", "4", "5", "57", "", "24", "25", "49"][..]);
         }
+    }
 
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
+    #[test]
+    fn test_that_result_is_not_changing_if_tokens_change_before_it() {
+        let test = create_app2(35);
+        test.paste("111");
 
-        test.assert_results(&["1", "", "2", "3", "6", "", "50 year"][..]);
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
 
-        match &test.tokens()[content_y(4)] {
-            Some(Tokens {
-                tokens,
-                shunting_output_stack: _,
-            }) => {
-                match tokens[0].typ {
-                    TokenType::LineReference { var_index } => assert_eq!(var_index, 0),
-                    _ => panic!("{:?}", &tokens[0]),
-                }
-                match tokens[2].typ {
-                    TokenType::LineReference { var_index } => assert_eq!(var_index, 2),
-                    _ => panic!("{:?}", &tokens[2]),
-                }
-                match tokens[4].typ {
-                    TokenType::LineReference { var_index } => assert_eq!(var_index, 3),
-                    _ => panic!("{:?}", &tokens[4]),
-                }
+        let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        // expect no pulsing since there were no value change
+        for command in render_commands {
+            match command {
+                OutputMessage::PulsingRectangle { .. } => assert!(false, "{:?}", render_commands),
+                _ => {}
             }
-            _ => {}
-        };
+        }
     }
 
-    mod test_line_dependency_and_pulsing_on_change {
-        use super::super::*;
-        use super::*;
-        #[test]
-        fn test_modifying_a_lineref_recalcs_its_dependants() {
-            let test = create_app2(35);
-            test.paste("2\n * 3");
-            test.set_cursor_row_col(1, 0);
+    #[test]
+    fn test_variable_redefine() {
+        let test = create_app2(35);
+        test.paste("apple = 12");
+        test.render();
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.paste("apple + 2");
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.paste("apple = 0");
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.paste("apple + 3");
 
-            test.assert_results(&["2", "3"][..]);
+        test.assert_results(&["12", "14", "0", "3"][..]);
+    }
+
+    #[test]
+    fn test_backspace_bug_editor_obj_deletion_for_simple_tokens() {
+        let test = create_app2(35);
+        test.paste("asd sad asd asd sX");
+        test.render();
+        test.input(EditorInputEvent::Backspace, InputModifiers::none());
+        assert_eq!("asd sad asd asd s", test.get_editor_content());
+    }
 
-            // insert linref of 1st line
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
+    #[test]
+    fn test_rendering_while_cursor_move() {
+        let test = create_app2(35);
+        test.paste("apple = 12$\nasd q");
+        test.render();
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.render();
+    }
 
-            test.assert_results(&["2", "6"][..]);
+    #[test]
+    fn stepping_into_a_matrix_renders_it_some_lines_below() {
+        let test = create_app2(35);
+        test.paste("asdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 2);
+        test.render();
 
-            // now modify the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Home, InputModifiers::none());
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+        test.input(EditorInputEvent::Down, InputModifiers::none());
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        {
+            let editor_objects = test.editor_objects();
+            assert_eq!(editor_objects[content_y(0)].len(), 1);
+            assert_eq!(editor_objects[content_y(1)].len(), 1);
 
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_result_rect(
-                    test.get_render_data().result_gutter_x + RIGHT_GUTTER_WIDTH,
-                    0,
-                    2,
-                    1,
-                ),
+            assert_eq!(test.app().render_data.get_rendered_height(content_y(0)), 1);
+            assert_eq!(test.app().render_data.get_rendered_height(content_y(1)), 6);
+            assert_eq!(
+                test.get_render_data().get_render_y(content_y(0)),
+                Some(canvas_y(0))
             );
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_result_rect(
-                    test.get_render_data().result_gutter_x + RIGHT_GUTTER_WIDTH,
-                    1,
-                    2,
-                    1,
-                ),
+            assert_eq!(
+                test.get_render_data().get_render_y(content_y(1)),
+                Some(canvas_y(1))
             );
-
-            test.assert_results(&["12", "36"][..]);
         }
 
-        #[test]
-        fn test_that_dependant_line_refs_are_pulsed_on_change() {
-            let test = create_app2(35);
-            test.paste("2\n * 3");
-            test.set_cursor_row_col(1, 0);
-            test.render();
+        test.render();
 
-            // insert linref of 1st line
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
-            test.render();
+        let editor_objects = test.editor_objects();
+        assert_eq!(editor_objects[content_y(0)].len(), 1);
+        assert_eq!(editor_objects[content_y(1)].len(), 1);
+        assert_eq!(test.app().render_data.get_rendered_height(content_y(0)), 1);
+        assert_eq!(test.app().render_data.get_rendered_height(content_y(1)), 6);
+        assert_eq!(
+            test.get_render_data().get_render_y(content_y(0)),
+            Some(canvas_y(0))
+        );
+        assert_eq!(
+            test.get_render_data().get_render_y(content_y(1)),
+            Some(canvas_y(1))
+        );
+    }
 
-            // now modify the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Home, InputModifiers::none());
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+    #[test]
+    fn select_only_2_lines_render_bug() {
+        let test = create_app2(35);
+        test.paste("1\n2\n3");
+        test.render();
+        test.input(EditorInputEvent::Up, InputModifiers::shift());
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_changed_content_rect(LEFT_GUTTER_MIN_WIDTH, 1, 2, 1),
-            );
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        let expected_x = left_gutter_width + 4;
+        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 1, '⎫'));
+        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 2, '⎭'));
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderString(RenderStringMsg {
+                text: " ∑ = 5".to_owned(),
+                row: canvas_y(1),
+                column: expected_x,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_selection_overlay_shows_the_count_of_the_summed_lines() {
+        let test = create_app2(35);
+        test.paste("1\n\n// comment\n2\n3");
+        test.set_cursor_row_col(0, 0);
+        for _ in 0..4 {
+            test.input(EditorInputEvent::Down, InputModifiers::shift());
         }
+        test.input(EditorInputEvent::End, InputModifiers::shift());
 
-        #[test]
-        fn test_that_all_dependant_line_refs_in_same_row_are_pulsed_only_once_on_change() {
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        let sum_x = left_gutter_width + "// comment".len() + 3;
+        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderString(RenderStringMsg {
+                text: " ∑ = 6".to_owned(),
+                row: canvas_y(2),
+                column: sum_x,
+            }),
+        );
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderString(RenderStringMsg {
+                text: "n = 3".to_owned(),
+                row: canvas_y(2),
+                column: sum_x + " ∑ = 6".chars().count() + 1,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_selection_sum_respects_resets() {
+        fn selection_sum(text: &str, respect_resets: bool) -> Option<String> {
             let test = create_app2(35);
-            test.paste("2\n * 3");
-            test.set_cursor_row_col(1, 0);
-            test.render();
+            test.mut_app()
+                .set_selection_sum_respects_resets(respect_resets);
+            test.paste(text);
+            test.set_cursor_row_col(0, 0);
+            for _ in 0..4 {
+                test.input(EditorInputEvent::Down, InputModifiers::shift());
+            }
+            test.input(EditorInputEvent::End, InputModifiers::shift());
+            test.render_bucket().custom_commands[Layer::AboveText as usize]
+                .iter()
+                .find_map(|it| match it {
+                    OutputMessage::RenderString(RenderStringMsg { text, .. }) => Some(text.clone()),
+                    _ => None,
+                })
+        }
+        assert_eq!(
+            selection_sum("1\n2\n# header\n3\n4", false),
+            Some(" ∑ = 10".to_owned())
+        );
+        assert_eq!(
+            selection_sum("1\n2\n# header\n3\n4", true),
+            Some(" ∑ = 7".to_owned())
+        );
+        assert_eq!(
+            selection_sum("1\n2\n--\n3\n4", true),
+            Some(" ∑ = 7".to_owned())
+        );
+        assert_eq!(
+            selection_sum("1\n2\n  -- subtotal\n3\n4", true),
+            Some(" ∑ = 7".to_owned())
+        );
+    }
 
-            // insert linref of 1st line
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
-            test.render();
+    #[test]
+    fn test_selection_average() {
+        let test = create_app2(35);
+        test.mut_app().set_selection_average(true);
+        test.paste("1\n2\n\n3 // three\n4");
+        test.set_cursor_row_col(0, 0);
+        for _ in 0..4 {
+            test.input(EditorInputEvent::Down, InputModifiers::shift());
+        }
+        test.input(EditorInputEvent::End, InputModifiers::shift());
+        let texts: Vec<String> = test.render_bucket().custom_commands[Layer::AboveText as usize]
+            .iter()
+            .filter_map(|it| match it {
+                OutputMessage::RenderString(RenderStringMsg { text, .. }) => Some(text.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                " ∑ = 10".to_owned(),
+                "n = 4".to_owned(),
+                " avg = 2.5".to_owned()
+            ]
+        );
 
-            test.input(EditorInputEvent::Char(' '), InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
-            test.render();
+        test.mut_app().set_selection_average(false);
+        test.render();
+        assert_eq!(
+            test.render_bucket().custom_commands[Layer::AboveText as usize]
+                .iter()
+                .filter(|it| matches!(it, OutputMessage::RenderString(..)))
+                .count(),
+            2
+        );
+    }
 
-            // now modify the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Home, InputModifiers::none());
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+    #[test]
+    fn test_hex_matrix_result_columns_are_right_aligned() {
+        let test = create_app2(35);
+        test.paste("[511, 1; 2, 4096]");
+        test.mut_app().set_result_format(0, ResultFormat::Hex);
+        test.render();
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        let result_gutter_x = test.get_render_data().result_gutter_x;
+        let find_cell = |text: &str| {
+            test.render_bucket().custom_commands[Layer::Text as usize]
+                .iter()
+                .find_map(|it| match it {
+                    OutputMessage::RenderString(RenderStringMsg {
+                        text: t,
+                        row,
+                        column,
+                    }) if t == text && *column > result_gutter_x => Some((*row, *column)),
+                    _ => None,
+                })
+                .expect(text)
+        };
+        let (first_row_y, first_col_x) = find_cell("1 FF");
+        assert_eq!(find_cell("2"), (first_row_y.add(1), first_col_x + 3));
+        // the second column starts after "1 FF" and 2 spaces,
+        // and "1" is right-aligned to "10 00"
+        assert_eq!(find_cell("10 00"), (first_row_y.add(1), first_col_x + 6));
+        assert_eq!(find_cell("1"), (first_row_y, first_col_x + 6 + 4));
+    }
 
-            let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_changed_content_rect(left_gutter_width, 1, 2, 1),
-            );
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_changed_content_rect(left_gutter_width + 3, 1, 2, 1),
-            );
+    #[test]
+    fn test_assignment_display() {
+        let test = create_app2(35);
+        test.paste("x = 2 + 3\nx * 2");
+        test.render();
+        test.assert_results(&["5", "10"][..]);
 
-            // the last 2 command is for pulsing references for the active row
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(left_gutter_width, 1, 2, 1),
-            );
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(left_gutter_width + 3, 1, 2, 1),
-            );
-        }
+        test.mut_app()
+            .set_assignment_display(AssignmentDisplay::Hide);
+        test.render();
+        test.assert_results(&["", "10"][..]);
 
-        #[test]
-        fn test_that_all_dependant_line_refs_in_different_rows_are_pulsed_on_change() {
-            let test = create_app2(35);
-            test.paste("2\n * 3");
-            test.set_cursor_row_col(1, 0);
+        test.mut_app()
+            .set_assignment_display(AssignmentDisplay::WithVarName);
+        test.render();
+        test.assert_results(&["x = 5", "10"][..]);
 
-            test.render();
+        test.mut_app()
+            .set_assignment_display(AssignmentDisplay::Value);
+        test.render();
+        test.assert_results(&["5", "10"][..]);
+    }
 
-            // insert linref of 1st line
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
-            test.render();
+    #[test]
+    fn test_bitwise_complement_in_hex() {
+        let test = create_app2(35);
+        test.paste("~0xFF\n~0");
+        test.mut_app().set_result_format(0, ResultFormat::Hex);
+        test.mut_app().set_result_format(1, ResultFormat::Hex);
+        test.render();
+        test.assert_results(&["FF FF FF FF FF FF FF 00", "FF FF FF FF FF FF FF FF"][..]);
+    }
 
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
-            test.render();
+    #[test]
+    fn test_auto_format_row() {
+        let test = create_app2(35);
+        test.paste("255\n100\n1024\n255.5");
+        for row in 0..4 {
+            test.mut_app().auto_format_row(row, test.mut_results());
+        }
+        test.render();
+        test.assert_results(&["FF", "100", "400", "255.5"][..]);
+    }
 
-            // now modify the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Home, InputModifiers::none());
+    #[test]
+    fn test_thousands_separator() {
+        let test = create_app2(35);
+        test.paste("1000000\n-100\n-1234.5\n65535");
+        test.mut_app().set_result_format(3, ResultFormat::Hex);
+        test.render();
+        test.assert_results(&["1 000 000", "-100", "-1 234.5", "FF FF"][..]);
+
+        test.mut_app()
+            .set_thousands_separator(Some(ThousandsSeparator::Comma));
+        test.render();
+        test.assert_results(&["1,000,000", "-100", "-1,234.5", "FF FF"][..]);
+
+        test.mut_app().set_thousands_separator(None);
+        test.render();
+        test.assert_results(&["1000000", "-100", "-1234.5", "FF FF"][..]);
+    }
 
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+    #[test]
+    fn test_label_results_with_var_name() {
+        let test = create_app2(35);
+        test.paste("distance = 5 km\ndistance * 2\n\nt = 3 s");
+        test.mut_app().set_label_results_with_var_name(true);
+        test.render();
+        test.assert_results(&["distance: 5 km", "10 km", "", "t: 3 s"][..]);
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        test.mut_app().set_label_results_with_var_name(false);
+        test.render();
+        test.assert_results(&["5 km", "10 km", "", "3 s"][..]);
 
-            let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_changed_content_rect(left_gutter_width, 1, 2, 1),
-            );
+        // turning it off does not override the other modes
+        test.mut_app()
+            .set_assignment_display(AssignmentDisplay::Hide);
+        test.mut_app().set_label_results_with_var_name(false);
+        test.render();
+        test.assert_results(&["", "10 km", "", ""][..]);
+    }
 
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_changed_content_rect(left_gutter_width, 2, 2, 1),
-            );
-            // the last 2 command is for pulsing references for the active row
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(left_gutter_width, 1, 2, 1),
-            );
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(left_gutter_width, 2, 2, 1),
-            );
-        }
+    #[test]
+    fn test_repeating_decimal_notation() {
+        let test = create_app2(35);
+        test.mut_app().set_repeating_decimal_notation(true);
+        test.paste("1/3\n1/7\n1/6\n-2/3 - 1\n1/4\n0.3333\n10 m / 3\nsqrt(2)");
+        test.render();
+        let expected = [
+            "0.(3)",
+            "0.(142857)",
+            "0.1(6)",
+            "-1.(6)",
+            "0.25",
+            "0.3333",
+            "3.(3) m",
+        ];
+        for (row, expected) in expected.iter().enumerate() {
+            assert_eq!(test.app().get_rendered_result(row), Some(*expected));
+        }
+        // irrational numbers are not close enough to any fraction
+        assert!(!test.app().get_rendered_result(7).unwrap().contains('('));
+    }
 
-        #[test]
-        fn test_that_dependant_line_refs_are_pulsing_when_the_cursor_is_on_the_referenced_line() {
-            let test = create_app2(35);
-            test.paste("2\n * 3");
-            test.set_cursor_row_col(1, 0);
-            test.render();
+    #[test]
+    fn test_superscript_unit_exponents() {
+        let test = create_app2(35);
+        test.paste("3m * 4m\n2 m/s^2\n5 m");
+        test.render();
+        test.assert_results(&["12 m^2", "2 m / s^2", "5 m"][..]);
 
-            // insert linref of 1st line
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
-            test.render(); // it is needed
+        test.mut_app().set_superscript_unit_exponents(true);
+        test.render();
+        test.assert_results(&["12 m²", "2 m / s²", "5 m"][..]);
+        assert_eq!(test.app().get_rendered_result(0), Some("12 m²"));
+        let commands = &test.render_bucket().custom_commands[Layer::Text as usize];
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderString(RenderStringMsg {
+                text: "m²".to_owned(),
+                row: canvas_y(0),
+                column: test.get_render_data().result_gutter_x + RIGHT_GUTTER_WIDTH + 7,
+            }),
+        );
+    }
 
-            // there should not be pulsing here yet
-            test.assert_no_pulsing();
+    #[test]
+    fn test_result_row_separators() {
+        fn separator_rows(test: &BorrowCheckerFighter) -> Vec<usize> {
+            let gr = &test.app().render_data;
+            let separator_x = gr.result_gutter_x + RIGHT_GUTTER_WIDTH;
+            let separator_w = gr.current_result_panel_width;
+            test.render_bucket().custom_commands[Layer::BehindText as usize]
+                .iter()
+                .filter_map(|it| match it {
+                    OutputMessage::RenderRectangle { x, y, w, h }
+                        if *x == separator_x && *w == separator_w && *h == 1 =>
+                    {
+                        Some(y.as_usize())
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+        let test = create_app2(35);
+        test.paste("1\n2\n[1;2;3]\nabc\n4");
+        test.render();
+        assert!(separator_rows(&test).is_empty());
 
-            // step into the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.mut_app().set_result_row_separators(true);
+        test.render();
+        // the matrix takes 5 rows, only its last one gets a separator
+        assert_eq!(separator_rows(&test), &[0, 1, 6, 8]);
+    }
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+    #[test]
+    fn test_result_separator() {
+        let test = create_app2(35);
+        test.paste("1+2\n\nabc\n3");
+        test.render();
+        let commands = &test.render_bucket().custom_commands[Layer::Text as usize];
+        assert!(!commands
+            .iter()
+            .any(|it| matches!(it, OutputMessage::RenderString(..))));
 
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 1, 1),
-            );
-        }
+        test.mut_app().set_result_separator("→".to_owned());
+        test.render();
+        let commands = &test.render_bucket().custom_commands[Layer::Text as usize];
+        let separators: Vec<&RenderStringMsg> = commands
+            .iter()
+            .filter_map(|it| match it {
+                OutputMessage::RenderString(msg) => Some(msg),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            separators,
+            vec![
+                &RenderStringMsg {
+                    text: "→".to_owned(),
+                    row: canvas_y(0),
+                    column: LEFT_GUTTER_MIN_WIDTH + 4,
+                },
+                &RenderStringMsg {
+                    text: "→".to_owned(),
+                    row: canvas_y(3),
+                    column: LEFT_GUTTER_MIN_WIDTH + 2,
+                },
+            ]
+        );
+    }
 
-        #[test]
-        fn test_that_variable_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it() {
-            let test = create_app3(30, 30);
-            test.paste(
-                "b = 1
-aaaaaaaaaaaaaaaaaaaaaa b",
-            );
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+    #[test]
+    fn sum_popup_position_itself_if_there_is_not_enough_space() {
+        let test = create_app2(35);
+        test.paste("1\n2\n3");
+        test.render();
+        test.input(EditorInputEvent::Up, InputModifiers::shift());
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(
-                    "aaaaaaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
-                    1,
-                    1,
-                    1,
-                ),
-            );
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        let expected_x = left_gutter_width + 4;
+        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 1, '⎫'));
+        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 2, '⎭'));
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderString(RenderStringMsg {
+                text: " ∑ = 5".to_owned(),
+                row: canvas_y(1),
+                column: expected_x,
+            }),
+        );
+    }
 
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            // this step reduces the editor width
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            assert_eq!(test.get_render_data().current_editor_width, 23);
-            // if it is out of screen, it is rendered on the '...'
-            test.render(); // we currently need one render cycle to align widths
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(render_commands, 1, pulsing_ref_rect(25, 1, 1, 1));
+    #[test]
+    fn test_undoing_selection_removal_works() {
+        let test = create_app2(35);
+        test.paste(
+            "aaa
+bbb
+ccc
 
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            test.render(); // we currently need one render cycle to align widths
-            assert_eq!(test.get_render_data().current_editor_width, 22);
+ddd",
+        );
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.input(EditorInputEvent::End, InputModifiers::none());
+        test.handle_time(1000);
+        test.input(EditorInputEvent::PageUp, InputModifiers::shift());
+        test.handle_time(1000);
+        test.input(EditorInputEvent::Del, InputModifiers::none());
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
 
-            // if it is out of screen, it is rendered on the '...'
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(render_commands, 1, pulsing_ref_rect(24, 1, 1, 1));
-        }
+        let left_gutter_width = test.get_render_data().left_gutter_width;
+        test.assert_contains_text(1, |cmd| {
+            cmd.text == &['a', 'a', 'a']
+                && cmd.row == canvas_y(0)
+                && cmd.column == left_gutter_width
+        });
+        test.assert_contains_text(1, |cmd| {
+            cmd.text == &['b', 'b', 'b']
+                && cmd.row == canvas_y(1)
+                && cmd.column == left_gutter_width
+        });
+        test.assert_contains_text(1, |cmd| {
+            cmd.text == &['c', 'c', 'c']
+                && cmd.row == canvas_y(2)
+                && cmd.column == left_gutter_width
+        });
+        test.assert_contains_text(1, |cmd| {
+            cmd.text == &['d', 'd', 'd']
+                && cmd.row == canvas_y(4)
+                && cmd.column == left_gutter_width
+        });
+    }
 
-        #[test]
-        fn test_that_variable_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it_2() {
-            let test = create_app3(30, 30);
-            test.paste(
-                "bcdef = 1
-aaaaaaaaaaaaaaaaaa bcdef",
-            );
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+    #[test]
+    fn scroll_dragging_limit() {
+        let test = create_app2(35);
+        test.repeated_paste("1\n", 39);
+        test.render();
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(
-                    "aaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
-                    1,
-                    5,
-                    1,
-                ),
+        test.input(EditorInputEvent::PageUp, InputModifiers::none());
+        assert_eq!(test.get_render_data().scroll_y, 0);
+
+        test.click(test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH, 0);
+        for i in 0..5 {
+            test.handle_drag(
+                test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH,
+                1 + i,
             );
+            assert_eq!(test.get_render_data().scroll_y, 1 + i as usize);
+        }
+        test.handle_drag(test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH, 6);
+        // the scrollbar reached its bottom position, it won't go further down
+        assert_eq!(test.get_render_data().scroll_y, 5);
+    }
 
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            // this step reduces the editor width
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            assert_eq!(test.get_render_data().current_editor_width, 23);
-            // if it is out of screen, it is rendered on the '...'
-            test.render(); // we currently need one render cycle to align widths
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 5, 1));
-            test.assert_contains_variable(1, |cmd| {
-                cmd.text == &['b', 'c', 'd', 'e'] && cmd.row == canvas_y(1) && cmd.column == 21
-            });
+    #[test]
+    fn scroll_dragging_upwards() {
+        let test = create_app2(35);
+        test.repeated_paste("1\n", 39);
 
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            test.render(); // we currently need one render cycle to align widths
-            assert_eq!(test.get_render_data().current_editor_width, 22);
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 4, 1));
-            test.assert_contains_variable(1, |cmd| {
-                cmd.text == &['b', 'c', 'd'] && cmd.row == canvas_y(1) && cmd.column == 21
-            });
+        test.input(EditorInputEvent::PageUp, InputModifiers::none());
+        test.click(test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH, 0);
 
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            test.render(); // we currently need one render cycle to align widths
-            assert_eq!(test.get_render_data().current_editor_width, 20);
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 2, 1));
-            test.assert_contains_variable(1, |cmd| {
-                cmd.text == &['b'] && cmd.row == canvas_y(1) && cmd.column == 21
-            });
+        assert_eq!(test.get_render_data().scroll_y, 0);
 
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            test.render(); // we currently need one render cycle to align widths
-            assert_eq!(test.get_render_data().current_editor_width, 19);
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(render_commands, 1, pulsing_ref_rect(21, 1, 1, 1));
-            test.assert_contains_variable(0, |cmd| cmd.row == canvas_y(1) && !cmd.text.is_empty());
+        for i in 0..5 {
+            test.handle_drag(
+                test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH,
+                1 + i,
+            );
+            assert_eq!(test.get_render_data().scroll_y, 1 + i as usize);
         }
-
-        #[test]
-        fn test_that_lineref_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it() {
-            let test = create_app3(30, 30);
-            test.paste(
-                "1
-aaaaaaaaaaaaaaaaaaaaaa &[1]",
+        for i in 0..5 {
+            test.handle_drag(
+                test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH,
+                4 - i,
             );
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+            assert_eq!(test.get_render_data().scroll_y, 4 - i as usize);
+        }
+    }
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(
-                    "aaaaaaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
-                    1,
-                    1,
-                    1,
-                ),
-            );
+    #[test]
+    fn clicking_behind_matrix_should_move_the_cursor_there() {
+        let test = create_app2(35);
 
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            // this step reduces the editor width
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            assert_eq!(test.get_render_data().current_editor_width, 23);
-            // should appear
-            // if it is out of screen, it is rendered on the '...'
-            test.render(); // we currently need one render cycle to align widths
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                OutputMessage::RenderChar(test.get_render_data().result_gutter_x - 1, 1, '…'),
-            );
-            assert_contains(render_commands, 1, pulsing_ref_rect(25, 1, 1, 1));
+        test.paste("firs 1t\nasdsad\n[1;2;3;4]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        assert_eq!(test.get_cursor_pos().row, 0);
+        let left_gutter_width = 1;
+        test.click(left_gutter_width + 50, 13);
+        assert_eq!(test.get_cursor_pos().row, 5);
+    }
 
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
-            test.render(); // we currently need one render cycle to align widths
-            assert_eq!(test.get_render_data().current_editor_width, 22);
+    #[test]
+    fn clicking_inside_matrix_while_selected_should_put_cursor_after_matrix() {
+        let test = create_app2(35);
+        test.paste("firs 1t\nasdsad\n[1;2;3;4]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        // select all
+        test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
+        test.render();
 
-            // if it is out of screen, it is rendered on the '...'
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(render_commands, 1, pulsing_ref_rect(24, 1, 1, 1));
-        }
+        // click inside the real matrix repr
+        // the problem is that this click is inside a SimpleToken (as the matrix is rendered as
+        // SimpleToken if it is selected), so the cursor is set accordingly,
+        // but as soon as the selection is cancelled by the click, we render a matrix,
+        // and the cursor is inside the matrix, which is not OK.
+        let left_gutter_width = 1;
+        test.click(left_gutter_width + 7, 2);
 
-        #[test]
-        fn test_that_lineref_pulsing_appears_at_edge_of_editor_if_it_is_outside_of_it_2() {
-            let test = create_app3(30, 30);
-            test.paste(
-                "1
-aaaaaaaaaaaaaaaaaaaa &[1]",
-            );
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+        // typing should append after the matrix
+        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
+        assert_eq!(
+            "firs 1t\nasdsad\n[1;2;3;4]X\nfirs 1t\nasdsad\n[1;2;3;4]",
+            test.get_editor_content()
+        );
+    }
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(
-                    "aaaaaaaaaaaaaaaaaaaa".len() + LEFT_GUTTER_MIN_WIDTH + " ".len(),
-                    1,
-                    1,
-                    1,
-                ),
+    #[test]
+    fn limiting_cursor_does_not_kill_selection() {
+        let test = create_app2(35);
+
+        test.repeated_paste("1\n", MAX_LINE_COUNT + 1);
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        test.input(EditorInputEvent::PageDown, InputModifiers::shift());
+        test.render();
+        assert_eq!(
+            test.get_selection().is_range(),
+            Some((
+                Pos::from_row_column(0, 0),
+                Pos::from_row_column(MAX_LINE_COUNT + 1, 0)
+            ))
+        );
+    }
+
+    #[test]
+    fn deleting_all_selected_lines_no_panic() {
+        let test = create_app2(35);
+        test.repeated_paste("1\n", MAX_LINE_COUNT + 20);
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
+        test.render();
+        test.input(EditorInputEvent::Del, InputModifiers::ctrl());
+    }
+
+    #[test]
+    fn test_setting_left_gutter_width() {
+        // future proof test
+        let test = create_app2(35);
+        test.paste("");
+        for i in 0..MAX_LINE_COUNT {
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            let rendered_line_num = i + 2;
+            let expected_w = format!("{}", rendered_line_num).len() + 1;
+            assert_eq!(
+                test.get_render_data().left_gutter_width,
+                expected_w,
+                "at line {}. the left gutter width should be {}",
+                rendered_line_num,
+                expected_w
             );
+        }
+    }
 
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            {
-                // this step reduces the editor width
-                test.input(EditorInputEvent::Char('2'), InputModifiers::none());
-                test.input(EditorInputEvent::Char('3'), InputModifiers::none());
-                assert_eq!(test.get_render_data().current_editor_width, 22);
-                // if it is out of screen, it is rendered on the '...'
-                test.render(); // we currently need one render cycle to align widths
-                let render_commands =
-                    &test.render_bucket().custom_commands[Layer::AboveText as usize];
-                assert_contains(
-                    render_commands,
-                    1,
-                    OutputMessage::RenderChar(test.get_render_data().result_gutter_x - 1, 1, '…'),
-                );
-                assert_contains(render_commands, 1, pulsing_ref_rect(23, 1, 2, 1));
-                test.assert_contains_line_ref_result(1, |cmd| {
-                    cmd.text == "1".to_owned() && cmd.row == canvas_y(1) && cmd.column == 23
-                });
-            }
+    #[test]
+    fn click_into_a_row_with_matrix_put_the_cursor_after_the_rendered_matrix() {
+        let test = create_app2(35);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        assert_eq!(test.get_cursor_pos().column, 0);
 
-            {
-                test.input(EditorInputEvent::Char('4'), InputModifiers::none());
-                test.render(); // we currently need one render cycle to align widths
-                assert_eq!(test.get_render_data().current_editor_width, 20);
-                let render_commands =
-                    &test.render_bucket().custom_commands[Layer::AboveText as usize];
-                assert_contains(render_commands, 1, pulsing_ref_rect(22, 1, 1, 1));
-                test.assert_contains_line_ref_result(0, |cmd| {
-                    !cmd.text.is_empty() && cmd.row == canvas_y(1)
-                });
-                assert_contains(
-                    render_commands,
-                    1,
-                    OutputMessage::RenderChar(test.get_render_data().result_gutter_x - 1, 1, '…'),
-                );
-            }
+        let left_gutter_width = 1;
+        for i in 0..5 {
+            test.click(left_gutter_width + 13 + i, 5);
+            assert_eq!(test.get_cursor_pos().column, 25);
         }
+    }
 
-        #[test]
-        fn test_that_multiple_dependant_line_refs_are_pulsed_when_the_cursor_is_on_the_referenced_line(
-        ) {
-            let test = create_app2(35);
-            test.paste("2\n * 3");
-            test.set_cursor_row_col(1, 0);
-            test.render();
+    #[test]
+    fn clicking_into_matrices_panic() {
+        let test = create_app2(35);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        // click into 1st matrix to edit it
+        let left_gutter_width = 1;
+        test.click(left_gutter_width + 1, 5);
+        test.render();
+        // write 333 into the first cell
+        for _ in 0..3 {
+            test.input(EditorInputEvent::Char('3'), InputModifiers::none());
+        }
+        test.render();
+        // click into 2nd matrix
+        test.click(left_gutter_width + 1, 15);
+        test.render();
+        // click back into 1nd matrix
+        test.click(left_gutter_width + 1, 5);
+        test.render();
+    }
 
-            // insert linref of 1st line
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
-            test.input(EditorInputEvent::Char(' '), InputModifiers::alt());
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
+    #[test]
+    fn leaving_matrix_by_clicking_should_trigger_reevaluation() {
+        let test = create_app2(35);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        // click into 1st matrix to edit it
+        let left_gutter_width = 1;
+        test.click(left_gutter_width + 1, 5);
+        test.render();
+        // write 333 into the first cell
+        for _ in 0..3 {
+            test.input(EditorInputEvent::Char('3'), InputModifiers::none());
+        }
+        test.render();
+        // click into 2nd matrix
+        test.click(left_gutter_width + 1, 15);
+        test.render();
+        assert_eq!(test.editor_objects()[content_y(2)][0].rendered_w, 8);
+    }
 
-            // requires so the pulsings caused by the changes above are consumed
-            test.render();
+    #[test]
+    fn click_into_a_matrix_start_mat_editing() {
+        let test = create_app2(35);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        let left_gutter_width = 1;
+        test.click(left_gutter_width + 1, 5);
+        assert!(test.app().matrix_editing.is_some());
+    }
 
-            // there should not be pulsing here yet
-            test.assert_no_pulsing();
+    #[test]
+    fn mouse_selecting_moving_mouse_out_of_editor() {
+        let test = create_app2(35);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
+        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
+        test.click(left_gutter_width + 7, 0);
+        test.handle_drag(0, 0);
+        assert_eq!(
+            test.get_selection().is_range(),
+            Some((Pos::from_row_column(0, 0), Pos::from_row_column(0, 7)))
+        );
+    }
 
-            // step into the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+    #[test]
+    fn test_dragging_right_gutter_panic() {
+        let test = create_app2(35);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        let orig_x = test.get_render_data().result_gutter_x;
+        test.click(test.get_render_data().result_gutter_x, 0);
 
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 1, 1),
-            );
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH + 2, 1, 1, 1),
-            );
+        for i in 1..=orig_x {
+            test.handle_drag(orig_x - i, 0);
         }
+    }
 
-        #[test]
-        fn test_that_multiple_dependant_vars_are_pulsed_when_the_cursor_is_on_the_definition_line()
-        {
-            let test = create_app2(35);
-            test.paste("var = 2\nvar * 3\n12 * var");
-            test.set_cursor_row_col(1, 0);
-            test.render();
-
-            // there should not be pulsing here yet
-            test.assert_no_pulsing();
-
-            // step into the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+    #[test]
+    fn test_editor_width_stays_positive_when_dragging_gutter_to_the_left_edge() {
+        let test = create_app3(20, 35);
+        test.paste("123456789012345 * 123456789\n1");
+        test.render();
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 3, 1),
-            );
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH + 5, 2, 3, 1),
-            );
-        }
+        test.click(test.get_render_data().result_gutter_x, 0);
+        test.handle_drag(0, 0);
+        test.render();
+        let gr = test.get_render_data();
+        assert!(gr.result_gutter_x > gr.left_gutter_width);
+        assert!(gr.current_editor_width > 0);
 
-        #[test]
-        fn test_that_dependant_vars_are_pulsed_when_the_cursor_is_on_the_definition_line() {
-            let test = create_app2(35);
-            test.paste("var = 2\nvar * 3");
-            test.set_cursor_row_col(1, 0);
-            test.render();
+        let mut gr = GlobalRenderData::new(1024, 1000, 1024 / 2, LEFT_GUTTER_MIN_WIDTH, 2);
+        gr.set_result_gutter_x(1024, 0);
+        assert_eq!(gr.result_gutter_x, LEFT_GUTTER_MIN_WIDTH + 2);
+        assert_eq!(gr.current_editor_width, 1);
+    }
 
-            // there should not be pulsing here yet
-            test.assert_no_pulsing();
+    #[test]
+    fn test_small_right_gutter_panic() {
+        let test = create_app3(20, 35);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
+        test.render();
 
-            // step into the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+        let orig_x = test.get_render_data().result_gutter_x;
+        test.click(test.get_render_data().result_gutter_x, 0);
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            assert_contains(
-                render_commands,
-                1,
-                pulsing_ref_rect(LEFT_GUTTER_MIN_WIDTH, 1, 3, 1),
-            );
+        for i in 1..=orig_x {
+            test.handle_drag(orig_x - i, 0);
         }
     }
 
     #[test]
-    fn test_modifying_a_lineref_does_not_change_the_line_id() {
+    fn bug_selection_rectangle_is_longer_than_the_selected_row() {
         let test = create_app2(35);
-        test.paste("2\n3\n");
-        test.set_cursor_row_col(2, 0);
+        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
+        test.set_cursor_row_col(0, 0);
         test.render();
-        // insert linref of 1st line
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
+        let left_gutter_width = 2;
+        test.click(left_gutter_width + 4, 0);
         test.render();
 
-        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
-        test.render();
-        test.input(EditorInputEvent::Char('*'), InputModifiers::none());
-        test.render();
-        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
+        test.handle_drag(left_gutter_width + 0, 1);
+
+        let render_buckets = test.render_bucket();
+        let pos = test
+            .render_bucket()
+            .custom_commands(Layer::BehindText)
+            .iter()
+            .position(|it| matches!(it, OutputMessage::SetColor(0xA6D2FF_FF)))
+            .expect("there is no selection box drawing");
+        assert_eq!(
+            render_buckets.custom_commands(Layer::BehindText)[pos + 1],
+            OutputMessage::RenderRectangle {
+                x: left_gutter_width + 4,
+                y: canvas_y(0),
+                w: 3,
+                h: 1
+            }
+        );
+        assert_eq!(
+            render_buckets.custom_commands(Layer::BehindText)[pos + 2],
+            OutputMessage::RenderRectangle {
+                x: left_gutter_width,
+                y: canvas_y(1),
+                w: 0,
+                h: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_handling_too_much_rows_no_panic() {
+        let test = create_app2(35);
+        test.paste(&("1\n".repeat(MAX_LINE_COUNT - 1).to_owned()));
+        test.set_cursor_row_col(MAX_LINE_COUNT - 2, 1);
+
         test.render();
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+    }
 
-        // insert linref of 2st line
-        test.input(EditorInputEvent::Up, InputModifiers::alt());
-        test.alt_key_released();
+    #[test]
+    fn test_enter_adds_lines_beyond_max_line_count() {
+        let test = create_app2(35);
+        test.paste(&("1\n".repeat(MAX_LINE_COUNT - 1) + "1"));
 
-        test.assert_results(&["2", "3", "6"][..]);
+        test.set_cursor_row_col(MAX_LINE_COUNT - 1, 1);
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        test.input(EditorInputEvent::Char('2'), InputModifiers::none());
+        assert_eq!(
+            test.get_editor_content(),
+            "1\n".repeat(MAX_LINE_COUNT) + "2"
+        );
+        assert_eq!(
+            test.get_cursor_pos(),
+            Pos::from_row_column(MAX_LINE_COUNT, 1)
+        );
+        assert_eq!(test.app().get_rendered_result(MAX_LINE_COUNT), Some("2"));
+    }
 
-        // now modify the 2nd row
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        test.input(EditorInputEvent::Home, InputModifiers::none());
-        test.input(EditorInputEvent::Del, InputModifiers::none());
+    #[test]
+    fn test_enter_is_refused_at_the_line_limit() {
+        let test = create_app2(35);
+        test.mut_app().set_line_limit(Some(MAX_LINE_COUNT));
+        test.paste(&("1\n".repeat(MAX_LINE_COUNT - 1) + "1"));
+        assert!(!test.app().is_truncated());
+        let content = test.get_editor_content();
 
-        test.assert_results(&["2", "Err", ""][..]);
+        test.set_cursor_row_col(MAX_LINE_COUNT - 1, 1);
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        assert_eq!(test.get_editor_content(), content);
+        assert_eq!(
+            test.get_cursor_pos(),
+            Pos::from_row_column(MAX_LINE_COUNT - 1, 1)
+        );
 
-        test.input(EditorInputEvent::Char('4'), InputModifiers::none());
+        test.set_cursor_row_col(5, 0);
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        assert_eq!(test.get_editor_content(), content);
 
-        test.assert_results(&["2", "4", "8"][..]);
+        // a multiline selection is removed, so there is room for the new line
+        test.set_selection(Selection::range(
+            Pos::from_row_column(5, 0),
+            Pos::from_row_column(7, 0),
+        ));
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        assert_eq!(
+            test.get_editor_content().lines().count(),
+            MAX_LINE_COUNT - 1
+        );
+        assert!(!test.app().is_truncated());
+
+        test.mut_app().set_line_limit(Some(100));
+        assert!(test.app().is_truncated());
     }
 
-    mod dependent_lines_recalculation_tests {
-        use super::super::*;
-        use super::*;
+    #[test]
+    fn test_lines_beyond_max_line_count_are_evaluated_in_a_scrolled_window() {
+        let test = create_app2(35);
+        test.paste(&(0..200).map(|i| format!("{}\n", i)).collect::<String>());
+        test.input(EditorInputEvent::PageUp, InputModifiers::none());
+        assert_eq!(test.get_render_data().window_y(), 0);
+        assert_eq!(test.app().get_rendered_result(180), None);
 
-        #[test]
-        fn test_modifying_a_lineref_recalcs_its_dependants_only_if_its_value_has_changed() {
-            let test = create_app2(35);
-            test.paste("2\n * 3");
-            test.set_cursor_row_col(1, 0);
+        test.input(EditorInputEvent::PageDown, InputModifiers::none());
+        assert!(test.get_render_data().window_y() > 0);
+        test.set_cursor_row_col(180, 3);
+        test.input(EditorInputEvent::Char('+'), InputModifiers::none());
+        test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+        assert!(test
+            .get_editor_content()
+            .starts_with(&(0..180).map(|i| format!("{}\n", i)).collect::<String>()));
+        assert_eq!(test.get_editor_content().lines().nth(180), Some("180+1"));
+        assert_eq!(test.app().get_rendered_result(180), Some("181"));
+        assert_eq!(
+            test.app()
+                .get_result_string(180, test.units(), test.mut_results(), test.mut_vars()),
+            Some("181".to_owned())
+        );
 
-            test.assert_results(&["2", "3"][..]);
+        // the line numbers are the indices of the lines in the document
+        let line_numbers: Vec<&String> = test.render_bucket().custom_commands[Layer::Text as usize]
+            .iter()
+            .filter_map(|it| match it {
+                OutputMessage::RenderString(RenderStringMsg { text, .. }) => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert!(line_numbers.iter().any(|it| *it == "181"));
 
-            // insert linref of 1st line
-            test.input(EditorInputEvent::Up, InputModifiers::alt());
-            test.alt_key_released();
+        // the references inside the window work as well
+        test.set_cursor_row_col(190, 3);
+        for ch in "+ans[185]".chars() {
+            test.input(EditorInputEvent::Char(ch), InputModifiers::none());
+        }
+        assert_eq!(test.app().get_rendered_result(190), Some("374"));
+    }
 
-            test.assert_results(&["2", "6"][..]);
+    #[test]
+    fn test_the_definitions_above_the_window_are_visible_in_it() {
+        let test = create_app2(35);
+        test.paste(&("a = 5\nf(x) = x * 2\n\n".to_owned() + &"1\n".repeat(200) + "sum\na + f(3)"));
+        // the whole document is exported, not only the window
+        assert!(test
+            .app()
+            .export_csv(test.units(), test.mut_vars())
+            .ends_with("204,sum,200,\n205,a + f(3),11,\n"));
+
+        test.set_cursor_row_col(204, 8);
+        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
+        assert!(test.get_render_data().window_y() > 3);
+        assert_eq!(test.app().get_rendered_result(203), Some("200"));
+        assert_eq!(test.app().get_rendered_result(204), Some("11"));
 
-            // now modify the first row
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::End, InputModifiers::none());
-            test.render();
-            // inserting a '.' does not modify the result of the line
-            test.input(EditorInputEvent::Char('.'), InputModifiers::none());
+        // a definition inside the window overrides the one above it
+        test.set_cursor_row_col(203, 3);
+        test.input(EditorInputEvent::Enter, InputModifiers::none());
+        for ch in "a = 10".chars() {
+            test.input(EditorInputEvent::Char(ch), InputModifiers::none());
+        }
+        assert_eq!(test.app().get_rendered_result(205), Some("16"));
+    }
 
-            let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-            // expect no pulsing since there were no value change
-            assert_contains(
-                render_commands,
-                0,
-                pulsing_changed_content_rect(90, 0, 2, 1),
-            );
-            assert_contains(
-                render_commands,
-                0,
-                pulsing_changed_content_rect(90, 1, 2, 1),
-            );
+    #[test]
+    fn inserting_too_many_rows_no_panic() {
+        let test = create_app2(35);
+        test.paste("");
+        test.set_cursor_row_col(0, 0);
 
-            test.assert_results(&["2", "6"][..]);
+        for _ in 0..20 {
+            test.paste("1\n2\n3\n4\n5\n6\n7\n8\n9\n0");
+            test.render();
+            test.input(EditorInputEvent::Enter, InputModifiers::none());
+            test.render();
         }
+        test.input(EditorInputEvent::Up, InputModifiers::none());
+        test.input(EditorInputEvent::PageDown, InputModifiers::none());
+    }
 
-        #[test]
-        fn test_renaming_variable_declaration2() {
+    #[test]
+    fn test_sum_rerender() {
+        // rust's borrow checker forces me to do this
+        {
             let test = create_app2(35);
-            test.paste("apple = 2\naapple * 3");
-            test.set_cursor_row_col(0, 0);
-
-            test.assert_results(&["2", "3"][..]);
-
-            // rename apple to aapple
-            test.input(EditorInputEvent::Char('a'), InputModifiers::none());
+            test.paste("1\n2\n3\nsum");
 
-            test.assert_results(&["2", "6"][..]);
+            test.assert_results(&["1", "2", "3", "6"][..]);
         }
-
-        #[test]
-        fn test_removing_variable_declaration() {
+        {
             let test = create_app2(35);
-            test.paste("apple = 2\napple * 3");
-            test.set_cursor_row_col(0, 0);
+            test.paste("1\n2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-            test.assert_results(&["2", "6"][..]);
+            test.assert_results(&["1", "2", "3", "6"][..]);
+        }
+        {
+            let test = create_app2(35);
+            test.paste("1\n2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-            // remove the content of the first line
-            test.input(EditorInputEvent::End, InputModifiers::shift());
+            test.assert_results(&["1", "2", "3", "6"][..]);
+        }
+        {
+            let test = create_app2(35);
+            test.paste("1\n2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Down, InputModifiers::none());
 
-            test.input(EditorInputEvent::Del, InputModifiers::none());
+            test.assert_results(&["1", "2", "3", "6"][..]);
+        }
+        {
+            let test = create_app2(35);
+            test.paste("1\n2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Down, InputModifiers::none());
+            test.input(EditorInputEvent::Down, InputModifiers::none());
 
-            test.assert_results(&["", "3"][..]);
+            test.assert_results(&["1", "2", "3", "6"][..]);
         }
+    }
 
-        #[test]
-        fn test_that_variable_dependent_rows_are_recalculated() {
+    #[test]
+    fn test_sum_rerender_with_ignored_lines() {
+        {
             let test = create_app2(35);
-            test.paste("apple = 2\napple * 3");
-            test.set_cursor_row_col(0, 9);
+            test.paste("1\n'2\n3\nsum");
 
-            test.assert_results(&["2", "6"][..]);
+            test.assert_results(&["1", "3", "4"][..]);
+        }
+        {
+            let test = create_app2(35);
+            test.paste("1\n'2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-            // change value of 'apple' from 2 to 24
-            test.input(EditorInputEvent::Char('4'), InputModifiers::none());
+            test.assert_results(&["1", "3", "4"][..]);
+        }
+        {
+            let test = create_app2(35);
+            test.paste("1\n'2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-            test.assert_results(&["24", "72"][..]);
+            test.assert_results(&["1", "3", "4"][..]);
         }
+        {
+            let test = create_app2(35);
+            test.paste("1\n'2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Down, InputModifiers::none());
 
-        #[test]
-        fn test_that_sum_is_recalculated_if_anything_changes_above() {
+            test.assert_results(&["1", "3", "4"][..]);
+        }
+        {
             let test = create_app2(35);
-            test.paste("2\n3\nsum");
-            test.set_cursor_row_col(0, 1);
+            test.paste("1\n'2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
+            test.input(EditorInputEvent::Down, InputModifiers::none());
 
-            test.assert_results(&["2", "3", "5"][..]);
+            test.assert_results(&["1", "3", "4"][..]);
+        }
+    }
 
-            // change value from 2 to 21
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+    #[test]
+    fn test_sum_rerender_with_sum_reset() {
+        {
+            let test = create_app2(35);
+            test.paste("1\n#2\n3\nsum");
 
-            test.assert_results(&["21", "3", "24"][..]);
+            test.assert_results(&["1", "3", "3"][..]);
         }
-
-        #[test]
-        fn test_that_sum_is_recalculated_if_anything_changes_above2() {
+        {
             let test = create_app2(35);
-            test.paste("2\n3\n4 * sum");
-            test.set_cursor_row_col(0, 1);
+            test.paste("1\n#2\n3\nsum");
+            test.input(EditorInputEvent::Up, InputModifiers::none());
 
-            test.assert_results(&["2", "3", "20"][..]);
+            test.assert_results(&["1", "3", "3"][..]);
+        }
+    }
 
-            // change value from 2 to 21
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+    #[test]
+    fn test_sum_can_be_disabled() {
+        let test = create_app2(35);
+        test.mut_app().set_sum_enabled(false);
+        test.paste("1\n2\nsum = 5\nsum * 2");
 
-            test.assert_results(&["21", "3", "96"][..]);
-        }
+        test.assert_results(&["1", "2", "5", "10"][..]);
+    }
 
-        #[test]
-        fn test_that_sum_is_not_recalculated_if_there_is_separator() {
-            let test = create_app2(35);
-            test.paste("2\n3\n#\n5\nsum");
-            test.set_cursor_row_col(0, 1);
+    #[test]
+    fn test_keyword_aliases() {
+        let test = create_app2(35);
+        test.mut_app().set_keyword_aliases(&[("nach", "in")]);
+        test.paste("1 km nach m\n2 h nach min\nnachher = 3\nnachher * 2");
+        test.assert_results(&["1 000 m", "120 min", "3", "6"][..]);
+    }
 
-            test.assert_results(&["2", "3", "", "5", "5"][..]);
+    #[test]
+    fn test_external_var() {
+        let test = create_app2(35);
+        test.mut_app().set_external_var("rate", "5%");
+        test.paste("rate = 10%\n200 + rate\n200 + rate2");
+        test.assert_results(&["10 %", "210", "200"][..]);
 
-            // change value from 2 to 12
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+        test.mut_app().set_external_var("rate", "50%");
+        test.mut_app().recalculate_from_row(
+            1,
+            test.allocator(),
+            test.units(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+        test.assert_results(&["10 %", "300", "200"][..]);
+    }
 
-            test.assert_results(&["21", "3", "", "5", "5"][..]);
-        }
+    #[test]
+    fn test_internal_scale() {
+        let test = create_app2(35);
+        test.mut_app().set_internal_scale(4);
+        test.paste("a = 1/7\na * 7\n2/3 m\n[1/3, 2/3]");
+        test.assert_results(&["0.1429", "1.0003", "0.6667 m", "[0.3333, 0.6667]"][..]);
 
-        #[test]
-        fn test_that_sum_is_not_recalculated_if_there_is_separator_with_comment() {
-            let test = create_app2(35);
-            test.paste("2\n3\n# some comment\n5\nsum");
-            test.set_cursor_row_col(0, 1);
+        // quantities are rounded in their own unit, not in the base unit
+        let test = create_app2(35);
+        test.mut_app().set_internal_scale(2);
+        test.paste("1.234 mm\n3.219 km\n1.234 mm * 2");
+        test.assert_results(&["1.23 mm", "3.22 km", "2.47 mm"][..]);
 
-            test.assert_results(&["2", "3", "", "5", "5"][..]);
+        // the maximum scale of Decimal
+        test.mut_app().set_internal_scale(50);
+        assert_eq!(test.app().internal_scale, 28);
+    }
 
-            // change value from 2 to 12
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+    #[test]
+    fn test_user_defined_functions() {
+        let test = create_app2(35);
+        test.paste("f(x) = x * 2 + 1\nf(10)\nf(2) * f(3)\nf(f(1))\ny = f(1 + 1)\ny");
+        test.assert_results(&["", "21", "35", "7", "5", "5"][..]);
 
-            test.assert_results(&["21", "3", "", "5", "5"][..]);
-        }
+        let test = create_app2(35);
+        test.paste("a = 3\ng(x, y) = x * y + a\nh() = g(2, 5) * 1 m\ng(2, 1)\nh()\na * 2");
+        test.assert_results(&["3", "", "", "5", "13 m", "6"][..]);
 
-        #[test]
-        fn test_adding_sum_updates_lower_sums() {
-            let test = create_app2(35);
-            test.paste("2\n3\n\n4\n5\nsum\n# some comment\n24\n25\nsum");
-            test.set_cursor_row_col(2, 0);
+        // the arguments are bound when the body is evaluated, it is not copied for every call
+        let test = create_app2(35);
+        test.paste("f(x) = x + x\ng(x) = f(f(f(f(f(f(f(f(x))))))))\nh(x) = g(g(g(x)))\nh(1)");
+        test.assert_results(&["", "", "", "16777216"][..]);
+        let app = test.app();
+        assert_eq!(
+            app.evaluate_line("h(1) + f(1)", test.units(), test.mut_vars())
+                .map(|it| it.typ),
+            Some(CalcResultType::Number(crate::calc::dec(16777218)))
+        );
+    }
 
-            test.assert_results(&["2", "3", "", "4", "5", "14", "", "24", "25", "49"][..]);
+    #[test]
+    fn test_summation() {
+        let test = create_app2(35);
+        test.paste(
+            "sum(i, 1, 5, i^2)\nn = 3\nsum(k, 1, n, k * 2 m)\nsum(i, 1, 3, sum(j, 1, i, j))\nsum(i, 1, 2.5, i)\nsum(i, 3, 1, i)\nsum([1, 2, 3])",
+        );
+        test.assert_results(&["55", "3", "12 m", "10", "Err", "0", "6"][..]);
 
-            test.paste("sum");
+        // the bound name hides the variables, and the body is evaluated for every term
+        // instead of being copied
+        let test = create_app2(35);
+        test.paste("i = 100\nf(x) = sum(i, 1, x, i * x)\nf(3)\nsum(i, 1, 1000, i) + i");
+        test.assert_results(&["100", "", "18", "500600"][..]);
+    }
 
-            test.assert_results(&["2", "3", "5", "4", "5", "19", "", "24", "25", "49"][..]);
-        }
+    #[test]
+    fn test_solve() {
+        let test = create_app2(35);
+        test.paste(
+            "solve(2*x + 3 = 11, x)\na = 4\ny = solve(a * (t - 1) = t + 2, t)\ny * 7\nsolve(x^2 = 4, x)\nsolve(3 = 3, x)",
+        );
+        test.assert_results(&["4", "4", "2", "14", "Err", "Err"][..]);
 
-        #[test]
-        fn test_updating_two_sums() {
-            let test = create_app2(35);
-            test.paste("2\n3\nsum\n4\n5\nsum\n# some comment\n24\n25\nsum");
-            test.set_cursor_row_col(0, 1);
+        // the variable is bound in the parsed equation, next to the other bound names
+        let test = create_app2(35);
+        test.paste("g(c) = solve(2*x = c, x)\ng(10)\nsolve(x + sum(i, 1, 3, i) = 10, x)");
+        test.assert_results(&["", "5", "4"][..]);
+    }
 
-            test.assert_results(&["2", "3", "5", "4", "5", "19", "", "24", "25", "49"][..]);
+    #[test]
+    fn test_register_function() {
+        let test = create_app2(35);
+        test.mut_app().register_function(
+            "double",
+            1,
+            Box::new(|args: &[CalcResult]| {
+                let two = CalcResult::new(CalcResultType::Number(Decimal::from(2)), 0);
+                crate::calc::multiply_op(&args[0], &two)
+            }),
+        );
+        test.mut_app().register_function(
+            "answer",
+            0,
+            Box::new(|_: &[CalcResult]| {
+                Some(CalcResult::new(
+                    CalcResultType::Number(Decimal::from(42)),
+                    0,
+                ))
+            }),
+        );
+        test.paste(
+            "double(21)\na = 2.5\ndouble(a) * 3\ndouble(2 km) + 1 m\ndouble(double(1) + answer())\ndouble(1, 2)\nsin(0)",
+        );
+        test.assert_results(&["42", "2.5", "15", "4.001 km", "88", "Err", "0"][..]);
 
-            // change value from 2 to 21
-            test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+        // the functions defined in the document can call them with their parameters,
+        // and hide them
+        let test = create_app2(35);
+        test.mut_app().register_function(
+            "double",
+            1,
+            Box::new(|args: &[CalcResult]| {
+                let two = CalcResult::new(CalcResultType::Number(Decimal::from(2)), 0);
+                crate::calc::multiply_op(&args[0], &two)
+            }),
+        );
+        test.mut_app()
+            .register_function("answer", 0, Box::new(|_: &[CalcResult]| None));
+        test.paste("f(x) = double(x) + 1 m\nf(2 m)\nanswer() = 7\nanswer() * 2");
+        test.assert_results(&["", "5 m", "", "14"][..]);
+    }
 
-            test.assert_results(&["21", "3", "24", "4", "5", "57", "", "24", "25", "49"][..]);
-        }
+    #[test]
+    fn test_user_defined_function_errors() {
+        let test = create_app2(35);
+        test.paste("f(x) = f(x - 1)\nf(2)\ng(x) = x\ng(1, 2)\ng()\n12");
+        test.assert_results(&["Err", "Err", "", "Err", "Err", "12"][..]);
+
+        // indirect recursion
+        let test = create_app2(35);
+        test.paste("g(x) = h(x)\nh(x) = g(x)\nh(1)");
+        test.assert_results(&["", "Err", "Err"][..]);
     }
 
     #[test]
-    fn test_that_result_is_not_changing_if_tokens_change_before_it() {
+    fn test_changing_a_function_definition_updates_its_calls() {
         let test = create_app2(35);
-        test.paste("111");
+        test.paste("f(x) = x * 2 + 1\nf(10)");
+        test.assert_results(&["", "21"][..]);
+        test.set_cursor_row_col(0, 16);
+        test.input(EditorInputEvent::Char('0'), InputModifiers::none());
+        test.assert_results(&["", "30"][..]);
 
-        test.input(EditorInputEvent::Home, InputModifiers::none());
-        test.input(EditorInputEvent::Char(' '), InputModifiers::none());
+        // it is not a definition anymore
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 0),
+            Pos::from_row_column(0, 17),
+        ));
+        test.input(EditorInputEvent::Char('1'), InputModifiers::none());
+        test.assert_results(&["1", "10"][..]);
+    }
 
-        let render_commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-        // expect no pulsing since there were no value change
-        for command in render_commands {
-            match command {
-                OutputMessage::PulsingRectangle { .. } => assert!(false, "{:?}", render_commands),
-                _ => {}
-            }
-        }
+    #[test]
+    fn test_extract_to_variable() {
+        let test = create_app2(35);
+        test.paste("2 + 3 * 4");
+        test.assert_results(&["14"][..]);
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 4),
+            Pos::from_row_column(0, 9),
+        ));
+        test.extract_to_variable("t");
+        assert_eq!(test.get_editor_content(), "t = (3 * 4)\n2 + t");
+        test.assert_results(&["12", "14"][..]);
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(1, 5));
     }
 
     #[test]
-    fn test_variable_redefine() {
+    fn test_extract_to_variable_is_undone_in_one_step() {
         let test = create_app2(35);
-        test.paste("apple = 12");
-        test.render();
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.paste("apple + 2");
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.paste("apple = 0");
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
-        test.paste("apple + 3");
+        test.paste("2 + 3 * 4");
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 4),
+            Pos::from_row_column(0, 9),
+        ));
+        test.extract_to_variable("t");
+        assert_eq!(test.get_editor_content(), "t = (3 * 4)\n2 + t");
+
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!(test.get_editor_content(), "2 + 3 * 4");
+        test.assert_results(&["14"][..]);
+    }
 
-        test.assert_results(&["12", "14", "0", "3"][..]);
+    #[test]
+    fn test_extract_to_variable_keeps_the_result() {
+        let test = create_app2(35);
+        test.paste("2 * 3 + 4");
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 4),
+            Pos::from_row_column(0, 9),
+        ));
+        // "2 * t" would be 14 instead of 10
+        test.extract_to_variable("t");
+        assert_eq!(test.get_editor_content(), "2 * 3 + 4");
+
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 0),
+            Pos::from_row_column(0, 5),
+        ));
+        test.extract_to_variable("x");
+        assert_eq!(test.get_editor_content(), "x = (2 * 3)\nx + 4");
+        test.assert_results(&["6", "10"][..]);
     }
 
     #[test]
-    fn test_backspace_bug_editor_obj_deletion_for_simple_tokens() {
+    fn test_extract_to_variable_rejects_invalid_names() {
         let test = create_app2(35);
-        test.paste("asd sad asd asd sX");
-        test.render();
-        test.input(EditorInputEvent::Backspace, InputModifiers::none());
-        assert_eq!("asd sad asd asd s", test.get_editor_content());
+        test.paste("a = 1\n2 + 3 * 4");
+        for name in &["12", "sum", "a", "sin", "x y", ""] {
+            test.set_selection(Selection::range(
+                Pos::from_row_column(1, 4),
+                Pos::from_row_column(1, 9),
+            ));
+            test.extract_to_variable(name);
+            assert_eq!(test.get_editor_content(), "a = 1\n2 + 3 * 4", "{}", name);
+        }
     }
 
     #[test]
-    fn test_rendering_while_cursor_move() {
+    fn test_extract_to_variable_without_selection_does_nothing() {
         let test = create_app2(35);
-        test.paste("apple = 12$\nasd q");
-        test.render();
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        test.render();
+        test.paste("2 + 3 * 4");
+        test.set_cursor_row_col(0, 4);
+        test.extract_to_variable("t");
+        assert_eq!(test.get_editor_content(), "2 + 3 * 4");
+
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, 3),
+            Pos::from_row_column(0, 4),
+        ));
+        test.extract_to_variable("t");
+        assert_eq!(test.get_editor_content(), "2 + 3 * 4");
     }
 
     #[test]
-    fn stepping_into_a_matrix_renders_it_some_lines_below() {
+    fn test_autocomplete() {
         let test = create_app2(35);
-        test.paste("asdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 2);
-        test.render();
+        test.paste("meter_count = 2\n3 me");
+        let suggestions = test.app().autocomplete(test.units(), test.mut_vars());
+        assert_eq!(suggestions[0], "meter_count");
+        assert!(suggestions.contains(&"meter".to_owned()));
+        assert!(suggestions.iter().all(|it| it.starts_with("me")));
 
-        test.input(EditorInputEvent::Down, InputModifiers::none());
+        test.paste(" + si");
+        let suggestions = test.app().autocomplete(test.units(), test.mut_vars());
+        assert!(suggestions.contains(&"sin".to_owned()));
+        assert!(suggestions.contains(&"sinh".to_owned()));
 
-        {
-            let editor_objects = test.editor_objects();
-            assert_eq!(editor_objects[content_y(0)].len(), 1);
-            assert_eq!(editor_objects[content_y(1)].len(), 1);
+        test.paste(" ");
+        assert!(test
+            .app()
+            .autocomplete(test.units(), test.mut_vars())
+            .is_empty());
+    }
 
-            assert_eq!(test.app().render_data.get_rendered_height(content_y(0)), 1);
-            assert_eq!(test.app().render_data.get_rendered_height(content_y(1)), 6);
-            assert_eq!(
-                test.get_render_data().get_render_y(content_y(0)),
-                Some(canvas_y(0))
-            );
-            assert_eq!(
-                test.get_render_data().get_render_y(content_y(1)),
-                Some(canvas_y(1))
-            );
-        }
+    #[test]
+    fn test_apply_completion() {
+        let test = create_app2(35);
+        test.paste("3 me\n2*me");
+        test.set_cursor_row_col(1, 4);
+        test.mut_app().apply_completion(
+            "meter",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+        assert_eq!(test.get_editor_content(), "3 me\n2*meter");
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(1, 7));
 
-        test.render();
+        // without a partial word, it is inserted at the cursor
+        test.set_cursor_row_col(0, 0);
+        test.mut_app().apply_completion(
+            "x",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+        assert_eq!(test.get_editor_content(), "x3 me\n2*meter");
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 1));
+    }
 
-        let editor_objects = test.editor_objects();
-        assert_eq!(editor_objects[content_y(0)].len(), 1);
-        assert_eq!(editor_objects[content_y(1)].len(), 1);
-        assert_eq!(test.app().render_data.get_rendered_height(content_y(0)), 1);
-        assert_eq!(test.app().render_data.get_rendered_height(content_y(1)), 6);
+    #[test]
+    fn test_find() {
+        let test = create_app2(35);
+        test.paste("apple 12\nbanana\nApple pie, apple");
         assert_eq!(
-            test.get_render_data().get_render_y(content_y(0)),
-            Some(canvas_y(0))
+            test.mut_app().find("apple"),
+            vec![Pos::from_row_column(0, 0), Pos::from_row_column(2, 11)]
         );
+        test.mut_app().set_find_ignore_case(true);
         assert_eq!(
-            test.get_render_data().get_render_y(content_y(1)),
-            Some(canvas_y(1))
+            test.mut_app().find("apple"),
+            vec![
+                Pos::from_row_column(0, 0),
+                Pos::from_row_column(2, 0),
+                Pos::from_row_column(2, 11)
+            ]
         );
-    }
+        assert_eq!(test.mut_app().find("cherry"), vec![]);
 
-    #[test]
-    fn select_only_2_lines_render_bug() {
-        let test = create_app2(35);
-        test.paste("1\n2\n3");
-        test.render();
-        test.input(EditorInputEvent::Up, InputModifiers::shift());
+        test.mut_app().find("apple");
+        test.set_cursor_row_col(1, 0);
+        assert_eq!(test.mut_app().find_next(), Some(Pos::from_row_column(2, 0)));
+        assert_eq!(
+            test.get_selection(),
+            Selection::range(Pos::from_row_column(2, 0), Pos::from_row_column(2, 5))
+        );
+        assert_eq!(
+            test.mut_app().find_next(),
+            Some(Pos::from_row_column(2, 11))
+        );
+        // wraps around
+        assert_eq!(test.mut_app().find_next(), Some(Pos::from_row_column(0, 0)));
+        assert_eq!(
+            test.mut_app().find_prev(),
+            Some(Pos::from_row_column(2, 11))
+        );
+        assert_eq!(test.mut_app().find_prev(), Some(Pos::from_row_column(2, 0)));
 
-        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-        let expected_x = left_gutter_width + 4;
-        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 1, '⎫'));
-        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 2, '⎭'));
+        test.render();
+        let left_gutter_width = test.get_render_data().left_gutter_width;
+        let commands = &test.render_bucket().custom_commands[Layer::BehindText as usize];
         assert_contains(
             commands,
             1,
-            OutputMessage::RenderString(RenderStringMsg {
-                text: " ∑ = 5".to_owned(),
-                row: canvas_y(1),
-                column: expected_x,
-            }),
+            OutputMessage::SetColor(FIND_MATCH_BACKGROUND_COLOR),
+        );
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderRectangle {
+                x: left_gutter_width + 11,
+                y: canvas_y(2),
+                w: 5,
+                h: 1,
+            },
         );
-    }
 
-    #[test]
-    fn sum_popup_position_itself_if_there_is_not_enough_space() {
-        let test = create_app2(35);
-        test.paste("1\n2\n3");
+        test.mut_app().clear_find();
         test.render();
-        test.input(EditorInputEvent::Up, InputModifiers::shift());
-
-        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-        let expected_x = left_gutter_width + 4;
-        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
-        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 1, '⎫'));
-        assert_contains(commands, 1, OutputMessage::RenderChar(expected_x, 2, '⎭'));
+        let commands = &test.render_bucket().custom_commands[Layer::BehindText as usize];
         assert_contains(
             commands,
-            1,
-            OutputMessage::RenderString(RenderStringMsg {
-                text: " ∑ = 5".to_owned(),
-                row: canvas_y(1),
-                column: expected_x,
-            }),
+            0,
+            OutputMessage::SetColor(FIND_MATCH_BACKGROUND_COLOR),
         );
+        assert_eq!(test.mut_app().find_next(), None);
     }
 
     #[test]
-    fn test_undoing_selection_removal_works() {
+    fn test_replace_all() {
         let test = create_app2(35);
-        test.paste(
-            "aaa
-bbb
-ccc
-
-ddd",
+        test.paste("a = 1\n[1,2] * a\n1 + 10");
+        let count = test.mut_app().replace_all(
+            "1",
+            "5",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
         );
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        test.input(EditorInputEvent::End, InputModifiers::none());
-        test.handle_time(1000);
-        test.input(EditorInputEvent::PageUp, InputModifiers::shift());
-        test.handle_time(1000);
-        test.input(EditorInputEvent::Del, InputModifiers::none());
-        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        // the matrix is left untouched
+        assert_eq!(count, 3);
+        assert_eq!(test.get_editor_content(), "a = 5\n[1,2] * a\n5 + 50");
 
-        let left_gutter_width = test.get_render_data().left_gutter_width;
-        test.assert_contains_text(1, |cmd| {
-            cmd.text == &['a', 'a', 'a']
-                && cmd.row == canvas_y(0)
-                && cmd.column == left_gutter_width
-        });
-        test.assert_contains_text(1, |cmd| {
-            cmd.text == &['b', 'b', 'b']
-                && cmd.row == canvas_y(1)
-                && cmd.column == left_gutter_width
-        });
-        test.assert_contains_text(1, |cmd| {
-            cmd.text == &['c', 'c', 'c']
-                && cmd.row == canvas_y(2)
-                && cmd.column == left_gutter_width
-        });
-        test.assert_contains_text(1, |cmd| {
-            cmd.text == &['d', 'd', 'd']
-                && cmd.row == canvas_y(4)
-                && cmd.column == left_gutter_width
-        });
+        // undone in one step
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!(test.get_editor_content(), "a = 1\n[1,2] * a\n1 + 10");
+
+        let count = test.mut_app().replace_all(
+            "x",
+            "5",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+        assert_eq!(count, 0);
+        assert_eq!(test.get_editor_content(), "a = 1\n[1,2] * a\n1 + 10");
     }
 
     #[test]
-    fn scroll_dragging_limit() {
+    fn test_replace_all_skips_the_overlapping_matches() {
         let test = create_app2(35);
-        test.repeated_paste("1\n", 39);
-        test.render();
-
-        test.input(EditorInputEvent::PageUp, InputModifiers::none());
-        assert_eq!(test.get_render_data().scroll_y, 0);
-
-        test.click(test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH, 0);
-        for i in 0..5 {
-            test.handle_drag(
-                test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH,
-                1 + i,
-            );
-            assert_eq!(test.get_render_data().scroll_y, 1 + i as usize);
-        }
-        test.handle_drag(test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH, 6);
-        // the scrollbar reached its bottom position, it won't go further down
-        assert_eq!(test.get_render_data().scroll_y, 5);
+        test.paste("aaa");
+        let count = test.mut_app().replace_all(
+            "aa",
+            "b",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+        assert_eq!(count, 1);
+        assert_eq!(test.get_editor_content(), "ba");
     }
 
     #[test]
-    fn scroll_dragging_upwards() {
+    fn test_replace_next() {
         let test = create_app2(35);
-        test.repeated_paste("1\n", 39);
-
-        test.input(EditorInputEvent::PageUp, InputModifiers::none());
-        test.click(test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH, 0);
-
-        assert_eq!(test.get_render_data().scroll_y, 0);
+        test.paste("ab ab ab");
+        test.set_cursor_row_col(0, 0);
+        let replace_next = || {
+            test.mut_app().replace_next(
+                "ab",
+                "x",
+                test.units(),
+                test.allocator(),
+                test.mut_tokens(),
+                test.mut_results(),
+                test.mut_vars(),
+                test.mut_editor_objects(),
+                test.mut_render_bucket(),
+            )
+        };
+        // nothing is selected, so it only selects the first match
+        assert_eq!(replace_next(), false);
+        assert_eq!(test.get_editor_content(), "ab ab ab");
+        assert_eq!(
+            test.get_selection(),
+            Selection::range(Pos::from_row_column(0, 0), Pos::from_row_column(0, 2))
+        );
 
-        for i in 0..5 {
-            test.handle_drag(
-                test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH,
-                1 + i,
-            );
-            assert_eq!(test.get_render_data().scroll_y, 1 + i as usize);
-        }
-        for i in 0..5 {
-            test.handle_drag(
-                test.get_render_data().result_gutter_x - SCROLLBAR_WIDTH,
-                4 - i,
-            );
-            assert_eq!(test.get_render_data().scroll_y, 4 - i as usize);
-        }
-    }
+        assert_eq!(replace_next(), true);
+        assert_eq!(test.get_editor_content(), "x ab ab");
+        assert_eq!(
+            test.get_selection(),
+            Selection::range(Pos::from_row_column(0, 2), Pos::from_row_column(0, 4))
+        );
 
-    #[test]
-    fn clicking_behind_matrix_should_move_the_cursor_there() {
-        let test = create_app2(35);
+        assert_eq!(replace_next(), true);
+        assert_eq!(test.get_editor_content(), "x x ab");
 
-        test.paste("firs 1t\nasdsad\n[1;2;3;4]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        assert_eq!(test.get_cursor_pos().row, 0);
-        let left_gutter_width = 1;
-        test.click(left_gutter_width + 50, 13);
-        assert_eq!(test.get_cursor_pos().row, 5);
+        test.input(EditorInputEvent::Char('z'), InputModifiers::ctrl());
+        assert_eq!(test.get_editor_content(), "x ab ab");
     }
 
     #[test]
-    fn clicking_inside_matrix_while_selected_should_put_cursor_after_matrix() {
+    fn test_copy_as_latex() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1;2;3;4]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        // select all
-        test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
-        test.render();
-
-        // click inside the real matrix repr
-        // the problem is that this click is inside a SimpleToken (as the matrix is rendered as
-        // SimpleToken if it is selected), so the cursor is set accordingly,
-        // but as soon as the selection is cancelled by the click, we render a matrix,
-        // and the cursor is inside the matrix, which is not OK.
-        let left_gutter_width = 1;
-        test.click(left_gutter_width + 7, 2);
-
-        // typing should append after the matrix
-        test.input(EditorInputEvent::Char('X'), InputModifiers::none());
+        test.paste("[1, 2; 3, 4]\nx = 2/3 + 4^(1+1)\n# header");
         assert_eq!(
-            "firs 1t\nasdsad\n[1;2;3;4]X\nfirs 1t\nasdsad\n[1;2;3;4]",
-            test.get_editor_content()
+            test.mut_app().copy_as_latex(0, test.tokens()),
+            Some("\\begin{bmatrix}1 & 2 \\\\ 3 & 4\\end{bmatrix}".to_owned())
+        );
+        assert_eq!(
+            test.app().clipboard,
+            Some("\\begin{bmatrix}1 & 2 \\\\ 3 & 4\\end{bmatrix}".to_owned())
+        );
+        assert_eq!(
+            test.mut_app().copy_as_latex(1, test.tokens()),
+            Some("x = \\frac{2}{3} + 4^{1 + 1}".to_owned())
         );
+        assert_eq!(test.mut_app().copy_as_latex(2, test.tokens()), None);
     }
 
     #[test]
-    fn limiting_cursor_does_not_kill_selection() {
+    fn test_get_error_spans() {
         let test = create_app2(35);
+        test.paste("2 + * 3\n1 + 2\n2 * -3");
+        let spans = test.app().get_error_spans(test.tokens());
+        assert!(spans.contains(&(0, 4..5)));
+        assert!(spans.iter().all(|(row, _)| *row == 0));
 
-        test.repeated_paste("1\n", MAX_LINE_COUNT + 1);
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        test.input(EditorInputEvent::PageDown, InputModifiers::shift());
-        test.render();
-        assert_eq!(
-            test.get_selection().is_range(),
-            Some((
-                Pos::from_row_column(0, 0),
-                Pos::from_row_column(MAX_LINE_COUNT - 1, 0)
-            ))
-        );
+        let test = create_app2(35);
+        test.paste("(1, 2)\n3 *\n(1 + 2\n1 + 2)");
+        let spans = test.app().get_error_spans(test.tokens());
+        assert_eq!(spans, vec![(0, 2..3), (1, 2..3), (2, 0..1), (3, 5..6)]);
     }
 
     #[test]
-    fn deleting_all_selected_lines_no_panic() {
+    fn test_list_functions() {
         let test = create_app2(35);
-        test.repeated_paste("1\n", MAX_LINE_COUNT + 20);
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        test.input(EditorInputEvent::Char('a'), InputModifiers::ctrl());
-        test.render();
-        test.input(EditorInputEvent::Del, InputModifiers::ctrl());
+        let names = test.app().list_functions();
+        for name in &["sin", "cos", "sqrt", "ln", "nth", "transpose"] {
+            assert!(names.contains(&name.to_string()), "{}", name);
+        }
+        assert!(names.windows(2).all(|it| it[0] < it[1]));
     }
 
     #[test]
-    fn test_setting_left_gutter_width() {
-        // future proof test
+    fn test_list_units() {
         let test = create_app2(35);
-        test.paste("");
-        for i in 0..MAX_LINE_COUNT {
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            let rendered_line_num = i + 2;
-            let expected_w = format!("{}", rendered_line_num).len() + 1;
-            assert_eq!(
-                test.get_render_data().left_gutter_width,
-                expected_w,
-                "at line {}. the left gutter width should be {}",
-                rendered_line_num,
-                expected_w
-            );
+        let names = test.app().list_units(test.units());
+        for name in &["m", "kg", "s"] {
+            assert!(names.contains(&name.to_string()), "{}", name);
         }
     }
 
     #[test]
-    fn click_into_a_row_with_matrix_put_the_cursor_after_the_rendered_matrix() {
+    fn test_get_row_percentages() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        assert_eq!(test.get_cursor_pos().column, 0);
+        test.paste("10\n30\n# header\n60\n5 m");
+        let percentages = test.app().get_row_percentages(test.mut_results(), 0..5);
+        assert_eq!(
+            percentages,
+            &[Some(10.0), Some(30.0), None, Some(60.0), None]
+        );
 
-        let left_gutter_width = 1;
-        for i in 0..5 {
-            test.click(left_gutter_width + 13 + i, 5);
-            assert_eq!(test.get_cursor_pos().column, 25);
-        }
+        let test = create_app2(35);
+        test.paste("0\n0");
+        let percentages = test.app().get_row_percentages(test.mut_results(), 0..2);
+        assert_eq!(percentages, &[None, None]);
     }
 
     #[test]
-    fn clicking_into_matrices_panic() {
+    fn test_replace_content_preserving_state() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        // click into 1st matrix to edit it
-        let left_gutter_width = 1;
-        test.click(left_gutter_width + 1, 5);
-        test.render();
-        // write 333 into the first cell
-        for _ in 0..3 {
-            test.input(EditorInputEvent::Char('3'), InputModifiers::none());
-        }
-        test.render();
-        // click into 2nd matrix
-        test.click(left_gutter_width + 1, 15);
-        test.render();
-        // click back into 1nd matrix
-        test.click(left_gutter_width + 1, 5);
-        test.render();
+        test.paste("255\n2\n3\n255");
+        test.mut_app().set_result_format(0, ResultFormat::Hex);
+        test.mut_app().set_result_format(3, ResultFormat::Bin);
+        test.set_cursor_row_col(3, 2);
+        let line_ids = test
+            .app()
+            .editor_content
+            .data()
+            .iter()
+            .take(4)
+            .map(|it| it.line_id)
+            .collect::<Vec<_>>();
+
+        test.mut_app().replace_content_preserving_state(
+            "255\n2\n30\n40\n255",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+
+        assert_eq!(test.get_editor_content(), "255\n2\n30\n40\n255");
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(4, 2));
+        let data = test.app().editor_content.data();
+        assert_eq!(data[0].line_id, line_ids[0]);
+        assert_eq!(data[1].line_id, line_ids[1]);
+        assert_eq!(data[4].line_id, line_ids[3]);
+        assert!(!line_ids.contains(&data[2].line_id));
+        assert!(!line_ids.contains(&data[3].line_id));
+        assert_ne!(data[2].line_id, data[3].line_id);
+        assert_eq!(data[0].result_format, ResultFormat::Hex);
+        assert_eq!(data[2].result_format, ResultFormat::Dec);
+        assert_eq!(data[4].result_format, ResultFormat::Bin);
+        test.assert_results(&["FF", "2", "30", "40", "11111111"][..]);
+
+        // the result formats line of a normalized content is applied
+        test.mut_app().replace_content_preserving_state(
+            "255\n2\n30\n40\n255\n#result_formats:1=bin",
+            test.units(),
+            test.allocator(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+        assert_eq!(test.get_editor_content(), "255\n2\n30\n40\n255");
+        test.assert_results(&["255", "10", "30", "40", "255"][..]);
     }
 
     #[test]
-    fn leaving_matrix_by_clicking_should_trigger_reevaluation() {
+    fn test_get_cumulative_sums() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        // click into 1st matrix to edit it
-        let left_gutter_width = 1;
-        test.click(left_gutter_width + 1, 5);
-        test.render();
-        // write 333 into the first cell
-        for _ in 0..3 {
-            test.input(EditorInputEvent::Char('3'), InputModifiers::none());
-        }
-        test.render();
-        // click into 2nd matrix
-        test.click(left_gutter_width + 1, 15);
-        test.render();
-        assert_eq!(test.editor_objects()[content_y(2)][0].rendered_w, 8);
+        test.paste("10\n30\n// comment\n60");
+        let sums = test
+            .app()
+            .get_cumulative_sums(test.units(), test.mut_results(), 0..4);
+        assert_eq!(
+            sums,
+            &[
+                Some("10".to_owned()),
+                Some("40".to_owned()),
+                None,
+                Some("100".to_owned())
+            ]
+        );
+
+        let test = create_app2(35);
+        test.paste("1 m\n20 cm\n3 kg\n4 m");
+        let sums = test
+            .app()
+            .get_cumulative_sums(test.units(), test.mut_results(), 0..4);
+        assert_eq!(
+            sums,
+            &[Some("1 m".to_owned()), Some("1.2 m".to_owned()), None, None]
+        );
     }
 
     #[test]
-    fn click_into_a_matrix_start_mat_editing() {
+    fn test_get_overview() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
+        test.paste("12 + 3\n// comment\n[1, 2]\n1 / 0\n\n# header");
         test.render();
-        let left_gutter_width = 1;
-        test.click(left_gutter_width + 1, 5);
-        assert!(test.app().matrix_editing.is_some());
+        let overview = test.app().get_overview(test.units(), test.mut_vars());
+        assert_eq!(overview.len(), 6);
+        let flags = overview
+            .iter()
+            .map(|it| (it.has_result, it.is_comment, it.is_error, it.is_matrix))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            flags,
+            &[
+                (true, false, false, false),
+                (false, true, false, false),
+                (true, false, false, true),
+                (false, false, true, false),
+                (false, false, false, false),
+                (false, true, false, false),
+            ]
+        );
+        assert_eq!(overview[1].relative_width, 1.0);
+        assert_eq!(overview[0].relative_width, 0.6);
+        assert_eq!(overview[4].relative_width, 0.0);
     }
 
     #[test]
-    fn mouse_selecting_moving_mouse_out_of_editor() {
+    fn test_get_line_result() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
+        test.paste("12 + 3\n5 km\n// comment\n[1, 2]\n1 / 0");
         test.render();
-        let left_gutter_width = LEFT_GUTTER_MIN_WIDTH;
-        test.click(left_gutter_width + 7, 0);
-        test.handle_drag(0, 0);
+        let results = test.mut_results();
+        let app = test.app();
         assert_eq!(
-            test.get_selection().is_range(),
-            Some((Pos::from_row_column(0, 0), Pos::from_row_column(0, 7)))
+            app.get_line_result(results, 0).map(|it| &it.typ),
+            Some(&CalcResultType::Number(Decimal::from(15)))
         );
+        match app.get_line_result(results, 1).map(|it| &it.typ) {
+            Some(CalcResultType::Quantity(num, unit)) => {
+                assert_eq!(*num, Decimal::from(5000));
+                assert_eq!(unit.to_string(), "km");
+            }
+            _ => panic!(),
+        }
+        assert!(app.get_line_result(results, 2).is_none());
+        match app.get_line_result(results, 3).map(|it| &it.typ) {
+            Some(CalcResultType::Matrix(mat)) => assert_eq!(mat.cells.len(), 2),
+            _ => panic!(),
+        }
+        assert!(app.get_line_result(results, 4).is_none());
+        assert!(app.get_line_result(results, 5).is_none());
     }
 
     #[test]
-    fn test_dragging_right_gutter_panic() {
+    fn test_export_csv() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-
-        let orig_x = test.get_render_data().result_gutter_x;
-        test.click(test.get_render_data().result_gutter_x, 0);
-
-        for i in 1..=orig_x {
-            test.handle_drag(orig_x - i, 0);
-        }
+        test.paste("12 + 3\nd = 5 km\n// comment\n1 / 0\n[1, 2]\n20%");
+        assert_eq!(
+            test.app().export_csv(test.units(), test.mut_vars()),
+            "line,expression,value,unit\n\
+             1,12 + 3,15,\n\
+             2,d = 5 km,5000,m\n\
+             4,1 / 0,Err,\n\
+             5,\"[1, 2]\",matrix,\n\
+             6,20%,20,%\n"
+        );
     }
 
     #[test]
-    fn test_small_right_gutter_panic() {
-        let test = create_app3(20, 35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
+    fn test_export_html() {
+        let test = create_app2(35);
+        test.paste("12 + 3\n// a < b\n[1, 2; 3, 4]");
+        let html = test.app().export_html(test.units(), test.mut_vars());
+        assert_eq!(html.matches("<tr class=\"line\">").count(), 3);
+        assert!(html.contains(
+            "<span style=\"color: #F92672\">12</span> <span style=\"color: #000000\">+</span> \
+             <span style=\"color: #F92672\">3</span>\
+             </td><td style=\"text-align: right\"><span style=\"color: #000000\">15</span>\
+             </td></tr>"
+        ));
+        assert!(html.contains("a &lt; b"));
+        assert!(html.contains(
+            "<td style=\"text-align: right\"><table style=\"display: inline-table\">\
+             <tr><td style=\"text-align: right\"><span style=\"color: #000000\">1</span></td>"
+        ));
+    }
 
-        let orig_x = test.get_render_data().result_gutter_x;
-        test.click(test.get_render_data().result_gutter_x, 0);
+    #[test]
+    fn test_validate() {
+        let units = Units::new();
+        let errors = NoteCalcApp::validate(
+            "12 + 3\nx = (2 * 3)\n1.23e50 + 0\n(1 + 2\n// (comment\nsome text",
+            &units,
+        );
+        assert_eq!(
+            errors,
+            vec![
+                (2, "invalid number: 1.23e50".to_owned()),
+                (3, "unbalanced parenthesis".to_owned())
+            ]
+        );
 
-        for i in 1..=orig_x {
-            test.handle_drag(orig_x - i, 0);
-        }
+        let errors = NoteCalcApp::validate(
+            "2 + * 3\n(1, 2)\n2 +\n1 + 2)\nmax(1, 2)\n[1, 2]\napples, pears - 3 kg",
+            &units,
+        );
+        assert_eq!(
+            errors,
+            vec![
+                (0, "missing operand".to_owned()),
+                (1, "misplaced comma".to_owned()),
+                (2, "missing operand".to_owned()),
+                (3, "unbalanced parenthesis".to_owned())
+            ]
+        );
     }
 
     #[test]
-    fn bug_selection_rectangle_is_longer_than_the_selected_row() {
+    fn test_ans_indexing() {
         let test = create_app2(35);
-        test.paste("firs 1t\nasdsad\n[1,0;2,0;3,0;4,0;5,0;6,0]\nfirs 1t\nasdsad\n[1;2;3;4]");
-        test.set_cursor_row_col(0, 0);
-        test.render();
-        let left_gutter_width = 2;
-        test.click(left_gutter_width + 4, 0);
-        test.render();
+        test.paste("2\n3\nans[1] + ans[2]\nans[3] * 2 m");
+        test.assert_results(&["2", "3", "5", "10 m"][..]);
 
-        test.handle_drag(left_gutter_width + 0, 1);
+        test.set_cursor_row_col(0, 1);
+        test.input(EditorInputEvent::Char('0'), InputModifiers::none());
+        test.assert_results(&["20", "3", "23", "46 m"][..]);
+    }
 
-        let render_buckets = test.render_bucket();
-        let pos = test
-            .render_bucket()
-            .custom_commands(Layer::BehindText)
-            .iter()
-            .position(|it| matches!(it, OutputMessage::SetColor(0xA6D2FF_FF)))
-            .expect("there is no selection box drawing");
-        assert_eq!(
-            render_buckets.custom_commands(Layer::BehindText)[pos + 1],
-            OutputMessage::RenderRectangle {
-                x: left_gutter_width + 4,
-                y: canvas_y(0),
-                w: 3,
-                h: 1
+    #[test]
+    fn test_unknown_unit_warnings() {
+        let test = create_app2(35);
+        test.paste("5 metre\n5 meter\nmetre\n3 inchs + 2");
+        let warnings = test.app().get_warnings(test.units(), test.mut_tokens());
+        assert_eq!(warnings.len(), 2);
+        match &warnings[0] {
+            Warning::UnknownUnit {
+                row,
+                name,
+                suggestions,
+            } => {
+                assert_eq!(*row, 0);
+                assert_eq!(name, "metre");
+                assert!(suggestions.contains(&"meter".to_owned()));
             }
-        );
-        assert_eq!(
-            render_buckets.custom_commands(Layer::BehindText)[pos + 2],
-            OutputMessage::RenderRectangle {
-                x: left_gutter_width,
-                y: canvas_y(1),
-                w: 0,
-                h: 1
+        }
+        match &warnings[1] {
+            Warning::UnknownUnit { row, name, .. } => {
+                assert_eq!(*row, 3);
+                assert_eq!(name, "inchs");
             }
-        );
+        }
     }
 
     #[test]
-    fn test_handling_too_much_rows_no_panic() {
+    fn test_currency_conversion() {
         let test = create_app2(35);
-        test.paste(&("1\n".repeat(MAX_LINE_COUNT - 1).to_owned()));
-        test.set_cursor_row_col(MAX_LINE_COUNT - 2, 1);
+        test.mut_app()
+            .set_exchange_rates(&[("EUR", 0.5), ("HUF", 400.0), ("GBP", -1.0)]);
+        test.paste("100 USD in EUR\n10 EUR in HUF\n100 $ in EUR\n100 USD in GBP\n3 CHF\n12");
+        test.assert_results(&["50 EUR", "8 000 HUF", "50 EUR", "Err", "Err", "12"][..]);
+    }
 
-        test.render();
-        test.input(EditorInputEvent::Enter, InputModifiers::none());
+    #[test]
+    fn test_exchange_rates_are_applied_without_rounding() {
+        let test = create_app2(35);
+        test.mut_app()
+            .set_exchange_rates(&[("EUR", 0.92), ("JPY", 3.0)]);
+        test.paste("1 USD in JPY\n3 JPY in USD\n1 USD in EUR\n9.2 EUR in USD\n6 JPY * 2");
+        test.assert_results(&["3 JPY", "1 USD", "0.92 EUR", "10 USD", "12 JPY"][..]);
     }
 
     #[test]
-    fn inserting_too_many_rows_no_panic() {
+    fn test_snap_tolerance() {
         let test = create_app2(35);
-        test.paste("");
-        test.set_cursor_row_col(0, 0);
+        test.paste("0.9999999999\n2.0000000001 km\n2.5\n-2.9999999999");
+        test.assert_results(&["0.9999999999", "2.0000000001 km", "2.5", "-2.9999999999"][..]);
 
-        for _ in 0..20 {
-            test.paste("1\n2\n3\n4\n5\n6\n7\n8\n9\n0");
-            test.render();
-            test.input(EditorInputEvent::Enter, InputModifiers::none());
-            test.render();
+        let test = create_app2(35);
+        test.mut_app().set_snap_tolerance(Some(Decimal::new(1, 9)));
+        test.paste("0.9999999999\n2.0000000001 km\n2.5\n-2.9999999999");
+        test.assert_results(&["1", "2 km", "2.5", "-3"][..]);
+    }
+
+    #[test]
+    fn test_inverse_times_matrix_is_a_clean_identity_with_snap_tolerance() {
+        let test = create_app2(35);
+        test.mut_app().set_snap_tolerance(Some(Decimal::new(1, 9)));
+        test.paste("inverse([1, 2; 3, 3]) * [1, 2; 3, 3]");
+        match &test.mut_vars()[0].as_ref().unwrap().value {
+            Ok(CalcResult {
+                typ: CalcResultType::Matrix(mat),
+                ..
+            }) => {
+                let cells: Vec<CalcResultType> =
+                    mat.cells.iter().map(|it| it.typ.clone()).collect();
+                assert_eq!(
+                    cells,
+                    vec![
+                        CalcResultType::Number(Decimal::from(1)),
+                        CalcResultType::Number(Decimal::from(0)),
+                        CalcResultType::Number(Decimal::from(0)),
+                        CalcResultType::Number(Decimal::from(1)),
+                    ]
+                );
+            }
+            _ => panic!(),
         }
-        test.input(EditorInputEvent::Up, InputModifiers::none());
-        test.input(EditorInputEvent::PageDown, InputModifiers::none());
     }
 
     #[test]
-    fn test_sum_rerender() {
-        // rust's borrow checker forces me to do this
-        {
-            let test = create_app2(35);
-            test.paste("1\n2\n3\nsum");
+    fn test_repeated_divisions_do_not_grow_scale() {
+        let test = create_app2(35);
+        test.mut_app().set_internal_scale(10);
+        test.paste("a = 1/7\na = a/7\na = a/7\na = a/7\na = a/7\na = a/7\na * 7^6");
+        test.assert_results(
+            &[
+                "0.1428571429",
+                "0.0204081633",
+                "0.0029154519",
+                "0.0004164931",
+                "0.000059499",
+                "0.0000084999",
+                "1.0000047351",
+            ][..],
+        );
+    }
 
-            test.assert_results(&["1", "2", "3", "6"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+    #[test]
+    fn test_rounding_mode_half_up_vs_half_even() {
+        let test = create_app2(35);
+        test.mut_app().set_rounding_mode(RoundingMode::HalfUp);
+        test.paste("round(2.5)\nround(3.5)\nround(-2.5)");
+        test.assert_results(&["3", "4", "-3"][..]);
 
-            test.assert_results(&["1", "2", "3", "6"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+        let test = create_app2(35);
+        test.paste("round(2.5)\nround(3.5)\nround(-2.5)");
+        test.assert_results(&["2", "4", "-2"][..]);
+    }
 
-            test.assert_results(&["1", "2", "3", "6"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Down, InputModifiers::none());
+    #[test]
+    fn test_rounding_mode_is_used_by_the_displayed_results() {
+        // the mantissa of the scientific notation can't have all the 29 digits
+        let test = create_app2(35);
+        test.mut_app().set_rounding_mode(RoundingMode::HalfUp);
+        test.paste("12345678901234567890123456785\n-12345678901234567890123456785");
+        test.mut_app().set_result_format(0, ResultFormat::Sci);
+        test.mut_app().set_result_format(1, ResultFormat::Sci);
+        test.render();
+        test.assert_results(
+            &[
+                "1.234567890123456789012345679e28",
+                "-1.234567890123456789012345679e28",
+            ][..],
+        );
 
-            test.assert_results(&["1", "2", "3", "6"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Down, InputModifiers::none());
-            test.input(EditorInputEvent::Down, InputModifiers::none());
+        let test = create_app2(35);
+        test.paste("12345678901234567890123456785\n-12345678901234567890123456785");
+        test.mut_app().set_result_format(0, ResultFormat::Sci);
+        test.mut_app().set_result_format(1, ResultFormat::Sci);
+        test.render();
+        test.assert_results(
+            &[
+                "1.234567890123456789012345678e28",
+                "-1.234567890123456789012345678e28",
+            ][..],
+        );
 
-            test.assert_results(&["1", "2", "3", "6"][..]);
-        }
+        let test = create_app2(35);
+        test.mut_app().set_rounding_mode(RoundingMode::Floor);
+        test.paste("12345678901234567890123456785\n-12345678901234567890123456785");
+        test.mut_app().set_result_format(0, ResultFormat::Sci);
+        test.mut_app().set_result_format(1, ResultFormat::Sci);
+        test.render();
+        test.assert_results(
+            &[
+                "1.234567890123456789012345678e28",
+                "-1.234567890123456789012345679e28",
+            ][..],
+        );
     }
 
     #[test]
-    fn test_sum_rerender_with_ignored_lines() {
-        {
-            let test = create_app2(35);
-            test.paste("1\n'2\n3\nsum");
-
-            test.assert_results(&["1", "3", "4"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n'2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+    fn test_rounding_mode_floor_ceil_toward_zero() {
+        let test = create_app2(35);
+        test.mut_app().set_rounding_mode(RoundingMode::Floor);
+        test.paste("round(2.7)\nround(-2.2)");
+        test.assert_results(&["2", "-3"][..]);
 
-            test.assert_results(&["1", "3", "4"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n'2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+        let test = create_app2(35);
+        test.mut_app().set_rounding_mode(RoundingMode::Ceil);
+        test.paste("round(2.2)\nround(-2.7)");
+        test.assert_results(&["3", "-2"][..]);
 
-            test.assert_results(&["1", "3", "4"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n'2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Down, InputModifiers::none());
+        let test = create_app2(35);
+        test.mut_app().set_rounding_mode(RoundingMode::TowardZero);
+        test.paste("round(2.7)\nround(-2.7)");
+        test.assert_results(&["2", "-2"][..]);
+    }
 
-            test.assert_results(&["1", "3", "4"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n'2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
-            test.input(EditorInputEvent::Down, InputModifiers::none());
+    #[test]
+    fn test_get_rendered_result() {
+        let test = create_app2(35);
+        test.paste("1+2\n\n3 km\n2/0\n[1,2]\n12345");
+        test.render();
+        assert_eq!(test.app().get_rendered_result(0), Some("3"));
+        assert_eq!(test.app().get_rendered_result(1), None);
+        assert_eq!(test.app().get_rendered_result(2), Some("3 km"));
+        assert_eq!(test.app().get_rendered_result(3), Some("Err"));
+        assert_eq!(test.app().get_rendered_result(4), None);
+        assert_eq!(test.app().get_rendered_result(5), Some("12 345"));
+        assert_eq!(test.app().get_rendered_result(6), None);
+        assert_eq!(test.app().get_rendered_result(MAX_LINE_COUNT + 1), None);
+    }
 
-            test.assert_results(&["1", "3", "4"][..]);
+    #[test]
+    fn test_get_result_string() {
+        let test = create_app2(35);
+        test.paste("1+2\n\n3 km\n2/0\n[1,2;3,4]\n12345\nx = 0xFF hex\n# header");
+        test.render();
+        for row in 0..8 {
+            if row == 4 {
+                continue;
+            }
+            assert_eq!(
+                test.app()
+                    .get_result_string(row, test.units(), test.mut_results(), test.mut_vars())
+                    .as_deref(),
+                test.app().get_rendered_result(row),
+                "row {}",
+                row
+            );
         }
+        assert_eq!(
+            test.app()
+                .get_result_string(4, test.units(), test.mut_results(), test.mut_vars()),
+            Some("[1, 2; 3, 4]".to_owned())
+        );
+        assert_eq!(
+            test.app().get_result_string(
+                MAX_LINE_COUNT + 1,
+                test.units(),
+                test.mut_results(),
+                test.mut_vars()
+            ),
+            None
+        );
     }
 
     #[test]
-    fn test_sum_rerender_with_sum_reset() {
-        {
-            let test = create_app2(35);
-            test.paste("1\n#2\n3\nsum");
+    fn test_evaluate_line() {
+        let test = create_app2(35);
+        test.paste("a = 12\nb = 2 km");
+        let app = test.app();
+        let eval = |text: &str| app.evaluate_line(text, test.units(), test.mut_vars());
 
-            test.assert_results(&["1", "3", "3"][..]);
-        }
-        {
-            let test = create_app2(35);
-            test.paste("1\n#2\n3\nsum");
-            test.input(EditorInputEvent::Up, InputModifiers::none());
+        assert_eq!(
+            eval("a * 2").map(|it| it.typ),
+            Some(CalcResultType::Number(crate::calc::dec(24)))
+        );
+        assert_eq!(
+            eval("b in m").map(|it| render_result(
+                test.units(),
+                &it,
+                &ResultFormat::Dec,
+                true,
+                None,
+                false,
+                &ScientificNotation::default(),
+                RoundingMode::HalfEven,
+            )),
+            Some("2000 m".to_owned())
+        );
+        assert_eq!(eval("unknown"), None);
+        assert_eq!(eval("1 / 0"), None);
+        assert_eq!(eval(""), None);
+        // the editor is untouched
+        assert_eq!("a = 12\nb = 2 km", test.get_editor_content());
+    }
 
-            test.assert_results(&["1", "3", "3"][..]);
-        }
+    #[test]
+    fn test_angle_mode() {
+        let test = create_app2(35);
+        test.paste("sin(90 deg)\ncos(180 deg)");
+        test.assert_results(&["1", "-1"][..]);
+
+        let test = create_app2(35);
+        test.mut_app().set_angle_mode(AngleMode::Deg);
+        test.paste("sin(90)\ncos(180)\nsin(0)\nsin(90 deg)\ncos(3.141592653589793 rad)");
+        test.assert_results(&["1", "-1", "0", "1", "-1"][..]);
     }
 
     #[test]
@@ -10649,6 +16442,8 @@ monthly payment = r/(1 - (1+r)^(-n)) * finance amount",
     fn test_if_number_is_too_big_for_hex_repr_show_err() {
         let test = create_app2(35);
         test.paste("10e24");
+        // Dec -> Sci -> Hex
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
         test.input(EditorInputEvent::Right, InputModifiers::alt());
 
         test.assert_results(&["Err"][..]);
@@ -10667,11 +16462,108 @@ monthly payment = r/(1 - (1+r)^(-n)) * finance amount",
     fn test_if_quantity_is_too_big_for_hex_repr_show_err() {
         let test = create_app2(35);
         test.paste("12km");
+        // Dec -> Sci -> Hex
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
         test.input(EditorInputEvent::Right, InputModifiers::alt());
 
         test.assert_results(&["Err"][..]);
     }
 
+    #[test]
+    fn test_oct_result_format() {
+        let test = create_app2(35);
+        // non-integers are truncated like in the other non-decimal formats
+        test.paste("493\n12km\n2.5");
+        test.set_cursor_row_col(0, 0);
+        test.input(EditorInputEvent::Down, InputModifiers::shift());
+        test.input(EditorInputEvent::Down, InputModifiers::shift());
+        // Dec -> Sci -> Hex -> Oct
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+
+        test.assert_results(&["755", "Err", "2"][..]);
+        assert_contains(
+            &test.render_bucket().custom_commands[Layer::AboveText as usize],
+            1,
+            OutputMessage::RenderUtf8Text(RenderUtf8TextMsg {
+                text: &['0', 'o'],
+                row: canvas_y(0),
+                column: test.get_render_data().result_gutter_x,
+            }),
+        );
+
+        // Oct -> Bin -> Oct -> Hex
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+        test.assert_results(&["1 11101101", "Err", "10"][..]);
+        test.input(EditorInputEvent::Left, InputModifiers::alt());
+        test.assert_results(&["755", "Err", "2"][..]);
+        test.input(EditorInputEvent::Left, InputModifiers::alt());
+        test.assert_results(&["1 ED", "Err", "2"][..]);
+    }
+
+    #[test]
+    fn test_sci_result_format() {
+        let test = create_app2(35);
+        test.paste("123456789012\n0.0000001\n12.5\n123456789012 m\n-0.00000123 * 1");
+        for row in 0..5 {
+            test.mut_app().set_result_format(row, ResultFormat::Sci);
+        }
+        test.mut_app().reparse_everything(
+            test.allocator(),
+            test.units(),
+            test.mut_tokens(),
+            test.mut_results(),
+            test.mut_vars(),
+            test.mut_editor_objects(),
+            test.mut_render_bucket(),
+        );
+        test.assert_results(
+            &[
+                "1.23456789012e11",
+                "1e-7",
+                "12.5",
+                "1.23456789012e11 m",
+                "-1.23e-6",
+            ][..],
+        );
+    }
+
+    #[test]
+    fn test_sci_result_format_is_part_of_the_format_cycle() {
+        let test = create_app2(35);
+        test.paste("123456789012");
+        // Dec -> Sci -> Hex
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+        test.assert_results(&["1.23456789012e11"][..]);
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
+        test.assert_results(&["1C BE 99 1A 14"][..]);
+        // Hex -> Sci -> Dec
+        test.input(EditorInputEvent::Left, InputModifiers::alt());
+        test.assert_results(&["1.23456789012e11"][..]);
+        test.input(EditorInputEvent::Left, InputModifiers::alt());
+        test.assert_results(&["123 456 789 012"][..]);
+    }
+
+    #[test]
+    fn test_scientific_notation_threshold_and_auto_switch() {
+        let test = create_app2(35);
+        test.paste("12345\n0.001\n12.5");
+        test.mut_app().set_scientific_notation_threshold(4, 2);
+        test.mut_app().set_result_format(0, ResultFormat::Sci);
+        test.mut_app().set_result_format(1, ResultFormat::Sci);
+        test.render();
+        test.assert_results(&["1.2345e4", "1e-3", "12.5"][..]);
+
+        // the Dec results are switched automatically
+        let test = create_app2(35);
+        test.paste("12345\n0.001\n12.5");
+        test.mut_app().set_scientific_notation_threshold(4, 2);
+        test.mut_app().set_auto_scientific_notation(true);
+        test.render();
+        test.assert_results(&["1.2345e4", "1e-3", "12.5"][..]);
+    }
+
     #[test]
     fn test_if_percentage_is_too_big_for_binary_repr_show_err() {
         let test = create_app2(35);
@@ -10685,6 +16577,8 @@ monthly payment = r/(1 - (1+r)^(-n)) * finance amount",
     fn test_if_percentage_is_too_big_for_hex_repr_show_err() {
         let test = create_app2(35);
         test.paste("12%");
+        // Dec -> Sci -> Hex
+        test.input(EditorInputEvent::Right, InputModifiers::alt());
         test.input(EditorInputEvent::Right, InputModifiers::alt());
 
         test.assert_results(&["Err"][..]);
@@ -10881,6 +16775,21 @@ monthly payment = r/(1 - (1+r)^(-n)) * finance amount",
         assert_eq!(test.get_render_data().result_gutter_x, orig_result_x);
     }
 
+    #[test]
+    fn test_incomplete_line_has_ghost_hint() {
+        let test = create_app2(35);
+        test.paste("2 +\n2 + 3\n4 *  // comment\n5 + asd");
+        let x = test.get_render_data().result_gutter_x
+            + RIGHT_GUTTER_WIDTH
+            + test.get_render_data().longest_visible_result_len
+            + 1;
+        let commands = &test.render_bucket().custom_commands[Layer::Text as usize];
+        assert_contains(commands, 1, OutputMessage::RenderChar(x, 0, '?'));
+        assert_contains(commands, 0, OutputMessage::RenderChar(x, 1, '?'));
+        assert_contains(commands, 1, OutputMessage::RenderChar(x, 2, '?'));
+        assert_contains(commands, 0, OutputMessage::RenderChar(x, 3, '?'));
+    }
+
     #[test]
     fn test_precision() {
         let test = create_app3(48, 32);
@@ -10889,6 +16798,166 @@ monthly payment = r/(1 - (1+r)^(-n)) * finance amount",
         test.assert_results(&["0.0000000001165124023817148381"][..]);
     }
 
+    #[test]
+    fn test_pretty_operators() {
+        let test = create_app2(35);
+        test.mut_app().set_pretty_operators(true);
+        test.paste("2 * 3 / 4");
+        test.render();
+        let left_gutter_width = test.get_render_data().left_gutter_width;
+        let operators = &test.render_bucket().operators;
+        assert!(operators.contains(&RenderUtf8TextMsg {
+            text: &['×'],
+            row: canvas_y(0),
+            column: left_gutter_width + 2,
+        }));
+        assert!(operators.contains(&RenderUtf8TextMsg {
+            text: &['÷'],
+            row: canvas_y(0),
+            column: left_gutter_width + 6,
+        }));
+        assert!(operators
+            .iter()
+            .all(|it| it.text != &['*'] && it.text != &['/']));
+        assert_eq!(test.get_editor_content(), "2 * 3 / 4");
+    }
+
+    #[test]
+    fn test_soft_wrap() {
+        let test = create_app2(35);
+        test.mut_app().set_soft_wrap(true);
+        let w = test.get_render_data().current_editor_width;
+        let long_line = "1+".repeat(w / 2 + 2) + "1";
+        test.paste(&(long_line.clone() + "\n2"));
+        test.set_cursor_row_col(0, long_line.len());
+        test.render();
+
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 2);
+        assert_eq!(
+            test.get_render_data().get_render_y(content_y(1)),
+            Some(canvas_y(2))
+        );
+        let left_gutter_width = test.get_render_data().left_gutter_width;
+        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderChar(left_gutter_width + long_line.len() - w, 1, '▏'),
+        );
+        assert_contains(
+            commands,
+            0,
+            OutputMessage::RenderChar(test.get_render_data().result_gutter_x - 1, 0, '…'),
+        );
+
+        // clicking into the second row of the wrapped line
+        test.click(left_gutter_width + 1, 1);
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, w + 1));
+
+        test.mut_app().set_soft_wrap(false);
+        test.render();
+        assert_eq!(test.get_render_data().get_rendered_height(content_y(0)), 1);
+        assert_eq!(
+            test.get_render_data().get_render_y(content_y(1)),
+            Some(canvas_y(1))
+        );
+    }
+
+    #[test]
+    fn test_selection_of_a_wrapped_line_is_highlighted_in_its_rows() {
+        let test = create_app2(35);
+        test.mut_app().set_soft_wrap(true);
+        let w = test.get_render_data().current_editor_width;
+        let long_line = "1+".repeat(w / 2 + 2) + "1";
+        test.paste(&long_line);
+        test.set_selection(Selection::range(
+            Pos::from_row_column(0, w - 2),
+            Pos::from_row_column(0, w + 3),
+        ));
+        test.render();
+
+        let left_gutter_width = test.get_render_data().left_gutter_width;
+        let commands = &test.render_bucket().custom_commands[Layer::BehindText as usize];
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderRectangle {
+                x: left_gutter_width + w - 2,
+                y: canvas_y(0),
+                w: 2,
+                h: 1,
+            },
+        );
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderRectangle {
+                x: left_gutter_width,
+                y: canvas_y(1),
+                w: 3,
+                h: 1,
+            },
+        );
+    }
+
+    #[test]
+    fn test_horizontal_scrolling() {
+        let test = create_app2(35);
+        test.mut_app().set_horizontal_scroll(true);
+        let w = test.get_render_data().current_editor_width;
+        let comment: String = "//".to_owned() + &"x".repeat(w + 8);
+        test.paste(&comment);
+        test.render();
+
+        // the cursor is at the end of the line
+        assert_eq!(test.get_render_data().scroll_x, 10);
+        let left_gutter_width = test.get_render_data().left_gutter_width;
+        let result_gutter_x = test.get_render_data().result_gutter_x;
+        let visible_text: Vec<char> = comment.chars().skip(10).take(w).collect();
+        assert!(test
+            .render_bucket()
+            .utf8_texts
+            .contains(&RenderUtf8TextMsg {
+                text: &visible_text,
+                row: canvas_y(0),
+                column: left_gutter_width,
+            }));
+        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderChar(left_gutter_width, 0, '…'),
+        );
+        assert_contains(
+            commands,
+            0,
+            OutputMessage::RenderChar(result_gutter_x - 1, 0, '…'),
+        );
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderChar(left_gutter_width + w, 0, '▏'),
+        );
+
+        test.click(left_gutter_width + 3, 0);
+        assert_eq!(test.get_cursor_pos(), Pos::from_row_column(0, 13));
+
+        test.input(EditorInputEvent::Home, InputModifiers::none());
+        test.render();
+        assert_eq!(test.get_render_data().scroll_x, 0);
+        let commands = &test.render_bucket().custom_commands[Layer::AboveText as usize];
+        assert_contains(
+            commands,
+            0,
+            OutputMessage::RenderChar(left_gutter_width, 0, '…'),
+        );
+        assert_contains(
+            commands,
+            1,
+            OutputMessage::RenderChar(result_gutter_x - 1, 0, '…'),
+        );
+    }
+
     #[test]
     fn test_that_cursor_is_rendered_at_the_end_of_the_editor() {
         let test = create_app3(44, 32);
@@ -10907,6 +16976,45 @@ monthly payment = r/(1 - (1+r)^(-n)) * finance amount",
         );
     }
 
+    #[test]
+    fn test_block_cursor_is_rendered_as_rect() {
+        let test = create_app3(44, 32);
+        test.mut_app().set_cursor_style(CursorStyle::Block);
+        test.paste("1234567890123456");
+        let render_bucket = test.render_bucket();
+        assert_contains(
+            &render_bucket.custom_commands[Layer::BehindText as usize],
+            1,
+            OutputMessage::RenderRectangle {
+                x: 18,
+                y: canvas_y(0),
+                w: 1,
+                h: 1,
+            },
+        );
+        assert_contains(
+            &render_bucket.custom_commands[Layer::AboveText as usize],
+            0,
+            OutputMessage::RenderChar(18, 0, '▏'),
+        );
+    }
+
+    #[test]
+    fn test_aligned_comments() {
+        let test = create_app2(35);
+        test.mut_app().set_align_comments(true);
+        test.paste("1 // first\n100 * 2 // second");
+        let comment_columns: Vec<usize> = test
+            .render_bucket()
+            .utf8_texts
+            .iter()
+            .filter(|it| it.text.starts_with(&['/', '/']))
+            .map(|it| it.column)
+            .collect();
+        assert_eq!(comment_columns.len(), 2);
+        assert_eq!(comment_columns[0], comment_columns[1]);
+    }
+
     #[test]
     fn results_must_be_rendered() {
         let test = create_app3(84, 36);
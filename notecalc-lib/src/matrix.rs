@@ -1,5 +1,6 @@
 use crate::calc::{divide_op, multiply_op, CalcResult, CalcResultType};
 use crate::MATRIX_ASCII_HEADER_FOOTER_LINE_COUNT;
+use rust_decimal::prelude::*;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct MatrixData {
@@ -90,4 +91,68 @@ impl MatrixData {
 
         return result;
     }
+
+    /// Gauss-Jordan elimination with partial pivoting.
+    /// None if the matrix is not square, it is singular or it has non-number cells.
+    pub fn inverse(&self) -> Option<MatrixData> {
+        if self.row_count != self.col_count {
+            return None;
+        }
+        let n = self.row_count;
+        let mut a: Vec<Decimal> = Vec::with_capacity(n * n);
+        for cell in &self.cells {
+            match &cell.typ {
+                CalcResultType::Number(num) => a.push(*num),
+                _ => return None,
+            }
+        }
+        let mut inv: Vec<Decimal> = (0..n * n)
+            .map(|i| {
+                if i / n == i % n {
+                    Decimal::one()
+                } else {
+                    Decimal::zero()
+                }
+            })
+            .collect();
+
+        for col in 0..n {
+            let pivot_row = (col..n)
+                .filter(|row| !a[row * n + col].is_zero())
+                .max_by(|x, y| a[x * n + col].abs().cmp(&a[y * n + col].abs()))?;
+            if pivot_row != col {
+                for c in 0..n {
+                    a.swap(col * n + c, pivot_row * n + c);
+                    inv.swap(col * n + c, pivot_row * n + c);
+                }
+            }
+            let pivot = a[col * n + col];
+            for c in 0..n {
+                a[col * n + c] = a[col * n + c].checked_div(pivot)?;
+                inv[col * n + c] = inv[col * n + c].checked_div(pivot)?;
+            }
+            for row in 0..n {
+                let factor = a[row * n + col];
+                if row == col || factor.is_zero() {
+                    continue;
+                }
+                for c in 0..n {
+                    a[row * n + c] =
+                        a[row * n + c].checked_sub(factor.checked_mul(a[col * n + c])?)?;
+                    inv[row * n + c] =
+                        inv[row * n + c].checked_sub(factor.checked_mul(inv[col * n + c])?)?;
+                }
+            }
+        }
+
+        let cells = inv
+            .into_iter()
+            .map(|num| {
+                // avoid "-0"
+                let num = if num.is_zero() { Decimal::zero() } else { num };
+                CalcResult::new(CalcResultType::Number(num), 0)
+            })
+            .collect();
+        Some(MatrixData::new(cells, n, n))
+    }
 }
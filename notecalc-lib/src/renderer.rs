@@ -1,6 +1,8 @@
-use crate::calc::{CalcResult, CalcResultType};
+use crate::calc::{round_dp, CalcResult, CalcResultType, ShuntingYardResult};
+use crate::functions::FnType;
+use crate::token_parser::{OperatorTokenType, Token, TokenType};
 use crate::units::units::Units;
-use crate::{ResultFormat, ResultLengths};
+use crate::{ResultFormat, ResultLengths, RoundingMode};
 use byteorder::WriteBytesExt;
 use rust_decimal::prelude::*;
 use rust_decimal::Decimal;
@@ -14,6 +16,8 @@ pub fn render_result(
     there_was_unit_conversion: bool,
     decimal_count: Option<usize>,
     use_grouping: bool,
+    scientific_notation: &ScientificNotation,
+    rounding_mode: RoundingMode,
 ) -> String {
     let mut c = Cursor::new(Vec::with_capacity(64));
     render_result_into(
@@ -24,6 +28,11 @@ pub fn render_result(
         &mut c,
         decimal_count,
         use_grouping,
+        b' ',
+        false,
+        false,
+        scientific_notation,
+        rounding_mode,
     );
     return unsafe { String::from_utf8_unchecked(c.into_inner()) };
 }
@@ -36,10 +45,17 @@ pub fn render_result_into(
     f: &mut impl std::io::Write,
     decimal_count: Option<usize>,
     use_grouping: bool,
+    // only for decimal numbers, hex and binary digits are always grouped by spaces
+    thousands_separator: u8,
+    superscript_exponents: bool,
+    // e.g. 0.(3) for 1/3
+    repeating_decimals: bool,
+    scientific_notation: &ScientificNotation,
+    rounding_mode: RoundingMode,
 ) -> ResultLengths {
     match &result.typ {
         CalcResultType::Quantity(num, unit) => {
-            if *format != ResultFormat::Dec {
+            if !is_decimal_format(format) {
                 f.write_u8(b'E').expect("");
                 f.write_u8(b'r').expect("");
                 f.write_u8(b'r').expect("");
@@ -56,24 +72,35 @@ pub fn render_result_into(
             };
             let unit = final_unit.as_ref().unwrap_or(unit);
             if unit.units.is_empty() {
-                num_to_string(f, &num, &ResultFormat::Dec, decimal_count, use_grouping)
+                num_to_string(
+                    f,
+                    &num,
+                    format,
+                    decimal_count,
+                    use_grouping,
+                    thousands_separator,
+                    repeating_decimals,
+                    scientific_notation,
+                    rounding_mode,
+                )
             } else {
                 let denormalized_num = unit.from_base_to_this_unit(num);
                 if let Some(denormalized_num) = denormalized_num {
                     let mut lens = num_to_string(
                         f,
                         &denormalized_num,
-                        &ResultFormat::Dec,
+                        format,
                         decimal_count,
                         use_grouping,
+                        thousands_separator,
+                        repeating_decimals,
+                        scientific_notation,
+                        rounding_mode,
                     );
                     f.write_u8(b' ').expect("");
                     // TODO:mem to_string -> into(buf)
                     // implement a into(std::io:Write) method for UnitOutput
-                    for ch in unit.to_string().as_bytes() {
-                        f.write_u8(*ch).expect("");
-                        lens.unit_part_len += 1;
-                    }
+                    lens.unit_part_len += write_unit(f, &unit.to_string(), superscript_exponents);
                     lens
                 } else {
                     ResultLengths {
@@ -87,11 +114,7 @@ pub fn render_result_into(
         CalcResultType::Unit(unit) => {
             // TODO:mem to_string -> into(buf)
             // implement a into(std::io:Write) method for UnitOutput
-            let mut len = 0;
-            for ch in unit.to_string().as_bytes() {
-                f.write_u8(*ch).expect("");
-                len += 1;
-            }
+            let len = write_unit(f, &unit.to_string(), superscript_exponents);
             ResultLengths {
                 int_part_len: 0,
                 frac_part_len: 0,
@@ -99,11 +122,33 @@ pub fn render_result_into(
             }
         }
         CalcResultType::Number(num) => {
-            // TODO optimize
-            num_to_string(f, num, format, decimal_count, use_grouping)
+            if result.is_boolean {
+                let text: &[u8] = if num.is_zero() { b"false" } else { b"true" };
+                f.write_all(text).expect("");
+                ResultLengths {
+                    int_part_len: text.len(),
+                    frac_part_len: 0,
+                    unit_part_len: 0,
+                }
+            } else if let Some(radix) = result.radix {
+                radix_to_string(f, num, radix)
+            } else {
+                // TODO optimize
+                num_to_string(
+                    f,
+                    num,
+                    format,
+                    decimal_count,
+                    use_grouping,
+                    thousands_separator,
+                    repeating_decimals,
+                    scientific_notation,
+                    rounding_mode,
+                )
+            }
         }
         CalcResultType::Percentage(num) => {
-            if *format != ResultFormat::Dec {
+            if !is_decimal_format(format) {
                 f.write_u8(b'E').expect("");
                 f.write_u8(b'r').expect("");
                 f.write_u8(b'r').expect("");
@@ -113,8 +158,17 @@ pub fn render_result_into(
                     unit_part_len: 0,
                 };
             } else {
-                let mut lens =
-                    num_to_string(f, num, &ResultFormat::Dec, decimal_count, use_grouping);
+                let mut lens = num_to_string(
+                    f,
+                    num,
+                    format,
+                    decimal_count,
+                    use_grouping,
+                    thousands_separator,
+                    repeating_decimals,
+                    scientific_notation,
+                    rounding_mode,
+                );
                 f.write_u8(b' ').expect("");
                 f.write_u8(b'%').expect("");
                 lens.unit_part_len += 1;
@@ -134,7 +188,20 @@ pub fn render_result_into(
                         f.write_u8(b' ').expect("");
                     }
                     let cell = &mat.cells[row_i * mat.col_count + col_i];
-                    render_result_into(units, cell, format, false, f, decimal_count, use_grouping);
+                    render_result_into(
+                        units,
+                        cell,
+                        format,
+                        false,
+                        f,
+                        decimal_count,
+                        use_grouping,
+                        thousands_separator,
+                        superscript_exponents,
+                        repeating_decimals,
+                        scientific_notation,
+                        rounding_mode,
+                    );
                 }
             }
             f.write_u8(b']').expect("");
@@ -147,19 +214,78 @@ pub fn render_result_into(
     }
 }
 
+/// Writes the unit, optionally replacing the integer exponents with superscript
+/// characters (e.g. "m^2" -> "m²"), and returns the number of written characters.
+fn write_unit(f: &mut impl std::io::Write, unit_str: &str, superscript_exponents: bool) -> usize {
+    if !superscript_exponents {
+        f.write_all(unit_str.as_bytes()).expect("");
+        return unit_str.len();
+    }
+    let mut len = 0;
+    let mut chars = unit_str.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let is_int_exponent = ch == '^'
+            && chars
+                .peek()
+                .map(|it| *it == '-' || it.is_ascii_digit())
+                .unwrap_or(false);
+        if !is_int_exponent {
+            let mut buf = [0; 4];
+            f.write_all(ch.encode_utf8(&mut buf).as_bytes()).expect("");
+            len += 1;
+            continue;
+        }
+        while let Some(superscript) = chars.peek().and_then(|it| to_superscript(*it)) {
+            let mut buf = [0; 4];
+            f.write_all(superscript.encode_utf8(&mut buf).as_bytes())
+                .expect("");
+            len += 1;
+            chars.next();
+        }
+    }
+    return len;
+}
+
+fn to_superscript(ch: char) -> Option<char> {
+    Some(match ch {
+        '0' => '⁰',
+        '1' => '¹',
+        '2' => '²',
+        '3' => '³',
+        '4' => '⁴',
+        '5' => '⁵',
+        '6' => '⁶',
+        '7' => '⁷',
+        '8' => '⁸',
+        '9' => '⁹',
+        '-' => '⁻',
+        _ => return None,
+    })
+}
+
 fn num_to_string(
     f: &mut impl std::io::Write,
     num: &Decimal,
     format: &ResultFormat,
     decimal_count: Option<usize>,
     use_grouping: bool,
+    thousands_separator: u8,
+    repeating_decimals: bool,
+    scientific_notation: &ScientificNotation,
+    rounding_mode: RoundingMode,
 ) -> ResultLengths {
-    let num_a = if *format != ResultFormat::Dec && num.trunc() == *num {
+    if scientific_notation.is_used_for(format, num) {
+        return num_to_scientific_string(f, num, decimal_count, rounding_mode);
+    }
+    let repeating_decimal_string = if repeating_decimals && is_decimal_format(format) {
+        to_repeating_decimal_string(num)
+    } else {
+        None
+    };
+    let num_a = if !is_decimal_format(format) && num.trunc() == *num {
         Some(num.clone())
     } else if let Some(decimal_count) = decimal_count {
-        let mut result = num.clone();
-        result.rescale(decimal_count as u32);
-        Some(result.normalize())
+        Some(round_dp(num, decimal_count as u32, rounding_mode).normalize())
     } else {
         let with_scale_0 = num.trunc();
         if *num == with_scale_0 {
@@ -170,26 +296,27 @@ fn num_to_string(
     };
     let num = num_a.as_ref().unwrap_or(num);
 
-    return if *format == ResultFormat::Bin || *format == ResultFormat::Hex {
+    return if !is_decimal_format(format) {
         if let Some(n) = num.to_i64() {
-            let ss = if *format == ResultFormat::Bin {
-                format!("{:b}", n)
-            } else {
-                format!("{:X}", n)
+            let ss = match format {
+                ResultFormat::Bin => format!("{:b}", n),
+                ResultFormat::Oct => format!("{:o}", n),
+                _ => format!("{:X}", n),
             };
             ResultLengths {
                 int_part_len: apply_grouping(
                     f,
                     &ss,
                     if use_grouping {
-                        if *format == ResultFormat::Bin {
-                            8
-                        } else {
-                            2
+                        match format {
+                            ResultFormat::Bin => 8,
+                            ResultFormat::Oct => 3,
+                            _ => 2,
                         }
                     } else {
                         std::i32::MAX as usize
                     },
+                    b' ',
                 ),
                 frac_part_len: 0,
                 unit_part_len: 0,
@@ -206,7 +333,9 @@ fn num_to_string(
         }
     } else {
         // TODO to_string opt
-        let string = if num.scale() == 0 {
+        let string = if let Some(string) = repeating_decimal_string {
+            string
+        } else if num.scale() == 0 {
             num.to_string()
         } else {
             if let Some(without_repeating_fract) = remove_repeatings(num) {
@@ -226,6 +355,7 @@ fn num_to_string(
                 } else {
                     std::i32::MAX as usize
                 },
+                thousands_separator,
             );
             for ch in fract_part.as_bytes() {
                 f.write_u8(*ch).expect("");
@@ -245,6 +375,7 @@ fn num_to_string(
                     } else {
                         std::i32::MAX as usize
                     },
+                    thousands_separator,
                 ),
                 frac_part_len: 0,
                 unit_part_len: 0,
@@ -253,6 +384,207 @@ fn num_to_string(
     };
 }
 
+// for the results of tobase, e.g. "FF" for tobase(255, 16)
+fn radix_to_string(f: &mut impl std::io::Write, num: &Decimal, radix: u32) -> ResultLengths {
+    let mut num = match num.to_u64() {
+        Some(num) => num,
+        None => {
+            f.write_all(b"Err").expect("");
+            return ResultLengths {
+                int_part_len: 3,
+                frac_part_len: 0,
+                unit_part_len: 0,
+            };
+        }
+    };
+    let mut digits: Vec<u8> = Vec::with_capacity(64);
+    loop {
+        let digit = std::char::from_digit((num % radix as u64) as u32, radix).unwrap_or('?');
+        digits.push(digit.to_ascii_uppercase() as u8);
+        num /= radix as u64;
+        if num == 0 {
+            break;
+        }
+    }
+    for ch in digits.iter().rev() {
+        f.write_u8(*ch).expect("");
+    }
+    ResultLengths {
+        int_part_len: digits.len(),
+        frac_part_len: 0,
+        unit_part_len: 0,
+    }
+}
+
+fn is_decimal_format(format: &ResultFormat) -> bool {
+    *format == ResultFormat::Dec || *format == ResultFormat::Sci
+}
+
+/// ResultFormat::Sci uses scientific notation for numbers whose absolute value is
+/// at least 10^max_exp or less than 10^-min_exp.
+/// If `auto` is set, ResultFormat::Dec switches to it as well.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScientificNotation {
+    pub max_exp: i32,
+    pub min_exp: i32,
+    pub auto: bool,
+}
+
+impl Default for ScientificNotation {
+    fn default() -> Self {
+        ScientificNotation {
+            max_exp: 11,
+            min_exp: 6,
+            auto: false,
+        }
+    }
+}
+
+impl ScientificNotation {
+    fn is_used_for(&self, format: &ResultFormat, num: &Decimal) -> bool {
+        let format_allows_it =
+            *format == ResultFormat::Sci || (self.auto && *format == ResultFormat::Dec);
+        if !format_allows_it || num.is_zero() {
+            return false;
+        }
+        let abs = num.abs();
+        abs >= pow10(self.max_exp) || abs < Decimal::one() / pow10(self.min_exp)
+    }
+}
+
+fn pow10(exp: i32) -> Decimal {
+    let mut result = Decimal::one();
+    for _ in 0..exp {
+        result *= Decimal::from(10);
+    }
+    result
+}
+
+fn num_to_scientific_string(
+    f: &mut impl std::io::Write,
+    num: &Decimal,
+    decimal_count: Option<usize>,
+    rounding_mode: RoundingMode,
+) -> ResultLengths {
+    let ten = Decimal::from(10);
+    let mut mantissa = num.abs();
+    let mut exp: i32 = 0;
+    while mantissa >= ten {
+        mantissa /= ten;
+        exp += 1;
+    }
+    while mantissa < Decimal::one() {
+        mantissa *= ten;
+        exp -= 1;
+    }
+    // the mantissa has one integer digit, so its scale can't be 28
+    let dp = decimal_count.unwrap_or(27).min(27) as u32;
+    // the sign matters for Floor and Ceil
+    mantissa = if num.is_sign_negative() {
+        -round_dp(&-mantissa, dp, rounding_mode)
+    } else {
+        round_dp(&mantissa, dp, rounding_mode)
+    };
+    if mantissa >= ten {
+        // e.g. 9.99999 was rounded up to 10
+        mantissa /= ten;
+        exp += 1;
+    }
+    let mantissa = mantissa.normalize();
+    let mantissa_str = if num.is_sign_negative() {
+        format!("-{}", mantissa)
+    } else {
+        mantissa.to_string()
+    };
+    let exp_str = format!("e{}", exp);
+    for ch in mantissa_str.as_bytes().iter().chain(exp_str.as_bytes()) {
+        f.write_u8(*ch).expect("");
+    }
+    let int_part_len = mantissa_str
+        .bytes()
+        .position(|it| it == b'.')
+        .unwrap_or(mantissa_str.len());
+    ResultLengths {
+        int_part_len,
+        frac_part_len: mantissa_str.len() - int_part_len + exp_str.len(),
+        unit_part_len: 0,
+    }
+}
+
+const MAX_REPEATING_DENOMINATOR: i64 = 10_000;
+const MAX_REPEATING_FRACT_DIGITS: usize = 24;
+
+/// Renders the number with its repeating digits in parentheses, e.g. "0.1(6)" for 1/6,
+/// if it is close enough to a fraction the decimal representation of which is infinite.
+/// Short numbers (e.g. 0.3333) are considered exact.
+fn to_repeating_decimal_string(num: &Decimal) -> Option<String> {
+    if num.scale() < 20 {
+        return None;
+    }
+    let abs = num.abs();
+    let int_part = abs.trunc();
+    let fract = abs - int_part;
+    let tolerance = Decimal::new(1, num.scale() - 2);
+    // the convergents of the continued fraction of `fract`
+    let (mut h, mut prev_h) = (0i64, 1i64);
+    let (mut k, mut prev_k) = (1i64, 0i64);
+    let mut x = fract;
+    loop {
+        let a = x.trunc().to_i64()?;
+        let new_h = a.checked_mul(h)?.checked_add(prev_h)?;
+        let new_k = a.checked_mul(k)?.checked_add(prev_k)?;
+        prev_h = h;
+        prev_k = k;
+        h = new_h;
+        k = new_k;
+        if k > MAX_REPEATING_DENOMINATOR {
+            return None;
+        }
+        if (Decimal::from(h).checked_div(Decimal::from(k))? - fract).abs() <= tolerance {
+            break;
+        }
+        let rest = x - x.trunc();
+        if rest.is_zero() {
+            return None;
+        }
+        x = Decimal::one().checked_div(rest)?;
+    }
+    let mut denominator = k;
+    while denominator % 2 == 0 {
+        denominator /= 2;
+    }
+    while denominator % 5 == 0 {
+        denominator /= 5;
+    }
+    if denominator == 1 {
+        // it has a finite decimal representation
+        return None;
+    }
+    // long division, the digits start repeating when a remainder appears again
+    let mut remainders = Vec::with_capacity(MAX_REPEATING_FRACT_DIGITS);
+    let mut digits = String::with_capacity(MAX_REPEATING_FRACT_DIGITS + 2);
+    let mut remainder = h;
+    let cycle_start = loop {
+        if let Some(pos) = remainders.iter().position(|it| *it == remainder) {
+            break pos;
+        }
+        if remainders.len() == MAX_REPEATING_FRACT_DIGITS {
+            return None;
+        }
+        remainders.push(remainder);
+        remainder *= 10;
+        digits.push((b'0' + (remainder / k) as u8) as char);
+        remainder %= k;
+    };
+    Some(format!(
+        "{}{}.{}({})",
+        if num.is_sign_negative() { "-" } else { "" },
+        int_part,
+        &digits[..cycle_start],
+        &digits[cycle_start..]
+    ))
+}
+
 fn remove_repeatings(num: &Decimal) -> Option<Decimal> {
     let string = num.to_string();
     if let Some(pos) = string.bytes().position(|it| it == b'.') {
@@ -287,7 +619,21 @@ fn remove_repeatings(num: &Decimal) -> Option<Decimal> {
     return None;
 }
 
-fn apply_grouping(f: &mut impl std::io::Write, ss: &str, group_size: usize) -> usize {
+fn apply_grouping(
+    f: &mut impl std::io::Write,
+    ss: &str,
+    group_size: usize,
+    separator: u8,
+) -> usize {
+    let mut len = 0;
+    // the sign is not part of the first group, e.g. "-100" is not "- 100"
+    let ss = if ss.starts_with('-') {
+        f.write_u8(b'-').expect("");
+        len += 1;
+        &ss[1..]
+    } else {
+        ss
+    };
     // TODO isnt it too much/is it enough?
     let mut buf: SmallVec<[u8; 128]> = SmallVec::with_capacity(ss.len());
     for ch in ss.as_bytes() {
@@ -295,10 +641,9 @@ fn apply_grouping(f: &mut impl std::io::Write, ss: &str, group_size: usize) -> u
     }
     let buff = &mut buf[0..ss.len()];
     buff.reverse();
-    let mut len = 0;
     for (i, group) in buff.chunks(group_size).rev().enumerate() {
         if i > 0 {
-            f.write_u8(b' ').expect("");
+            f.write_u8(separator).expect("");
             len += 1;
         }
         for ch in group.iter().rev() {
@@ -351,6 +696,20 @@ pub fn get_int_frac_part_len(cell_str: &str) -> ResultLengths {
     };
 }
 
+/// Non-decimal results have no fractional or unit part, so their grouping spaces
+/// (e.g. "1 FF") belong to the integer part.
+pub fn get_int_frac_part_len_in_format(cell_str: &str, format: &ResultFormat) -> ResultLengths {
+    if is_decimal_format(format) {
+        get_int_frac_part_len(cell_str)
+    } else {
+        ResultLengths {
+            int_part_len: cell_str.len(),
+            frac_part_len: 0,
+            unit_part_len: 0,
+        }
+    }
+}
+
 // TODO: really hack and ugly and slow
 // pub fn strip_trailing_zeroes(num: &BigDecimal) -> BigDecimal {
 //     let (_, mut scale) = num.as_bigint_and_exponent();
@@ -369,3 +728,215 @@ pub fn get_int_frac_part_len(cell_str: &str) -> ResultLengths {
 //     }
 //     return result;
 // }
+
+// the precedence of the LaTeX snippets which never need parentheses around them
+const LATEX_ATOM_PRECEDENCE: usize = std::usize::MAX;
+
+/// Converts the shunting yard output of a line into LaTeX, e.g. "[1,2] / 3^2" is rendered as
+/// "\frac{\begin{bmatrix}1 & 2\end{bmatrix}}{3^{2}}".
+/// Returns None for lines which can't be expressed this way (e.g. conditionals).
+pub fn render_latex(tokens: &[Token], shunting_output: &[ShuntingYardResult]) -> Option<String> {
+    // the LaTeX string and the precedence of its outermost operator
+    let mut stack: Vec<(String, usize)> = Vec::with_capacity(8);
+    let token_text = |index: usize| -> String {
+        tokens[index]
+            .ptr
+            .iter()
+            .filter(|it| !it.is_whitespace())
+            .collect()
+    };
+    for item in shunting_output {
+        match &item.typ {
+            TokenType::NumberLiteral(_)
+            | TokenType::Variable { .. }
+            | TokenType::BoundVariable { .. } => {
+                stack.push((token_text(item.index_into_tokens), LATEX_ATOM_PRECEDENCE));
+            }
+            TokenType::Unit(unit) => {
+                stack.push((format!("\\mathrm{{{}}}", unit), LATEX_ATOM_PRECEDENCE));
+            }
+            TokenType::Operator(op) => {
+                let latex = match op {
+                    OperatorTokenType::Comma | OperatorTokenType::Semicolon => continue,
+                    // the markers in front of the lazy arguments, see `ShuntingYard::shunting_yard`
+                    OperatorTokenType::Fn { arg_count: 0, typ } if typ.has_lazy_args() => continue,
+                    OperatorTokenType::Assign => {
+                        let (value, _) = stack.pop()?;
+                        let name: String = tokens[0..item.index_into_tokens]
+                            .iter()
+                            .flat_map(|it| it.ptr.iter())
+                            .collect();
+                        (format!("{} = {}", name.trim(), value), 0)
+                    }
+                    OperatorTokenType::Div => {
+                        let (rhs, _) = stack.pop()?;
+                        let (lhs, _) = stack.pop()?;
+                        (
+                            format!("\\frac{{{}}}{{{}}}", lhs, rhs),
+                            LATEX_ATOM_PRECEDENCE,
+                        )
+                    }
+                    OperatorTokenType::Pow => {
+                        let (exp, _) = stack.pop()?;
+                        let base = stack.pop()?;
+                        (
+                            format!("{}^{{{}}}", latex_paren(base, op.precedence() + 1), exp),
+                            op.precedence(),
+                        )
+                    }
+                    OperatorTokenType::Add
+                    | OperatorTokenType::Sub
+                    | OperatorTokenType::Mult
+                    | OperatorTokenType::Mod
+                    | OperatorTokenType::BinAnd
+                    | OperatorTokenType::BinOr
+                    | OperatorTokenType::BinXor
+                    | OperatorTokenType::ShiftLeft
+                    | OperatorTokenType::ShiftRight
+                    | OperatorTokenType::Less
+                    | OperatorTokenType::Greater
+                    | OperatorTokenType::LessEq
+                    | OperatorTokenType::GreaterEq
+                    | OperatorTokenType::Eq
+                    | OperatorTokenType::NotEq
+                    | OperatorTokenType::UnitConverter => {
+                        let op_str = match op {
+                            OperatorTokenType::Add => "+",
+                            OperatorTokenType::Sub => "-",
+                            OperatorTokenType::Mult => "\\cdot",
+                            OperatorTokenType::Mod => "\\bmod",
+                            OperatorTokenType::BinAnd => "\\mathbin{\\&}",
+                            OperatorTokenType::BinOr => "\\mathbin{|}",
+                            OperatorTokenType::BinXor => "\\oplus",
+                            OperatorTokenType::ShiftLeft => "\\ll",
+                            OperatorTokenType::ShiftRight => "\\gg",
+                            OperatorTokenType::Less => "<",
+                            OperatorTokenType::Greater => ">",
+                            OperatorTokenType::LessEq => "\\le",
+                            OperatorTokenType::GreaterEq => "\\ge",
+                            OperatorTokenType::Eq => "=",
+                            OperatorTokenType::NotEq => "\\ne",
+                            _ => "\\to",
+                        };
+                        let rhs = stack.pop()?;
+                        let lhs = stack.pop()?;
+                        let prec = op.precedence();
+                        (
+                            format!(
+                                "{} {} {}",
+                                latex_paren(lhs, prec),
+                                op_str,
+                                // e.g. "a - (b + c)"
+                                latex_paren(rhs, prec + 1)
+                            ),
+                            prec,
+                        )
+                    }
+                    OperatorTokenType::UnaryMinus
+                    | OperatorTokenType::UnaryPlus
+                    | OperatorTokenType::BinNot => {
+                        let operand = latex_paren(stack.pop()?, op.precedence());
+                        let op_str = match op {
+                            OperatorTokenType::UnaryMinus => "-",
+                            OperatorTokenType::UnaryPlus => "+",
+                            _ => "\\sim ",
+                        };
+                        (format!("{}{}", op_str, operand), op.precedence())
+                    }
+                    OperatorTokenType::Perc | OperatorTokenType::Factorial => {
+                        let operand = latex_paren(stack.pop()?, op.precedence() + 1);
+                        let op_str = if *op == OperatorTokenType::Perc {
+                            "\\%"
+                        } else {
+                            "!"
+                        };
+                        (format!("{}{}", operand, op_str), op.precedence())
+                    }
+                    OperatorTokenType::ApplyUnit(unit) => {
+                        let operand = latex_paren(stack.pop()?, op.precedence());
+                        (
+                            format!("{}\\ \\mathrm{{{}}}", operand, unit),
+                            op.precedence(),
+                        )
+                    }
+                    OperatorTokenType::Matrix {
+                        row_count,
+                        col_count,
+                    } => {
+                        let cell_count = row_count * col_count;
+                        if stack.len() < cell_count {
+                            return None;
+                        }
+                        let cells: Vec<String> = stack
+                            .drain(stack.len() - cell_count..)
+                            .map(|it| it.0)
+                            .collect();
+                        let rows: Vec<String> = cells
+                            .chunks(*col_count)
+                            .map(|row| row.join(" & "))
+                            .collect();
+                        (
+                            format!("\\begin{{bmatrix}}{}\\end{{bmatrix}}", rows.join(" \\\\ ")),
+                            LATEX_ATOM_PRECEDENCE,
+                        )
+                    }
+                    OperatorTokenType::Fn { arg_count, typ } => {
+                        if stack.len() < *arg_count {
+                            return None;
+                        }
+                        let args: Vec<String> = stack
+                            .drain(stack.len() - arg_count..)
+                            .map(|it| it.0)
+                            .collect();
+                        let latex = match typ {
+                            FnType::Sqrt if *arg_count == 1 => format!("\\sqrt{{{}}}", args[0]),
+                            FnType::Abs if *arg_count == 1 => {
+                                format!("\\left|{}\\right|", args[0])
+                            }
+                            _ => {
+                                // the user defined functions have no builtin name
+                                let name = if typ.name().is_empty() {
+                                    token_text(item.index_into_tokens)
+                                } else {
+                                    typ.name().iter().collect::<String>()
+                                };
+                                format!(
+                                    "\\operatorname{{{}}}\\left({}\\right)",
+                                    name,
+                                    args.join(", ")
+                                )
+                            }
+                        };
+                        (latex, LATEX_ATOM_PRECEDENCE)
+                    }
+                    OperatorTokenType::Question
+                    | OperatorTokenType::Colon
+                    | OperatorTokenType::ParenOpen
+                    | OperatorTokenType::ParenClose
+                    | OperatorTokenType::AbsBarOpen
+                    | OperatorTokenType::AbsBarClose
+                    | OperatorTokenType::BracketOpen
+                    | OperatorTokenType::BracketClose => return None,
+                };
+                stack.push(latex);
+            }
+            TokenType::LineReference { .. }
+            | TokenType::NumberErr
+            | TokenType::StringLiteral
+            | TokenType::Header => return None,
+        }
+    }
+    if stack.len() == 1 {
+        stack.pop().map(|it| it.0)
+    } else {
+        None
+    }
+}
+
+fn latex_paren((latex, precedence): (String, usize), min_precedence: usize) -> String {
+    if precedence < min_precedence {
+        format!("\\left({}\\right)", latex)
+    } else {
+        latex
+    }
+}
@@ -1,7 +1,7 @@
 use crate::calc::ShuntingYardResult;
 use crate::functions::FnType;
 use crate::token_parser::{Assoc, OperatorTokenType, Token, TokenType};
-use std::ops::Neg;
+use std::ops::{Neg, Range};
 
 #[derive(Eq, PartialEq, Debug)]
 enum ValidationTokenType {
@@ -23,6 +23,8 @@ struct FnStackEntry {
     typ: FnType,
     fn_arg_count: usize,
     fn_token_index: usize,
+    // see `ShuntingYard::shunting_yard`
+    marks_args: bool,
 }
 
 #[derive(Debug)]
@@ -45,11 +47,12 @@ impl ParenStackEntry {
         })
     }
 
-    fn new_fn(typ: FnType, fn_token_index: usize) -> ParenStackEntry {
+    fn new_fn(typ: FnType, fn_token_index: usize, marks_args: bool) -> ParenStackEntry {
         ParenStackEntry::Fn(FnStackEntry {
             typ,
             fn_arg_count: 1,
             fn_token_index,
+            marks_args,
         })
     }
 }
@@ -227,6 +230,39 @@ impl ValidationState {
 
 pub struct ShuntingYard {}
 
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum SyntaxErrorKind {
+    /// e.g. the '*' in "2 + * 3" or the trailing '+' in "2 +"
+    MissingOperand,
+    /// e.g. "(1, 2)"
+    MisplacedComma,
+    UnbalancedParenthesis,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct SyntaxError {
+    pub kind: SyntaxErrorKind,
+    /// character range of the erroneous token inside the line
+    pub columns: Range<usize>,
+}
+
+fn token_columns(tokens: &[Token], token_index: usize) -> Range<usize> {
+    let start: usize = tokens[0..token_index].iter().map(|it| it.ptr.len()).sum();
+    start..start + tokens[token_index].ptr.len()
+}
+
+fn add_syntax_error(
+    syntax_errors: &mut Vec<SyntaxError>,
+    tokens: &[Token],
+    token_index: usize,
+    kind: SyntaxErrorKind,
+) {
+    syntax_errors.push(SyntaxError {
+        kind,
+        columns: token_columns(tokens, token_index),
+    });
+}
+
 fn to_out(output_stack: &mut Vec<ShuntingYardResult>, typ: &TokenType, input_index: isize) {
     output_stack.push(ShuntingYardResult::new(typ.clone(), input_index as usize))
 }
@@ -242,9 +278,23 @@ pub struct ShuntingYardOperatorResult {
 }
 
 impl ShuntingYard {
+    /// The calls of the functions with lazy arguments (`FnType::has_lazy_args`) typed by the
+    /// tokenizer put a copy of their `Fn` entry with 0 arguments in front of each of their
+    /// arguments, so the evaluator can find and evaluate them when it needs.
     pub fn shunting_yard<'text_ptr>(
         tokens: &mut Vec<Token<'text_ptr>>,
         output_stack: &mut Vec<ShuntingYardResult>,
+    ) {
+        ShuntingYard::shunting_yard_with_errors(tokens, output_stack, &mut Vec::new());
+    }
+
+    /// Same as `shunting_yard`, but also collects the syntax errors which made parts of
+    /// the line invalid. Plain text is not reported, only the errors which
+    /// can't be part of a note, e.g. an operator without its operand.
+    pub fn shunting_yard_with_errors<'text_ptr>(
+        tokens: &mut Vec<Token<'text_ptr>>,
+        output_stack: &mut Vec<ShuntingYardResult>,
+        syntax_errors: &mut Vec<SyntaxError>,
     ) {
         // TODO: into iter!!!
         // TODO:mem extract out so no alloc SmallVec?
@@ -252,6 +302,10 @@ impl ShuntingYard {
 
         let mut v = ValidationState::new();
         let mut input_index: isize = -1;
+        let mut last_operator_index: Option<usize> = None;
+        // rollbacks clear the parenthesis_stack, so the parens are counted separately for
+        // the error reports (by columns, since matrix tokens are inserted into `tokens`)
+        let mut open_paren_columns: Vec<Range<usize>> = vec![];
 
         while input_index + 1 < tokens.len() as isize {
             input_index += 1; // it is here so it is incremented always when "continue"
@@ -260,8 +314,13 @@ impl ShuntingYard {
                 TokenType::Header => {
                     return;
                 }
-                TokenType::StringLiteral => {
-                    if let Some(fn_type) = FnType::value_of(input_token.ptr) {
+                TokenType::StringLiteral | TokenType::Operator(OperatorTokenType::Fn { .. }) => {
+                    // the tokenizer types the functions which are not builtin
+                    let typed_fn = match input_token.typ {
+                        TokenType::Operator(OperatorTokenType::Fn { typ, .. }) => Some(typ),
+                        _ => None,
+                    };
+                    if let Some(fn_type) = typed_fn.or_else(|| FnType::value_of(input_token.ptr)) {
                         // next token is parenthesis
                         if tokens
                             .get(input_index as usize + 1)
@@ -275,14 +334,27 @@ impl ShuntingYard {
                                     typ: fn_type,
                                 });
 
-                            v.parenthesis_stack
-                                .push(ParenStackEntry::new_fn(fn_type, input_index as usize));
+                            let marks_args = typed_fn.is_some() && fn_type.has_lazy_args();
+                            if marks_args {
+                                to_out2(
+                                    output_stack,
+                                    tokens[input_index as usize].typ.clone(),
+                                    input_index,
+                                );
+                            }
+                            v.parenthesis_stack.push(ParenStackEntry::new_fn(
+                                fn_type,
+                                input_index as usize,
+                                marks_args,
+                            ));
                             v.prev_token_type = ValidationTokenType::Nothing;
                             v.expect_expression = true;
                             operator_stack.push(ShuntingYardOperatorResult {
                                 op_type: OperatorTokenType::ParenOpen,
                                 index_into_tokens: input_index + 1,
                             });
+                            open_paren_columns
+                                .push(token_columns(tokens, input_index as usize + 1));
                             // skip the next paren
                             input_index += 1;
                             continue;
@@ -295,6 +367,9 @@ impl ShuntingYard {
                     if v.valid_range_start_token_index == input_index as usize {
                         v.valid_range_start_token_index += 1;
                     }
+                    if typed_fn.is_some() {
+                        tokens[input_index as usize].typ = TokenType::StringLiteral;
+                    }
                 }
                 TokenType::Unit(_) => {
                     // TODO: a token ownershipjét nem vehetem el mert kell a rendereléshez (checkold le azért)
@@ -328,20 +403,52 @@ impl ShuntingYard {
                             op_type: op.clone(),
                             index_into_tokens: input_index,
                         });
+                        open_paren_columns.push(token_columns(tokens, input_index as usize));
                         v.parenthesis_stack.push(ParenStackEntry::Simple);
                         v.prev_token_type = ValidationTokenType::Nothing;
                     }
-                    OperatorTokenType::ParenClose => {
+                    OperatorTokenType::AbsBarOpen => {
+                        // |x| is evaluated as abs(x)
+                        v.parenthesis_stack.push(ParenStackEntry::new_fn(
+                            FnType::Abs,
+                            input_index as usize,
+                            false,
+                        ));
+                        v.prev_token_type = ValidationTokenType::Nothing;
+                        v.expect_expression = true;
+                        operator_stack.push(ShuntingYardOperatorResult {
+                            op_type: OperatorTokenType::ParenOpen,
+                            index_into_tokens: input_index,
+                        });
+                    }
+                    OperatorTokenType::ParenClose | OperatorTokenType::AbsBarClose => {
+                        let closes_abs_bar = matches!(op, OperatorTokenType::AbsBarClose);
                         let is_error = match v.parenthesis_stack.last() {
                             None | Some(ParenStackEntry::Matrix(..)) => true,
-                            Some(ParenStackEntry::Simple) | Some(ParenStackEntry::Fn(..)) => false,
+                            Some(ParenStackEntry::Simple) => closes_abs_bar,
+                            Some(ParenStackEntry::Fn(fn_entry)) => {
+                                let opened_by_abs_bar = matches!(
+                                    tokens[fn_entry.fn_token_index].typ,
+                                    TokenType::Operator(OperatorTokenType::AbsBarOpen)
+                                );
+                                closes_abs_bar != opened_by_abs_bar
+                            }
                         };
-                        let prev_token_is_open_paren = input_index > 0
+                        let prev_token_is_open_paren = !closes_abs_bar
+                            && input_index > 0
                             && matches!(
                                 tokens[(input_index - 1) as usize].typ,
                                 TokenType::Operator(OperatorTokenType::ParenOpen)
                             );
 
+                        if !closes_abs_bar && open_paren_columns.pop().is_none() {
+                            add_syntax_error(
+                                syntax_errors,
+                                tokens,
+                                input_index as usize,
+                                SyntaxErrorKind::UnbalancedParenthesis,
+                            );
+                        }
                         if !prev_token_is_open_paren && (v.expect_expression || is_error) {
                             ShuntingYard::rollback(
                                 &mut operator_stack,
@@ -537,6 +644,16 @@ impl ShuntingYard {
                     }
                     OperatorTokenType::Comma => {
                         if v.is_comma_not_allowed() {
+                            if !v.parenthesis_stack.is_empty()
+                                || v.prev_token_type == ValidationTokenType::Op
+                            {
+                                add_syntax_error(
+                                    syntax_errors,
+                                    tokens,
+                                    input_index as usize,
+                                    SyntaxErrorKind::MisplacedComma,
+                                );
+                            }
                             ShuntingYard::rollback(
                                 &mut operator_stack,
                                 output_stack,
@@ -556,6 +673,22 @@ impl ShuntingYard {
                             &mut v.last_valid_output_range,
                             input_index,
                         );
+                        if let Some(ParenStackEntry::Fn(FnStackEntry {
+                            typ,
+                            fn_token_index,
+                            marks_args: true,
+                            ..
+                        })) = v.parenthesis_stack.last()
+                        {
+                            to_out2(
+                                output_stack,
+                                TokenType::Operator(OperatorTokenType::Fn {
+                                    arg_count: 0,
+                                    typ: *typ,
+                                }),
+                                *fn_token_index as isize,
+                            );
+                        }
                     }
                     OperatorTokenType::Semicolon => {
                         if v.open_brackets == 0 || v.is_matrix_row_len_err() {
@@ -579,7 +712,21 @@ impl ShuntingYard {
                             input_index,
                         );
                     }
-                    OperatorTokenType::Perc => {
+                    OperatorTokenType::Factorial
+                        if v.expect_expression
+                            || tokens[input_index as usize - 1].typ == TokenType::StringLiteral =>
+                    {
+                        // e.g. "5 apples!", the '!' has to follow its operand directly
+                        ShuntingYard::rollback(
+                            &mut operator_stack,
+                            output_stack,
+                            input_index + 1,
+                            &mut v,
+                        );
+                        continue;
+                    }
+                    OperatorTokenType::Perc | OperatorTokenType::Factorial => {
+                        // postfix operators are applied immediately on the previous operand
                         to_out2(output_stack, TokenType::Operator(op.clone()), input_index);
                         v.prev_token_type = ValidationTokenType::Expr;
                         if v.can_be_valid_closing_token() {
@@ -672,11 +819,108 @@ impl ShuntingYard {
                             // it is not an "in" operator but a string literal
                         }
                     }
+                    OperatorTokenType::Question => {
+                        if v.expect_expression {
+                            ShuntingYard::rollback(
+                                &mut operator_stack,
+                                output_stack,
+                                input_index + 1,
+                                &mut v,
+                            );
+                            continue;
+                        }
+                        v.had_operator = true;
+                        v.expect_expression = true;
+                        v.prev_token_type = ValidationTokenType::Op;
+                        ShuntingYard::operator_rule(
+                            op,
+                            &mut operator_stack,
+                            output_stack,
+                            &mut v.last_valid_operator_index,
+                            &mut v.last_valid_output_range,
+                            input_index,
+                        );
+                        // the condition is complete, calc jumps from here to the
+                        // else branch if it is false
+                        to_out(output_stack, &input_token.typ, input_index);
+                        operator_stack.push(ShuntingYardOperatorResult {
+                            op_type: op.clone(),
+                            index_into_tokens: input_index,
+                        });
+                    }
+                    OperatorTokenType::Colon => {
+                        let question_index = operator_stack
+                            .iter()
+                            .rposition(|it| {
+                                matches!(
+                                    it.op_type,
+                                    OperatorTokenType::Question
+                                        | OperatorTokenType::ParenOpen
+                                        | OperatorTokenType::BracketOpen
+                                )
+                            })
+                            .filter(|it| {
+                                operator_stack[*it].op_type == OperatorTokenType::Question
+                            });
+                        let question_index = match question_index {
+                            Some(question_index) if !v.expect_expression => question_index,
+                            _ => {
+                                ShuntingYard::rollback(
+                                    &mut operator_stack,
+                                    output_stack,
+                                    input_index + 1,
+                                    &mut v,
+                                );
+                                continue;
+                            }
+                        };
+                        v.had_operator = true;
+                        v.expect_expression = true;
+                        v.prev_token_type = ValidationTokenType::Op;
+                        // finish the then branch
+                        while operator_stack.len() > question_index + 1 {
+                            if v.last_valid_operator_index == Some(operator_stack.len() - 1) {
+                                v.last_valid_operator_index = None;
+                                v.last_valid_output_range.as_mut().expect("ok").1 += 1;
+                            }
+                            let top = operator_stack.pop().expect("ok");
+                            to_out2(
+                                output_stack,
+                                TokenType::Operator(top.op_type),
+                                top.index_into_tokens,
+                            );
+                        }
+                        // the '?' is replaced by the ':', which marks the end of the else branch
+                        // when it is popped
+                        operator_stack.pop();
+                        if v.last_valid_operator_index
+                            .map(|it| it >= question_index)
+                            .unwrap_or(false)
+                        {
+                            v.last_valid_operator_index = None;
+                        }
+                        // calc jumps from here to the end of the else branch
+                        to_out(output_stack, &input_token.typ, input_index);
+                        operator_stack.push(ShuntingYardOperatorResult {
+                            op_type: op.clone(),
+                            index_into_tokens: input_index,
+                        });
+                    }
                     OperatorTokenType::UnaryPlus | OperatorTokenType::UnaryMinus => {
                         panic!("Token parser does not generate unary operators");
                     }
                     _ => {
                         if !matches!(op, OperatorTokenType::BinNot) && v.expect_expression {
+                            if v.prev_token_type == ValidationTokenType::Op
+                                || !v.parenthesis_stack.is_empty()
+                            {
+                                add_syntax_error(
+                                    syntax_errors,
+                                    tokens,
+                                    input_index as usize,
+                                    SyntaxErrorKind::MissingOperand,
+                                );
+                            }
                             ShuntingYard::rollback(
                                 &mut operator_stack,
                                 output_stack,
@@ -700,6 +944,7 @@ impl ShuntingYard {
                             op_type: op.clone(),
                             index_into_tokens: input_index,
                         });
+                        last_operator_index = Some(input_index as usize);
                     }
                 },
                 TokenType::NumberErr => {
@@ -725,7 +970,9 @@ impl ShuntingYard {
                         &mut input_index,
                     );
                 }
-                TokenType::Variable { .. } | TokenType::LineReference { .. } => {
+                TokenType::Variable { .. }
+                | TokenType::LineReference { .. }
+                | TokenType::BoundVariable { .. } => {
                     if !v.expect_expression {
                         ShuntingYard::rollback(
                             &mut operator_stack,
@@ -750,6 +997,29 @@ impl ShuntingYard {
             }
         }
 
+        // an operator at the end of the line without its right operand
+        let last_nonws_token_index = tokens
+            .iter()
+            .rposition(|it| !it.is_string() || it.ptr.iter().any(|ch| !ch.is_whitespace()));
+        if v.prev_token_type == ValidationTokenType::Op
+            && last_operator_index.is_some()
+            && last_operator_index == last_nonws_token_index
+        {
+            add_syntax_error(
+                syntax_errors,
+                tokens,
+                last_operator_index.expect("ok"),
+                SyntaxErrorKind::MissingOperand,
+            );
+        }
+        // parenthesis which were not closed
+        for columns in open_paren_columns {
+            syntax_errors.push(SyntaxError {
+                kind: SyntaxErrorKind::UnbalancedParenthesis,
+                columns,
+            });
+        }
+
         if v.last_valid_output_range.is_some() {
             ShuntingYard::send_everything_to_output(
                 &mut operator_stack,
@@ -908,12 +1178,23 @@ impl ShuntingYard {
         {
             return;
         }
+        let is_conditional = |op: &OperatorTokenType| {
+            matches!(op, OperatorTokenType::Question | OperatorTokenType::Colon)
+        };
+        if is_conditional(&top_of_stack.op_type) {
+            // only a ':' can close the branches of a conditional, or the end of the expression
+            return;
+        }
         let incoming_op_precedence = incoming_op.precedence();
         let top_of_stack_precedence = top_of_stack.op_type.precedence();
         let assoc = incoming_op.assoc();
         let incoming_prec_left_assoc_and_equal =
             assoc == Assoc::Left && incoming_op_precedence == top_of_stack_precedence;
-        if incoming_op_precedence < top_of_stack_precedence || incoming_prec_left_assoc_and_equal {
+        // the conditional operator has the lowest precedence
+        if is_conditional(incoming_op)
+            || incoming_op_precedence < top_of_stack_precedence
+            || incoming_prec_left_assoc_and_equal
+        {
             if let Some(last_valid_operator_index) = maybe_last_valid_operator_index.as_mut() {
                 if *last_valid_operator_index == (operator_stack.len() - 1) {
                     *maybe_last_valid_operator_index = None;
@@ -1374,6 +1655,20 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_bit_operator_precedence() {
+        test_output(
+            "1 << 3 > 4",
+            &[
+                num(1),
+                num(3),
+                op(OperatorTokenType::ShiftLeft),
+                num(4),
+                op(OperatorTokenType::Greater),
+            ],
+        );
+    }
+
     #[test]
     fn test_binary_not() {
         test_output("NOT(0b11)", &[num(0b11), op(OperatorTokenType::BinNot)]);
@@ -2163,6 +2458,32 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_variadic_fn_arg_count() {
+        test_output(
+            "max(3, 7, 2)",
+            &[
+                num(3),
+                num(7),
+                num(2),
+                op(OperatorTokenType::Fn {
+                    arg_count: 3,
+                    typ: FnType::Max,
+                }),
+            ],
+        );
+        test_output(
+            "min(1)",
+            &[
+                num(1),
+                op(OperatorTokenType::Fn {
+                    arg_count: 1,
+                    typ: FnType::Min,
+                }),
+            ],
+        );
+    }
+
     #[test]
     fn test_missing_arg_nth_panic() {
         test_tokens(
@@ -2329,6 +2650,42 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_conditional_operator() {
+        test_output(
+            "1 > 2 ? 3 + 4 : 5",
+            &[
+                num(1),
+                num(2),
+                op(OperatorTokenType::Greater),
+                op(OperatorTokenType::Question),
+                num(3),
+                num(4),
+                op(OperatorTokenType::Add),
+                op(OperatorTokenType::Colon),
+                num(5),
+                op(OperatorTokenType::Colon),
+            ],
+        );
+        // right associative
+        test_output(
+            "1 ? 2 : 3 ? 4 : 5",
+            &[
+                num(1),
+                op(OperatorTokenType::Question),
+                num(2),
+                op(OperatorTokenType::Colon),
+                num(3),
+                op(OperatorTokenType::Question),
+                num(4),
+                op(OperatorTokenType::Colon),
+                num(5),
+                op(OperatorTokenType::Colon),
+                op(OperatorTokenType::Colon),
+            ],
+        );
+    }
+
     #[test]
     fn test_yl_parsing() {
         test_output("909636Yl", &[num(909636), apply_to_prev_token_unit("Yl")]);
@@ -1,6 +1,12 @@
-use crate::functions::FnType;
+use crate::functions::{
+    find_base_digits_arg, find_bindings, identifier_end, is_identifier_char, Binding, FnNames,
+    FnType,
+};
 use crate::units::units::{UnitOutput, Units};
-use crate::{Variables, SUM_VARIABLE_INDEX};
+use crate::{
+    Variables, FIRST_CARRIED_VAR_INDEX, FIRST_EXTERNAL_VAR_INDEX, MAX_EXTERNAL_VAR_COUNT,
+    SUM_VARIABLE_INDEX,
+};
 use bumpalo::Bump;
 use rust_decimal::prelude::*;
 use std::str::FromStr;
@@ -12,6 +18,8 @@ pub enum TokenType {
     // index to the variable vec
     Variable { var_index: usize },
     LineReference { var_index: usize },
+    // a parameter of the tokenized function body, index into the values bound by the evaluator
+    BoundVariable { index: usize },
     NumberLiteral(Decimal),
     Operator(OperatorTokenType),
     Unit(UnitOutput),
@@ -26,6 +34,7 @@ pub struct Token<'a> {
 }
 
 const PI: Decimal = Decimal::from_parts(1102470953, 185874565, 1703060790, false, 28);
+const E: Decimal = Decimal::from_parts(2239425882, 3958169141, 1473583531, false, 28);
 
 impl<'text_ptr> Token<'text_ptr> {
     pub fn is_number(&self) -> bool {
@@ -59,7 +68,10 @@ pub enum OperatorTokenType {
     UnaryMinus,
     Mult,
     Div,
+    // "17 mod 5", the remainder of the truncated division
+    Mod,
     Perc,
+    Factorial,
     BinAnd,
     BinOr,
     BinXor,
@@ -67,11 +79,23 @@ pub enum OperatorTokenType {
     Pow,
     ParenOpen,
     ParenClose,
+    // |x| is the absolute value of x
+    AbsBarOpen,
+    AbsBarClose,
     BracketOpen,
     Semicolon,
     BracketClose,
     ShiftLeft,
     ShiftRight,
+    Less,
+    Greater,
+    LessEq,
+    GreaterEq,
+    Eq,
+    NotEq,
+    // the two parts of the conditional operator, e.g. "x > 2 ? 10 : 20"
+    Question,
+    Colon,
     Assign,
     UnitConverter,
     ApplyUnit(UnitOutput),
@@ -88,22 +112,32 @@ pub enum Assoc {
 impl OperatorTokenType {
     pub fn precedence(&self) -> usize {
         match self {
-            OperatorTokenType::Add => 2,
-            OperatorTokenType::UnaryPlus => 4,
-            OperatorTokenType::Sub => 2,
-            OperatorTokenType::UnaryMinus => 4,
-            OperatorTokenType::Mult => 3,
-            OperatorTokenType::Div => 3,
-            OperatorTokenType::Perc => 6,
-            OperatorTokenType::BinAnd => 0,
-            OperatorTokenType::BinOr => 0,
-            OperatorTokenType::BinXor => 0,
-            OperatorTokenType::BinNot => 4,
-            OperatorTokenType::Pow => 6,
+            OperatorTokenType::Add => 3,
+            OperatorTokenType::UnaryPlus => 5,
+            OperatorTokenType::Sub => 3,
+            OperatorTokenType::UnaryMinus => 5,
+            OperatorTokenType::Mult => 4,
+            OperatorTokenType::Div => 4,
+            OperatorTokenType::Mod => 4,
+            OperatorTokenType::Perc => 7,
+            OperatorTokenType::Factorial => 7,
+            OperatorTokenType::BinAnd => 2,
+            OperatorTokenType::BinOr => 2,
+            OperatorTokenType::BinXor => 2,
+            OperatorTokenType::BinNot => 5,
+            OperatorTokenType::Pow => 7,
             OperatorTokenType::ParenOpen => 0,
             OperatorTokenType::ParenClose => 0,
-            OperatorTokenType::ShiftLeft => 0,
-            OperatorTokenType::ShiftRight => 0,
+            OperatorTokenType::AbsBarOpen | OperatorTokenType::AbsBarClose => 0,
+            OperatorTokenType::ShiftLeft => 2,
+            OperatorTokenType::ShiftRight => 2,
+            OperatorTokenType::Less
+            | OperatorTokenType::Greater
+            | OperatorTokenType::LessEq
+            | OperatorTokenType::GreaterEq
+            | OperatorTokenType::Eq
+            | OperatorTokenType::NotEq => 1,
+            OperatorTokenType::Question | OperatorTokenType::Colon => 0,
             OperatorTokenType::Assign => 0,
             OperatorTokenType::UnitConverter => 0,
             OperatorTokenType::Semicolon | OperatorTokenType::Comma => 0,
@@ -111,7 +145,7 @@ impl OperatorTokenType {
             OperatorTokenType::BracketClose => 0,
             OperatorTokenType::Matrix { .. } => 0,
             OperatorTokenType::Fn { .. } => 0,
-            OperatorTokenType::ApplyUnit(_) => 5,
+            OperatorTokenType::ApplyUnit(_) => 6,
         }
     }
 
@@ -124,15 +158,25 @@ impl OperatorTokenType {
             OperatorTokenType::UnaryMinus => Assoc::Left,
             OperatorTokenType::Mult => Assoc::Left,
             OperatorTokenType::Div => Assoc::Left,
+            OperatorTokenType::Mod => Assoc::Left,
             OperatorTokenType::Perc => Assoc::Left,
+            OperatorTokenType::Factorial => Assoc::Left,
             OperatorTokenType::BinAnd => Assoc::Left,
             OperatorTokenType::BinOr => Assoc::Left,
             OperatorTokenType::BinXor => Assoc::Left,
             OperatorTokenType::BinNot => Assoc::Left,
             OperatorTokenType::Pow => Assoc::Right,
             OperatorTokenType::ParenOpen => Assoc::Left,
+            OperatorTokenType::AbsBarOpen | OperatorTokenType::AbsBarClose => Assoc::Left,
             OperatorTokenType::ShiftLeft => Assoc::Left,
             OperatorTokenType::ShiftRight => Assoc::Left,
+            OperatorTokenType::Less
+            | OperatorTokenType::Greater
+            | OperatorTokenType::LessEq
+            | OperatorTokenType::GreaterEq
+            | OperatorTokenType::Eq
+            | OperatorTokenType::NotEq => Assoc::Left,
+            OperatorTokenType::Question | OperatorTokenType::Colon => Assoc::Right,
             OperatorTokenType::Assign => Assoc::Left,
             OperatorTokenType::UnitConverter => Assoc::Left,
             // Right, so 1 comma won't replace an other on the operator stack
@@ -163,6 +207,34 @@ impl TokenParser {
         units: &Units,
         line_index: usize,
         allocator: &'text_ptr Bump,
+    ) {
+        TokenParser::parse_line_with_fns(
+            line,
+            variable_names,
+            dst,
+            units,
+            line_index,
+            0,
+            allocator,
+            FnNames::default(),
+            &[],
+        );
+    }
+
+    /// Same as `parse_line`, but the calls of the functions in `fns` are tokenized
+    /// as function tokens, and `params` are the parameters of the tokenized function body.
+    /// The variables of the lines start with the line `window_y` of the document,
+    /// `line_index` is relative to it just like the variables.
+    pub fn parse_line_with_fns<'text_ptr>(
+        line: &[char],
+        variable_names: &Variables,
+        dst: &mut Vec<Token<'text_ptr>>,
+        units: &Units,
+        line_index: usize,
+        window_y: usize,
+        allocator: &'text_ptr Bump,
+        fns: FnNames,
+        params: &[&[char]],
     ) {
         let mut index = 0;
         let mut can_be_unit = CanBeUnit::Not;
@@ -174,8 +246,31 @@ impl TokenParser {
             });
             return;
         }
+        let bindings = find_bindings(line, params.len());
         while index < line.len() {
             let parse_result = TokenParser::try_extract_comment(&line[index..], allocator)
+                .or_else(|| TokenParser::try_extract_base_digits(line, index, allocator))
+                .or_else(|| {
+                    TokenParser::try_extract_custom_name(
+                        line, index, fns, params, &bindings, allocator,
+                    )
+                })
+                .or_else(|| {
+                    let prev_was_perc = dst
+                        .iter()
+                        .rev()
+                        .find(|token| {
+                            !(token.typ == TokenType::StringLiteral
+                                && token.ptr.iter().all(|ch| ch.is_ascii_whitespace()))
+                        })
+                        .map(|token| token.typ == TokenType::Operator(OperatorTokenType::Perc))
+                        .unwrap_or(false);
+                    if prev_was_perc {
+                        TokenParser::try_extract_percentage_of(&line[index..], allocator)
+                    } else {
+                        None
+                    }
+                })
                 .or_else(|| {
                     let prev_was_lineref = dst
                         .last()
@@ -185,30 +280,85 @@ impl TokenParser {
                         &line[index..],
                         variable_names,
                         line_index,
+                        window_y,
                         allocator,
                         prev_was_lineref,
                     )
                 })
+                .or_else(|| {
+                    // "3e" and "3 e" are incomplete exponents, not multiplications by the constant
+                    let prev_was_number = dst
+                        .iter()
+                        .rev()
+                        .find(|token| {
+                            !(token.typ == TokenType::StringLiteral
+                                && token.ptr.iter().all(|ch| ch.is_ascii_whitespace()))
+                        })
+                        .map(|token| {
+                            matches!(
+                                token.typ,
+                                TokenType::NumberLiteral(..) | TokenType::NumberErr
+                            )
+                        })
+                        .unwrap_or(false);
+                    if prev_was_number {
+                        None
+                    } else {
+                        TokenParser::try_extract_euler_number(&line[index..], allocator)
+                    }
+                })
                 .or_else(|| {
                     TokenParser::try_extract_unit(&line[index..], units, can_be_unit, allocator)
                         .or_else(|| {
-                            TokenParser::try_extract_operator(&line[index..], allocator).or_else(
-                                || {
-                                    TokenParser::try_extract_number_literal(
-                                        &line[index..],
-                                        allocator,
-                                    )
+                            TokenParser::try_extract_operator(&line[index..], allocator)
                                     .or_else(|| {
-                                        TokenParser::try_extract_string_literal(
+                                        // commas separate matrix cells and function arguments
+                                        let mut depth = 0;
+                                        for token in dst.iter() {
+                                            match token.typ {
+                                                TokenType::Operator(
+                                                    OperatorTokenType::ParenOpen,
+                                                )
+                                                | TokenType::Operator(
+                                                    OperatorTokenType::BracketOpen,
+                                                ) => depth += 1,
+                                                TokenType::Operator(
+                                                    OperatorTokenType::ParenClose,
+                                                )
+                                                | TokenType::Operator(
+                                                    OperatorTokenType::BracketClose,
+                                                ) => depth -= 1,
+                                                _ => {}
+                                            }
+                                        }
+                                        TokenParser::try_extract_number_literal(
                                             &line[index..],
                                             allocator,
+                                            depth <= 0,
                                         )
+                                        .or_else(|| {
+                                            TokenParser::try_extract_string_literal(
+                                                &line[index..],
+                                                allocator,
+                                            )
+                                        })
                                     })
-                                },
-                            )
                         })
                 });
-            if let Some(token) = parse_result {
+            if let Some(mut token) = parse_result {
+                if token.typ == TokenType::Operator(OperatorTokenType::AbsBarOpen)
+                    && TokenParser::next_abs_bar_is_closing(dst)
+                {
+                    token.typ = TokenType::Operator(OperatorTokenType::AbsBarClose);
+                }
+                if token.typ == TokenType::Operator(OperatorTokenType::Assign)
+                    && bindings.iter().any(|it| {
+                        it.typ == FnType::Solve && it.scope.0 <= index && index < it.scope.1
+                    })
+                {
+                    // the equation of e.g. "solve(2*x = 4, x)"
+                    token.typ = TokenType::Operator(OperatorTokenType::Eq);
+                }
                 match &token.typ {
                     TokenType::Header => {
                         // the functions already returned in this case
@@ -239,7 +389,9 @@ impl TokenParser {
                             _ => can_be_unit = CanBeUnit::Not,
                         }
                     }
-                    TokenType::Variable { .. } | TokenType::LineReference { .. } => {
+                    TokenType::Variable { .. }
+                    | TokenType::LineReference { .. }
+                    | TokenType::BoundVariable { .. } => {
                         can_be_unit = CanBeUnit::Not;
                     }
                 }
@@ -251,9 +403,68 @@ impl TokenParser {
         }
     }
 
+    /// A bar closes the last open one if it follows an operand, e.g. the 2nd bar in "|-5|",
+    /// but opens a new one in "||-5| - 2|"
+    fn next_abs_bar_is_closing(tokens_so_far: &[Token]) -> bool {
+        let mut open_bar_count = 0isize;
+        for token in tokens_so_far {
+            match token.typ {
+                TokenType::Operator(OperatorTokenType::AbsBarOpen) => open_bar_count += 1,
+                TokenType::Operator(OperatorTokenType::AbsBarClose) => open_bar_count -= 1,
+                _ => {}
+            }
+        }
+        if open_bar_count <= 0 {
+            return false;
+        }
+        let prev_token = tokens_so_far.iter().rev().find(|token| {
+            !(token.typ == TokenType::StringLiteral
+                && token.ptr.iter().all(|ch| ch.is_ascii_whitespace()))
+        });
+        match prev_token.map(|it| &it.typ) {
+            Some(TokenType::NumberLiteral(..))
+            | Some(TokenType::NumberErr)
+            | Some(TokenType::Unit(..))
+            | Some(TokenType::Variable { .. })
+            | Some(TokenType::LineReference { .. })
+            | Some(TokenType::BoundVariable { .. })
+            | Some(TokenType::Operator(OperatorTokenType::ParenClose))
+            | Some(TokenType::Operator(OperatorTokenType::BracketClose))
+            | Some(TokenType::Operator(OperatorTokenType::AbsBarClose))
+            | Some(TokenType::Operator(OperatorTokenType::Perc))
+            | Some(TokenType::Operator(OperatorTokenType::Factorial)) => true,
+            _ => false,
+        }
+    }
+
+    /// A bare "e" is Euler's number (e.g. "ln(e)"), while "e()" is parsed as the function
+    /// and "e.g." remains text.
+    fn try_extract_euler_number<'text_ptr>(
+        str: &[char],
+        allocator: &'text_ptr Bump,
+    ) -> Option<Token<'text_ptr>> {
+        let is_bare = str[0] == 'e'
+            && str
+                .get(1)
+                .map(|it| !it.is_alphanumeric() && !['_', '(', '.'].contains(it))
+                .unwrap_or(true);
+        if is_bare {
+            Some(Token {
+                typ: TokenType::NumberLiteral(E),
+                ptr: allocator.alloc_slice_fill_iter(str.iter().map(|it| *it).take(1)),
+                has_error: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Digits can be grouped by whitespaces or underscores (`1_000`), and by commas
+    /// (`1,000,000`) if `allow_comma_grouping` is true.
     pub fn try_extract_number_literal<'text_ptr>(
         str: &[char],
         allocator: &'text_ptr Bump,
+        allow_comma_grouping: bool,
     ) -> Option<Token<'text_ptr>> {
         let mut number_str = [b'0'; 256];
         let mut number_str_index = 0;
@@ -282,6 +493,7 @@ impl TokenParser {
 
         if str[i..].starts_with(&['0', 'b']) {
             i += 2;
+            let prefix_end = i;
             let mut end_index_before_last_whitespace = i;
             while i < str.len() {
                 if str[i] == '0' || str[i] == '1' {
@@ -296,24 +508,28 @@ impl TokenParser {
                 i += 1;
             }
             i = end_index_before_last_whitespace;
-            if i > 2 {
-                // Decimal cannot parse binary, that's why the explicit i64 type
-                let num: i64 = i64::from_str_radix(
+            // Decimal cannot parse binary, that's why the explicit i64 type
+            let num: Option<i64> = if i > prefix_end {
+                i64::from_str_radix(
                     &unsafe { std::str::from_utf8_unchecked(&number_str[0..number_str_index]) },
                     2,
                 )
-                .ok()?;
-                Some(Token {
-                    typ: TokenType::NumberLiteral(num.into()),
-                    // ptr: &str[0..i],
-                    ptr: allocator.alloc_slice_fill_iter(str.iter().map(|it| *it).take(i)),
-                    has_error: false,
-                })
+                .ok()
             } else {
+                // e.g. "0b" without digits
                 None
-            }
+            };
+            Some(Token {
+                typ: num
+                    .map(|it| TokenType::NumberLiteral(it.into()))
+                    .unwrap_or(TokenType::NumberErr),
+                // ptr: &str[0..i],
+                ptr: allocator.alloc_slice_fill_iter(str.iter().map(|it| *it).take(i)),
+                has_error: num.is_none(),
+            })
         } else if str[i..].starts_with(&['0', 'x']) {
             i += 2;
+            let prefix_end = i;
             let mut end_index_before_last_whitespace = i;
             while i < str.len() {
                 if str[i].is_ascii_hexdigit()
@@ -337,22 +553,25 @@ impl TokenParser {
                 i += 1;
             }
             i = end_index_before_last_whitespace;
-            if i > 2 {
-                // Decimal cannot parse hex, that's why the explicit i64 type
-                let num: i64 = i64::from_str_radix(
+            // Decimal cannot parse hex, that's why the explicit i64 type
+            let num: Option<i64> = if i > prefix_end {
+                i64::from_str_radix(
                     &unsafe { std::str::from_utf8_unchecked(&number_str[0..number_str_index]) },
                     16,
                 )
-                .ok()?;
-                Some(Token {
-                    typ: TokenType::NumberLiteral(num.into()),
-                    // ptr: &str[0..i],
-                    ptr: allocator.alloc_slice_fill_iter(str.iter().map(|it| *it).take(i)),
-                    has_error: false,
-                })
+                .ok()
             } else {
+                // e.g. "0x" without digits
                 None
-            }
+            };
+            Some(Token {
+                typ: num
+                    .map(|it| TokenType::NumberLiteral(it.into()))
+                    .unwrap_or(TokenType::NumberErr),
+                // ptr: &str[0..i],
+                ptr: allocator.alloc_slice_fill_iter(str.iter().map(|it| *it).take(i)),
+                has_error: num.is_none(),
+            })
         } else if str
             .get(0)
             .map(|it| it.is_ascii_digit() || *it == '.' || *it == '-')
@@ -365,6 +584,7 @@ impl TokenParser {
             let mut e_neg = false;
             let mut e_already_added = false;
             let mut multiplier = None;
+            let mut digits_since_comma = 0;
 
             while i < str.len() {
                 if str[i] == '.' && decimal_point_count < 1 && e_count < 1 {
@@ -410,10 +630,40 @@ impl TokenParser {
                         e_already_added = true;
                     } else {
                         digit_count += 1;
+                        digits_since_comma += 1;
                         end_index_before_last_whitespace = i + 1;
                         number_str[number_str_index] = str[i] as u8;
                         number_str_index += 1;
                     }
+                } else if str[i] == '_'
+                    && e_count < 1
+                    && i > 0
+                    && str[i - 1].is_ascii_digit()
+                    && str
+                        .get(i + 1)
+                        .map(|it| it.is_ascii_digit())
+                        .unwrap_or(false)
+                {
+                    // allowed
+                } else if str[i] == ','
+                    && allow_comma_grouping
+                    && decimal_point_count == 0
+                    && e_count < 1
+                    && digits_since_comma >= 1
+                    && digits_since_comma <= 3
+                    && str[i - 1].is_ascii_digit()
+                    && (1..=3).all(|j| {
+                        str.get(i + j)
+                            .map(|it| it.is_ascii_digit())
+                            .unwrap_or(false)
+                    })
+                    && str
+                        .get(i + 4)
+                        .map(|it| !it.is_ascii_digit())
+                        .unwrap_or(true)
+                {
+                    // thousands separator, e.g. 1,000,000
+                    digits_since_comma = 0;
                 } else if str[i].is_ascii_whitespace() {
                     // allowed
                 } else {
@@ -507,6 +757,84 @@ impl TokenParser {
         };
     }
 
+    /// The names bound by calls like "sum(i, 1, 5, i^2)" and the parameters of the tokenized
+    /// function body hide everything else with the same name, and the user defined and native
+    /// functions are recognized by their names followed by '('.
+    fn try_extract_custom_name<'text_ptr>(
+        line: &[char],
+        index: usize,
+        fns: FnNames,
+        params: &[&[char]],
+        bindings: &[Binding],
+        allocator: &'text_ptr Bump,
+    ) -> Option<Token<'text_ptr>> {
+        if index > 0 && is_identifier_char(line[index - 1]) {
+            return None;
+        }
+        let end = identifier_end(line, index)?;
+        let name = &line[index..end];
+        // the innermost binding hides the outer ones and the parameters
+        let bound_value_index = bindings
+            .iter()
+            .find(|it| it.name_index == index)
+            .or_else(|| {
+                bindings
+                    .iter()
+                    .rev()
+                    .find(|it| it.name == name && it.scope.0 <= index && index < it.scope.1)
+            })
+            .map(|it| it.value_index)
+            .or_else(|| params.iter().position(|it| *it == name));
+        let typ = if let Some(binding) = bindings.iter().find(|it| it.fn_index == index) {
+            TokenType::Operator(OperatorTokenType::Fn {
+                arg_count: 0, // unused in tokens, so can be fixed 0
+                typ: binding.typ,
+            })
+        } else if let Some(bound_value_index) = bound_value_index {
+            TokenType::BoundVariable {
+                index: bound_value_index,
+            }
+        } else if line.get(end) == Some(&'(') {
+            let fn_type = fns
+                .user_fns
+                .iter()
+                .rposition(|it| it.name == name)
+                .map(FnType::User)
+                .or_else(|| {
+                    fns.native_fns
+                        .iter()
+                        .position(|it| &*it.name == name)
+                        .map(FnType::Native)
+                })?;
+            TokenType::Operator(OperatorTokenType::Fn {
+                arg_count: 0, // unused in tokens, so can be fixed 0
+                typ: fn_type,
+            })
+        } else {
+            return None;
+        };
+        Some(Token {
+            typ,
+            ptr: allocator.alloc_slice_fill_iter(name.iter().map(|it| *it)),
+            has_error: false,
+        })
+    }
+
+    /// The digits of "frombase(FF, 16)" are a number, or an invalid one if they are not
+    /// digits of the base, see `find_base_digits_arg`.
+    fn try_extract_base_digits<'text_ptr>(
+        line: &[char],
+        index: usize,
+        allocator: &'text_ptr Bump,
+    ) -> Option<Token<'text_ptr>> {
+        let (end, value) = find_base_digits_arg(line, index)?;
+        Some(Token {
+            typ: value.map_or(TokenType::NumberErr, TokenType::NumberLiteral),
+            ptr: allocator.alloc_slice_fill_iter(line[index..end].iter().copied()),
+            has_error: false,
+        })
+    }
+
     fn try_extract_comment<'text_ptr>(
         line: &[char],
         allocator: &'text_ptr Bump,
@@ -526,10 +854,15 @@ impl TokenParser {
         line: &[char],
         vars: &Variables,
         row_index: usize,
+        window_y: usize,
         allocator: &'text_ptr Bump,
         prev_was_lineref: bool,
     ) -> Option<Token<'text_ptr>> {
-        if line.starts_with(&['s', 'u', 'm']) && line.get(3).map(|it| *it == ' ').unwrap_or(true) {
+        // the sum variable is missing if it was disabled
+        if vars[SUM_VARIABLE_INDEX].is_some()
+            && line.starts_with(&['s', 'u', 'm'])
+            && line.get(3).map(|it| *it == ' ').unwrap_or(true)
+        {
             return Some(Token {
                 typ: TokenType::Variable {
                     var_index: SUM_VARIABLE_INDEX,
@@ -538,9 +871,44 @@ impl TokenParser {
                 has_error: false,
             });
         }
+        // "ans[n]" is the result of the n-th line (1-based) of the document
+        if line.starts_with(&['a', 'n', 's', '[']) {
+            let digit_count = line[4..]
+                .iter()
+                .take_while(|it| it.is_ascii_digit())
+                .count();
+            if digit_count > 0 && line.get(4 + digit_count) == Some(&']') {
+                let var_index = line[4..4 + digit_count]
+                    .iter()
+                    .collect::<String>()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|it| *it > window_y && *it <= window_y + row_index)
+                    .map(|it| it - 1 - window_y);
+                if let Some(var_index) = var_index.filter(|it| vars[*it].is_some()) {
+                    return Some(Token {
+                        typ: TokenType::Variable { var_index },
+                        ptr: allocator
+                            .alloc_slice_fill_iter(line.iter().map(|it| *it).take(digit_count + 5)),
+                        has_error: false,
+                    });
+                }
+            }
+        }
         let mut longest_match_index = 0;
         let mut longest_match = 0;
-        'asd: for (var_index, var) in vars[0..row_index].iter().enumerate().rev() {
+        // the external variables come first so they win over the document's definitions,
+        // and the ones carried from above the window come last, the window's lines override them
+        let external_vars = vars
+            .iter()
+            .enumerate()
+            .skip(FIRST_EXTERNAL_VAR_INDEX)
+            .take(MAX_EXTERNAL_VAR_COUNT);
+        let carried_vars = vars.iter().enumerate().skip(FIRST_CARRIED_VAR_INDEX);
+        'asd: for (var_index, var) in external_vars
+            .chain(vars[0..row_index].iter().enumerate().rev())
+            .chain(carried_vars)
+        {
             if var.is_none() {
                 continue;
             }
@@ -638,6 +1006,24 @@ impl TokenParser {
         }
     }
 
+    /// "20% of 500" is the same as "20% * 500"
+    fn try_extract_percentage_of<'text_ptr>(
+        str: &[char],
+        allocator: &'text_ptr Bump,
+    ) -> Option<Token<'text_ptr>> {
+        if str.starts_with(&['o', 'f'])
+            && str.get(2).map(|it| !it.is_alphanumeric()).unwrap_or(true)
+        {
+            Some(Token {
+                typ: TokenType::Operator(OperatorTokenType::Mult),
+                ptr: allocator.alloc_slice_fill_iter(str.iter().map(|it| *it).take(2)),
+                has_error: false,
+            })
+        } else {
+            None
+        }
+    }
+
     fn try_extract_operator<'text_ptr>(
         str: &[char],
         allocator: &'text_ptr Bump,
@@ -656,6 +1042,7 @@ impl TokenParser {
             });
         }
         match str[0] {
+            '=' if str.get(1) == Some(&'=') => op(OperatorTokenType::Eq, str, 2, allocator),
             '=' => op(OperatorTokenType::Assign, str, 1, allocator),
             '+' => op(OperatorTokenType::Add, str, 1, allocator),
             '-' => op(OperatorTokenType::Sub, str, 1, allocator),
@@ -669,8 +1056,13 @@ impl TokenParser {
             ']' => op(OperatorTokenType::BracketClose, str, 1, allocator),
             ',' => op(OperatorTokenType::Comma, str, 1, allocator),
             ';' => op(OperatorTokenType::Semicolon, str, 1, allocator),
+            '?' => op(OperatorTokenType::Question, str, 1, allocator),
+            '~' => op(OperatorTokenType::BinNot, str, 1, allocator),
+            ':' => op(OperatorTokenType::Colon, str, 1, allocator),
+            // parse_line decides whether it is a closing one
+            '|' => op(OperatorTokenType::AbsBarOpen, str, 1, allocator),
             _ => {
-                if str.starts_with(&['i', 'n', ' ']) {
+                if str.starts_with(&['i', 'n', ' ']) || str.starts_with(&['t', 'o', ' ']) {
                     op(OperatorTokenType::UnitConverter, str, 2, allocator)
                 } else if str.starts_with(&['A', 'N', 'D'])
                     && str.get(3).map(|it| !it.is_alphabetic()).unwrap_or(true)
@@ -688,10 +1080,26 @@ impl TokenParser {
                     && str.get(3).map(|it| !it.is_alphabetic()).unwrap_or(true)
                 {
                     op(OperatorTokenType::BinXor, str, 3, allocator)
+                } else if str.starts_with(&['m', 'o', 'd'])
+                    && str.get(3).map(|it| !it.is_alphabetic()).unwrap_or(true)
+                {
+                    op(OperatorTokenType::Mod, str, 3, allocator)
                 } else if str.starts_with(&['<', '<']) {
                     op(OperatorTokenType::ShiftLeft, str, 2, allocator)
                 } else if str.starts_with(&['>', '>']) {
                     op(OperatorTokenType::ShiftRight, str, 2, allocator)
+                } else if str.starts_with(&['<', '=']) {
+                    op(OperatorTokenType::LessEq, str, 2, allocator)
+                } else if str.starts_with(&['>', '=']) {
+                    op(OperatorTokenType::GreaterEq, str, 2, allocator)
+                } else if str.starts_with(&['!', '=']) {
+                    op(OperatorTokenType::NotEq, str, 2, allocator)
+                } else if str[0] == '!' {
+                    op(OperatorTokenType::Factorial, str, 1, allocator)
+                } else if str[0] == '<' {
+                    op(OperatorTokenType::Less, str, 1, allocator)
+                } else if str[0] == '>' {
+                    op(OperatorTokenType::Greater, str, 1, allocator)
                 } else {
                     None
                 }
@@ -756,6 +1164,7 @@ mod tests {
 
         test_parse("0x1", 1);
         test_parse("0xAB Cd e    f", 11_259_375);
+        test_parse("0xfF", 255);
 
         test_parse("1", 1);
         test_parse("123456", 123456);
@@ -1029,6 +1438,16 @@ mod tests {
             "0xFF XOR",
             &[num(0xff), str(" "), op(OperatorTokenType::BinXor)],
         );
+        test(
+            "17 mod 5",
+            &[
+                num(17),
+                str(" "),
+                op(OperatorTokenType::Mod),
+                str(" "),
+                num(5),
+            ],
+        );
 
         test(
             "((0b00101 AND 0xFF) XOR 0xFF00) << 16 >> 16",
@@ -1056,6 +1475,73 @@ mod tests {
                 num(16),
             ],
         );
+        test(
+            "1 <= 2 == 3 != 4 >= 5 < 6 > 7",
+            &[
+                num(1),
+                str(" "),
+                op(OperatorTokenType::LessEq),
+                str(" "),
+                num(2),
+                str(" "),
+                op(OperatorTokenType::Eq),
+                str(" "),
+                num(3),
+                str(" "),
+                op(OperatorTokenType::NotEq),
+                str(" "),
+                num(4),
+                str(" "),
+                op(OperatorTokenType::GreaterEq),
+                str(" "),
+                num(5),
+                str(" "),
+                op(OperatorTokenType::Less),
+                str(" "),
+                num(6),
+                str(" "),
+                op(OperatorTokenType::Greater),
+                str(" "),
+                num(7),
+            ],
+        );
+        test(
+            "||1| - 2|",
+            &[
+                op(OperatorTokenType::AbsBarOpen),
+                op(OperatorTokenType::AbsBarOpen),
+                num(1),
+                op(OperatorTokenType::AbsBarClose),
+                str(" "),
+                op(OperatorTokenType::Sub),
+                str(" "),
+                num(2),
+                op(OperatorTokenType::AbsBarClose),
+            ],
+        );
+        test("0x", &[num_err()]);
+        test(
+            "0b + 0xg",
+            &[
+                num_err(),
+                str(" "),
+                op(OperatorTokenType::Add),
+                str(" "),
+                num_err(),
+                str("g"),
+            ],
+        );
+        test(
+            "~0xFF + 1",
+            &[
+                op(OperatorTokenType::BinNot),
+                num(0xFF),
+                str(" "),
+                op(OperatorTokenType::Add),
+                str(" "),
+                num(1),
+            ],
+        );
         test(
             "NOT(0xFF)",
             &[
@@ -1115,6 +1601,49 @@ mod tests {
             ],
         );
 
+        test(
+            "10km/h * 45min to m",
+            &[
+                num(10),
+                apply_to_prev_token_unit("km/h"),
+                str(" "),
+                op(OperatorTokenType::Mult),
+                str(" "),
+                num(45),
+                apply_to_prev_token_unit("min"),
+                str(" "),
+                op(OperatorTokenType::UnitConverter),
+                str(" "),
+                unit("m"),
+            ],
+        );
+
+        test(
+            "20% of 500",
+            &[
+                num(20),
+                op(OperatorTokenType::Perc),
+                str(" "),
+                op(OperatorTokenType::Mult),
+                str(" "),
+                num(500),
+            ],
+        );
+        // "of" is an operator only after a percentage
+        test(
+            "rest of 500",
+            &[str("rest"), str(" "), str("of"), str(" "), num(500)],
+        );
+        test(
+            "20% offset",
+            &[
+                num(20),
+                op(OperatorTokenType::Perc),
+                str(" "),
+                str("offset"),
+            ],
+        );
+
         test(
             "45min in m",
             &[
@@ -1274,6 +1803,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_thousands_separators() {
+        test("1,000,000", &[num(1_000_000)]);
+        test("1_000_000", &[num(1_000_000)]);
+        test("12,345.5", &[numf(12345.5)]);
+        test(
+            "1,000 + 1",
+            &[
+                num(1000),
+                str(" "),
+                op(OperatorTokenType::Add),
+                str(" "),
+                num(1),
+            ],
+        );
+        // not groups of 3 digits
+        test("1,00", &[num(1), op(OperatorTokenType::Comma), num(0)]);
+        test(
+            "1234,567",
+            &[num(1234), op(OperatorTokenType::Comma), num(567)],
+        );
+        test(
+            "[1,000]",
+            &[
+                op(OperatorTokenType::BracketOpen),
+                num(1),
+                op(OperatorTokenType::Comma),
+                num(0),
+                op(OperatorTokenType::BracketClose),
+            ],
+        );
+        test(
+            "max(1,000)",
+            &[
+                str("max"),
+                op(OperatorTokenType::ParenOpen),
+                num(1),
+                op(OperatorTokenType::Comma),
+                num(0),
+                op(OperatorTokenType::ParenClose),
+            ],
+        );
+    }
+
     #[test]
     fn matrix_parsing() {
         // there are no empty matrices
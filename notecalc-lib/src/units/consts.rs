@@ -49,9 +49,49 @@ pub type UnitDimensionExponent = i8;
 pub const BASE_UNIT_DIMENSION_COUNT: usize = 10;
 pub const ALL_UNIT_COUNT: usize = 25;
 
+/// USD is the base currency, the value of the others comes from the exchange rates
+/// (see `Units::set_exchange_rates`) and it is zero until their rate is known.
+pub const BASE_CURRENCY: &str = "USD";
+/// The value of a currency is its exchange rate (how many of it one USD is worth),
+/// so unlike the other units, it divides when converting to the base unit.
+pub(crate) const MONEY_DIMENSIONS: [UnitDimensionExponent; BASE_UNIT_DIMENSION_COUNT] =
+    BASE_UNIT_DIMENSIONS[UnitType::Money as usize];
+pub const CURRENCIES: &[(&str, &[char])] = &[
+    ("USD", &['U', 'S', 'D']),
+    ("EUR", &['E', 'U', 'R']),
+    ("GBP", &['G', 'B', 'P']),
+    ("JPY", &['J', 'P', 'Y']),
+    ("CHF", &['C', 'H', 'F']),
+    ("CAD", &['C', 'A', 'D']),
+    ("AUD", &['A', 'U', 'D']),
+    ("NZD", &['N', 'Z', 'D']),
+    ("CNY", &['C', 'N', 'Y']),
+    ("HKD", &['H', 'K', 'D']),
+    ("SGD", &['S', 'G', 'D']),
+    ("KRW", &['K', 'R', 'W']),
+    ("INR", &['I', 'N', 'R']),
+    ("HUF", &['H', 'U', 'F']),
+    ("PLN", &['P', 'L', 'N']),
+    ("CZK", &['C', 'Z', 'K']),
+    ("SEK", &['S', 'E', 'K']),
+    ("NOK", &['N', 'O', 'K']),
+    ("DKK", &['D', 'K', 'K']),
+    ("TRY", &['T', 'R', 'Y']),
+    ("BRL", &['B', 'R', 'L']),
+    ("MXN", &['M', 'X', 'N']),
+    ("ZAR", &['Z', 'A', 'R']),
+];
+
+// the units in which the quantities are stored, in the order of the dimensions
+pub const BASE_UNIT_NAMES: [&str; BASE_UNIT_DIMENSION_COUNT] =
+    ["kg", "m", "s", "A", "K", "cd", "mol", "rad", "bit", "$"];
+
 pub const EMPTY_UNIT_DIMENSIONS: [UnitDimensionExponent; BASE_UNIT_DIMENSION_COUNT] =
     [0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
 
+pub const ANGLE_UNIT_DIMENSIONS: [UnitDimensionExponent; BASE_UNIT_DIMENSION_COUNT] =
+    BASE_UNIT_DIMENSIONS[UnitType::Angle as usize];
+
 pub(crate) const BASE_UNIT_DIMENSIONS: [[UnitDimensionExponent; BASE_UNIT_DIMENSION_COUNT];
     ALL_UNIT_COUNT] = [
     [1, 0, 0, 0, 0, 0, 0, 0, 0, 0], // Mass
@@ -2020,6 +2060,18 @@ pub fn init_units() -> (HashMap<&'static str, RefCell<Unit>>, UnitPrefixes) {
             offset: Decimal::from_i64(0).unwrap(),
         },
     );
+    for &(code, name) in CURRENCIES {
+        map.insert(
+            code,
+            Unit {
+                name,
+                base: BASE_UNIT_DIMENSIONS[UnitType::Money as usize],
+                prefix_groups: (None, None),
+                value: Decimal::from_i64(if code == BASE_CURRENCY { 1 } else { 0 }).unwrap(),
+                offset: Decimal::from_i64(0).unwrap(),
+            },
+        );
+    }
 
     let map = map
         .into_iter()
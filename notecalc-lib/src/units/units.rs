@@ -1,9 +1,10 @@
 use crate::calc::pow;
 use crate::units::consts::{
-    get_base_unit_for, init_aliases, init_units, UnitDimensionExponent, BASE_UNIT_DIMENSIONS,
-    BASE_UNIT_DIMENSION_COUNT,
+    get_base_unit_for, init_aliases, init_units, UnitDimensionExponent, BASE_CURRENCY,
+    BASE_UNIT_DIMENSIONS, BASE_UNIT_DIMENSION_COUNT, BASE_UNIT_NAMES, CURRENCIES, MONEY_DIMENSIONS,
 };
 use crate::units::{Prefix, Unit, UnitPrefixes};
+use rust_decimal::prelude::{One, Zero};
 use rust_decimal::Decimal;
 use smallvec::alloc::fmt::{Debug, Display, Formatter};
 use smallvec::SmallVec;
@@ -13,6 +14,48 @@ use std::convert::TryFrom;
 use std::fmt::Write;
 use std::str::FromStr;
 
+const MAX_UNIT_SUGGESTION_COUNT: usize = 5;
+// the prefixes whose prefixed forms are listed by `list_units`
+const COMMON_PREFIXES: &[&str] = &[
+    "k", "M", "G", "c", "m", "u", "kilo", "mega", "giga", "centi", "milli", "micro",
+];
+
+// Levenshtein distance where swapping two adjacent chars counts as one edit
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut prev_prev_row: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev_row: Vec<usize> = (0..=b.len()).collect();
+    let mut row: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        row[0] = i;
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            row[j] = (prev_row[j] + 1)
+                .min(row[j - 1] + 1)
+                .min(prev_row[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(prev_prev_row[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut prev_prev_row, &mut prev_row);
+        std::mem::swap(&mut prev_row, &mut row);
+    }
+    return prev_row[b.len()];
+}
+
+/// value * (mul / div)^power, without computing the (rounded) reciprocal of anything,
+/// e.g. the exchange rates are applied by it.
+fn mul_by_exchange_rate(
+    value: &Decimal,
+    mul: &Decimal,
+    div: &Decimal,
+    power: i64,
+) -> Option<Decimal> {
+    let (mul, div) = if power < 0 { (div, mul) } else { (mul, div) };
+    value
+        .checked_mul(&pow(mul.clone(), power.abs())?)?
+        .checked_div(&pow(div.clone(), power.abs())?)
+}
+
 fn next(str: &[char]) -> &[char] {
     &str[1..]
 }
@@ -57,6 +100,81 @@ impl Units {
         }
     }
 
+    /// `rates` tells how many units of a currency one USD (the base currency) is worth.
+    /// Currencies missing from it have no value, so calculations with them fail.
+    /// Already parsed quantities keep the rates they were parsed with.
+    pub fn set_exchange_rates(&self, rates: &[(String, Decimal)]) {
+        for &(code, _name) in CURRENCIES {
+            if code == BASE_CURRENCY {
+                continue;
+            }
+            // the rate is stored as it is, see `MONEY_DIMENSIONS`
+            let value = rates
+                .iter()
+                .find(|(it, _rate)| it == code)
+                .map(|(_code, rate)| rate.clone())
+                .unwrap_or(Decimal::zero());
+            self.units[code].borrow_mut().value = value;
+        }
+    }
+
+    /// Every unit name and alias in alphabetical order, together with the forms prefixed
+    /// by the common prefixes (e.g. "g" and "kg", but not "Eg").
+    pub fn list_units(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .units
+            .keys()
+            .chain(self.aliases.keys())
+            .map(|name| (*name).to_owned())
+            .collect();
+        for (name, unit) in &self.units {
+            let unit = unit.borrow();
+            for prefix_group in &[&unit.prefix_groups.0, &unit.prefix_groups.1] {
+                if let Some(prefixes) = prefix_group {
+                    for prefix in prefixes.borrow().iter() {
+                        let prefix: String = prefix.borrow().name.iter().collect();
+                        if COMMON_PREFIXES.contains(&prefix.as_str()) {
+                            names.push(prefix + name);
+                        }
+                    }
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Unit names and aliases similar to `token`, the closest ones first.
+    /// It is empty if `token` is a known unit.
+    pub fn suggest_unit(&self, token: &str) -> Vec<String> {
+        let token: Vec<char> = token.chars().collect();
+        if token.is_empty() || self.find_unit(&token).is_some() {
+            return vec![];
+        }
+        let max_distance = if token.len() <= 4 { 1 } else { 2 };
+        let mut candidates: Vec<(usize, &str)> = self
+            .units
+            .keys()
+            .chain(self.aliases.keys())
+            .filter_map(|name| {
+                let name_chars: Vec<char> = name.chars().collect();
+                let distance = edit_distance(&token, &name_chars);
+                if distance <= max_distance {
+                    Some((distance, *name))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        candidates.sort();
+        candidates
+            .into_iter()
+            .take(MAX_UNIT_SUGGESTION_COUNT)
+            .map(|(_distance, name)| name.to_owned())
+            .collect()
+    }
+
     pub fn parse(&self, text: &[char]) -> (UnitOutput, usize) {
         let mut output = UnitOutput::new();
         let mut power_multiplier_current: UnitDimensionExponent = 1;
@@ -460,6 +578,24 @@ impl UnitOutput {
         return true;
     }
 
+    /// The unit of the normalized value written as base units, e.g. "kg m s^-2" for N.
+    pub fn base_unit_string(&self) -> String {
+        let mut result = String::new();
+        for (name, exp) in BASE_UNIT_NAMES.iter().zip(self.dimensions.iter()) {
+            if *exp == 0 {
+                continue;
+            }
+            if !result.is_empty() {
+                result.push(' ');
+            }
+            result.push_str(name);
+            if *exp != 1 {
+                write!(result, "^{}", exp).expect("");
+            }
+        }
+        return result;
+    }
+
     pub fn is_unitless(&self) -> bool {
         self.dimensions.iter().all(|it| *it == 0)
     }
@@ -570,14 +706,28 @@ impl UnitOutput {
                 let base_value = &unit.unit.borrow().value;
                 let prefix_val = &unit.prefix.borrow().value;
                 let power = unit.power;
+                if base_value.is_zero() {
+                    // e.g. a currency without exchange rate
+                    return None;
+                }
 
-                result = result.checked_mul(&pow(base_value * prefix_val, power as i64)?)?;
+                result = if unit.unit.borrow().base == MONEY_DIMENSIONS {
+                    mul_by_exchange_rate(&result, prefix_val, base_value, power as i64)?
+                } else {
+                    result.checked_mul(&pow(base_value * prefix_val, power as i64)?)?
+                };
             }
             return Some(result);
         } else {
             let base_value = &self.units[0].unit.borrow().value;
             let offset = &self.units[0].unit.borrow().offset;
             let prefix_val = &self.units[0].prefix.borrow().value;
+            if base_value.is_zero() {
+                return None;
+            }
+            if self.units[0].unit.borrow().base == MONEY_DIMENSIONS {
+                return mul_by_exchange_rate(&(value + offset), prefix_val, base_value, 1);
+            }
 
             let a = value + offset;
             let b = base_value * prefix_val;
@@ -592,6 +742,10 @@ impl UnitOutput {
                 let base_value = &unit.unit.borrow().value;
                 let prefix_val = &unit.prefix.borrow().value;
                 let power = unit.power;
+                if unit.unit.borrow().base == MONEY_DIMENSIONS {
+                    result = mul_by_exchange_rate(&result, base_value, prefix_val, power as i64)?;
+                    continue;
+                }
                 let pow = pow(base_value.checked_mul(prefix_val)?, power as i64)?;
                 result = result.checked_div(&pow)?;
             }
@@ -608,8 +762,9 @@ impl UnitOutput {
             let borrow_prefix = self.units[0].prefix.borrow();
             let prefix_val = &borrow_prefix.value;
 
-            use rust_decimal::prelude::One;
-            if base_value < &Decimal::one() {
+            if borrow.base == MONEY_DIMENSIONS {
+                mul_by_exchange_rate(value, base_value, prefix_val, 1)?.checked_sub(offset)
+            } else if base_value < &Decimal::one() {
                 let denom = prefix_val.checked_mul(base_value)?;
                 value.checked_div(&denom)?.checked_sub(offset)
             } else {
@@ -632,6 +787,29 @@ impl UnitOutput {
         return Some(result);
     }
 
+    /// The inverse of pow, None if any of the powers is not divisible by n
+    pub fn root(&self, n: i64) -> Option<UnitOutput> {
+        let mut result = self.clone();
+        let n = i8::try_from(n).ok()?;
+        if n < 1 {
+            return None;
+        }
+        for dim in &mut result.dimensions {
+            if *dim % n != 0 {
+                return None;
+            }
+            *dim /= n;
+        }
+        for unit in &mut result.units {
+            if unit.power % n != 0 {
+                return None;
+            }
+            unit.power /= n;
+        }
+
+        return Some(result);
+    }
+
     pub fn is_derived(&self) -> bool {
         self.units.len() > 1 || (self.units.len() == 1 && self.units[0].power > 1)
     }
@@ -1126,6 +1304,38 @@ mod tests {
         assert_eq!(0, unit1.units.len());
     }
 
+    #[test]
+    fn test_suggest_unit() {
+        let units = Units::new();
+
+        assert!(units.suggest_unit("metre").contains(&"meter".to_owned()));
+        assert!(units.suggest_unit("meter").is_empty());
+        assert!(units.suggest_unit("qwxyz").is_empty());
+    }
+
+    #[test]
+    fn test_list_units() {
+        let units = Units::new();
+        let names = units.list_units();
+
+        for name in &[
+            "m",
+            "g",
+            "s",
+            "h",
+            "meter",
+            "USD",
+            "kg",
+            "km",
+            "cm",
+            "ms",
+            "kilometer",
+        ] {
+            assert!(names.contains(&name.to_string()), "{}", name);
+        }
+        assert!(!names.contains(&"Eg".to_owned()));
+    }
+
     #[test]
     fn parsing_bug_fuzz() {
         let units = Units::new();
@@ -339,3 +339,16 @@ fn test_line_ref_denormalization() {
     assert_eq!(2, content.get_data(1).line_id);
     assert_eq!(3, content.get_data(2).line_id);
 }
+
+#[test]
+fn test_line_refs_to_rows_above_999_are_normalized() {
+    let test = create_app2(35);
+    let content = "1\n".repeat(1200) + "&[1100] + &[5]";
+    test.set_normalized_content(&content);
+    assert_eq!(
+        test.app().get_line_ref_normalized_content(),
+        content.clone() + "\n"
+    );
+    test.mut_app().normalize_line_refs_in_place();
+    assert_eq!(test.get_editor_content(), content);
+}